@@ -2,9 +2,16 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 use image::{DynamicImage, GenericImageView, Rgba};
 use itertools::Itertools;
+use keyberon::action::Action;
+use keyboard_shared::LegendKey;
+
+#[allow(dead_code)]
+#[path = "src/layout.rs"]
+mod layout;
 
 fn generate_image(image: DynamicImage) -> Vec<(u32, Vec<(u32, bool)>)> {
     let pixels = image
@@ -25,6 +32,79 @@ fn generate_image(image: DynamicImage) -> Vec<(u32, Vec<(u32, bool)>)> {
         .collect::<Vec<_>>()
 }
 
+/// What the legend shows for an action of `LAYERS`
+fn legend_key(action: &Action<layout::CustomEvent>) -> LegendKey {
+    match action {
+        Action::NoOp => LegendKey::NoOp,
+        Action::Trans => LegendKey::Trans,
+        Action::KeyCode(k) => LegendKey::Key(*k as u8),
+        Action::MultipleKeyCodes(keys) => {
+            LegendKey::Combo(keys.iter().map(|k| *k as u8).collect())
+        }
+        Action::Layer(n) => LegendKey::Layer(*n),
+        Action::DefaultLayer(n) => LegendKey::DefaultLayer(*n),
+        Action::HoldTap(ht) => legend_key(&ht.tap),
+        Action::Custom(layout::CustomEvent::Consumer(c)) => LegendKey::Named(c.label()),
+        _ => LegendKey::Other,
+    }
+}
+
+/// Writes `legend.rs`: a nul padded label for each key of each layer, for the
+/// layer legend display
+fn generate_legend(out: &PathBuf) {
+    use layout::{LAYERS, ROWS};
+
+    // the chord row is checked too, though only the physical rows are drawn
+    let labels = keyboard_shared::legend(&LAYERS, legend_key).unwrap_or_else(|m| {
+        panic!(
+            "The keycode {:#04x} at {:?} of LAYERS needs a label in keyboard_shared's legend.rs",
+            m.keycode, m.pos
+        )
+    });
+
+    let mut f = File::create(out.join("legend.rs")).unwrap();
+
+    write!(f, "[").unwrap();
+    for layer in labels.iter() {
+        write!(f, "[").unwrap();
+        for row in layer.iter().take(ROWS) {
+            write!(f, "[").unwrap();
+            for label in row.iter() {
+                let mut bytes = [0u8; keyboard_shared::MAX_LABEL];
+                bytes[..label.len()].copy_from_slice(label.as_bytes());
+                write!(f, "{:?},", bytes).unwrap();
+            }
+            write!(f, "],").unwrap();
+        }
+        write!(f, "],").unwrap();
+    }
+    write!(f, "]").unwrap();
+}
+
+/// The short hash of the commit being built, so the halves can tell if
+/// they're running different firmware. Builds with uncommitted changes get
+/// `-dirty` on the end, as the hash alone doesn't say what they're running.
+fn git_hash() -> String {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=8", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_owned());
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map_or(false, |o| !o.stdout.is_empty());
+
+    match hash {
+        Some(hash) if dirty => format!("{}-dirty", hash),
+        Some(hash) => hash,
+        None => "unknown".to_owned(),
+    }
+}
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -57,6 +137,17 @@ fn main() {
 
     // panic!("lol");
 
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+    // and whenever the sources change, for the dirty flag
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../keyboard_shared/src");
+    println!("cargo:rustc-env=KEYBOARD_GIT_HASH={}", git_hash());
+
+    println!("cargo:rerun-if-changed=src/layout.rs");
+    generate_legend(out);
+
     File::create(out.join("memory.x"))
         .unwrap()
         .write_all(include_bytes!("memory.x"))
@@ -1,7 +1,9 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00026000, LENGTH = 868K
+  FLASH : ORIGIN = 0x00026000, LENGTH = 856K
+  /* the last three pages before 0xFF000 are kept, two for settings (see
+     settings.rs) and one for the keypress count (see keypress_store.rs) */
   RAM : ORIGIN = 0x20020000, LENGTH = 128K
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
@@ -0,0 +1,37 @@
+//! Chords that run firmware actions instead of typing.
+//!
+//! These are matched on the left half, on both halves' key events together,
+//! so a chord can use a key from each. Each half has already done its
+//! normal chording by then, which turns the members of a normal chord into
+//! its output on the chord row, so where the two overlap the normal chord
+//! always wins.
+//!
+//! The matching is [`keyboard_shared::ActionChordMatcher`]. The window is
+//! longer than the normal chords get, but it only ever delays presses of
+//! member keys that type something, modifiers go straight through.
+
+use keyboard_shared::{action_chords_valid, ChordEvent};
+
+use crate::{
+    key_event::KeyEvent,
+    layout::{CustomEvent, ACTION_CHORDS, ROWS},
+};
+
+pub type ActionChordMatcher<const N: usize> =
+    keyboard_shared::ActionChordMatcher<CustomEvent, KeyEvent, N>;
+
+const _: () = assert!(action_chords_valid(&ACTION_CHORDS, ROWS));
+
+impl ChordEvent for KeyEvent {
+    fn coord(&self) -> (u8, u8) {
+        KeyEvent::coord(self)
+    }
+
+    fn is_press(&self) -> bool {
+        KeyEvent::is_press(self)
+    }
+
+    fn is_synthetic(&self) -> bool {
+        KeyEvent::is_synthetic(self)
+    }
+}
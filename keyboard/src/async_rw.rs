@@ -1,15 +1,27 @@
-use defmt::debug;
 use embassy_futures::select::select;
-use embassy_nrf::uarte::{self, UarteRx, UarteTx};
+use embassy_nrf::{
+    pac,
+    peripherals::UARTE0,
+    uarte::{self, UarteRx, UarteTx},
+};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
 use embassy_usb::driver::{Driver, EndpointError};
 use embassy_usb::class::cdc_acm::CdcAcmClass;
 use futures::Future;
+use keyboard_shared::LinkErrorKind;
+
+use crate::log_sampled;
 
 pub trait AsyncRead {
     type Error;
 
     async fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> Result<(), Self::Error>;
+
+    /// A problem on the line since this was last asked, for transports that
+    /// can see them. Bytes read around it can't be trusted.
+    fn take_line_error(&mut self) -> Option<LinkErrorKind> {
+        None
+    }
 }
 
 pub trait AsyncWrite {
@@ -18,13 +30,45 @@ pub trait AsyncWrite {
     async fn write<'a>(&'a mut self, buf: &'a [u8]) -> Result<(), Self::Error>;
 }
 
-impl<'d, T: uarte::Instance> AsyncRead for UarteRx<'d, T> {
+/// The link between the halves is on UARTE0, which is the only instance whose
+/// line errors can be read, as the driver keeps its registers to itself
+impl<'d> AsyncRead for UarteRx<'d, UARTE0> {
     type Error = uarte::Error;
 
     #[inline]
     async fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> Result<(), Self::Error> {
         UarteRx::read(self, buf).await
     }
+
+    /// The driver doesn't report line errors, the bytes just turn up wrong,
+    /// so they're read from ERRORSRC
+    fn take_line_error(&mut self) -> Option<LinkErrorKind> {
+        // SAFETY: ERRORSRC isn't used by the driver, and this is the only
+        // reader of UARTE0
+        let uarte = unsafe { &*pac::UARTE0::ptr() };
+        let bits = uarte.errorsrc.read().bits();
+        if bits == 0 {
+            return None;
+        }
+        // the bits are cleared by writing ones to them
+        uarte.errorsrc.write(|w| unsafe { w.bits(bits) });
+
+        // a break also shows up as a framing error, so it's checked first
+        const OVERRUN: u32 = 1 << 0;
+        const PARITY: u32 = 1 << 1;
+        const FRAMING: u32 = 1 << 2;
+        const BREAK: u32 = 1 << 3;
+        Some(if bits & BREAK != 0 {
+            LinkErrorKind::Break
+        } else if bits & FRAMING != 0 {
+            LinkErrorKind::Framing
+        } else if bits & PARITY != 0 {
+            LinkErrorKind::Parity
+        } else {
+            debug_assert!(bits & OVERRUN != 0);
+            LinkErrorKind::Overrun
+        })
+    }
 }
 
 impl<'d, T: uarte::Instance> AsyncWrite for UarteTx<'d, T> {
@@ -94,7 +138,7 @@ impl<'a, 'd, D: Driver<'d>, const N: usize> UsbSerialWrapper<'a, 'd, D, N> {
                     }
                 }
 
-                debug!("Sent a serial packet of length {}", v.len());
+                log_sampled!("Sent a serial packet of length {}", v.len());
 
                 v
             };
@@ -104,7 +148,9 @@ impl<'a, 'd, D: Driver<'d>, const N: usize> UsbSerialWrapper<'a, 'd, D, N> {
 
                 let n = self.class.read_packet(&mut v).await?;
 
-                Ok(heapless::Vec::<u8, N>::from_slice(&v[..n]).unwrap())
+                // the class never reports more than the buffer, but a bad
+                // length shouldn't be able to take the keyboard down
+                Ok(v.into_iter().take(n).collect::<heapless::Vec<u8, N>>())
             };
 
             match select(a, b).await {
@@ -2,48 +2,103 @@
 #![no_std]
 #![feature(type_alias_impl_trait)]
 
-use core::sync::atomic::AtomicU32;
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, AtomicU32},
+};
 
 use defmt::debug;
 use embassy_executor::Spawner;
-use embassy_futures::select::select3;
+use embassy_futures::select::{select, select3, select4, Either, Either4};
 use embassy_nrf::{
-    gpio::{AnyPin, Input, Output},
-    interrupt, pac,
+    interrupt,
+    nvmc::Nvmc,
+    pac,
     peripherals::{self, TWISPI0, UARTE0},
     twim::{self, Twim},
     uarte::{self, UarteRx, UarteTx},
     usb::{self, Driver, PowerUsb},
 };
 use embassy_sync::{
-    blocking_mutex::raw::ThreadModeRawMutex,
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
     channel::{Channel, Receiver},
     mutex::Mutex,
 };
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
 use embassy_usb::class::cdc_acm::CdcAcmClass;
 use embassy_usb::class::hid::HidWriter;
 use embassy_usb::UsbDevice;
 use futures::{Future, StreamExt};
-use keyberon::{chording::Chording, debounce::Debouncer, layout::Event, matrix::Matrix};
+use keyberon::layout::{CustomEvent, Event};
 use keyboard_thing::{
     self as _,
+    action_chords::ActionChordMatcher,
     async_rw::UsbSerialWrapper,
-    cps::{cps_task, Cps, SampleBuffer},
-    forever, init_heap,
-    layout::{Layout, COLS_PER_SIDE, ROWS},
-    leds::{rainbow_single, Leds, TapWaves},
-    lhs_display::{
-        self, DisplayOverride, LHSDisplay, AVERAGE_KEYPRESSES, KEYPRESS_EVENT, TOTAL_KEYPRESSES,
+    channel_stats::{self, DropChannel},
+    chord_guard::{self, GuardedChording},
+    clock,
+    consumer::{consumer_report, ConsumerKeys, CONSUMER_REPORT_DESCRIPTOR, CONSUMER_REPORT_LEN},
+    cps::{self, cps_task, Cps, SampleBuffer, SYNC_PERIOD},
+    debounce::{self, KeyDebouncer},
+    display_bus::{self, display_bus_task},
+    display_widgets::{
+        key_grid_shown, next_screen, rejected_pixel_write, run_display, DisplayRole,
+        AVERAGE_KEYPRESSES, KEYPRESS_EVENT, TOTAL_KEYPRESSES,
+    },
+    fast_path::{self, TICK_NOW},
+    flash::AsyncFlash,
+    forever,
+    host_dispatch::{handle_command, DispatchCtx, HostSession, ReplyChannel},
+    host_log::HOST_LOG_CHAN,
+    hostlog, init_heap,
+    jiggle::{self, mouse_report, Jiggler, MOUSE_REPORT_DESCRIPTOR, MOUSE_REPORT_LEN},
+    key_event::{EventSource, KeyEvent},
+    key_grid, keypress_store,
+    layout::{
+        is_peek_key, longest_hold_tap_timeout, CustomEvent as KeyAction, Layers, Layout, CHORDS,
+        CHORD_DEFS, COLS, COLS_PER_SIDE, LAYOUT_ROWS, NUM_ACTION_CHORDS, ROWS,
+    },
+    leds::{
+        flash_key, key_led_overlay, layer_tint, led_mode, locked_pattern, next_led_mode,
+        presence_indicator, render_effect, report_mirror_indicator, safe_mode_pattern,
+        set_calibration, set_status_led, show_self_test_pattern, status_led_overlay, test_colour,
+        test_led, Effects, Leds, LinkStatus, LEFT_LEDS,
+    },
+    link_health::{self, Heartbeat},
+    log_if,
+    matrix::{KeyMatrix, REMOTE_PHANTOM_PRESSES},
+    messages::{
+        self, to_global, CommandQueue, DomToSub, Eventer, HidMode, HostToKeyboard, KeyLocation,
+        KeyboardSide, KeyboardToHost, KeypressStore, LinkGate, LinkHealth, PeerSync, Priority,
+        SendOutcome, SendPolicy, StateKey, StatusReport, SubToDom, TickPacer, HOST_TIMEOUT_MS,
+        RETRANSMITS, STATUS_REPORT_DESCRIPTOR,
+    },
+    oled::{display_timeout_task, interacted, peeked, remote_interacted, remote_peeked, Oled},
+    profiling::busy,
+    quiet_hours::{self, QuietHoursTracker},
+    remap::HeldRemaps,
+    report_mirror, safe_mode,
+    self_test::{self, SelfTestResults},
+    settings::{
+        self, apply_config, Settings, SettingsError, SettingsStore, FACTORY_RESET, SETTINGS_CHANGED,
+    },
+    system_state::{
+        active_layer, set_active_layer, status_report, LockMatcher, SystemState, SYSTEM_STATE,
+    },
+    telemetry::{
+        light_queued, press_queued, record_key_press, record_layer, record_source, report_sent,
+        HoldTapTelemetry, REMOTE_CHORD_FIRES, REMOTE_CHORD_NEAR_MISSES,
     },
-    messages::{DomToSub, Eventer, HostToKeyboard, KeyLocation, KeyboardToHost, SubToDom},
-    oled::{display_timeout_task, interacted, Oled},
+    tuning, version_check,
     wrapping_id::WrappingID,
-    DEBOUNCER_TICKS, POLL_PERIOD, UART_BAUD,
+    POLL_PERIOD, UART_BAUD,
 };
 use num_enum::TryFromPrimitive;
 use packed_struct::PackedStruct;
-use usbd_human_interface_device::{device::keyboard::NKROBootKeyboardReport, page::Keyboard};
+use usbd_human_interface_device::{
+    device::keyboard::{BootKeyboardReport, NKROBootKeyboardReport},
+    page::Keyboard,
+};
 
 static TOTAL_LHS_KEYPRESSES: AtomicU32 = AtomicU32::new(0);
 
@@ -52,14 +107,94 @@ static LED_KEY_LISTEN_CHAN: Channel<ThreadModeRawMutex, Event, 16> = Channel::ne
 static OTHERSIDE_KEY_TRANSMIT_CHAN: Channel<ThreadModeRawMutex, Event, 16> = Channel::new();
 
 /// Channels that receive each debounced key press
-static KEY_EVENT_CHANS: &[&Channel<ThreadModeRawMutex, Event, 16>] =
-    &[&LED_KEY_LISTEN_CHAN, &OTHERSIDE_KEY_TRANSMIT_CHAN];
+static KEY_EVENT_CHANS: &[(&Channel<ThreadModeRawMutex, Event, 16>, DropChannel)] = &[
+    (&LED_KEY_LISTEN_CHAN, DropChannel::LedKeyListen),
+    (&OTHERSIDE_KEY_TRANSMIT_CHAN, DropChannel::KeyTransmit),
+];
+/// The right half's key events while it leaves chording to this half
+static RAW_REMOTE_KEY_CHAN: Channel<ThreadModeRawMutex, Event, 16> = Channel::new();
+/// Keys of the right half pressed and not yet released, as sent chorded and
+/// raw. When the link goes down their releases are given up on, so
+/// `release_remote_keys` lets go of them instead.
+static REMOTE_HELD: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<[u128; 2]>> =
+    blocking_mutex::Mutex::new(Cell::new([0; 2]));
+const _: () = assert!(LAYOUT_ROWS * COLS <= u128::BITS as usize);
 /// Key events that have been chorded or received from the other side
-static PROCESSED_KEY_CHAN: Channel<ThreadModeRawMutex, Event, 16> = Channel::new();
-/// Channel HID events are put on to be sent to the computer
-static HID_CHAN: Channel<ThreadModeRawMutex, NKROBootKeyboardReport, 1> = Channel::new();
-/// Channel commands are put on to be sent to the other side
-static COMMAND_CHAN: Channel<ThreadModeRawMutex, (DomToSub, Duration), 4> = Channel::new();
+static PROCESSED_KEY_CHAN: Channel<ThreadModeRawMutex, KeyEvent, 16> = Channel::new();
+/// Channel HID events are put on to be sent to the computer. The layout
+/// doesn't wait for room, so ticking keeps to time however fast the host
+/// takes reports.
+static HID_CHAN: Channel<ThreadModeRawMutex, HidReport, 4> = Channel::new();
+/// Consumer page usages for the media key interface, likewise
+static CONSUMER_CHAN: Channel<ThreadModeRawMutex, u16, 4> = Channel::new();
+/// Set while the event task holds an event it's yet to apply, so the fast
+/// path doesn't overtake it
+static EVENT_IN_FLIGHT: AtomicBool = AtomicBool::new(false);
+/// Set when events have been handed to the layout since its last tick
+static LAYOUT_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// The report for the `HidMode` the keyboard enumerated with
+enum HidReport {
+    Nkro(NKROBootKeyboardReport),
+    SixKro(BootKeyboardReport),
+}
+
+impl HidReport {
+    fn new(mode: HidMode, keys: &[Keyboard]) -> Self {
+        match mode {
+            HidMode::Nkro => Self::Nkro(NKROBootKeyboardReport::new(keys)),
+            HidMode::SixKro => Self::SixKro(BootKeyboardReport::new(&sent_keys(mode, keys))),
+        }
+    }
+}
+
+/// The keys that make it into a report for `mode`
+fn sent_keys(mode: HidMode, keys: &[Keyboard]) -> heapless::Vec<Keyboard, 24> {
+    match mode {
+        HidMode::Nkro => keys.iter().copied().collect(),
+        HidMode::SixKro => {
+            // like any other keyboard, keys past the sixth are ignored
+            // until one is released. Modifiers have their own bits.
+            let mut normal = 0;
+            keys.iter()
+                .filter(|k| {
+                    is_modifier(**k) || {
+                        normal += 1;
+                        normal <= 6
+                    }
+                })
+                .copied()
+                .collect()
+        }
+    }
+}
+
+fn is_modifier(k: Keyboard) -> bool {
+    (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8).contains(&(k as u8))
+}
+/// Channel commands are put on to be sent to the other side, see
+/// `send_to_peer` for getting them here. Nothing is taken off it until the
+/// link is first up.
+static COMMAND_CHAN: CommandQueue<DomToSub, 4> = CommandQueue::new();
+/// Commands setting the right half's state, the latest of each, see
+/// `set_peer_state`
+static PEER_STATE: blocking_mutex::Mutex<
+    ThreadModeRawMutex,
+    RefCell<LinkGate<StateKey, (DomToSub, SendPolicy), 32>>,
+> = blocking_mutex::Mutex::new(RefCell::new(LinkGate::new()));
+/// Set when `PEER_STATE` has something to send
+static PEER_STATE_CHANGED: keyboard_thing::event::Event = keyboard_thing::event::Event::new();
+/// Commands going out over the link, `Hello` goes straight on here
+static LINK_CHAN: CommandQueue<DomToSub, 4> = CommandQueue::new();
+/// Set once the USB device has been started
+static USB_RUNNING: AtomicBool = AtomicBool::new(false);
+/// Set while the host has the USB device configured, for the status LED
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
+static USB_STATE_HANDLER: UsbStateHandler = UsbStateHandler;
+/// How long to wait for USB power at boot before carrying on without it
+const USB_BOOT_WAIT: Duration = Duration::from_millis(500);
+/// Replies from the other side that should be forwarded to the host
+static HOST_REPLY_CHAN: Channel<ThreadModeRawMutex, KeyboardToHost, 4> = Channel::new();
 
 trait StaticLen {
     const LEN: usize;
@@ -71,21 +206,58 @@ impl<T, const N: usize> StaticLen for [T; N] {
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    // before anything that could be what keeps failing
+    let safe_mode = safe_mode::enter();
+
     let p = embassy_nrf::init(Default::default());
 
     init_heap();
 
-    let clock: pac::CLOCK = unsafe { core::mem::transmute(()) };
-    let power: pac::POWER = unsafe { core::mem::transmute(()) };
+    if safe_mode {
+        debug!("Boot keeps failing, starting in safe mode");
+        hostlog!(Warn, "boot keeps failing, in safe mode");
+    }
 
-    clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
-    while clock.events_hfclkstarted.read().bits() != 1 {}
+    // USB needs the external crystal, without it we carry on as a keyboard
+    // half that just can't talk to a computer
+    let hfxo_started = start_hfxo().await;
+    if !hfxo_started {
+        debug!("HFXO didn't start, USB disabled");
+    }
 
-    while !power.usbregstatus.read().vbusdetect().is_vbus_present() {}
+    let usb_at_boot = hfxo_started && with_timeout(USB_BOOT_WAIT, wait_for_vbus()).await.is_ok();
+    if !usb_at_boot {
+        debug!("No USB power, continuing without it");
+    }
 
     let mut cortex_p = cortex_m::Peripherals::take().unwrap();
     cortex_p.SCB.enable_icache();
 
+    // the HID mode decides the report descriptor, so settings have to be
+    // loaded before USB is set up
+    let mut settings_store = SettingsStore::new(Nvmc::new(p.NVMC));
+    let saved_settings = if safe_mode {
+        // a saved setting could be what keeps failing
+        Settings::default()
+    } else {
+        match settings_store.load() {
+            Ok(s) => {
+                settings::replace(s.clone());
+                s
+            }
+            Err(e) => {
+                debug!("Using default settings: {}", e);
+                Settings::default()
+            }
+        }
+    };
+    let hid_mode = saved_settings.hid_mode;
+
+    let mut keypress_store = keypress_store::store();
+    let restored_keypresses = keypress_store.load(settings_store.flash());
+    keypress_store::set_restored(restored_keypresses);
+    TOTAL_KEYPRESSES.store(restored_keypresses, core::sync::atomic::Ordering::Relaxed);
+
     let irq = interrupt::take!(USBD);
     let power_irq = interrupt::take!(POWER_CLOCK);
     let usb_driver = usb::Driver::new(p.USBD, irq, PowerUsb::new(power_irq));
@@ -111,6 +283,9 @@ async fn main(spawner: Spawner) {
         control_buf: [u8; 128],
         serial_state: embassy_usb::class::cdc_acm::State<'static>,
         usb_state: embassy_usb::class::hid::State<'static>,
+        status_state: embassy_usb::class::hid::State<'static>,
+        mouse_state: embassy_usb::class::hid::State<'static>,
+        consumer_state: embassy_usb::class::hid::State<'static>,
     }
 
     let res: &mut Resources = forever!(Resources {
@@ -120,6 +295,9 @@ async fn main(spawner: Spawner) {
         control_buf: [0; 128],
         serial_state: embassy_usb::class::cdc_acm::State::new(),
         usb_state: embassy_usb::class::hid::State::new(),
+        status_state: embassy_usb::class::hid::State::new(),
+        mouse_state: embassy_usb::class::hid::State::new(),
+        consumer_state: embassy_usb::class::hid::State::new(),
     });
 
     let mut builder = embassy_usb::Builder::new(
@@ -129,14 +307,20 @@ async fn main(spawner: Spawner) {
         &mut res.config_descriptor,
         &mut res.bos_descriptor,
         &mut res.control_buf,
-        None,
+        Some(&USB_STATE_HANDLER),
     );
 
     let serial_class = CdcAcmClass::new(&mut builder, &mut res.serial_state, 64);
 
     let hid_config = embassy_usb::class::hid::Config {
-        report_descriptor:
-            usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
+        report_descriptor: match hid_mode {
+            HidMode::Nkro => {
+                usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR
+            }
+            HidMode::SixKro => {
+                usbd_human_interface_device::device::keyboard::BOOT_KEYBOARD_REPORT_DESCRIPTOR
+            }
+        },
         request_handler: None,
         poll_ms: 1,
         max_packet_size: 64,
@@ -147,21 +331,52 @@ async fn main(spawner: Spawner) {
         hid_config,
     );
 
+    let status_config = embassy_usb::class::hid::Config {
+        report_descriptor: STATUS_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: 8,
+    };
+    let status_hid = HidWriter::<_, { StatusReport::LEN }>::new(
+        &mut builder,
+        &mut res.status_state,
+        status_config,
+    );
+
+    let mouse_config = embassy_usb::class::hid::Config {
+        report_descriptor: MOUSE_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: 8,
+    };
+    let mouse_hid =
+        HidWriter::<_, MOUSE_REPORT_LEN>::new(&mut builder, &mut res.mouse_state, mouse_config);
+
+    let consumer_config = embassy_usb::class::hid::Config {
+        report_descriptor: CONSUMER_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: 8,
+    };
+    let consumer_hid = HidWriter::<_, CONSUMER_REPORT_LEN>::new(
+        &mut builder,
+        &mut res.consumer_state,
+        consumer_config,
+    );
+
+    // Building the device doesn't touch the hardware, `usb_task` only starts
+    // it once VBUS appears
     let usb = builder.build();
 
     debug!("hello");
 
-    let leds = Leds::new(p.PWM0, p.P0_06);
+    let mut leds = Leds::new(p.PWM0, p.P0_06, &LEFT_LEDS);
 
-    let matrix = keyboard_thing::build_matrix!(p);
-    let debouncer = Debouncer::new(
-        [[false; COLS_PER_SIDE]; ROWS],
-        [[false; COLS_PER_SIDE]; ROWS],
-        DEBOUNCER_TICKS,
-    );
-    let chording = Chording::new(&keyboard_thing::layout::CHORDS);
+    let mut matrix = keyboard_thing::build_matrix!(p);
+    let debouncer = KeyDebouncer::new(|x, y| to_global(KeyboardSide::Left, x, y));
+    let chording = GuardedChording::new(&CHORDS, &CHORD_DEFS);
 
-    let layout = forever!(Mutex::new(Layout::new(&keyboard_thing::layout::LAYERS)));
+    let keys = forever!(Mutex::new(Keys::new()));
 
     let mut uart_config = uarte::Config::default();
     uart_config.parity = uarte::Parity::EXCLUDED;
@@ -185,52 +400,112 @@ async fn main(spawner: Spawner) {
         UarteTx<'static, UARTE0>,
         UarteRx<'static, UARTE0>,
     >::new_uart(uart, SUB_TO_DOM_CHAN.sender()));
-    let (e_a, e_b, e_c) = eventer.split_tasks(&COMMAND_CHAN);
+    let (e_a, e_b, e_c) = eventer.split_tasks(&LINK_CHAN);
 
     let irq = interrupt::take!(SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
     let mut config = twim::Config::default();
-    config.frequency = unsafe { core::mem::transmute(159715200) };
+    config.frequency = twim::Frequency::K400;
     config.scl_high_drive = true;
     config.sda_high_drive = true;
     let twim = Twim::new(p.TWISPI0, irq, p.P0_17, p.P0_20, config);
+    if !safe_mode {
+        display_bus::overdrive(display_bus::LEFT_BUS_KHZ);
+    }
     let oled = forever!(Mutex::new(Oled::new(twim)));
 
-    let cps_samples = forever!(Mutex::new(SampleBuffer::default()));
+    let cps_samples = forever!(Mutex::new(cps::sample_buffer()));
     let cps = Cps::new(&TOTAL_KEYPRESSES, &AVERAGE_KEYPRESSES, cps_samples);
 
-    spawner.spawn(cps_task(cps)).unwrap();
-    spawner.spawn(usb_task(usb)).unwrap();
-    spawner.spawn(usb_serial_task(serial_class)).unwrap();
-    spawner.spawn(hid_task(hid)).unwrap();
+    let mut results = SelfTestResults {
+        // safe mode leaves the display alone, so it can't pass
+        oled: !safe_mode && self_test::check_oled(oled).await,
+        link: false,
+        leds: leds.self_test(),
+        matrix: self_test::check_matrix(&mut matrix),
+    };
+    self_test::record(results);
 
-    spawner.spawn(oled_task(oled)).unwrap();
-    spawner.spawn(oled_timeout_task(oled)).unwrap();
-    spawner.spawn(otherside_key_transmit_task()).unwrap();
-    spawner.spawn(led_task(leds)).unwrap();
-    spawner
-        .spawn(keyboard_poll_task(matrix, debouncer, chording))
-        .unwrap();
-    spawner.spawn(keyboard_event_task(layout)).unwrap();
-    spawner.spawn(layout_task(layout)).unwrap();
+    // the link check needs the eventer running
     spawner
         .spawn(read_events_task(SUB_TO_DOM_CHAN.receiver()))
         .unwrap();
     spawner.spawn(eventer_a(e_a)).unwrap();
     spawner.spawn(eventer_b(e_b)).unwrap();
     spawner.spawn(eventer_c(e_c)).unwrap();
-    spawner.spawn(sync_kp_task()).unwrap();
+    spawner.spawn(link_gate_task()).unwrap();
+
+    LINK_CHAN
+        .send((
+            DomToSub::Hello(version_check::ours()),
+            SendPolicy::KEY_EVENT,
+        ))
+        .await;
+    results.link = with_timeout(self_test::LINK_TIMEOUT, self_test::PEER_HELLO.wait())
+        .await
+        .is_ok();
+    self_test::record(results);
+    debug!("self test: {}", results);
+
+    spawner.spawn(cps_task(cps)).unwrap();
+    if hfxo_started {
+        spawner.spawn(usb_task(usb)).unwrap();
+    }
+    spawner.spawn(usb_serial_task(serial_class, oled)).unwrap();
+    spawner.spawn(hid_task(hid)).unwrap();
+    spawner.spawn(status_report_task(status_hid)).unwrap();
+    spawner.spawn(mouse_jiggle_task(mouse_hid)).unwrap();
+    spawner.spawn(consumer_task(consumer_hid)).unwrap();
+
+    if safe_mode {
+        spawner.spawn(safe_mode_led_task(leds)).unwrap();
+    } else {
+        spawner
+            .spawn(oled_task(oled, cps_samples, usb_at_boot))
+            .unwrap();
+        spawner.spawn(oled_timeout_task(oled)).unwrap();
+        spawner.spawn(led_task(leds)).unwrap();
+    }
+    spawner.spawn(otherside_key_transmit_task()).unwrap();
+    spawner
+        .spawn(keyboard_poll_task(matrix, debouncer, chording, keys))
+        .unwrap();
+    spawner.spawn(keyboard_event_task(keys)).unwrap();
+    spawner.spawn(layout_tick_task(keys, hid_mode)).unwrap();
+    spawner.spawn(sync_kp_task(restored_keypresses)).unwrap();
+    spawner.spawn(link_health_task()).unwrap();
+    spawner.spawn(heartbeat_task()).unwrap();
+    spawner.spawn(link_resync_task()).unwrap();
+    spawner.spawn(quiet_hours_task()).unwrap();
+    spawner.spawn(key_grid_sync_task()).unwrap();
+    spawner.spawn(version_check_task()).unwrap();
+    #[cfg(feature = "inject-keys")]
+    spawner.spawn(inject_task()).unwrap();
+    spawner
+        .spawn(settings_task(
+            settings_store,
+            keypress_store,
+            saved_settings,
+        ))
+        .unwrap();
+    spawner.spawn(display_bus_task()).unwrap();
+    spawner.spawn(boot_ok_task()).unwrap();
+    #[cfg(feature = "profiling")]
+    spawner
+        .spawn(keyboard_thing::profiling::profiling_task())
+        .unwrap();
 }
 
 #[embassy_executor::task]
-async fn oled_task(oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>) {
-    Timer::after(Duration::from_millis(100)).await;
-    {
-        let _ = oled.lock().await.init().await;
-    }
+async fn oled_task(
+    oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    cps_samples: &'static Mutex<ThreadModeRawMutex, SampleBuffer>,
+    usb_at_boot: bool,
+) {
+    let note = if usb_at_boot { None } else { Some("no USB") };
+    self_test::show_summary(oled, note).await;
     debug!("oled starting up");
 
-    let mut display = LHSDisplay::new(oled);
-    display.run().await;
+    run_display(oled, cps_samples, DisplayRole::Bongo).await;
 }
 
 #[embassy_executor::task]
@@ -238,31 +513,252 @@ async fn oled_timeout_task(oled: &'static Mutex<ThreadModeRawMutex, Oled<'static
     display_timeout_task(oled).await;
 }
 
+/// Hold commands back until the right half has answered `Hello`, each lane
+/// is passed on by itself so key events don't wait behind bulk commands
 #[embassy_executor::task]
-async fn sync_kp_task() {
-    Timer::after(Duration::from_millis(1000)).await;
-    let mut ticker = Ticker::every(Duration::from_millis(100));
-    let mut last = 0u32;
+async fn link_gate_task() {
+    link_health::wait_up().await;
+
+    select3(
+        forward_lane(Priority::High),
+        forward_lane(Priority::Low),
+        forward_state(),
+    )
+    .await;
+}
 
+/// Send what's changed in `PEER_STATE` while the link is up, and all of it
+/// each time the link comes up
+async fn forward_state() -> ! {
     loop {
-        let current = TOTAL_LHS_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
-        let diff = current - last;
+        while let Some(cmd) = PEER_STATE.lock(|s| s.borrow_mut().next(link_health::up())) {
+            LINK_CHAN.send(cmd).await;
+        }
+        PEER_STATE_CHANGED.wait().await;
+    }
+}
+
+/// Set a piece of the right half's state, see `DomToSub::state`. This never
+/// waits, only the latest of each is kept until the link is up to send it.
+fn set_peer_state(cmd: (DomToSub, SendPolicy)) {
+    let key = cmd.0.state();
+    defmt::debug_assert!(key.is_some(), "not state: {}", cmd.0);
+    PEER_STATE.lock(|s| s.borrow_mut().offer(link_health::up(), key, cmd));
+    PEER_STATE_CHANGED.set();
+}
+
+/// Queue a command for the right half, or drop it if the link isn't up, as
+/// nobody is listening to act on it
+async fn send_to_peer(cmd: (DomToSub, SendPolicy)) {
+    if let Some(cmd) = PEER_STATE.lock(|s| s.borrow_mut().offer(link_health::up(), None, cmd)) {
+        COMMAND_CHAN.send(cmd).await;
+    }
+}
+
+/// The link to the right half came up, it's sent its whole state again
+fn peer_link_up() {
+    PEER_STATE.lock(|s| s.borrow_mut().link_up());
+    PEER_STATE_CHANGED.set();
+}
 
-        if diff != 0 {
-            COMMAND_CHAN
-                .send((
-                    DomToSub::SyncKeypresses(diff as u16),
-                    Duration::from_millis(5),
-                ))
-                .await;
+async fn forward_lane(priority: Priority) -> ! {
+    loop {
+        let cmd = COMMAND_CHAN.lane(priority).recv().await;
+        LINK_CHAN.lane(priority).send(cmd).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn sync_kp_task(restored: u32) {
+    static SYNCED: SendOutcome = SendOutcome::new();
+
+    let mut ticker = Ticker::every(SYNC_PERIOD);
+    let mut sync = PeerSync::new();
+    let mut restored = Some(restored).filter(|&kp| kp != 0);
+
+    loop {
+        if !link_health::up() {
+            ticker.next().await;
+            continue;
+        }
+
+        if let Some(kp) = restored.take() {
+            set_peer_state((DomToSub::RestoredKeypresses(kp), SendPolicy::KEY_EVENT));
         }
 
-        last = current;
+        let current = TOTAL_LHS_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
+
+        // a failed sync is tried again next time round even if nothing's
+        // been pressed since, so one go at it is enough
+        if let Some(kp) = sync.keypresses(true, current) {
+            let cmd = (
+                DomToSub::SyncKeypresses(kp),
+                SendPolicy::BACKGROUND.with_retries(0),
+            );
+            if COMMAND_CHAN.send_acked(cmd, &SYNCED).await.is_ok() {
+                sync.keypresses_synced(kp);
+            }
+        }
 
         ticker.next().await;
     }
 }
 
+/// How long settings have to stay unchanged before they're written to flash
+const SETTINGS_SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[embassy_executor::task]
+async fn settings_task(
+    mut store: SettingsStore<Nvmc<'static>>,
+    mut keypresses: KeypressStore,
+    mut saved: Settings,
+) {
+    let hid_mode = saved.hid_mode;
+    // kept across settings changes, which would otherwise keep putting the
+    // periodic save off
+    let mut next_save = Instant::now() + keypress_store::SAVE_PERIOD;
+
+    loop {
+        match select4(
+            SETTINGS_CHANGED.wait(),
+            FACTORY_RESET.wait(),
+            keypress_store::SAVE_NOW.wait(),
+            Timer::at(next_save),
+        )
+        .await
+        {
+            Either4::First(()) => {}
+            Either4::Second(()) => factory_reset(&mut store).await,
+            Either4::Third(()) => {
+                save_keypresses(&mut store, &mut keypresses, true).await;
+                next_save = Instant::now() + keypress_store::SAVE_PERIOD;
+                continue;
+            }
+            Either4::Fourth(()) => {
+                save_keypresses(&mut store, &mut keypresses, false).await;
+                next_save = Instant::now() + keypress_store::SAVE_PERIOD;
+                continue;
+            }
+        }
+        apply_settings(&settings::get());
+
+        while with_timeout(SETTINGS_SAVE_DELAY, SETTINGS_CHANGED.wait())
+            .await
+            .is_ok()
+        {
+            apply_settings(&settings::get());
+        }
+
+        let current = settings::get();
+        // the saved settings were never loaded, so they'd be lost
+        if safe_mode::active() {
+            debug!("Not saving settings in safe mode");
+        } else if current != saved {
+            match save_settings(&mut store, &current).await {
+                Ok(()) => saved = current,
+                Err(e) => {
+                    debug!("Failed to save settings: {}", e);
+                    hostlog!(Error, "failed to save settings");
+                }
+            }
+
+            if saved.hid_mode != hid_mode {
+                debug!("HID mode changed, resetting to enumerate again");
+                safe_mode::clear();
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+    }
+}
+
+/// Write the keypress count to flash, unless `now` isn't set and too few
+/// presses have been made since it was last written
+async fn save_keypresses(
+    store: &mut SettingsStore<Nvmc<'static>>,
+    keypresses: &mut KeypressStore,
+    now: bool,
+) {
+    let count = TOTAL_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
+    if !now && !keypresses.due(count) {
+        return;
+    }
+
+    if let Some((from, to)) = keypresses.erase_needed(count) {
+        if let Err(e) = store.flash().erase_async(from, to).await {
+            debug!("Failed to erase the keypress count: {}", e);
+            hostlog!(Error, "failed to save the keypress count");
+            return;
+        }
+        keypresses.erased();
+    }
+
+    if let Err(e) = keypresses.save(store.flash(), count) {
+        debug!("Failed to save the keypress count: {}", e);
+        hostlog!(Error, "failed to save the keypress count");
+    }
+}
+
+/// Erase the page the settings go to without holding up the other tasks,
+/// then write them
+async fn save_settings(
+    store: &mut SettingsStore<Nvmc<'static>>,
+    settings: &Settings,
+) -> Result<(), SettingsError> {
+    if let Some((from, to)) = store.erase_needed() {
+        store
+            .flash()
+            .erase_async(from, to)
+            .await
+            .map_err(|_| SettingsError::Invalid)?;
+        store.erased();
+    }
+
+    store.save(settings)
+}
+
+/// Save the default settings and restart, out of safe mode if it's in it
+async fn factory_reset(store: &mut SettingsStore<Nvmc<'static>>) {
+    // the save has to land after the newest page for it to be loaded
+    store.follow_newest();
+    if let Err(e) = save_settings(store, &Settings::default()).await {
+        debug!("Failed to reset settings: {}", e);
+        hostlog!(Error, "failed to reset settings");
+        return;
+    }
+
+    debug!("Settings reset, restarting");
+    safe_mode::clear();
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+fn apply_settings(settings: &Settings) {
+    set_peer_state((
+        DomToSub::ShowDebugScreen(settings.debug_screen),
+        SendPolicy::KEY_EVENT,
+    ));
+
+    set_calibration(settings.led_calibration[KeyboardSide::Left as usize]);
+    set_peer_state((
+        DomToSub::SetLedCalibration(settings.led_calibration[KeyboardSide::Right as usize]),
+        SendPolicy::KEY_EVENT,
+    ));
+
+    // only this half keeps the time, has the mouse and knows the USB state,
+    // so these stay here
+    quiet_hours::set_schedule(settings.quiet_hours);
+    jiggle::set_max_minutes(settings.jiggle_max_minutes);
+    set_status_led(settings.status_led);
+
+    if tuning::load_saved(settings.tuning) {
+        set_peer_state((DomToSub::SetTuning(settings.tuning), SendPolicy::KEY_EVENT));
+    }
+
+    for item in settings.config_items() {
+        apply_config(item, KeyboardSide::Left);
+        set_peer_state((DomToSub::SetConfig(item), SendPolicy::KEY_EVENT));
+    }
+}
+
 type EventerA = impl Future + 'static;
 
 #[embassy_executor::task]
@@ -288,147 +784,875 @@ async fn eventer_c(f: EventerC) {
 async fn read_events_task(events_in: Receiver<'static, ThreadModeRawMutex, SubToDom, 16>) {
     loop {
         let event = events_in.recv().await;
-        if let Some(event) = event.as_keyberon_event() {
-            // events from the other side are already debounced and chord-resolved
-            PROCESSED_KEY_CHAN.send(event).await;
-
-            if event.is_press() {
-                let (x, y) = event.coord();
-                OTHERSIDE_LED_KEY_LISTEN_CHAN
-                    .send(KeyLocation::pack(x, y))
+        match event {
+            SubToDom::ChordStats {
+                chord,
+                fires,
+                near_misses,
+            } => {
+                if let (Some(f), Some(n)) = (
+                    REMOTE_CHORD_FIRES.get(chord as usize),
+                    REMOTE_CHORD_NEAR_MISSES.get(chord as usize),
+                ) {
+                    f.store(fires, core::sync::atomic::Ordering::Relaxed);
+                    n.store(near_misses, core::sync::atomic::Ordering::Relaxed);
+                }
+            }
+            SubToDom::PhantomPresses(presses) => {
+                REMOTE_PHANTOM_PRESSES.store(presses, core::sync::atomic::Ordering::Relaxed);
+            }
+            SubToDom::Debounce {
+                row,
+                col,
+                scans,
+                chatters,
+            } => debounce::publish(row, col, scans, chatters),
+            SubToDom::Hello(results, version) => {
+                self_test::record_peer(results);
+                version_check::peer_hello(version);
+                if link_health::link_up() {
+                    debug!("Link up");
+                    hostlog!(Info, "link to the right half up");
+                }
+                // the right half may have restarted and lost its state
+                peer_link_up();
+                // the time has moved on since it was last sent
+                if let Some(seconds_of_day) = clock::seconds_of_day() {
+                    set_peer_state((DomToSub::SetTime { seconds_of_day }, SendPolicy::BACKGROUND));
+                }
+                // and the count from flash, it only adds what it hasn't seen
+                let restored = keypress_store::restored();
+                if restored != 0 {
+                    set_peer_state((
+                        DomToSub::RestoredKeypresses(restored),
+                        SendPolicy::KEY_EVENT,
+                    ));
+                }
+            }
+            SubToDom::Version(version) => version_check::record_peer(version),
+            SubToDom::Pong => {
+                if link_health::heard() {
+                    hostlog!(Info, "link to the right half back up");
+                    peer_link_up();
+                }
+            }
+            SubToDom::EnteringBootloader => {
+                // only drained while the host is connected, and the right
+                // half is on its way down either way
+                channel_stats::try_send(
+                    &HOST_REPLY_CHAN,
+                    KeyboardToHost::EnteringBootloader {
+                        side: KeyboardSide::Right,
+                    },
+                    DropChannel::LinkStatus,
+                );
+            }
+            SubToDom::InjectedKey { key, pressed } => {
+                let (x, y) = key.unpack();
+                let event = if pressed {
+                    Event::Press(x, y)
+                } else {
+                    Event::Release(x, y)
+                };
+                PROCESSED_KEY_CHAN
+                    .send(KeyEvent::new(event, EventSource::Synthetic))
                     .await;
             }
+            SubToDom::PixelRow {
+                row,
+                data_0,
+                data_1,
+            } => {
+                // don't hold up key events if nobody is listening on the host side
+                channel_stats::try_send(
+                    &HOST_REPLY_CHAN,
+                    KeyboardToHost::PixelRow {
+                        row,
+                        data_0,
+                        data_1,
+                    },
+                    DropChannel::PixelRow,
+                );
+            }
+            SubToDom::PixelsRejected { row } => {
+                rejected_pixel_write(row);
+                channel_stats::try_send(
+                    &HOST_REPLY_CHAN,
+                    KeyboardToHost::PixelsRejected {
+                        side: KeyboardSide::Right,
+                        row,
+                    },
+                    DropChannel::PixelRow,
+                );
+            }
+            SubToDom::FrameNack { frame_id } => {
+                channel_stats::try_send(
+                    &HOST_REPLY_CHAN,
+                    KeyboardToHost::FrameNack {
+                        side: KeyboardSide::Right,
+                        frame_id,
+                    },
+                    DropChannel::PixelRow,
+                );
+            }
+            SubToDom::Stats { keypresses } => {
+                let cps = AVERAGE_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
+                channel_stats::try_send(
+                    &HOST_REPLY_CHAN,
+                    KeyboardToHost::StatsV2 {
+                        keypresses: TOTAL_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed),
+                        left_keypresses: TOTAL_LHS_KEYPRESSES
+                            .load(core::sync::atomic::Ordering::Relaxed),
+                        right_keypresses: keypresses,
+                        uptime_secs: Instant::now().as_secs() as u32,
+                        cps_hundredths: (cps * 100.0) as u16,
+                    },
+                    DropChannel::Stats,
+                );
+            }
+            msg @ SubToDom::KeyEvents { .. } => {
+                for packed in msg.packed_key_events() {
+                    let event = messages::unpack_event(*packed);
+                    note_remote_held(&event, false);
+                    remote_key_event(event).await;
+                }
+            }
+            msg @ SubToDom::RawKeyEvents { .. } => {
+                for packed in msg.packed_key_events() {
+                    let event = messages::unpack_event(*packed);
+                    note_remote_held(&event, true);
+                    raw_remote_key_event(event).await;
+                }
+            }
+            event => {
+                if let Some(event) = messages::as_keyberon_event(&event) {
+                    note_remote_held(&event, false);
+                    remote_key_event(event).await;
+                }
+            }
         }
     }
 }
 
+fn note_remote_held(event: &Event, raw: bool) {
+    let (x, y) = event.coord();
+    let bit = 1 << (x as usize * COLS + y as usize);
+
+    REMOTE_HELD.lock(|held| {
+        let mut keys = held.get();
+        if event.is_press() {
+            keys[raw as usize] |= bit;
+        } else {
+            keys[raw as usize] &= !bit;
+        }
+        held.set(keys);
+    });
+}
+
+/// Release the right half's keys still held, the link went down so their
+/// own releases won't arrive
+async fn release_remote_keys() {
+    let held = REMOTE_HELD.lock(|held| held.replace([0; 2]));
+
+    for (raw, keys) in held.into_iter().enumerate() {
+        for idx in (0..u128::BITS as usize).filter(|idx| keys & (1 << idx) != 0) {
+            let event = Event::Release((idx / COLS) as u8, (idx % COLS) as u8);
+            if raw == 1 {
+                raw_remote_key_event(event).await;
+            } else {
+                remote_key_event(event).await;
+            }
+        }
+    }
+}
+
+async fn raw_remote_key_event(event: Event) {
+    // chorded along with this half's keys by the poll task
+    RAW_REMOTE_KEY_CHAN.send(event).await;
+    remote_key_seen(&event).await;
+}
+
+async fn remote_key_event(event: Event) {
+    // events from the other side are already debounced and chord-resolved
+    PROCESSED_KEY_CHAN.send(KeyEvent::remote(event)).await;
+    remote_key_seen(&event).await;
+}
+
+/// Show a key event from the other side on this half, however it gets to
+/// the layout
+async fn remote_key_seen(event: &Event) {
+    key_grid::key_event(event);
+
+    if event.is_press() {
+        let (x, y) = event.coord();
+        OTHERSIDE_LED_KEY_LISTEN_CHAN
+            .send(KeyLocation::pack(x, y))
+            .await;
+    }
+}
+
 #[embassy_executor::task]
-async fn layout_task(layout: &'static Mutex<ThreadModeRawMutex, Layout>) {
+async fn layout_tick_task(keys: &'static Mutex<ThreadModeRawMutex, Keys>, hid_mode: HidMode) {
+    let mut layers: &'static Layers = &keyboard_thing::layout::LAYERS;
+    // keyberon applies events on the tick after they arrive, and a hold-tap
+    // can resolve on any tick up to its timeout after that
+    let mut settle_ticks = longest_hold_tap_timeout(layers) + 2;
+    let mut settling = 0;
     let mut last_report = None;
+    let mut consumer = ConsumerKeys::new();
+    let mut last_consumer = 0;
+    let mut last_state = SystemState::Normal;
+    let mut pacer = TickPacer::new(Instant::now().as_micros(), 1000);
     loop {
+        let state = SYSTEM_STATE.get();
+
+        let retuned = tuning::take_hold_taps_changed();
+        if retuned {
+            // SAFETY: only this task retunes, and the layout is moved onto
+            // the new layers below, so nothing is left using the ones that
+            // get overwritten next time
+            layers = unsafe { tuning::retuned_layers() };
+            settle_ticks = longest_hold_tap_timeout(layers) + 2;
+        }
+
+        if LAYOUT_CHANGED.swap(false, core::sync::atomic::Ordering::Relaxed)
+            || state != last_state
+            || retuned
         {
-            let mut layout = layout.lock().await;
-            layout.tick();
+            settling = settle_ticks;
+        }
+
+        let layer = {
+            let mut keys = keys.lock().await;
+            if retuned {
+                keys.telemetry.use_layers(layers);
+            }
+            let keys = &mut *keys;
+            let layout = &mut keys.layout;
+
+            if state != last_state || retuned {
+                // start from a clean slate so nothing stays held across the
+                // lock or onto the new timings
+                *layout = Layout::new(layers);
+                keys.remapped.clear();
+                consumer.clear();
+            }
+
+            let _busy = busy();
+            let event = layout.tick();
+            match event {
+                CustomEvent::Press(KeyAction::Consumer(action)) => consumer.press(*action),
+                CustomEvent::Release(KeyAction::Consumer(action)) => consumer.release(*action),
+                CustomEvent::Press(action) => run_action(*action),
+                _ => {}
+            }
+            if !matches!(event, CustomEvent::NoEvent) {
+                settling = settle_ticks;
+            }
 
-            let collect = layout
-                .keycodes()
-                .filter_map(|k| Keyboard::try_from_primitive(k as u8).ok())
-                .collect::<heapless::Vec<_, 24>>();
+            // media keys are diffed on their own, a full channel is retried
+            // next tick as the usage still differs
+            let usage = if state == SystemState::Locked {
+                0
+            } else {
+                consumer.usage()
+            };
+            if usage != last_consumer && CONSUMER_CHAN.try_send(usage).is_ok() {
+                last_consumer = usage;
+            }
 
-            if last_report.as_ref() != Some(&collect) {
-                last_report = Some(collect.clone());
-                HID_CHAN.send(NKROBootKeyboardReport::new(&collect)).await;
+            // the keycodes can't have changed once the layout has settled,
+            // so there's no need to collect them every tick
+            if settling > 0 {
+                settling -= 1;
+
+                let collect = if state == SystemState::Locked {
+                    heapless::Vec::new()
+                } else {
+                    layout
+                        .keycodes()
+                        .map(|k| k as u8)
+                        .chain(keys.remapped.keycodes())
+                        .filter_map(|k| Keyboard::try_from_primitive(k).ok())
+                        .collect::<heapless::Vec<_, 24>>()
+                };
+
+                if last_report.as_ref() != Some(&collect) {
+                    if HID_CHAN
+                        .try_send(HidReport::new(hid_mode, &collect))
+                        .is_ok()
+                    {
+                        if report_mirror::active() {
+                            channel_stats::try_send(
+                                &HOST_REPLY_CHAN,
+                                report_mirror::report(&sent_keys(hid_mode, &collect)),
+                                DropChannel::Report,
+                            );
+                        }
+                        last_report = Some(collect);
+                        report_sent();
+                    } else {
+                        // the host is behind, keep ticking and offer
+                        // whatever the keycodes are by then next time
+                        settling = settling.max(1);
+                    }
+                }
             }
+
+            layout.current_layer() as u8
+        };
+
+        if set_active_layer(layer) {
+            record_layer(layer);
+            set_peer_state((DomToSub::SetLayer(layer), SendPolicy::KEY_EVENT));
+        }
+
+        if state != last_state {
+            last_state = state;
+            set_peer_state((
+                DomToSub::SetLocked(state == SystemState::Locked),
+                SendPolicy::KEY_EVENT,
+            ));
         }
 
-        Timer::after(Duration::from_millis(1)).await;
+        // an event from the fast path shouldn't wait for the next tick, but
+        // the tick it gets instead can't leave hold-taps timing out early
+        pacer.ticked(Instant::now().as_micros());
+        let due = Instant::from_micros(pacer.due());
+        if let Either::Second(()) = select(Timer::at(due), TICK_NOW.wait()).await {
+            if !pacer.early_ok(Instant::now().as_micros()) {
+                Timer::at(due).await;
+            }
+        }
     }
 }
 
-#[embassy_executor::task]
-async fn keyboard_event_task(layout: &'static Mutex<ThreadModeRawMutex, Layout>) {
-    loop {
-        let event = PROCESSED_KEY_CHAN.recv().await;
-        let mut count = if event.is_press() { 1 } else { 0 };
+/// The layout and everything key events go through on their way into it.
+///
+/// The event task and the poll task's fast path both apply events with this
+/// locked, so each event is applied once and in the order it arrived.
+struct Keys {
+    layout: Layout,
+    telemetry: HoldTapTelemetry,
+    lock_matcher: LockMatcher,
+    action_chords: ActionChordMatcher<NUM_ACTION_CHORDS>,
+    remapped: HeldRemaps,
+}
+
+impl Keys {
+    fn new() -> Self {
+        Self {
+            layout: Layout::new(&keyboard_thing::layout::LAYERS),
+            telemetry: HoldTapTelemetry::new(&keyboard_thing::layout::LAYERS),
+            lock_matcher: LockMatcher::new(),
+            action_chords: ActionChordMatcher::new(&keyboard_thing::layout::ACTION_CHORDS),
+            remapped: HeldRemaps::new(),
+        }
+    }
+
+    /// When `poll` next has something to do
+    fn deadline(&self) -> Option<Instant> {
+        let action_chords = self.action_chords.deadline().map(Instant::from_millis);
+        [self.lock_matcher.deadline(), action_chords]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn poll(&mut self, now: Instant) {
+        if self.lock_matcher.poll(now) {
+            SYSTEM_STATE.set_locked(!SYSTEM_STATE.is_locked());
+        }
+
+        // presses held back for an action chord that never finished
+        let expired = self.action_chords.poll(now.as_millis());
+        if !expired.is_empty() {
+            for event in expired {
+                self.process(event);
+            }
+            LAYOUT_CHANGED.store(true, core::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn event(&mut self, event: KeyEvent) {
+        let now = Instant::now();
+        self.lock_matcher.event(event.event, now);
         if event.is_press() {
             KEYPRESS_EVENT.set();
         }
-        interacted();
-        {
-            let mut layout = layout.lock().await;
-            layout.event(event);
-            debug!("evt: press: {} {:?}", event.is_press(), event.coord());
-            while let Ok(event) = PROCESSED_KEY_CHAN.try_recv() {
-                debug!("evt: press: {} {:?}", event.is_press(), event.coord());
-                layout.event(event);
-                count += if event.is_press() { 1 } else { 0 };
+        let peek = is_peek_key(
+            &keyboard_thing::layout::LAYERS,
+            active_layer(),
+            event.coord(),
+        );
+        match (event.source == EventSource::Remote, peek) {
+            (true, true) => remote_peeked(),
+            (true, false) => remote_interacted(),
+            (false, true) => peeked(),
+            (false, false) => interacted(),
+        }
+        if event.source != EventSource::Synthetic {
+            jiggle::input();
+        }
+
+        if SYSTEM_STATE.is_locked() {
+            // the layout is bypassed entirely, only the lock combo is looked at
+            self.action_chords.reset();
+            return;
+        }
+
+        // presses are counted even when an action chord swallows them
+        if event.is_press() && !event.is_synthetic() {
+            TOTAL_KEYPRESSES.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        }
+
+        // keys that don't type on their own, like modifiers, aren't held back
+        let (action, passed) = self.action_chords.event(event, now.as_millis(), !peek);
+        if let Some(action) = action {
+            run_action(action);
+        }
+        for event in passed {
+            self.process(event);
+        }
+        LAYOUT_CHANGED.store(true, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Hand an event to the layout, or keep it if it's for a remapped key
+    fn process(&mut self, event: KeyEvent) {
+        log_if!(
+            Sampled,
+            "evt: press: {} {:?} from {}",
+            event.is_press(),
+            event.coord(),
+            event.source
+        );
+        if event.is_press() {
+            record_source(event.source);
+            press_queued(event.at, event.is_synthetic());
+        }
+        let remapped = self.remapped.event(event.event, active_layer());
+        if !remapped {
+            self.layout.event(event.event);
+        }
+        if !event.is_synthetic() {
+            if event.is_press() {
+                record_key_press(event.coord());
             }
+            // a remapped key isn't whatever hold-tap the layers have there
+            if !remapped {
+                self.telemetry.event(event.event, Instant::now());
+            }
+        }
+    }
+
+    /// Apply whatever has been queued up
+    fn drain(&mut self) {
+        while let Ok(event) = PROCESSED_KEY_CHAN.try_recv() {
+            self.event(event);
         }
-        TOTAL_KEYPRESSES.fetch_add(count, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[embassy_executor::task]
+async fn keyboard_event_task(keys: &'static Mutex<ThreadModeRawMutex, Keys>) {
+    loop {
+        let deadline = {
+            let mut keys = keys.lock().await;
+            keys.poll(Instant::now());
+            keys.deadline()
+        };
+
+        let event = match deadline {
+            Some(deadline) => match select(PROCESSED_KEY_CHAN.recv(), Timer::at(deadline)).await {
+                Either::First(event) => event,
+                Either::Second(()) => continue,
+            },
+            None => PROCESSED_KEY_CHAN.recv().await,
+        };
+        EVENT_IN_FLIGHT.store(true, core::sync::atomic::Ordering::Relaxed);
+
+        let mut keys = keys.lock().await;
+        keys.event(event);
+        keys.drain();
+        EVENT_IN_FLIGHT.store(false, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Runs a firmware action, from a key of the layout or an action chord
+fn run_action(action: KeyAction) {
+    match action {
+        KeyAction::QuietHoursOverride => quiet_hours::toggle_override(),
+        KeyAction::MouseJiggle => jiggle::toggle(),
+        // the key press has already peeked, this covers action chords
+        KeyAction::DisplayPeek => peeked(),
+        KeyAction::NextLedMode => settings::update(|s| s.led_mode = next_led_mode(s.led_mode)),
+        KeyAction::NextScreen => settings::update(|s| {
+            (s.display_swap, s.layer_legend, s.key_grid) =
+                next_screen(s.display_swap, s.layer_legend, s.key_grid)
+        }),
+        KeyAction::Lock => SYSTEM_STATE.set_locked(true),
+        // held rather than run, the layout tick reports it
+        KeyAction::Consumer(_) => {}
+    }
+}
+
+/// Whether a chorded event is one of the right half's keys, which only come
+/// through the poll task with cross side chording
+fn from_right(event: &Event) -> bool {
+    let (x, y) = event.coord();
+    (x as usize) < ROWS && y as usize >= COLS_PER_SIDE
+}
+
+fn polled_key_event(event: Event) -> KeyEvent {
+    if from_right(&event) {
+        KeyEvent::remote(event)
+    } else {
+        KeyEvent::local(event)
     }
 }
 
 #[embassy_executor::task]
 async fn keyboard_poll_task(
-    mut matrix: Matrix<Input<'static, AnyPin>, Output<'static, AnyPin>, COLS_PER_SIDE, ROWS>,
-    mut debouncer: Debouncer<[[bool; COLS_PER_SIDE]; ROWS]>,
-    mut chording: Chording<{ keyboard_thing::layout::NUM_CHORDS }>,
+    mut matrix: KeyMatrix,
+    mut debouncer: KeyDebouncer,
+    mut chording: GuardedChording<{ keyboard_thing::layout::NUM_CHORDS }>,
+    keys: &'static Mutex<ThreadModeRawMutex, Keys>,
 ) {
+    let mut gather = chord_guard::cross_side_gather();
+
     loop {
-        let events = debouncer
-            .events(matrix.get().unwrap())
-            .collect::<heapless::Vec<_, 8>>();
+        let events = {
+            let _busy = busy();
+
+            let state = matrix.get();
+            let now = Instant::now();
+            chording.observe_raw(&state, now, |x, y| to_global(KeyboardSide::Left, x, y));
+
+            let mut events = debouncer.events(&state, now.as_millis());
 
-        for event in &events {
-            for chan in KEY_EVENT_CHANS {
-                let _ = chan.try_send(*event);
+            for event in &events {
+                for (chan, which) in KEY_EVENT_CHANS {
+                    channel_stats::try_send(chan, *event, *which);
+                }
+                if event.is_press() {
+                    light_queued(now);
+                }
+                flash_key(event);
+                key_grid::key_event(event);
             }
-        }
 
-        let events = chording.tick(events);
+            // anything left over waits for the next tick, so whatever is
+            // gathered can still go out with this tick's events
+            while events.len() + gather.len() < events.capacity() {
+                let Ok(event) = RAW_REMOTE_KEY_CHAN.try_recv() else {
+                    break;
+                };
+                chording.observe_remote(&event, now);
+                let _ = events.push(event);
+            }
+            let window = tuning::get().chord_window_ms as u64;
+            let events = gather.tick(events, now.as_millis(), window, chord_guard::cross_side());
+
+            let raw = events.clone();
+            let mut events = chording.tick(events);
+            for (chord, (x, y)) in chord_guard::suppress_leaks(&raw, &mut events, &CHORDS) {
+                defmt::warn!("chord {} leaked a press of {:?}", chord, (x, y));
+                hostlog!(Warn, "chord {} leaked a press of ({}, {})", chord, x, y);
+            }
+            events
+        };
 
-        let count = events.iter().filter(|e| e.is_press()).count() as u32;
+        let count = events
+            .iter()
+            .filter(|e| e.is_press() && !from_right(e))
+            .count() as u32;
         TOTAL_LHS_KEYPRESSES.fetch_add(count, core::sync::atomic::Ordering::Relaxed);
 
-        for event in events {
-            PROCESSED_KEY_CHAN.send(event).await;
+        let fast = match fast_path::single(&events) {
+            Some(event) => {
+                let mut keys = keys.lock().await;
+                // the event task may have taken an event off the queue and be
+                // waiting for the lock, and an action chord holding presses
+                // back would hold this one too
+                let clear = keyboard_shared::may_overtake(
+                    EVENT_IN_FLIGHT.load(core::sync::atomic::Ordering::Relaxed),
+                    keys.action_chords.holding(),
+                );
+                if clear {
+                    keys.drain();
+                    keys.event(polled_key_event(event));
+                    TICK_NOW.set();
+                }
+                clear
+            }
+            None => false,
+        };
+
+        if !fast {
+            for event in events {
+                PROCESSED_KEY_CHAN.send(polled_key_event(event)).await;
+            }
         }
 
         Timer::after(POLL_PERIOD).await;
     }
 }
 
+/// Key presses from the host
+#[cfg(feature = "inject-keys")]
+static INJECT_CHAN: keyboard_thing::host_dispatch::InjectChannel = Channel::new();
+
+#[cfg(feature = "inject-keys")]
+#[embassy_executor::task]
+async fn inject_task() {
+    use keyboard_thing::host_dispatch::Injection;
+
+    loop {
+        let Injection {
+            side,
+            row,
+            col,
+            duration_ms,
+        } = INJECT_CHAN.recv().await;
+        if row as usize >= ROWS || col as usize >= COLS {
+            continue;
+        }
+
+        let inject = |pressed| async move {
+            match side {
+                KeyboardSide::Left => {
+                    let event = if pressed {
+                        Event::Press(row, col)
+                    } else {
+                        Event::Release(row, col)
+                    };
+                    PROCESSED_KEY_CHAN
+                        .send(KeyEvent::new(event, EventSource::Synthetic))
+                        .await;
+                }
+                KeyboardSide::Right => {
+                    send_to_peer((
+                        DomToSub::InjectKey { row, col, pressed },
+                        SendPolicy::KEY_EVENT,
+                    ))
+                    .await;
+                }
+            }
+        };
+
+        inject(true).await;
+        Timer::after(Duration::from_millis(duration_ms as u64)).await;
+        inject(false).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn link_health_task() {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut health = LinkHealth::new(RETRANSMITS.load(core::sync::atomic::Ordering::Relaxed));
+
+    loop {
+        ticker.next().await;
+
+        let retransmits = RETRANSMITS.load(core::sync::atomic::Ordering::Relaxed);
+        if let Some(degraded) = health.sample(retransmits) {
+            link_health::set_degraded(degraded);
+            debug!("Link degraded: {}", degraded);
+            if degraded {
+                hostlog!(Warn, "link to the right half degraded");
+            } else {
+                hostlog!(Info, "link to the right half recovered");
+            }
+            channel_stats::try_send(
+                &HOST_REPLY_CHAN,
+                KeyboardToHost::LinkDegraded(degraded),
+                DropChannel::LinkStatus,
+            );
+        }
+    }
+}
+
+/// Ping the right half, and mark the link down when it stops answering
+#[embassy_executor::task]
+async fn heartbeat_task() {
+    let mut ticker = Ticker::every(link_health::HEARTBEAT_PERIOD);
+    let mut heartbeat = Heartbeat::new();
+
+    loop {
+        ticker.next().await;
+
+        if heartbeat.tick() {
+            debug!("Link down");
+            hostlog!(Warn, "lost the link to the right half");
+            release_remote_keys().await;
+        }
+        // straight onto the link like `Hello`, the gate would hold it back
+        // until the link first came up
+        LINK_CHAN
+            .send((DomToSub::Ping, SendPolicy::HEARTBEAT))
+            .await;
+    }
+}
+
+/// Say `Hello` again after a burst of link errors, the right half answers with
+/// its self test and version as it did at boot
+#[embassy_executor::task]
+async fn link_resync_task() {
+    loop {
+        messages::LINK_RESYNC.wait().await;
+        hostlog!(Warn, "burst of errors on the link to the right half");
+        LINK_CHAN
+            .send((
+                DomToSub::Hello(version_check::ours()),
+                SendPolicy::KEY_EVENT,
+            ))
+            .await;
+    }
+}
+
+#[embassy_executor::task]
+async fn quiet_hours_task() {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut tracker = QuietHoursTracker::new();
+
+    loop {
+        ticker.next().await;
+
+        if let Some(quiet) = tracker.sample() {
+            debug!("Quiet hours: {}", quiet);
+            set_peer_state((DomToSub::SetQuiet(quiet), SendPolicy::BACKGROUND));
+        }
+    }
+}
+
+/// The right half only hears about this half's presses, so while the key
+/// grid is shown it's also told which keys are held
+#[embassy_executor::task]
+async fn key_grid_sync_task() {
+    let mut ticker = Ticker::every(key_grid::SYNC_PERIOD);
+    let mut last = None;
+    let mut ticks = 0u32;
+
+    loop {
+        ticker.next().await;
+        ticks = ticks.wrapping_add(1);
+
+        if !key_grid_shown() {
+            last = None;
+            continue;
+        }
+
+        // sends aren't retried, so the state goes again every so often in
+        // case one was lost
+        let held = key_grid::held(KeyboardSide::Left);
+        if last != Some(held) || ticks % 10 == 0 {
+            send_to_peer((DomToSub::KeyGridState { held }, SendPolicy::BACKGROUND)).await;
+            last = Some(held);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn version_check_task() {
+    version_check::watch_peer().await;
+}
+
 #[embassy_executor::task]
 async fn otherside_key_transmit_task() {
     loop {
         let evt = OTHERSIDE_KEY_TRANSMIT_CHAN.recv().await;
         if evt.is_press() {
             let (x, y) = evt.coord();
-            COMMAND_CHAN
-                .send((
-                    DomToSub::KeyPressed(KeyLocation::pack(x, y)),
-                    Duration::from_millis(2),
-                ))
-                .await;
+            send_to_peer((
+                DomToSub::KeyPressed(KeyLocation::pack(x, y)),
+                SendPolicy::BACKGROUND,
+            ))
+            .await;
         }
     }
 }
 
+/// Blinks slowly in place of `led_task` while in safe mode
+#[embassy_executor::task]
+async fn safe_mode_led_task(mut leds: Leds) {
+    let layout = leds.layout();
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut on = false;
+
+    loop {
+        on = !on;
+        leds.send(safe_mode_pattern(layout, on));
+        ticker.next().await;
+    }
+}
+
 #[embassy_executor::task]
 async fn led_task(mut leds: Leds) {
     let fps = 30;
-    let mut tapwaves = TapWaves::new();
+    let mut effects = Effects::new();
     let mut ticker = Ticker::every(Duration::from_millis(1000 / fps));
     let mut counter = WrappingID::<u16>::new(0);
+    // the right half's counter is only worth setting once it's listening
+    let mut sync = PeerSync::new();
+
+    let layout = leds.layout();
+    show_self_test_pattern(&mut leds).await;
 
     loop {
-        while let Ok(event) = LED_KEY_LISTEN_CHAN.try_recv() {
-            if event.is_press() {
-                let (x, y) = event.coord();
-                tapwaves.update(x, y);
-            }
-        }
+        let showing_effect = {
+            let _busy = busy();
 
-        while let Ok(loc) = OTHERSIDE_LED_KEY_LISTEN_CHAN.try_recv() {
-            let (x, y) = loc.unpack();
+            let effect = effects.get(led_mode());
 
-            tapwaves.update(x, y);
-        }
+            while let Ok(event) = LED_KEY_LISTEN_CHAN.try_recv() {
+                effect.on_event(event);
+            }
+
+            while let Ok(loc) = OTHERSIDE_LED_KEY_LISTEN_CHAN.try_recv() {
+                let (x, y) = loc.unpack();
 
-        tapwaves.tick();
+                effect.on_event(Event::Press(x, y));
+            }
 
-        leds.send(tapwaves.render(|x, y| rainbow_single(x, y, counter.get() as u8)));
+            effect.tick(counter.get());
+
+            let status =
+                LinkStatus::current(USB_CONFIGURED.load(core::sync::atomic::Ordering::Relaxed));
+
+            if SYSTEM_STATE.is_locked() {
+                let frame = locked_pattern(layout, counter.get() as u8);
+                leds.send(status_led_overlay(frame, status, counter.get()));
+                false
+            } else if let Some(colour) = test_colour(layout) {
+                leds.send(status_led_overlay(colour, status, counter.get()));
+                false
+            } else if let Some(frame) = test_led(layout) {
+                leds.send(status_led_overlay(frame, status, counter.get()));
+                false
+            } else {
+                let frame = layer_tint(render_effect(layout, effect), layout, active_layer());
+                let frame = key_led_overlay(frame);
+                let frame = presence_indicator(frame, counter.get(), jiggle::active());
+                let frame = report_mirror_indicator(frame, report_mirror::active());
+                leds.send(status_led_overlay(frame, status, counter.get()));
+                true
+            }
+        };
 
         counter.inc();
 
-        if (counter.get() % 128) == 0 {
-            let _ = COMMAND_CHAN.try_send((
-                DomToSub::ResyncLeds(counter.get()),
-                Duration::from_millis(5),
-            ));
+        // keeps trying each frame until the first one is queued
+        if sync.resync_leds(link_health::up())
+            && channel_stats::try_send(
+                COMMAND_CHAN.lane(SendPolicy::BACKGROUND.priority),
+                (DomToSub::ResyncLeds(counter.get()), SendPolicy::BACKGROUND),
+                DropChannel::LedResync,
+            )
+        {
+            sync.leds_resynced();
         }
 
-        ticker.next().await;
+        leds.next_frame(&mut ticker, showing_effect).await;
     }
 }
 
@@ -444,77 +1668,194 @@ async fn hid_task(
 ) {
     loop {
         let report = HID_CHAN.recv().await;
-        let _ = hid.write(&report.pack().unwrap()).await;
+        // writes wait for the host, which would back up the layout while
+        // we're running without USB
+        if !USB_RUNNING.load(core::sync::atomic::Ordering::Relaxed) {
+            continue;
+        }
+        let _ = match report {
+            HidReport::Nkro(r) => hid.write(&r.pack().unwrap()).await,
+            HidReport::SixKro(r) => hid.write(&r.pack().unwrap()).await,
+        };
+    }
+}
+
+/// How often the layer and lock state are checked for changes
+const STATUS_CHECK_PERIOD: Duration = Duration::from_millis(20);
+
+/// Tell the OS about layer and lock changes through the status interface,
+/// so it can show them without opening the serial port. Nothing is sent
+/// unless something changed.
+#[embassy_executor::task]
+async fn status_report_task(mut hid: HidWriter<'static, UsbDriver, { StatusReport::LEN }>) {
+    let mut ticker = Ticker::every(STATUS_CHECK_PERIOD);
+    let mut last = None;
+
+    loop {
+        ticker.next().await;
+
+        if !USB_RUNNING.load(core::sync::atomic::Ordering::Relaxed) {
+            continue;
+        }
+
+        let report = status_report();
+        if last != Some(report) {
+            last = Some(report);
+            let _ = hid.write(&report.to_bytes()).await;
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn consumer_task(mut hid: HidWriter<'static, UsbDriver, CONSUMER_REPORT_LEN>) {
+    loop {
+        let usage = CONSUMER_CHAN.recv().await;
+        if !USB_RUNNING.load(core::sync::atomic::Ordering::Relaxed) {
+            continue;
+        }
+        let _ = hid.write(&consumer_report(usage)).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn mouse_jiggle_task(mut hid: HidWriter<'static, UsbDriver, MOUSE_REPORT_LEN>) {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut jiggler = Jiggler::new();
+
+    loop {
+        ticker.next().await;
+
+        if !USB_RUNNING.load(core::sync::atomic::Ordering::Relaxed) {
+            continue;
+        }
+
+        if let Some(dx) = jiggler.poll() {
+            let _ = hid.write(&mouse_report(dx)).await;
+        }
     }
 }
 
+const HOST_TIMEOUT: Duration = Duration::from_millis(HOST_TIMEOUT_MS);
+
 #[embassy_executor::task]
-async fn usb_serial_task(mut class: CdcAcmClass<'static, UsbDriver>) {
+async fn usb_serial_task(
+    mut class: CdcAcmClass<'static, UsbDriver>,
+    oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+) {
     loop {
         let in_chan: &mut Channel<ThreadModeRawMutex, u8, 128> = forever!(Channel::new());
         let out_chan: &mut Channel<ThreadModeRawMutex, u8, 128> = forever!(Channel::new());
         let msg_out_chan: &mut Channel<ThreadModeRawMutex, HostToKeyboard, 16> =
             forever!(Channel::new());
-        let msg_in_chan: &mut Channel<ThreadModeRawMutex, (KeyboardToHost, Duration), 16> =
-            forever!(Channel::new());
+        let msg_in_chan: &mut ReplyChannel = forever!(CommandQueue::new());
         class.wait_connection().await;
         let mut wrapper = UsbSerialWrapper::new(&mut class, &*in_chan, &*out_chan);
         let mut eventer = Eventer::new(&*in_chan, &*out_chan, msg_out_chan.sender());
 
+        let ctx = DispatchCtx {
+            side: KeyboardSide::Left,
+            oled,
+            commands: &COMMAND_CHAN,
+            peer_state: set_peer_state,
+            replies: &*msg_in_chan,
+            #[cfg(feature = "inject-keys")]
+            inject: &INJECT_CHAN,
+        };
+        let mut session = HostSession::new();
+
         let handle = async {
             loop {
-                match msg_out_chan.recv().await {
-                    HostToKeyboard::RequestStats => {
-                        msg_in_chan
-                            .send((
-                                KeyboardToHost::Stats {
-                                    keypresses: TOTAL_KEYPRESSES
-                                        .load(core::sync::atomic::Ordering::Relaxed),
-                                },
-                                Duration::from_millis(5),
-                            ))
-                            .await;
-                    }
-                    HostToKeyboard::WritePixels {
-                        side,
-                        row,
-                        data_0,
-                        data_1,
-                    } => match side {
-                        keyboard_thing::messages::KeyboardSide::Left => {
-                            lhs_display::OVERRIDE_CHAN
-                                .send(DisplayOverride {
-                                    row,
-                                    data_0,
-                                    data_1,
-                                })
-                                .await;
-                            interacted();
-                        }
-                        keyboard_thing::messages::KeyboardSide::Right => {
-                            COMMAND_CHAN
-                                .send((
-                                    DomToSub::WritePixels {
-                                        row,
-                                        data_0,
-                                        data_1,
-                                    },
-                                    Duration::from_millis(1),
-                                ))
-                                .await
+                match with_timeout(HOST_TIMEOUT, msg_out_chan.recv()).await {
+                    Ok(cmd) => handle_command(cmd, &ctx, &mut session).await,
+                    Err(_) => {
+                        if session.is_active() {
+                            debug!("Host went quiet, ending its session");
+                            session.end(&ctx).await;
                         }
-                    },
+                    }
                 }
             }
         };
 
+        let forward_replies = async {
+            loop {
+                let reply = HOST_REPLY_CHAN.recv().await;
+                msg_in_chan.send((reply, SendPolicy::BULK)).await;
+            }
+        };
+
+        // polled last so replies go first, and when the host stops reading
+        // this backs up and the log lines get dropped instead
+        let forward_logs = async {
+            loop {
+                let line = HOST_LOG_CHAN.recv().await;
+                msg_in_chan.send((line, SendPolicy::BACKGROUND)).await;
+            }
+        };
+
         let (e_a, e_b, e_c) = eventer.split_tasks(msg_in_chan);
 
-        select3(wrapper.run(), select3(e_a, e_b, e_c), handle).await;
+        select3(
+            wrapper.run(),
+            select3(e_a, e_b, e_c),
+            select3(handle, forward_replies, forward_logs),
+        )
+        .await;
+
+        session.end(&ctx).await;
+    }
+}
+
+/// Lets the next boot start counting again once this one has lasted
+#[embassy_executor::task]
+async fn boot_ok_task() {
+    Timer::after(safe_mode::BOOT_OK_AFTER).await;
+    safe_mode::boot_ok();
+}
+
+struct UsbStateHandler;
+
+impl embassy_usb::DeviceStateHandler for UsbStateHandler {
+    fn reset(&self) {
+        USB_CONFIGURED.store(false, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn configured(&self, configured: bool) {
+        USB_CONFIGURED.store(configured, core::sync::atomic::Ordering::Relaxed);
     }
 }
 
 #[embassy_executor::task]
 async fn usb_task(mut device: UsbDevice<'static, UsbDriver>) {
+    wait_for_vbus().await;
+    debug!("USB power present, starting USB");
+    USB_RUNNING.store(true, core::sync::atomic::Ordering::Relaxed);
     device.run().await;
 }
+
+/// Start the external crystal, returning whether it started in time
+async fn start_hfxo() -> bool {
+    let clock: pac::CLOCK = unsafe { core::mem::transmute(()) };
+
+    clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+    // the crystal normally starts in well under a millisecond
+    for _ in 0..100 {
+        if clock.events_hfclkstarted.read().bits() == 1 {
+            return true;
+        }
+        Timer::after(Duration::from_micros(100)).await;
+    }
+
+    false
+}
+
+fn vbus_present() -> bool {
+    let power: pac::POWER = unsafe { core::mem::transmute(()) };
+    power.usbregstatus.read().vbusdetect().is_vbus_present()
+}
+
+async fn wait_for_vbus() {
+    while !vbus_present() {
+        Timer::after(Duration::from_millis(100)).await;
+    }
+}
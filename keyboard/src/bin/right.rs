@@ -2,12 +2,11 @@
 #![no_std]
 #![feature(type_alias_impl_trait)]
 
-use core::sync::atomic::AtomicU16;
+use core::sync::atomic::{AtomicU16, AtomicU32};
 
 use defmt::debug;
 use embassy_executor::Spawner;
 use embassy_nrf::{
-    gpio::{AnyPin, Input, Output},
     interrupt,
     peripherals::{TWISPI0, UARTE0},
     twim::{self, Twim},
@@ -18,20 +17,43 @@ use embassy_sync::{
     channel::{Channel, Receiver},
     mutex::Mutex,
 };
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
 use futures::{Future, StreamExt};
-use keyberon::{chording::Chording, debounce::Debouncer, layout::Event, matrix::Matrix};
+use keyberon::layout::Event;
 use keyboard_thing::{
-    self as _,
-    cps::{cps_task, Cps, SampleBuffer},
-    forever, init_heap,
-    layout::{COLS_PER_SIDE, ROWS},
-    leds::{rainbow_single, Leds, TapWaves},
-    messages::{DomToSub, Eventer, SubToDom, KeyLocation},
-    oled::{display_timeout_task, interacted, Oled},
-    rhs_display::{
-        self, DisplayOverride, RHSDisplay, AVERAGE_KEYPRESSES, KEYPRESS_EVENT, TOTAL_KEYPRESSES,
+    self as _, bootloader,
+    channel_stats::{self, DropChannel},
+    chord_guard::{cross_side, GuardedChording},
+    clock,
+    cps::{self, cps_task, Cps, SampleBuffer},
+    debounce::{self, KeyDebouncer},
+    display_bus::{self, display_bus_task},
+    display_widgets::{
+        self, run_display, DisplayOverride, DisplayRole, HostPixels, AVERAGE_KEYPRESSES,
+        KEYPRESS_EVENT, TOTAL_KEYPRESSES,
     },
+    forever, init_heap, key_grid,
+    layout::{is_peek_key, COLS_PER_SIDE, LAYERS, NUM_CHORDS, ROWS},
+    leds::{
+        clear_key_leds, flash_key, key_led_overlay, layer_tint, led_mode, locked_pattern,
+        render_effect, set_calibration, set_key_led, set_test_colour, set_test_led,
+        show_self_test_pattern, test_colour, test_led, Effects, Leds, RIGHT_LEDS,
+    },
+    link_health::{self, Heartbeat},
+    log_level, log_sampled,
+    matrix::{KeyMatrix, PHANTOM_PRESSES},
+    messages::{
+        self, to_global, to_local, CommandQueue, DomToSub, Eventer, FrameCheck, KeyLocation,
+        KeyboardSide, MonotonicCounter, RawMode, SendPolicy, SubToDom, MAX_KEY_EVENTS,
+    },
+    oled::{display_timeout_task, interacted, peeked, remote_interacted, remote_peeked, Oled},
+    profiling::busy,
+    quiet_hours, rhs_display,
+    self_test::{self, SelfTestResults},
+    settings::apply_config,
+    system_state::{active_layer, set_active_layer, SYSTEM_STATE},
+    telemetry::{CHORD_FIRES, CHORD_NEAR_MISSES},
+    tuning, version_check,
     wrapping_id::WrappingID,
     DEBOUNCER_TICKS, POLL_PERIOD, UART_BAUD,
 };
@@ -40,12 +62,19 @@ use micromath::F32Ext;
 static OTHERSIDE_LED_KEY_LISTEN_CHAN: Channel<ThreadModeRawMutex, KeyLocation, 16> = Channel::new();
 static LED_KEY_LISTEN_CHAN: Channel<ThreadModeRawMutex, Event, 16> = Channel::new();
 /// Channels that receive each debounced key press
-static KEY_EVENT_CHANS: &[&Channel<ThreadModeRawMutex, Event, 16>] = &[&LED_KEY_LISTEN_CHAN];
+static KEY_EVENT_CHANS: &[(&Channel<ThreadModeRawMutex, Event, 16>, DropChannel)] =
+    &[(&LED_KEY_LISTEN_CHAN, DropChannel::LedKeyListen)];
 /// Channel commands are put on to be sent to the other side
-static COMMAND_CHAN: Channel<ThreadModeRawMutex, (SubToDom, Duration), 4> = Channel::new();
+static COMMAND_CHAN: CommandQueue<SubToDom, 4> = CommandQueue::new();
 
+/// Presses of this half's own keys, the total includes the left half's
+static TOTAL_RHS_KEYPRESSES: AtomicU32 = AtomicU32::new(0);
 static LED_COUNTER_TARGET: AtomicU16 = AtomicU16::new(0);
 
+/// Key events from the left half's `DomToSub::InjectKey`
+#[cfg(feature = "inject-keys")]
+static INJECT_CHAN: Channel<ThreadModeRawMutex, Event, 4> = Channel::new();
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_nrf::init(Default::default());
@@ -55,15 +84,14 @@ async fn main(spawner: Spawner) {
     let mut cortex_p = cortex_m::Peripherals::take().unwrap();
     cortex_p.SCB.enable_icache();
 
-    let leds = Leds::new(p.PWM0, p.P0_06);
+    let mut leds = Leds::new(p.PWM0, p.P0_06, &RIGHT_LEDS);
 
-    let matrix = keyboard_thing::build_matrix!(p);
-    let debouncer = Debouncer::new(
-        [[false; COLS_PER_SIDE]; ROWS],
-        [[false; COLS_PER_SIDE]; ROWS],
-        DEBOUNCER_TICKS,
+    let mut matrix = keyboard_thing::build_matrix!(p);
+    let debouncer = KeyDebouncer::new(|x, y| to_global(KeyboardSide::Right, x, y));
+    let chording = GuardedChording::new(
+        &keyboard_thing::layout::CHORDS,
+        &keyboard_thing::layout::CHORD_DEFS,
     );
-    let chording = Chording::new(&keyboard_thing::layout::CHORDS);
 
     let mut uart_config = uarte::Config::default();
     uart_config.parity = uarte::Parity::EXCLUDED;
@@ -90,15 +118,38 @@ async fn main(spawner: Spawner) {
 
     let irq = interrupt::take!(SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
     let mut config = twim::Config::default();
-    config.frequency = unsafe { core::mem::transmute(209715200) };
+    config.frequency = twim::Frequency::K400;
     config.scl_high_drive = true;
     config.sda_high_drive = true;
     let twim = Twim::new(p.TWISPI0, irq, p.P0_17, p.P0_20, config);
+    display_bus::overdrive(display_bus::RIGHT_BUS_KHZ);
     let oled = forever!(Mutex::new(Oled::new(twim)));
 
-    let cps_samples = forever!(Mutex::new(SampleBuffer::default()));
+    let cps_samples = forever!(Mutex::new(cps::sample_buffer()));
     let cps = Cps::new(&TOTAL_KEYPRESSES, &AVERAGE_KEYPRESSES, cps_samples);
 
+    let mut results = SelfTestResults {
+        oled: self_test::check_oled(oled).await,
+        link: false,
+        leds: leds.self_test(),
+        matrix: self_test::check_matrix(&mut matrix),
+    };
+    self_test::record(results);
+
+    // the left half says hello once it's done its own checks
+    spawner
+        .spawn(read_events_task(DOM_TO_SUB_CHAN.receiver(), oled))
+        .unwrap();
+    spawner.spawn(eventer_a(e_a)).unwrap();
+    spawner.spawn(eventer_b(e_b)).unwrap();
+    spawner.spawn(eventer_c(e_c)).unwrap();
+
+    results.link = with_timeout(self_test::LINK_TIMEOUT, self_test::PEER_HELLO.wait())
+        .await
+        .is_ok();
+    self_test::record(results);
+    debug!("self test: {}", results);
+
     spawner.spawn(cps_task(cps)).unwrap();
     spawner.spawn(oled_task(oled, cps_samples)).unwrap();
     spawner.spawn(oled_timeout_task(oled)).unwrap();
@@ -106,27 +157,139 @@ async fn main(spawner: Spawner) {
     spawner
         .spawn(keyboard_poll_task(matrix, debouncer, chording))
         .unwrap();
+    spawner.spawn(sync_stats_task()).unwrap();
+    spawner.spawn(link_resync_task()).unwrap();
+    spawner.spawn(heartbeat_task()).unwrap();
+    spawner.spawn(version_check_task()).unwrap();
+    spawner.spawn(display_bus_task()).unwrap();
+    #[cfg(feature = "profiling")]
     spawner
-        .spawn(read_events_task(DOM_TO_SUB_CHAN.receiver()))
+        .spawn(keyboard_thing::profiling::profiling_task())
         .unwrap();
-    spawner.spawn(eventer_a(e_a)).unwrap();
-    spawner.spawn(eventer_b(e_b)).unwrap();
-    spawner.spawn(eventer_c(e_c)).unwrap();
+}
+
+#[embassy_executor::task]
+async fn version_check_task() {
+    version_check::watch_peer().await;
+}
+
+/// Answer the left half's `Hello`, with this half's self test and version
+async fn say_hello() {
+    if let Some(results) = self_test::results() {
+        // we've just heard from the left, so the link works
+        let results = SelfTestResults {
+            link: true,
+            ..results
+        };
+        COMMAND_CHAN
+            .send((
+                SubToDom::Hello(results, version_check::ours()),
+                SendPolicy::KEY_EVENT,
+            ))
+            .await;
+    }
+}
+
+/// Say `Hello` again after a burst of link errors, as the left half may have
+/// missed things while the link was down
+#[embassy_executor::task]
+async fn link_resync_task() {
+    loop {
+        messages::LINK_RESYNC.wait().await;
+        say_hello().await;
+    }
+}
+
+/// Mark the link down when the left half's pings stop arriving
+#[embassy_executor::task]
+async fn heartbeat_task() {
+    let mut ticker = Ticker::every(link_health::HEARTBEAT_PERIOD);
+    let mut heartbeat = Heartbeat::new();
+
+    loop {
+        ticker.next().await;
+
+        if heartbeat.tick() {
+            debug!("Link down");
+        }
+    }
+}
+
+/// Chording, scanning and debouncing happen on each half, so ship our chord
+/// counters, phantom presses and debounce thresholds over to the left half
+/// which answers the host's stats requests.
+#[embassy_executor::task]
+async fn sync_stats_task() {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut last = [(0u16, 0u16); NUM_CHORDS];
+    let mut last_phantoms = 0;
+    let mut last_debounce = [[(DEBOUNCER_TICKS, 0u16); COLS_PER_SIDE]; ROWS];
+
+    loop {
+        ticker.next().await;
+
+        let phantoms = PHANTOM_PRESSES.load(core::sync::atomic::Ordering::Relaxed);
+        if phantoms != last_phantoms {
+            COMMAND_CHAN
+                .send((SubToDom::PhantomPresses(phantoms), SendPolicy::BACKGROUND))
+                .await;
+            last_phantoms = phantoms;
+        }
+
+        for (chord, last) in last.iter_mut().enumerate() {
+            let current = (
+                CHORD_FIRES[chord].load(core::sync::atomic::Ordering::Relaxed),
+                CHORD_NEAR_MISSES[chord].load(core::sync::atomic::Ordering::Relaxed),
+            );
+
+            if current != *last {
+                COMMAND_CHAN
+                    .send((
+                        SubToDom::ChordStats {
+                            chord: chord as u8,
+                            fires: current.0,
+                            near_misses: current.1,
+                        },
+                        SendPolicy::BACKGROUND,
+                    ))
+                    .await;
+                *last = current;
+            }
+        }
+
+        for (row, lasts) in last_debounce.iter_mut().enumerate() {
+            for (col, last) in lasts.iter_mut().enumerate() {
+                let (_, col) = to_global(KeyboardSide::Right, row as u8, col as u8);
+                let current = debounce::key(row, col as usize);
+
+                if current != *last {
+                    COMMAND_CHAN
+                        .send((
+                            SubToDom::Debounce {
+                                row: row as u8,
+                                col,
+                                scans: current.0,
+                                chatters: current.1,
+                            },
+                            SendPolicy::BACKGROUND,
+                        ))
+                        .await;
+                    *last = current;
+                }
+            }
+        }
+    }
 }
 
 #[embassy_executor::task]
 async fn oled_task(
     oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
-    cpm_samples: &'static Mutex<ThreadModeRawMutex, SampleBuffer>,
+    cps_samples: &'static Mutex<ThreadModeRawMutex, SampleBuffer>,
 ) {
-    Timer::after(Duration::from_millis(100)).await;
-    {
-        let _ = oled.lock().await.init().await;
-    }
+    self_test::show_summary(oled, None).await;
     debug!("oled starting up");
 
-    let mut display = RHSDisplay::new(oled, cpm_samples);
-    display.run().await;
+    run_display(oled, cps_samples, DisplayRole::Stats).await;
 }
 
 #[embassy_executor::task]
@@ -156,7 +319,18 @@ async fn eventer_c(f: EventerC) {
 }
 
 #[embassy_executor::task]
-async fn read_events_task(events_in: Receiver<'static, ThreadModeRawMutex, DomToSub, 16>) {
+async fn read_events_task(
+    events_in: Receiver<'static, ThreadModeRawMutex, DomToSub, 16>,
+    oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+) {
+    // the left half's presses counted into the total so far
+    let mut left_presses = MonotonicCounter::new(0);
+    // the count the left half loaded from flash that's been added so far
+    let mut restored = 0;
+    // the frame the host has started on this display, checked here as rows
+    // can go missing on the way over
+    let mut frame: Option<FrameCheck> = None;
+
     loop {
         let event = events_in.recv().await;
         match event {
@@ -167,117 +341,366 @@ async fn read_events_task(events_in: Receiver<'static, ThreadModeRawMutex, DomTo
             DomToSub::Reset => {
                 cortex_m::peripheral::SCB::sys_reset();
             }
-            DomToSub::SyncKeypresses(kp) => {
+            DomToSub::EnterBootloader => {
+                COMMAND_CHAN
+                    .send((SubToDom::EnteringBootloader, SendPolicy::KEY_EVENT))
+                    .await;
+                Timer::after(bootloader::ENTER_DELAY).await;
+                bootloader::enter();
+            }
+            DomToSub::SetTime { seconds_of_day } => clock::set_time(seconds_of_day),
+            DomToSub::Ping => {
+                if link_health::heard() {
+                    debug!("Link up");
+                }
+                COMMAND_CHAN
+                    .send((SubToDom::Pong, SendPolicy::HEARTBEAT))
+                    .await;
+            }
+            DomToSub::SyncKeypresses(total) => {
+                let kp = left_presses.delta_since(total);
                 if kp != 0 {
-                    TOTAL_KEYPRESSES.fetch_add(kp as u32, core::sync::atomic::Ordering::Relaxed);
+                    TOTAL_KEYPRESSES.fetch_add(kp, core::sync::atomic::Ordering::Relaxed);
+                    cps::synced_keypresses(kp);
+                    KEYPRESS_EVENT.set();
+                }
+            }
+            DomToSub::RestoredKeypresses(kp) => {
+                // sent again with every hello, so only what's beyond the
+                // count already added is new
+                let new = kp.saturating_sub(restored);
+                restored = restored.max(kp);
+                if new != 0 {
+                    TOTAL_KEYPRESSES.fetch_add(new, core::sync::atomic::Ordering::Relaxed);
+                    cps::restored_keypresses(new);
                     KEYPRESS_EVENT.set();
-                    interacted();
                 }
             }
+            DomToSub::WritePixels { row, .. } if !DisplayOverride::row_in_bounds(row) => {
+                display_widgets::rejected_pixel_write(row);
+                if let Some(frame) = &mut frame {
+                    frame.drop_rows();
+                }
+                COMMAND_CHAN
+                    .send((SubToDom::PixelsRejected { row }, SendPolicy::BULK))
+                    .await;
+            }
             DomToSub::WritePixels {
                 row,
                 data_0,
                 data_1,
             } => {
-                rhs_display::OVERRIDE_CHAN
-                    .send(DisplayOverride {
+                if let Some(frame) = &mut frame {
+                    frame.add_rows(row, [data_0, data_1]);
+                }
+                display_widgets::OVERRIDE_CHAN
+                    .send(HostPixels::Rows(DisplayOverride {
                         row,
                         data_0,
                         data_1,
-                    })
+                    }))
                     .await;
-                interacted();
+                remote_interacted();
+            }
+            DomToSub::FlushDisplay => {
+                display_widgets::OVERRIDE_CHAN.send(HostPixels::Flush).await;
+            }
+            DomToSub::DiscardDisplay => {
+                frame = None;
+                display_widgets::OVERRIDE_CHAN
+                    .send(HostPixels::Discard)
+                    .await;
+            }
+            DomToSub::BeginFrame { frame_id } => {
+                if frame.replace(FrameCheck::new(frame_id)).is_some() {
+                    display_widgets::OVERRIDE_CHAN
+                        .send(HostPixels::Discard)
+                        .await;
+                }
+            }
+            DomToSub::EndFrame { frame_id, crc } => {
+                if matches!(frame.take(), Some(frame) if frame.intact(frame_id, crc)) {
+                    display_widgets::OVERRIDE_CHAN.send(HostPixels::Flush).await;
+                } else {
+                    defmt::warn!("dropping frame {} for the display", frame_id);
+                    display_widgets::OVERRIDE_CHAN
+                        .send(HostPixels::Discard)
+                        .await;
+                    COMMAND_CHAN
+                        .send((SubToDom::FrameNack { frame_id }, SendPolicy::BULK))
+                        .await;
+                }
             }
             DomToSub::KeyPressed(v) => {
+                // the synced keypress count can't tell peeks apart, so the
+                // display wakes from these instead
+                if is_peek_key(&LAYERS, active_layer(), v.unpack()) {
+                    remote_peeked();
+                } else {
+                    remote_interacted();
+                }
+                let (x, y) = v.unpack();
+                key_grid::key_event(&Event::Press(x, y));
                 OTHERSIDE_LED_KEY_LISTEN_CHAN.send(v).await;
             }
+            DomToSub::ReadPixels { row } => {
+                let (data_0, data_1) = oled.lock().await.read_rows(row);
+                COMMAND_CHAN
+                    .send((
+                        SubToDom::PixelRow {
+                            row,
+                            data_0,
+                            data_1,
+                        },
+                        SendPolicy::BULK,
+                    ))
+                    .await;
+            }
+            DomToSub::SetConfig(item) => {
+                apply_config(item, KeyboardSide::Right);
+            }
+            DomToSub::RequestStats => {
+                let keypresses = TOTAL_RHS_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
+                COMMAND_CHAN
+                    .send((SubToDom::Stats { keypresses }, SendPolicy::BULK))
+                    .await;
+            }
+            DomToSub::SetLedCalibration(gains) => {
+                set_calibration(gains);
+            }
+            DomToSub::LedTestColour(colour) => {
+                set_test_colour(colour);
+            }
+            DomToSub::LedTestIndex(index) => {
+                set_test_led(index);
+            }
+            DomToSub::SetKeyLed { index, r, g, b } => set_key_led(index, [r, g, b]),
+            DomToSub::ClearKeyLeds => clear_key_leds(),
+            DomToSub::SetLayer(layer) => {
+                set_active_layer(layer);
+            }
+            DomToSub::SetLocked(locked) => {
+                SYSTEM_STATE.set_locked(locked);
+                interacted();
+            }
+            DomToSub::ShowDebugScreen(show) => {
+                rhs_display::DEBUG_SCREEN.store(show, core::sync::atomic::Ordering::Relaxed);
+                interacted();
+            }
+            #[cfg(feature = "inject-keys")]
+            DomToSub::InjectKey { row, col, pressed } => {
+                use keyboard_thing::layout::COLS;
+
+                if (row as usize) < ROWS && (COLS_PER_SIDE..COLS).contains(&(col as usize)) {
+                    let event = if pressed {
+                        Event::Press(row, col)
+                    } else {
+                        Event::Release(row, col)
+                    };
+                    channel_stats::try_send(&INJECT_CHAN, event, DropChannel::InjectKey);
+                }
+            }
+            #[cfg(not(feature = "inject-keys"))]
+            DomToSub::InjectKey { .. } => {}
+            DomToSub::Hello(version) => {
+                self_test::PEER_HELLO.set();
+                version_check::peer_hello(version);
+                say_hello().await;
+            }
+            DomToSub::Version(version) => version_check::record_peer(version),
+            DomToSub::SetLogLevel(level) => log_level::set(level),
+            DomToSub::SetQuiet(quiet) => quiet_hours::set_quiet(quiet),
+            DomToSub::SetTuning(tuning) => tuning::set(tuning),
+            DomToSub::KeyGridState { held } => key_grid::sync_held(KeyboardSide::Left, held),
         }
     }
 }
 
 #[embassy_executor::task]
 async fn keyboard_poll_task(
-    mut matrix: Matrix<Input<'static, AnyPin>, Output<'static, AnyPin>, 6, 4>,
-    mut debouncer: Debouncer<[[bool; 6]; 4]>,
-    mut chording: Chording<{ keyboard_thing::layout::NUM_CHORDS }>,
+    mut matrix: KeyMatrix,
+    mut debouncer: KeyDebouncer,
+    mut chording: GuardedChording<{ keyboard_thing::layout::NUM_CHORDS }>,
 ) {
+    // keys currently held down by injection, their events are tagged as
+    // synthetic on the way out
+    #[cfg(feature = "inject-keys")]
+    let mut injected = heapless::Vec::<(u8, u8), 4>::new();
+    let mut raw_mode = RawMode::new();
+
     loop {
-        let events = debouncer
-            .events(matrix.get().unwrap())
-            .map(|e| e.transform(|x, y| (x, 11 - y)))
-            .collect::<heapless::Vec<_, 8>>();
+        let (events, raw) = {
+            let _busy = busy();
+
+            let state = matrix.get();
+            let now = Instant::now();
+            chording.observe_raw(&state, now, |x, y| to_global(KeyboardSide::Right, x, y));
+
+            #[allow(unused_mut)]
+            let mut events = debouncer
+                .events(&state, now.as_millis())
+                .into_iter()
+                .map(|e| e.transform(|x, y| to_global(KeyboardSide::Right, x, y)))
+                .collect::<heapless::Vec<_, 8>>();
+
+            #[cfg(feature = "inject-keys")]
+            while !events.is_full() {
+                match INJECT_CHAN.try_recv() {
+                    Ok(event) => {
+                        if event.is_press() {
+                            let _ = injected.push(event.coord());
+                        }
+                        let _ = events.push(event);
+                    }
+                    Err(_) => break,
+                }
+            }
 
-        if !events.is_empty() {
-            interacted();
-        }
+            let layer = active_layer();
+            if events
+                .iter()
+                .any(|e| !is_peek_key(&LAYERS, layer, e.coord()))
+            {
+                interacted();
+            } else if !events.is_empty() {
+                peeked();
+            }
 
-        for event in &events {
-            for chan in KEY_EVENT_CHANS {
-                let _ = chan.try_send(event.transform(|x, y| (x, 11 - y)));
+            for event in &events {
+                key_grid::key_event(event);
+                let event = event.transform(|x, y| to_local(KeyboardSide::Right, x, y));
+                for (chan, which) in KEY_EVENT_CHANS {
+                    channel_stats::try_send(chan, event, *which);
+                }
+                flash_key(&event);
             }
-        }
 
-        let events = chording.tick(events);
+            // with cross side chording the left half chords these along
+            // with its own keys
+            let raw = raw_mode.update(events.iter().map(|e| e.is_press()), cross_side());
+            if raw {
+                (events, raw)
+            } else {
+                (chording.tick(events), raw)
+            }
+        };
 
+        // everything from one scan goes in one frame, so a roll doesn't wait
+        // on an ack per key
+        let mut batch = heapless::Vec::<_, MAX_KEY_EVENTS>::new();
         for event in events {
-            let msg = match event {
-                keyberon::layout::Event::Press(x, y) => SubToDom::key_pressed(x, y),
-                keyberon::layout::Event::Release(x, y) => SubToDom::key_released(x, y),
-            };
-            COMMAND_CHAN.send((msg, Duration::from_millis(10))).await;
             if event.is_press() {
                 TOTAL_KEYPRESSES.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+                TOTAL_RHS_KEYPRESSES.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
                 KEYPRESS_EVENT.set();
             }
+
+            #[cfg(feature = "inject-keys")]
+            if let Some(idx) = injected.iter().position(|c| *c == event.coord()) {
+                if event.is_release() {
+                    injected.swap_remove(idx);
+                }
+                // anything batched so far happened first
+                send_key_events(&mut batch, raw).await;
+                let (x, y) = event.coord();
+                let msg = SubToDom::InjectedKey {
+                    key: KeyLocation::pack(x, y),
+                    pressed: event.is_press(),
+                };
+                COMMAND_CHAN.send((msg, SendPolicy::KEY_EVENT)).await;
+                continue;
+            }
+
+            // a scan (or a chord timing out along with it) can give more
+            // events than fit in a frame, so send what's there and go on
+            if batch.is_full() {
+                send_key_events(&mut batch, raw).await;
+            }
+            let _ = batch.push(event);
         }
+        send_key_events(&mut batch, raw).await;
 
         Timer::after(POLL_PERIOD).await;
     }
 }
 
+async fn send_key_events(batch: &mut heapless::Vec<Event, MAX_KEY_EVENTS>, raw: bool) {
+    if batch.is_empty() {
+        return;
+    }
+
+    COMMAND_CHAN
+        .send((messages::key_events(batch, raw), SendPolicy::KEY_EVENT))
+        .await;
+    batch.clear();
+}
+
 #[embassy_executor::task]
 async fn led_task(mut leds: Leds) {
     let fps = 30;
-    let mut tapwaves = TapWaves::new();
+    let mut effects = Effects::new();
     let mut ticker = Ticker::every(Duration::from_millis(1000 / fps));
     let mut counter = WrappingID::<u16>::new(0);
 
+    let layout = leds.layout();
+    show_self_test_pattern(&mut leds).await;
+
     loop {
-        while let Ok(event) = LED_KEY_LISTEN_CHAN.try_recv() {
-            if event.is_press() {
-                let (x, y) = event.coord();
-                tapwaves.update(x, y);
-            }
-        }
+        let showing_effect = {
+            let _busy = busy();
 
-        while let Ok(loc) = OTHERSIDE_LED_KEY_LISTEN_CHAN.try_recv() {
-            let (x, y) = loc.unpack();
-            let y = 11 - y;
+            let effect = effects.get(led_mode());
 
-            tapwaves.update(x, y);
-        }
+            while let Ok(event) = LED_KEY_LISTEN_CHAN.try_recv() {
+                effect.on_event(event);
+            }
 
-        tapwaves.tick();
+            while let Ok(loc) = OTHERSIDE_LED_KEY_LISTEN_CHAN.try_recv() {
+                let (x, y) = loc.unpack();
+                let (x, y) = to_local(KeyboardSide::Right, x, y);
 
-        counter.inc();
-        let lhs =
-            WrappingID::new(LED_COUNTER_TARGET.fetch_add(1, core::sync::atomic::Ordering::Acquire));
-        let delta = lhs.delta(counter);
-        if delta != 0 {
-            let sign = delta.signum();
-            let correction = (delta as f32 * 0.5).abs().sqrt();
-            let correction = (correction as i16).max(1) * sign;
+                effect.on_event(Event::Press(x, y));
+            }
 
-            debug!(
-                "lhs: {}, counter: {}, delta: {}, correction: {}",
-                lhs, counter, delta, correction
+            counter.inc();
+            let lhs = WrappingID::new(
+                LED_COUNTER_TARGET.fetch_add(1, core::sync::atomic::Ordering::Acquire),
             );
+            let delta = lhs.delta(counter);
+            if delta != 0 {
+                let sign = delta.signum();
+                let correction = (delta as f32 * 0.5).abs().sqrt();
+                let correction = (correction as i16).max(1) * sign;
+
+                log_sampled!(
+                    "lhs: {}, counter: {}, delta: {}, correction: {}",
+                    lhs,
+                    counter,
+                    delta,
+                    correction
+                );
+
+                counter.add(correction);
+            }
 
-            counter.add(correction);
-        }
-
-        leds.send(tapwaves.render(|x, y| rainbow_single(x, y, counter.get() as u8)));
+            effect.tick(counter.get());
+
+            if SYSTEM_STATE.is_locked() {
+                leds.send(locked_pattern(layout, counter.get() as u8));
+                false
+            } else if let Some(colour) = test_colour(layout) {
+                leds.send(colour);
+                false
+            } else if let Some(frame) = test_led(layout) {
+                leds.send(frame);
+                false
+            } else {
+                let frame = layer_tint(render_effect(layout, effect), layout, active_layer());
+                leds.send(key_led_overlay(frame));
+                true
+            }
+        };
 
-        ticker.next().await;
+        leds.next_frame(&mut ticker, showing_effect).await;
     }
 }
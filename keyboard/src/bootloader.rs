@@ -0,0 +1,28 @@
+//! Restarting into the UF2 bootloader, so new firmware can be copied over
+//! without double tapping reset on each half.
+
+use embassy_nrf::pac;
+use embassy_time::Duration;
+
+use crate::safe_mode;
+
+/// Left in GPREGRET across the reset, the Adafruit nRF52 bootloader that the
+/// nice!nano ships with stays in UF2 mode when it finds it there
+const DFU_MAGIC_UF2_RESET: u32 = 0x57;
+
+/// How long to carry on after saying the bootloader is coming, so the reply
+/// and the link's acks get out first
+pub const ENTER_DELAY: Duration = Duration::from_millis(200);
+
+/// Reset into the bootloader, a deliberate reset so it doesn't count towards
+/// safe mode
+pub fn enter() -> ! {
+    // SAFETY: GPREGRET isn't used anywhere else
+    let power: pac::POWER = unsafe { core::mem::transmute(()) };
+    power
+        .gpregret
+        .write(|w| unsafe { w.bits(DFU_MAGIC_UF2_RESET) });
+
+    safe_mode::clear();
+    cortex_m::peripheral::SCB::sys_reset()
+}
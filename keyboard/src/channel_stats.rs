@@ -0,0 +1,41 @@
+//! Drop counts for channels that are sent to with `try_send`, where a full
+//! channel is expected now and then but should still be visible.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+
+pub use keyboard_shared::DropChannel;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU32 = AtomicU32::new(0);
+static DROPS: [AtomicU32; DropChannel::COUNT] = [ZERO; DropChannel::COUNT];
+
+/// `try_send` on `chan`, counting the message against `which` if the channel
+/// is full. Returns whether it was queued.
+#[inline]
+pub fn try_send<T, const N: usize>(
+    chan: &Channel<ThreadModeRawMutex, T, N>,
+    msg: T,
+    which: DropChannel,
+) -> bool {
+    let sent = chan.try_send(msg).is_ok();
+    if !sent {
+        dropped(which);
+    }
+    sent
+}
+
+#[cold]
+fn dropped(which: DropChannel) {
+    DROPS[which as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Drop counts indexed by `DropChannel`
+pub fn drops() -> [u32; DropChannel::COUNT] {
+    DropChannel::ALL.map(|c| DROPS[c as usize].load(Ordering::Relaxed))
+}
+
+pub fn total_drops() -> u32 {
+    drops().iter().sum()
+}
@@ -0,0 +1,185 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::Instant;
+use keyberon::{chording::Chording, layout::Event};
+use keyboard_shared::{to_logical, ChordGuard, ChordTiming, Defused};
+
+use crate::{
+    layout::{Chord, CHORD_KEYS, COLS_PER_SIDE, ROWS},
+    telemetry::{bump, CHORD_FIRES, CHORD_NEAR_MISSES},
+    tuning,
+};
+
+/// Members pressed within this many ms of each other but in different ticks
+/// count as a near miss
+const NEAR_MISS_WINDOW_MS: u64 = 50;
+
+/// Drop presses of a chord's members that come out of keyberon alongside the
+/// chord itself, see [`keyboard_shared::suppress_leaks`]
+pub fn suppress_leaks(
+    input: &[Event],
+    output: &mut heapless::Vec<Event, 8>,
+    chords: &[Chord],
+) -> heapless::Vec<(usize, (u8, u8)), 8> {
+    keyboard_shared::suppress_leaks(input, output, chords.iter().map(|c| c.def), press_coord)
+}
+
+/// Wraps keyberon's chording with per-chord timing checks.
+///
+/// Keyberon fires a chord whenever all its members come out of the debouncer
+/// in the same tick, which happens for fast rolls too. We track when each key
+/// was physically pressed (from the raw matrix, before debouncing) and if a
+/// chord would fire but breaks its [`ChordTiming`], the presses are fed to
+/// keyberon over separate ticks so it resolves them as plain keys.
+pub struct GuardedChording<const N: usize> {
+    inner: Chording<N>,
+    chords: &'static [Chord; N],
+    guard: ChordGuard,
+}
+
+impl<const N: usize> GuardedChording<N> {
+    pub fn new(
+        chords: &'static [Chord; N],
+        defs: &'static [keyberon::chording::ChordDef; N],
+    ) -> Self {
+        Self {
+            inner: Chording::new(defs),
+            chords,
+            guard: ChordGuard::new(),
+        }
+    }
+
+    /// Record the raw (undebounced) matrix state so press edges can be timestamped.
+    ///
+    /// `to_global` maps this half's coordinates to the layout, the matrix is
+    /// put through [`to_logical`] first as the debouncer does.
+    pub fn observe_raw(
+        &mut self,
+        state: &[[bool; COLS_PER_SIDE]; ROWS],
+        now: Instant,
+        to_global: impl Fn(u8, u8) -> (u8, u8),
+    ) {
+        for (x, row) in state.iter().enumerate() {
+            for (y, pressed) in row.iter().enumerate() {
+                let (x, y) = to_logical(x as u8, y as u8);
+                self.guard.observe(to_global(x, y), *pressed, now.as_millis());
+            }
+        }
+    }
+
+    /// Record a key event from the other half, which comes already debounced
+    /// so its press is timed from when it arrived
+    pub fn observe_remote(&mut self, event: &Event, now: Instant) {
+        self.guard.observe(event.coord(), event.is_press(), now.as_millis());
+    }
+
+    pub fn tick(&mut self, events: heapless::Vec<Event, 8>) -> heapless::Vec<Event, 8> {
+        let defused = self.find_defused(&events);
+        self.record_telemetry(&events, defused);
+
+        let Some((_, defused)) = defused else {
+            return self.inner.tick(events);
+        };
+
+        // hold back the last pressed member so keyberon never sees the whole
+        // chord in one tick
+        let (held, rest): (heapless::Vec<Event, 8>, heapless::Vec<Event, 8>) = events
+            .into_iter()
+            .partition(|e| *e == Event::Press(defused.0, defused.1));
+
+        let mut out = self.inner.tick(rest);
+        for e in self.inner.tick(held) {
+            let _ = out.push(e);
+        }
+        out
+    }
+
+    fn record_telemetry(&self, events: &[Event], defused: Option<(usize, (u8, u8))>) {
+        for (idx, chord) in self.chords.iter().enumerate() {
+            let (_, keys) = chord.def;
+
+            if defused.map(|(d, _)| d) == Some(idx) {
+                bump(&CHORD_NEAR_MISSES[idx]);
+                continue;
+            }
+
+            let in_batch = keys
+                .iter()
+                .filter(|&&(x, y)| events.contains(&Event::Press(x, y)))
+                .count();
+
+            if in_batch == keys.len() {
+                bump(&CHORD_FIRES[idx]);
+                continue;
+            }
+
+            if in_batch == 0 {
+                continue;
+            }
+
+            // some members arrived this tick and the rest are already held
+            let Some(first) = keys.iter().filter_map(|k| self.guard.pressed_at(*k)).min() else {
+                continue;
+            };
+            let all_held = keys.iter().all(|k| self.guard.held(*k));
+
+            if all_held && Instant::now().as_millis().saturating_sub(first) < NEAR_MISS_WINDOW_MS {
+                bump(&CHORD_NEAR_MISSES[idx]);
+            }
+        }
+    }
+
+    /// Find a chord in this batch that breaks its timing rules, returning the
+    /// chord index and the member that should be delayed to stop it firing.
+    fn find_defused(&self, events: &[Event]) -> Option<(usize, (u8, u8))> {
+        let window = tuning::get().chord_window_ms;
+
+        self.chords.iter().enumerate().find_map(|(idx, chord)| {
+            let (_, keys) = chord.def;
+            if !keys
+                .iter()
+                .all(|&(x, y)| events.contains(&Event::Press(x, y)))
+            {
+                return None;
+            }
+
+            let (last_key, why) = self.guard.defuse(keys, chord.timing, window)?;
+            match why {
+                Defused::Spread(ms) => {
+                    defmt::debug!("Chord {:?} defused: spread of {}ms", chord.def.0, ms)
+                }
+                Defused::Busy => defmt::debug!(
+                    "Chord {:?} defused: other keys pressed recently",
+                    chord.def.0
+                ),
+            }
+            Some((idx, last_key))
+        })
+    }
+}
+
+static CROSS_SIDE: AtomicBool = AtomicBool::new(false);
+
+/// Chord keys from both halves on the left, see `ConfigItem::CrossSideChords`
+pub fn set_cross_side(on: bool) {
+    CROSS_SIDE.store(on, Ordering::Relaxed);
+}
+
+pub fn cross_side() -> bool {
+    CROSS_SIDE.load(Ordering::Relaxed)
+}
+
+/// Holds back presses of the members of chords that span the halves, see
+/// [`keyboard_shared::CrossSideGather`]
+pub type CrossSideGather = keyboard_shared::CrossSideGather<Event>;
+
+fn press_coord(event: &Event) -> Option<(u8, u8)> {
+    match *event {
+        Event::Press(x, y) => Some((x, y)),
+        Event::Release(..) => None,
+    }
+}
+
+pub const fn cross_side_gather() -> CrossSideGather {
+    keyboard_shared::CrossSideGather::new(&CHORD_KEYS, press_coord)
+}
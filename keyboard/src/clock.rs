@@ -0,0 +1,42 @@
+//! Time of day, as last told by the host. There's no RTC on either half, so
+//! this counts on from the most recent `SetTime` and is unknown until then.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When the host last set the time, and the second of the day it said it was
+static SYNCED: Mutex<ThreadModeRawMutex, Cell<Option<(Instant, u32)>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn set_time(seconds_of_day: u32) {
+    let seconds_of_day = (seconds_of_day as u64 % SECONDS_PER_DAY) as u32;
+    SYNCED.lock(|s| s.set(Some((Instant::now(), seconds_of_day))));
+}
+
+/// Seconds since midnight, if the host has ever set the time
+pub fn seconds_of_day() -> Option<u32> {
+    let (at, seconds_of_day) = SYNCED.lock(|s| s.get())?;
+    Some(((seconds_of_day as u64 + at.elapsed().as_secs()) % SECONDS_PER_DAY) as u32)
+}
+
+/// Minutes since midnight, if the host has ever set the time
+pub fn minute_of_day() -> Option<u16> {
+    seconds_of_day().map(|s| (s / 60) as u16)
+}
+
+/// The time as `HH:MM`, if the host has ever set it
+pub fn hh_mm() -> Option<[u8; 5]> {
+    let minute = minute_of_day()?;
+    let (h, m) = ((minute / 60) as u8, (minute % 60) as u8);
+    Some([
+        b'0' + h / 10,
+        b'0' + h % 10,
+        b':',
+        b'0' + m / 10,
+        b'0' + m % 10,
+    ])
+}
@@ -0,0 +1,84 @@
+//! Media keys, sent through their own HID interface on the consumer control
+//! page. The layout hands out a press and a release of each as custom
+//! events, and whichever held key was pressed last is the one reported.
+
+use crate::layout::ConsumerAction;
+
+/// Report descriptor of the consumer control interface: one 16 bit usage,
+/// 0 when nothing is held
+#[rustfmt::skip]
+pub const CONSUMER_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0c,       // usage page (consumer)
+    0x09, 0x01,       // usage (consumer control)
+    0xa1, 0x01,       // collection (application)
+    0x15, 0x00,       //   logical minimum (0)
+    0x26, 0xff, 0x03, //   logical maximum (0x3ff)
+    0x19, 0x00,       //   usage minimum (0)
+    0x2a, 0xff, 0x03, //   usage maximum (0x3ff)
+    0x75, 0x10,       //   report size (16)
+    0x95, 0x01,       //   report count (1)
+    0x81, 0x00,       //   input (data, array, absolute)
+    0xc0,             // end collection
+];
+
+pub const CONSUMER_REPORT_LEN: usize = 2;
+
+/// Most media keys held at once, any more are ignored
+const MAX_HELD: usize = 4;
+
+/// The usage of `action` on the consumer page
+pub const fn usage(action: ConsumerAction) -> u16 {
+    match action {
+        ConsumerAction::PlayPause => 0xcd,
+        ConsumerAction::NextTrack => 0xb5,
+        ConsumerAction::PrevTrack => 0xb6,
+        ConsumerAction::Mute => 0xe2,
+        ConsumerAction::VolumeUp => 0xe9,
+        ConsumerAction::VolumeDown => 0xea,
+        ConsumerAction::BrightnessUp => 0x6f,
+        ConsumerAction::BrightnessDown => 0x70,
+    }
+}
+
+pub fn consumer_report(usage: u16) -> [u8; CONSUMER_REPORT_LEN] {
+    usage.to_le_bytes()
+}
+
+/// The media keys being held, oldest first
+pub struct ConsumerKeys {
+    held: heapless::Vec<ConsumerAction, MAX_HELD>,
+}
+
+impl ConsumerKeys {
+    pub const fn new() -> Self {
+        Self {
+            held: heapless::Vec::new(),
+        }
+    }
+
+    pub fn press(&mut self, action: ConsumerAction) {
+        if !self.held.contains(&action) {
+            let _ = self.held.push(action);
+        }
+    }
+
+    pub fn release(&mut self, action: ConsumerAction) {
+        self.held.retain(|a| *a != action);
+    }
+
+    /// Let go of everything, for when the layout starts over
+    pub fn clear(&mut self) {
+        self.held.clear();
+    }
+
+    /// The usage to report, 0 for none
+    pub fn usage(&self) -> u16 {
+        self.held.last().map_or(0, |a| usage(*a))
+    }
+}
+
+impl Default for ConsumerKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
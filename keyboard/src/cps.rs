@@ -1,47 +1,106 @@
-use core::sync::atomic::AtomicU32;
+use core::{cell::RefCell, sync::atomic::AtomicU32};
 
 use atomic_float::AtomicF32;
 use embassy_sync::{
-    blocking_mutex::raw::ThreadModeRawMutex,
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
     mutex::Mutex,
 };
 use embassy_time::{Duration, Ticker};
 use futures::StreamExt;
-use heapless::HistoryBuffer;
+use keyboard_shared::{BatchSpreader, MonotonicCounter, RateHistory, RateWindow};
 
 pub const CPS_PERIOD: Duration = Duration::from_secs(3);
 pub const CPS_SAMPLES: usize = 32;
 pub const CPS_RATE: Duration = Duration::from_ticks(CPS_PERIOD.as_ticks() / CPS_SAMPLES as u64);
 
-pub type SampleBuffer = HistoryBuffer<u8, CPS_SAMPLES>;
+pub type SampleBuffer = RateWindow<CPS_SAMPLES>;
+
+/// An empty sample buffer for [`Cps`]
+pub const fn sample_buffer() -> SampleBuffer {
+    RateWindow::new(CPS_RATE.as_micros() as u32)
+}
+
+/// Bumped whenever a sample changes what the sample buffer or the press
+/// history holds, so the graph can tell it's out of date without locking
+/// either
+pub static SAMPLES_REVISION: AtomicU32 = AtomicU32::new(0);
+
+/// Each sample of the press history covers this many samples of the
+/// presses per second, six seconds
+pub const HISTORY_TICKS: u32 = 64;
+pub const HISTORY_RATE: Duration = Duration::from_ticks(CPS_RATE.as_ticks() * HISTORY_TICKS as u64);
+
+/// How often the left half sends its keypress count to the right
+pub const SYNC_PERIOD: Duration = Duration::from_millis(100);
+
+static SYNCED: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<BatchSpreader>> =
+    blocking_mutex::Mutex::new(RefCell::new(BatchSpreader::new(
+        CPS_RATE.as_micros() as u32,
+        SYNC_PERIOD.as_micros() as u32,
+    )));
+
+/// Key presses over the last few minutes, a sample every [`HISTORY_RATE`]
+/// built up from the presses per second samples
+static HISTORY: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<RateHistory<CPS_SAMPLES>>> =
+    blocking_mutex::Mutex::new(RefCell::new(RateHistory::new(
+        CPS_RATE.as_micros() as u32,
+        HISTORY_TICKS,
+    )));
+
+/// Look at the press history, see [`HISTORY_RATE`]
+pub fn with_history<R>(f: impl FnOnce(&RateWindow<CPS_SAMPLES>) -> R) -> R {
+    HISTORY.lock(|h| f(h.borrow().window()))
+}
+
+/// Note that `presses` were just added to the total in one go, call this
+/// straight after adding them
+pub fn synced_keypresses(presses: u32) {
+    SYNCED.lock(|s| s.borrow_mut().add(presses));
+}
+
+/// Note that `presses` were added to the total without being made, like a
+/// count loaded from flash, so they don't show up as presses per second
+pub fn restored_keypresses(presses: u32) {
+    SYNCED.lock(|s| s.borrow_mut().skip(presses));
+}
 
 pub struct Cps {
     total: &'static AtomicU32,
     samples: &'static Mutex<ThreadModeRawMutex, SampleBuffer>,
     avg: &'static AtomicF32,
+    /// How many of the latest samples have all been the same
+    repeats: usize,
 }
 
 impl Cps {
     pub fn new(
         total: &'static AtomicU32,
         avg: &'static AtomicF32,
-        samples: &'static Mutex<ThreadModeRawMutex, HistoryBuffer<u8, CPS_SAMPLES>>,
+        samples: &'static Mutex<ThreadModeRawMutex, SampleBuffer>,
     ) -> Self {
         Self {
             total,
             samples,
             avg,
+            repeats: 0,
         }
     }
 
     async fn sample(&mut self, sample: u8) {
         let mut samples = self.samples.lock().await;
-        samples.write(sample);
-        self.avg.store(
-            samples.iter().map(|s| *s as u16).sum::<u16>() as f32
-                / (samples.len() as f32 / CPS_PERIOD.as_secs() as f32),
-            core::sync::atomic::Ordering::Relaxed,
-        );
+        if samples.recent() == Some(sample) {
+            self.repeats = self.repeats.saturating_add(1);
+        } else {
+            self.repeats = 1;
+        }
+        // once the whole buffer is the same sample, writing it again changes
+        // nothing
+        if self.repeats <= CPS_SAMPLES {
+            SAMPLES_REVISION.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        }
+        samples.push(sample);
+        self.avg
+            .store(samples.per_second(), core::sync::atomic::Ordering::Relaxed);
     }
 }
 
@@ -49,21 +108,23 @@ impl Cps {
 pub async fn cps_task(mut cps: Cps) {
     let mut ticker = Ticker::every(CPS_RATE);
 
-    let mut last = 0u32;
+    // the total can start from a count loaded from flash
+    let mut total = MonotonicCounter::new(cps.total.load(core::sync::atomic::Ordering::Relaxed));
 
     loop {
         let current = cps.total.load(core::sync::atomic::Ordering::Relaxed);
-        let diff = current - last;
+        let diff = SYNCED.lock(|s| s.borrow_mut().tick(total.delta_since(current)));
 
         cps.sample(diff as u8).await;
+        if HISTORY.lock(|h| h.borrow_mut().tick(diff)) {
+            SAMPLES_REVISION.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        }
 
         // defmt::debug!("kp: {}, tot: {}",
         //        AVERAGE_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed),
         //               current
         // );
 
-        last = current;
-
         ticker.next().await;
     }
 }
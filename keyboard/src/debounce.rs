@@ -0,0 +1,168 @@
+//! Debouncing each key on its own, with thresholds that learn from chatter.
+//!
+//! A worn or dirty switch can open for long enough in the middle of a press
+//! to get through the debouncer, turning one press into two. Each key's
+//! threshold starts at `DEBOUNCER_TICKS` and goes up when it chatters, see
+//! [`keyboard_shared::DebouncedKey`] for how.
+//!
+//! Thresholds only last until the keyboard restarts.
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+use keyberon::layout::Event;
+use keyboard_shared::{to_logical, DebounceAdjustment, DebouncedKey, MAX_DEBOUNCE_ADJUSTMENTS};
+
+use crate::{
+    hostlog,
+    layout::{COLS, COLS_PER_SIDE, ROWS},
+    matrix::MatrixState,
+    DEBOUNCER_TICKS,
+};
+
+/// Most events handed out from one scan, any more are held for the next
+pub const MAX_EVENTS: usize = 8;
+
+/// Debounces one half's matrix, raw scans go in and key events come out
+pub struct KeyDebouncer {
+    keys: [[DebouncedKey; COLS_PER_SIDE]; ROWS],
+    /// From this half's coordinates to the layout, for publishing the
+    /// thresholds
+    to_layout: fn(u8, u8) -> (u8, u8),
+}
+
+impl KeyDebouncer {
+    pub fn new(to_layout: fn(u8, u8) -> (u8, u8)) -> Self {
+        Self {
+            keys: [[DebouncedKey::new(DEBOUNCER_TICKS); COLS_PER_SIDE]; ROWS],
+            to_layout,
+        }
+    }
+
+    /// Feed in a scan of the matrix, returning the presses and releases that
+    /// have held for long enough. They're in this half's coordinates, where
+    /// the keys sit rather than how they're wired, see [`to_logical`].
+    pub fn events(&mut self, state: &MatrixState, now_ms: u64) -> heapless::Vec<Event, MAX_EVENTS> {
+        let mut events = heapless::Vec::new();
+
+        for (row, (keys, raw)) in self.keys.iter_mut().zip(state).enumerate() {
+            for (col, (key, &raw)) in keys.iter_mut().zip(raw).enumerate() {
+                let (row, col) = to_logical(row as u8, col as u8);
+                let (edge, changed) = key.scan(raw, now_ms, !events.is_full());
+
+                match edge {
+                    Some(true) => {
+                        let _ = events.push(Event::Press(row, col));
+                    }
+                    Some(false) => {
+                        let _ = events.push(Event::Release(row, col));
+                    }
+                    None => {}
+                }
+
+                if changed {
+                    let (row, col) = (self.to_layout)(row, col);
+                    publish(row, col, key.ticks(), key.chatters());
+                }
+            }
+        }
+
+        events
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const DEFAULT_TICKS: AtomicU16 = AtomicU16::new(DEBOUNCER_TICKS);
+#[allow(clippy::declare_interior_mutable_const)]
+const DEFAULT_TICKS_ROW: [AtomicU16; COLS] = [DEFAULT_TICKS; COLS];
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU16 = AtomicU16::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_ROW: [AtomicU16; COLS] = [ZERO; COLS];
+
+/// Threshold of each key of the layout in scans. Each half fills in its own
+/// keys, and the left half also gets the right half's over the link.
+static TICKS: [[AtomicU16; COLS]; ROWS] = [DEFAULT_TICKS_ROW; ROWS];
+/// Chatter seen on each key of the layout
+static CHATTERS: [[AtomicU16; COLS]; ROWS] = [ZERO_ROW; ROWS];
+
+struct Adjustment {
+    row: u8,
+    col: u8,
+    ticks: u16,
+    at: Instant,
+}
+
+static ADJUSTMENTS: Mutex<
+    ThreadModeRawMutex,
+    RefCell<heapless::Deque<Adjustment, MAX_DEBOUNCE_ADJUSTMENTS>>,
+> = Mutex::new(RefCell::new(heapless::Deque::new()));
+
+/// Record the threshold and chatter count of a key of the layout, noting an
+/// adjustment if the threshold moved
+pub fn publish(row: u8, col: u8, ticks: u16, chatters: u16) {
+    let (Some(t), Some(c)) = (
+        TICKS.get(row as usize).and_then(|r| r.get(col as usize)),
+        CHATTERS.get(row as usize).and_then(|r| r.get(col as usize)),
+    ) else {
+        return;
+    };
+
+    c.store(chatters, Ordering::Relaxed);
+    if t.swap(ticks, Ordering::Relaxed) == ticks {
+        return;
+    }
+
+    defmt::info!("debounce for ({}, {}) now {} scans", row, col, ticks);
+    hostlog!(Info, "debounce for ({}, {}) now {} scans", row, col, ticks);
+    ADJUSTMENTS.lock(|a| {
+        let mut a = a.borrow_mut();
+        if a.is_full() {
+            a.pop_front();
+        }
+        let _ = a.push_back(Adjustment {
+            row,
+            col,
+            ticks,
+            at: Instant::now(),
+        });
+    });
+}
+
+/// Threshold and chatter count of a key of the layout
+pub fn key(row: usize, col: usize) -> (u16, u16) {
+    (
+        TICKS[row][col].load(Ordering::Relaxed),
+        CHATTERS[row][col].load(Ordering::Relaxed),
+    )
+}
+
+/// Thresholds and chatter counts of a row of the layout
+pub fn row(row: usize) -> ([u16; COLS], [u16; COLS]) {
+    (
+        core::array::from_fn(|col| TICKS[row][col].load(Ordering::Relaxed)),
+        core::array::from_fn(|col| CHATTERS[row][col].load(Ordering::Relaxed)),
+    )
+}
+
+/// The latest adjustments, oldest first
+pub fn adjustments() -> (u8, [DebounceAdjustment; MAX_DEBOUNCE_ADJUSTMENTS]) {
+    let mut out = [DebounceAdjustment::EMPTY; MAX_DEBOUNCE_ADJUSTMENTS];
+
+    ADJUSTMENTS.lock(|a| {
+        let a = a.borrow();
+        for (out, a) in out.iter_mut().zip(a.iter()) {
+            *out = DebounceAdjustment {
+                row: a.row,
+                col: a.col,
+                scans: a.ticks,
+                secs_ago: a.at.elapsed().as_secs() as u32,
+            };
+        }
+        (a.len() as u8, out)
+    })
+}
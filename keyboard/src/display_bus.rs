@@ -0,0 +1,106 @@
+//! Timing of display flushes over the I2C bus.
+//!
+//! Both halves drive their display well past the 400kHz the nRF52840
+//! documents for the TWIM, see [`twim_frequency`]. Every flush is timed so the
+//! clock the bus actually manages can be checked, along with how much of each
+//! second it spends busy.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
+
+use embassy_nrf::pac;
+use embassy_time::{Duration, Ticker};
+use futures::StreamExt;
+use keyboard_shared::DisplayBusStats;
+
+/// Display bus clock on the left half
+pub const LEFT_BUS_KHZ: u32 = 609;
+/// Display bus clock on the right half
+pub const RIGHT_BUS_KHZ: u32 = 800;
+
+/// Bus utilisation past which display traffic starts visibly delaying other
+/// work
+pub const SATURATED_PCT: u8 = 50;
+
+/// Clocks in a full frame flush: 512 bytes of pixels written 16 at a time,
+/// each write led by the address and a control byte, plus the commands that
+/// set the window. Every byte takes 9 clocks with its ack.
+const FULL_FRAME_BITS: u32 = (512 + 32 * 2 + 18) * 9;
+
+static LAST_FLUSH_US: AtomicU32 = AtomicU32::new(0);
+static AVG_FLUSH_US: AtomicU32 = AtomicU32::new(0);
+static MAX_FLUSH_US: AtomicU32 = AtomicU32::new(0);
+static KHZ: AtomicU16 = AtomicU16::new(0);
+static BUSY_US: AtomicU32 = AtomicU32::new(0);
+static BUSY_PCT: AtomicU8 = AtomicU8::new(0);
+static SATURATED: AtomicBool = AtomicBool::new(false);
+
+/// Value for the TWIM `FREQUENCY` register to clock the bus at `khz`.
+///
+/// The register is a fractional divider with the documented 400kHz setting
+/// at `0x0640_0000`, so it goes up in steps of 2^18 per kHz. Only 100, 250
+/// and 400kHz are listed, which is why embassy's `Frequency` enum stops there.
+pub const fn twim_frequency(khz: u32) -> u32 {
+    khz << 18
+}
+
+/// Clock the display's TWIM at `khz`, call this after creating the `Twim` and
+/// before the first transfer
+pub fn overdrive(khz: u32) {
+    // SAFETY: the display is the only thing on TWIM0, and nothing is using it
+    // yet
+    unsafe {
+        (*pac::TWIM0::ptr())
+            .frequency
+            .write(|w| w.bits(twim_frequency(khz)));
+    }
+}
+
+/// Record a flush that took `took`, `full_frame` when every pixel was sent
+pub fn record(took: Duration, full_frame: bool) {
+    let us = took.as_micros() as u32;
+
+    LAST_FLUSH_US.store(us, Ordering::Relaxed);
+    MAX_FLUSH_US.fetch_max(us, Ordering::Relaxed);
+    BUSY_US.fetch_add(us, Ordering::Relaxed);
+
+    let avg = AVG_FLUSH_US.load(Ordering::Relaxed);
+    let avg = if avg == 0 { us } else { avg - avg / 8 + us / 8 };
+    AVG_FLUSH_US.store(avg, Ordering::Relaxed);
+
+    if full_frame && us > 0 {
+        let khz = (FULL_FRAME_BITS * 1000 / us).min(u16::MAX as u32);
+        KHZ.store(khz as u16, Ordering::Relaxed);
+    }
+}
+
+pub fn stats() -> DisplayBusStats {
+    DisplayBusStats {
+        last_flush_us: LAST_FLUSH_US.load(Ordering::Relaxed),
+        avg_flush_us: AVG_FLUSH_US.load(Ordering::Relaxed),
+        max_flush_us: MAX_FLUSH_US.load(Ordering::Relaxed),
+        busy_pct: BUSY_PCT.load(Ordering::Relaxed),
+        khz: KHZ.load(Ordering::Relaxed),
+        saturated: SATURATED.load(Ordering::Relaxed),
+    }
+}
+
+/// Turn the time spent flushing into a utilisation once a second
+#[embassy_executor::task]
+pub async fn display_bus_task() {
+    let period = Duration::from_secs(1);
+    let mut ticker = Ticker::every(period);
+
+    loop {
+        ticker.next().await;
+
+        let busy = BUSY_US.swap(0, Ordering::Relaxed) as u64;
+        let pct = (busy * 100 / period.as_micros()).min(100) as u8;
+        BUSY_PCT.store(pct, Ordering::Relaxed);
+
+        let saturated = pct > SATURATED_PCT;
+        if saturated && !SATURATED.load(Ordering::Relaxed) {
+            defmt::warn!("display bus {}% busy", pct);
+        }
+        SATURATED.store(saturated, Ordering::Relaxed);
+    }
+}
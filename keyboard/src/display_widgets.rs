@@ -0,0 +1,327 @@
+//! State and helpers shared by both display screens, so either half can run
+//! either screen.
+
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, AtomicU32},
+};
+
+use atomic_float::AtomicF32;
+use bitvec::{order::Lsb0, view::BitView};
+use embassy_futures::select::select3;
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    channel::Channel,
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant};
+use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::{DrawTarget, Point},
+    Drawable, Pixel,
+};
+use keyboard_shared::{transform_pair, FrameTransform};
+
+use crate::{
+    cps::SampleBuffer,
+    event::Event,
+    hostlog,
+    key_grid::KeyGridDisplay,
+    layout::COLS_PER_SIDE,
+    legend_display::LegendDisplay,
+    lhs_display::LHSDisplay,
+    oled::{self, Oled},
+    rhs_display::RHSDisplay,
+};
+
+#[derive(defmt::Format)]
+pub struct DisplayOverride {
+    pub row: u8,
+    pub data_0: [u8; 4],
+    pub data_1: [u8; 4],
+}
+
+/// Host supplied pixels for this half's display
+#[derive(defmt::Format)]
+pub enum HostPixels {
+    /// Two rows for the staging buffer
+    Rows(DisplayOverride),
+    /// Show the staged rows, ending the frame
+    Flush,
+    /// Throw away the staged rows, for a frame that didn't arrive intact
+    Discard,
+}
+
+// the host's frames are checked against the shared display size
+const _: () = assert!(oled::ROWS == keyboard_shared::DISPLAY_ROWS);
+
+impl DisplayOverride {
+    /// Whether both of the rows starting at `row` are on the display, anything
+    /// from the host should be checked before it's queued
+    pub fn row_in_bounds(row: u8) -> bool {
+        keyboard_shared::row_in_bounds(row)
+    }
+}
+
+pub static TOTAL_KEYPRESSES: AtomicU32 = AtomicU32::new(0);
+pub static AVERAGE_KEYPRESSES: AtomicF32 = AtomicF32::new(0.0);
+pub static KEYPRESS_EVENT: Event = Event::new();
+pub static OVERRIDE_CHAN: Channel<ThreadModeRawMutex, HostPixels, 256> = Channel::new();
+/// Host pixel writes that were turned away for being out of bounds, on
+/// either half
+pub static REJECTED_PIXEL_WRITES: AtomicU32 = AtomicU32::new(0);
+
+pub fn rejected_pixel_write(row: u8) {
+    defmt::warn!("rejected pixels for out of bounds row {}", row);
+    hostlog!(Warn, "rejected pixels for out of bounds row {}", row);
+    REJECTED_PIXEL_WRITES.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Run the other half's screen on this half's display
+static DISPLAY_SWAP: AtomicBool = AtomicBool::new(false);
+/// Show the active layer's legend on both displays
+static LAYER_LEGEND: AtomicBool = AtomicBool::new(false);
+/// Show every key of both halves on both displays, over the layer legend
+static KEY_GRID: AtomicBool = AtomicBool::new(false);
+static DISPLAY_MODE_CHANGED: Event = Event::new();
+/// Applied to host supplied pixels on this half
+static FRAME_TRANSFORM: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<FrameTransform>> =
+    blocking_mutex::Mutex::new(Cell::new(FrameTransform::NONE));
+static STAGING: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Staging>> =
+    blocking_mutex::Mutex::new(RefCell::new(Staging::new()));
+
+/// How long a frame's rows wait for its `Flush` before they're thrown away,
+/// so a host that stops part way through never gets half a frame shown
+const FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub fn set_display_swap(swap: bool) {
+    if DISPLAY_SWAP.swap(swap, core::sync::atomic::Ordering::Relaxed) != swap {
+        DISPLAY_MODE_CHANGED.set();
+    }
+}
+
+pub fn set_layer_legend(show: bool) {
+    if LAYER_LEGEND.swap(show, core::sync::atomic::Ordering::Relaxed) != show {
+        DISPLAY_MODE_CHANGED.set();
+    }
+}
+
+pub fn set_key_grid(show: bool) {
+    if KEY_GRID.swap(show, core::sync::atomic::Ordering::Relaxed) != show {
+        DISPLAY_MODE_CHANGED.set();
+    }
+}
+
+pub fn key_grid_shown() -> bool {
+    KEY_GRID.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+/// The screen after the one given by `display_swap`, `layer_legend` and
+/// `key_grid`: the usual screens, then them swapped, then the layer legend,
+/// then the key grid
+pub fn next_screen(display_swap: bool, layer_legend: bool, key_grid: bool) -> (bool, bool, bool) {
+    match (display_swap, layer_legend, key_grid) {
+        (_, _, true) => (false, false, false),
+        (_, true, false) => (false, false, true),
+        (true, false, false) => (false, true, false),
+        (false, false, false) => (true, false, false),
+    }
+}
+
+pub fn set_frame_transform(transform: FrameTransform) {
+    FRAME_TRANSFORM.lock(|t| t.set(transform));
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DisplayRole {
+    /// The bongo cat, normally on the left
+    Bongo,
+    /// Keypress stats, normally on the right
+    Stats,
+}
+
+impl DisplayRole {
+    fn swapped(self) -> Self {
+        match self {
+            DisplayRole::Bongo => DisplayRole::Stats,
+            DisplayRole::Stats => DisplayRole::Bongo,
+        }
+    }
+}
+
+/// Run whichever screen this half should be showing, switching over when the
+/// display settings change
+pub async fn run_display(
+    oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    sample_buffer: &'static Mutex<ThreadModeRawMutex, SampleBuffer>,
+    native: DisplayRole,
+) -> ! {
+    let mut bongo = LHSDisplay::new(oled);
+    let mut stats = RHSDisplay::new(oled, sample_buffer);
+    // the bongo cat lives on the left half
+    let first_col = match native {
+        DisplayRole::Bongo => 0,
+        DisplayRole::Stats => COLS_PER_SIDE,
+    };
+    let mut legend = LegendDisplay::new(oled, first_col);
+    let mut grid = KeyGridDisplay::new(oled);
+
+    // each screen draws as soon as it starts, so restarting it on a wake
+    // gets a fresh frame up before the display comes back on
+    loop {
+        if key_grid_shown() {
+            select3(
+                grid.run(),
+                DISPLAY_MODE_CHANGED.wait(),
+                oled::WAKE_REDRAW.wait(),
+            )
+            .await;
+            continue;
+        }
+
+        if LAYER_LEGEND.load(core::sync::atomic::Ordering::Relaxed) {
+            select3(
+                legend.run(),
+                DISPLAY_MODE_CHANGED.wait(),
+                oled::WAKE_REDRAW.wait(),
+            )
+            .await;
+            continue;
+        }
+
+        let role = if DISPLAY_SWAP.load(core::sync::atomic::Ordering::Relaxed) {
+            native.swapped()
+        } else {
+            native
+        };
+
+        match role {
+            DisplayRole::Bongo => {
+                select3(
+                    bongo.run(),
+                    DISPLAY_MODE_CHANGED.wait(),
+                    oled::WAKE_REDRAW.wait(),
+                )
+                .await;
+            }
+            DisplayRole::Stats => {
+                select3(
+                    stats.run(),
+                    DISPLAY_MODE_CHANGED.wait(),
+                    oled::WAKE_REDRAW.wait(),
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Stage host supplied pixels, along with any others that are already queued,
+/// and show them once their frame is flushed
+pub async fn read_in_overrides(
+    oled: &Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    initial: HostPixels,
+) {
+    let transform = FRAME_TRANSFORM.lock(|t| t.get());
+    let mut next = Some(initial);
+    let mut flush = false;
+
+    STAGING.lock(|s| {
+        let mut staging = s.borrow_mut();
+        while let Some(pixels) = next.take().or_else(|| OVERRIDE_CHAN.try_recv().ok()) {
+            match pixels {
+                HostPixels::Rows(o) => staging.stage(&o, transform),
+                HostPixels::Discard => staging.discard(),
+                HostPixels::Flush => {
+                    // anything after this is the next frame
+                    flush = true;
+                    break;
+                }
+            }
+        }
+    });
+
+    if !flush {
+        return;
+    }
+
+    let mut oled = oled.lock().await;
+    let committed = STAGING.lock(|s| {
+        let mut staging = s.borrow_mut();
+        let mut committed = false;
+        oled.draw_no_clear_no_flush(|d| committed = staging.commit(d));
+        committed
+    });
+    if committed {
+        let _ = oled.flush().await;
+    }
+}
+
+/// Rows of the frame the host is part way through sending, already
+/// transformed for this half's display
+struct Staging {
+    rows: [[u8; 4]; oled::ROWS],
+    /// Bit `n` is set once row `n` has been written
+    written: u128,
+    started: Option<Instant>,
+}
+
+impl Staging {
+    const fn new() -> Self {
+        Self {
+            rows: [[0; 4]; oled::ROWS],
+            written: 0,
+            started: None,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        matches!(self.started, Some(started) if started.elapsed() > FRAME_TIMEOUT)
+    }
+
+    fn discard(&mut self) {
+        self.written = 0;
+        self.started = None;
+    }
+
+    fn stage(&mut self, o: &DisplayOverride, transform: FrameTransform) {
+        if self.expired() {
+            defmt::warn!("discarding a host frame that was never flushed");
+            self.discard();
+        }
+        self.started.get_or_insert_with(Instant::now);
+
+        let (row, rows) = transform_pair(transform, o.row, [o.data_0, o.data_1]);
+        for (offset, data) in rows.into_iter().enumerate() {
+            let row = row as usize + offset;
+            if let Some(staged) = self.rows.get_mut(row) {
+                *staged = data;
+                self.written |= 1 << row;
+            }
+        }
+    }
+
+    /// Draw the staged rows, returning whether there were any to draw
+    fn commit<D: DrawTarget<Color = BinaryColor>>(&mut self, d: &mut D) -> bool {
+        if self.expired() {
+            defmt::warn!("discarding a host frame that was flushed too late");
+            self.discard();
+            return false;
+        }
+
+        let written = self.written;
+        for (row, data) in self.rows.iter().enumerate() {
+            if written & (1 << row) == 0 {
+                continue;
+            }
+
+            for (col, pix) in data.view_bits::<Lsb0>().into_iter().enumerate() {
+                let _ = Pixel(Point::new(col as i32, row as i32), BinaryColor::from(*pix)).draw(d);
+            }
+        }
+
+        self.discard();
+        written != 0
+    }
+}
@@ -0,0 +1,81 @@
+//! Temporal dithering for the LEDs. Gamma correction squashes the bottom of
+//! the 8 bit range down to a handful of levels, so at low brightness whole
+//! channels round away to nothing and colours shift. Colours are carried as
+//! the colour at full value and a 16 bit value until after gamma correction,
+//! and the part of a level that doesn't fit in 8 bits is carried over to the
+//! next frame, so each channel averages out to the right level instead. The
+//! maths is in [`keyboard_shared::LedGamma`] and
+//! [`keyboard_shared::dither_led`].
+
+use cichlid::HSV;
+use keyboard_shared::dither_led;
+use nrf_smartled::RGB8;
+
+use crate::leds::MAX_LEDS;
+
+/// A colour on its way to the LEDs, as the colour at full value and the
+/// value to show it at
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Light {
+    pub colour: RGB8,
+    pub value: u16,
+}
+
+impl Light {
+    /// Roughly what this looks like in 8 bits, for mixing with other
+    /// colours
+    pub fn to_rgb8(self) -> RGB8 {
+        let scale = |c: u8| (c as u32 * self.value as u32 / u16::MAX as u32) as u8;
+        RGB8::new(
+            scale(self.colour.r),
+            scale(self.colour.g),
+            scale(self.colour.b),
+        )
+    }
+}
+
+impl From<RGB8> for Light {
+    fn from(colour: RGB8) -> Self {
+        Self {
+            colour,
+            value: u16::MAX,
+        }
+    }
+}
+
+impl From<HSV> for Light {
+    fn from(hsv: HSV) -> Self {
+        // converted at full value so the hue isn't rounded away when dim
+        let c = HSV { v: 255, ..hsv }.to_rgb_rainbow();
+        Self {
+            colour: RGB8::new(c.r, c.g, c.b),
+            value: hsv.v as u16 * 257,
+        }
+    }
+}
+
+/// The error each channel of each LED has built up, in 1/2^24ths of a level
+pub struct Dither {
+    error: [[u32; 3]; MAX_LEDS],
+}
+
+impl Dither {
+    pub const fn new() -> Self {
+        Self {
+            error: [[0; 3]; MAX_LEDS],
+        }
+    }
+
+    /// Reduce linear light to 8 bits for the LED at `idx`, keeping the
+    /// remainder for the next frame
+    pub fn apply(&mut self, idx: usize, linear: [u32; 3]) -> RGB8 {
+        let [r, g, b] = dither_led(&mut self.error[idx], linear);
+        RGB8::new(r, g, b)
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -15,4 +15,9 @@ impl Event {
     pub fn set(&self) {
         self.0.signal(());
     }
+
+    /// Forget a `set` nobody has waited for yet
+    pub fn reset(&self) {
+        self.0.reset();
+    }
 }
@@ -0,0 +1,43 @@
+//! Letting a lone key event skip the queue.
+//!
+//! Normally a scan's events go onto a channel for the event task, which
+//! hands them to the layout, and the report goes out on the layout's next
+//! tick. That's a couple of task switches and up to a tick of waiting. When
+//! a scan produces just one event for a key that nothing waits on, the poll
+//! task can apply it itself and wake the tick straight away.
+//!
+//! Both paths go through the same code with the layout locked, and anything
+//! already queued is applied first, so an event is applied once whichever
+//! way it goes and never overtakes one before it.
+//!
+//! Waking the tick early would let ticks come closer together than a
+//! millisecond and hold-taps time out sooner, so the early tick takes the
+//! place of the next one, see [`keyboard_shared::TickPacer`]. Presses that
+//! took the fast path land in the low buckets of the latency histogram, see
+//! `keyboard_control bench-latency --typed`.
+
+use keyberon::layout::Event;
+use keyboard_shared::lone_event;
+
+use crate::{
+    event,
+    layout::{ACTION_CHORDS, CHORDS},
+    system_state::LOCK_KEYS,
+};
+
+/// Wakes the layout tick early, once an event has been applied
+pub static TICK_NOW: event::Event = event::Event::new();
+
+/// Whether anything waits to see what follows a press of this key: a chord,
+/// an action chord or the lock combo
+pub fn waits_on(coord: (u8, u8)) -> bool {
+    CHORDS.iter().any(|c| c.def.1.contains(&coord))
+        || ACTION_CHORDS.iter().any(|c| c.keys.contains(&coord))
+        || LOCK_KEYS.contains(&coord)
+}
+
+/// The event of a scan that can take the fast path, if there's exactly one
+/// and nothing waits on its key
+pub fn single(events: &[Event]) -> Option<Event> {
+    lone_event(events, |event| waits_on(event.coord()))
+}
@@ -0,0 +1,59 @@
+//! Erasing and writing the internal flash without holding everything else
+//! up.
+//!
+//! The CPU stalls while the NVMC erases or writes, as the firmware runs from
+//! the same flash. Erasing a page takes around 85ms, long enough to drop key
+//! events and link bytes, so it's done with partial erases of a millisecond
+//! each instead, letting the other tasks run in between. Writes are split
+//! into short runs of words for the same reason.
+
+use embassy_futures::yield_now;
+use embassy_nrf::{nvmc::Nvmc, pac};
+use embedded_storage::nor_flash::NorFlash;
+
+/// Each partial erase, in milliseconds
+const PARTIAL_ERASE_MS: u8 = 1;
+/// How long a page takes to erase, partial erases add up to this
+const PAGE_ERASE_MS: u32 = 85;
+const PAGE_SIZE: u32 = 4096;
+/// Bytes written between letting other tasks run, each word takes ~41us
+const WRITE_RUN: usize = 32;
+
+pub trait AsyncFlash: NorFlash {
+    /// Like `NorFlash::erase`, but letting other tasks run while it goes
+    async fn erase_async(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+
+    /// Like `NorFlash::write`, but letting other tasks run while it goes
+    async fn write_async(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        for (idx, run) in bytes.chunks(WRITE_RUN).enumerate() {
+            self.write(offset + (idx * WRITE_RUN) as u32, run)?;
+            yield_now().await;
+        }
+        Ok(())
+    }
+}
+
+impl<'d> AsyncFlash for Nvmc<'d> {
+    async fn erase_async(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        // let the driver check the range, erasing nothing
+        self.erase(from, from)?;
+
+        // SAFETY: the driver is borrowed mutably, so nothing else is using
+        // the NVMC, and it's left read only as the driver expects
+        let nvmc = unsafe { &*pac::NVMC::ptr() };
+        for page in (from..to).step_by(PAGE_SIZE as usize) {
+            let mut erased_ms = 0;
+            while erased_ms < PAGE_ERASE_MS {
+                nvmc.config.write(|w| w.wen().een());
+                nvmc.erasepagepartialcfg
+                    .write(|w| unsafe { w.duration().bits(PARTIAL_ERASE_MS) });
+                nvmc.erasepagepartial.write(|w| unsafe { w.bits(page) });
+                while nvmc.ready.read().ready().is_busy() {}
+                nvmc.config.write(|w| w.wen().ren());
+                erased_ms += PARTIAL_ERASE_MS as u32;
+                yield_now().await;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! The heap, and allocating from it without resetting the keyboard when it
+//! runs short.
+//!
+//! The only things allocated are the link's ack waiters. Allocations go
+//! through [`try_arc`], which turns down any that would leave less than
+//! `RESERVE` bytes free. The caller then waits for room or does without
+//! whatever the allocation was for, and the refusal is counted in
+//! [`oom_averted`]. The
+//! reserve also leaves slack for fragmentation, since the free space
+//! needn't be in one piece, so the `oom` handler should never be reached.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    alloc::Layout,
+    mem::{size_of, MaybeUninit},
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+};
+
+use alloc_cortex_m::CortexMHeap;
+use keyboard_shared::leaves_reserve;
+
+const HEAP_SIZE: usize = 8192;
+/// Bytes an allocation has to leave free to go ahead
+const RESERVE: usize = 1024;
+
+#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+
+/// Most bytes of the heap in use at once
+static HIGH_WATER: AtomicU16 = AtomicU16::new(0);
+/// Allocations turned down for leaving too little free
+static OOM_AVERTED: AtomicU32 = AtomicU32::new(0);
+
+pub fn init_heap() {
+    static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+    unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
+}
+
+#[alloc_error_handler]
+fn oom(_: Layout) -> ! {
+    panic!("oom");
+}
+
+/// Whether `bytes` can be allocated and still leave the reserve, counting it
+/// as averted if not
+fn reserve(bytes: usize) -> bool {
+    if leaves_reserve(ALLOCATOR.free(), bytes, RESERVE) {
+        true
+    } else {
+        OOM_AVERTED.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+fn note_used() {
+    HIGH_WATER.fetch_max(ALLOCATOR.used() as u16, Ordering::Relaxed);
+}
+
+/// `Arc::new`, or `None` if the heap is too full
+pub fn try_arc<T>(value: T) -> Option<Arc<T>> {
+    // the value sits after the strong and weak counts
+    if !reserve(size_of::<T>() + 2 * size_of::<usize>()) {
+        return None;
+    }
+
+    let arc = Arc::new(value);
+    note_used();
+    Some(arc)
+}
+
+/// Allocate and free `bytes`, returning whether the allocation went ahead.
+/// For checking on hardware that a big allocation is turned down cleanly.
+pub fn try_alloc(bytes: usize) -> bool {
+    if !reserve(bytes) {
+        return false;
+    }
+
+    // black_box so the unused allocation isn't optimised out
+    let buf = core::hint::black_box(Vec::<u8>::with_capacity(bytes));
+    note_used();
+    drop(buf);
+    true
+}
+
+/// Most bytes of the heap in use at once since boot
+pub fn high_water() -> u16 {
+    HIGH_WATER.load(Ordering::Relaxed)
+}
+
+pub fn oom_averted() -> u32 {
+    OOM_AVERTED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,541 @@
+//! Handling of messages from the host, run by the left half which owns the
+//! USB connection. Anything for the right half is forwarded over the link.
+//! What goes where is worked out by `HostRouter`, this carries it out.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, mutex::Mutex};
+use embassy_time::Timer;
+
+#[cfg(feature = "link-capture")]
+use crate::link_capture;
+#[cfg(not(feature = "link-capture"))]
+use crate::messages::CAPTURE_CHUNK;
+use crate::{
+    bootloader, channel_stats, clock, debounce, display_bus,
+    display_widgets::{self, DisplayOverride, HostPixels, TOTAL_KEYPRESSES},
+    heap, jiggle, keypress_store,
+    layout::{NUM_CHORDS, N_LAYERS, ROWS},
+    leds::{clear_key_leds, set_key_led, set_test_colour, set_test_led},
+    link_health, log_level,
+    matrix::{PHANTOM_PRESSES, REMOTE_PHANTOM_PRESSES},
+    messages::{
+        link_errors, CommandQueue, Conditions, DomToSub, HostRouter, HostToKeyboard, KeyboardSide,
+        KeyboardToHost, SendPolicy, SettingsExporter, SettingsImporter, Step, UsageEntry,
+        UsageKind, ACK_QUEUE_HIGH_WATER, COMMAND_QUEUE_HIGH_WATER, CORRUPT_FRAMES, FAILED_SENDS,
+        KEY_COLS, LINK_READ_ERRORS, LINK_RESYNCS, RETRANSMITS, USAGE_CHUNK,
+    },
+    oled::{self, remote_interacted, Oled},
+    profiling::CPU_BUSY_PCT,
+    remap, report_mirror, safe_mode, self_test, settings,
+    system_state::{status_report, SYSTEM_STATE},
+    telemetry::{
+        key_presses, latency, layer_usage, light_latency, presses_by_source, CHORD_FIRES,
+        CHORD_NEAR_MISSES, HOLD_TAP_STATS, MAX_HOLD_TAPS, REMOTE_CHORD_FIRES,
+        REMOTE_CHORD_NEAR_MISSES,
+    },
+    tuning, version_check,
+};
+
+/// Commands on their way to the right half
+pub type CommandChannel = CommandQueue<DomToSub, 4>;
+/// Replies on their way to the host
+pub type ReplyChannel = CommandQueue<KeyboardToHost, 16>;
+/// A key press from the host, `side` is the half that the event enters
+#[cfg(feature = "inject-keys")]
+pub struct Injection {
+    pub side: KeyboardSide,
+    pub row: u8,
+    pub col: u8,
+    pub duration_ms: u16,
+}
+
+#[cfg(feature = "inject-keys")]
+pub type InjectChannel = Channel<ThreadModeRawMutex, Injection, 4>;
+
+/// Everything outside of the dispatcher that host commands act on
+pub struct DispatchCtx<'a> {
+    /// The half the host is talking to
+    pub side: KeyboardSide,
+    pub oled: &'a Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    pub commands: &'a CommandChannel,
+    /// Sets a piece of the right half's state, see `DomToSub::state`
+    pub peer_state: fn((DomToSub, SendPolicy)),
+    pub replies: &'a ReplyChannel,
+    #[cfg(feature = "inject-keys")]
+    pub inject: &'a InjectChannel,
+}
+
+/// State the host has set up that only lasts while it keeps talking to us
+pub struct HostSession {
+    router: HostRouter,
+    importer: SettingsImporter,
+    exporter: SettingsExporter,
+}
+
+impl HostSession {
+    pub const fn new() -> Self {
+        Self {
+            router: HostRouter::new(),
+            importer: SettingsImporter::new(),
+            exporter: SettingsExporter::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.router.is_active()
+    }
+
+    /// Undo anything the host left running
+    pub async fn end(&mut self, ctx: &DispatchCtx<'_>) {
+        for cmd in self.router.end() {
+            handle_command(cmd, ctx, self).await;
+        }
+    }
+}
+
+impl Default for HostSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn reply(ctx: &DispatchCtx<'_>, msg: KeyboardToHost) {
+    ctx.replies.send((msg, SendPolicy::BULK)).await;
+}
+
+async fn reply_tuning(ctx: &DispatchCtx<'_>) {
+    let tuning = tuning::get();
+    let saved = settings::get().tuning == tuning;
+    reply(ctx, KeyboardToHost::Tuning { tuning, saved }).await;
+}
+
+async fn reply_hold_tap(ctx: &DispatchCtx<'_>, layer: u8, row: u8, col: u8) {
+    let msg = match tuning::hold_tap(layer, row, col) {
+        Some((behaviour, overridden)) => KeyboardToHost::HoldTap {
+            layer,
+            row,
+            col,
+            behaviour,
+            overridden,
+        },
+        None => KeyboardToHost::HoldTapRejected,
+    };
+    reply(ctx, msg).await;
+}
+
+/// Carry out the steps `HostRouter` works out for a command
+pub async fn handle_command(cmd: HostToKeyboard, ctx: &DispatchCtx<'_>, session: &mut HostSession) {
+    let now = Conditions {
+        link_up: link_health::up(),
+        link_degraded: link_health::degraded(),
+        tuning: tuning::get(),
+        saved_tuning: settings::get().tuning,
+    };
+
+    for step in session.router.route(&cmd, now) {
+        match step {
+            Step::Local => handle_locally(&cmd, ctx, session).await,
+            Step::UseTuning(tuning) => tuning::set(tuning),
+            Step::Draw {
+                row,
+                rows: [data_0, data_1],
+            } => {
+                display_widgets::OVERRIDE_CHAN
+                    .send(HostPixels::Rows(DisplayOverride {
+                        row,
+                        data_0,
+                        data_1,
+                    }))
+                    .await;
+                remote_interacted();
+            }
+            Step::Flush => display_widgets::OVERRIDE_CHAN.send(HostPixels::Flush).await,
+            Step::Discard => {
+                display_widgets::OVERRIDE_CHAN
+                    .send(HostPixels::Discard)
+                    .await
+            }
+            Step::RejectedPixels { row } => display_widgets::rejected_pixel_write(row),
+            Step::DroppedPixels => link_health::dropped_pixel_write(),
+            Step::Peer(cmd, traffic) => {
+                let cmd = (cmd, SendPolicy::from(traffic));
+                if cmd.0.state().is_some() {
+                    (ctx.peer_state)(cmd);
+                } else {
+                    ctx.commands.send(cmd).await;
+                }
+            }
+            Step::Reply(msg) => {
+                if let KeyboardToHost::FrameNack { side, frame_id } = msg {
+                    defmt::warn!("dropping frame {} for the {} display", frame_id, side);
+                }
+                reply(ctx, msg).await;
+            }
+            Step::ReplyTuning => reply_tuning(ctx).await,
+        }
+    }
+}
+
+/// The part of a command this half handles itself, after it's been routed
+async fn handle_locally(cmd: &HostToKeyboard, ctx: &DispatchCtx<'_>, session: &mut HostSession) {
+    match *cmd {
+        HostToKeyboard::RequestDisplayInfo { side } => {
+            reply(
+                ctx,
+                KeyboardToHost::DisplayInfo {
+                    side,
+                    width: oled::WIDTH as u8,
+                    height: oled::ROWS as u8,
+                    rotation: settings::get().display_rotation[side as usize],
+                    transform: settings::get().frame_transform[side as usize],
+                },
+            )
+            .await;
+        }
+        HostToKeyboard::RequestStats => send_stats(ctx).await,
+        HostToKeyboard::ReadPixels { row, .. } => {
+            let (data_0, data_1) = ctx.oled.lock().await.read_rows(row);
+            reply(
+                ctx,
+                KeyboardToHost::PixelRow {
+                    row,
+                    data_0,
+                    data_1,
+                },
+            )
+            .await;
+        }
+        HostToKeyboard::Lock(locked) => {
+            SYSTEM_STATE.set_locked(locked);
+        }
+        HostToKeyboard::ShowDebugScreen(show) => {
+            settings::update(|s| s.debug_screen = show);
+        }
+        HostToKeyboard::SetConfig(item) => {
+            settings::update(|s| s.set(item));
+        }
+        HostToKeyboard::SetLedCalibration { side, r, g, b } => {
+            settings::update(|s| s.led_calibration[side as usize] = [r, g, b]);
+        }
+        HostToKeyboard::LedTestColour(colour) => set_test_colour(colour),
+        HostToKeyboard::LedTestIndex { index, .. } => set_test_led(index),
+        HostToKeyboard::SetKeyLed { index, r, g, b, .. } => set_key_led(index, [r, g, b]),
+        HostToKeyboard::ClearKeyLeds => clear_key_leds(),
+        HostToKeyboard::SetKeyAction {
+            layer,
+            row,
+            col,
+            keycode,
+        } => remap::set(layer, row, col, keycode),
+        HostToKeyboard::ClearRemaps => remap::clear(),
+        HostToKeyboard::ExportSettings { offset } => {
+            let chunk = settings::export_chunk(&mut session.exporter, offset);
+            reply(ctx, chunk).await;
+        }
+        HostToKeyboard::ImportSettings {
+            offset,
+            total,
+            len,
+            ref data,
+        } => {
+            let len = (len as usize).min(data.len());
+            let status = settings::import_chunk(&mut session.importer, offset, total, &data[..len]);
+            reply(ctx, KeyboardToHost::SettingsImport { offset, status }).await;
+        }
+        HostToKeyboard::RequestTimingStats => send_timing_stats(ctx).await,
+        HostToKeyboard::RequestUsageStats => send_usage_stats(ctx).await,
+        HostToKeyboard::RequestDebounce => {
+            for row in 0..ROWS {
+                let (scans, chatters) = debounce::row(row);
+                reply(
+                    ctx,
+                    KeyboardToHost::Debounce {
+                        row: row as u8,
+                        scans,
+                        chatters,
+                    },
+                )
+                .await;
+            }
+
+            let (len, adjustments) = debounce::adjustments();
+            reply(
+                ctx,
+                KeyboardToHost::DebounceAdjustments { len, adjustments },
+            )
+            .await;
+        }
+        HostToKeyboard::RequestStatus => reply(ctx, KeyboardToHost::Status(status_report())).await,
+        HostToKeyboard::RequestLatency => {
+            for synthetic in [false, true] {
+                reply(
+                    ctx,
+                    KeyboardToHost::Latency {
+                        synthetic,
+                        buckets: latency(synthetic),
+                    },
+                )
+                .await;
+            }
+            reply(
+                ctx,
+                KeyboardToHost::LightLatency {
+                    buckets: light_latency(),
+                },
+            )
+            .await;
+        }
+        HostToKeyboard::RequestKeyPresses => {
+            for row in 0..ROWS {
+                reply(
+                    ctx,
+                    KeyboardToHost::KeyPresses {
+                        row: row as u8,
+                        counts: key_presses(row),
+                    },
+                )
+                .await;
+            }
+        }
+        #[cfg(feature = "inject-keys")]
+        HostToKeyboard::InjectKey {
+            row,
+            col,
+            duration_ms,
+        } => {
+            let injection = Injection {
+                side: KeyboardSide::Left,
+                row,
+                col,
+                duration_ms,
+            };
+            channel_stats::try_send(ctx.inject, injection, channel_stats::DropChannel::InjectKey);
+        }
+        #[cfg(feature = "inject-keys")]
+        HostToKeyboard::InjectRemoteKey {
+            row,
+            col,
+            duration_ms,
+        } => {
+            let injection = Injection {
+                side: KeyboardSide::Right,
+                row,
+                col,
+                duration_ms,
+            };
+            channel_stats::try_send(ctx.inject, injection, channel_stats::DropChannel::InjectKey);
+        }
+        #[cfg(not(feature = "inject-keys"))]
+        HostToKeyboard::InjectKey { .. } | HostToKeyboard::InjectRemoteKey { .. } => {}
+        HostToKeyboard::SetTime { seconds_of_day } => clock::set_time(seconds_of_day),
+        HostToKeyboard::CommitTuning => {
+            let tuning = tuning::get();
+            settings::update(|s| s.tuning = tuning);
+        }
+        HostToKeyboard::SetHoldTap {
+            layer,
+            row,
+            col,
+            behaviour,
+        } => {
+            if tuning::set_hold_tap(layer, row, col, behaviour) {
+                reply_hold_tap(ctx, layer, row, col).await;
+            } else {
+                reply(ctx, KeyboardToHost::HoldTapRejected).await;
+            }
+        }
+        HostToKeyboard::RequestHoldTap { layer, row, col } => {
+            reply_hold_tap(ctx, layer, row, col).await
+        }
+        HostToKeyboard::SetLogLevel(level) => log_level::set(level),
+        HostToKeyboard::SubscribeReports(on) => report_mirror::subscribe(on),
+        HostToKeyboard::FactoryReset => settings::FACTORY_RESET.set(),
+        HostToKeyboard::PrepareShutdown => keypress_store::SAVE_NOW.set(),
+        HostToKeyboard::EnterBootloader { side } => enter_bootloader(ctx, side).await,
+        HostToKeyboard::Identify => reply(ctx, KeyboardToHost::Identity { side: ctx.side }).await,
+        HostToKeyboard::ForceAllocation { bytes } => {
+            let allocated = heap::try_alloc(bytes as usize);
+            reply(ctx, KeyboardToHost::ForcedAllocation { bytes, allocated }).await;
+        }
+        #[cfg(feature = "link-capture")]
+        HostToKeyboard::ReadCapture { offset } => reply(ctx, link_capture::chunk(offset)).await,
+        #[cfg(feature = "link-capture")]
+        HostToKeyboard::ClearCapture => link_capture::clear(),
+        #[cfg(not(feature = "link-capture"))]
+        HostToKeyboard::ReadCapture { .. } => {
+            reply(
+                ctx,
+                KeyboardToHost::CaptureChunk {
+                    offset: 0,
+                    total: 0,
+                    len: 0,
+                    data: [0; CAPTURE_CHUNK],
+                },
+            )
+            .await
+        }
+        #[cfg(not(feature = "link-capture"))]
+        HostToKeyboard::ClearCapture => {}
+        // routed without anything to do here
+        HostToKeyboard::KeepAlive
+        | HostToKeyboard::WritePixels { .. }
+        | HostToKeyboard::WritePackedPixels { .. }
+        | HostToKeyboard::SetTuning { .. }
+        | HostToKeyboard::RevertTuning
+        | HostToKeyboard::RequestTuning
+        | HostToKeyboard::FlushDisplay { .. }
+        | HostToKeyboard::BeginFrame { .. }
+        | HostToKeyboard::EndFrame { .. } => {}
+    }
+}
+
+async fn send_stats(ctx: &DispatchCtx<'_>) {
+    reply(
+        ctx,
+        KeyboardToHost::Stats {
+            keypresses: TOTAL_KEYPRESSES.load(Ordering::Relaxed),
+        },
+    )
+    .await;
+    reply(
+        ctx,
+        KeyboardToHost::DebugStats {
+            cpu_busy_pct: CPU_BUSY_PCT.load(Ordering::Relaxed),
+            presses_by_source: presses_by_source(),
+            corrupt_frames: CORRUPT_FRAMES.load(Ordering::Relaxed),
+            retransmits: RETRANSMITS.load(Ordering::Relaxed),
+            failed_sends: FAILED_SENDS.load(Ordering::Relaxed),
+            channel_drops: channel_stats::drops(),
+            settings_erases: settings::SETTINGS_ERASES.load(Ordering::Relaxed),
+            rejected_pixel_writes: display_widgets::REJECTED_PIXEL_WRITES.load(Ordering::Relaxed),
+            firmware_mismatch: version_check::mismatch(),
+            command_queue_high_water: COMMAND_QUEUE_HIGH_WATER.load(Ordering::Relaxed),
+            ack_queue_high_water: ACK_QUEUE_HIGH_WATER.load(Ordering::Relaxed),
+            presence_mode: jiggle::active(),
+            link_read_errors: LINK_READ_ERRORS.load(Ordering::Relaxed),
+            link_errors: link_errors(),
+            link_resyncs: LINK_RESYNCS.load(Ordering::Relaxed),
+            link_up: link_health::up(),
+            link_losses: link_health::LINK_LOSSES.load(Ordering::Relaxed),
+            display_bus: display_bus::stats(),
+            phantom_presses: [
+                PHANTOM_PRESSES.load(Ordering::Relaxed),
+                REMOTE_PHANTOM_PRESSES.load(Ordering::Relaxed),
+            ],
+            safe_mode: safe_mode::reason(),
+            heap_high_water: heap::high_water(),
+            oom_averted: heap::oom_averted(),
+        },
+    )
+    .await;
+    for (side, results) in [
+        (KeyboardSide::Left, self_test::results()),
+        (KeyboardSide::Right, self_test::peer_results()),
+    ] {
+        reply(ctx, KeyboardToHost::SelfTest { side, results }).await;
+    }
+}
+
+async fn enter_bootloader(ctx: &DispatchCtx<'_>, side: KeyboardSide) {
+    reply(ctx, KeyboardToHost::EnteringBootloader { side }).await;
+    // the keypresses since the last save would be lost otherwise
+    keypress_store::SAVE_NOW.set();
+    Timer::after(bootloader::ENTER_DELAY).await;
+    bootloader::enter();
+}
+
+async fn send_timing_stats(ctx: &DispatchCtx<'_>) {
+    let hold_taps = HOLD_TAP_STATS.lock(|s| s.borrow().clone());
+    let total = hold_taps.len() as u8;
+    for (index, s) in hold_taps.iter().enumerate() {
+        reply(
+            ctx,
+            KeyboardToHost::HoldTapStats {
+                index: index as u8,
+                total,
+                row: s.coord.0,
+                col: s.coord.1,
+                timeout_ms: s.timeout,
+                taps: s.taps,
+                holds: s.holds,
+                tap_durations: s.tap_durations,
+                hold_durations: s.hold_durations,
+            },
+        )
+        .await;
+    }
+
+    let load = |c: &AtomicU16| c.load(Ordering::Relaxed);
+    for index in 0..NUM_CHORDS {
+        reply(
+            ctx,
+            KeyboardToHost::ChordStats {
+                index: index as u8,
+                total: NUM_CHORDS as u8,
+                fires: load(&CHORD_FIRES[index]).saturating_add(load(&REMOTE_CHORD_FIRES[index])),
+                near_misses: load(&CHORD_NEAR_MISSES[index])
+                    .saturating_add(load(&REMOTE_CHORD_NEAR_MISSES[index])),
+            },
+        )
+        .await;
+    }
+}
+
+/// Every usage counter: chords, then layers, then the taps and holds of each
+/// hold-tap
+const MAX_USAGE_ENTRIES: usize = NUM_CHORDS + N_LAYERS + MAX_HOLD_TAPS * 2;
+
+async fn send_usage_stats(ctx: &DispatchCtx<'_>) {
+    let mut entries = heapless::Vec::<UsageEntry, MAX_USAGE_ENTRIES>::new();
+
+    let load = |c: &AtomicU16| c.load(Ordering::Relaxed) as u32;
+    for index in 0..NUM_CHORDS {
+        let _ = entries.push(UsageEntry {
+            kind: UsageKind::Chord,
+            index: index as u8,
+            count: load(&CHORD_FIRES[index]) + load(&REMOTE_CHORD_FIRES[index]),
+            millis: 0,
+        });
+    }
+
+    for index in 0..N_LAYERS {
+        let (count, millis) = layer_usage(index);
+        let _ = entries.push(UsageEntry {
+            kind: UsageKind::Layer,
+            index: index as u8,
+            count,
+            millis,
+        });
+    }
+
+    let hold_taps = HOLD_TAP_STATS.lock(|s| s.borrow().clone());
+    for s in &hold_taps {
+        let index = s.coord.0 * KEY_COLS as u8 + s.coord.1;
+        for (kind, count) in [(UsageKind::Tap, s.taps), (UsageKind::Hold, s.holds)] {
+            let _ = entries.push(UsageEntry {
+                kind,
+                index,
+                count: count as u32,
+                millis: 0,
+            });
+        }
+    }
+
+    let total = entries.len() as u8;
+    for (chunk_idx, chunk) in entries.chunks(USAGE_CHUNK).enumerate() {
+        let mut padded = [UsageEntry::EMPTY; USAGE_CHUNK];
+        padded[..chunk.len()].copy_from_slice(chunk);
+        reply(
+            ctx,
+            KeyboardToHost::Usage {
+                offset: (chunk_idx * USAGE_CHUNK) as u8,
+                total,
+                len: chunk.len() as u8,
+                entries: padded,
+            },
+        )
+        .await;
+    }
+}
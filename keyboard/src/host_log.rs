@@ -0,0 +1,87 @@
+//! Logging to the host over USB serial, for when there's no debugger
+//! attached.
+//!
+//! `hostlog!` formats into a fixed buffer and queues it without waiting, so
+//! it's safe anywhere, key handling included. Lines are dropped and counted
+//! as `DropChannel::HostLog` once the host stops reading and the queue
+//! fills. Only the left half's lines reach the host.
+
+use core::convert::Infallible;
+
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::Instant;
+use keyboard_shared::{DropChannel, KeyboardToHost, LogSeverity, LogText, LOG_MSG_LEN};
+
+use crate::{channel_stats, log_level};
+
+/// Log lines waiting for the host
+pub static HOST_LOG_CHAN: Channel<ThreadModeRawMutex, KeyboardToHost, 8> = Channel::new();
+
+/// Whether lines of `severity` are sent at the current verbosity
+pub fn enabled(severity: LogSeverity) -> bool {
+    severity <= log_level::get().host_log_threshold()
+}
+
+/// A log line being formatted, anything past `LOG_MSG_LEN` bytes is cut off
+pub struct LogLine {
+    len: usize,
+    msg: [u8; LOG_MSG_LEN],
+}
+
+impl LogLine {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            msg: [0; LOG_MSG_LEN],
+        }
+    }
+
+    /// Queue the line for the host, dropping it if the queue is full
+    pub fn send(self, severity: LogSeverity) {
+        let Some(msg) = LogText::new(&self.msg[..self.len]) else {
+            return;
+        };
+
+        channel_stats::try_send(
+            &HOST_LOG_CHAN,
+            KeyboardToHost::Log {
+                severity,
+                uptime_ms: Instant::now().as_millis() as u32,
+                msg,
+            },
+            DropChannel::HostLog,
+        );
+    }
+}
+
+impl Default for LogLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ufmt::uWrite for LogLine {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Infallible> {
+        let rest = &mut self.msg[self.len..];
+        let n = s.len().min(rest.len());
+        rest[..n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Send a line to the host, formatted with `ufmt`, if the verbosity allows
+/// lines of `$severity`, one of the `LogSeverity` variants
+#[macro_export]
+macro_rules! hostlog {
+    ($severity:ident, $($arg:tt)*) => {{
+        let severity = $crate::messages::LogSeverity::$severity;
+        if $crate::host_log::enabled(severity) {
+            let mut line = $crate::host_log::LogLine::new();
+            let _ = ::ufmt::uwrite!(&mut line, $($arg)*);
+            line.send(severity);
+        }
+    }};
+}
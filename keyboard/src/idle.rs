@@ -0,0 +1,102 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::event::Event;
+
+/// How long without interaction before the displays start the screensaver
+pub const SCREENSAVER_AFTER: Duration = Duration::from_secs(20);
+/// How long without interaction before the displays are turned off
+pub const OLED_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a peek shows the displays for
+pub const PEEK_TIME: Duration = Duration::from_secs(3);
+
+#[derive(PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum IdlePhase {
+    Active,
+    /// Idle, but showing the usual screens for a peek
+    Peek,
+    Screensaver,
+    Off,
+}
+
+/// Single source of truth for when the user last interacted with the keyboard.
+///
+/// Both the display timeout and the display run loops derive their state from
+/// this so they can't disagree about which phase we're in.
+pub struct IdleTracker {
+    last: Mutex<ThreadModeRawMutex, Cell<Instant>>,
+    peek_until: Mutex<ThreadModeRawMutex, Cell<Option<Instant>>>,
+    event: Event,
+}
+
+impl IdleTracker {
+    pub const fn new() -> Self {
+        Self {
+            last: Mutex::new(Cell::new(Instant::from_ticks(0))),
+            peek_until: Mutex::new(Cell::new(None)),
+            event: Event::new(),
+        }
+    }
+
+    pub fn interacted(&self) {
+        self.last.lock(|l| l.set(Instant::now()));
+        self.peek_until.lock(|p| p.set(None));
+        self.event.set();
+    }
+
+    /// A glance at the displays, which shows them for `PEEK_TIME` without
+    /// counting as an interaction. Peeking again before that's up doesn't
+    /// make it last any longer, only an interaction does.
+    pub fn peeked(&self) {
+        if self.phase() != IdlePhase::Active && self.peek_left().is_none() {
+            self.peek_until
+                .lock(|p| p.set(Some(Instant::now() + PEEK_TIME)));
+            self.event.set();
+        }
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last.lock(|l| l.get()).elapsed()
+    }
+
+    /// How long the current peek has left, if there is one
+    pub fn peek_left(&self) -> Option<Duration> {
+        let until = self.peek_until.lock(|p| p.get())?;
+        let now = Instant::now();
+        (until > now).then(|| until - now)
+    }
+
+    pub fn phase(&self) -> IdlePhase {
+        let idle = self.idle_for();
+
+        if idle < SCREENSAVER_AFTER {
+            IdlePhase::Active
+        } else if self.peek_left().is_some() {
+            IdlePhase::Peek
+        } else if idle >= OLED_TIMEOUT {
+            IdlePhase::Off
+        } else {
+            IdlePhase::Screensaver
+        }
+    }
+
+    /// How long until the displays should next change brightness without
+    /// any more interaction, `None` if they're staying off
+    pub fn next_change(&self) -> Option<Duration> {
+        let until_off = OLED_TIMEOUT - self.idle_for().min(OLED_TIMEOUT);
+        let until_off = (until_off > Duration::from_ticks(0)).then_some(until_off);
+        match (self.peek_left(), until_off) {
+            (Some(peek), Some(until_off)) => Some(peek.min(until_off)),
+            (peek, until_off) => peek.or(until_off),
+        }
+    }
+
+    /// Wait for the next interaction, only one task should be waiting on this
+    pub async fn wait_interaction(&self) {
+        self.event.wait().await;
+    }
+}
+
+pub static IDLE: IdleTracker = IdleTracker::new();
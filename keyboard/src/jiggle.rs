@@ -0,0 +1,119 @@
+//! Presence mode: nudges the mouse pointer every so often so a remote session
+//! doesn't go idle. It stays out of the way while the keyboard is being used
+//! and turns itself off after a while in case it's forgotten about.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+/// Time between nudges
+pub const JIGGLE_PERIOD: Duration = Duration::from_secs(30);
+/// Real input this recent holds off the next nudge
+pub const INPUT_GRACE: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_MINUTES: u16 = 4 * 60;
+
+/// Report descriptor of the mouse interface: three buttons, which are never
+/// pressed, then relative x and y
+#[rustfmt::skip]
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // usage page (generic desktop)
+    0x09, 0x02, // usage (mouse)
+    0xa1, 0x01, // collection (application)
+    0x09, 0x01, //   usage (pointer)
+    0xa1, 0x00, //   collection (physical)
+    0x05, 0x09, //     usage page (buttons)
+    0x19, 0x01, //     usage minimum (1)
+    0x29, 0x03, //     usage maximum (3)
+    0x15, 0x00, //     logical minimum (0)
+    0x25, 0x01, //     logical maximum (1)
+    0x75, 0x01, //     report size (1)
+    0x95, 0x03, //     report count (3)
+    0x81, 0x02, //     input (data, variable, absolute)
+    0x75, 0x05, //     report size (5)
+    0x95, 0x01, //     report count (1)
+    0x81, 0x01, //     input (constant)
+    0x05, 0x01, //     usage page (generic desktop)
+    0x09, 0x30, //     usage (x)
+    0x09, 0x31, //     usage (y)
+    0x15, 0x81, //     logical minimum (-127)
+    0x25, 0x7f, //     logical maximum (127)
+    0x75, 0x08, //     report size (8)
+    0x95, 0x02, //     report count (2)
+    0x81, 0x06, //     input (data, variable, relative)
+    0xc0,       //   end collection
+    0xc0,       // end collection
+];
+pub const MOUSE_REPORT_LEN: usize = 3;
+
+/// When presence mode was turned on, `None` while it's off
+static STARTED: Mutex<ThreadModeRawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+static LAST_INPUT: Mutex<ThreadModeRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::from_ticks(0)));
+static MAX_MINUTES: AtomicU16 = AtomicU16::new(DEFAULT_MAX_MINUTES);
+
+pub fn set_max_minutes(minutes: u16) {
+    MAX_MINUTES.store(minutes, Ordering::Relaxed);
+}
+
+pub fn active() -> bool {
+    STARTED.lock(|s| s.get()).is_some()
+}
+
+pub fn toggle() {
+    STARTED.lock(|s| {
+        s.set(match s.get() {
+            Some(_) => None,
+            None => Some(Instant::now()),
+        })
+    });
+}
+
+/// Record a key event from the user, as opposed to one made up by the firmware
+pub fn input() {
+    LAST_INPUT.lock(|l| l.set(Instant::now()));
+}
+
+pub fn mouse_report(dx: i8) -> [u8; MOUSE_REPORT_LEN] {
+    [0, dx as u8, 0]
+}
+
+/// Decides when to nudge, alternating directions so the pointer stays put
+pub struct Jiggler {
+    last: Instant,
+    direction: i8,
+}
+
+impl Jiggler {
+    pub const fn new() -> Self {
+        Self {
+            last: Instant::from_ticks(0),
+            direction: 1,
+        }
+    }
+
+    /// The x movement to send now, if a nudge is due. Turns presence mode
+    /// off once it has been on for the maximum duration.
+    pub fn poll(&mut self) -> Option<i8> {
+        let started = STARTED.lock(|s| s.get())?;
+        let max = Duration::from_secs(MAX_MINUTES.load(Ordering::Relaxed) as u64 * 60);
+
+        if started.elapsed() >= max {
+            STARTED.lock(|s| s.set(None));
+            return None;
+        }
+
+        if LAST_INPUT.lock(|l| l.get()).elapsed() < INPUT_GRACE
+            || self.last.elapsed() < JIGGLE_PERIOD
+        {
+            return None;
+        }
+
+        self.last = Instant::now();
+        self.direction = -self.direction;
+        Some(self.direction)
+    }
+}
@@ -0,0 +1,57 @@
+use embassy_time::Instant;
+use keyberon::layout::Event;
+
+pub use keyboard_shared::EventSource;
+
+use crate::layout::CHORD_ROW;
+
+/// A key event on its way to the layout, tagged with where it came from
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub event: Event,
+    pub source: EventSource,
+    /// When the event entered the pipeline, for latency stats
+    pub at: Instant,
+}
+
+impl KeyEvent {
+    pub fn new(event: Event, source: EventSource) -> Self {
+        Self {
+            event,
+            source,
+            at: Instant::now(),
+        }
+    }
+
+    /// An event from this half's matrix and chording
+    pub fn local(event: Event) -> Self {
+        Self::new(event, Self::chord_or(event, EventSource::Local))
+    }
+
+    /// An event received from the other half, which has already been chorded
+    pub fn remote(event: Event) -> Self {
+        Self::new(event, Self::chord_or(event, EventSource::Remote))
+    }
+
+    // chords resolve to keys on the extra row past the physical matrix
+    fn chord_or(event: Event, source: EventSource) -> EventSource {
+        if event.coord().0 as usize == CHORD_ROW {
+            EventSource::Chord
+        } else {
+            source
+        }
+    }
+
+    /// Injected by the host, kept out of the usual typing stats
+    pub fn is_synthetic(&self) -> bool {
+        self.source == EventSource::Synthetic
+    }
+
+    pub fn is_press(&self) -> bool {
+        self.event.is_press()
+    }
+
+    pub fn coord(&self) -> (u8, u8) {
+        self.event.coord()
+    }
+}
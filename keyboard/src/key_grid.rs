@@ -0,0 +1,282 @@
+//! A map of the whole keyboard on one display, filling in the keys that are
+//! held on either half and leaving an outline of each released key for a
+//! moment after.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::Point,
+    text::{Baseline, Text},
+    Drawable, Pixel,
+};
+use keyberon::layout::Event;
+use keyboard_shared::{key_grid_bit, DecayCounter, KeyboardSide, BUMP};
+use profont::PROFONT_7_POINT;
+
+use crate::{
+    display_widgets::{read_in_overrides, OVERRIDE_CHAN},
+    idle::{IdlePhase, IDLE},
+    layout::{COLS, COLS_PER_SIDE, ROWS},
+    oled::{self, Oled},
+    screensaver::Screensaver,
+    system_state::SYSTEM_STATE,
+};
+
+/// How often the left half tells the right which of its keys are held while
+/// the grid is shown
+pub const SYNC_PERIOD: Duration = Duration::from_millis(100);
+
+const FRAME_TIME: Duration = Duration::from_millis(50);
+/// Halving every other frame, a released key drops below [`VISIBLE_LEVEL`]
+/// after three half lives, around 300ms
+const FADE_HALF_LIFE_TICKS: u16 = 2;
+const VISIBLE_LEVEL: u8 = (BUMP >> 8) as u8 / 8;
+
+const HEADER_HEIGHT: i32 = 12;
+/// Cells are 2x3, the twelve columns only fit side by side with no gap
+/// between them, so the halves are split by a wider one instead
+const CELL_WIDTH: i32 = 2;
+const CELL_HEIGHT: i32 = 3;
+const ROW_PITCH: i32 = CELL_HEIGHT + 1;
+const HALF_GAP: i32 = 4;
+const MARGIN: i32 = (oled::WIDTH as i32 - COLS as i32 * CELL_WIDTH - HALF_GAP) / 2;
+
+const _: () = assert!(MARGIN >= 0);
+const _: () = assert!(HEADER_HEIGHT + ROWS as i32 * ROW_PITCH <= oled::ROWS as i32);
+
+static KEY_GRID: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<KeyGrid>> =
+    blocking_mutex::Mutex::new(RefCell::new(KeyGrid::new()));
+static KEY_GRID_CHANGED: crate::event::Event = crate::event::Event::new();
+
+struct KeyGrid {
+    /// Bits from [`key_grid_bit`]
+    held: u64,
+    /// Counting down from the release of each key
+    fading: [[DecayCounter; COLS]; ROWS],
+}
+
+impl KeyGrid {
+    const fn new() -> Self {
+        Self {
+            held: 0,
+            fading: [[DecayCounter::new(FADE_HALF_LIFE_TICKS); COLS]; ROWS],
+        }
+    }
+
+    fn set(&mut self, row: u8, col: u8, pressed: bool) {
+        if row as usize >= ROWS || col as usize >= COLS {
+            return;
+        }
+
+        let bit = key_grid_bit(row, col);
+        if pressed {
+            self.held |= bit;
+        } else if self.held & bit != 0 {
+            self.held &= !bit;
+            let fading = &mut self.fading[row as usize][col as usize];
+            fading.value = 0;
+            fading.bump();
+        }
+    }
+
+    fn tick(&mut self) {
+        for fading in self.fading.iter_mut().flatten() {
+            fading.tick();
+        }
+    }
+
+    /// The held keys, and the released ones that are still fading
+    fn snapshot(&self) -> (u64, u64) {
+        let mut fading = 0;
+        for (row, counters) in self.fading.iter().enumerate() {
+            for (col, counter) in counters.iter().enumerate() {
+                if counter.level() >= VISIBLE_LEVEL {
+                    fading |= key_grid_bit(row as u8, col as u8);
+                }
+            }
+        }
+        (self.held, fading & !self.held)
+    }
+}
+
+/// The bits of every key on `side`'s half of the layout
+const fn half_mask(side: KeyboardSide) -> u64 {
+    let first_col = match side {
+        KeyboardSide::Left => 0,
+        KeyboardSide::Right => COLS_PER_SIDE,
+    };
+    let mut mask = 0;
+    let mut row = 0;
+    while row < ROWS {
+        let mut col = first_col;
+        while col < first_col + COLS_PER_SIDE {
+            mask |= key_grid_bit(row as u8, col as u8);
+            col += 1;
+        }
+        row += 1;
+    }
+    mask
+}
+
+/// A key event from either half, in layout coordinates
+pub fn key_event(event: &Event) {
+    let (row, col) = event.coord();
+    KEY_GRID.lock(|g| g.borrow_mut().set(row, col, event.is_press()));
+    KEY_GRID_CHANGED.set();
+}
+
+/// The held keys on `side`'s half, for [`sync_held`] on the other half
+pub fn held(side: KeyboardSide) -> u64 {
+    KEY_GRID.lock(|g| g.borrow().held) & half_mask(side)
+}
+
+/// Catch up with the keys held on `side`'s half, as told by that half
+pub fn sync_held(side: KeyboardSide, held: u64) {
+    let held = held & half_mask(side);
+    let changed = KEY_GRID.lock(|g| {
+        let mut grid = g.borrow_mut();
+        let changed = (grid.held ^ held) & half_mask(side);
+        for row in 0..ROWS as u8 {
+            for col in 0..COLS as u8 {
+                let bit = key_grid_bit(row, col);
+                if changed & bit != 0 {
+                    grid.set(row, col, held & bit != 0);
+                }
+            }
+        }
+        changed
+    });
+    if changed != 0 {
+        KEY_GRID_CHANGED.set();
+    }
+}
+
+fn cell_pixels(row: usize, col: usize, hollow: bool) -> impl Iterator<Item = Pixel<BinaryColor>> {
+    let half_gap = if col >= COLS_PER_SIDE { HALF_GAP } else { 0 };
+    let x = MARGIN + col as i32 * CELL_WIDTH + half_gap;
+    let y = HEADER_HEIGHT + row as i32 * ROW_PITCH;
+
+    (0..CELL_HEIGHT)
+        // two pixels across leaves no middle to hollow out, so a released
+        // key keeps only its top and bottom
+        .filter(move |dy| !hollow || *dy == 0 || *dy == CELL_HEIGHT - 1)
+        .flat_map(move |dy| {
+            (0..CELL_WIDTH).map(move |dx| Pixel(Point::new(x + dx, y + dy), BinaryColor::On))
+        })
+}
+
+/// Shows every key of both halves, held ones filled in
+pub struct KeyGridDisplay {
+    oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    last_tick: Instant,
+    screensaver: Screensaver,
+}
+
+impl KeyGridDisplay {
+    pub fn new(oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>) -> Self {
+        Self {
+            oled,
+            last_tick: Instant::now(),
+            screensaver: Screensaver::new(),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        let mut override_timeout: Option<Instant> = None;
+
+        loop {
+            let fading = self.tick();
+
+            match override_timeout {
+                Some(t) => {
+                    if Instant::now() > t {
+                        override_timeout = None;
+                    }
+                }
+                None => {
+                    if SYSTEM_STATE.is_locked() {
+                        let _ = self.oled.lock().await.draw_banner("LOCKED").await;
+                    } else {
+                        if IDLE.phase() == IdlePhase::Screensaver {
+                            self.screensaver.run(self.oled).await;
+                        }
+                        self.render().await
+                    }
+                }
+            }
+
+            // only redraw every frame while something is fading
+            let wait = if fading {
+                FRAME_TIME
+            } else {
+                Duration::from_secs(1)
+            };
+
+            match select3(
+                KEY_GRID_CHANGED.wait(),
+                Timer::after(wait),
+                OVERRIDE_CHAN.recv(),
+            )
+            .await
+            {
+                Either3::First(()) => {}
+                Either3::Second(()) => {}
+                Either3::Third(o) => {
+                    read_in_overrides(self.oled, o).await;
+                    override_timeout = Some(Instant::now() + Duration::from_secs(1));
+                }
+            };
+        }
+    }
+
+    /// Advance the fades by however many frames have passed since the last
+    /// time, returning whether any are still showing
+    fn tick(&mut self) -> bool {
+        let frames = self.last_tick.elapsed().as_ticks() / FRAME_TIME.as_ticks();
+        self.last_tick += Duration::from_ticks(frames * FRAME_TIME.as_ticks());
+
+        KEY_GRID.lock(|g| {
+            let mut grid = g.borrow_mut();
+            // everything has long faded out after this many
+            for _ in 0..frames.min(16) {
+                grid.tick();
+            }
+            grid.snapshot().1 != 0
+        })
+    }
+
+    async fn render(&mut self) {
+        let (held, fading) = KEY_GRID.lock(|g| g.borrow().snapshot());
+        let header_style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
+
+        let _ = self
+            .oled
+            .lock()
+            .await
+            .draw(move |d| {
+                let _ =
+                    Text::with_baseline("KEYS", Point::zero(), header_style, Baseline::Top).draw(d);
+
+                for row in 0..ROWS {
+                    for col in 0..COLS {
+                        let bit = key_grid_bit(row as u8, col as u8);
+                        if held & bit != 0 {
+                            let _ = d.draw_iter(cell_pixels(row, col, false));
+                        } else if fading & bit != 0 {
+                            let _ = d.draw_iter(cell_pixels(row, col, true));
+                        }
+                    }
+                }
+            })
+            .await;
+    }
+}
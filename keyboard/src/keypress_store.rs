@@ -0,0 +1,44 @@
+//! The lifetime keypress count, kept in flash so it survives power cycles.
+//!
+//! The count has its own page, just below the settings pages, see
+//! [`keyboard_shared::KeypressStore`] for how it's laid out. On top of only
+//! erasing once the page is full, saves are batched: one is only made once
+//! [`keyboard_shared::KEYPRESS_SAVE_EVERY`] presses have piled up, at most once per
+//! [`SAVE_PERIOD`], or when the host says the keyboard is about to lose
+//! power.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::Duration;
+use keyboard_shared::KeypressStore;
+
+use crate::event::Event;
+
+/// The page after the settings pages, memory.x keeps it out of the firmware
+const PAGE: u32 = 0x000F_C000;
+const PAGE_SIZE: u32 = 4096;
+
+/// How often the count is checked for a save being due, which bounds how
+/// often it's written
+pub const SAVE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Set by the host's `PrepareShutdown`, the settings task saves the count
+/// whether or not a save is due
+pub static SAVE_NOW: Event = Event::new();
+
+/// The count loaded at boot, which the right half is sent whenever it says
+/// hello as it may have restarted since
+static RESTORED: AtomicU32 = AtomicU32::new(0);
+
+pub const fn store() -> KeypressStore {
+    KeypressStore::new(PAGE, PAGE_SIZE)
+}
+
+/// Note the count that was loaded at boot
+pub fn set_restored(count: u32) {
+    RESTORED.store(count, Ordering::Relaxed);
+}
+
+pub fn restored() -> u32 {
+    RESTORED.load(Ordering::Relaxed)
+}
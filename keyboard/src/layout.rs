@@ -1,15 +1,117 @@
 use keyberon::action::{k, l, Action, HoldTapAction};
 use keyberon::chording::ChordDef;
 use keyberon::key_code::KeyCode;
+use keyboard_shared::{to_global, ChordTiming, KeyboardSide};
 
 pub const COLS_PER_SIDE: usize = 6;
 pub const COLS: usize = COLS_PER_SIDE * 2;
+/// Physical rows of the key matrix
+#[cfg(not(feature = "pcb-v3"))]
 pub const ROWS: usize = 4;
+/// Physical rows of the key matrix, the v3 PCB adds a row with two more
+/// thumb keys per side
+#[cfg(feature = "pcb-v3")]
+pub const ROWS: usize = 5;
+/// Virtual row past the physical ones that chords resolve to
+pub const CHORD_ROW: usize = ROWS;
+/// Rows of the layout, the physical ones then the chord row
+pub const LAYOUT_ROWS: usize = CHORD_ROW + 1;
 pub const N_LAYERS: usize = 3;
 
-pub type CustomEvent = core::convert::Infallible;
-pub type Layers = keyberon::layout::Layers<COLS, { ROWS + 1 }, N_LAYERS, CustomEvent>;
-pub type Layout = keyberon::layout::Layout<COLS, { ROWS + 1 }, N_LAYERS, CustomEvent>;
+/// Whether every physical key on either half lands on its own spot in the
+/// layout, clear of the chord row
+const fn keys_map_uniquely() -> bool {
+    let mut seen = [[false; COLS]; LAYOUT_ROWS];
+    let mut row = 0;
+    while row < ROWS {
+        let mut col = 0;
+        while col < COLS_PER_SIDE {
+            let left = to_global(KeyboardSide::Left, row as u8, col as u8).1 as usize;
+            let right = to_global(KeyboardSide::Right, row as u8, col as u8).1 as usize;
+            if right < COLS_PER_SIDE || right >= COLS || seen[row][left] || seen[row][right] {
+                return false;
+            }
+            seen[row][left] = true;
+            seen[row][right] = true;
+            col += 1;
+        }
+        row += 1;
+    }
+
+    let mut col = 0;
+    while col < COLS {
+        if seen[CHORD_ROW][col] {
+            return false;
+        }
+        col += 1;
+    }
+    true
+}
+
+const _: () = assert!(keys_map_uniquely());
+
+/// Keys handled by the firmware rather than sent to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomEvent {
+    /// Suspend quiet hours until the window next starts or ends
+    QuietHoursOverride,
+    /// Turn presence mode, which keeps nudging the mouse, on or off
+    MouseJiggle,
+    /// Show the displays for a moment without waking them fully, see
+    /// [`is_peek_key`]
+    DisplayPeek,
+    /// Move on to the next LED mode
+    NextLedMode,
+    /// Move on to the next display screen: the usual ones, swapped, then the
+    /// layer legend
+    NextScreen,
+    /// Lock the keyboard, the lock combo unlocks it again
+    Lock,
+    /// A media key, held for as long as the key is, see `consumer`
+    Consumer(ConsumerAction),
+}
+
+/// Media keys, sent on the consumer control page since most OSes ignore the
+/// keyboard page's volume keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumerAction {
+    PlayPause,
+    NextTrack,
+    PrevTrack,
+    Mute,
+    VolumeUp,
+    VolumeDown,
+    BrightnessUp,
+    BrightnessDown,
+}
+
+impl ConsumerAction {
+    /// For the layer legend
+    pub const fn label(self) -> &'static str {
+        match self {
+            ConsumerAction::PlayPause => "Ply",
+            ConsumerAction::NextTrack => "Nxt",
+            ConsumerAction::PrevTrack => "Prv",
+            ConsumerAction::Mute => "Mut",
+            ConsumerAction::VolumeUp => "Vo+",
+            ConsumerAction::VolumeDown => "Vo-",
+            ConsumerAction::BrightnessUp => "Br+",
+            ConsumerAction::BrightnessDown => "Br-",
+        }
+    }
+}
+
+pub type Layers = keyberon::layout::Layers<COLS, LAYOUT_ROWS, N_LAYERS, CustomEvent>;
+pub type Layout = keyberon::layout::Layout<COLS, LAYOUT_ROWS, N_LAYERS, CustomEvent>;
+
+const QUIET: Action<CustomEvent> = Action::Custom(CustomEvent::QuietHoursOverride);
+const JIGGLE: Action<CustomEvent> = Action::Custom(CustomEvent::MouseJiggle);
+const PEEK: Action<CustomEvent> = Action::Custom(CustomEvent::DisplayPeek);
+
+/// A media key for `LAYERS`, like `{media(ConsumerAction::PlayPause)}`
+pub const fn media(action: ConsumerAction) -> Action<CustomEvent> {
+    Action::Custom(CustomEvent::Consumer(action))
+}
 
 const ALT_TAB: Action<CustomEvent> = Action::HoldTap(&HoldTapAction {
     timeout: 200,
@@ -35,28 +137,143 @@ const L2_SP: Action<CustomEvent> = Action::HoldTap(&HoldTapAction {
     tap_hold_interval: 0,
 });
 
+/// The longest any hold-tap in `layers` waits before resolving, in ms
+pub fn longest_hold_tap_timeout(layers: &Layers) -> u16 {
+    layers
+        .iter()
+        .flatten()
+        .flatten()
+        .filter_map(|action| match action {
+            Action::HoldTap(ht) => Some(ht.timeout),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn is_modifier(k: KeyCode) -> bool {
+    (KeyCode::LCtrl as u8..=KeyCode::RGui as u8).contains(&(k as u8))
+}
+
+fn only_modifies(action: &Action<CustomEvent>) -> bool {
+    match action {
+        Action::Custom(CustomEvent::DisplayPeek) => true,
+        Action::KeyCode(k) => is_modifier(*k),
+        Action::MultipleKeyCodes(keys) => keys.iter().all(|k| is_modifier(*k)),
+        Action::Layer(_) => true,
+        // what a hold-tap does isn't known when it's pressed, so it only
+        // counts if tapping it wouldn't type anything either, otherwise
+        // layer keys like space would never wake the displays
+        Action::HoldTap(ht) => only_modifies(&ht.hold) && only_modifies(&ht.tap),
+        _ => false,
+    }
+}
+
+/// Whether the key at `coord` only peeks at the displays rather than waking
+/// them: the peek key, and keys that only hold modifiers or switch layers.
+/// `Trans` goes by the default layer.
+pub fn is_peek_key(layers: &Layers, layer: u8, (row, col): (u8, u8)) -> bool {
+    let action = |layer: usize| {
+        layers
+            .get(layer)
+            .and_then(|l| l.get(row as usize))
+            .and_then(|r| r.get(col as usize))
+    };
+
+    match action(layer as usize) {
+        Some(Action::Trans) => action(0).map_or(false, only_modifies),
+        Some(action) => only_modifies(action),
+        None => false,
+    }
+}
+
 pub const NUM_CHORDS: usize = 14;
 
+#[derive(Clone, Copy)]
+pub struct Chord {
+    pub def: ChordDef,
+    pub timing: ChordTiming,
+}
+
+const fn c(output: (u8, u8), keys: &'static [(u8, u8)]) -> Chord {
+    Chord {
+        def: (output, keys),
+        timing: ChordTiming::DEFAULT,
+    }
+}
+
+const fn strict(output: (u8, u8), keys: &'static [(u8, u8)]) -> Chord {
+    Chord {
+        def: (output, keys),
+        timing: ChordTiming::STRICT,
+    }
+}
+
+/// Shorthand for the chord row in `CHORDS`
+const CR: u8 = CHORD_ROW as u8;
+
+/// Keys are layout coordinates. A chord with keys on both halves only fires
+/// with `ConfigItem::CrossSideChords` on, otherwise each half only chords
+/// its own keys.
 #[rustfmt::skip]
-pub static CHORDS: [ChordDef; NUM_CHORDS] = [
-    ((3, 8), &[(0, 6), (0, 7)]), // y + u = bspc
-    ((4, 3), &[(0, 7), (0, 8)]), // u + i = del
-    ((4, 0), &[(0, 0), (0, 1)]), // ` + q = esc
-    ((4, 0), &[(0, 1), (0, 2)]), // q + w = esc
-    ((4, 1), &[(2, 2), (2, 3)]), // x + c = M-x
-    ((4, 2), &[(2, 3), (2, 4)]), // c + v = spc, grave
+pub const CHORDS: [Chord; NUM_CHORDS] = [
+    strict((3, 8), &[(0, 6), (0, 7)]), // y + u = bspc
+    c((CR, 3), &[(0, 7), (0, 8)]), // u + i = del
+    c((CR, 0), &[(0, 0), (0, 1)]), // ` + q = esc
+    strict((CR, 0), &[(0, 1), (0, 2)]), // q + w = esc
+    c((CR, 1), &[(2, 2), (2, 3)]), // x + c = M-x
+    c((CR, 2), &[(2, 3), (2, 4)]), // c + v = spc, grave
+
+    c((CR, 4), &[(1, 6), (1, 7)]), // h + j = <, presence mode on layer 2
+    c((CR, 5), &[(1, 7), (1, 8)]), // j + k = :
+    c((CR, 6), &[(1, 8), (1, 9)]), // k + l = >
+
+    c((CR, 7), &[(0, 8), (0, 9)]), // i + o = \
+    c((CR, 8), &[(0, 9), (0, 10)]), // o + p = /
+
+    c((CR, 9), &[(2, 6), (2, 7)]), // n + m = "
+    c((CR, 10), &[(2, 7), (2, 8)]), // m + , = '
+    c((CR, 11), &[(2, 8), (2, 9)]), // , + . = _
+
+];
+
+const fn chord_defs<const N: usize>(chords: &[Chord; N]) -> [ChordDef; N] {
+    let mut defs: [ChordDef; N] = [((0, 0), &[]); N];
+    let mut i = 0;
+    while i < N {
+        defs[i] = chords[i].def;
+        i += 1;
+    }
+    defs
+}
 
-    ((4, 4), &[(1, 6), (1, 7)]), // h + j = <
-    ((4, 5), &[(1, 7), (1, 8)]), // j + k = :
-    ((4, 6), &[(1, 8), (1, 9)]), // k + l = >
+/// The chord definitions handed to keyberon
+pub static CHORD_DEFS: [ChordDef; NUM_CHORDS] = chord_defs(&CHORDS);
 
-    ((4, 7), &[(0, 8), (0, 9)]), // i + o = \
-    ((4, 8), &[(0, 9), (0, 10)]), // o + p = /
+const fn chord_keys<const N: usize>(chords: &[Chord; N]) -> [&'static [(u8, u8)]; N] {
+    let mut keys: [&'static [(u8, u8)]; N] = [&[]; N];
+    let mut i = 0;
+    while i < N {
+        keys[i] = chords[i].def.1;
+        i += 1;
+    }
+    keys
+}
+
+/// The keys of each chord
+pub static CHORD_KEYS: [&[(u8, u8)]; NUM_CHORDS] = chord_keys(&CHORDS);
 
-    ((4, 9), &[(2, 6), (2, 7)]), // n + m = "
-    ((4, 10), &[(2, 7), (2, 8)]), // m + , = '
-    ((4, 11), &[(2, 8), (2, 9)]), // , + . = _
+/// A chord that runs a firmware action rather than typing, see
+/// `action_chords`
+pub type ActionChord = keyboard_shared::ActionChord<CustomEvent>;
 
+pub const NUM_ACTION_CHORDS: usize = 3;
+
+#[rustfmt::skip]
+pub const ACTION_CHORDS: [ActionChord; NUM_ACTION_CHORDS] = [
+    ActionChord { action: CustomEvent::NextLedMode, keys: &[(0, 0), (0, 11)] }, // ` + '
+    ActionChord { action: CustomEvent::NextScreen, keys: &[(1, 0), (1, 11)] }, // both shifts
+    ActionChord { action: CustomEvent::Lock, keys: &[(2, 0), (2, 11)] }, // both ctrls
 ];
 
 macro_rules! m {
@@ -65,6 +282,35 @@ macro_rules! m {
     };
 }
 
+#[cfg(not(feature = "pcb-v3"))]
+#[rustfmt::skip]
+pub static LAYERS: Layers  = keyberon::layout::layout! {
+    {
+        ['`' Q W E R T Y U I O P '\''],
+        [LShift A S D F G H J K L ; RShift],
+        [LCtrl Z X C V B N M , . / RCtrl],
+        [n n n LGui {ALT_TAB} {L1_SP} {L2_SP} Enter BSpace n n n],
+        [Escape {m!(KeyCode::LAlt, KeyCode::X)} {m!(KeyCode::Space, KeyCode::Grave)} Delete < {m!(KeyCode::LShift, KeyCode::SColon)} > / '\\' '"' '\'' '_'],
+    }
+    {
+        ['`' ! @ '{' '}' | '`' ~ '\\' n '"'  n],
+        [ t  # $ '(' ')' n  +  -  /   * '\'' t],
+        [ t  % ^ '[' ']' n  &  =  ,   . '_'  t],
+        [n n n LGui LAlt =  = Tab BSpace n n n],
+        [n n n n    n    n  n n   n      n n n],
+    }
+    {
+        [{QUIET} Kb1 Kb2 Kb3 Kb4 Kb5 Kb6 Kb7 Kb8 Kb9 Kb0 {PEEK}],
+        [t F1  F2  F3  F4  F5  Left Down Up Right {media(ConsumerAction::VolumeUp)} t],
+        [t F6  F7  F8  F9  F10 PgDown {m!(KeyCode::LCtrl, KeyCode::Down)} {m!(KeyCode::LCtrl, KeyCode::Up)} PgUp {media(ConsumerAction::VolumeDown)} t],
+        [n n n F11 F12 t t RAlt End n n n],
+        [n n n n   {JIGGLE} n n n    n   n n n],
+    }
+};
+
+/// The v3 PCB's extra thumb keys sit under the inner two thumb keys of
+/// each half
+#[cfg(feature = "pcb-v3")]
 #[rustfmt::skip]
 pub static LAYERS: Layers  = keyberon::layout::layout! {
     {
@@ -72,6 +318,7 @@ pub static LAYERS: Layers  = keyberon::layout::layout! {
         [LShift A S D F G H J K L ; RShift],
         [LCtrl Z X C V B N M , . / RCtrl],
         [n n n LGui {ALT_TAB} {L1_SP} {L2_SP} Enter BSpace n n n],
+        [n n n n Escape Tab Delete RAlt n n n n],
         [Escape {m!(KeyCode::LAlt, KeyCode::X)} {m!(KeyCode::Space, KeyCode::Grave)} Delete < {m!(KeyCode::LShift, KeyCode::SColon)} > / '\\' '"' '\'' '_'],
     }
     {
@@ -79,13 +326,15 @@ pub static LAYERS: Layers  = keyberon::layout::layout! {
         [ t  # $ '(' ')' n  +  -  /   * '\'' t],
         [ t  % ^ '[' ']' n  &  =  ,   . '_'  t],
         [n n n LGui LAlt =  = Tab BSpace n n n],
+        [n n n n    t    t  t t   n      n n n],
         [n n n n    n    n  n n   n      n n n],
     }
     {
-        [n Kb1 Kb2 Kb3 Kb4 Kb5 Kb6 Kb7 Kb8 Kb9 Kb0 n],
-        [t F1  F2  F3  F4  F5  Left Down Up Right VolUp t],
-        [t F6  F7  F8  F9  F10 PgDown {m!(KeyCode::LCtrl, KeyCode::Down)} {m!(KeyCode::LCtrl, KeyCode::Up)} PgUp VolDown t],
+        [{QUIET} Kb1 Kb2 Kb3 Kb4 Kb5 Kb6 Kb7 Kb8 Kb9 Kb0 {PEEK}],
+        [t F1  F2  F3  F4  F5  Left Down Up Right {media(ConsumerAction::VolumeUp)} t],
+        [t F6  F7  F8  F9  F10 PgDown {m!(KeyCode::LCtrl, KeyCode::Down)} {m!(KeyCode::LCtrl, KeyCode::Up)} PgUp {media(ConsumerAction::VolumeDown)} t],
         [n n n F11 F12 t t RAlt End n n n],
-        [n n n n   n   n n n    n   n n n],
+        [n n n n   t   t t t    n   n n n],
+        [n n n n   {JIGGLE} n n n    n   n n n],
     }
 };
@@ -1,78 +1,358 @@
+use core::cell::Cell;
+
 use cichlid::HSV;
 use defmt::debug;
+use embassy_futures::select::{select, Either};
 use embassy_nrf::{gpio::Pin, peripherals::PWM0, Peripheral};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use keyberon::layout::Event;
+use keyboard_shared::{
+    flash_over, rainbow_hue, DecayCounter, FlashLimiter, KeyHeat, LedColour, LedGamma, LedMode,
+    SelfTestResults, SWITCH_LED_POSITIONS,
+};
+pub use keyboard_shared::{LedLayout, LEFT_LEDS, MAX_LEDS, RIGHT_LEDS};
 use micromath::F32Ext;
 use nrf_smartled::RGB8;
-use smart_leds::{gamma, SmartLedsWrite};
+use smart_leds::SmartLedsWrite;
 
-use crate::layout::{COLS_PER_SIDE, ROWS};
+use crate::{
+    dither::{Dither, Light},
+    layout::{COLS_PER_SIDE, N_LAYERS, ROWS},
+    link_health,
+    quiet_hours::{self, QUIET_LED_GAIN},
+    self_test, telemetry,
+};
 
-pub const UNDERGLOW_LEDS: usize = 6;
-pub const SWITCH_LEDS: usize = 21;
-pub const TOTAL_LEDS: usize = UNDERGLOW_LEDS + SWITCH_LEDS;
+/// Per channel gains for this half, applied after gamma correction
+static CALIBRATION: Mutex<ThreadModeRawMutex, Cell<[u8; 3]>> = Mutex::new(Cell::new([255; 3]));
+/// Solid colour shown instead of the usual animation while calibrating
+static TEST_COLOUR: Mutex<ThreadModeRawMutex, Cell<Option<RGB8>>> = Mutex::new(Cell::new(None));
+/// Single LED lit, by its index in the chain, while checking the wiring
+static TEST_LED: Mutex<ThreadModeRawMutex, Cell<Option<u8>>> = Mutex::new(Cell::new(None));
 
-// underglow LEDs are left to right
-#[rustfmt::skip]
-pub const UNDERGLOW_LED_POSITIONS: [(u8, u8); UNDERGLOW_LEDS] = [
-    // top row: 1, 2, 3
-    (0, 1), (2, 1), (4, 1),
-    // bottom row: 4, 5, 6
-    (4, 2), (2, 3), (0, 3),
-];
+/// Least time between flashes written outside the frame ticker, so the
+/// driver is never handed frames faster than it can clock them out
+pub const URGENT_MIN_GAP: Duration = Duration::from_millis(2);
+/// Colour pressed keys flash in [`LedMode::Flash`], what [`Flashes`] starts
+/// them at
+const FLASH_COLOUR: RGB8 = RGB8::new(255, 255, 255);
 
-// switch leds are bottom to top
-#[rustfmt::skip]
-pub const SWITCH_LED_POSITIONS: [(u8, u8); SWITCH_LEDS] = [
-    // first column: 7, 8, 9, 10
-    (3, 5), (2, 5), (1, 5), (0, 5),
-    // second column: 11, 12, 13, 14
-    (0, 4), (1, 4), (2, 4), (3, 4),
-    // third column: 15, 16, 17, 18
-    (3, 3), (2, 3), (1, 3), (0, 3),
-    // fourth column: 19, 20, 21
-    (0, 2), (1, 2), (2, 2),
-    // fifth column: 22, 23, 24
-    (2, 1), (1, 1), (0, 1),
-    // sixth column: 25, 26, 27
-    (0, 0), (1, 0), (2, 0)
-];
+/// Keys pressed since the last flash was written, a bit per column in each
+/// row of this half
+static FLASH_PENDING: Mutex<ThreadModeRawMutex, Cell<[u16; ROWS]>> =
+    Mutex::new(Cell::new([0; ROWS]));
+static FLASH_WAKE: crate::event::Event = crate::event::Event::new();
+
+pub fn set_calibration(gains: [u8; 3]) {
+    CALIBRATION.lock(|c| c.set(gains));
+}
+
+pub fn set_test_colour(colour: Option<[u8; 3]>) {
+    TEST_COLOUR.lock(|c| c.set(colour.map(|[r, g, b]| RGB8::new(r, g, b))));
+}
+
+pub fn test_colour(layout: &LedLayout) -> Option<impl Iterator<Item = RGB8>> {
+    let colour = TEST_COLOUR.lock(|c| c.get())?;
+    Some(core::iter::repeat(colour).take(layout.len()))
+}
+
+/// Colours the host has pinned LEDs of this half to, by index in the chain
+static KEY_LEDS: Mutex<ThreadModeRawMutex, Cell<[Option<RGB8>; MAX_LEDS]>> =
+    Mutex::new(Cell::new([None; MAX_LEDS]));
+
+/// Pin the LED at `index` to `colour`, ignored if this half has no such LED
+pub fn set_key_led(index: u8, [r, g, b]: [u8; 3]) {
+    KEY_LEDS.lock(|k| {
+        let mut leds = k.get();
+        if let Some(led) = leds.get_mut(index as usize) {
+            *led = Some(RGB8::new(r, g, b));
+        }
+        k.set(leds);
+    });
+}
+
+pub fn clear_key_leds() {
+    KEY_LEDS.lock(|k| k.set([None; MAX_LEDS]));
+}
 
-pub fn colour_gen<F, U>(f: F) -> impl Iterator<Item = U>
+/// Draws the LEDs pinned by the host over `frame`
+pub fn key_led_overlay<C: From<RGB8>>(frame: impl Iterator<Item = C>) -> impl Iterator<Item = C> {
+    let pinned = KEY_LEDS.lock(|k| k.get());
+    frame
+        .zip(pinned)
+        .map(|(colour, pinned)| pinned.map_or(colour, C::from))
+}
+
+pub fn set_test_led(index: Option<u8>) {
+    TEST_LED.lock(|c| c.set(index));
+}
+
+pub fn test_led(layout: &LedLayout) -> Option<impl Iterator<Item = RGB8>> {
+    let index = TEST_LED.lock(|c| c.get())? as usize;
+    Some((0..layout.len()).map(move |idx| {
+        if idx == index {
+            RGB8::new(255, 255, 255)
+        } else {
+            RGB8::default()
+        }
+    }))
+}
+
+/// Whether the host is driving any of this half's LEDs itself
+pub fn host_streaming() -> bool {
+    TEST_COLOUR.lock(|c| c.get()).is_some()
+        || TEST_LED.lock(|c| c.get()).is_some()
+        || KEY_LEDS.lock(|k| k.get()).iter().any(Option::is_some)
+}
+
+/// LED kept for [`status_led_overlay`], by its index in the chain
+static STATUS_LED: Mutex<ThreadModeRawMutex, Cell<Option<u8>>> = Mutex::new(Cell::new(None));
+
+pub fn set_status_led(index: Option<u8>) {
+    STATUS_LED.lock(|c| c.set(index));
+}
+
+/// What the status LED shows, the first of these that applies
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LinkStatus {
+    /// Off, the LEDs are meant to be dark
+    Quiet,
+    /// Blue, the host has taken over some of the LEDs
+    HostStreaming,
+    /// Blinking red, the other half hasn't answered
+    LinkDown,
+    /// Yellow, commands to the other half are being retransmitted
+    LinkDegraded,
+    /// Green, everything is working
+    Connected,
+    /// Off, nothing has configured the USB device
+    NoHost,
+}
+
+impl LinkStatus {
+    pub fn current(usb_configured: bool) -> Self {
+        if quiet_hours::is_quiet() {
+            Self::Quiet
+        } else if host_streaming() {
+            Self::HostStreaming
+        } else if !link_health::up() {
+            Self::LinkDown
+        } else if link_health::degraded() {
+            Self::LinkDegraded
+        } else if usb_configured {
+            Self::Connected
+        } else {
+            Self::NoHost
+        }
+    }
+
+    fn colour(self, counter: u16) -> RGB8 {
+        match self {
+            Self::Quiet | Self::NoHost => RGB8::default(),
+            Self::HostStreaming => RGB8::new(0, 0, 255),
+            Self::LinkDown if counter / 15 % 2 == 0 => RGB8::new(255, 0, 0),
+            Self::LinkDown => RGB8::default(),
+            Self::LinkDegraded => RGB8::new(255, 160, 0),
+            Self::Connected => RGB8::new(0, 255, 0),
+        }
+    }
+}
+
+/// Draws `status` on the status LED over `frame`, if one is set. This goes
+/// over everything else, including whatever the host has put on the LEDs
+pub fn status_led_overlay<C: From<RGB8>>(
+    frame: impl Iterator<Item = C>,
+    status: LinkStatus,
+    counter: u16,
+) -> impl Iterator<Item = C> {
+    let index = STATUS_LED.lock(|c| c.get()).map(usize::from);
+    let colour = status.colour(counter);
+
+    frame.enumerate().map(move |(idx, c)| match index {
+        Some(index) if index == idx => colour.into(),
+        _ => c,
+    })
+}
+
+fn scale(v: u32, gain: u8) -> u32 {
+    ((v as u64 * gain as u64) / 255) as u32
+}
+
+fn calibrate([r, g, b]: [u32; 3], [gr, gg, gb]: [u8; 3]) -> [u32; 3] {
+    [scale(r, gr), scale(g, gg), scale(b, gb)]
+}
+
+/// An LED being coloured in by an effect
+#[derive(Clone, Copy)]
+pub struct LedPos {
+    /// Row of this half, the same coordinates key events use
+    pub x: u8,
+    /// Column of this half
+    pub y: u8,
+    /// Whether this LED is under a switch, rather than underglow
+    pub switch: bool,
+    /// Where the LED is on the whole keyboard, see
+    /// [`LedLayout::board_position`]
+    pub board: (f32, f32),
+}
+
+pub fn colour_gen<F, U>(layout: &'static LedLayout, f: F) -> impl Iterator<Item = U>
 where
-    F: Fn(u8, u8) -> U,
+    F: Fn(LedPos) -> U,
 {
-    let buf_a = UNDERGLOW_LED_POSITIONS.map(|(x, y)| f(x, y));
-    let buf_b = SWITCH_LED_POSITIONS.map(|(x, y)| f(x, y));
-    buf_a.into_iter().chain(buf_b.into_iter())
+    let switches = layout.underglow.len()..layout.underglow.len() + layout.switches.len();
+    layout.positions().enumerate().map(move |(idx, (x, y))| {
+        f(LedPos {
+            x,
+            y,
+            switch: switches.contains(&idx),
+            board: layout.board_position((x, y)),
+        })
+    })
 }
 
-pub fn split_colour_gen<FU, FS, U>(underglow: FU, switches: FS) -> impl Iterator<Item = U>
+/// Like [`colour_gen`], but the switch LEDs get `switches` and every other
+/// LED gets `underglow`
+pub fn split_colour_gen<FU, FS, U>(
+    layout: &'static LedLayout,
+    underglow: FU,
+    switches: FS,
+) -> impl Iterator<Item = U>
 where
-    FU: Fn(u8, u8) -> U,
-    FS: Fn(u8, u8) -> U,
+    FU: Fn(LedPos) -> U,
+    FS: Fn(LedPos) -> U,
 {
-    let buf_a = UNDERGLOW_LED_POSITIONS.map(|(x, y)| underglow(x, y));
-    let buf_b = SWITCH_LED_POSITIONS.map(|(x, y)| switches(x, y));
-    buf_a.into_iter().chain(buf_b.into_iter())
+    colour_gen(layout, move |pos| {
+        if pos.switch {
+            switches(pos)
+        } else {
+            underglow(pos)
+        }
+    })
 }
 
-pub fn rainbow_single(x: u8, y: u8, offset: u8) -> HSV {
+/// Hue by where the LED is on the whole keyboard, see [`rainbow_hue`]
+pub fn rainbow_single(pos: LedPos, offset: u8) -> HSV {
     HSV {
-        h: x.wrapping_mul(6)
-            .wrapping_add(y.wrapping_mul(2))
-            .wrapping_add(offset),
+        h: rainbow_hue(pos.board).wrapping_add(offset),
         s: 255,
         v: 127,
     }
 }
 
-fn conv_colour(c: cichlid::ColorRGB) -> RGB8 {
-    RGB8::new(c.r, c.g, c.b)
+pub fn rainbow(layout: &'static LedLayout, offset: u8) -> impl Iterator<Item = Light> {
+    colour_gen(layout, move |pos| Light::from(rainbow_single(pos, offset)))
 }
 
-pub fn rainbow(offset: u8) -> impl Iterator<Item = RGB8> {
-    colour_gen(move |x, y| conv_colour(rainbow_single(x, y, offset).to_rgb_rainbow()))
+/// The first few LEDs are green or red for each self test check in order
+fn self_test_pattern(layout: &LedLayout, results: SelfTestResults) -> impl Iterator<Item = RGB8> {
+    let checks = results.checks();
+    (0..layout.len()).map(move |idx| match checks.get(idx) {
+        Some((_, true)) => RGB8::new(0, 64, 0),
+        Some((_, false)) => RGB8::new(64, 0, 0),
+        None => RGB8::default(),
+    })
+}
+
+/// If the display failed its self test it can't show the results, so blink
+/// them on the LEDs for a few seconds instead
+pub async fn show_self_test_pattern(leds: &mut Leds) {
+    let results = match self_test::results() {
+        Some(results) if !results.oled => results,
+        _ => return,
+    };
+
+    let layout = leds.layout();
+    for _ in 0..6 {
+        leds.send(self_test_pattern(layout, results));
+        Timer::after(Duration::from_millis(250)).await;
+        leds.send(core::iter::repeat(RGB8::default()).take(layout.len()));
+        Timer::after(Duration::from_millis(250)).await;
+    }
+}
+
+/// Blinks the first underglow LED over `frame` while presence mode is on, so
+/// it doesn't get forgotten about
+pub fn presence_indicator<C: From<RGB8>>(
+    frame: impl Iterator<Item = C>,
+    counter: u16,
+    active: bool,
+) -> impl Iterator<Item = C> {
+    let on = counter / 15 % 2 == 0;
+
+    frame.enumerate().map(move |(idx, colour)| match (idx, on) {
+        (0, true) if active => RGB8::new(0, 0, 255).into(),
+        (0, false) if active => RGB8::default().into(),
+        _ => colour,
+    })
+}
+
+/// Holds the second underglow LED bright red over `frame` while HID reports
+/// are being mirrored to the host
+pub fn report_mirror_indicator<C: From<RGB8>>(
+    frame: impl Iterator<Item = C>,
+    active: bool,
+) -> impl Iterator<Item = C> {
+    frame.enumerate().map(move |(idx, colour)| match idx {
+        1 if active => RGB8::new(255, 0, 0).into(),
+        _ => colour,
+    })
+}
+
+/// Underglow colour of each layer, mixed half and half with the effect so it's
+/// clear which layer is active. The base layer is left alone.
+pub const LAYER_TINTS: [Option<RGB8>; N_LAYERS] = [
+    None,
+    Some(RGB8::new(0, 96, 255)),
+    Some(RGB8::new(255, 64, 0)),
+];
+
+/// Tints the underglow LEDs of `frame` by `layer`, see [`LAYER_TINTS`]. The
+/// switch LEDs are left to the effect, and modes that keep the underglow
+/// dark aren't tinted.
+pub fn layer_tint(
+    frame: impl Iterator<Item = Light>,
+    layout: &LedLayout,
+    layer: u8,
+) -> impl Iterator<Item = Light> {
+    let tint = LAYER_TINTS
+        .get(layer as usize)
+        .copied()
+        .flatten()
+        .filter(|_| led_mode().lights_underglow());
+    let switches = layout.underglow.len()..layout.underglow.len() + layout.switches.len();
+
+    frame.enumerate().map(move |(idx, light)| match tint {
+        Some(t) if !switches.contains(&idx) => {
+            let c = light.to_rgb8();
+            RGB8::new(c.r / 2 + t.r / 2, c.g / 2 + t.g / 2, c.b / 2 + t.b / 2).into()
+        }
+        _ => light,
+    })
+}
+
+/// Slow amber blink on the first underglow LED with the rest dark, shown in
+/// safe mode instead of the effects
+pub fn safe_mode_pattern(layout: &LedLayout, on: bool) -> impl Iterator<Item = RGB8> {
+    (0..layout.len()).map(move |idx| match idx {
+        0 if on => RGB8::new(255, 96, 0),
+        _ => RGB8::default(),
+    })
+}
+
+/// Slowly pulsing red, shown while the keyboard is locked
+pub fn locked_pattern(layout: &'static LedLayout, frame: u8) -> impl Iterator<Item = Light> {
+    let phase = frame.wrapping_mul(2);
+    let v = if phase < 128 { phase } else { 255 - phase };
+
+    colour_gen(layout, move |_| {
+        Light::from(HSV {
+            h: 0,
+            s: 255,
+            v: 16 + v / 2,
+        })
+    })
 }
 
 fn lerp(a: f32, b: f32, t: f32) -> f32 {
@@ -176,41 +456,590 @@ impl TapWaves {
 
         brightness.clamp(0.0, 1.0)
     }
+}
 
-    pub fn render<'s, 'a: 's>(
-        &'s self,
-        below: impl Fn(u8, u8) -> HSV + 'a,
-    ) -> impl Iterator<Item = RGB8> + 's {
-        colour_gen(move |x, y| {
-            let colour = below(x, y);
+/// An LED animation, see [`Effects`] for the registered ones
+pub trait LedEffect {
+    /// Advance by a frame, `frame` is kept in sync between the halves so
+    /// effects that only depend on it line up
+    fn tick(&mut self, frame: u16);
 
-            let b = self.brightness_sums(x, y);
+    /// Colour of one LED. Effects that should line up across the halves go
+    /// by `pos.board`, since the halves needn't have the same LEDs.
+    fn colour(&self, pos: LedPos) -> HSV;
 
-            let white = HSV { h: 0, s: 0, v: 255 };
-            let colour_out = blend_hsv(colour, white, b);
-            // defmt::debug!("in: {:?}, out: {:?}, b: {}", components(colour), components(colour_out), b);
+    /// A key event, coordinates are the same as [`TapWaves::update`]
+    fn on_event(&mut self, _event: Event) {}
+}
 
-            conv_colour(colour_out.to_rgb_rainbow())
-        })
+pub fn render_effect<'a>(
+    layout: &'static LedLayout,
+    effect: &'a dyn LedEffect,
+) -> impl Iterator<Item = Light> + 'a {
+    colour_gen(layout, move |pos| Light::from(effect.colour(pos)))
+}
+
+pub struct Rainbow {
+    offset: u8,
+}
+
+impl LedEffect for Rainbow {
+    fn tick(&mut self, frame: u16) {
+        self.offset = frame as u8;
+    }
+
+    fn colour(&self, pos: LedPos) -> HSV {
+        rainbow_single(pos, self.offset)
+    }
+}
+
+pub struct Solid {
+    colour: HSV,
+}
+
+impl LedEffect for Solid {
+    fn tick(&mut self, _frame: u16) {
+        let LedColour { h, s, v } = led_colour();
+        self.colour = HSV { h, s, v };
+    }
+
+    fn colour(&self, _pos: LedPos) -> HSV {
+        self.colour
+    }
+}
+
+/// The whole board slowly fading in and out
+pub struct Breathing {
+    hue: u8,
+    s: u8,
+    v: u8,
+}
+
+impl LedEffect for Breathing {
+    fn tick(&mut self, frame: u16) {
+        let colour = led_colour();
+        self.hue = colour.h;
+        self.s = colour.s;
+
+        let phase = (frame as u8).wrapping_mul(2);
+        let v = if phase < 128 { phase } else { 255 - phase };
+        self.v = 16 + v / 2;
+    }
+
+    fn colour(&self, _pos: LedPos) -> HSV {
+        HSV {
+            h: self.hue,
+            s: self.s,
+            v: self.v,
+        }
+    }
+}
+
+pub struct Off;
+
+impl LedEffect for Off {
+    fn tick(&mut self, _frame: u16) {}
+
+    fn colour(&self, _pos: LedPos) -> HSV {
+        HSV { h: 0, s: 0, v: 0 }
+    }
+}
+
+/// Keys light up when pressed and cool down afterwards
+#[derive(Default)]
+pub struct Reactive {
+    heat: KeyHeat<u8>,
+}
+
+impl LedEffect for Reactive {
+    fn tick(&mut self, _frame: u16) {
+        for v in self.heat.iter_mut() {
+            *v = v.saturating_sub(8);
+        }
+    }
+
+    fn colour(&self, LedPos { x, y, .. }: LedPos) -> HSV {
+        let heat = self.heat.get(x, y).copied().unwrap_or(0);
+
+        HSV {
+            // cools from white hot through orange to red
+            h: heat / 8,
+            s: 255 - heat / 2,
+            v: heat,
+        }
+    }
+
+    fn on_event(&mut self, event: Event) {
+        if !event.is_press() {
+            return;
+        }
+
+        let (x, y) = event.coord();
+        if let Some(v) = self.heat.get_mut(x, y) {
+            *v = 255;
+        }
+    }
+}
+
+/// Brightness a tap fade keeps each frame, out of 256. It's under 2% after
+/// 12 frames, about 400ms.
+const TAP_FADE_KEEP: u16 = 181;
+
+/// The switch LED nearest each key of a half, by row then column. Most keys
+/// have their own.
+const NEAREST_SWITCH_LED: [[(u8, u8); COLS_PER_SIDE]; ROWS] = nearest_switch_leds();
+
+const fn nearest_switch_leds() -> [[(u8, u8); COLS_PER_SIDE]; ROWS] {
+    let mut nearest = [[(0, 0); COLS_PER_SIDE]; ROWS];
+    let mut row = 0;
+    while row < ROWS {
+        let mut col = 0;
+        while col < COLS_PER_SIDE {
+            let mut closest = u8::MAX;
+            let mut idx = 0;
+            while idx < SWITCH_LED_POSITIONS.len() {
+                let (r, c) = SWITCH_LED_POSITIONS[idx];
+                let dr = r.abs_diff(row as u8);
+                let dc = c.abs_diff(col as u8);
+                let dist = dr * dr + dc * dc;
+                if dist < closest {
+                    closest = dist;
+                    nearest[row][col] = (r, c);
+                }
+                idx += 1;
+            }
+            col += 1;
+        }
+        row += 1;
+    }
+    nearest
+}
+
+const fn own_leds_nearest() -> bool {
+    let mut idx = 0;
+    while idx < SWITCH_LED_POSITIONS.len() {
+        let (row, col) = SWITCH_LED_POSITIONS[idx];
+        let (r, c) = NEAREST_SWITCH_LED[row as usize][col as usize];
+        if r != row || c != col {
+            return false;
+        }
+        idx += 1;
+    }
+    true
+}
+
+// a key with its own switch LED lights that one
+const _: () = assert!(own_leds_nearest());
+
+/// Only the pressed key's own switch LED lights, at full brightness in the
+/// LED colour, then fades out quickly
+#[derive(Default)]
+pub struct TapFade {
+    hue: u8,
+    s: u8,
+    heat: KeyHeat<u8>,
+}
+
+impl LedEffect for TapFade {
+    fn tick(&mut self, _frame: u16) {
+        let colour = led_colour();
+        self.hue = colour.h;
+        self.s = colour.s;
+
+        for v in self.heat.iter_mut() {
+            *v = (*v as u16 * TAP_FADE_KEEP / 256) as u8;
+        }
+    }
+
+    fn colour(&self, LedPos { x, y, switch, .. }: LedPos) -> HSV {
+        let heat = self.heat.get(x, y).copied().filter(|_| switch).unwrap_or(0);
+
+        HSV {
+            h: self.hue,
+            s: self.s,
+            v: heat,
+        }
+    }
+
+    fn on_event(&mut self, event: Event) {
+        if !event.is_press() {
+            return;
+        }
+
+        // chords have no key, let alone an LED
+        let (x, y) = event.coord();
+        let Some(&(row, col)) = NEAREST_SWITCH_LED
+            .get(x as usize)
+            .and_then(|cols| cols.get(y as usize % COLS_PER_SIDE))
+        else {
+            return;
+        };
+        // staying on the same half as the key
+        let y = y - y % COLS_PER_SIDE as u8 + col;
+        if let Some(v) = self.heat.get_mut(row, y) {
+            *v = 255;
+        }
+    }
+}
+
+/// Frames between heatmap decay ticks, about a second
+const HEATMAP_TICK_FRAMES: u16 = 30;
+/// Seconds for a key's heat to halve
+const HEATMAP_HALF_LIFE: u16 = 30;
+
+/// Keys are coloured from blue to red by how much they've been used recently
+pub struct Heatmap {
+    heat: KeyHeat<DecayCounter>,
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self {
+            heat: KeyHeat::new(DecayCounter::new(HEATMAP_HALF_LIFE)),
+        }
+    }
+}
+
+impl LedEffect for Heatmap {
+    fn tick(&mut self, frame: u16) {
+        if frame % HEATMAP_TICK_FRAMES != 0 {
+            return;
+        }
+
+        for c in self.heat.iter_mut() {
+            c.tick();
+        }
+    }
+
+    fn colour(&self, LedPos { x, y, .. }: LedPos) -> HSV {
+        let heat = self.heat.get(x, y).map(|c| c.level()).unwrap_or(0);
+
+        HSV {
+            h: 170 - (heat as u16 * 170 / 255) as u8,
+            s: 255,
+            v: 32 + heat / 2,
+        }
+    }
+
+    fn on_event(&mut self, event: Event) {
+        if !event.is_press() {
+            return;
+        }
+
+        let (x, y) = event.coord();
+        if let Some(c) = self.heat.get_mut(x, y) {
+            c.bump();
+        }
+    }
+}
+
+/// Ripples from each key press drawn over another effect
+pub struct WithTapWaves<E> {
+    below: E,
+    waves: TapWaves,
+}
+
+impl<E: LedEffect> LedEffect for WithTapWaves<E> {
+    fn tick(&mut self, frame: u16) {
+        self.below.tick(frame);
+        self.waves.tick();
+    }
+
+    fn colour(&self, pos: LedPos) -> HSV {
+        let b = self.waves.brightness_sums(pos.x, pos.y);
+        let white = HSV { h: 0, s: 0, v: 255 };
+        blend_hsv(self.below.colour(pos), white, b)
+    }
+
+    fn on_event(&mut self, event: Event) {
+        if event.is_press() {
+            let (x, y) = event.coord();
+            self.waves.update(x, y);
+        }
+        self.below.on_event(event);
+    }
+}
+
+/// Frames for a flash to fade back into the effect below, about a third of a
+/// second
+const FLASH_FADE: u8 = 10;
+
+/// Keys flash white when pressed and fade back into another effect. The
+/// flash itself is written as soon as the key is scanned, see
+/// [`flash_key`], and this carries it on from the next frame.
+pub struct Flashes<E> {
+    below: E,
+    heat: KeyHeat<u8>,
+}
+
+impl<E: LedEffect> LedEffect for Flashes<E> {
+    fn tick(&mut self, frame: u16) {
+        self.below.tick(frame);
+        for v in self.heat.iter_mut() {
+            *v = v.saturating_sub(255 / FLASH_FADE);
+        }
+    }
+
+    fn colour(&self, pos: LedPos) -> HSV {
+        let heat = self.heat.get(pos.x, pos.y).copied().unwrap_or(0);
+        let white = HSV { h: 0, s: 0, v: 255 };
+        blend_hsv(self.below.colour(pos), white, c_b(heat))
+    }
+
+    fn on_event(&mut self, event: Event) {
+        if event.is_press() {
+            let (x, y) = event.coord();
+            if let Some(v) = self.heat.get_mut(x, y) {
+                *v = 255;
+            }
+        }
+        self.below.on_event(event);
+    }
+}
+
+/// One of each effect, indexed by [`LedMode`]. Effects keep their state
+/// while another mode is active.
+pub struct Effects {
+    rainbow_waves: WithTapWaves<Rainbow>,
+    rainbow: Rainbow,
+    solid: Solid,
+    breathing: Breathing,
+    reactive: Reactive,
+    heatmap: Heatmap,
+    flash: Flashes<Rainbow>,
+    tap_fade: TapFade,
+    off: Off,
+}
+
+impl Effects {
+    pub fn new() -> Self {
+        Self {
+            rainbow_waves: WithTapWaves {
+                below: Rainbow { offset: 0 },
+                waves: TapWaves::new(),
+            },
+            rainbow: Rainbow { offset: 0 },
+            solid: Solid {
+                colour: HSV { h: 0, s: 0, v: 127 },
+            },
+            breathing: Breathing { hue: 0, s: 0, v: 0 },
+            reactive: Reactive::default(),
+            heatmap: Heatmap::default(),
+            flash: Flashes {
+                below: Rainbow { offset: 0 },
+                heat: Default::default(),
+            },
+            tap_fade: TapFade::default(),
+            off: Off,
+        }
+    }
+
+    pub fn get(&mut self, mode: LedMode) -> &mut dyn LedEffect {
+        match mode {
+            LedMode::RainbowWaves => &mut self.rainbow_waves,
+            LedMode::Rainbow => &mut self.rainbow,
+            LedMode::Solid => &mut self.solid,
+            LedMode::Breathing => &mut self.breathing,
+            LedMode::Reactive => &mut self.reactive,
+            LedMode::Heatmap => &mut self.heatmap,
+            LedMode::Flash => &mut self.flash,
+            LedMode::TapFade => &mut self.tap_fade,
+            LedMode::Off => &mut self.off,
+        }
+    }
+}
+
+static LED_MODE: Mutex<ThreadModeRawMutex, Cell<LedMode>> =
+    Mutex::new(Cell::new(LedMode::RainbowWaves));
+
+pub fn set_led_mode(mode: LedMode) {
+    LED_MODE.lock(|m| m.set(mode));
+}
+
+pub fn led_mode() -> LedMode {
+    LED_MODE.lock(|m| m.get())
+}
+
+static LED_COLOUR: Mutex<ThreadModeRawMutex, Cell<LedColour>> =
+    Mutex::new(Cell::new(LedColour::DEFAULT));
+
+pub fn set_led_colour(colour: LedColour) {
+    LED_COLOUR.lock(|c| c.set(colour));
+}
+
+pub fn led_colour() -> LedColour {
+    LED_COLOUR.lock(|c| c.get())
+}
+
+/// The mode after `mode`, for stepping through them from the keyboard. Off
+/// is left out, a dark board looks like it's stopped working.
+pub fn next_led_mode(mode: LedMode) -> LedMode {
+    match mode {
+        LedMode::RainbowWaves => LedMode::Rainbow,
+        LedMode::Rainbow => LedMode::Solid,
+        LedMode::Solid => LedMode::Breathing,
+        LedMode::Breathing => LedMode::Reactive,
+        LedMode::Reactive => LedMode::Heatmap,
+        LedMode::Heatmap => LedMode::Flash,
+        LedMode::Flash => LedMode::TapFade,
+        LedMode::TapFade | LedMode::Off => LedMode::RainbowWaves,
     }
 }
 
 pub struct Leds {
     pwm: nrf_smartled::pwm::Pwm<'static, PWM0>,
+    layout: &'static LedLayout,
+    /// Frames fully rendered before they're handed to the driver, one with
+    /// the last frame the effect rendered and one to draw into. Only the
+    /// first `layout.len()` of each are used.
+    frames: [[RGB8; MAX_LEDS]; 2],
+    /// Which of `frames` has the last rendered frame, key flashes are drawn
+    /// over a copy of it in the other
+    rendered: usize,
+    /// Keys flashed since the last rendered frame, a bit per column in each
+    /// row of this half
+    flashed: [u16; ROWS],
+    gamma: LedGamma,
+    dither: Dither,
+    /// Spaces out flashes written outside the frame ticker
+    flash_limiter: FlashLimiter,
 }
 
 impl Leds {
-    pub fn new<P: Pin + Peripheral<P = P>>(pwm0: PWM0, pin: P) -> Self {
+    pub fn new<P: Pin + Peripheral<P = P>>(pwm0: PWM0, pin: P, layout: &'static LedLayout) -> Self {
         Self {
             pwm: nrf_smartled::pwm::Pwm::new(pwm0, pin),
+            layout,
+            frames: [[RGB8::default(); MAX_LEDS]; 2],
+            rendered: 0,
+            flashed: [0; ROWS],
+            gamma: LedGamma::new(),
+            dither: Dither::new(),
+            flash_limiter: FlashLimiter::new(URGENT_MIN_GAP.as_micros()),
         }
     }
 
+    /// Wait for `ticker`'s next frame, meanwhile writing any key flashes
+    /// over the last frame as they come in, at most one write per
+    /// [`URGENT_MIN_GAP`]. `showing_effect` is whether the last frame was
+    /// the effect, rather than something flashes shouldn't be drawn over.
+    pub async fn next_frame(&mut self, ticker: &mut Ticker, showing_effect: bool) {
+        loop {
+            match select(ticker.next(), FLASH_WAKE.wait()).await {
+                Either::First(()) => return,
+                Either::Second(()) => {
+                    let now = Instant::now().as_micros();
+                    let at = self.flash_limiter.next_write_us(now);
+                    Timer::at(Instant::from_micros(at)).await;
+                    let keys = FLASH_PENDING.lock(|p| p.take());
+                    if showing_effect {
+                        self.flash(keys);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Where this half's LEDs are
+    pub fn layout(&self) -> &'static LedLayout {
+        self.layout
+    }
+
+    /// Write a blank frame, returning whether the driver accepted it
+    pub fn self_test(&mut self) -> bool {
+        let frame = &mut self.frames[1 - self.rendered][..self.layout.len()];
+        frame.fill(RGB8::default());
+        self.pwm.write(frame.iter().copied()).is_ok()
+    }
+
     pub fn send<T, I>(&mut self, iterator: T)
     where
         T: Iterator<Item = I>,
-        I: Into<RGB8>,
+        I: Into<Light>,
     {
-        let _ = self.pwm.write(gamma(iterator.map(Into::into)));
+        // The driver keeps interrupts off while it encodes the frame into the
+        // PWM buffer, and it pulls from the iterator as it goes. Rendering up
+        // front keeps the colour maths out of that section so only the
+        // encoding itself holds off the UART.
+        //
+        // Per half calibration is applied first, so any later scaling
+        // happens to the colour that's actually shown. The value stays apart
+        // from the colour until gamma correction, calibration is applied to
+        // the linear light and the dither takes it down to 8 bits, so dim
+        // colours keep their hue rather than losing whole channels. Quiet
+        // hours dim through the gains too.
+        let gains = gains();
+        let back = 1 - self.rendered;
+        let frame = &mut self.frames[back][..self.layout.len()];
+        for (idx, (slot, light)) in frame.iter_mut().zip(iterator).enumerate() {
+            let Light { colour, value } = light.into();
+            let linear = self.gamma.correct([colour.r, colour.g, colour.b], value);
+            *slot = self.dither.apply(idx, calibrate(linear, gains));
+        }
+
+        let _ = self.pwm.write(frame.iter().copied());
+        self.rendered = back;
+        self.flashed = [0; ROWS];
+        telemetry::light_shown();
+    }
+
+    /// Light the switch LEDs of `keys`, and any others flashed since, over
+    /// the last rendered frame and write it again. Only those LEDs are worked
+    /// out afresh, the chain still has to be clocked out whole.
+    fn flash(&mut self, keys: [u16; ROWS]) {
+        for (flashed, keys) in self.flashed.iter_mut().zip(keys) {
+            *flashed |= keys;
+        }
+
+        let gains = gains();
+        let RGB8 { r, g, b } = FLASH_COLOUR;
+        let linear = self.gamma.correct([r, g, b], u16::MAX);
+        let len = self.layout.len();
+        let [first, second] = &mut self.frames;
+        let (base, out) = if self.rendered == 0 {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        let dither = &mut self.dither;
+        flash_over(
+            self.layout,
+            &base[..len],
+            &mut out[..len],
+            &self.flashed,
+            |idx| dither.apply(idx, calibrate(linear, gains)),
+        );
+
+        let _ = self.pwm.write(out[..len].iter().copied());
+        self.flash_limiter.written(Instant::now().as_micros());
+        telemetry::light_shown();
     }
 }
+
+/// Calibration gains, dimmed further during quiet hours
+fn gains() -> [u8; 3] {
+    let gains = CALIBRATION.lock(|c| c.get());
+    if quiet_hours::is_quiet() {
+        gains.map(|g| (g as u16 * QUIET_LED_GAIN as u16 / 255) as u8)
+    } else {
+        gains
+    }
+}
+
+/// A key event straight from the scan. In [`LedMode::Flash`] a press lights
+/// its key right away instead of waiting for the next frame.
+pub fn flash_key(event: &Event) {
+    if !event.is_press() || led_mode() != LedMode::Flash {
+        return;
+    }
+
+    let (x, y) = event.coord();
+    FLASH_PENDING.lock(|p| {
+        let mut keys = p.get();
+        if let Some(row) = keys.get_mut(x as usize) {
+            *row |= 1 << y;
+        }
+        p.set(keys);
+    });
+    FLASH_WAKE.set();
+}
@@ -0,0 +1,161 @@
+use embassy_futures::select::{select3, Either3};
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::Point,
+    text::{Baseline, Text},
+    Drawable,
+};
+use futures::StreamExt;
+use profont::PROFONT_7_POINT;
+use ufmt::uwrite;
+
+use crate::{
+    display_widgets::{read_in_overrides, OVERRIDE_CHAN},
+    idle::{IdlePhase, IDLE},
+    layout::{COLS, COLS_PER_SIDE, ROWS},
+    oled::{self, Oled},
+    screensaver::Screensaver,
+    system_state::{active_layer, LAYER_CHANGED, SYSTEM_STATE},
+};
+
+/// Up to three characters for each key, nul padded, generated from `LAYERS`
+/// by build.rs
+type Legend = [[[u8; 3]; COLS]; ROWS];
+
+static LEGENDS: &[Legend] = &include!(concat!(env!("OUT_DIR"), "/legend.rs"));
+
+/// Each key row is drawn as three lines of two labels
+const KEYS_PER_LINE: usize = 2;
+const LINE_HEIGHT: i32 = 7;
+const HEADER_HEIGHT: i32 = 12;
+/// A gap of four below each key row, or whatever's left when that would run
+/// off the bottom
+const ROW_HEIGHT: i32 = {
+    let spaced = LINE_HEIGHT * (COLS_PER_SIDE / KEYS_PER_LINE) as i32 + 4;
+    let fitted = (oled::ROWS as i32 - HEADER_HEIGHT) / ROWS as i32;
+    if spaced < fitted {
+        spaced
+    } else {
+        fitted
+    }
+};
+
+/// Shows the labels of this half's keys on the active layer
+pub struct LegendDisplay {
+    oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    /// First layout column on this half
+    first_col: usize,
+    sec_ticker: Ticker,
+    screensaver: Screensaver,
+}
+
+impl LegendDisplay {
+    pub fn new(
+        oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+        first_col: usize,
+    ) -> Self {
+        Self {
+            oled,
+            first_col,
+            sec_ticker: Ticker::every(Duration::from_secs(1)),
+            screensaver: Screensaver::new(),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        let mut override_timeout: Option<Instant> = None;
+
+        loop {
+            match override_timeout {
+                Some(t) => {
+                    if Instant::now() > t {
+                        override_timeout = None;
+                    }
+                }
+                None => {
+                    if SYSTEM_STATE.is_locked() {
+                        let _ = self.oled.lock().await.draw_banner("LOCKED").await;
+                    } else {
+                        if IDLE.phase() == IdlePhase::Screensaver {
+                            self.screensaver.run(self.oled).await;
+                        }
+                        self.render().await
+                    }
+                }
+            }
+
+            match select3(
+                LAYER_CHANGED.wait(),
+                self.sec_ticker.next(),
+                OVERRIDE_CHAN.recv(),
+            )
+            .await
+            {
+                Either3::First(()) => {}
+                Either3::Second(_) => {}
+                Either3::Third(o) => {
+                    read_in_overrides(self.oled, o).await;
+                    override_timeout = Some(Instant::now() + Duration::from_secs(1));
+                }
+            };
+        }
+    }
+
+    async fn render(&mut self) {
+        let layer = active_layer();
+
+        let mut header = heapless::String::<8>::new();
+        let _ = uwrite!(&mut header, "L{}", layer);
+
+        let legend = match LEGENDS.get(layer as usize) {
+            Some(legend) => legend,
+            None => {
+                let _ = self.oled.lock().await.draw_banner(&header).await;
+                return;
+            }
+        };
+
+        let header_style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
+        let label_style = MonoTextStyle::new(&FONT_4X6, BinaryColor::On);
+        let first_col = self.first_col;
+
+        let _ = self
+            .oled
+            .lock()
+            .await
+            .draw(move |d| {
+                let _ = Text::with_baseline(&header, Point::zero(), header_style, Baseline::Top)
+                    .draw(d);
+
+                for (row_idx, row) in legend.iter().enumerate() {
+                    let row_keys = &row[first_col..first_col + COLS_PER_SIDE];
+
+                    for (idx, label) in row_keys.iter().enumerate() {
+                        let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+                        let label = match core::str::from_utf8(&label[..len]) {
+                            Ok(label) => label,
+                            Err(_) => continue,
+                        };
+
+                        let x = (idx % KEYS_PER_LINE) as i32 * 17;
+                        let y = HEADER_HEIGHT
+                            + row_idx as i32 * ROW_HEIGHT
+                            + (idx / KEYS_PER_LINE) as i32 * LINE_HEIGHT;
+
+                        let _ = Text::with_baseline(
+                            label,
+                            Point::new(x, y),
+                            label_style,
+                            Baseline::Top,
+                        )
+                        .draw(d);
+                    }
+                }
+            })
+            .await;
+    }
+}
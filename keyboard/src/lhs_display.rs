@@ -1,30 +1,26 @@
-use core::sync::atomic::AtomicU32;
-
-use atomic_float::AtomicF32;
-use bitvec::{order::Lsb0, view::BitView};
-use defmt::info;
 use embassy_futures::select::{select3, Either3};
 use embassy_nrf::peripherals::TWISPI0;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, mutex::Mutex};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Instant, Ticker};
 use embedded_graphics::{
-    draw_target::DrawTarget, pixelcolor::BinaryColor, prelude::Point, Drawable, Pixel,
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::Point,
+    text::{Baseline, Text},
+    Drawable, Pixel,
 };
 use futures::StreamExt;
 
-use crate::{event::Event, oled::Oled};
-
-#[derive(defmt::Format)]
-pub struct DisplayOverride {
-    pub row: u8,
-    pub data_0: [u8; 4],
-    pub data_1: [u8; 4],
-}
-
-pub static TOTAL_KEYPRESSES: AtomicU32 = AtomicU32::new(0);
-pub static AVERAGE_KEYPRESSES: AtomicF32 = AtomicF32::new(0.0);
-pub static KEYPRESS_EVENT: Event = Event::new();
-pub static OVERRIDE_CHAN: Channel<ThreadModeRawMutex, DisplayOverride, 256> = Channel::new();
+use crate::{
+    display_widgets::{read_in_overrides, AVERAGE_KEYPRESSES, KEYPRESS_EVENT, OVERRIDE_CHAN},
+    idle::{IdlePhase, IDLE},
+    jiggle, link_health,
+    oled::Oled,
+    report_mirror,
+    screensaver::Screensaver,
+    system_state::{active_layer, SYSTEM_STATE},
+};
 
 type BongoImage = &'static [(u8, &'static [(u8, bool)])];
 
@@ -46,6 +42,15 @@ fn bongo_pixels(data: BongoImage) -> impl Iterator<Item = Pixel<BinaryColor>> {
     })
 }
 
+// These were picked when the rate read 9/32 of what it should, so they're
+// scaled up to keep the cat as it was
+/// Below this the paws take turns and lift between keypresses
+const BONGO_SLOW_CPS: f32 = 1.0;
+/// Below this the paws keep moving on their own between keypresses
+const BONGO_IDLE_CPS: f32 = 3.5;
+/// From this a keypress brings both paws down together
+const BONGO_FAST_CPS: f32 = 10.5;
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 enum BongoState {
     BothUp,
@@ -56,8 +61,8 @@ enum BongoState {
 
 impl BongoState {
     fn next(&self, cps: f32, source: BongoUpdateSource) -> BongoState {
-        if (source == BongoUpdateSource::FromKeyPress && cps < 0.3)
-            || (source == BongoUpdateSource::FromTicker && cps < 1.0)
+        if (source == BongoUpdateSource::FromKeyPress && cps < BONGO_SLOW_CPS)
+            || (source == BongoUpdateSource::FromTicker && cps < BONGO_IDLE_CPS)
         {
             match self {
                 BongoState::BothUp => Self::LeftDown,
@@ -67,7 +72,7 @@ impl BongoState {
             }
         } else if source == BongoUpdateSource::FromTicker {
             *self
-        } else if cps < 3.0 {
+        } else if cps < BONGO_FAST_CPS {
             match self {
                 BongoState::BothUp => Self::LeftDown,
                 BongoState::LeftDown => Self::RightDown,
@@ -100,6 +105,7 @@ pub struct LHSDisplay {
     // buf: heapless::String<128>,
     ticks: u32,
     bongo_state: BongoState,
+    screensaver: Screensaver,
 }
 
 #[derive(PartialEq, Eq)]
@@ -116,6 +122,7 @@ impl LHSDisplay {
             // buf: Default::default(),
             ticks: 0,
             bongo_state: BongoState::BothUp,
+            screensaver: Screensaver::new(),
         }
     }
 
@@ -129,7 +136,16 @@ impl LHSDisplay {
                         override_timeout = None;
                     }
                 }
-                None => self.render_normal().await,
+                None => {
+                    if SYSTEM_STATE.is_locked() {
+                        let _ = self.oled.lock().await.draw_banner("LOCKED").await;
+                    } else {
+                        if IDLE.phase() == IdlePhase::Screensaver {
+                            self.screensaver.run(self.oled).await;
+                        }
+                        self.render_normal().await
+                    }
+                }
             }
 
             match select3(
@@ -146,7 +162,7 @@ impl LHSDisplay {
                     self.update_bongo(BongoUpdateSource::FromTicker);
                 }
                 Either3::Third(o) => {
-                    self.read_in_overrides(o).await;
+                    read_in_overrides(self.oled, o).await;
                     override_timeout = Some(Instant::now() + Duration::from_secs(1));
                 }
             };
@@ -169,55 +185,13 @@ impl LHSDisplay {
         self.ticks = self.ticks.wrapping_add(1);
     }
 
-    async fn read_in_overrides(&mut self, initial: DisplayOverride) {
-        let mut oled = self.oled.lock().await;
-        let mut should_flush = initial.row >= 126;
-        oled.draw_no_clear_no_flush(|d| {
-            for (col, pix) in initial.data_0.view_bits::<Lsb0>().into_iter().enumerate() {
-                let _ = Pixel(
-                    Point::new(col as i32, initial.row as i32),
-                    BinaryColor::from(*pix),
-                )
-                .draw(d);
-            }
-
-            for (col, pix) in initial.data_1.view_bits::<Lsb0>().into_iter().enumerate() {
-                let _ = Pixel(
-                    Point::new(col as i32, 1 + initial.row as i32),
-                    BinaryColor::from(*pix),
-                )
-                .draw(d);
-            }
-
-            while let Ok(o) = OVERRIDE_CHAN.try_recv() {
-                should_flush ^= o.row >= 126;
-                for (col, pix) in o.data_0.view_bits::<Lsb0>().into_iter().enumerate() {
-                    let _ = Pixel(
-                        Point::new(col as i32, o.row as i32),
-                        BinaryColor::from(*pix),
-                    )
-                    .draw(d);
-                }
-
-                for (col, pix) in o.data_1.view_bits::<Lsb0>().into_iter().enumerate() {
-                    let _ = Pixel(
-                        Point::new(col as i32, 1 + o.row as i32),
-                        BinaryColor::from(*pix),
-                    )
-                    .draw(d);
-                }
-            }
-        });
-        if should_flush {
-            let now = Instant::now();
-            let _ = oled.flush().await;
-            let flush_time = now.elapsed();
-            info!("Flush time: {}ms", flush_time.as_millis());
-        }
-    }
-
     async fn render_normal(&mut self) {
         let (left_paw, right_paw) = self.bongo_state.images();
+        let link_down = !link_health::up();
+        let link_degraded = link_health::degraded();
+        let presence_mode = jiggle::active();
+        let mirroring_reports = report_mirror::active();
+        let layer = active_layer();
 
         {
             let _ = self
@@ -228,6 +202,39 @@ impl LHSDisplay {
                     let _ = d.draw_iter(bongo_pixels(BONGO_BASE));
                     let _ = d.draw_iter(bongo_pixels(left_paw));
                     let _ = d.draw_iter(bongo_pixels(right_paw));
+
+                    let style = MonoTextStyle::new(&FONT_4X6, BinaryColor::On);
+                    // a link that's down is worse news than one that's struggling
+                    let link = if link_down {
+                        Some("NOLINK")
+                    } else if link_degraded {
+                        Some("LINK")
+                    } else {
+                        None
+                    };
+                    if let Some(link) = link {
+                        let _ = Text::with_baseline(link, Point::new(0, 122), style, Baseline::Top)
+                            .draw(d);
+                    }
+                    if presence_mode {
+                        let _ =
+                            Text::with_baseline("JIG", Point::new(52, 122), style, Baseline::Top)
+                                .draw(d);
+                    }
+                    if mirroring_reports {
+                        let _ =
+                            Text::with_baseline("LOG", Point::new(26, 122), style, Baseline::Top)
+                                .draw(d);
+                    }
+                    // in the bottom right corner above the flags, the base
+                    // layer goes without
+                    if layer != 0 {
+                        let label = [b'L', b'0' + layer];
+                        let label = core::str::from_utf8(&label).unwrap_or("L?");
+                        let _ =
+                            Text::with_baseline(label, Point::new(24, 116), style, Baseline::Top)
+                                .draw(d);
+                    }
                 })
                 .await;
         }
@@ -7,22 +7,55 @@
 
 extern crate alloc;
 
+pub mod action_chords;
 pub mod async_rw;
+pub mod bootloader;
+pub mod channel_stats;
+pub mod chord_guard;
+pub mod clock;
+pub mod consumer;
 pub mod cps;
+pub mod debounce;
+pub mod display_bus;
+pub mod display_widgets;
+pub mod dither;
 pub mod event;
+pub mod fast_path;
+pub mod flash;
+pub mod heap;
+pub mod host_dispatch;
+pub mod host_log;
+pub mod idle;
+pub mod jiggle;
+pub mod key_event;
+pub mod key_grid;
+pub mod keypress_store;
 pub mod layout;
+pub mod legend_display;
 pub mod leds;
+#[cfg(feature = "link-capture")]
+pub mod link_capture;
+pub mod link_health;
+pub mod log_level;
 pub mod lhs_display;
 pub mod matrix;
 pub mod messages;
 pub mod oled;
+pub mod profiling;
+pub mod quiet_hours;
+pub mod remap;
+pub mod report_mirror;
 pub mod rhs_display;
+pub mod safe_mode;
+pub mod screensaver;
+pub mod self_test;
+pub mod settings;
+pub mod system_state;
+pub mod telemetry;
+pub mod tuning;
+pub mod version_check;
 pub mod wrapping_id;
 
-use core::alloc::Layout;
-
-use alloc_cortex_m::CortexMHeap;
-
 #[cfg(feature = "debugger")]
 use defmt_rtt as _;
 use embassy_time::Duration;
@@ -32,7 +65,11 @@ use embassy_nrf::uarte;
 use panic_probe as _;
 
 pub const UART_BAUD: uarte::Baudrate = uarte::Baudrate::BAUD460800;
-pub const POLL_PERIOD: Duration = Duration::from_micros(200);
+/// `UART_BAUD` in bits per second, for working out link timings
+pub const UART_BAUD_BPS: u32 = 460_800;
+pub const POLL_PERIOD: Duration = Duration::from_micros(keyboard_shared::SCAN_PERIOD_US as u64);
+/// Scans a key has to stay changed for before the change is accepted, the
+/// starting point for `debounce::KeyDebouncer`
 pub const DEBOUNCER_TICKS: u16 = 50;
 
 #[cfg(all(not(feature = "debugger"), feature = "log-noop"))]
@@ -50,17 +87,4 @@ macro_rules! forever {
 #[cfg(feature = "panic-reset")]
 use panic_reset as _;
 
-#[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
-
-pub fn init_heap() {
-    use core::mem::MaybeUninit;
-    const HEAP_SIZE: usize = 8192;
-    static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-    unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
-}
-
-#[alloc_error_handler]
-fn oom(_: Layout) -> ! {
-    panic!("oom");
-}
+pub use heap::init_heap;
@@ -0,0 +1,104 @@
+//! Recording the raw bytes that cross the link between the halves, so a
+//! misbehaving link can be looked at afterwards with `keyboard_control
+//! capture`.
+//!
+//! Only built with the `link-capture` feature. Bytes read are kept a frame at
+//! a time and bytes written a frame per write, each as a `CaptureRecord` in a
+//! ring that throws away its oldest records to make room. Both halves record
+//! their own link, but only the left half's can be read by the host.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use keyboard_shared::{
+    CaptureDirection, CaptureRecord, KeyboardToHost, CAPTURE_CHUNK, CAPTURE_HEADER_LEN,
+};
+
+/// Size of the ring, headers included
+const CAPTURE_BYTES: usize = 4096;
+/// How long a read can leave the capture paused, in case the host goes away
+/// partway through
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Capture {
+    ring: heapless::Deque<u8, CAPTURE_BYTES>,
+    /// When the host last read a chunk, while it's partway through
+    paused: Option<Instant>,
+}
+
+static CAPTURE: Mutex<ThreadModeRawMutex, RefCell<Capture>> = Mutex::new(RefCell::new(Capture {
+    ring: heapless::Deque::new(),
+    paused: None,
+}));
+
+/// Record `bytes` going `direction`, unless the host is reading the capture
+pub fn record(direction: CaptureDirection, bytes: &[u8]) {
+    let record = CaptureRecord {
+        direction,
+        at_us: Instant::now().as_micros() as u32,
+        bytes,
+    };
+
+    CAPTURE.lock(|c| {
+        let mut c = c.borrow_mut();
+        match c.paused {
+            Some(at) if at.elapsed() < PAUSE_TIMEOUT => return,
+            _ => c.paused = None,
+        }
+
+        let len = CAPTURE_HEADER_LEN + bytes.len();
+        while CAPTURE_BYTES - c.ring.len() < len {
+            drop_oldest(&mut c.ring);
+        }
+
+        for byte in record.header().into_iter().chain(bytes.iter().copied()) {
+            let _ = c.ring.push_back(byte);
+        }
+    });
+}
+
+fn drop_oldest(ring: &mut heapless::Deque<u8, CAPTURE_BYTES>) {
+    // the length is the last byte of the header
+    let len = ring
+        .iter()
+        .nth(CAPTURE_HEADER_LEN - 1)
+        .map_or(ring.len(), |&len| CAPTURE_HEADER_LEN + len as usize);
+
+    for _ in 0..len {
+        ring.pop_front();
+    }
+}
+
+/// The `CaptureChunk` reply for a `ReadCapture` request
+pub fn chunk(offset: u16) -> KeyboardToHost {
+    CAPTURE.lock(|c| {
+        let mut c = c.borrow_mut();
+        let total = c.ring.len();
+        let start = (offset as usize).min(total);
+        let end = (start + CAPTURE_CHUNK).min(total);
+
+        let mut data = [0u8; CAPTURE_CHUNK];
+        for (slot, &byte) in data.iter_mut().zip(c.ring.iter().skip(start)) {
+            *slot = byte;
+        }
+
+        // each read holds it a while longer, until the last one lets it go
+        c.paused = (end < total).then(Instant::now);
+
+        KeyboardToHost::CaptureChunk {
+            offset: start as u16,
+            total: total as u16,
+            len: (end - start) as u8,
+            data,
+        }
+    })
+}
+
+pub fn clear() {
+    CAPTURE.lock(|c| {
+        let mut c = c.borrow_mut();
+        while c.ring.pop_front().is_some() {}
+        c.paused = None;
+    });
+}
@@ -0,0 +1,116 @@
+//! Watches how often commands to the other half need retransmitting. When the
+//! link is struggling, bulk traffic like display pixels is dropped so key
+//! events still get through.
+//!
+//! Also tracks whether the link is up at all. Until the other half answers
+//! `Hello` nothing is listening, so commands aren't sent rather than burning
+//! through their retries, see `LinkGate`. After that the halves swap
+//! heartbeats, and the link is down while they go missing. Commands are given
+//! up on while it's down.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_time::Duration;
+
+use crate::event::Event;
+
+/// Set from a `LinkHealth` sampling the retransmits
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+/// How often the left half sends `Ping`
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_millis(500);
+/// Heartbeats in a row without word from the other half before the link
+/// counts as down
+pub const MISSED_HEARTBEATS: u8 = 3;
+
+static UP: AtomicBool = AtomicBool::new(false);
+static CAME_UP: Event = Event::new();
+/// Set by each heartbeat from the other half, taken by `Heartbeat::tick`
+static HEARD: AtomicBool = AtomicBool::new(false);
+/// Times the link went down after being up
+pub static LINK_LOSSES: AtomicU32 = AtomicU32::new(0);
+/// `WritePixels` for the other half dropped while the link was degraded
+pub static DROPPED_PIXEL_WRITES: AtomicU32 = AtomicU32::new(0);
+
+pub fn degraded() -> bool {
+    DEGRADED.load(Ordering::Relaxed)
+}
+
+pub fn set_degraded(degraded: bool) {
+    DEGRADED.store(degraded, Ordering::Relaxed);
+}
+
+pub fn dropped_pixel_write() {
+    DROPPED_PIXEL_WRITES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Whether the other half has answered `Hello`
+pub fn up() -> bool {
+    UP.load(Ordering::Relaxed)
+}
+
+/// The other half answered `Hello`, true if the link wasn't already up
+pub fn link_up() -> bool {
+    let was_up = UP.swap(true, Ordering::Relaxed);
+    if !was_up {
+        CAME_UP.set();
+    }
+    !was_up
+}
+
+/// A heartbeat arrived from the other half, true if the link wasn't already
+/// up
+pub fn heard() -> bool {
+    HEARD.store(true, Ordering::Relaxed);
+    link_up()
+}
+
+/// True if the link was up
+fn link_down() -> bool {
+    let was_up = UP.swap(false, Ordering::Relaxed);
+    if was_up {
+        LINK_LOSSES.fetch_add(1, Ordering::Relaxed);
+    }
+    was_up
+}
+
+/// Whether the link went down after being up and hasn't come back. Commands
+/// are given up on rather than left waiting for it.
+pub fn lost() -> bool {
+    !up() && LINK_LOSSES.load(Ordering::Relaxed) != 0
+}
+
+/// Wait for the link to come up, only one task can wait at a time
+pub async fn wait_up() {
+    if !up() {
+        CAME_UP.wait().await;
+    }
+}
+
+/// Counts the heartbeats missed in a row, fed every `HEARTBEAT_PERIOD`
+pub struct Heartbeat {
+    missed: u8,
+}
+
+impl Heartbeat {
+    pub const fn new() -> Self {
+        Self { missed: 0 }
+    }
+
+    /// Check for a heartbeat since the last tick, true if the link has just
+    /// gone down
+    pub fn tick(&mut self) -> bool {
+        if HEARD.swap(false, Ordering::Relaxed) {
+            self.missed = 0;
+            return false;
+        }
+
+        self.missed = self.missed.saturating_add(1);
+        self.missed >= MISSED_HEARTBEATS && link_down()
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,69 @@
+//! How much the hot paths log, changeable at runtime so a debugger can stay
+//! attached while streaming pixels without the RTT traffic throwing the
+//! timing off. Without the `debugger` feature the checks compile away.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+pub use keyboard_shared::LogLevel;
+
+/// Frame level logs kept at `LogLevel::Sampled`, one in this many
+pub const SAMPLE_EVERY: u32 = 64;
+
+const DEFAULT: LogLevel = if cfg!(feature = "log-verbose") {
+    LogLevel::Verbose
+} else {
+    LogLevel::Quiet
+};
+
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT as u8);
+
+pub fn set(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn get() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Quiet,
+        1 => LogLevel::Sampled,
+        _ => LogLevel::Verbose,
+    }
+}
+
+/// Whether a log at `level` should be written
+pub fn enabled(level: LogLevel) -> bool {
+    cfg!(feature = "debugger") && get() >= level
+}
+
+/// Whether a frame level log should be written, `count` is the call site's
+/// own counter
+pub fn sample(count: &AtomicU32) -> bool {
+    match get() {
+        _ if !cfg!(feature = "debugger") => false,
+        LogLevel::Quiet => false,
+        LogLevel::Sampled => count.fetch_add(1, Ordering::Relaxed) % SAMPLE_EVERY == 0,
+        LogLevel::Verbose => true,
+    }
+}
+
+/// `defmt::debug!` when the verbosity is at least `level`, one of the
+/// `LogLevel` variants
+#[macro_export]
+macro_rules! log_if {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log_level::enabled($crate::log_level::LogLevel::$level) {
+            ::defmt::debug!($($arg)*);
+        }
+    };
+}
+
+/// `defmt::debug!` for something that happens every frame, only every
+/// `SAMPLE_EVERY`th one is logged at `LogLevel::Sampled`
+#[macro_export]
+macro_rules! log_sampled {
+    ($($arg:tt)*) => {{
+        static COUNT: ::core::sync::atomic::AtomicU32 = ::core::sync::atomic::AtomicU32::new(0);
+        if $crate::log_level::sample(&COUNT) {
+            ::defmt::debug!($($arg)*);
+        }
+    }};
+}
@@ -1,9 +1,148 @@
+//! Scanning the key matrix.
+//!
+//! keyberon's matrix always drives the rows in the same order with no pause
+//! before reading the columns, which on a long cable can leave a row's
+//! charge on the next one and show up as phantom presses. This scans the
+//! same way by default, but the drive order and a settle delay can be
+//! changed at runtime to work around that.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use embassy_nrf::gpio::{AnyPin, Input, Output};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use keyboard_shared::{ScanOrder, MAX_SCAN_SETTLE_US};
+
+use crate::layout::{COLS_PER_SIDE, ROWS};
+
+pub type MatrixState = [[bool; COLS_PER_SIDE]; ROWS];
+
+/// CPU cycles per microsecond at 64MHz, for busy waiting
+const CYCLES_PER_US: u32 = 64;
+
+static SCAN_ORDER: Mutex<ThreadModeRawMutex, Cell<ScanOrder>> =
+    Mutex::new(Cell::new(ScanOrder::Fixed));
+static SETTLE_US: AtomicU8 = AtomicU8::new(0);
+
+/// Presses that lasted a single scan, far too short for the debouncer to
+/// confirm, so most likely crosstalk or noise
+pub static PHANTOM_PRESSES: AtomicU32 = AtomicU32::new(0);
+/// `PHANTOM_PRESSES` from the right half, kept by the left
+pub static REMOTE_PHANTOM_PRESSES: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_scan_order(order: ScanOrder) {
+    SCAN_ORDER.lock(|o| o.set(order));
+}
+
+/// Wait this long after driving a row before reading the columns
+pub fn set_settle_us(us: u8) {
+    SETTLE_US.store(us.min(MAX_SCAN_SETTLE_US), Ordering::Relaxed);
+}
+
+pub struct KeyMatrix {
+    cols: [Input<'static, AnyPin>; COLS_PER_SIDE],
+    rows: [Output<'static, AnyPin>; ROWS],
+    scans: u32,
+    /// xorshift state for `ScanOrder::Random`
+    rng: u32,
+    /// The raw state from the last two scans, for spotting phantom presses
+    last: MatrixState,
+    before_last: MatrixState,
+}
+
+impl KeyMatrix {
+    pub fn new(
+        cols: [Input<'static, AnyPin>; COLS_PER_SIDE],
+        mut rows: [Output<'static, AnyPin>; ROWS],
+    ) -> Self {
+        for row in &mut rows {
+            row.set_high();
+        }
+
+        Self {
+            cols,
+            rows,
+            scans: 0,
+            rng: 0x2545_f491,
+            last: [[false; COLS_PER_SIDE]; ROWS],
+            before_last: [[false; COLS_PER_SIDE]; ROWS],
+        }
+    }
+
+    /// Scan every key, true for pressed
+    pub fn get(&mut self) -> MatrixState {
+        let order = self.next_order();
+        let settle_cycles = SETTLE_US.load(Ordering::Relaxed) as u32 * CYCLES_PER_US;
+        let mut state = [[false; COLS_PER_SIDE]; ROWS];
+
+        for row in order {
+            self.rows[row].set_low();
+            if settle_cycles > 0 {
+                cortex_m::asm::delay(settle_cycles);
+            }
+            for (col, input) in self.cols.iter().enumerate() {
+                state[row][col] = input.is_low();
+            }
+            self.rows[row].set_high();
+        }
+
+        self.count_phantoms(&state);
+        state
+    }
+
+    fn next_order(&mut self) -> [usize; ROWS] {
+        let mut order: [usize; ROWS] = core::array::from_fn(|idx| idx);
+
+        match SCAN_ORDER.lock(|o| o.get()) {
+            ScanOrder::Fixed => {}
+            ScanOrder::Rotate => order.rotate_left(self.scans as usize % ROWS),
+            ScanOrder::Random => {
+                for idx in (1..ROWS).rev() {
+                    let swap = self.next_random() as usize % (idx + 1);
+                    order.swap(idx, swap);
+                }
+            }
+        }
+
+        self.scans = self.scans.wrapping_add(1);
+        order
+    }
+
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    /// Count keys that were up two scans ago, down last scan and up again now
+    fn count_phantoms(&mut self, state: &MatrixState) {
+        let phantoms = self
+            .before_last
+            .iter()
+            .flatten()
+            .zip(self.last.iter().flatten())
+            .zip(state.iter().flatten())
+            .filter(|((&before_last, &last), &now)| !before_last && last && !now)
+            .count() as u32;
+        if phantoms > 0 {
+            PHANTOM_PRESSES.fetch_add(phantoms, Ordering::Relaxed);
+        }
+
+        self.before_last = self.last;
+        self.last = *state;
+    }
+}
+
 #[macro_export]
 macro_rules! build_matrix {
     ($p:ident) => {{
-        use embassy_nrf::gpio::{Input, Level, OutputDrive, Pin, Pull};
-        use keyberon::matrix::Matrix;
-        Matrix::new(
+        use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pin, Pull};
+        $crate::matrix::KeyMatrix::new(
             [
                 Input::new($p.P0_31.degrade(), Pull::Up),
                 Input::new($p.P0_29.degrade(), Pull::Up),
@@ -17,8 +156,10 @@ macro_rules! build_matrix {
                 Output::new($p.P0_24.degrade(), Level::High, OutputDrive::Standard),
                 Output::new($p.P1_00.degrade(), Level::High, OutputDrive::Standard),
                 Output::new($p.P0_11.degrade(), Level::High, OutputDrive::Standard),
+                // the v3 PCB's extra thumb row
+                #[cfg(feature = "pcb-v3")]
+                Output::new($p.P1_06.degrade(), Level::High, OutputDrive::Standard),
             ],
         )
-        .unwrap()
     }};
 }
@@ -1,168 +1,468 @@
 use alloc::sync::Arc;
-use core::hash::Hash;
-use defmt::{debug, warn, Format};
+use core::{
+    convert::Infallible,
+    hash::Hash,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+use defmt::{warn, Format};
+use embassy_futures::select::{select, select3, Either3};
 use embassy_nrf::uarte::{Instance, Uarte, UarteRx, UarteTx};
 use embassy_sync::{
     blocking_mutex::raw::ThreadModeRawMutex,
     channel::{Channel, Sender},
     mutex::Mutex,
+    signal::Signal,
 };
-use embassy_time::{with_timeout, Duration};
+use embassy_time::{with_timeout, Duration, Timer};
 use futures::Future;
-use postcard::accumulator::{CobsAccumulator, FeedResult};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Serialize};
 
 pub use keyboard_shared::*;
 
 use crate::{
     async_rw::{AsyncRead, AsyncWrite},
     event::Event,
+    heap,
+    layout::{CHORD_ROW, COLS},
+    link_health,
+    log_sampled, UART_BAUD_BPS,
 };
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Format, Hash, Copy, Clone)]
-pub struct KeyLocation(u8);
+// packed events keep three bits for the row, chord row included, and four
+// for the column
+const _: () = assert!(CHORD_ROW < 8 && COLS <= 16);
 
-impl KeyLocation {
-    pub fn unpack(self) -> (u8, u8) {
-        ((self.0 >> 4) & 0xf, self.0 & 0xf)
+pub fn unpack_event(packed: u8) -> keyberon::layout::Event {
+    match KeyLocation::unpack_key(packed) {
+        (x, y, true) => keyberon::layout::Event::Release(x, y),
+        (x, y, false) => keyberon::layout::Event::Press(x, y),
     }
+}
 
-    pub fn pack(x: u8, y: u8) -> Self {
-        Self(((x & 0xf) << 4) | (y & 0xf))
+/// The key event a `KeyPressed` or `KeyReleased` carries
+pub fn as_keyberon_event(msg: &SubToDom) -> Option<keyberon::layout::Event> {
+    match msg {
+        SubToDom::KeyPressed(v) => {
+            let (x, y) = v.unpack();
+            Some(keyberon::layout::Event::Press(x, y))
+        }
+        SubToDom::KeyReleased(v) => {
+            let (x, y) = v.unpack();
+            Some(keyberon::layout::Event::Release(x, y))
+        }
+        _ => None,
     }
 }
 
+/// Pack a scan's events into one `KeyEvents`, or `RawKeyEvents` if they
+/// haven't been chorded, see [`SubToDom::key_events`]
+pub fn key_events(batch: &[keyberon::layout::Event], raw: bool) -> SubToDom {
+    let events = batch.iter().map(|event| {
+        let (x, y) = event.coord();
+        (x, y, event.is_release())
+    });
+    SubToDom::key_events(events, raw)
+}
+
+/// Frames dropped by any `Eventer` on this half because they failed to
+/// decode or validate
+pub static CORRUPT_FRAMES: AtomicU32 = AtomicU32::new(0);
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Format, Hash, Clone)]
-pub enum DomToSub {
-    ResyncLeds(u16),
-    Reset,
-    SyncKeypresses(u16),
-    WritePixels {
-        row: u8,
-        data_0: [u8; 4],
-        data_1: [u8; 4],
-    },
-    KeyPressed(KeyLocation),
+fn corrupt_frame() {
+    CORRUPT_FRAMES.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Format, Hash, Clone)]
-pub enum SubToDom {
-    KeyPressed(KeyLocation),
-    KeyReleased(KeyLocation),
+/// Reads from the link that failed in the transport, such as UART framing or
+/// overrun errors
+pub static LINK_READ_ERRORS: AtomicU32 = AtomicU32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// `LINK_READ_ERRORS` split up by kind, indexed by `LinkErrorKind`
+static LINK_ERRORS: [AtomicU32; LinkErrorKind::COUNT] = [NO_ERRORS; LinkErrorKind::COUNT];
+/// Bursts of link errors that had this half say `Hello` again
+pub static LINK_RESYNCS: AtomicU32 = AtomicU32::new(0);
+/// Set after a burst of link errors, each half then says `Hello` again so the
+/// other knows it's back. Only one task can wait on it.
+pub static LINK_RESYNC: Event = Event::new();
+
+pub fn link_errors() -> [u32; LinkErrorKind::COUNT] {
+    core::array::from_fn(|kind| LINK_ERRORS[kind].load(Ordering::Relaxed))
 }
 
-impl SubToDom {
-    pub fn as_keyberon_event(&self) -> Option<keyberon::layout::Event> {
-        match self {
-            SubToDom::KeyPressed(v) => {
-                let (x, y) = v.unpack();
-                Some(keyberon::layout::Event::Press(x, y))
-            }
-            SubToDom::KeyReleased(v) => {
-                let (x, y) = v.unpack();
-                Some(keyberon::layout::Event::Release(x, y))
-            }
+/// Why reading a frame from the other half failed
+#[derive(Debug, Format)]
+pub enum LinkError<E> {
+    /// The transport itself errored
+    Io(E),
+    /// The transport saw a problem on the line
+    Line(LinkErrorKind),
+    /// The frame didn't decode, or was too long to be one
+    Deser,
+    /// The frame decoded but failed its checksum
+    Checksum,
+}
+
+/// Commands sent again by any `Eventer` on this half because no ack arrived
+/// in time
+pub static RETRANSMITS: AtomicU32 = AtomicU32::new(0);
+/// Commands any `Eventer` on this half gave up on, best effort ones after
+/// running out of retries and any of them once the link went down. This
+/// climbing steadily means the other half isn't there.
+pub static FAILED_SENDS: AtomicU32 = AtomicU32::new(0);
+
+/// Most frames any `Eventer` on this half has had waiting to go out, commands
+/// on both lanes then acks
+pub static COMMAND_QUEUE_HIGH_WATER: AtomicU8 = AtomicU8::new(0);
+pub static ACK_QUEUE_HIGH_WATER: AtomicU8 = AtomicU8::new(0);
+
+/// Count a frame into one of an `Eventer`'s queues
+fn queued(depth: &AtomicU8, high_water: &AtomicU8) {
+    let depth = depth.fetch_add(1, Ordering::Relaxed) + 1;
+    high_water.fetch_max(depth, Ordering::Relaxed);
+}
+
+fn dequeued(depth: &AtomicU8) {
+    depth.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// A start and a stop bit for each byte, there's no parity
+const UART_BITS_PER_BYTE: u64 = 10;
+/// A COBS encoded ack
+const ACK_FRAME_BYTES: usize = 8;
+/// Time for the other half to get round to handling a frame and sending the
+/// ack, it may be busy writing out an LED frame
+const HANDLING_SLACK_US: u64 = 2000;
+
+/// How long a command of `frame_bytes` and its ack take to cross the UART
+/// link and be handled
+pub const fn round_trip(baud: u32, frame_bytes: usize) -> Duration {
+    let bits = (frame_bytes + ACK_FRAME_BYTES) as u64 * UART_BITS_PER_BYTE;
+    Duration::from_micros(bits * 1_000_000 / baud as u64 + HANDLING_SLACK_US)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Reliability {
+    /// Keep retrying until acked, or until the link goes down after being up
+    Reliable,
+    /// Give up after `max_retries`, for commands that are superseded by the
+    /// next one anyway
+    BestEffort,
+}
+
+/// A command was given up on without being acked, see [`FAILED_SENDS`]
+#[derive(Debug, Format)]
+pub struct SendFailed;
+
+/// Where a send says whether it was acked, see [`CommandQueue::send_acked`]
+pub type SendOutcome = Signal<ThreadModeRawMutex, Result<(), SendFailed>>;
+
+/// How a command is retried when the ack doesn't arrive
+#[derive(Clone, Copy)]
+pub struct SendPolicy {
+    pub initial_timeout: Duration,
+    /// The timeout is multiplied by this after each retry
+    pub backoff: u32,
+    /// Retries before the timeout stops growing, or before giving up on a
+    /// best effort command
+    pub max_retries: u8,
+    pub reliability: Reliability,
+    pub priority: Priority,
+    report_to: Option<&'static SendOutcome>,
+}
+
+impl Format for SendPolicy {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "timeout {} backoff {} retries {} {} {}",
+            self.initial_timeout,
+            self.backoff,
+            self.max_retries,
+            self.reliability,
+            self.priority,
+        );
+    }
+}
+
+impl SendPolicy {
+    /// This policy, giving up on a best effort command after `max_retries`
+    pub const fn with_retries(self, max_retries: u8) -> Self {
+        Self {
+            max_retries,
+            ..self
         }
     }
 
-    pub fn key_pressed(x: u8, y: u8) -> Self {
-        Self::KeyPressed(KeyLocation::pack(x, y))
+    /// Key events and anything else the user is waiting to see
+    pub const KEY_EVENT: Self = Self {
+        initial_timeout: round_trip(UART_BAUD_BPS, 20),
+        backoff: 2,
+        max_retries: 4,
+        reliability: Reliability::Reliable,
+        priority: Priority::High,
+        report_to: None,
+    };
+
+    /// Pixel rows, stats replies and anything else that can fill a frame
+    pub const BULK: Self = Self {
+        initial_timeout: round_trip(UART_BAUD_BPS, BUF_SIZE),
+        backoff: 2,
+        max_retries: 4,
+        reliability: Reliability::Reliable,
+        priority: Priority::Low,
+        report_to: None,
+    };
+
+    /// Periodic syncing where a lost command is made up for by a later one
+    pub const BACKGROUND: Self = Self {
+        initial_timeout: round_trip(UART_BAUD_BPS, 20),
+        backoff: 4,
+        max_retries: 2,
+        reliability: Reliability::BestEffort,
+        priority: Priority::Low,
+        report_to: None,
+    };
+
+    /// `Ping` and `Pong`, high priority so bulk traffic can't hold them up
+    /// long enough to look like the link is down. There's another along in
+    /// half a second, so they're barely retried.
+    pub const HEARTBEAT: Self = Self {
+        initial_timeout: round_trip(UART_BAUD_BPS, 20),
+        backoff: 2,
+        max_retries: 1,
+        reliability: Reliability::BestEffort,
+        priority: Priority::High,
+        report_to: None,
+    };
+}
+
+impl From<Traffic> for SendPolicy {
+    fn from(traffic: Traffic) -> Self {
+        match traffic {
+            Traffic::KeyEvent => Self::KEY_EVENT,
+            Traffic::Bulk => Self::BULK,
+            Traffic::Background => Self::BACKGROUND,
+        }
+    }
+}
+
+type Lane<T, const N: usize> = Channel<ThreadModeRawMutex, (T, SendPolicy), N>;
+
+/// Commands waiting for an `Eventer` to send them, in a lane for each
+/// `Priority`. Each lane is sent from and retried on its own, so a key event
+/// never waits for a pixel row to be acked.
+pub struct CommandQueue<T, const N: usize> {
+    lanes: Lanes<Lane<T, N>>,
+}
+
+impl<T, const N: usize> CommandQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            lanes: Lanes::new(Channel::new(), Channel::new()),
+        }
     }
 
-    pub fn key_released(x: u8, y: u8) -> Self {
-        Self::KeyReleased(KeyLocation::pack(x, y))
+    pub fn lane(&self, priority: Priority) -> &Lane<T, N> {
+        self.lanes.lane(priority)
+    }
+
+    /// Queue a command in the lane its policy asks for
+    pub async fn send(&self, (cmd, policy): (T, SendPolicy)) {
+        self.lane(policy.priority).send((cmd, policy)).await;
+    }
+
+    /// Queue a command and wait until it's acked or given up on. `outcome`
+    /// can't be shared with another caller waiting at the same time.
+    pub async fn send_acked(
+        &self,
+        (cmd, policy): (T, SendPolicy),
+        outcome: &'static SendOutcome,
+    ) -> Result<(), SendFailed> {
+        let policy = SendPolicy {
+            report_to: Some(outcome),
+            ..policy
+        };
+        self.send((cmd, policy)).await;
+        outcome.wait().await
+    }
+}
+
+impl<T, const N: usize> Default for CommandQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 const BUF_SIZE: usize = 128;
 
+/// Acks going out, kept apart from commands so they don't wait behind bulk
+/// traffic and leave the other half retransmitting
+const ACK_QUEUE: usize = 4;
+/// High priority commands going out. Each lane has at most one command in
+/// flight, so this only has to cover them being sent back to back.
+const HIGH_QUEUE: usize = 4;
+
 pub struct Eventer<'a, T, U, TX, RX> {
     tx: TX,
     rx: RX,
-    mix_chan: Channel<ThreadModeRawMutex, CmdOrAck<T>, 16>,
+    high_chan: Channel<ThreadModeRawMutex, Command<T>, HIGH_QUEUE>,
+    /// Low priority commands going out
+    mix_chan: Channel<ThreadModeRawMutex, Command<T>, 16>,
+    ack_chan: Channel<ThreadModeRawMutex, Ack, ACK_QUEUE>,
+    mix_depth: AtomicU8,
+    ack_depth: AtomicU8,
     out_chan: Sender<'a, ThreadModeRawMutex, U, 16>,
     waiters: Mutex<ThreadModeRawMutex, heapless::FnvIndexMap<u8, Arc<Event>, 128>>,
+    /// Whether the other end went away after being there, see
+    /// `link_health::lost`
+    link_lost: fn() -> bool,
+    /// Whether this is the link between the halves, which is captured
+    #[cfg(feature = "link-capture")]
+    capture: bool,
 }
 
 struct EventSender<'e, T> {
-    mix_chan: &'e Channel<ThreadModeRawMutex, CmdOrAck<T>, 16>,
+    high_chan: &'e Channel<ThreadModeRawMutex, Command<T>, HIGH_QUEUE>,
+    mix_chan: &'e Channel<ThreadModeRawMutex, Command<T>, 16>,
+    mix_depth: &'e AtomicU8,
     waiters: &'e Mutex<ThreadModeRawMutex, heapless::FnvIndexMap<u8, Arc<Event>, 128>>,
+    link_lost: fn() -> bool,
 }
 
 struct EventOutProcessor<'e, T, TX> {
     tx: &'e mut TX,
-    mix_chan: &'e Channel<ThreadModeRawMutex, CmdOrAck<T>, 16>,
+    high_chan: &'e Channel<ThreadModeRawMutex, Command<T>, HIGH_QUEUE>,
+    mix_chan: &'e Channel<ThreadModeRawMutex, Command<T>, 16>,
+    ack_chan: &'e Channel<ThreadModeRawMutex, Ack, ACK_QUEUE>,
+    mix_depth: &'e AtomicU8,
+    ack_depth: &'e AtomicU8,
+    #[cfg(feature = "link-capture")]
+    capture: bool,
 }
 
-struct EventInProcessor<'a, 'e, T, U, RX> {
+struct EventInProcessor<'a, 'e, U, RX> {
     rx: &'e mut RX,
     out_chan: Sender<'a, ThreadModeRawMutex, U, 16>,
-    mix_chan: &'e Channel<ThreadModeRawMutex, CmdOrAck<T>, 16>,
+    ack_chan: &'e Channel<ThreadModeRawMutex, Ack, ACK_QUEUE>,
+    ack_depth: &'e AtomicU8,
     waiters: &'e Mutex<ThreadModeRawMutex, heapless::FnvIndexMap<u8, Arc<Event>, 128>>,
+    /// The frame being read, kept for the capture
+    #[cfg(feature = "link-capture")]
+    captured: Option<heapless::Vec<u8, BUF_SIZE>>,
 }
 
-impl<'a, 'e, T, U, RX> EventInProcessor<'a, 'e, T, U, RX>
+impl<'a, 'e, U, RX> EventInProcessor<'a, 'e, U, RX>
 where
     U: DeserializeOwned + Hash + Format,
     RX: AsyncRead,
+    <RX as AsyncRead>::Error: Format,
 {
-    async fn recv_task_inner(&mut self) -> Option<()> {
-        let mut accumulator = CobsAccumulator::<BUF_SIZE>::new();
-
+    /// Read and handle frames until one fails. Bytes are read one at a time,
+    /// so nothing after a bad frame is lost by returning early.
+    async fn recv_task_inner(
+        &mut self,
+        reader: &mut LinkReader<BUF_SIZE>,
+    ) -> Result<Infallible, LinkError<RX::Error>> {
         loop {
             let mut buf = [0u8; 1];
-            self.rx.read(&mut buf).await.ok()?;
-            let mut window = &buf[..];
-
-            'cobs: while !window.is_empty() {
-                window = match accumulator.feed(window) {
-                    FeedResult::Consumed => break 'cobs,
-                    FeedResult::OverFull(buf) => buf,
-                    FeedResult::DeserError(buf) => {
-                        warn!(
-                            "Message decoder failed to deserialize a message of type {}: {:?}",
-                            core::any::type_name::<CmdOrAck<U>>(),
-                            buf
-                        );
-                        buf
-                    }
-                    FeedResult::Success { data, remaining } => {
-                        let data: CmdOrAck<U> = data;
-
-                        match data {
-                            CmdOrAck::Cmd(c) => {
-                                if c.validate() {
-                                    debug!("Received command: {:?}", c);
-                                    self.mix_chan.send(CmdOrAck::Ack(c.ack())).await;
-                                    self.out_chan.send(c.cmd).await;
-                                } else {
-                                    warn!("Corrupted parsed command: {:?}", c);
-                                }
-                            }
-                            CmdOrAck::Ack(a) => {
-                                if let Some(a) = a.validate() {
-                                    debug!("Received ack: {:?}", a);
-                                    let mut waiters = self.waiters.lock().await;
-                                    if let Some(waker) = waiters.remove(&a.uuid) {
-                                        waker.set();
-                                    }
-                                } else {
-                                    warn!("Corrupted parsed ack");
-                                }
-                            }
-                        }
-
-                        remaining
+            self.rx.read(&mut buf).await.map_err(LinkError::Io)?;
+            #[cfg(feature = "link-capture")]
+            self.capture_byte(buf[0]);
+            if let Some(kind) = self.rx.take_line_error() {
+                return Err(LinkError::Line(kind));
+            }
+
+            let frame = match reader.byte(buf[0]) {
+                LinkByte::Partial => continue,
+                LinkByte::TooLong => return Err(LinkError::Deser),
+                LinkByte::Frame(frame) => frame,
+            };
+            let data: CmdOrAck<U> = match postcard::from_bytes_cobs(frame) {
+                Ok(data) => data,
+                Err(_) => {
+                    warn!(
+                        "Message decoder failed to deserialize a message of type {}",
+                        core::any::type_name::<CmdOrAck<U>>(),
+                    );
+                    return Err(LinkError::Deser);
+                }
+            };
+
+            match data.receive() {
+                Received::Cmd { cmd, ack } => {
+                    reader.frame_ok();
+                    log_sampled!("Received command: {:?}", cmd);
+                    self.ack_chan.send(ack).await;
+                    queued(self.ack_depth, &ACK_QUEUE_HIGH_WATER);
+                    self.out_chan.send(cmd).await;
+                }
+                Received::Ack(uuid) => {
+                    reader.frame_ok();
+                    log_sampled!("Received ack: {:?}", uuid);
+                    let mut waiters = self.waiters.lock().await;
+                    if let Some(waker) = waiters.remove(&uuid) {
+                        waker.set();
                     }
                 }
+                Received::Corrupt => {
+                    warn!("Corrupted parsed frame");
+                    return Err(LinkError::Checksum);
+                }
             }
         }
     }
 
+    /// Add a byte read to the frame being captured, recording it once it ends
+    #[cfg(feature = "link-capture")]
+    fn capture_byte(&mut self, byte: u8) {
+        let Some(frame) = &mut self.captured else {
+            return;
+        };
+
+        let _ = frame.push(byte);
+        if byte == 0 || frame.is_full() {
+            crate::link_capture::record(CaptureDirection::Received, &frame[..]);
+            frame.clear();
+        }
+    }
+
     async fn task(mut self) {
+        let mut reader = LinkReader::new();
+
         loop {
-            let _ = self.recv_task_inner().await;
+            let error = match self.recv_task_inner(&mut reader).await {
+                Ok(never) => match never {},
+                Err(error) => error,
+            };
+
+            let kind = match error {
+                LinkError::Io(e) => {
+                    warn!("Link read failed: {:?}", e);
+                    LinkErrorKind::Driver
+                }
+                LinkError::Line(kind) => kind,
+                LinkError::Deser | LinkError::Checksum => {
+                    reader.flush();
+                    corrupt_frame();
+                    continue;
+                }
+            };
+
+            LINK_READ_ERRORS.fetch_add(1, Ordering::Relaxed);
+            LINK_ERRORS[kind as usize].fetch_add(1, Ordering::Relaxed);
+            if reader.error() {
+                warn!("Burst of link errors, saying hello again");
+                LINK_RESYNCS.fetch_add(1, Ordering::Relaxed);
+                LINK_RESYNC.set();
+            }
+
+            let delay = Duration::from_millis(reader.backoff_ms());
+            warn!("Link {} error, backing off {}ms", kind, delay.as_millis());
+            Timer::after(delay).await;
+            // bytes that came in while backing off may have overrun, which is
+            // down to us rather than the line, and any of them may be gone
+            let _ = self.rx.take_line_error();
+            reader.resume();
         }
     }
 }
@@ -173,48 +473,143 @@ where
     TX: AsyncWrite,
     <TX as AsyncWrite>::Error: Format,
 {
+    /// The next frame to go out, taken from the queues in `Outgoing::ORDER`
+    async fn next(&self) -> CmdOrAck<T> {
+        if let Some(frame) = Outgoing::first(|from| self.try_take(from)) {
+            return frame;
+        }
+
+        // polled in the same order, so the earlier queue wins if several
+        // become ready at once
+        match select3(
+            self.ack_chan.recv(),
+            self.high_chan.recv(),
+            self.mix_chan.recv(),
+        )
+        .await
+        {
+            Either3::First(ack) => self.took_ack(ack),
+            Either3::Second(cmd) | Either3::Third(cmd) => self.took_cmd(cmd),
+        }
+    }
+
+    fn try_take(&self, from: Outgoing) -> Option<CmdOrAck<T>> {
+        match from {
+            Outgoing::Ack => self.ack_chan.try_recv().ok().map(|a| self.took_ack(a)),
+            Outgoing::Cmd(Priority::High) => {
+                self.high_chan.try_recv().ok().map(|c| self.took_cmd(c))
+            }
+            Outgoing::Cmd(Priority::Low) => self.mix_chan.try_recv().ok().map(|c| self.took_cmd(c)),
+        }
+    }
+
+    fn took_ack(&self, ack: Ack) -> CmdOrAck<T> {
+        dequeued(self.ack_depth);
+        CmdOrAck::Ack(ack)
+    }
+
+    fn took_cmd(&self, cmd: Command<T>) -> CmdOrAck<T> {
+        dequeued(self.mix_depth);
+        CmdOrAck::Cmd(cmd)
+    }
+
     async fn task(self) {
         loop {
-            let val = self.mix_chan.recv().await;
+            let val = self.next().await;
 
             let mut buf = [0u8; BUF_SIZE];
             if let Ok(buf) = postcard::to_slice_cobs(&val, &mut buf) {
+                #[cfg(feature = "link-capture")]
+                if self.capture {
+                    crate::link_capture::record(CaptureDirection::Sent, buf);
+                }
                 let r = self.tx.write(buf).await;
-                debug!("Transmitted {:?}, r: {:?}", val, r);
+                log_sampled!("Transmitted {:?}, r: {:?}", val, r);
             }
         }
     }
 }
 
 impl<'a, T: Hash + Clone> EventSender<'a, T> {
-    async fn send(&self, cmd: T, timeout: Duration) {
+    /// Send `cmd` until it's acked. A best effort command fails once it runs
+    /// out of retries, and any command fails if the link goes down after
+    /// being up. Until the link first comes up reliable ones keep being
+    /// retried, the other half may still be booting.
+    async fn send(&self, cmd: T, policy: SendPolicy) -> Result<(), SendFailed> {
+        let mut timeout = policy.initial_timeout;
+        let mut retries = 0;
+
         loop {
             let cmd = Command::new(cmd.clone());
             let uuid = cmd.uuid;
-            let waiter = self.register_waiter(uuid).await;
-            self.mix_chan.send(CmdOrAck::Cmd(cmd)).await;
 
-            match with_timeout(timeout, waiter.wait()).await {
-                Ok(_) => {
-                    debug!("Waiter for uuid {} completed", uuid);
-                    return;
-                }
-                Err(_) => {
-                    warn!("Waiter for uuid{} timing out", uuid);
-                    self.deregister_waiter(uuid).await;
+            if let Some(waiter) = self.register_waiter(uuid).await {
+                self.enqueue(cmd, policy.priority).await;
+
+                if with_timeout(timeout, waiter.wait()).await.is_ok() {
+                    log_sampled!("Waiter for uuid {} completed", uuid);
+                    return Ok(());
                 }
+                warn!("Waiter for uuid{} timing out", uuid);
+                self.deregister_waiter(uuid).await;
+            } else {
+                // an ack couldn't be told apart from none without a waiter,
+                // so hold the command back a timeout for some to be freed
+                // rather than send it unacked
+                warn!("No room for a waiter, holding back uuid {}", uuid);
+                Timer::after(timeout).await;
             }
+
+            if (self.link_lost)() {
+                // nothing will ack it until the other half is back, and
+                // holding on to it holds up everything queued behind it
+                warn!("Link down, giving up on uuid {}", uuid);
+                FAILED_SENDS.fetch_add(1, Ordering::Relaxed);
+                return Err(SendFailed);
+            } else if retries < policy.max_retries {
+                retries += 1;
+                timeout = timeout * policy.backoff;
+            } else if policy.reliability == Reliability::BestEffort {
+                warn!("Giving up on uuid {}", uuid);
+                FAILED_SENDS.fetch_add(1, Ordering::Relaxed);
+                return Err(SendFailed);
+            }
+            RETRANSMITS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
         }
     }
 
-    async fn register_waiter(&self, uuid: u8) -> Arc<Event> {
-        let signal = Arc::new(Event::new());
-        let mut waiters = self.waiters.lock().await;
-        if waiters.insert(uuid, signal.clone()).is_ok() {
-            signal
-        } else {
-            panic!("Duped waiter uuid")
+    /// Send each command from one lane of `queue` in turn, a lane only waits
+    /// on its own acks
+    async fn send_lane<const N: usize>(
+        &self,
+        queue: &CommandQueue<T, N>,
+        priority: Priority,
+    ) -> ! {
+        loop {
+            let (cmd, policy) = queue.lane(priority).recv().await;
+            // a failed send is already counted, so unless the caller is
+            // waiting to hear there's nothing else to do but move on
+            let result = self.send(cmd, policy).await;
+            if let Some(outcome) = policy.report_to {
+                outcome.signal(result);
+            }
+        }
+    }
+
+    async fn enqueue(&self, cmd: Command<T>, priority: Priority) {
+        match priority {
+            Priority::High => self.high_chan.send(cmd).await,
+            Priority::Low => self.mix_chan.send(cmd).await,
         }
+        queued(self.mix_depth, &COMMAND_QUEUE_HIGH_WATER);
+    }
+
+    /// `None` if the heap or the map of waiters is too full for one
+    async fn register_waiter(&self, uuid: u8) -> Option<Arc<Event>> {
+        let signal = heap::try_arc(Event::new())?;
+        let mut waiters = self.waiters.lock().await;
+        waiters.insert(uuid, signal.clone()).ok()?;
+        Some(signal)
     }
 
     async fn deregister_waiter(&self, uuid: u8) {
@@ -227,9 +622,16 @@ impl<'a, T, U, TX, RX> Eventer<'a, T, U, TX, RX> {
         Self {
             tx,
             rx,
+            high_chan: Channel::new(),
             mix_chan: Channel::new(),
+            ack_chan: Channel::new(),
+            mix_depth: AtomicU8::new(0),
+            ack_depth: AtomicU8::new(0),
             out_chan,
             waiters: Mutex::new(heapless::FnvIndexMap::new()),
+            link_lost: || false,
+            #[cfg(feature = "link-capture")]
+            capture: false,
         }
     }
 
@@ -239,12 +641,18 @@ impl<'a, T, U, TX, RX> Eventer<'a, T, U, TX, RX> {
     ) -> Eventer<'a, T, U, UarteTx<'static, UT>, UarteRx<'static, UT>> {
         let (tx, rx) = uart.split();
 
-        Eventer::new(tx, rx, out_chan)
+        let mut eventer = Eventer::new(tx, rx, out_chan);
+        eventer.link_lost = link_health::lost;
+        #[cfg(feature = "link-capture")]
+        {
+            eventer.capture = true;
+        }
+        eventer
     }
 
     pub fn split_tasks<'s, const N: usize>(
         &'s mut self,
-        cmd_chan: &'static Channel<ThreadModeRawMutex, (T, Duration), N>,
+        cmd_queue: &'static CommandQueue<T, N>,
     ) -> (impl Future + 's, impl Future + 's, impl Future + 's)
     where
         T: Hash + Clone + Serialize + Format,
@@ -252,29 +660,43 @@ impl<'a, T, U, TX, RX> Eventer<'a, T, U, TX, RX> {
         TX: AsyncWrite,
         RX: AsyncRead,
         <TX as AsyncWrite>::Error: Format,
+        <RX as AsyncRead>::Error: Format,
     {
         let sender = EventSender {
+            high_chan: &self.high_chan,
             mix_chan: &self.mix_chan,
+            mix_depth: &self.mix_depth,
             waiters: &self.waiters,
+            link_lost: self.link_lost,
         };
 
         let out_processor = EventOutProcessor {
             tx: &mut self.tx,
+            high_chan: &self.high_chan,
             mix_chan: &self.mix_chan,
+            ack_chan: &self.ack_chan,
+            mix_depth: &self.mix_depth,
+            ack_depth: &self.ack_depth,
+            #[cfg(feature = "link-capture")]
+            capture: self.capture,
         };
 
         let in_processor = EventInProcessor {
             rx: &mut self.rx,
             out_chan: self.out_chan.clone(),
-            mix_chan: &self.mix_chan,
+            ack_chan: &self.ack_chan,
+            ack_depth: &self.ack_depth,
             waiters: &self.waiters,
+            #[cfg(feature = "link-capture")]
+            captured: self.capture.then(heapless::Vec::new),
         };
 
         let sender_proc = async move {
-            loop {
-                let (evt, timeout) = cmd_chan.recv().await;
-                let _ = sender.send(evt, timeout).await;
-            }
+            select(
+                sender.send_lane(cmd_queue, Priority::High),
+                sender.send_lane(cmd_queue, Priority::Low),
+            )
+            .await
         };
 
         (sender_proc, out_processor.task(), in_processor.task())
@@ -1,10 +1,28 @@
+use core::cell::Cell;
+
 use defmt::debug;
 use display_interface::DisplayError;
 use embassy_futures::select::select;
 use embassy_nrf::twim::{Instance, Twim};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_4X6, MonoTextStyle, MonoTextStyleBuilder},
+    pixelcolor::BinaryColor,
+    prelude::{OriginDimensions, Point, Size},
+    text::{Alignment, Baseline, Text},
+    Drawable, Pixel,
+};
 use embedded_hal_async::i2c::I2c;
+use keyboard_shared::{
+    lit_percent, ContrastCurve, DisplayContrast, Fade, Rotation, CONTRAST_LEVELS, DISPLAY_ROWS,
+    DISPLAY_WIDTH,
+};
+use profont::PROFONT_7_POINT;
 use ssd1306::{
     mode::{BufferedGraphicsMode, DisplayConfig},
     prelude::{Brightness, I2CInterface},
@@ -13,14 +31,108 @@ use ssd1306::{
     I2CDisplayInterface, Ssd1306,
 };
 
-use crate::event::Event;
+use crate::{
+    display_bus,
+    event::Event,
+    idle::{IdlePhase, IDLE, OLED_TIMEOUT},
+    profiling::busy,
+    version_check,
+};
 
 type OledDisplay<'a, T> =
     Ssd1306<I2CInterface<Twim<'a, T>>, DisplaySize128x32, BufferedGraphicsMode<DisplaySize128x32>>;
 
+/// Width of the display in its rotated orientation
+pub const WIDTH: usize = DISPLAY_WIDTH;
+/// Height of the display in its rotated orientation
+pub const ROWS: usize = DISPLAY_ROWS;
+
+/// How this half's display is mounted, applied to everything drawn through
+/// [`Canvas`] so the rest of the firmware and the host can ignore it
+static MOUNTING: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<Rotation>> =
+    blocking_mutex::Mutex::new(Cell::new(Rotation::Rotate0));
+
+pub fn set_display_rotation(rotation: Rotation) {
+    MOUNTING.lock(|r| r.set(rotation));
+}
+
+pub fn display_rotation() -> Rotation {
+    MOUNTING.lock(|r| r.get())
+}
+
+static CONTRAST: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<DisplayContrast>> =
+    blocking_mutex::Mutex::new(Cell::new(DisplayContrast::Auto(ContrastCurve::DEFAULT)));
+
+/// Picked up on the next flush
+pub fn set_display_contrast(contrast: DisplayContrast) {
+    CONTRAST.lock(|c| c.set(contrast));
+}
+
+fn display_contrast() -> DisplayContrast {
+    CONTRAST.lock(|c| c.get())
+}
+
+/// Where a pixel drawn at `p` actually goes on a display mounted with `rotation`
+pub fn orient(p: Point, rotation: Rotation) -> Point {
+    let (x, y) = rotation.orient((p.x, p.y));
+    Point::new(x, y)
+}
+
+/// A copy of the display contents, one 32 bit row per line in the same
+/// layout as `WritePixels`.
+///
+/// The ssd1306 driver doesn't let us read its own buffer back, so every
+/// pixel drawn through [`Canvas`] is mirrored here.
+type Shadow = [[u8; 4]; ROWS];
+
 pub struct Oled<'a, T: Instance> {
     status: bool,
     display: OledDisplay<'a, T>,
+    shadow: Shadow,
+    fade: Fade,
+    /// The brightest the display goes, an index into `FADE_STEPS`. Set from
+    /// what's on screen at each flush unless the contrast is manual.
+    contrast: u8,
+}
+
+/// Draw target handed out by [`Oled`], keeps the shadow buffer in sync
+pub struct Canvas<'o, 'a, T: Instance> {
+    display: &'o mut OledDisplay<'a, T>,
+    shadow: &'o mut Shadow,
+}
+
+impl<'o, 'a, T: Instance> DrawTarget for Canvas<'o, 'a, T> {
+    type Color = BinaryColor;
+    type Error = DisplayError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let shadow = &mut *self.shadow;
+        let rotation = display_rotation();
+        self.display
+            .draw_iter(pixels.into_iter().map(|Pixel(p, c)| {
+                // the shadow is kept in the host's orientation
+                if (0..WIDTH as i32).contains(&p.x) && (0..ROWS as i32).contains(&p.y) {
+                    let byte = &mut shadow[p.y as usize][p.x as usize / 8];
+                    let mask = 1 << (p.x % 8);
+                    if c.is_on() {
+                        *byte |= mask;
+                    } else {
+                        *byte &= !mask;
+                    }
+                }
+
+                Pixel(orient(p, rotation), c)
+            }))
+    }
+}
+
+impl<'o, 'a, T: Instance> OriginDimensions for Canvas<'o, 'a, T> {
+    fn size(&self) -> Size {
+        self.display.size()
+    }
 }
 
 impl<'a, T: Instance> Oled<'a, T> {
@@ -31,6 +143,9 @@ impl<'a, T: Instance> Oled<'a, T> {
         Self {
             status: true,
             display,
+            shadow: [[0; 4]; ROWS],
+            fade: Fade::ON,
+            contrast: CONTRAST_LEVELS - 1,
         }
     }
 
@@ -43,104 +158,209 @@ impl<'a, T: Instance> Oled<'a, T> {
 
     pub async fn draw(
         &mut self,
-        f: impl FnOnce(&mut OledDisplay<'a, T>),
+        f: impl FnOnce(&mut Canvas<'_, 'a, T>),
     ) -> Result<(), DisplayError> {
-        self.display.clear();
-        f(&mut self.display);
-        self.display.flush().await?;
-        Ok(())
+        {
+            let _busy = busy();
+            self.clear();
+            f(&mut self.canvas());
+            if version_check::mismatch() {
+                draw_mismatch_warning(&mut self.canvas());
+            }
+        }
+        // everything was cleared, so this sends the whole frame
+        let result = self.timed_flush(true).await;
+        FRAME_DRAWN.set();
+        result
+    }
+
+    /// Draw a single word in the middle of an otherwise blank display
+    pub async fn draw_banner(&mut self, text: &str) -> Result<(), DisplayError> {
+        let style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
+        let text = Text::with_alignment(text, Point::new(16, 64), style, Alignment::Center);
+
+        self.draw(move |d| {
+            let _ = text.draw(d);
+        })
+        .await
     }
 
     pub fn clear(&mut self) {
         self.display.clear();
+        self.shadow = [[0; 4]; ROWS];
     }
 
     pub async fn flush(&mut self) -> Result<(), DisplayError> {
-        self.display.flush().await
+        self.timed_flush(false).await
     }
 
-    pub fn draw_no_clear_no_flush(&mut self, f: impl FnOnce(&mut OledDisplay<'a, T>)) {
-        f(&mut self.display);
+    async fn timed_flush(&mut self, full_frame: bool) -> Result<(), DisplayError> {
+        self.update_contrast().await?;
+        let start = Instant::now();
+        let result = self.display.flush().await;
+        display_bus::record(start.elapsed(), full_frame);
+        result
     }
 
-    pub async fn set_on(&mut self) -> Result<(), DisplayError> {
-        if self.status {
+    /// Pick the contrast for the frame about to be sent, only touching the
+    /// display when it changes
+    async fn update_contrast(&mut self) -> Result<(), DisplayError> {
+        let contrast = match display_contrast() {
+            DisplayContrast::Auto(curve) => curve.settle(lit_percent(&self.shadow), self.contrast),
+            DisplayContrast::Manual(level) => level.min(CONTRAST_LEVELS - 1),
+        };
+
+        if contrast == self.contrast {
             return Ok(());
         }
-
-        debug!("Turning display on");
-
-        self.display.set_brightness(Brightness::DIMMEST).await?;
-        self.display.set_display_on(true).await?;
-
-        for brightness in [
-            Brightness::DIM,
-            Brightness::NORMAL,
-            Brightness::BRIGHT,
-            Brightness::BRIGHTEST,
-        ] {
-            Timer::after(Duration::from_millis(100)).await;
-            self.display.set_brightness(brightness).await?;
+        self.contrast = contrast;
+        match brightness(self.fade, self.contrast) {
+            Some(brightness) if self.status => self.display.set_brightness(brightness).await,
+            _ => Ok(()),
         }
+    }
 
-        self.status = true;
+    pub fn draw_no_clear_no_flush(&mut self, f: impl FnOnce(&mut Canvas<'_, 'a, T>)) {
+        f(&mut self.canvas());
+    }
 
-        Ok(())
+    /// Read back the pixels at `row` and `row + 1`
+    pub fn read_rows(&self, row: u8) -> ([u8; 4], [u8; 4]) {
+        let row = (row as usize).min(ROWS - 2);
+        (self.shadow[row], self.shadow[row + 1])
     }
 
-    pub async fn set_off(&mut self) -> Result<(), DisplayError> {
-        if !self.status {
-            return Ok(());
+    fn canvas(&mut self) -> Canvas<'_, 'a, T> {
+        Canvas {
+            display: &mut self.display,
+            shadow: &mut self.shadow,
         }
+    }
 
-        debug!("Turning display off");
-
-        self.display.set_brightness(Brightness::BRIGHTEST).await?;
-
-        for brightness in [
-            Brightness::BRIGHT,
-            Brightness::NORMAL,
-            Brightness::DIM,
-            Brightness::DIMMEST,
-        ] {
-            Timer::after(Duration::from_millis(100)).await;
-            self.display.set_brightness(brightness).await?;
+    /// Show `fade`, turning the display on or off as needed
+    pub async fn set_fade(&mut self, fade: Fade) -> Result<(), DisplayError> {
+        self.fade = fade;
+        match brightness(fade, self.contrast) {
+            Some(brightness) => {
+                self.display.set_brightness(brightness).await?;
+                if !self.status {
+                    debug!("Turning display on");
+                    self.display.set_display_on(true).await?;
+                    self.status = true;
+                }
+            }
+            None => {
+                if self.status {
+                    debug!("Turning display off");
+                    self.display.set_display_on(false).await?;
+                    self.status = false;
+                }
+            }
         }
 
-        self.display.set_display_on(false).await?;
-
-        self.status = false;
-
         Ok(())
     }
 }
 
-pub const OLED_TIMEOUT: Duration = Duration::from_secs(30);
-static INTERACTED_EVENT: Event = Event::new();
+/// Brightness levels a fade passes through, dimmest first
+const FADE_STEPS: [Brightness; 5] = [
+    Brightness::DIMMEST,
+    Brightness::DIM,
+    Brightness::NORMAL,
+    Brightness::BRIGHT,
+    Brightness::BRIGHTEST,
+];
+const _: () = assert!(FADE_STEPS.len() == CONTRAST_LEVELS as usize);
+const FADE_STEP_TIME: Duration = Duration::from_millis(100);
 
-pub fn interacted() {
-    INTERACTED_EVENT.set();
+/// What `fade` shows at, `None` when the display should be off
+fn brightness(fade: Fade, contrast: u8) -> Option<Brightness> {
+    FADE_STEPS.get(fade.contrast(contrast)? as usize).copied()
 }
 
-async fn turn_off(oled: &Mutex<ThreadModeRawMutex, Oled<'_, impl Instance>>) {
-    Timer::after(OLED_TIMEOUT).await;
-
-    let _ = oled.lock().await.set_off().await;
+pub fn interacted() {
+    IDLE.interacted();
+}
 
-    turn_on(oled).await;
+/// Activity from the other half or the host, which doesn't wake the display
+/// during quiet hours
+pub fn remote_interacted() {
+    if !crate::quiet_hours::is_quiet() {
+        interacted();
+    }
 }
 
-async fn turn_on(oled: &Mutex<ThreadModeRawMutex, Oled<'_, impl Instance>>) {
-    INTERACTED_EVENT.wait().await;
+/// A peek key, see [`crate::layout::is_peek_key`]
+pub fn peeked() {
+    IDLE.peeked();
+}
 
-    let _ = oled.lock().await.set_on().await;
+/// A peek key on the other half
+pub fn remote_peeked() {
+    if !crate::quiet_hours::is_quiet() {
+        peeked();
+    }
 }
 
+/// Set when the display should be redrawn straight away, as it's about to
+/// come back on
+pub static WAKE_REDRAW: Event = Event::new();
+/// Set after every full frame [`Oled::draw`] sends
+static FRAME_DRAWN: Event = Event::new();
+/// How long to wait for the display loop to draw before coming on anyway
+const WAKE_REDRAW_TIMEOUT: Duration = Duration::from_millis(100);
+
 pub async fn display_timeout_task<'a, T: Instance>(oled: &Mutex<ThreadModeRawMutex, Oled<'a, T>>)
 where
     Twim<'a, T>: I2c<u8>,
 {
+    let mut fade = Fade::ON;
+
     loop {
-        select(turn_on(oled), turn_off(oled)).await;
+        let target = match IDLE.phase() {
+            IdlePhase::Off => Fade::OFF,
+            // dimmed only where the display would otherwise be off
+            IdlePhase::Peek if IDLE.idle_for() >= OLED_TIMEOUT => Fade::PEEK,
+            _ => Fade::ON,
+        };
+
+        if fade == Fade::OFF && target != Fade::OFF {
+            // whatever is in the buffer could be from long ago, so get a
+            // fresh frame drawn before the display comes on at its dimmest
+            FRAME_DRAWN.reset();
+            WAKE_REDRAW.set();
+            let _ = select(FRAME_DRAWN.wait(), Timer::after(WAKE_REDRAW_TIMEOUT)).await;
+        }
+
+        match fade.step(target) {
+            Some(next) => {
+                fade = next;
+                // only hold the display for the step itself, so drawing
+                // carries on through the fade
+                let _ = oled.lock().await.set_fade(fade).await;
+                // an interaction turns a fade out round straight away
+                let _ = select(Timer::after(FADE_STEP_TIME), IDLE.wait_interaction()).await;
+            }
+            None => match IDLE.next_change() {
+                Some(remaining) => {
+                    let _ = select(Timer::after(remaining), IDLE.wait_interaction()).await;
+                }
+                None => IDLE.wait_interaction().await,
+            },
+        }
+    }
+}
+
+/// Drawn over every screen while the halves are running different firmware
+fn draw_mismatch_warning<D: DrawTarget<Color = BinaryColor>>(d: &mut D) {
+    let style = MonoTextStyleBuilder::new()
+        .font(&FONT_4X6)
+        .text_color(BinaryColor::On)
+        .background_color(BinaryColor::Off)
+        .build();
+
+    for (line, text) in ["FW", "MISMATCH"].into_iter().enumerate() {
+        let y = line as i32 * 6;
+        let _ = Text::with_baseline(text, Point::new(0, y), style, Baseline::Top).draw(d);
     }
 }
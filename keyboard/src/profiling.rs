@@ -0,0 +1,62 @@
+//! Rough CPU usage estimation.
+//!
+//! The hot task loops wrap their synchronous work in [`busy`], the time spent
+//! inside those sections is summed and turned into a busy percentage once a
+//! second. It doesn't see time spent in interrupts or in the executor itself,
+//! so treat the number as a relative measure.
+
+use core::sync::atomic::AtomicU8;
+
+/// Percentage of the last second spent in measured sections
+pub static CPU_BUSY_PCT: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(feature = "profiling")]
+mod imp {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use embassy_time::{Duration, Instant, Ticker};
+    use futures::StreamExt;
+
+    use super::CPU_BUSY_PCT;
+
+    static BUSY_TICKS: AtomicU32 = AtomicU32::new(0);
+
+    pub struct BusyGuard(Instant);
+
+    impl Drop for BusyGuard {
+        fn drop(&mut self) {
+            BUSY_TICKS.fetch_add(self.0.elapsed().as_ticks() as u32, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn busy() -> BusyGuard {
+        BusyGuard(Instant::now())
+    }
+
+    #[embassy_executor::task]
+    pub async fn profiling_task() {
+        let period = Duration::from_secs(1);
+        let mut ticker = Ticker::every(period);
+
+        loop {
+            ticker.next().await;
+
+            let busy = BUSY_TICKS.swap(0, Ordering::Relaxed) as u64;
+            let pct = (busy * 100 / period.as_ticks()).min(100) as u8;
+            CPU_BUSY_PCT.store(pct, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+mod imp {
+    pub struct BusyGuard;
+
+    #[inline(always)]
+    pub fn busy() -> BusyGuard {
+        BusyGuard
+    }
+}
+
+pub use imp::*;
@@ -0,0 +1,75 @@
+//! Quiet hours: a daily window, set in the config, where the LEDs are capped
+//! to a dim level and the displays don't wake for activity that didn't come
+//! from this half's own keys. The left half checks the schedule against the
+//! host's time once a second and tells the right half when it changes.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+pub use keyboard_shared::QuietHours;
+use keyboard_shared::QuietHoursTracker as Window;
+
+use crate::clock;
+
+/// LED channel gain while quiet, on top of the calibration
+pub const QUIET_LED_GAIN: u8 = 24;
+
+static SCHEDULE: Mutex<ThreadModeRawMutex, Cell<QuietHours>> =
+    Mutex::new(Cell::new(QuietHours::OFF));
+static QUIET: AtomicBool = AtomicBool::new(false);
+/// Set from the override key, cleared whenever the window starts or ends
+static OVERRIDDEN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_schedule(schedule: QuietHours) {
+    SCHEDULE.lock(|s| s.set(schedule));
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// For the right half, which is told the state rather than working it out
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Suspend quiet hours until the window next starts or ends, or resume them
+/// if they were already suspended
+pub fn toggle_override() {
+    OVERRIDDEN.fetch_xor(true, Ordering::Relaxed);
+}
+
+/// Tracks window boundaries so an override only lasts until the next one
+pub struct QuietHoursTracker {
+    window: Window,
+}
+
+impl QuietHoursTracker {
+    pub const fn new() -> Self {
+        Self {
+            window: Window::new(),
+        }
+    }
+
+    /// Check the schedule against the time, returning the new state if quiet
+    /// hours just started or ended
+    pub fn sample(&mut self) -> Option<bool> {
+        let schedule = SCHEDULE.lock(|s| s.get());
+        // the override key is handled on the same executor, so nothing can
+        // toggle it between the load and the store
+        let mut overridden = OVERRIDDEN.load(Ordering::Relaxed);
+        let now_quiet = self
+            .window
+            .quiet(schedule, clock::minute_of_day(), &mut overridden);
+        OVERRIDDEN.store(overridden, Ordering::Relaxed);
+
+        if now_quiet == QUIET.swap(now_quiet, Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(now_quiet)
+    }
+}
@@ -0,0 +1,92 @@
+//! Keys the host has remapped to a plain keycode, for trying out a layout
+//! without reflashing, see `HostToKeyboard::SetKeyAction`.
+//!
+//! keyberon's layout only reads the `'static` layers, so a remapped key never
+//! reaches it. Its keycode goes into the HID report alongside the layout's
+//! for as long as the key is held. Remaps only last until restart.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use heapless::FnvIndexMap;
+use keyberon::layout::Event;
+
+use crate::layout::{COLS, LAYOUT_ROWS, N_LAYERS};
+
+/// Most keys that can be remapped at once
+pub const MAX_REMAPS: usize = 32;
+/// Most remapped keys held at once, any more go to the layout as usual
+const MAX_HELD: usize = 8;
+
+/// Keycodes by `(layer, row, col)` of the layout
+static REMAPS: Mutex<ThreadModeRawMutex, RefCell<FnvIndexMap<(u8, u8, u8), u8, MAX_REMAPS>>> =
+    Mutex::new(RefCell::new(FnvIndexMap::new()));
+
+/// Remap a key of the layout to `keycode`, ignored if there's no such key or
+/// too many are remapped already
+pub fn set(layer: u8, row: u8, col: u8, keycode: u8) {
+    if layer as usize >= N_LAYERS || row as usize >= LAYOUT_ROWS || col as usize >= COLS {
+        return;
+    }
+
+    REMAPS.lock(|r| {
+        let _ = r.borrow_mut().insert((layer, row, col), keycode);
+    });
+}
+
+/// Give every key back to the layout, keys held now stay remapped until
+/// they're released
+pub fn clear() {
+    REMAPS.lock(|r| r.borrow_mut().clear());
+}
+
+fn lookup(layer: u8, (row, col): (u8, u8)) -> Option<u8> {
+    REMAPS.lock(|r| r.borrow().get(&(layer, row, col)).copied())
+}
+
+/// The remapped keys being held, with what each was remapped to when it was
+/// pressed
+pub struct HeldRemaps {
+    held: heapless::Vec<((u8, u8), u8), MAX_HELD>,
+}
+
+impl HeldRemaps {
+    pub const fn new() -> Self {
+        Self {
+            held: heapless::Vec::new(),
+        }
+    }
+
+    /// Take `event` if it's for a remapped key on `layer`, returning false if
+    /// it should go on to the layout. A release always goes where its press
+    /// went, however the remaps changed in between.
+    pub fn event(&mut self, event: Event, layer: u8) -> bool {
+        let coord = event.coord();
+        if event.is_press() {
+            match lookup(layer, coord) {
+                Some(keycode) => self.held.push((coord, keycode)).is_ok(),
+                None => false,
+            }
+        } else if let Some(idx) = self.held.iter().position(|(c, _)| *c == coord) {
+            self.held.swap_remove(idx);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn keycodes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.held.iter().map(|(_, keycode)| *keycode)
+    }
+
+    /// Let go of everything, for when the layout starts over
+    pub fn clear(&mut self) {
+        self.held.clear();
+    }
+}
+
+impl Default for HeldRemaps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,40 @@
+//! Mirroring HID reports to the host, for keystroke analytics.
+//!
+//! This hands the host everything typed, so it's never saved and never on
+//! for long by accident: the host has to subscribe again within
+//! `REPORT_SUBSCRIPTION_SECS`, it ends with the host session, and an LED and
+//! a badge on the display show while it's on.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+use keyboard_shared::{hid_report, KeyboardToHost, ReportSubscription};
+use usbd_human_interface_device::page::Keyboard;
+
+static SUBSCRIPTION: Mutex<ThreadModeRawMutex, Cell<ReportSubscription>> =
+    Mutex::new(Cell::new(ReportSubscription::new()));
+
+pub fn subscribe(on: bool) {
+    SUBSCRIPTION.lock(|s| {
+        let mut sub = s.get();
+        sub.subscribe(on, Instant::now().as_millis());
+        s.set(sub);
+    });
+}
+
+/// Whether reports are being mirrored, turning it off once the subscription
+/// has lapsed
+pub fn active() -> bool {
+    SUBSCRIPTION.lock(|s| {
+        let mut sub = s.get();
+        let active = sub.active(Instant::now().as_millis());
+        s.set(sub);
+        active
+    })
+}
+
+/// The `Report` for the keys of a HID report
+pub fn report(keys: &[Keyboard]) -> KeyboardToHost {
+    hid_report(keys.iter().map(|&k| k as u8))
+}
@@ -1,37 +1,88 @@
-use core::sync::atomic::AtomicU32;
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU32},
+};
 
-use atomic_float::AtomicF32;
-use bitvec::{order::Lsb0, view::BitView};
 use embassy_futures::select::{select, select3, Either3};
 use embassy_nrf::peripherals::TWISPI0;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, mutex::Mutex};
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    mutex::Mutex,
+};
 use embassy_time::{Duration, Instant, Ticker};
 use embedded_graphics::{
     mono_font::MonoTextStyle,
     pixelcolor::BinaryColor,
     prelude::{Point, Primitive, Size},
     primitives::{Line, PrimitiveStyle, Rectangle},
-    Drawable, Pixel,
+    Drawable,
 };
 use embedded_text::{style::TextBoxStyleBuilder, TextBox};
 use futures::StreamExt;
+use keyboard_shared::{bar_height, Rotation, StatsGraph};
 use micromath::F32Ext;
-use profont::PROFONT_9_POINT;
+use profont::{PROFONT_7_POINT, PROFONT_9_POINT};
 use ufmt::uwriteln;
 
-use crate::{cps::SampleBuffer, event::Event, oled::Oled};
+use crate::{
+    channel_stats::total_drops,
+    clock,
+    cps::{self, SampleBuffer, CPS_SAMPLES, SAMPLES_REVISION},
+    display_bus,
+    display_widgets::{
+        read_in_overrides, AVERAGE_KEYPRESSES, KEYPRESS_EVENT, OVERRIDE_CHAN, TOTAL_KEYPRESSES,
+    },
+    idle::{IdlePhase, IDLE},
+    link_health,
+    messages::{CORRUPT_FRAMES, FAILED_SENDS},
+    oled::{self, display_rotation, Oled},
+    profiling::CPU_BUSY_PCT,
+    screensaver::Screensaver,
+    system_state::{active_layer, SYSTEM_STATE},
+    version_check,
+};
+
+/// Show diagnostics instead of the usual stats
+pub static DEBUG_SCREEN: AtomicBool = AtomicBool::new(false);
+/// Stats screen redraws skipped because nothing on it had changed
+pub static FRAMES_SKIPPED: AtomicU32 = AtomicU32::new(0);
+static STATS_GRAPH: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<StatsGraph>> =
+    blocking_mutex::Mutex::new(Cell::new(StatsGraph::Cps));
 
-#[derive(defmt::Format)]
-pub struct DisplayOverride {
-    pub row: u8,
-    pub data_0: [u8; 4],
-    pub data_1: [u8; 4],
+/// The graph takes the bottom rows of the display, under the six lines of
+/// stats which end 96 rows down
+const GRAPH_HEIGHT: u32 = 32;
+/// The graph's x axis, along the bottom row
+const GRAPH_BASELINE: i32 = oled::ROWS as i32 - 1;
+/// The tallest bar, which fills the graph above the baseline
+const BAR_MAX: u32 = GRAPH_HEIGHT - 1;
+
+pub fn set_stats_graph(graph: StatsGraph) {
+    STATS_GRAPH.lock(|g| g.set(graph));
+}
+
+/// The stats screen's third line, there's only room for one of these
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StatusLine {
+    LinkDown,
+    /// `HH:MM`, once the host has set the clock
+    Time([u8; 5]),
+    Ticks(u32),
 }
 
-pub static TOTAL_KEYPRESSES: AtomicU32 = AtomicU32::new(0);
-pub static AVERAGE_KEYPRESSES: AtomicF32 = AtomicF32::new(0.0);
-pub static KEYPRESS_EVENT: Event = Event::new();
-pub static OVERRIDE_CHAN: Channel<ThreadModeRawMutex, DisplayOverride, 256> = Channel::new();
+/// Everything the stats screen is drawn from
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct StatsInputs {
+    keypresses: u32,
+    /// Keys per second in tenths, as shown
+    cps_tenths: u32,
+    status: StatusLine,
+    layer: u8,
+    samples_revision: u32,
+    rotation: Rotation,
+    mismatch: bool,
+    graph: StatsGraph,
+}
 
 pub struct RHSDisplay {
     oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
@@ -40,6 +91,9 @@ pub struct RHSDisplay {
     upd_ticker: Ticker,
     buf: heapless::String<128>,
     ticks: u32,
+    screensaver: Screensaver,
+    /// What's on the display, if it's still the stats screen
+    drawn: Option<StatsInputs>,
 }
 
 impl RHSDisplay {
@@ -54,11 +108,15 @@ impl RHSDisplay {
             upd_ticker: Ticker::every(Duration::from_millis(100)),
             buf: Default::default(),
             ticks: 0,
+            screensaver: Screensaver::new(),
+            drawn: None,
         }
     }
 
     pub async fn run(&mut self) {
         let mut override_timeout: Option<Instant> = None;
+        // another screen may have been shown since this one last ran
+        self.drawn = None;
 
         loop {
             match override_timeout {
@@ -67,7 +125,23 @@ impl RHSDisplay {
                         override_timeout = None;
                     }
                 }
-                None => self.render_normal().await,
+                None => {
+                    if SYSTEM_STATE.is_locked() {
+                        self.drawn = None;
+                        let _ = self.oled.lock().await.draw_banner("LOCKED").await;
+                    } else {
+                        if IDLE.phase() == IdlePhase::Screensaver {
+                            self.drawn = None;
+                            self.screensaver.run(self.oled).await;
+                        }
+                        if DEBUG_SCREEN.load(core::sync::atomic::Ordering::Relaxed) {
+                            self.drawn = None;
+                            self.render_debug().await
+                        } else {
+                            self.render_normal().await
+                        }
+                    }
+                }
             }
 
             match select3(
@@ -80,7 +154,8 @@ impl RHSDisplay {
                 Either3::First(()) => {}
                 Either3::Second(()) => {}
                 Either3::Third(o) => {
-                    self.read_in_overrides(o).await;
+                    self.drawn = None;
+                    read_in_overrides(self.oled, o).await;
                     override_timeout = Some(Instant::now() + Duration::from_secs(1));
                 }
             };
@@ -102,51 +177,32 @@ impl RHSDisplay {
         select(a, b).await;
     }
 
-    async fn read_in_overrides(&mut self, initial: DisplayOverride) {
-        let mut oled = self.oled.lock().await;
-        let mut should_flush = initial.row >= 126;
-        oled.draw_no_clear_no_flush(|d| {
-            for (col, pix) in initial.data_0.view_bits::<Lsb0>().into_iter().enumerate() {
-                let _ = Pixel(
-                    Point::new(col as i32, initial.row as i32),
-                    BinaryColor::from(*pix),
-                )
-                .draw(d);
-            }
-
-            for (col, pix) in initial.data_1.view_bits::<Lsb0>().into_iter().enumerate() {
-                let _ = Pixel(
-                    Point::new(col as i32, 1 + initial.row as i32),
-                    BinaryColor::from(*pix),
-                )
-                .draw(d);
-            }
-
-            while let Ok(o) = OVERRIDE_CHAN.try_recv() {
-                should_flush ^= o.row >= 126;
-                for (col, pix) in o.data_0.view_bits::<Lsb0>().into_iter().enumerate() {
-                    let _ = Pixel(
-                        Point::new(col as i32, o.row as i32),
-                        BinaryColor::from(*pix),
-                    )
-                    .draw(d);
-                }
-
-                for (col, pix) in o.data_1.view_bits::<Lsb0>().into_iter().enumerate() {
-                    let _ = Pixel(
-                        Point::new(col as i32, 1 + o.row as i32),
-                        BinaryColor::from(*pix),
-                    )
-                    .draw(d);
-                }
-            }
-        });
-        if should_flush {
-            let _ = oled.flush().await;
+    async fn render_normal(&mut self) {
+        let kp = TOTAL_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
+        let cps = AVERAGE_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
+        let cps_tenths = f32::trunc(cps * 10.0);
+        // the link being down matters most, and the time's more use than the
+        // ticks once the host has set it
+        let status = match clock::hh_mm() {
+            _ if !link_health::up() => StatusLine::LinkDown,
+            Some(time) => StatusLine::Time(time),
+            None => StatusLine::Ticks(self.ticks),
+        };
+        let inputs = StatsInputs {
+            keypresses: kp,
+            cps_tenths: cps_tenths as u32,
+            status,
+            layer: active_layer(),
+            samples_revision: SAMPLES_REVISION.load(core::sync::atomic::Ordering::Relaxed),
+            rotation: display_rotation(),
+            mismatch: version_check::mismatch(),
+            graph: STATS_GRAPH.lock(|g| g.get()),
+        };
+        if self.drawn == Some(inputs) {
+            FRAMES_SKIPPED.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+            return;
         }
-    }
 
-    async fn render_normal(&mut self) {
         let character_style = MonoTextStyle::new(&PROFONT_9_POINT, BinaryColor::On);
         let textbox_style = TextBoxStyleBuilder::new()
             .height_mode(embedded_text::style::HeightMode::FitToText)
@@ -158,9 +214,7 @@ impl RHSDisplay {
 
         self.buf.clear();
 
-        let kp = TOTAL_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
-        let cps = AVERAGE_KEYPRESSES.load(core::sync::atomic::Ordering::Relaxed);
-        let cps = f32::trunc(cps * 10.0) / 10.0;
+        let cps = cps_tenths / 10.0;
         let mut fp_buf = dtoa::Buffer::new();
         let cps = fp_buf.format_finite(cps);
 
@@ -168,26 +222,121 @@ impl RHSDisplay {
         let _ = uwriteln!(&mut self.buf, "{}", kp);
         let _ = uwriteln!(&mut self.buf, "cps:");
         let _ = uwriteln!(&mut self.buf, "{}/s", cps);
-        let _ = uwriteln!(&mut self.buf, "tick:");
-        let _ = uwriteln!(&mut self.buf, "{}", self.ticks);
+        match inputs.status {
+            StatusLine::LinkDown => {
+                let _ = uwriteln!(&mut self.buf, "link:");
+                let _ = uwriteln!(&mut self.buf, "down");
+            }
+            StatusLine::Time(time) => {
+                let _ = uwriteln!(&mut self.buf, "time:");
+                let _ = uwriteln!(
+                    &mut self.buf,
+                    "{}",
+                    core::str::from_utf8(&time).unwrap_or_default()
+                );
+            }
+            StatusLine::Ticks(ticks) => {
+                let _ = uwriteln!(&mut self.buf, "tick:");
+                let _ = uwriteln!(&mut self.buf, "{}", ticks);
+            }
+        }
 
         let text_box =
             TextBox::with_textbox_style(&self.buf, bounds, character_style, textbox_style);
 
-        let lines = {
-            let samples = self.sample_buffer.lock().await;
-            samples
-                .oldest_ordered()
-                .enumerate()
-                .map(|(idx, height)| {
+        let samples: heapless::Vec<u8, CPS_SAMPLES> = match inputs.graph {
+            StatsGraph::Cps => {
+                let samples = self.sample_buffer.lock().await;
+                samples.oldest_ordered().copied().collect()
+            }
+            StatsGraph::History => cps::with_history(|h| h.oldest_ordered().copied().collect()),
+        };
+        let peak = samples.iter().copied().max().unwrap_or(0);
+
+        let style = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let baseline = Line::new(
+            Point::new(0, GRAPH_BASELINE),
+            Point::new(oled::WIDTH as i32 - 1, GRAPH_BASELINE),
+        )
+        .into_styled(style);
+        let bars = samples
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, sample)| {
+                let height = bar_height(*sample, peak, BAR_MAX) as i32;
+                (height > 0).then(|| {
                     Line::new(
-                        Point::new(idx as i32, 128 - (*height as i32).clamp(0, 16)),
-                        Point::new(idx as i32, 128),
+                        Point::new(idx as i32, GRAPH_BASELINE - height),
+                        Point::new(idx as i32, GRAPH_BASELINE - 1),
                     )
-                    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                    .into_styled(style)
                 })
-                .collect::<heapless::Vec<_, 32>>()
-        };
+            })
+            .collect::<heapless::Vec<_, CPS_SAMPLES>>();
+
+        let drawn = self
+            .oled
+            .lock()
+            .await
+            .draw(move |d| {
+                let _ = text_box.draw(d);
+                let _ = baseline.draw(d);
+                for bar in bars {
+                    let _ = bar.draw(d);
+                }
+            })
+            .await;
+        self.drawn = drawn.is_ok().then_some(inputs);
+    }
+
+    async fn render_debug(&mut self) {
+        // smaller and tighter than the stats so every line fits
+        let character_style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::FitToText)
+            .alignment(embedded_text::alignment::HorizontalAlignment::Justified)
+            .build();
+
+        let bounds = Rectangle::new(Point::zero(), Size::new(32, 0));
+
+        self.buf.clear();
+
+        let cpu = CPU_BUSY_PCT.load(core::sync::atomic::Ordering::Relaxed);
+
+        let _ = uwriteln!(&mut self.buf, "cpu:");
+        let _ = uwriteln!(&mut self.buf, "{}%", cpu);
+        let _ = uwriteln!(&mut self.buf, "bad:");
+        let _ = uwriteln!(
+            &mut self.buf,
+            "{}",
+            CORRUPT_FRAMES.load(core::sync::atomic::Ordering::Relaxed)
+        );
+        let _ = uwriteln!(&mut self.buf, "fail:");
+        let _ = uwriteln!(
+            &mut self.buf,
+            "{}",
+            FAILED_SENDS.load(core::sync::atomic::Ordering::Relaxed)
+        );
+        let _ = uwriteln!(&mut self.buf, "drop:");
+        let _ = uwriteln!(&mut self.buf, "{}", total_drops());
+        let _ = uwriteln!(&mut self.buf, "skip:");
+        let _ = uwriteln!(
+            &mut self.buf,
+            "{}",
+            FRAMES_SKIPPED.load(core::sync::atomic::Ordering::Relaxed)
+        );
+
+        let bus = display_bus::stats();
+        let _ = uwriteln!(&mut self.buf, "i2c:");
+        if bus.saturated {
+            let _ = uwriteln!(&mut self.buf, "{}%!", bus.busy_pct);
+        } else {
+            let _ = uwriteln!(&mut self.buf, "{}%", bus.busy_pct);
+        }
+        let _ = uwriteln!(&mut self.buf, "{}ms", bus.avg_flush_us / 1000);
+
+        let text_box =
+            TextBox::with_textbox_style(&self.buf, bounds, character_style, textbox_style);
 
         {
             let _ = self
@@ -196,9 +345,6 @@ impl RHSDisplay {
                 .await
                 .draw(move |d| {
                     let _ = text_box.draw(d);
-                    for line in lines {
-                        let _ = line.draw(d);
-                    }
                 })
                 .await;
         }
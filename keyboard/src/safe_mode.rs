@@ -0,0 +1,102 @@
+//! Booting in a safe mode when the keyboard keeps resetting before it's
+//! properly up.
+//!
+//! A count of boots that haven't yet lasted `BOOT_OK_AFTER` is kept in RAM
+//! that startup leaves alone, so it survives a reset but not losing power.
+//! Once it reaches `SAFE_MODE_AT`, the half boots in safe mode instead: the
+//! display, the LED effects and the saved settings are skipped, leaving USB,
+//! the matrix and the link, and the LEDs blink slowly.
+//! The host sees why in the stats, and `FactoryReset` gets out of it along
+//! with whatever saved setting caused it. So does losing power, though a bad
+//! setting will just put it back again.
+
+use core::{cell::Cell, mem::MaybeUninit, ptr};
+
+use embassy_nrf::pac;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Duration;
+use keyboard_shared::SafeModeReason;
+
+/// How long a boot has to keep running for to count as a good one
+pub const BOOT_OK_AFTER: Duration = Duration::from_secs(10);
+/// Boots in a row that haven't lasted, counting this one, that put it in
+/// safe mode
+pub const SAFE_MODE_AT: u32 = 3;
+
+/// Tells a record left by an earlier boot from whatever RAM held at power on
+const MAGIC: u32 = 0x5AFE_B007;
+
+/// The magic, then the boots so far that haven't lasted
+#[link_section = ".uninit.safe_mode"]
+static mut RECORD: MaybeUninit<[u32; 2]> = MaybeUninit::uninit();
+
+static ACTIVE: Mutex<ThreadModeRawMutex, Cell<Option<SafeModeReason>>> =
+    Mutex::new(Cell::new(None));
+
+fn read() -> Option<u32> {
+    // SAFETY: only touched from thread mode, and any bit pattern is a u32
+    let [magic, attempts] = unsafe { ptr::read_volatile(RECORD.as_ptr()) };
+    (magic == MAGIC).then_some(attempts)
+}
+
+fn write(attempts: u32) {
+    // SAFETY: as for `read`
+    unsafe { ptr::write_volatile(RECORD.as_mut_ptr(), [MAGIC, attempts]) }
+}
+
+/// What caused the last reset, clearing it so the next boot sees only its own
+fn reset_reason() -> SafeModeReason {
+    // SAFETY: RESETREAS isn't used anywhere else
+    let power: pac::POWER = unsafe { core::mem::transmute(()) };
+    let reason = power.resetreas.read();
+
+    let cause = if reason.sreq().is_detected() {
+        SafeModeReason::Panic
+    } else if reason.lockup().is_detected() {
+        SafeModeReason::Lockup
+    } else if reason.dog().is_detected() {
+        SafeModeReason::Watchdog
+    } else {
+        SafeModeReason::Other
+    };
+
+    // the bits are cleared by writing ones to them
+    power.resetreas.write(|w| unsafe { w.bits(reason.bits()) });
+    cause
+}
+
+/// Count this boot, returning whether to boot in safe mode. Call this first
+/// thing in `main`.
+pub fn enter() -> bool {
+    let cause = reset_reason();
+    let attempts = read().unwrap_or(0).saturating_add(1);
+    write(attempts);
+
+    let safe = attempts >= SAFE_MODE_AT;
+    if safe {
+        ACTIVE.lock(|a| a.set(Some(cause)));
+    }
+    safe
+}
+
+/// The boot has lasted `BOOT_OK_AFTER`, so the next one starts counting
+/// again. Safe mode keeps its count, so it stays put across resets.
+pub fn boot_ok() {
+    if !active() {
+        write(0);
+    }
+}
+
+/// Forget the boots so far, for a reset that's on purpose
+pub fn clear() {
+    write(0);
+}
+
+pub fn active() -> bool {
+    reason().is_some()
+}
+
+/// Why this boot is in safe mode, `None` if it isn't
+pub fn reason() -> Option<SafeModeReason> {
+    ACTIVE.lock(|a| a.get())
+}
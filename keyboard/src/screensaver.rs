@@ -0,0 +1,139 @@
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Timer};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::{Point, Primitive, Size},
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+    Drawable, Pixel,
+};
+
+use crate::{
+    clock,
+    idle::{IdlePhase, IDLE},
+    oled::Oled,
+};
+
+pub const FRAME_TIME: Duration = Duration::from_millis(50);
+
+const WIDTH: i32 = 32;
+const HEIGHT: i32 = 128;
+
+const LOGO_SIZE: i32 = 8;
+const LOGO_BOX: Size = Size::new(LOGO_SIZE as u32, LOGO_SIZE as u32);
+/// `HH:MM` in `FONT_4X6`
+const CLOCK_BOX: Size = Size::new(5 * 4, 6);
+
+// a little keycap
+#[rustfmt::skip]
+const LOGO: [u8; LOGO_SIZE as usize] = [
+    0b01111110,
+    0b10000001,
+    0b10111101,
+    0b10100101,
+    0b10100101,
+    0b10111101,
+    0b10000001,
+    0b01111110,
+];
+
+/// A logo bouncing around the screen, or the time once the host has set it.
+///
+/// Each frame only erases and redraws the logo's old and new positions and
+/// never clears the whole buffer, so the flush only sends the dirty region.
+pub struct Screensaver {
+    pos: Point,
+    vel: Point,
+}
+
+impl Default for Screensaver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screensaver {
+    pub fn new() -> Self {
+        Self {
+            pos: Point::new(3, 17),
+            vel: Point::new(1, 2),
+        }
+    }
+
+    fn logo_pixels(at: Point) -> impl Iterator<Item = Pixel<BinaryColor>> {
+        LOGO.iter().enumerate().flat_map(move |(y, row)| {
+            (0..LOGO_SIZE).filter_map(move |x| {
+                if (row >> (LOGO_SIZE - 1 - x)) & 1 == 1 {
+                    Some(Pixel(at + Point::new(x, y as i32), BinaryColor::On))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    fn advance(&mut self, size: Size) {
+        let (max_x, max_y) = (WIDTH - size.width as i32, HEIGHT - size.height as i32);
+        let next = self.pos + self.vel;
+
+        if next.x < 0 || next.x > max_x {
+            self.vel.x = -self.vel.x;
+        }
+
+        if next.y < 0 || next.y > max_y {
+            self.vel.y = -self.vel.y;
+        }
+
+        // the clock is wider than the logo, so it may have just turned up
+        // past the edge
+        self.pos += self.vel;
+        self.pos.x = self.pos.x.clamp(0, max_x);
+        self.pos.y = self.pos.y.clamp(0, max_y);
+    }
+
+    /// Animate until the idle phase changes
+    pub async fn run(&mut self, oled: &'static Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>) {
+        {
+            let mut oled = oled.lock().await;
+            oled.clear();
+            let _ = oled.flush().await;
+        }
+
+        let mut old_size = LOGO_BOX;
+
+        while IDLE.phase() == IdlePhase::Screensaver {
+            let time = clock::hh_mm();
+            let size = if time.is_some() { CLOCK_BOX } else { LOGO_BOX };
+            let old = self.pos;
+            self.advance(size);
+            let new = self.pos;
+
+            {
+                let mut oled = oled.lock().await;
+                oled.draw_no_clear_no_flush(|d| {
+                    let _ = Rectangle::new(old, old_size)
+                        .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+                        .draw(d);
+                    match time {
+                        Some(time) => {
+                            let style = MonoTextStyle::new(&FONT_4X6, BinaryColor::On);
+                            let time = core::str::from_utf8(&time).unwrap_or_default();
+                            let _ = Text::with_baseline(time, new, style, Baseline::Top).draw(d);
+                        }
+                        None => {
+                            for p in Self::logo_pixels(new) {
+                                let _ = p.draw(d);
+                            }
+                        }
+                    }
+                });
+                let _ = oled.flush().await;
+            }
+
+            old_size = size;
+            Timer::after(FRAME_TIME).await;
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Power on self test, run by each half before its steady state tasks start.
+//!
+//! Failed checks are only reported, everything carries on as well as it can.
+
+use core::cell::Cell;
+
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::Point,
+    text::{Baseline, Text},
+    Drawable,
+};
+use profont::PROFONT_7_POINT;
+
+pub use keyboard_shared::SelfTestResults;
+
+use crate::{event::Event, matrix::KeyMatrix, oled::Oled};
+
+/// How long a single check can take, so boot stays quick
+pub const CHECK_TIMEOUT: Duration = Duration::from_millis(250);
+/// Each half runs its own checks before saying hello to the other
+pub const LINK_TIMEOUT: Duration = Duration::from_millis(500);
+
+static RESULTS: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<Option<SelfTestResults>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+/// The right half's results, from its reply to `Hello`
+static PEER_RESULTS: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<Option<SelfTestResults>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+/// Set when the other half says `Hello`
+pub static PEER_HELLO: Event = Event::new();
+
+pub fn record(results: SelfTestResults) {
+    RESULTS.lock(|r| r.set(Some(results)));
+}
+
+pub fn results() -> Option<SelfTestResults> {
+    RESULTS.lock(|r| r.get())
+}
+
+pub fn record_peer(results: SelfTestResults) {
+    PEER_RESULTS.lock(|r| r.set(Some(results)));
+    PEER_HELLO.set();
+}
+
+pub fn peer_results() -> Option<SelfTestResults> {
+    PEER_RESULTS.lock(|r| r.get())
+}
+
+/// Bring up the display, which also checks that it answers on I2C
+pub async fn check_oled(oled: &Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>) -> bool {
+    // give the display a moment to power up
+    Timer::after(Duration::from_millis(100)).await;
+    let mut oled = oled.lock().await;
+    matches!(with_timeout(CHECK_TIMEOUT, oled.init()).await, Ok(Ok(())))
+}
+
+/// Nothing should be pressed while we boot, a key that reads as pressed is
+/// most likely a short or a bad solder joint
+pub fn check_matrix(matrix: &mut KeyMatrix) -> bool {
+    matrix.get().iter().flatten().all(|pressed| !pressed)
+}
+
+/// Show the results for a moment, a bit longer if something failed. `note`
+/// is drawn underneath for anything else worth knowing about.
+pub async fn show_summary(
+    oled: &Mutex<ThreadModeRawMutex, Oled<'static, TWISPI0>>,
+    note: Option<&'static str>,
+) {
+    let results = match results() {
+        Some(r) => r,
+        None => return,
+    };
+
+    let style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
+    let _ = oled
+        .lock()
+        .await
+        .draw(move |d| {
+            for (idx, (name, passed)) in results.checks().into_iter().enumerate() {
+                let y = idx as i32 * 20;
+                let _ = Text::with_baseline(name, Point::new(0, y), style, Baseline::Top).draw(d);
+                let status = if passed { "ok" } else { "FAIL" };
+                let _ =
+                    Text::with_baseline(status, Point::new(0, y + 9), style, Baseline::Top).draw(d);
+            }
+
+            if let Some(note) = note {
+                let y = results.checks().len() as i32 * 20;
+                let _ = Text::with_baseline(note, Point::new(0, y), style, Baseline::Top).draw(d);
+            }
+        })
+        .await;
+
+    let hold = if results.passed() && note.is_none() {
+        1
+    } else {
+        3
+    };
+    Timer::after(Duration::from_secs(hold)).await;
+}
@@ -0,0 +1,352 @@
+//! Settings that persist across power cycles and can be backed up by the host.
+//!
+//! Settings are stored as a versioned blob: a version byte, the postcard
+//! serialized [`Settings`], then a checksum (see
+//! [`keyboard_shared::settings_checksum`]). The same blob is written to flash
+//! and streamed to the host by `ExportSettings`, so a backup can be restored
+//! onto any firmware with the same version.
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use keyboard_shared::{
+    settings_checksum, ConfigItem, ContrastCurve, DisplayContrast, FrameTransform, HidMode,
+    KeyboardSide, KeyboardToHost, LedColour, LedMode, QuietHours, Rotation, SaveFailed, ScanOrder,
+    SettingsBlob, SettingsExporter, SettingsImportStatus, SettingsImporter, SettingsJournal,
+    StatsGraph, Tuning, SETTINGS_MAX_BLOB,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chord_guard,
+    display_widgets::{set_display_swap, set_frame_transform, set_key_grid, set_layer_legend},
+    event::Event,
+    jiggle::{self, DEFAULT_MAX_MINUTES},
+    leds::{set_led_colour, set_led_mode, set_status_led},
+    matrix::{set_scan_order, set_settle_us},
+    oled::{set_display_contrast, set_display_rotation},
+    quiet_hours::set_schedule,
+    rhs_display::set_stats_graph,
+};
+
+/// Bump this when [`Settings`] changes shape, a blob of any other version
+/// isn't loaded
+pub const SETTINGS_VERSION: u8 = 1;
+
+/// The two flash pages reserved for settings in memory.x, see
+/// [`SettingsJournal`]
+const SETTINGS_PAGES: [u32; 2] = [0x000F_E000, 0x000F_D000];
+const PAGE_SIZE: u32 = 4096;
+
+/// Erase cycles of both settings pages, as far as we know
+pub static SETTINGS_ERASES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+    pub debug_screen: bool,
+    pub display_swap: bool,
+    pub layer_legend: bool,
+    /// LED channel gains for each half, indexed by `KeyboardSide`
+    pub led_calibration: [[u8; 3]; 2],
+    /// How each half's display is mounted, indexed by `KeyboardSide`
+    pub display_rotation: [Rotation; 2],
+    pub led_mode: LedMode,
+    pub hid_mode: HidMode,
+    /// Applied to host supplied pixels, indexed by `KeyboardSide`
+    pub frame_transform: [FrameTransform; 2],
+    pub quiet_hours: QuietHours,
+    /// Presence mode turns itself off after this long
+    pub jiggle_max_minutes: u16,
+    /// Committed with `CommitTuning`, live changes aren't saved until then
+    pub tuning: Tuning,
+    pub scan_order: ScanOrder,
+    pub scan_settle_us: u8,
+    pub display_contrast: DisplayContrast,
+    pub key_grid: bool,
+    pub led_colour: LedColour,
+    pub status_led: Option<u8>,
+    pub cross_side_chords: bool,
+    pub stats_graph: StatsGraph,
+}
+
+impl Settings {
+    pub const DEFAULT: Self = Self {
+        debug_screen: false,
+        display_swap: false,
+        layer_legend: false,
+        led_calibration: [[255; 3]; 2],
+        display_rotation: [Rotation::Rotate0; 2],
+        led_mode: LedMode::RainbowWaves,
+        hid_mode: HidMode::Nkro,
+        frame_transform: [FrameTransform::NONE; 2],
+        quiet_hours: QuietHours::OFF,
+        jiggle_max_minutes: DEFAULT_MAX_MINUTES,
+        tuning: Tuning::DEFAULT,
+        scan_order: ScanOrder::Fixed,
+        scan_settle_us: 0,
+        display_contrast: DisplayContrast::Auto(ContrastCurve::DEFAULT),
+        key_grid: false,
+        led_colour: LedColour::DEFAULT,
+        status_led: None,
+        cross_side_chords: false,
+        stats_graph: StatsGraph::Cps,
+    };
+
+    pub fn set(&mut self, item: ConfigItem) {
+        match item {
+            ConfigItem::DisplaySwap(v) => self.display_swap = v,
+            ConfigItem::LayerLegend(v) => self.layer_legend = v,
+            ConfigItem::DisplayRotation { side, rotation } => {
+                self.display_rotation[side as usize] = rotation
+            }
+            ConfigItem::LedMode(mode) => self.led_mode = mode,
+            ConfigItem::HidMode(mode) => self.hid_mode = mode,
+            ConfigItem::FrameTransform { side, transform } => {
+                self.frame_transform[side as usize] = transform
+            }
+            ConfigItem::QuietHours(schedule) => self.quiet_hours = schedule,
+            ConfigItem::JiggleMaxMinutes(minutes) => self.jiggle_max_minutes = minutes,
+            ConfigItem::ScanOrder(order) => self.scan_order = order,
+            ConfigItem::ScanSettleUs(us) => self.scan_settle_us = us,
+            ConfigItem::DisplayContrast(contrast) => self.display_contrast = contrast,
+            ConfigItem::KeyGrid(v) => self.key_grid = v,
+            ConfigItem::LedColour(colour) => self.led_colour = colour,
+            ConfigItem::StatusLed(index) => self.status_led = index,
+            ConfigItem::CrossSideChords(v) => self.cross_side_chords = v,
+            ConfigItem::StatsGraph(graph) => self.stats_graph = graph,
+        }
+    }
+
+    /// Every config value the other half uses, for pushing the full config
+    /// to it
+    pub fn config_items(&self) -> [ConfigItem; 14] {
+        [
+            ConfigItem::DisplaySwap(self.display_swap),
+            ConfigItem::LayerLegend(self.layer_legend),
+            ConfigItem::DisplayRotation {
+                side: KeyboardSide::Left,
+                rotation: self.display_rotation[KeyboardSide::Left as usize],
+            },
+            ConfigItem::DisplayRotation {
+                side: KeyboardSide::Right,
+                rotation: self.display_rotation[KeyboardSide::Right as usize],
+            },
+            ConfigItem::LedMode(self.led_mode),
+            ConfigItem::FrameTransform {
+                side: KeyboardSide::Left,
+                transform: self.frame_transform[KeyboardSide::Left as usize],
+            },
+            ConfigItem::FrameTransform {
+                side: KeyboardSide::Right,
+                transform: self.frame_transform[KeyboardSide::Right as usize],
+            },
+            ConfigItem::ScanOrder(self.scan_order),
+            ConfigItem::ScanSettleUs(self.scan_settle_us),
+            ConfigItem::DisplayContrast(self.display_contrast),
+            ConfigItem::KeyGrid(self.key_grid),
+            ConfigItem::LedColour(self.led_colour),
+            ConfigItem::CrossSideChords(self.cross_side_chords),
+            ConfigItem::StatsGraph(self.stats_graph),
+        ]
+    }
+}
+
+/// Apply a config value to this half, which is `side`
+pub fn apply_config(item: ConfigItem, side: KeyboardSide) {
+    match item {
+        ConfigItem::DisplaySwap(v) => set_display_swap(v),
+        ConfigItem::LayerLegend(v) => set_layer_legend(v),
+        ConfigItem::DisplayRotation { side: s, rotation } => {
+            if s == side {
+                set_display_rotation(rotation)
+            }
+        }
+        ConfigItem::LedMode(mode) => set_led_mode(mode),
+        // only read at boot, before USB is set up
+        ConfigItem::HidMode(_) => {}
+        ConfigItem::FrameTransform { side: s, transform } => {
+            if s == side {
+                set_frame_transform(transform)
+            }
+        }
+        ConfigItem::QuietHours(schedule) => set_schedule(schedule),
+        ConfigItem::JiggleMaxMinutes(minutes) => jiggle::set_max_minutes(minutes),
+        ConfigItem::ScanOrder(order) => set_scan_order(order),
+        ConfigItem::ScanSettleUs(us) => set_settle_us(us),
+        ConfigItem::DisplayContrast(contrast) => set_display_contrast(contrast),
+        ConfigItem::KeyGrid(v) => set_key_grid(v),
+        ConfigItem::LedColour(colour) => set_led_colour(colour),
+        ConfigItem::StatusLed(index) => set_status_led(index),
+        ConfigItem::CrossSideChords(v) => chord_guard::set_cross_side(v),
+        ConfigItem::StatsGraph(graph) => set_stats_graph(graph),
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SettingsError {
+    TooLarge,
+    BadChecksum,
+    UnsupportedVersion,
+    Invalid,
+}
+
+impl From<SettingsError> for SettingsImportStatus {
+    fn from(e: SettingsError) -> Self {
+        match e {
+            SettingsError::TooLarge => SettingsImportStatus::TooLarge,
+            SettingsError::BadChecksum => SettingsImportStatus::BadChecksum,
+            SettingsError::UnsupportedVersion => SettingsImportStatus::UnsupportedVersion,
+            SettingsError::Invalid => SettingsImportStatus::Invalid,
+        }
+    }
+}
+
+impl Settings {
+    pub fn to_blob(&self) -> Result<SettingsBlob, SettingsError> {
+        let mut buf = [0u8; SETTINGS_MAX_BLOB];
+        buf[0] = SETTINGS_VERSION;
+        let len = postcard::to_slice(self, &mut buf[1..SETTINGS_MAX_BLOB - 4])
+            .map_err(|_| SettingsError::TooLarge)?
+            .len()
+            + 1;
+
+        let csum = settings_checksum(&buf[..len]);
+        buf[len..len + 4].copy_from_slice(&csum.to_le_bytes());
+
+        SettingsBlob::from_slice(&buf[..len + 4]).map_err(|_| SettingsError::TooLarge)
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, SettingsError> {
+        if blob.len() < 5 {
+            return Err(SettingsError::Invalid);
+        }
+
+        let (body, csum) = blob.split_at(blob.len() - 4);
+        if settings_checksum(body).to_le_bytes() != csum {
+            return Err(SettingsError::BadChecksum);
+        }
+
+        let payload = &body[1..];
+        match body[0] {
+            SETTINGS_VERSION => postcard::from_bytes(payload).map_err(|_| SettingsError::Invalid),
+            _ => Err(SettingsError::UnsupportedVersion),
+        }
+    }
+}
+
+static SETTINGS: Mutex<ThreadModeRawMutex, RefCell<Settings>> =
+    Mutex::new(RefCell::new(Settings::DEFAULT));
+/// Set whenever the settings change, the settings task applies and saves them
+pub static SETTINGS_CHANGED: Event = Event::new();
+/// Set by the host's `FactoryReset`, the settings task saves the defaults and
+/// restarts
+pub static FACTORY_RESET: Event = Event::new();
+
+pub fn get() -> Settings {
+    SETTINGS.lock(|s| s.borrow().clone())
+}
+
+pub fn update(f: impl FnOnce(&mut Settings)) {
+    SETTINGS.lock(|s| f(&mut s.borrow_mut()));
+    SETTINGS_CHANGED.set();
+}
+
+pub fn replace(settings: Settings) {
+    update(move |s| *s = settings);
+}
+
+/// The `SettingsChunk` reply for an `ExportSettings` request
+pub fn export_chunk(exporter: &mut SettingsExporter, offset: u16) -> KeyboardToHost {
+    exporter.chunk(offset, || get().to_blob().unwrap_or_default())
+}
+
+/// Handle an `ImportSettings` message, applying the settings once the whole
+/// blob has arrived and decoded
+pub fn import_chunk(
+    importer: &mut SettingsImporter,
+    offset: u16,
+    total: u16,
+    data: &[u8],
+) -> SettingsImportStatus {
+    let blob = match importer.chunk(offset, total, data) {
+        Ok(Some(blob)) => blob,
+        Ok(None) => return SettingsImportStatus::Continue,
+        Err(status) => return status,
+    };
+
+    match Settings::from_blob(&blob) {
+        Ok(settings) => {
+            replace(settings);
+            SettingsImportStatus::Applied
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Reads and writes the settings pages, alternating between them
+pub struct SettingsStore<F> {
+    flash: F,
+    journal: SettingsJournal,
+}
+
+impl<F: NorFlash + ReadNorFlash> SettingsStore<F> {
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            journal: SettingsJournal::new(SETTINGS_PAGES, PAGE_SIZE),
+        }
+    }
+
+    /// The flash the settings are stored on, for anything else that keeps a
+    /// page of its own
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Load the newest page that holds valid settings
+    pub fn load(&mut self) -> Result<Settings, SettingsError> {
+        let result = self
+            .journal
+            .load(&mut self.flash, Settings::from_blob)
+            .unwrap_or(Err(SettingsError::Invalid));
+        SETTINGS_ERASES.store(self.journal.erases(), Ordering::Relaxed);
+        result
+    }
+
+    /// Make the next save go over whatever was saved, without needing it to
+    /// have been loaded first
+    pub fn follow_newest(&mut self) {
+        self.journal.follow_newest(&mut self.flash);
+    }
+
+    /// The range to erase before the next save, see
+    /// [`SettingsJournal::erase_needed`]
+    pub fn erase_needed(&self) -> Option<(u32, u32)> {
+        self.journal.erase_needed()
+    }
+
+    /// The range from [`SettingsStore::erase_needed`] has been erased
+    pub fn erased(&mut self) {
+        self.journal.erased();
+        SETTINGS_ERASES.store(self.journal.erases(), Ordering::Relaxed);
+    }
+
+    /// Write to the page not holding the current settings, which has to
+    /// have been erased first
+    pub fn save(&mut self, settings: &Settings) -> Result<(), SettingsError> {
+        let blob = settings.to_blob()?;
+        self.journal
+            .save(&mut self.flash, &blob)
+            .map_err(|SaveFailed| SettingsError::Invalid)
+    }
+}
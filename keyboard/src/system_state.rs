@@ -0,0 +1,146 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use keyberon::layout::Event;
+use keyboard_shared::StatusReport;
+
+#[derive(PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum SystemState {
+    Normal,
+    /// No keycodes are sent to the host, used for cleaning or keeping small
+    /// hands away from the computer
+    Locked,
+}
+
+pub struct SystemStateCell {
+    state: Mutex<ThreadModeRawMutex, Cell<SystemState>>,
+}
+
+impl SystemStateCell {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(SystemState::Normal)),
+        }
+    }
+
+    pub fn get(&self) -> SystemState {
+        self.state.lock(|s| s.get())
+    }
+
+    pub fn set(&self, state: SystemState) {
+        self.state.lock(|s| s.set(state));
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.get() == SystemState::Locked
+    }
+
+    pub fn set_locked(&self, locked: bool) {
+        self.set(if locked {
+            SystemState::Locked
+        } else {
+            SystemState::Normal
+        });
+    }
+}
+
+pub static SYSTEM_STATE: SystemStateCell = SystemStateCell::new();
+
+/// The layout's current layer, the left half forwards it to the right
+static ACTIVE_LAYER: AtomicU8 = AtomicU8::new(0);
+/// Set whenever the active layer changes
+pub static LAYER_CHANGED: crate::event::Event = crate::event::Event::new();
+
+pub fn active_layer() -> u8 {
+    ACTIVE_LAYER.load(Ordering::Relaxed)
+}
+
+/// Returns true if the layer changed
+pub fn set_active_layer(layer: u8) -> bool {
+    let changed = ACTIVE_LAYER.swap(layer, Ordering::Relaxed) != layer;
+    if changed {
+        LAYER_CHANGED.set();
+    }
+    changed
+}
+
+pub fn status_report() -> StatusReport {
+    let mut flags = 0;
+    if SYSTEM_STATE.is_locked() {
+        flags |= StatusReport::LOCKED;
+    }
+
+    StatusReport {
+        layer: active_layer(),
+        flags,
+    }
+}
+
+/// The outer thumb key of each half, in global coordinates
+pub const LOCK_KEYS: [(u8, u8); 2] = [(3, 3), (3, 8)];
+/// How long both lock keys need to be held to toggle the lock
+pub const LOCK_HOLD: Duration = Duration::from_secs(2);
+
+/// Detects the lock toggle combo directly from key events, so it still works
+/// while the layout is being bypassed.
+///
+/// Pressing any other key while the combo is held cancels it, and after
+/// firing both keys have to be released before it can fire again.
+pub struct LockMatcher {
+    held: [bool; 2],
+    since: Option<Instant>,
+    fired: bool,
+}
+
+impl LockMatcher {
+    pub const fn new() -> Self {
+        Self {
+            held: [false; 2],
+            since: None,
+            fired: false,
+        }
+    }
+
+    pub fn event(&mut self, event: Event, now: Instant) {
+        let Some(idx) = LOCK_KEYS.iter().position(|k| *k == event.coord()) else {
+            if event.is_press() {
+                self.since = None;
+            }
+            return;
+        };
+
+        self.held[idx] = event.is_press();
+
+        if self.held.iter().all(|h| *h) {
+            if !self.fired {
+                self.since = Some(now);
+            }
+        } else {
+            self.since = None;
+            if self.held.iter().all(|h| !*h) {
+                self.fired = false;
+            }
+        }
+    }
+
+    /// When the combo will fire if nothing else happens
+    pub fn deadline(&self) -> Option<Instant> {
+        self.since.map(|s| s + LOCK_HOLD)
+    }
+
+    /// Returns true once the combo has been held for long enough
+    pub fn poll(&mut self, now: Instant) -> bool {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => {
+                self.since = None;
+                self.fired = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
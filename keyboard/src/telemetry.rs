@@ -0,0 +1,333 @@
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicU16, AtomicU32},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use keyberon::{
+    action::{Action, HoldTapAction, HoldTapConfig},
+    layout::Event,
+};
+use keyboard_shared::{
+    EventSource, KEY_COLS, KEY_ROWS, LATENCY_BUCKETS, LATENCY_BUCKETS_US, TIMING_BUCKETS,
+    TIMING_BUCKETS_MS,
+};
+
+use crate::{
+    layout::{CustomEvent, Layers, COLS, NUM_CHORDS, N_LAYERS, ROWS},
+    tuning,
+};
+
+pub const MAX_HOLD_TAPS: usize = 8;
+
+#[derive(Clone, Copy, Default)]
+pub struct HoldTapStats {
+    pub coord: (u8, u8),
+    pub timeout: u16,
+    pub taps: u16,
+    pub holds: u16,
+    pub tap_durations: [u16; TIMING_BUCKETS],
+    pub hold_durations: [u16; TIMING_BUCKETS],
+}
+
+pub static HOLD_TAP_STATS: Mutex<
+    ThreadModeRawMutex,
+    RefCell<heapless::Vec<HoldTapStats, MAX_HOLD_TAPS>>,
+> = Mutex::new(RefCell::new(heapless::Vec::new()));
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU16 = AtomicU16::new(0);
+
+/// Chords resolved on this half
+pub static CHORD_FIRES: [AtomicU16; NUM_CHORDS] = [ZERO; NUM_CHORDS];
+/// All members of a chord were pressed, but not close enough together to fire it
+pub static CHORD_NEAR_MISSES: [AtomicU16; NUM_CHORDS] = [ZERO; NUM_CHORDS];
+
+/// Chord counts reported by the other half
+pub static REMOTE_CHORD_FIRES: [AtomicU16; NUM_CHORDS] = [ZERO; NUM_CHORDS];
+pub static REMOTE_CHORD_NEAR_MISSES: [AtomicU16; NUM_CHORDS] = [ZERO; NUM_CHORDS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_32: AtomicU32 = AtomicU32::new(0);
+
+/// Key presses that reached the layout, indexed by `EventSource`
+pub static PRESSES_BY_SOURCE: [AtomicU32; EventSource::COUNT] = [ZERO_32; EventSource::COUNT];
+
+pub fn record_source(source: EventSource) {
+    PRESSES_BY_SOURCE[source as usize].fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn presses_by_source() -> [u32; EventSource::COUNT] {
+    core::array::from_fn(|i| PRESSES_BY_SOURCE[i].load(core::sync::atomic::Ordering::Relaxed))
+}
+
+/// Times each layer has been switched to
+static LAYER_ACTIVATIONS: [AtomicU32; N_LAYERS] = [ZERO_32; N_LAYERS];
+/// Time spent on each layer in ms, not counting the current stretch
+static LAYER_MILLIS: [AtomicU32; N_LAYERS] = [ZERO_32; N_LAYERS];
+/// The active layer and when it became active, in ms since boot
+static LAYER_SINCE: Mutex<ThreadModeRawMutex, Cell<(u8, u64)>> = Mutex::new(Cell::new((0, 0)));
+
+/// The layout has switched to `layer`
+pub fn record_layer(layer: u8) {
+    let now = Instant::now().as_millis();
+    let (prev, since) = LAYER_SINCE.lock(|l| l.replace((layer, now)));
+
+    if let Some(millis) = LAYER_MILLIS.get(prev as usize) {
+        let spent = (now - since).min(u32::MAX as u64) as u32;
+        let _ = millis.fetch_update(
+            core::sync::atomic::Ordering::Relaxed,
+            core::sync::atomic::Ordering::Relaxed,
+            |m| Some(m.saturating_add(spent)),
+        );
+    }
+    if let Some(count) = LAYER_ACTIVATIONS.get(layer as usize) {
+        count.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Switches to `layer` and the ms spent on it, including the current stretch
+pub fn layer_usage(layer: usize) -> (u32, u32) {
+    let (active, since) = LAYER_SINCE.lock(|l| l.get());
+    let current = if active as usize == layer {
+        (Instant::now().as_millis() - since).min(u32::MAX as u64) as u32
+    } else {
+        0
+    };
+
+    (
+        LAYER_ACTIVATIONS[layer].load(core::sync::atomic::Ordering::Relaxed),
+        LAYER_MILLIS[layer]
+            .load(core::sync::atomic::Ordering::Relaxed)
+            .saturating_add(current),
+    )
+}
+
+const _: () = assert!(ROWS == KEY_ROWS && COLS == KEY_COLS);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_ROW: [AtomicU32; COLS] = [ZERO_32; COLS];
+
+/// Real presses of each key, by row and column of the full layout
+pub static KEY_PRESSES: [[AtomicU32; COLS]; ROWS] = [ZERO_ROW; ROWS];
+
+pub fn record_key_press((row, col): (u8, u8)) {
+    if let Some(count) = KEY_PRESSES
+        .get(row as usize)
+        .and_then(|r| r.get(col as usize))
+    {
+        count.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+pub fn key_presses(row: usize) -> [u32; COLS] {
+    core::array::from_fn(|col| KEY_PRESSES[row][col].load(core::sync::atomic::Ordering::Relaxed))
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_LATENCY: [AtomicU16; LATENCY_BUCKETS] = [ZERO; LATENCY_BUCKETS];
+
+/// Press to HID report latency, real presses then synthetic ones
+pub static LATENCY: [[AtomicU16; LATENCY_BUCKETS]; 2] = [ZERO_LATENCY; 2];
+
+/// The earliest press that hasn't made it into a HID report yet
+static PENDING_PRESS: Mutex<ThreadModeRawMutex, Cell<Option<(Instant, bool)>>> =
+    Mutex::new(Cell::new(None));
+
+/// A press has been handed to the layout, `at` is when it entered the pipeline
+pub fn press_queued(at: Instant, synthetic: bool) {
+    PENDING_PRESS.lock(|p| {
+        if p.get().is_none() {
+            p.set(Some((at, synthetic)));
+        }
+    });
+}
+
+/// A HID report has been queued, which carries any pending press
+pub fn report_sent() {
+    if let Some((at, synthetic)) = PENDING_PRESS.lock(|p| p.take()) {
+        bump(&LATENCY[synthetic as usize][latency_bucket(at)]);
+    }
+}
+
+fn latency_bucket(since: Instant) -> usize {
+    let us = since.elapsed().as_micros();
+    LATENCY_BUCKETS_US
+        .iter()
+        .position(|b| us < *b as u64)
+        .unwrap_or(LATENCY_BUCKETS - 1)
+}
+
+/// Press to the LEDs next being written, for key presses scanned on this half
+pub static LIGHT_LATENCY: [AtomicU16; LATENCY_BUCKETS] = ZERO_LATENCY;
+
+/// The earliest press scanned that the LEDs haven't been written since
+static PENDING_LIGHT: Mutex<ThreadModeRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// A press was scanned at `at` and handed to the LED effects
+pub fn light_queued(at: Instant) {
+    PENDING_LIGHT.lock(|p| {
+        if p.get().is_none() {
+            p.set(Some(at));
+        }
+    });
+}
+
+/// The LEDs have been written with a frame that takes in any pending press
+pub fn light_shown() {
+    if let Some(at) = PENDING_LIGHT.lock(|p| p.take()) {
+        bump(&LIGHT_LATENCY[latency_bucket(at)]);
+    }
+}
+
+pub fn light_latency() -> [u16; LATENCY_BUCKETS] {
+    core::array::from_fn(|i| LIGHT_LATENCY[i].load(core::sync::atomic::Ordering::Relaxed))
+}
+
+pub fn latency(synthetic: bool) -> [u16; LATENCY_BUCKETS] {
+    core::array::from_fn(|i| {
+        LATENCY[synthetic as usize][i].load(core::sync::atomic::Ordering::Relaxed)
+    })
+}
+
+pub fn bump(counter: &AtomicU16) {
+    let _ = counter.fetch_update(
+        core::sync::atomic::Ordering::Relaxed,
+        core::sync::atomic::Ordering::Relaxed,
+        |v| Some(v.saturating_add(1)),
+    );
+}
+
+fn bucket(duration: Duration) -> usize {
+    let ms = duration.as_millis();
+    TIMING_BUCKETS_MS
+        .iter()
+        .position(|b| ms < *b as u64)
+        .unwrap_or(TIMING_BUCKETS - 1)
+}
+
+/// Classify hold-taps against a new timeout, to match the retuned layout
+pub fn set_hold_tap_timeout(timeout: u16) {
+    HOLD_TAP_STATS.lock(|stats| {
+        for s in stats.borrow_mut().iter_mut() {
+            s.timeout = timeout;
+        }
+    });
+}
+
+struct Tracked {
+    action: &'static HoldTapAction<CustomEvent>,
+    pressed_at: Option<Instant>,
+    other_pressed: bool,
+    other_released: bool,
+}
+
+/// Watches the processed event stream and classifies each hold-tap press as a
+/// tap or a hold using the same rules as keyberon.
+pub struct HoldTapTelemetry {
+    tracked: heapless::Vec<Tracked, MAX_HOLD_TAPS>,
+}
+
+impl HoldTapTelemetry {
+    pub fn new(layers: &'static Layers) -> Self {
+        let mut tracked = heapless::Vec::new();
+
+        HOLD_TAP_STATS.lock(|stats| {
+            let mut stats = stats.borrow_mut();
+            stats.clear();
+
+            for (row_idx, row) in layers[0].iter().enumerate() {
+                for (col_idx, action) in row.iter().enumerate() {
+                    if let Action::HoldTap(ht) = action {
+                        let stat = HoldTapStats {
+                            coord: (row_idx as u8, col_idx as u8),
+                            timeout: tuning::get().hold_tap_timeout_ms,
+                            ..Default::default()
+                        };
+
+                        if stats.push(stat).is_ok() {
+                            let _ = tracked.push(Tracked {
+                                action: ht,
+                                pressed_at: None,
+                                other_pressed: false,
+                                other_released: false,
+                            });
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { tracked }
+    }
+
+    /// Classify against the hold-taps of `layers` from now on, keeping the
+    /// stats so far, for when the layout is rebuilt with changed hold-taps
+    pub fn use_layers(&mut self, layers: &'static Layers) {
+        let hold_taps = layers[0]
+            .iter()
+            .flatten()
+            .filter_map(|action| match action {
+                Action::HoldTap(ht) => Some(*ht),
+                _ => None,
+            });
+
+        for (t, action) in self.tracked.iter_mut().zip(hold_taps) {
+            t.action = action;
+        }
+    }
+
+    pub fn event(&mut self, event: Event, now: Instant) {
+        let coord = event.coord();
+
+        let idx = HOLD_TAP_STATS.lock(|stats| stats.borrow().iter().position(|s| s.coord == coord));
+
+        let Some(idx) = idx else {
+            for t in self.tracked.iter_mut().filter(|t| t.pressed_at.is_some()) {
+                if event.is_press() {
+                    t.other_pressed = true;
+                } else if t.other_pressed {
+                    t.other_released = true;
+                }
+            }
+            return;
+        };
+
+        let t = &mut self.tracked[idx];
+
+        if event.is_press() {
+            t.pressed_at = Some(now);
+            t.other_pressed = false;
+            t.other_released = false;
+            return;
+        }
+
+        let Some(pressed_at) = t.pressed_at.take() else {
+            return;
+        };
+
+        let duration = now - pressed_at;
+        let timeout = HOLD_TAP_STATS.lock(|stats| stats.borrow()[idx].timeout);
+        let timed_out = duration >= Duration::from_millis(timeout as u64);
+        let held = match t.action.config {
+            HoldTapConfig::HoldOnOtherKeyPress => timed_out || t.other_pressed,
+            HoldTapConfig::PermissiveHold => timed_out || t.other_released,
+            _ => timed_out,
+        };
+
+        HOLD_TAP_STATS.lock(|stats| {
+            let mut stats = stats.borrow_mut();
+            let s = &mut stats[idx];
+            let b = bucket(duration);
+            if held {
+                s.holds = s.holds.saturating_add(1);
+                s.hold_durations[b] = s.hold_durations[b].saturating_add(1);
+            } else {
+                s.taps = s.taps.saturating_add(1);
+                s.tap_durations[b] = s.tap_durations[b].saturating_add(1);
+            }
+        });
+    }
+}
@@ -0,0 +1,208 @@
+//! Timings the host can change on the fly. Changes take effect straight away
+//! and are only written to flash when committed, see `HostToKeyboard::SetTuning`.
+//!
+//! keyberon reads each hold-tap's timeout from the `'static` action in the
+//! layers, so retuning builds a copy of `LAYERS` with new hold-tap actions
+//! and the layout is recreated on top of it.
+//!
+//! The same copy carries any hold-taps whose behaviour the host has changed,
+//! see `HostToKeyboard::SetHoldTap`. Those changes only last until restart,
+//! the keymap is where a behaviour should go once it feels right.
+
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use keyberon::action::{Action, HoldTapAction, HoldTapConfig};
+pub use keyboard_shared::Tuning;
+use keyboard_shared::{hold_tap_index, HoldTapBehaviour, HoldTapMode, HoldTapOverrides};
+
+use crate::{
+    layout::{CustomEvent, Layers, COLS, LAYERS, LAYOUT_ROWS, N_LAYERS},
+    telemetry,
+};
+
+/// Most hold-tap keys across all layers that can be retuned, any past this
+/// keep the timeout and behaviour they were written with
+const MAX_TUNED_HOLD_TAPS: usize = 8;
+/// Most hold-taps whose behaviour can be changed at once
+const MAX_HOLD_TAP_OVERRIDES: usize = 8;
+
+static TUNING: Mutex<ThreadModeRawMutex, Cell<Tuning>> = Mutex::new(Cell::new(Tuning::DEFAULT));
+/// The saved tuning as of the last `load_saved`
+static SAVED: Mutex<ThreadModeRawMutex, Cell<Option<Tuning>>> = Mutex::new(Cell::new(None));
+/// Set when the hold-tap timeout or a behaviour changes, the layout task
+/// rebuilds the layout
+static HOLD_TAPS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+static HOLD_TAP_OVERRIDES: Mutex<
+    ThreadModeRawMutex,
+    RefCell<HoldTapOverrides<MAX_HOLD_TAP_OVERRIDES>>,
+> = Mutex::new(RefCell::new(HoldTapOverrides::new()));
+
+pub fn get() -> Tuning {
+    TUNING.lock(|t| t.get())
+}
+
+/// Use `tuning` on this half, it should have been checked with
+/// [`Tuning::valid`]
+pub fn set(tuning: Tuning) {
+    let old = TUNING.lock(|t| t.replace(tuning));
+
+    if old.hold_tap_timeout_ms != tuning.hold_tap_timeout_ms {
+        telemetry::set_hold_tap_timeout(tuning.hold_tap_timeout_ms);
+        HOLD_TAPS_CHANGED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Use the saved tuning if it has changed since it was last loaded, so saving
+/// other settings doesn't undo timings that are being tried out. Returns
+/// whether it was used.
+pub fn load_saved(saved: Tuning) -> bool {
+    if SAVED.lock(|s| s.replace(Some(saved))) == Some(saved) {
+        return false;
+    }
+
+    set(saved);
+    true
+}
+
+/// The hold-tap at a key of the keymap, if there is one
+fn keymap_hold_tap(layer: u8, row: u8, col: u8) -> Option<&'static HoldTapAction<CustomEvent>> {
+    match LAYERS
+        .get(layer as usize)?
+        .get(row as usize)?
+        .get(col as usize)?
+    {
+        Action::HoldTap(ht) => Some(*ht),
+        _ => None,
+    }
+}
+
+fn mode(config: &HoldTapConfig) -> HoldTapMode {
+    match config {
+        HoldTapConfig::HoldOnOtherKeyPress => HoldTapMode::HoldOnOtherKeyPress,
+        HoldTapConfig::PermissiveHold => HoldTapMode::PermissiveHold,
+        // the keymap has no custom handlers, they'd only time out here
+        HoldTapConfig::Default | HoldTapConfig::Custom(_) => HoldTapMode::Default,
+    }
+}
+
+fn config(mode: HoldTapMode) -> HoldTapConfig {
+    match mode {
+        HoldTapMode::Default => HoldTapConfig::Default,
+        HoldTapMode::HoldOnOtherKeyPress => HoldTapConfig::HoldOnOtherKeyPress,
+        HoldTapMode::PermissiveHold => HoldTapConfig::PermissiveHold,
+    }
+}
+
+/// How the hold-tap at a key of the layout behaves, and whether that's been
+/// changed from the keymap's. `None` if there's no hold-tap there.
+pub fn hold_tap(layer: u8, row: u8, col: u8) -> Option<(HoldTapBehaviour, bool)> {
+    let ht = keymap_hold_tap(layer, row, col)?;
+    let keymap = HoldTapBehaviour {
+        mode: mode(&ht.config),
+        tap_hold_interval_ms: ht.tap_hold_interval,
+    };
+
+    Some(HOLD_TAP_OVERRIDES.lock(|o| o.borrow().behaviour((layer, row, col), keymap)))
+}
+
+fn is_hold_tap(action: &Action<CustomEvent>) -> bool {
+    matches!(action, Action::HoldTap(_))
+}
+
+/// Change how the hold-tap at a key of the layout behaves, or go back to the
+/// keymap's with `None`. The behaviour should have been checked with
+/// [`HoldTapBehaviour::valid`]. Returns false if there's no hold-tap there,
+/// it's past the ones [`retuned_layers`] has room to change, or too many are
+/// changed already.
+pub fn set_hold_tap(layer: u8, row: u8, col: u8, behaviour: Option<HoldTapBehaviour>) -> bool {
+    let idx = hold_tap_index(&LAYERS, is_hold_tap, (layer, row, col));
+    if !matches!(idx, Some(i) if i < MAX_TUNED_HOLD_TAPS) {
+        return false;
+    }
+
+    let done = HOLD_TAP_OVERRIDES.lock(|o| o.borrow_mut().set((layer, row, col), behaviour));
+
+    if done {
+        HOLD_TAPS_CHANGED.store(true, Ordering::Relaxed);
+    }
+    done
+}
+
+/// Whether the layout needs rebuilding with [`retuned_layers`]
+pub fn take_hold_taps_changed() -> bool {
+    HOLD_TAPS_CHANGED.swap(false, Ordering::Relaxed)
+}
+
+/// The keymap's hold-tap `ht` with `timeout`, and `behaviour` if the host
+/// has changed it
+fn tuned(
+    ht: &HoldTapAction<CustomEvent>,
+    timeout: u16,
+    behaviour: Option<HoldTapBehaviour>,
+) -> HoldTapAction<CustomEvent> {
+    let mut tuned = HoldTapAction { timeout, ..*ht };
+    if let Some(behaviour) = behaviour {
+        tuned.config = config(behaviour.mode);
+        tuned.tap_hold_interval = behaviour.tap_hold_interval_ms;
+    }
+    tuned
+}
+
+struct TunedLayers {
+    layers: Layers,
+    hold_taps: [HoldTapAction<CustomEvent>; MAX_TUNED_HOLD_TAPS],
+}
+
+const UNTUNED_HOLD_TAP: HoldTapAction<CustomEvent> = HoldTapAction {
+    timeout: 0,
+    hold: Action::NoOp,
+    tap: Action::NoOp,
+    config: HoldTapConfig::Default,
+    tap_hold_interval: 0,
+};
+
+const EMPTY_TUNED_LAYERS: TunedLayers = TunedLayers {
+    layers: [[[Action::NoOp; COLS]; LAYOUT_ROWS]; N_LAYERS],
+    hold_taps: [UNTUNED_HOLD_TAP; MAX_TUNED_HOLD_TAPS],
+};
+
+/// The layout only ever points at one of these, the other is free to be
+/// rewritten for the next change
+static mut TUNED_LAYERS: [TunedLayers; 2] = [EMPTY_TUNED_LAYERS, EMPTY_TUNED_LAYERS];
+static mut SPARE: usize = 0;
+
+/// A copy of `LAYERS` with every hold-tap's timeout set to the current tuning,
+/// and any behaviours the host has changed.
+///
+/// # Safety
+///
+/// This overwrites the layers returned by the call before last, so the caller
+/// must have stopped using those, and only one task may call this.
+pub unsafe fn retuned_layers() -> &'static Layers {
+    let timeout = get().hold_tap_timeout_ms;
+
+    let tuned = &mut TUNED_LAYERS[SPARE];
+    SPARE = 1 - SPARE;
+
+    tuned.layers = LAYERS;
+    HOLD_TAP_OVERRIDES.lock(|o| {
+        o.borrow().retune(
+            &mut tuned.layers,
+            tuned.hold_taps.iter_mut(),
+            is_hold_tap,
+            |action, slot, behaviour| {
+                if let Action::HoldTap(ht) = action {
+                    *slot = self::tuned(ht, timeout, behaviour);
+                    *action = Action::HoldTap(slot);
+                }
+            },
+        )
+    });
+
+    &tuned.layers
+}
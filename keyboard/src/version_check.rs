@@ -0,0 +1,91 @@
+//! Checks that both halves are running the same firmware, since flashing one
+//! half and forgetting the other leads to all sorts of odd behaviour.
+//!
+//! Each half's `FirmwareVersion` trails its `Hello`, so firmware from before
+//! versions were swapped reads the `Hello` without it and the halves still
+//! pair. Firmware from before it trailed `Hello` sends it as a `Version`
+//! message of its own just after. Key events keep working through a
+//! mismatch, it's only shown as a warning.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::warn;
+use embassy_time::{with_timeout, Duration};
+
+use crate::{
+    event::Event,
+    hostlog,
+    messages::{FirmwareVersion, Trailing},
+};
+
+pub const CURRENT: FirmwareVersion = FirmwareVersion {
+    protocol: crate::messages::PROTOCOL_VERSION,
+    git_hash: git_hash(env!("KEYBOARD_GIT_HASH")),
+};
+
+/// Sent in our `Hello`
+pub const fn ours() -> Trailing<FirmwareVersion> {
+    Trailing(Some(CURRENT))
+}
+
+/// How long after the other half's `Hello` its version should turn up
+const VERSION_TIMEOUT: Duration = Duration::from_secs(2);
+
+static MISMATCH: AtomicBool = AtomicBool::new(false);
+static PEER_HELLO: Event = Event::new();
+static PEER_VERSION: Event = Event::new();
+
+const fn git_hash(hash: &str) -> [u8; 16] {
+    let hash = hash.as_bytes();
+    let mut out = [b'?'; 16];
+    let mut i = 0;
+    while i < out.len() && i < hash.len() {
+        out[i] = hash[i];
+        i += 1;
+    }
+    out
+}
+
+/// Whether the other half is running different firmware, or firmware too old
+/// to say
+pub fn mismatch() -> bool {
+    MISMATCH.load(Ordering::Relaxed)
+}
+
+/// The other half said `Hello`, with its version unless it's too old to
+/// send it there, in which case it should follow
+pub fn peer_hello(version: Trailing<FirmwareVersion>) {
+    PEER_HELLO.set();
+    if let Trailing(Some(version)) = version {
+        record_peer(version);
+    }
+}
+
+pub fn record_peer(version: FirmwareVersion) {
+    let mismatch = version != CURRENT;
+    if mismatch {
+        warn!(
+            "other half is running {}, we're running {}",
+            version, CURRENT
+        );
+        hostlog!(Warn, "other half is running different firmware");
+    }
+    MISMATCH.store(mismatch, Ordering::Relaxed);
+    PEER_VERSION.set();
+}
+
+/// Flag a mismatch when the other half says `Hello` but never sends its
+/// version
+pub async fn watch_peer() -> ! {
+    loop {
+        PEER_HELLO.wait().await;
+        if with_timeout(VERSION_TIMEOUT, PEER_VERSION.wait())
+            .await
+            .is_err()
+        {
+            warn!("other half didn't send its version");
+            hostlog!(Warn, "other half didn't send its version");
+            MISMATCH.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardToHost, LATENCY_BUCKETS, LATENCY_BUCKETS_US};
+
+use crate::host_link::HostLink;
+
+/// Layout columns on the left half, the rest belong to the right
+const COLS_PER_SIDE: u8 = 6;
+
+/// Measure key press to HID report latency by having the keyboard press a key
+/// for us, or from keys typed on it. Having it press keys needs firmware
+/// built with the `inject-keys` feature.
+#[derive(Debug, clap::Parser)]
+pub struct BenchLatencyOpts {
+    #[clap(long, default_value = "100")]
+    samples: u16,
+
+    /// Key to press, the default is left shift so nothing gets typed. Keys on
+    /// the right half (col 6 and up) are pressed by the right half, so the
+    /// measurement includes the trip across the link.
+    #[clap(long, default_value = "1")]
+    row: u8,
+    #[clap(long, default_value = "0")]
+    col: u8,
+
+    /// How long each press is held for
+    #[clap(long, default_value = "20")]
+    press_ms: u16,
+
+    /// Time between presses
+    #[clap(long, default_value = "50")]
+    gap_ms: u64,
+
+    /// Instead of pressing keys, measure the keys typed on the keyboard for
+    /// this many seconds. These go from the key being scanned rather than
+    /// from the host's command, so they show what typing actually sees.
+    /// Presses on the left half are also timed to the LEDs next being
+    /// written, to compare LED modes.
+    #[clap(long)]
+    typed: Option<u64>,
+
+    port: Option<String>,
+}
+
+impl BenchLatencyOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        if let Some(secs) = self.typed {
+            let before = latency(&mut link, false).await?;
+            let light_before = light_latency(&mut link).await?;
+            println!("Type on the keyboard for {}s...", secs);
+            link.keep_alive_while(tokio::time::sleep(Duration::from_secs(secs)))
+                .await?;
+            let after = latency(&mut link, false).await?;
+            let light_after = light_latency(&mut link).await?;
+
+            let buckets = since(&before, &after);
+            let total: u32 = buckets.iter().map(|b| *b as u32).sum();
+            if total == 0 {
+                return Err(eyre!("No presses were typed"));
+            }
+
+            println!("{} presses recorded", total);
+            print_buckets(&buckets, total);
+
+            let buckets = since(&light_before, &light_after);
+            let total: u32 = buckets.iter().map(|b| *b as u32).sum();
+            if total > 0 {
+                println!();
+                println!("Press to LEDs, {} presses on the left half", total);
+                print_buckets(&buckets, total);
+            }
+            return Ok(());
+        }
+
+        let before = latency(&mut link, true).await?;
+
+        for _ in 0..self.samples {
+            let (row, col, duration_ms) = (self.row, self.col, self.press_ms);
+            let msg = if col >= COLS_PER_SIDE {
+                HostToKeyboard::InjectRemoteKey {
+                    row,
+                    col,
+                    duration_ms,
+                }
+            } else {
+                HostToKeyboard::InjectKey {
+                    row,
+                    col,
+                    duration_ms,
+                }
+            };
+            link.send(msg).await?;
+            tokio::time::sleep(Duration::from_millis(self.press_ms as u64 + self.gap_ms)).await;
+        }
+
+        let after = latency(&mut link, true).await?;
+
+        let buckets = since(&before, &after);
+        let total: u32 = buckets.iter().map(|b| *b as u32).sum();
+
+        if total == 0 {
+            return Err(eyre!(
+                "No synthetic presses were recorded, is the firmware built with the inject-keys feature?"
+            ));
+        }
+
+        println!("{} of {} presses recorded", total, self.samples);
+        print_buckets(&buckets, total);
+
+        Ok(())
+    }
+}
+
+fn since(
+    before: &[u16; LATENCY_BUCKETS],
+    after: &[u16; LATENCY_BUCKETS],
+) -> [u16; LATENCY_BUCKETS] {
+    std::array::from_fn(|i| after[i].wrapping_sub(before[i]))
+}
+
+fn print_buckets(buckets: &[u16; LATENCY_BUCKETS], total: u32) {
+    println!();
+    for (idx, count) in buckets.iter().enumerate() {
+        println!("{:>10} {:>6}", bucket_label(idx), count);
+    }
+    println!();
+    for pct in [0.5, 0.9, 0.99] {
+        println!(
+            "p{:<3} {}",
+            (pct * 100.0) as u32,
+            bucket_label(percentile_bucket(buckets, total, pct))
+        );
+    }
+}
+
+async fn latency(link: &mut HostLink, synthetic: bool) -> Result<[u16; LATENCY_BUCKETS]> {
+    link.send(HostToKeyboard::RequestLatency).await?;
+
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(2))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for latency stats"))?;
+
+        if let KeyboardToHost::Latency {
+            synthetic: s,
+            buckets,
+        } = msg
+        {
+            if s == synthetic {
+                return Ok(buckets);
+            }
+        }
+    }
+}
+
+async fn light_latency(link: &mut HostLink) -> Result<[u16; LATENCY_BUCKETS]> {
+    link.send(HostToKeyboard::RequestLatency).await?;
+
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(2))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for latency stats"))?;
+
+        if let KeyboardToHost::LightLatency { buckets } = msg {
+            return Ok(buckets);
+        }
+    }
+}
+
+/// The bucket containing the given percentile
+fn percentile_bucket(buckets: &[u16; LATENCY_BUCKETS], total: u32, pct: f32) -> usize {
+    let target = (total as f32 * pct).ceil().max(1.0) as u32;
+    let mut seen = 0;
+    for (idx, b) in buckets.iter().enumerate() {
+        seen += *b as u32;
+        if seen >= target {
+            return idx;
+        }
+    }
+
+    LATENCY_BUCKETS - 1
+}
+
+pub(crate) fn bucket_label(idx: usize) -> String {
+    match LATENCY_BUCKETS_US.get(idx) {
+        Some(upper) => format!("<{}us", upper),
+        None => format!(">={}us", LATENCY_BUCKETS_US[LATENCY_BUCKETS_US.len() - 1]),
+    }
+}
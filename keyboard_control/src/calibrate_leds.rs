@@ -0,0 +1,84 @@
+use std::io::Write;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardSide};
+
+use crate::{host_link::HostLink, util::read_line};
+
+const TEST_COLOURS: &[(&str, [u8; 3])] = &[
+    ("white", [255, 255, 255]),
+    ("grey", [96, 96, 96]),
+    ("warm white", [255, 180, 110]),
+    ("red", [255, 0, 0]),
+    ("green", [0, 255, 0]),
+    ("blue", [0, 0, 255]),
+];
+
+/// Interactively tune each half's LED colour balance against some solid test
+/// colours, the gains are saved on the keyboard as they're changed
+#[derive(Debug, clap::Parser)]
+pub struct CalibrateLedsOpts {
+    port: Option<String>,
+}
+
+impl CalibrateLedsOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        println!("Commands:");
+        println!("  <left|right> <r> <g> <b>  set the gains for a half, 0-255");
+        println!("  n                         next test colour");
+        println!("  q                         finish");
+
+        let result = calibrate(&mut link).await;
+
+        link.send(HostToKeyboard::LedTestColour(None)).await?;
+
+        result
+    }
+}
+
+async fn calibrate(link: &mut HostLink) -> Result<()> {
+    for (name, colour) in TEST_COLOURS.iter().cycle() {
+        link.send(HostToKeyboard::LedTestColour(Some(*colour)))
+            .await?;
+        println!("Showing {}", name);
+
+        loop {
+            print!("> ");
+            std::io::stdout().flush()?;
+
+            let line = match link.keep_alive_while(read_line()).await?? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            let words = line.split_whitespace().collect::<Vec<_>>();
+
+            match words[..] {
+                [] => {}
+                ["n"] => break,
+                ["q"] => return Ok(()),
+                [side, r, g, b] => match parse_gains(side, r, g, b) {
+                    Ok((side, [r, g, b])) => {
+                        link.send(HostToKeyboard::SetLedCalibration { side, r, g, b })
+                            .await?;
+                    }
+                    Err(e) => println!("{}", e),
+                },
+                _ => println!("Unknown command: {}", line.trim()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_gains(side: &str, r: &str, g: &str, b: &str) -> Result<(KeyboardSide, [u8; 3])> {
+    let side = match side {
+        "left" | "l" => KeyboardSide::Left,
+        "right" | "r" => KeyboardSide::Right,
+        _ => return Err(eyre!("Unknown side: {}", side)),
+    };
+
+    Ok((side, [r.parse()?, g.parse()?, b.parse()?]))
+}
@@ -0,0 +1,182 @@
+use std::{path::PathBuf, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    CaptureDirection, CaptureRecord, DomToSub, HostToKeyboard, KeyboardToHost, Received, SubToDom,
+};
+
+use crate::host_link::{FrameDecoder, HostLink};
+
+/// Save the bytes that crossed the link between the halves, as recorded by
+/// the left half, or print the messages in a saved capture. The firmware has
+/// to be built with the `link-capture` feature.
+#[derive(Debug, clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct CaptureOpts {
+    #[clap(subcommand)]
+    command: Option<CaptureCommand>,
+
+    /// Where to save the capture
+    #[clap(long, parse(from_os_str), required = true)]
+    out: Option<PathBuf>,
+
+    /// Empty the capture and wait this many seconds before saving it, so it
+    /// only holds what happens in that time
+    #[clap(long)]
+    seconds: Option<u64>,
+
+    port: Option<String>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum CaptureCommand {
+    /// Print the messages in a saved capture, with when each crossed the
+    /// link and any frames that failed to decode or validate
+    Decode {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+impl CaptureOpts {
+    pub async fn execute(self) -> Result<()> {
+        if let Some(CaptureCommand::Decode { file }) = self.command {
+            return decode(&std::fs::read(&file)?);
+        }
+
+        let out = self.out.ok_or_else(|| eyre!("--out is needed"))?;
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        if let Some(seconds) = self.seconds {
+            link.send(HostToKeyboard::ClearCapture).await?;
+            println!("Capturing for {}s", seconds);
+            tokio::time::sleep(Duration::from_secs(seconds)).await;
+        }
+
+        let capture = fetch(&mut link).await?;
+        if capture.is_empty() {
+            return Err(eyre!(
+                "Nothing was captured, is the firmware built with the link-capture feature?"
+            ));
+        }
+
+        std::fs::write(&out, &capture)?;
+        println!(
+            "Saved {} bytes of capture to {}",
+            capture.len(),
+            out.display()
+        );
+
+        Ok(())
+    }
+}
+
+async fn fetch(link: &mut HostLink) -> Result<Vec<u8>> {
+    let mut capture = Vec::new();
+
+    loop {
+        let offset = capture.len() as u16;
+        link.send(HostToKeyboard::ReadCapture { offset }).await?;
+
+        let (total, chunk) = loop {
+            let msg = link
+                .recv_timeout(Duration::from_secs(1))
+                .await?
+                .ok_or_else(|| eyre!("Timed out waiting for the capture at offset {}", offset))?;
+
+            if let KeyboardToHost::CaptureChunk {
+                offset: o,
+                total,
+                len,
+                data,
+            } = msg
+            {
+                if o == offset {
+                    break (total as usize, data[..len as usize].to_vec());
+                }
+            }
+        };
+
+        if chunk.is_empty() && capture.len() < total {
+            return Err(eyre!("Keyboard sent an empty chunk at offset {}", offset));
+        }
+
+        capture.extend_from_slice(&chunk);
+
+        if capture.len() >= total {
+            return Ok(capture);
+        }
+    }
+}
+
+/// Replay each record through a decoder for its direction, printing what
+/// each frame turned out to be
+fn decode(mut capture: &[u8]) -> Result<()> {
+    let mut sent = FrameDecoder::<DomToSub>::new();
+    let mut received = FrameDecoder::<SubToDom>::new();
+    let (mut frames, mut corrupt) = (0, 0);
+
+    while !capture.is_empty() {
+        let (record, rest) = CaptureRecord::parse(capture).ok_or_else(|| {
+            eyre!(
+                "Capture is cut short or garbled at {} bytes from the end",
+                capture.len()
+            )
+        })?;
+        capture = rest;
+
+        let stamp = format!(
+            "[{:>4}.{:06}]",
+            record.at_us / 1_000_000,
+            record.at_us % 1_000_000
+        );
+
+        let (lines, undecodable) = match record.direction {
+            CaptureDirection::Sent => {
+                let before = sent.undecodable();
+                let lines = describe(sent.feed(record.bytes)?);
+                (lines, sent.undecodable() - before)
+            }
+            CaptureDirection::Received => {
+                let before = received.undecodable();
+                let lines = describe(received.feed(record.bytes)?);
+                (lines, received.undecodable() - before)
+            }
+        };
+
+        let arrow = match record.direction {
+            CaptureDirection::Sent => "->",
+            CaptureDirection::Received => "<-",
+        };
+        for (line, ok) in lines {
+            frames += 1;
+            corrupt += !ok as u64;
+            println!("{} {} {}", stamp, arrow, line);
+        }
+        for _ in 0..undecodable {
+            frames += 1;
+            corrupt += 1;
+            println!("{} {} undecodable frame", stamp, arrow);
+        }
+    }
+
+    println!();
+    println!(
+        "{} frames, {} failed to decode or validate",
+        frames, corrupt
+    );
+
+    Ok(())
+}
+
+/// A line for each frame, and whether it checked out
+fn describe<T: std::fmt::Debug>(received: Vec<Received<T>>) -> Vec<(String, bool)> {
+    received
+        .into_iter()
+        .map(|r| match r {
+            Received::Cmd { cmd, ack } => (format!("cmd {}: {:?}", ack.uuid, cmd), true),
+            Received::Ack(uuid) => (format!("ack {}", uuid), true),
+            Received::Corrupt => ("checksum failed".to_string(), false),
+        })
+        .collect()
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    DebounceAdjustment, HostToKeyboard, KeyboardToHost, KEY_COLS, KEY_ROWS, SCAN_PERIOD_US,
+};
+
+use crate::{heatmap_render, host_link::HostLink};
+
+/// Show the debounce threshold the keyboard has learnt for each key, how
+/// often each key has chattered, and the latest threshold changes
+#[derive(Debug, clap::Parser)]
+pub struct ChatterOpts {
+    port: Option<String>,
+}
+
+type Row = [u16; KEY_COLS];
+
+impl ChatterOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+        link.send(HostToKeyboard::RequestDebounce).await?;
+
+        let mut scans = [[0; KEY_COLS]; KEY_ROWS];
+        let mut chatters = [[0; KEY_COLS]; KEY_ROWS];
+        let adjustments = loop {
+            let msg = link
+                .recv_timeout(Duration::from_secs(2))
+                .await?
+                .ok_or_else(|| eyre!("Timed out waiting for debounce stats"))?;
+
+            match msg {
+                KeyboardToHost::Debounce {
+                    row,
+                    scans: row_scans,
+                    chatters: row_chatters,
+                } => {
+                    if let (Some(s), Some(c)) =
+                        (scans.get_mut(row as usize), chatters.get_mut(row as usize))
+                    {
+                        *s = row_scans;
+                        *c = row_chatters;
+                    }
+                }
+                KeyboardToHost::DebounceAdjustments { len, adjustments } => {
+                    break adjustments[..(len as usize).min(adjustments.len())].to_vec();
+                }
+                _ => {}
+            }
+        };
+
+        println!("Debounce (ms):");
+        print_grid(&scans, |s| format!("{:.1}", to_ms(s)));
+        println!();
+        println!("Chatter:");
+        print_grid(&chatters, |c| c.to_string());
+        println!();
+        print_adjustments(&adjustments);
+
+        Ok(())
+    }
+}
+
+fn to_ms(scans: u16) -> f32 {
+    scans as f32 * SCAN_PERIOD_US as f32 / 1000.0
+}
+
+fn print_grid(rows: &[Row; KEY_ROWS], cell: impl Fn(u16) -> String) {
+    let present = heatmap_render::keys().collect::<Vec<_>>();
+
+    for (row, values) in rows.iter().enumerate() {
+        let line = values
+            .iter()
+            .enumerate()
+            .map(|(col, value)| {
+                let cell = if present.contains(&(row, col)) {
+                    cell(*value)
+                } else {
+                    String::new()
+                };
+                let split = if col == KEY_COLS / 2 { "   " } else { "" };
+                format!("{}{:>7}", split, cell)
+            })
+            .collect::<String>();
+        println!("{}", line.trim_end());
+    }
+}
+
+fn print_adjustments(adjustments: &[DebounceAdjustment]) {
+    if adjustments.is_empty() {
+        println!("No keys have needed their debounce changing");
+        return;
+    }
+
+    println!("Latest changes:");
+    for a in adjustments.iter().rev() {
+        println!(
+            "  ({}, {}) to {:.1}ms, {} ago",
+            a.row,
+            a.col,
+            to_ms(a.scans),
+            format_age(a.secs_ago)
+        );
+    }
+}
+
+fn format_age(secs: u32) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
@@ -0,0 +1,227 @@
+use std::io;
+
+use clap::{Arg, Command, CommandFactory};
+use color_eyre::Result;
+use serde::Serialize;
+
+/// Bump this when the layout of the description changes, not when commands
+/// or options are added
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Description {
+    schema_version: u32,
+    command: CommandInfo,
+}
+
+#[derive(Serialize)]
+struct CommandInfo {
+    name: String,
+    about: Option<String>,
+    args: Vec<ArgInfo>,
+    subcommands: Vec<CommandInfo>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ArgKind {
+    /// Present or not, takes no value
+    Flag,
+    /// `--name <value>`
+    Option,
+    /// Given by position, in order of `index`
+    Positional,
+}
+
+#[derive(Serialize)]
+struct ArgInfo {
+    name: String,
+    kind: ArgKind,
+    long: Option<String>,
+    short: Option<char>,
+    /// Where a positional argument goes, from 1
+    index: Option<usize>,
+    help: Option<String>,
+    value_names: Vec<String>,
+    /// The only values accepted, if they're limited
+    possible_values: Vec<String>,
+    default_values: Vec<String>,
+    required: bool,
+    multiple: bool,
+    /// Given before the subcommand, but applies to all of them
+    global: bool,
+}
+
+/// Print the whole command tree as JSON, for tools that build their own UI
+/// around keyboard_control. Hidden commands and options are left out.
+pub fn dump() -> Result<()> {
+    serde_json::to_writer_pretty(io::stdout(), &description())?;
+    println!();
+    Ok(())
+}
+
+fn description() -> Description {
+    let mut cmd = crate::Opts::command();
+    // fills in what clap works out when parsing, like positional indices
+    cmd.build();
+
+    Description {
+        schema_version: SCHEMA_VERSION,
+        command: command_info(&cmd),
+    }
+}
+
+fn command_info(cmd: &Command) -> CommandInfo {
+    CommandInfo {
+        name: cmd.get_name().to_owned(),
+        about: cmd.get_about().map(str::to_owned),
+        args: cmd
+            .get_arguments()
+            .filter(|a| !a.is_hide_set())
+            .map(arg_info)
+            .collect(),
+        subcommands: cmd
+            .get_subcommands()
+            .filter(|c| !c.is_hide_set())
+            .map(command_info)
+            .collect(),
+    }
+}
+
+fn arg_info(arg: &Arg) -> ArgInfo {
+    let kind = if arg.is_positional() {
+        ArgKind::Positional
+    } else if arg.is_takes_value_set() {
+        ArgKind::Option
+    } else {
+        ArgKind::Flag
+    };
+
+    ArgInfo {
+        name: arg.get_id().to_owned(),
+        kind,
+        long: arg.get_long().map(str::to_owned),
+        short: arg.get_short(),
+        index: arg.get_index(),
+        help: arg.get_help().map(str::to_owned),
+        value_names: arg
+            .get_value_names()
+            .unwrap_or_default()
+            .iter()
+            .map(|n| n.to_string())
+            .collect(),
+        possible_values: arg
+            .get_possible_values()
+            .unwrap_or_default()
+            .iter()
+            .filter(|v| !v.is_hide_set())
+            .map(|v| v.get_name().to_owned())
+            .collect(),
+        default_values: arg
+            .get_default_values()
+            .iter()
+            .map(|v| v.to_string_lossy().into_owned())
+            .collect(),
+        required: arg.is_required_set(),
+        multiple: arg.is_multiple_occurrences_set() || arg.is_multiple_values_set(),
+        global: arg.is_global_set(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use serde_json::Value;
+
+    use super::*;
+
+    type Shape = BTreeMap<String, BTreeSet<&'static str>>;
+
+    /// Every field under `path` with the JSON types seen there, arrays as
+    /// `[]`. Subcommands are merged into their parent, so the shape doesn't
+    /// depend on how deep the tree goes.
+    fn shape(value: &Value, path: &str, out: &mut Shape) {
+        let kind = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(items) => {
+                for item in items {
+                    shape(item, &format!("{path}[]"), out);
+                }
+                "array"
+            }
+            Value::Object(fields) => {
+                for (name, field) in fields {
+                    match (name.as_str(), field) {
+                        ("subcommands", Value::Array(cmds)) => {
+                            for cmd in cmds {
+                                shape(cmd, path, out);
+                            }
+                            out.entry(format!("{path}.subcommands"))
+                                .or_default()
+                                .insert("array");
+                        }
+                        _ => shape(field, &format!("{path}.{name}"), out),
+                    }
+                }
+                "object"
+            }
+        };
+        out.entry(path.to_owned()).or_default().insert(kind);
+    }
+
+    /// Changing this means changing the layout wrappers read, so bump
+    /// `SCHEMA_VERSION` along with it
+    const SNAPSHOT: &str = "\
+$: object
+$.command: object
+$.command.about: null | string
+$.command.args: array
+$.command.args[]: object
+$.command.args[].default_values: array
+$.command.args[].default_values[]: string
+$.command.args[].global: bool
+$.command.args[].help: null | string
+$.command.args[].index: null | number
+$.command.args[].kind: string
+$.command.args[].long: null | string
+$.command.args[].multiple: bool
+$.command.args[].name: string
+$.command.args[].possible_values: array
+$.command.args[].possible_values[]: string
+$.command.args[].required: bool
+$.command.args[].short: null | string
+$.command.args[].value_names: array
+$.command.args[].value_names[]: string
+$.command.name: string
+$.command.subcommands: array
+$.schema_version: number
+";
+
+    #[test]
+    fn schema_is_stable() {
+        let json = serde_json::to_value(description()).unwrap();
+        let mut out = Shape::new();
+        shape(&json, "$", &mut out);
+
+        let shape = out
+            .iter()
+            .map(|(path, kinds)| {
+                let kinds = kinds.iter().copied().collect::<Vec<_>>();
+                format!("{path}: {}\n", kinds.join(" | "))
+            })
+            .collect::<String>();
+        assert_eq!(shape, SNAPSHOT);
+        assert_eq!(SCHEMA_VERSION, 1);
+    }
+
+    #[test]
+    fn hidden_options_are_left_out() {
+        let json = serde_json::to_string(&description()).unwrap();
+        assert!(json.contains("\"hold-tap\""));
+        assert!(!json.contains("dump_cli_json"));
+    }
+}
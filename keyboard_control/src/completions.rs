@@ -0,0 +1,43 @@
+use std::io::{self, Write};
+
+use clap::CommandFactory;
+use clap_complete::Shell;
+use color_eyre::Result;
+
+/// Print a completion script for a shell, to save wherever the shell loads
+/// completions from
+#[derive(Debug, clap::Parser)]
+pub struct CompletionsOpts {
+    #[clap(arg_enum)]
+    shell: Shell,
+}
+
+impl CompletionsOpts {
+    pub fn execute(self) -> Result<()> {
+        generate(self.shell, &mut io::stdout());
+        Ok(())
+    }
+}
+
+fn generate(shell: Shell, out: &mut dyn Write) {
+    let mut cmd = crate::Opts::command();
+    let name = cmd.get_name().to_owned();
+    clap_complete::generate(shell, &mut cmd, name, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_cover_the_subcommands() {
+        for shell in [Shell::Bash, Shell::Zsh] {
+            let mut script = Vec::new();
+            generate(shell, &mut script);
+            let script = String::from_utf8(script).unwrap();
+
+            assert!(script.contains("keyboard-control"), "{shell}");
+            assert!(script.contains("hold-tap"), "{shell}");
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    ConfigItem, ContrastCurve, DisplayContrast, FrameTransform, HidMode, HostToKeyboard,
+    KeyboardSide, LedColour, LedMode, QuietHours, Rotation, ScanOrder, StatsGraph,
+    CONTRAST_LEVELS, MAX_SCAN_SETTLE_US,
+};
+
+use crate::host_link::HostLink;
+
+/// Change the keyboard's configuration, changes are saved on the keyboard
+#[derive(Debug, clap::Parser)]
+pub struct ConfigOpts {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum ConfigCommand {
+    /// Set a config value, known keys are: display_swap, layer_legend,
+    /// key_grid, left_rotation, right_rotation, led_mode, led_colour,
+    /// hid_mode, left_transform, right_transform, quiet_hours,
+    /// jiggle_max_minutes, scan_order, scan_settle_us, display_contrast,
+    /// status_led, cross_side_chords, stats_graph. Changing hid_mode resets
+    /// the keyboard.
+    ///
+    /// led_colour is the hue, saturation and brightness of the solid mode as
+    /// h,s,v from 0 to 255, breathing uses its hue and saturation.
+    ///
+    /// Transforms are a comma separated list of invert, flip_x and flip_y,
+    /// or none.
+    ///
+    /// quiet_hours is a local time range like 22:00-07:00, or off. It only
+    /// applies once the keyboard has been told the time with set-time.
+    ///
+    /// scan_order is fixed, rotate or random, and scan_settle_us is how long
+    /// to wait after driving each matrix row, up to 50. Both can help with
+    /// phantom presses on long cables, which show up in stats.
+    ///
+    /// display_contrast is auto, which dims the displays the more of the
+    /// screen is lit, or a fixed level from 0 (dimmest) to 4. auto can take
+    /// the four lit percentages it drops a level at, like auto:10,25,45,70.
+    ///
+    /// status_led is the index of an LED on the left half to show the link
+    /// and USB state on instead of the effects, or off. Green is connected,
+    /// yellow a struggling link, blinking red no link and blue while LEDs are
+    /// being set from here.
+    ///
+    /// cross_side_chords lets chords take keys from both halves, by chording
+    /// on the left half instead of each half chording its own keys. It adds
+    /// a little latency to the right half's keys, so it's off by default.
+    ///
+    /// stats_graph is what the graph under the stats screen shows: cps for
+    /// presses over the last three seconds, or history for presses every
+    /// six seconds over the last three minutes.
+    Set {
+        key: String,
+        value: String,
+
+        port: Option<String>,
+    },
+}
+
+impl ConfigOpts {
+    pub async fn execute(self) -> Result<()> {
+        match self.command {
+            ConfigCommand::Set { key, value, port } => {
+                let item = parse_item(&key, &value)?;
+                let mut link = HostLink::open(port.as_deref())?;
+                link.send(HostToKeyboard::SetConfig(item)).await?;
+                println!("Set {} to {}", key, value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_item(key: &str, value: &str) -> Result<ConfigItem> {
+    match key {
+        "display_swap" => Ok(ConfigItem::DisplaySwap(value.parse()?)),
+        "layer_legend" => Ok(ConfigItem::LayerLegend(value.parse()?)),
+        "key_grid" => Ok(ConfigItem::KeyGrid(value.parse()?)),
+        "left_rotation" => Ok(ConfigItem::DisplayRotation {
+            side: KeyboardSide::Left,
+            rotation: parse_rotation(value)?,
+        }),
+        "right_rotation" => Ok(ConfigItem::DisplayRotation {
+            side: KeyboardSide::Right,
+            rotation: parse_rotation(value)?,
+        }),
+        "led_mode" => Ok(ConfigItem::LedMode(parse_led_mode(value)?)),
+        "led_colour" => Ok(ConfigItem::LedColour(parse_led_colour(value)?)),
+        "hid_mode" => Ok(ConfigItem::HidMode(parse_hid_mode(value)?)),
+        "left_transform" => Ok(ConfigItem::FrameTransform {
+            side: KeyboardSide::Left,
+            transform: parse_transform(value)?,
+        }),
+        "right_transform" => Ok(ConfigItem::FrameTransform {
+            side: KeyboardSide::Right,
+            transform: parse_transform(value)?,
+        }),
+        "quiet_hours" => Ok(ConfigItem::QuietHours(parse_quiet_hours(value)?)),
+        "jiggle_max_minutes" => Ok(ConfigItem::JiggleMaxMinutes(value.parse()?)),
+        "scan_order" => Ok(ConfigItem::ScanOrder(parse_scan_order(value)?)),
+        "scan_settle_us" => Ok(ConfigItem::ScanSettleUs(parse_settle_us(value)?)),
+        "display_contrast" => Ok(ConfigItem::DisplayContrast(parse_contrast(value)?)),
+        "status_led" => Ok(ConfigItem::StatusLed(parse_status_led(value)?)),
+        "cross_side_chords" => Ok(ConfigItem::CrossSideChords(value.parse()?)),
+        "stats_graph" => Ok(ConfigItem::StatsGraph(parse_stats_graph(value)?)),
+        _ => Err(eyre!("Unknown config key: {}", key)),
+    }
+}
+
+fn parse_scan_order(value: &str) -> Result<ScanOrder> {
+    match value {
+        "fixed" => Ok(ScanOrder::Fixed),
+        "rotate" => Ok(ScanOrder::Rotate),
+        "random" => Ok(ScanOrder::Random),
+        _ => Err(eyre!(
+            "Unknown scan order {}, try fixed, rotate or random",
+            value
+        )),
+    }
+}
+
+fn parse_stats_graph(value: &str) -> Result<StatsGraph> {
+    match value {
+        "cps" => Ok(StatsGraph::Cps),
+        "history" => Ok(StatsGraph::History),
+        _ => Err(eyre!("Unknown stats graph {}, try cps or history", value)),
+    }
+}
+
+fn parse_settle_us(value: &str) -> Result<u8> {
+    let us = value.parse()?;
+    if us > MAX_SCAN_SETTLE_US {
+        return Err(eyre!(
+            "The settle delay can be at most {}us",
+            MAX_SCAN_SETTLE_US
+        ));
+    }
+    Ok(us)
+}
+
+fn parse_contrast(value: &str) -> Result<DisplayContrast> {
+    if value == "auto" {
+        return Ok(DisplayContrast::Auto(ContrastCurve::DEFAULT));
+    }
+
+    if let Some(thresholds) = value.strip_prefix("auto:") {
+        let thresholds = thresholds
+            .split(',')
+            .map(|t| t.trim().parse())
+            .collect::<Result<Vec<u8>, _>>()?;
+        let curve = ContrastCurve {
+            thresholds: thresholds.try_into().map_err(|_| {
+                eyre!(
+                    "The contrast curve needs {} percentages",
+                    CONTRAST_LEVELS - 1
+                )
+            })?,
+        };
+        if !curve.is_valid() {
+            return Err(eyre!(
+                "The contrast curve's percentages must go up and be at most 100"
+            ));
+        }
+        return Ok(DisplayContrast::Auto(curve));
+    }
+
+    let level = value.parse()?;
+    if level >= CONTRAST_LEVELS {
+        return Err(eyre!(
+            "The contrast level can be at most {}",
+            CONTRAST_LEVELS - 1
+        ));
+    }
+    Ok(DisplayContrast::Manual(level))
+}
+
+fn parse_rotation(value: &str) -> Result<Rotation> {
+    match value {
+        "0" => Ok(Rotation::Rotate0),
+        "180" => Ok(Rotation::Rotate180),
+        _ => Err(eyre!("Rotation must be 0 or 180, not {}", value)),
+    }
+}
+
+fn parse_transform(value: &str) -> Result<FrameTransform> {
+    let mut transform = FrameTransform::NONE;
+    if value == "none" {
+        return Ok(transform);
+    }
+
+    for flag in value.split(',') {
+        match flag.trim() {
+            "invert" => transform.invert = true,
+            "flip_x" => transform.flip_x = true,
+            "flip_y" => transform.flip_y = true,
+            _ => {
+                return Err(eyre!(
+                    "Unknown transform {}, try invert, flip_x, flip_y or none",
+                    flag
+                ))
+            }
+        }
+    }
+
+    Ok(transform)
+}
+
+fn parse_quiet_hours(value: &str) -> Result<QuietHours> {
+    if value == "off" {
+        return Ok(QuietHours::OFF);
+    }
+
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| eyre!("Quiet hours look like 22:00-07:00 or off, not {}", value))?;
+
+    Ok(QuietHours {
+        enabled: true,
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    })
+}
+
+/// Minutes since midnight from HH:MM
+fn parse_time(value: &str) -> Result<u16> {
+    let (hours, minutes) = value
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| eyre!("Times look like 07:30, not {}", value))?;
+    let (hours, minutes) = (hours.parse::<u16>()?, minutes.parse::<u16>()?);
+
+    if hours >= 24 || minutes >= 60 {
+        return Err(eyre!("{} isn't a time of day", value));
+    }
+
+    Ok(hours * 60 + minutes)
+}
+
+fn parse_hid_mode(value: &str) -> Result<HidMode> {
+    match value {
+        "nkro" => Ok(HidMode::Nkro),
+        "6kro" => Ok(HidMode::SixKro),
+        _ => Err(eyre!("HID mode must be nkro or 6kro, not {}", value)),
+    }
+}
+
+pub(crate) fn parse_led_mode(value: &str) -> Result<LedMode> {
+    Ok(match value {
+        "rainbow_waves" => LedMode::RainbowWaves,
+        "rainbow" => LedMode::Rainbow,
+        "solid" => LedMode::Solid,
+        "breathing" => LedMode::Breathing,
+        "reactive" => LedMode::Reactive,
+        "heatmap" => LedMode::Heatmap,
+        "flash" => LedMode::Flash,
+        "tap_fade" => LedMode::TapFade,
+        "off" => LedMode::Off,
+        _ => {
+            return Err(eyre!(
+                "Unknown LED mode {}, try rainbow_waves, rainbow, solid, breathing, reactive, heatmap, flash, tap_fade or off",
+                value
+            ))
+        }
+    })
+}
+
+fn parse_status_led(value: &str) -> Result<Option<u8>> {
+    if value == "off" {
+        return Ok(None);
+    }
+
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| eyre!("Status LED should be an LED index or off, not {}: {}", value, e))
+}
+
+fn parse_led_colour(value: &str) -> Result<LedColour> {
+    let parts = value
+        .split(',')
+        .map(|p| p.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| eyre!("Bad LED colour {}: {}", value, e))?;
+
+    match parts[..] {
+        [h, s, v] => Ok(LedColour { h, s, v }),
+        _ => Err(eyre!("LED colour should be h,s,v, not {}", value)),
+    }
+}
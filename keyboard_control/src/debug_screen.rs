@@ -0,0 +1,25 @@
+use color_eyre::Result;
+use keyboard_shared::HostToKeyboard;
+
+use crate::host_link::HostLink;
+
+/// Toggle the debug screen on the right hand display
+#[derive(Debug, clap::Parser)]
+pub struct DebugScreenOpts {
+    /// Hide the debug screen instead of showing it
+    #[clap(long)]
+    off: bool,
+
+    port: Option<String>,
+}
+
+impl DebugScreenOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        link.send(HostToKeyboard::ShowDebugScreen(!self.off))
+            .await?;
+
+        Ok(())
+    }
+}
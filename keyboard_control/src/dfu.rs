@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardSide, KeyboardToHost};
+
+use crate::host_link::HostLink;
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+enum Side {
+    Left,
+    Right,
+    Both,
+}
+
+/// Restart halves into their UF2 bootloader, so new firmware can be copied
+/// over without double tapping reset
+#[derive(Debug, clap::Parser)]
+pub struct DfuOpts {
+    #[clap(long, arg_enum, default_value = "both")]
+    side: Side,
+
+    port: Option<String>,
+}
+
+impl DfuOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        // the right half is reached over the left's link, so it has to go
+        // first
+        let sides: &[KeyboardSide] = match self.side {
+            Side::Left => &[KeyboardSide::Left],
+            Side::Right => &[KeyboardSide::Right],
+            Side::Both => &[KeyboardSide::Right, KeyboardSide::Left],
+        };
+
+        for &side in sides {
+            link.send(HostToKeyboard::EnterBootloader { side }).await?;
+            wait_for(&mut link, side).await?;
+            println!("{:?} half is restarting into its bootloader", side);
+        }
+
+        Ok(())
+    }
+}
+
+async fn wait_for(link: &mut HostLink, side: KeyboardSide) -> Result<()> {
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(2))
+            .await?
+            .ok_or_else(|| match side {
+                KeyboardSide::Left => eyre!("Timed out waiting for the left half to reply"),
+                KeyboardSide::Right => eyre!(
+                    "Timed out waiting for the right half, is the link between the halves up?"
+                ),
+            })?;
+
+        if msg == (KeyboardToHost::EnteringBootloader { side }) {
+            return Ok(());
+        }
+    }
+}
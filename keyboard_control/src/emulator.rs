@@ -0,0 +1,519 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    CmdOrAck, Command, DisplayBusStats, DropChannel, EventSource, FrameCheck, FrameTransform,
+    HostToKeyboard, KeyboardSide, KeyboardToHost, LinkErrorKind, Rotation, SelfTestResults,
+    SettingsBlob, SettingsExporter, SettingsImportStatus, SettingsImporter, StatusReport, Tuning,
+    UsageEntry, UsageKind, KEY_COLS, KEY_ROWS, LATENCY_BUCKETS, SETTINGS_CHUNK, USAGE_CHUNK,
+};
+use postcard::CobsAccumulator;
+
+use crate::{heatmap_render::KeyCounts, pty::Pty};
+
+/// Size of each display, as reported in `DisplayInfo`
+const WIDTH: usize = 32;
+const HEIGHT: usize = 128;
+
+/// Pretend to be a keyboard on a pseudo terminal, so the other commands can
+/// be tried out without one. The host protocol, key press counts, settings
+/// blob and displays are emulated; the layout and the link between the
+/// halves aren't.
+#[derive(Debug, clap::Parser)]
+pub struct EmulatorOpts {
+    /// Don't draw the displays in the terminal, only log what the host does
+    #[clap(long)]
+    headless: bool,
+
+    /// Say this is the right half's port when asked, run one with and one
+    /// without to stand in for a keyboard with a port on each half
+    #[clap(long)]
+    right: bool,
+}
+
+impl EmulatorOpts {
+    pub async fn execute(self) -> Result<()> {
+        let pty = Pty::open()?;
+        println!("Emulating a keyboard on {}", pty.path());
+
+        let side = if self.right {
+            KeyboardSide::Right
+        } else {
+            KeyboardSide::Left
+        };
+        serve(&pty, Emulated::new(side), !self.headless).await
+    }
+}
+
+/// An emulated keyboard on its own pty for tests, returning where to open it
+#[cfg(test)]
+pub fn spawn(side: KeyboardSide) -> Result<String> {
+    let pty = Pty::open()?;
+    let path = pty.path().to_owned();
+    tokio::spawn(async move { serve(&pty, Emulated::new(side), false).await });
+    Ok(path)
+}
+
+/// As [`spawn`], along with how many frames each display has shown
+#[cfg(test)]
+pub fn spawn_counting_flushes(side: KeyboardSide) -> Result<(String, Arc<[AtomicU32; 2]>)> {
+    let pty = Pty::open()?;
+    let path = pty.path().to_owned();
+    let keyboard = Emulated::new(side);
+    let flushes = keyboard.flushes.clone();
+    tokio::spawn(async move { serve(&pty, keyboard, false).await });
+    Ok((path, flushes))
+}
+
+async fn serve(pty: &Pty, mut keyboard: Emulated, draw: bool) -> Result<()> {
+    let mut accumulator = CobsAccumulator::<256>::new();
+    let mut buf = [0u8; 256];
+
+    loop {
+        let len = pty.read(&mut buf).await?;
+        let mut window = &buf[..len];
+
+        while !window.is_empty() {
+            window = match accumulator.feed(window) {
+                postcard::FeedResult::Consumed => break,
+                postcard::FeedResult::OverFull(rest) => rest,
+                postcard::FeedResult::DeserError(rest) => {
+                    tracing::warn!("dropped a frame that didn't decode");
+                    rest
+                }
+                postcard::FeedResult::Success { data, remaining } => {
+                    let data: CmdOrAck<HostToKeyboard> = data;
+
+                    // the pty doesn't lose anything, so the keyboard's
+                    // acks don't need checking
+                    if let CmdOrAck::Cmd(c) = data {
+                        if c.validate() {
+                            send(pty, &CmdOrAck::<KeyboardToHost>::Ack(c.ack())).await?;
+
+                            for reply in keyboard.handle(c.cmd) {
+                                send(pty, &CmdOrAck::Cmd(Command::new(reply))).await?;
+                            }
+                        }
+                    }
+
+                    remaining
+                }
+            };
+        }
+
+        if draw && keyboard.redraw {
+            keyboard.redraw = false;
+            keyboard.draw(pty.path())?;
+        }
+    }
+}
+
+async fn send(pty: &Pty, msg: &CmdOrAck<KeyboardToHost>) -> Result<()> {
+    let buf = postcard::to_allocvec_cobs(msg).map_err(|e| eyre!("Serde error: {}", e))?;
+    pty.write_all(&buf).await?;
+    Ok(())
+}
+
+type Frame = [[u8; 4]; HEIGHT];
+
+/// A display, host pixels are staged until they're flushed
+struct Display {
+    shown: Frame,
+    staged: Frame,
+    /// The frame started with `BeginFrame`
+    frame: Option<FrameCheck>,
+}
+
+impl Display {
+    fn new() -> Self {
+        Self {
+            shown: [[0; 4]; HEIGHT],
+            staged: [[0; 4]; HEIGHT],
+            frame: None,
+        }
+    }
+
+    fn lit(&self, x: usize, y: usize) -> bool {
+        self.shown[y][x / 8] & (1 << (x % 8)) != 0
+    }
+}
+
+struct Emulated {
+    /// Which half's port this is
+    side: KeyboardSide,
+    keypresses: u32,
+    key_presses: KeyCounts,
+    locked: bool,
+    displays: [Display; 2],
+    /// Frames shown on each display
+    flushes: Arc<[AtomicU32; 2]>,
+    /// The host sends `FlushDisplay` or frames, so the last rows don't flush
+    flush_markers: bool,
+    rejected_pixel_writes: u32,
+    settings: SettingsBlob,
+    importer: SettingsImporter,
+    exporter: SettingsExporter,
+    tuning: Tuning,
+    saved_tuning: Tuning,
+    /// The emulated layout never leaves the base layer
+    started: Instant,
+    redraw: bool,
+}
+
+impl Emulated {
+    fn new(side: KeyboardSide) -> Self {
+        Self {
+            side,
+            keypresses: 0,
+            key_presses: [[0; KEY_COLS]; KEY_ROWS],
+            locked: false,
+            displays: [Display::new(), Display::new()],
+            flushes: Arc::new([AtomicU32::new(0), AtomicU32::new(0)]),
+            flush_markers: false,
+            rejected_pixel_writes: 0,
+            settings: SettingsBlob::new(),
+            importer: SettingsImporter::new(),
+            exporter: SettingsExporter::new(),
+            tuning: Tuning::DEFAULT,
+            saved_tuning: Tuning::DEFAULT,
+            started: Instant::now(),
+            redraw: true,
+        }
+    }
+
+    /// Injected presses of the keys on `side`'s half of the layout
+    fn side_keypresses(&self, side: KeyboardSide) -> u32 {
+        let half = match side {
+            KeyboardSide::Left => 0..KEY_COLS / 2,
+            KeyboardSide::Right => KEY_COLS / 2..KEY_COLS,
+        };
+        self.key_presses
+            .iter()
+            .map(|row| row[half.clone()].iter().sum::<u32>())
+            .sum()
+    }
+
+    fn handle(&mut self, cmd: HostToKeyboard) -> Vec<KeyboardToHost> {
+        tracing::debug!(?cmd, "from the host");
+
+        match cmd {
+            HostToKeyboard::KeepAlive => vec![],
+            HostToKeyboard::RequestStats => {
+                let self_test = Some(SelfTestResults {
+                    oled: true,
+                    link: true,
+                    leds: true,
+                    matrix: true,
+                });
+
+                vec![
+                    KeyboardToHost::Stats {
+                        keypresses: self.keypresses,
+                    },
+                    KeyboardToHost::DebugStats {
+                        cpu_busy_pct: 0,
+                        presses_by_source: {
+                            let mut by_source = [0; EventSource::COUNT];
+                            by_source[EventSource::Synthetic as usize] = self.keypresses;
+                            by_source
+                        },
+                        corrupt_frames: 0,
+                        retransmits: 0,
+                        failed_sends: 0,
+                        channel_drops: [0; DropChannel::COUNT],
+                        settings_erases: 0,
+                        rejected_pixel_writes: self.rejected_pixel_writes,
+                        firmware_mismatch: false,
+                        command_queue_high_water: 0,
+                        ack_queue_high_water: 0,
+                        presence_mode: false,
+                        link_read_errors: 0,
+                        link_errors: [0; LinkErrorKind::COUNT],
+                        link_resyncs: 0,
+                        link_up: true,
+                        link_losses: 0,
+                        display_bus: DisplayBusStats::NONE,
+                        phantom_presses: [0; 2],
+                        safe_mode: None,
+                        heap_high_water: 0,
+                        oom_averted: 0,
+                    },
+                    KeyboardToHost::SelfTest {
+                        side: KeyboardSide::Left,
+                        results: self_test,
+                    },
+                    KeyboardToHost::SelfTest {
+                        side: KeyboardSide::Right,
+                        results: self_test,
+                    },
+                    KeyboardToHost::StatsV2 {
+                        keypresses: self.keypresses,
+                        left_keypresses: self.side_keypresses(KeyboardSide::Left),
+                        right_keypresses: self.side_keypresses(KeyboardSide::Right),
+                        uptime_secs: self.started.elapsed().as_secs() as u32,
+                        cps_hundredths: 0,
+                    },
+                ]
+            }
+            HostToKeyboard::RequestStatus => {
+                let flags = if self.locked { StatusReport::LOCKED } else { 0 };
+                vec![KeyboardToHost::Status(StatusReport { layer: 0, flags })]
+            }
+            HostToKeyboard::RequestLatency => [false, true]
+                .into_iter()
+                .map(|synthetic| KeyboardToHost::Latency {
+                    synthetic,
+                    buckets: [0; LATENCY_BUCKETS],
+                })
+                .chain([KeyboardToHost::LightLatency {
+                    buckets: [0; LATENCY_BUCKETS],
+                }])
+                .collect(),
+            HostToKeyboard::RequestKeyPresses => self
+                .key_presses
+                .iter()
+                .enumerate()
+                .map(|(row, counts)| KeyboardToHost::KeyPresses {
+                    row: row as u8,
+                    counts: *counts,
+                })
+                .collect(),
+            HostToKeyboard::RequestDisplayInfo { side } => vec![KeyboardToHost::DisplayInfo {
+                side,
+                width: WIDTH as u8,
+                height: HEIGHT as u8,
+                rotation: Rotation::Rotate0,
+                transform: FrameTransform::NONE,
+            }],
+            HostToKeyboard::Identify => vec![KeyboardToHost::Identity { side: self.side }],
+            HostToKeyboard::Lock(locked) => {
+                self.locked = locked;
+                self.redraw = true;
+                vec![]
+            }
+            HostToKeyboard::InjectKey { row, col, .. }
+            | HostToKeyboard::InjectRemoteKey { row, col, .. } => {
+                self.keypresses += 1;
+                if let Some(count) = self
+                    .key_presses
+                    .get_mut(row as usize)
+                    .and_then(|r| r.get_mut(col as usize))
+                {
+                    *count += 1;
+                }
+                self.redraw = true;
+                vec![]
+            }
+            HostToKeyboard::WritePixels {
+                side,
+                row,
+                data_0,
+                data_1,
+            } => self.write_pixels(side, row, Some([data_0, data_1])),
+            HostToKeyboard::WritePackedPixels { side, row, data } => {
+                self.write_pixels(side, row, data.unpack())
+            }
+            HostToKeyboard::FlushDisplay { side } => {
+                self.flush_markers = true;
+                self.flush(side);
+                vec![]
+            }
+            HostToKeyboard::BeginFrame { side, frame_id } => {
+                self.flush_markers = true;
+                let display = &mut self.displays[side as usize];
+                if display.frame.replace(FrameCheck::new(frame_id)).is_some() {
+                    display.staged = display.shown;
+                }
+                vec![]
+            }
+            HostToKeyboard::EndFrame {
+                side,
+                frame_id,
+                crc,
+            } => {
+                let display = &mut self.displays[side as usize];
+                let intact = matches!(
+                    display.frame.take(),
+                    Some(frame) if frame.intact(frame_id, crc)
+                );
+
+                if intact {
+                    self.flush(side);
+                    vec![]
+                } else {
+                    display.staged = display.shown;
+                    vec![KeyboardToHost::FrameNack { side, frame_id }]
+                }
+            }
+            HostToKeyboard::ReadPixels { side, row } => {
+                let shown = &self.displays[side as usize].shown;
+                match shown.get(row as usize..row as usize + 2) {
+                    Some(rows) => vec![KeyboardToHost::PixelRow {
+                        row,
+                        data_0: rows[0],
+                        data_1: rows[1],
+                    }],
+                    None => vec![],
+                }
+            }
+            HostToKeyboard::ExportSettings { offset } => {
+                vec![self.exporter.chunk(offset, || self.settings.clone())]
+            }
+            HostToKeyboard::ImportSettings {
+                offset,
+                total,
+                len,
+                data,
+            } => {
+                let data = &data[..(len as usize).min(SETTINGS_CHUNK)];
+                // the blob is the firmware's to decode, only its checksum is
+                // checked
+                let status = match self.importer.chunk(offset, total, data) {
+                    Ok(Some(blob)) => {
+                        self.settings = blob;
+                        SettingsImportStatus::Applied
+                    }
+                    Ok(None) => SettingsImportStatus::Continue,
+                    Err(status) => status,
+                };
+                vec![KeyboardToHost::SettingsImport { offset, status }]
+            }
+            HostToKeyboard::SetTuning {
+                chord_window_ms,
+                hold_tap_timeout_ms,
+            } => {
+                let tuning = Tuning {
+                    chord_window_ms: chord_window_ms.unwrap_or(self.tuning.chord_window_ms),
+                    hold_tap_timeout_ms: hold_tap_timeout_ms
+                        .unwrap_or(self.tuning.hold_tap_timeout_ms),
+                };
+                if !tuning.valid() {
+                    return vec![KeyboardToHost::TuningRejected];
+                }
+                self.tuning = tuning;
+                vec![self.tuning_reply()]
+            }
+            HostToKeyboard::CommitTuning => {
+                self.saved_tuning = self.tuning;
+                vec![self.tuning_reply()]
+            }
+            HostToKeyboard::RevertTuning => {
+                self.tuning = self.saved_tuning;
+                vec![self.tuning_reply()]
+            }
+            HostToKeyboard::RequestTuning => vec![self.tuning_reply()],
+            HostToKeyboard::RequestUsageStats => {
+                let mut entries = [UsageEntry::EMPTY; USAGE_CHUNK];
+                entries[0] = UsageEntry {
+                    kind: UsageKind::Layer,
+                    index: 0,
+                    count: 0,
+                    millis: self.started.elapsed().as_millis() as u32,
+                };
+                vec![KeyboardToHost::Usage {
+                    offset: 0,
+                    total: 1,
+                    len: 1,
+                    entries,
+                }]
+            }
+            // there's no bootloader to go to, so it carries on
+            HostToKeyboard::EnterBootloader { side } => {
+                vec![KeyboardToHost::EnteringBootloader { side }]
+            }
+            cmd => {
+                tracing::info!(?cmd, "not emulated");
+                vec![]
+            }
+        }
+    }
+
+    fn tuning_reply(&self) -> KeyboardToHost {
+        KeyboardToHost::Tuning {
+            tuning: self.tuning,
+            saved: self.tuning == self.saved_tuning,
+        }
+    }
+
+    fn write_pixels(
+        &mut self,
+        side: KeyboardSide,
+        row: u8,
+        rows: Option<[[u8; 4]; 2]>,
+    ) -> Vec<KeyboardToHost> {
+        let display = &mut self.displays[side as usize];
+        match (rows, display.staged.get_mut(row as usize..row as usize + 2)) {
+            (Some(rows), Some(dest)) => {
+                dest.copy_from_slice(&rows);
+                if let Some(frame) = &mut display.frame {
+                    frame.add_rows(row, rows);
+                }
+            }
+            _ => {
+                self.rejected_pixel_writes += 1;
+                return vec![KeyboardToHost::PixelsRejected { side, row }];
+            }
+        }
+
+        // older hosts end each frame with the last rows instead
+        if !self.flush_markers && row as usize == HEIGHT - 2 {
+            self.flush(side);
+        }
+
+        vec![]
+    }
+
+    fn flush(&mut self, side: KeyboardSide) {
+        let display = &mut self.displays[side as usize];
+        display.shown = display.staged;
+        self.flushes[side as usize].fetch_add(1, Ordering::Relaxed);
+        self.redraw = true;
+    }
+
+    /// Draw both displays side by side, two pixel rows to a line
+    fn draw(&self, path: &str) -> Result<()> {
+        let mut out = String::from("\x1b[H\x1b[2J");
+
+        for y in (0..HEIGHT).step_by(2) {
+            for (idx, display) in self.displays.iter().enumerate() {
+                if idx > 0 {
+                    out.push_str("  ");
+                }
+
+                for x in 0..WIDTH {
+                    out.push(match (display.lit(x, y), display.lit(x, y + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    });
+                }
+            }
+            out.push('\n');
+        }
+
+        writeln!(
+            out,
+            "{}  {} presses  {}  {}/{} frames",
+            path,
+            self.keypresses,
+            if self.locked { "locked" } else { "unlocked" },
+            self.flushes[0].load(Ordering::Relaxed),
+            self.flushes[1].load(Ordering::Relaxed),
+        )?;
+
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
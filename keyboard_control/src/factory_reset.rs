@@ -0,0 +1,33 @@
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::HostToKeyboard;
+
+use crate::host_link::HostLink;
+
+/// Put the keyboard's saved settings back to the defaults and restart it,
+/// which also gets it out of safe mode. Export the settings first to keep a
+/// copy.
+#[derive(Debug, clap::Parser)]
+pub struct FactoryResetOpts {
+    /// Confirm that the saved settings should be lost
+    #[clap(long)]
+    yes: bool,
+
+    port: Option<String>,
+}
+
+impl FactoryResetOpts {
+    pub async fn execute(self) -> Result<()> {
+        if !self.yes {
+            return Err(eyre!(
+                "This loses the keyboard's saved settings, pass --yes to go ahead"
+            ));
+        }
+
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        link.send(HostToKeyboard::FactoryReset).await?;
+        println!("Settings reset, the keyboard is restarting");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardToHost};
+
+use crate::host_link::HostLink;
+
+/// Have the left half allocate a block of its heap and free it again, to
+/// check an allocation too big for the heap is turned down rather than
+/// resetting the keyboard. Turned down allocations show up in `stats`.
+#[derive(Debug, clap::Parser)]
+pub struct ForceAllocOpts {
+    /// Bytes to allocate, the heap is 8192 bytes
+    bytes: u16,
+
+    port: Option<String>,
+}
+
+impl ForceAllocOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        link.send(HostToKeyboard::ForceAllocation { bytes: self.bytes })
+            .await?;
+
+        loop {
+            let msg = link
+                .recv_timeout(Duration::from_secs(2))
+                .await?
+                .ok_or_else(|| eyre!("Timed out waiting for the keyboard to reply"))?;
+
+            if let KeyboardToHost::ForcedAllocation { bytes, allocated } = msg {
+                if allocated {
+                    println!("Allocated and freed {} bytes", bytes);
+                } else {
+                    println!("Turned down {} bytes, the keyboard is still running", bytes);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{path::PathBuf, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardSide, KeyboardToHost, KEY_COLS, KEY_ROWS};
+
+use crate::{
+    heatmap_render::{self, KeyCounts},
+    host_link::HostLink,
+    render::{check_displays, emit_image, Stream},
+    util::open_keyboard,
+};
+
+/// How often the map on the displays is redrawn with fresh counts, the
+/// keyboard goes back to its own display a second after the last one
+const PUSH_PERIOD: Duration = Duration::from_millis(500);
+
+/// Show how many times each key has been pressed since the keyboard
+/// powered on
+#[derive(Debug, clap::Parser)]
+pub struct HeatmapOpts {
+    /// Draw the counts over the physical layout to this PNG
+    #[clap(long, parse(from_os_str))]
+    render: Option<PathBuf>,
+
+    /// Keep a black and white version on the keyboard's displays, until
+    /// interrupted
+    #[clap(long)]
+    push: bool,
+
+    port: Option<String>,
+}
+
+impl HeatmapOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut conn = open_keyboard(self.port.as_deref()).await?;
+        let counts = request_counts(conn.link_for(KeyboardSide::Left)).await?;
+
+        match &self.render {
+            Some(path) => heatmap_render::render(&counts).save(path)?,
+            None if !self.push => print_counts(&counts),
+            None => {}
+        }
+
+        if !self.push {
+            return Ok(());
+        }
+
+        check_displays(&mut conn).await?;
+
+        let mut stream = Stream::default();
+        let mut counts = counts;
+        loop {
+            let image = heatmap_render::for_displays(&heatmap_render::render(&counts));
+            emit_image(&image, false, &mut conn, &mut stream).await?;
+
+            tokio::time::sleep(PUSH_PERIOD).await;
+            counts = request_counts(conn.link_for(KeyboardSide::Left)).await?;
+        }
+    }
+}
+
+async fn request_counts(link: &mut HostLink) -> Result<KeyCounts> {
+    link.send(HostToKeyboard::RequestKeyPresses).await?;
+
+    let mut rows = [[0; KEY_COLS]; KEY_ROWS];
+    let mut received = 0;
+    while received < KEY_ROWS {
+        let msg = link
+            .recv_timeout(Duration::from_secs(2))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for key presses"))?;
+
+        if let KeyboardToHost::KeyPresses { row, counts } = msg {
+            if let Some(slot) = rows.get_mut(row as usize) {
+                *slot = counts;
+                received += 1;
+            }
+        }
+    }
+
+    Ok(rows)
+}
+
+fn print_counts(counts: &KeyCounts) {
+    let present = heatmap_render::keys().collect::<Vec<_>>();
+
+    for (row, row_counts) in counts.iter().enumerate() {
+        let line = row_counts
+            .iter()
+            .enumerate()
+            .map(|(col, count)| {
+                let cell = if present.contains(&(row, col)) {
+                    count.to_string()
+                } else {
+                    String::new()
+                };
+                let split = if col == KEY_COLS / 2 { "   " } else { "" };
+                format!("{}{:>7}", split, cell)
+            })
+            .collect::<String>();
+        println!("{}", line.trim_end());
+    }
+}
@@ -0,0 +1,155 @@
+//! Drawing key press counts over the physical layout of the keyboard.
+
+use image::{
+    imageops::{dither, grayscale, overlay, resize, rotate90, BiLevel, FilterType},
+    GrayImage, Rgb, RgbImage,
+};
+use keyboard_shared::{to_global, KeyboardSide, KEY_COLS, KEY_ROWS, SWITCH_LED_POSITIONS};
+
+/// Presses of each key, by row and column of the full matrix
+pub type KeyCounts = [[u32; KEY_COLS]; KEY_ROWS];
+
+/// Side length of a key, in pixels
+const KEY: u32 = 48;
+/// Space between neighbouring keys
+const GAP: u32 = 6;
+/// Space between the halves
+const SPLIT: u32 = 48;
+/// Space around the outside
+const MARGIN: u32 = 12;
+
+const BACKGROUND: Rgb<u8> = Rgb([24, 24, 24]);
+
+/// Colours from no presses up to the most pressed key, evenly spaced. Their
+/// brightness only goes up, so the ramp still reads once it's grey.
+const RAMP: [[u8; 3]; 5] = [
+    [16, 16, 64],
+    [40, 80, 200],
+    [220, 40, 40],
+    [250, 210, 60],
+    [255, 255, 255],
+];
+
+/// The keys that exist, as `(row, col)` of the full matrix. Both halves have
+/// a switch under each switch LED.
+pub fn keys() -> impl Iterator<Item = (usize, usize)> {
+    SWITCH_LED_POSITIONS.iter().flat_map(|&(row, col)| {
+        [KeyboardSide::Left, KeyboardSide::Right].map(|side| {
+            let (row, col) = to_global(side, row, col);
+            (row as usize, col as usize)
+        })
+    })
+}
+
+/// Where the top left corner of a key is drawn
+fn key_origin(row: usize, col: usize) -> (u32, u32) {
+    let split = if col >= KEY_COLS / 2 { SPLIT } else { 0 };
+    (
+        MARGIN + col as u32 * (KEY + GAP) + split,
+        MARGIN + row as u32 * (KEY + GAP),
+    )
+}
+
+fn ramp(t: f32) -> Rgb<u8> {
+    let t = t.clamp(0.0, 1.0) * (RAMP.len() - 1) as f32;
+    let idx = (t as usize).min(RAMP.len() - 2);
+    let frac = t - idx as f32;
+
+    let (a, b) = (RAMP[idx], RAMP[idx + 1]);
+    Rgb(std::array::from_fn(|c| {
+        (a[c] as f32 + (b[c] as f32 - a[c] as f32) * frac).round() as u8
+    }))
+}
+
+/// The heatmap, with each key coloured by its presses relative to the most
+/// pressed key. The square root spreads out the rarely pressed keys, which
+/// would otherwise all look the same.
+pub fn render(counts: &KeyCounts) -> RgbImage {
+    let width = 2 * MARGIN + KEY_COLS as u32 * (KEY + GAP) - GAP + SPLIT;
+    let height = 2 * MARGIN + KEY_ROWS as u32 * (KEY + GAP) - GAP;
+    let mut image = RgbImage::from_pixel(width, height, BACKGROUND);
+
+    let max = keys().map(|(r, c)| counts[r][c]).max().unwrap_or(0).max(1);
+
+    for (row, col) in keys() {
+        let colour = ramp((counts[row][col] as f32 / max as f32).sqrt());
+        let (x, y) = key_origin(row, col);
+
+        for dy in 0..KEY {
+            for dx in 0..KEY {
+                image.put_pixel(x + dx, y + dy, colour);
+            }
+        }
+    }
+
+    image
+}
+
+/// A black and white version for the displays, 64x128 with the left half's
+/// display on the left. Each half is turned on its side to fit its display.
+pub fn for_displays(heatmap: &RgbImage) -> GrayImage {
+    let half_width = heatmap.width() / 2;
+    let mut out = GrayImage::new(64, 128);
+
+    for (idx, x) in [0, half_width].into_iter().enumerate() {
+        let half = image::imageops::crop_imm(heatmap, x, 0, half_width, heatmap.height());
+        let half = grayscale(&rotate90(&half.to_image()));
+        let half = resize(&half, 32, 128, FilterType::Triangle);
+        overlay(&mut out, &half, idx as i64 * 32, 0);
+    }
+
+    dither(&mut out, &BiLevel);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+
+    use super::*;
+
+    /// A different press count for most keys, with some never pressed
+    fn counts() -> KeyCounts {
+        let mut counts = [[0; KEY_COLS]; KEY_ROWS];
+        for (idx, (row, col)) in keys().enumerate() {
+            counts[row][col] = (idx as u32 * 37) % 101;
+        }
+        counts
+    }
+
+    /// Compare against the checked in image `name`, there's one for each PCB
+    /// as they have different keys. `UPDATE_SNAPSHOTS=1` writes them afresh.
+    fn assert_snapshot(image: DynamicImage, name: &str) {
+        let pcb = if cfg!(feature = "pcb-v3") {
+            "-pcb-v3"
+        } else {
+            ""
+        };
+        let path = format!("{}/testdata/{name}{pcb}.png", env!("CARGO_MANIFEST_DIR"));
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            image.save(&path).unwrap();
+        }
+
+        let expected = image::open(&path).unwrap_or_else(|e| panic!("{path}: {e}"));
+        assert!(image == expected, "{name} doesn't match {path}");
+    }
+
+    #[test]
+    fn heatmap_matches_snapshot() {
+        assert_snapshot(render(&counts()).into(), "heatmap");
+    }
+
+    #[test]
+    fn display_heatmap_matches_snapshot() {
+        assert_snapshot(for_displays(&render(&counts())).into(), "heatmap-displays");
+    }
+
+    #[test]
+    fn unpressed_keys_get_the_bottom_of_the_ramp() {
+        let image = render(&[[0; KEY_COLS]; KEY_ROWS]);
+        for (row, col) in keys() {
+            let (x, y) = key_origin(row, col);
+            assert_eq!(*image.get_pixel(x, y), Rgb(RAMP[0]));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HoldTapBehaviour, HoldTapMode, HostToKeyboard, KeyboardToHost, Tuning};
+
+use crate::host_link::HostLink;
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+enum Mode {
+    /// Only the timeout makes it a hold
+    Default,
+    /// Pressing another key makes it a hold
+    HoldOnOtherKeyPress,
+    /// Pressing and releasing another key makes it a hold
+    PermissiveHold,
+}
+
+impl From<Mode> for HoldTapMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Default => HoldTapMode::Default,
+            Mode::HoldOnOtherKeyPress => HoldTapMode::HoldOnOtherKeyPress,
+            Mode::PermissiveHold => HoldTapMode::PermissiveHold,
+        }
+    }
+}
+
+/// Try out how one hold-tap of the layout behaves, the change lasts until the
+/// keyboard restarts. With no options, prints how it behaves now.
+#[derive(Debug, clap::Parser)]
+pub struct HoldTapOpts {
+    layer: u8,
+    row: u8,
+    col: u8,
+
+    /// What makes it resolve as a hold before its timeout
+    #[clap(long, arg_enum)]
+    mode: Option<Mode>,
+
+    /// Pressing the key again within this many ms of a tap holds the tap
+    /// down, so it auto-repeats. 0 turns this off.
+    #[clap(long)]
+    tap_hold_interval: Option<u16>,
+
+    /// Go back to the keymap's behaviour
+    #[clap(long, conflicts_with_all = &["mode", "tap-hold-interval"])]
+    reset: bool,
+
+    port: Option<String>,
+}
+
+impl HoldTapOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+        let (layer, row, col) = (self.layer, self.row, self.col);
+
+        link.send(HostToKeyboard::RequestHoldTap { layer, row, col })
+            .await?;
+        let (mut behaviour, mut overridden) = recv_hold_tap(&mut link).await?;
+
+        let change = if self.reset {
+            Some(None)
+        } else if self.mode.is_some() || self.tap_hold_interval.is_some() {
+            Some(Some(HoldTapBehaviour {
+                mode: self.mode.map_or(behaviour.mode, Into::into),
+                tap_hold_interval_ms: self
+                    .tap_hold_interval
+                    .unwrap_or(behaviour.tap_hold_interval_ms),
+            }))
+        } else {
+            None
+        };
+
+        if let Some(change) = change {
+            link.send(HostToKeyboard::SetHoldTap {
+                layer,
+                row,
+                col,
+                behaviour: change,
+            })
+            .await?;
+            (behaviour, overridden) = recv_hold_tap(&mut link).await?;
+        }
+
+        println!("Mode: {:?}", behaviour.mode);
+        println!("Tap-hold interval: {}ms", behaviour.tap_hold_interval_ms);
+        if overridden {
+            println!("Changed from the keymap until the keyboard restarts, use --reset to undo");
+        }
+
+        Ok(())
+    }
+}
+
+async fn recv_hold_tap(link: &mut HostLink) -> Result<(HoldTapBehaviour, bool)> {
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(1))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for the keyboard's hold-tap"))?;
+
+        match msg {
+            KeyboardToHost::HoldTap {
+                behaviour,
+                overridden,
+                ..
+            } => return Ok((behaviour, overridden)),
+            KeyboardToHost::HoldTapRejected => {
+                return Err(eyre!(
+                    "The keyboard rejected that, there needs to be a hold-tap at that key, only \
+                     the first few hold-taps of the keymap can be changed, the interval can be \
+                     at most {}ms, and only a few keys can be changed at once",
+                    Tuning::MAX_MS
+                ))
+            }
+            _ => {}
+        }
+    }
+}
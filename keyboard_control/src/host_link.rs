@@ -0,0 +1,397 @@
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    fs,
+    future::Future,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    CmdOrAck, Command, HostToKeyboard, KeyboardToHost, Received, HOST_TIMEOUT_MS,
+};
+use once_cell::sync::OnceCell;
+use postcard::CobsAccumulator;
+use serde::de::DeserializeOwned;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_serial::SerialStream;
+
+use crate::util::open_port;
+
+/// Comfortably inside the keyboard's host timeout
+const KEEPALIVE_PERIOD: Duration = Duration::from_millis(HOST_TIMEOUT_MS / 3);
+
+/// From `--dump-frames`, where to write each frame received
+static DUMP_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+pub fn dump_frames(dir: Option<PathBuf>) {
+    if let Some(dir) = dir {
+        let _ = DUMP_DIR.set(dir);
+    }
+}
+
+/// Shared between the link and its reader, which writes acks
+type Writer = Arc<Mutex<WriteHalf<SerialStream>>>;
+
+/// A framed connection to the keyboard.
+///
+/// A reader task decodes everything the keyboard sends as it arrives, however
+/// the reads happen to split it up, and acks commands straight away. Acks
+/// from the keyboard are dropped. Once a command that opens a session has
+/// been sent, keepalives are sent while waiting so the keyboard doesn't end
+/// it.
+pub struct HostLink {
+    writer: Writer,
+    messages: mpsc::UnboundedReceiver<Result<KeyboardToHost>>,
+    reader: JoinHandle<()>,
+    pending: VecDeque<KeyboardToHost>,
+    session: bool,
+}
+
+impl HostLink {
+    pub fn open(port: Option<&str>) -> Result<Self> {
+        Ok(Self::new(open_port(port)?))
+    }
+
+    pub fn new(port: SerialStream) -> Self {
+        let (rx, tx) = tokio::io::split(port);
+        let writer = Arc::new(Mutex::new(tx));
+        let (messages_tx, messages) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_task(rx, writer.clone(), messages_tx));
+
+        Self {
+            writer,
+            messages,
+            reader,
+            pending: VecDeque::new(),
+            session: false,
+        }
+    }
+
+    /// Write commands that have already been framed, for streaming where
+    /// batching writes matters
+    pub async fn write_raw(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.lock().await.write_all(buf).await?;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the keyboard to send something, queueing up
+    /// everything that has arrived for `try_recv`
+    pub async fn poll(&mut self, timeout: Duration) -> Result<()> {
+        if self.pending.is_empty() {
+            match tokio::time::timeout(timeout, self.messages.recv()).await {
+                Ok(Some(msg)) => self.pending.push_back(msg?),
+                Ok(None) => return Err(eyre!("Keyboard link closed")),
+                Err(_) => {}
+            }
+        }
+
+        while let Ok(msg) = self.messages.try_recv() {
+            self.pending.push_back(msg?);
+        }
+
+        Ok(())
+    }
+
+    /// Take a message that has already arrived, without waiting
+    pub fn try_recv(&mut self) -> Option<KeyboardToHost> {
+        self.pending.pop_front()
+    }
+
+    pub async fn send(&mut self, cmd: HostToKeyboard) -> Result<()> {
+        self.session |= cmd.opens_session();
+        let cmd = CmdOrAck::Cmd(Command::new(cmd));
+        let buf = postcard::to_allocvec_cobs(&cmd).map_err(|e| eyre!("Serde error: {}", e))?;
+        self.write_raw(&buf).await
+    }
+
+    /// Wait for the next message from the keyboard
+    pub async fn recv(&mut self) -> Result<KeyboardToHost> {
+        self.next(None)
+            .await?
+            .ok_or_else(|| eyre!("Keyboard link closed"))
+    }
+
+    /// Wait for something other than the keyboard, such as user input,
+    /// without letting the session time out
+    pub async fn keep_alive_while<F: Future>(&mut self, fut: F) -> Result<F::Output> {
+        tokio::pin!(fut);
+
+        loop {
+            match tokio::time::timeout(KEEPALIVE_PERIOD, &mut fut).await {
+                Ok(r) => return Ok(r),
+                Err(_) => {
+                    if self.session {
+                        self.send(HostToKeyboard::KeepAlive).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for the next message from the keyboard, giving up after `timeout`
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<KeyboardToHost>> {
+        self.next(Some(Instant::now() + timeout)).await
+    }
+
+    /// The next message, `None` once `deadline` passes. Only the wait for a
+    /// message is ever cut short, never a write.
+    async fn next(&mut self, deadline: Option<Instant>) -> Result<Option<KeyboardToHost>> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Ok(Some(msg));
+            }
+
+            let mut wait = KEEPALIVE_PERIOD;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if deadline <= now {
+                    return Ok(None);
+                }
+                wait = wait.min(deadline - now);
+            }
+
+            match tokio::time::timeout(wait, self.messages.recv()).await {
+                Ok(Some(msg)) => return msg.map(Some),
+                Ok(None) => return Err(eyre!("Keyboard link closed")),
+                // a keepalive is only due once it's been quiet for the whole period
+                Err(_) if self.session && wait == KEEPALIVE_PERIOD => {
+                    self.send(HostToKeyboard::KeepAlive).await?
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+impl Drop for HostLink {
+    fn drop(&mut self) {
+        // let go of the port
+        self.reader.abort();
+    }
+}
+
+/// Longest frame the decoder takes
+const FRAME_MAX: usize = 256;
+
+/// Decodes the keyboard's frames from a stream of bytes, however it's split
+/// up into reads. Frames between the halves decode the same way, with
+/// `DomToSub` or `SubToDom` for `T`.
+pub struct FrameDecoder<T = KeyboardToHost> {
+    accumulator: CobsAccumulator<FRAME_MAX>,
+    /// The frame being received, kept only when dumping frames
+    frame: Vec<u8>,
+    /// The frame being received got too long to be one, so it isn't kept
+    overlong: bool,
+    undecodable: u64,
+    _msg: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned + Hash> FrameDecoder<T> {
+    pub fn new() -> Self {
+        Self {
+            accumulator: CobsAccumulator::new(),
+            frame: Vec::new(),
+            overlong: false,
+            undecodable: 0,
+            _msg: PhantomData,
+        }
+    }
+
+    /// Frames so far that didn't decode, or were too long to be one
+    pub fn undecodable(&self) -> u64 {
+        self.undecodable
+    }
+
+    /// Feed in bytes as they're read, returning whatever the frames they
+    /// finish decode to
+    pub fn feed(&mut self, mut window: &[u8]) -> Result<Vec<Received<T>>> {
+        self.dump(window)?;
+
+        let mut received = Vec::new();
+
+        'cobs: while !window.is_empty() {
+            window = match self.accumulator.feed(window) {
+                postcard::FeedResult::Consumed => break 'cobs,
+                postcard::FeedResult::OverFull(buf) | postcard::FeedResult::DeserError(buf) => {
+                    self.undecodable += 1;
+                    buf
+                }
+                postcard::FeedResult::Success { data, remaining } => {
+                    let data: CmdOrAck<T> = data;
+                    received.push(data.receive());
+                    remaining
+                }
+            }
+        }
+
+        Ok(received)
+    }
+
+    /// Write each complete frame in `buf` to its own file, still COBS encoded
+    /// and named by its hash, so a session can seed a fuzzing corpus
+    fn dump(&mut self, buf: &[u8]) -> Result<()> {
+        let dir = match DUMP_DIR.get() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        for &byte in buf {
+            if byte != 0 {
+                // a line that never sends a delimiter doesn't grow this
+                // forever, the accumulator wouldn't decode it either
+                if self.frame.len() == FRAME_MAX {
+                    self.frame.clear();
+                    self.overlong = true;
+                }
+                self.frame.push(byte);
+                continue;
+            }
+            if std::mem::take(&mut self.overlong) {
+                self.frame.clear();
+                continue;
+            }
+            self.frame.push(byte);
+
+            let mut hasher = DefaultHasher::new();
+            self.frame.hash(&mut hasher);
+            fs::create_dir_all(dir)?;
+            fs::write(dir.join(format!("{:016x}", hasher.finish())), &self.frame)?;
+            self.frame.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned + Hash> Default for FrameDecoder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read from the keyboard until the link goes away or nobody is listening,
+/// acking commands as they're decoded
+async fn read_task(
+    mut rx: ReadHalf<SerialStream>,
+    writer: Writer,
+    messages: mpsc::UnboundedSender<Result<KeyboardToHost>>,
+) {
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0u8; 256];
+
+    let result: Result<()> = async {
+        loop {
+            let len = rx.read(&mut buf).await?;
+            if len == 0 {
+                return Err(eyre!("Keyboard link closed"));
+            }
+
+            for received in decoder.feed(&buf[..len])? {
+                if let Received::Cmd { cmd, ack } = received {
+                    let ack = CmdOrAck::<HostToKeyboard>::Ack(ack);
+                    let ack = postcard::to_allocvec_cobs(&ack)
+                        .map_err(|e| eyre!("Serde error: {}", e))?;
+                    writer.lock().await.write_all(&ack).await?;
+
+                    if messages.send(Ok(cmd)).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+    .await;
+
+    if let Err(e) = result {
+        let _ = messages.send(Err(e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the keyboard might send in a session: commands, an ack, a frame
+    /// that fails to decode, and line noise between frames
+    fn recorded() -> Vec<u8> {
+        let cmds = [
+            KeyboardToHost::Stats { keypresses: 1234 },
+            KeyboardToHost::PixelRow {
+                row: 6,
+                data_0: [0, 0, 0xff, 1],
+                data_1: [2, 0, 0, 0],
+            },
+            KeyboardToHost::Stats { keypresses: 0 },
+        ];
+
+        let mut stream = Vec::new();
+        for cmd in cmds {
+            let frame = CmdOrAck::Cmd(Command::new(cmd));
+            stream.extend(postcard::to_allocvec_cobs(&frame).unwrap());
+        }
+        let ack = Command::new(HostToKeyboard::KeepAlive).ack();
+        let ack = CmdOrAck::<KeyboardToHost>::Ack(ack);
+        stream.extend(postcard::to_allocvec_cobs(&ack).unwrap());
+        stream.extend([0x13, 0x37, 0xff, 0]);
+        let frame = CmdOrAck::Cmd(Command::new(KeyboardToHost::Stats { keypresses: 9 }));
+        stream.extend(postcard::to_allocvec_cobs(&frame).unwrap());
+        stream
+    }
+
+    /// Decode `stream` fed in reads of the sizes `sizes` gives
+    fn decode(stream: &[u8], mut sizes: impl FnMut() -> usize) -> (Vec<String>, u64) {
+        let mut decoder = FrameDecoder::<KeyboardToHost>::new();
+        let mut decoded = Vec::new();
+        let mut rest = stream;
+        while !rest.is_empty() {
+            let (read, after) = rest.split_at(sizes().clamp(1, rest.len()));
+            rest = after;
+            let received = decoder.feed(read).unwrap();
+            decoded.extend(received.iter().map(|r| format!("{:?}", r)));
+        }
+        (decoded, decoder.undecodable())
+    }
+
+    #[test]
+    fn whole_stream_decodes() {
+        let (decoded, undecodable) = decode(&recorded(), || usize::MAX);
+        assert_eq!(decoded.len(), 5);
+        assert!(decoded[0].contains("keypresses: 1234"));
+        assert!(decoded[3].starts_with("Ack("));
+        assert!(decoded[4].contains("keypresses: 9"));
+        assert_eq!(undecodable, 1);
+    }
+
+    #[test]
+    fn byte_at_a_time_decodes_the_same() {
+        let stream = recorded();
+        assert_eq!(decode(&stream, || 1), decode(&stream, || usize::MAX));
+    }
+
+    #[test]
+    fn random_reads_decode_the_same() {
+        let stream = recorded();
+        let whole = decode(&stream, || usize::MAX);
+
+        for seed in 1..100u32 {
+            let mut state = seed;
+            let sizes = || {
+                // xorshift, for reads of 1 to 16 bytes
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as usize % 16 + 1
+            };
+            assert_eq!(decode(&stream, sizes), whole, "seed {seed}");
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use color_eyre::Result;
+use keyboard_shared::{HostToKeyboard, KeyboardSide};
+
+use crate::host_link::HostLink;
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+enum Side {
+    Left,
+    Right,
+}
+
+/// Hold single LEDs at a colour over the running effect, until they're
+/// cleared or the keyboard resets. Use led-test to find an LED's index.
+#[derive(Debug, clap::Parser)]
+pub struct KeyLedOpts {
+    #[clap(subcommand)]
+    command: KeyLedCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum KeyLedCommand {
+    /// Set the LED at `index` in one half's chain to r g b
+    Set {
+        #[clap(arg_enum)]
+        side: Side,
+        index: u8,
+        r: u8,
+        g: u8,
+        b: u8,
+
+        port: Option<String>,
+    },
+    /// Give every LED back to the effect, on both halves
+    Clear { port: Option<String> },
+}
+
+impl KeyLedOpts {
+    pub async fn execute(self) -> Result<()> {
+        match self.command {
+            KeyLedCommand::Set {
+                side,
+                index,
+                r,
+                g,
+                b,
+                port,
+            } => {
+                let side = match side {
+                    Side::Left => KeyboardSide::Left,
+                    Side::Right => KeyboardSide::Right,
+                };
+                let mut link = HostLink::open(port.as_deref())?;
+                link.send(HostToKeyboard::SetKeyLed {
+                    side,
+                    index,
+                    r,
+                    g,
+                    b,
+                })
+                .await?;
+            }
+            KeyLedCommand::Clear { port } => {
+                let mut link = HostLink::open(port.as_deref())?;
+                link.send(HostToKeyboard::ClearKeyLeds).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
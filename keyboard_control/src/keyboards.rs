@@ -0,0 +1,132 @@
+//! Picking one keyboard out of several by its USB serial number, or by an
+//! alias for one.
+//!
+//! Aliases live in `keyboard_control/keyboards` under the config directory
+//! (`$XDG_CONFIG_HOME`, or `~/.config`), one `<alias> <serial>` per line.
+//! Blank lines and lines starting with `#` are skipped. A keyboard with a USB
+//! port on each half gets a line for each half's serial under the same
+//! alias.
+
+use std::path::PathBuf;
+
+use color_eyre::{eyre::eyre, Result};
+use once_cell::sync::OnceCell;
+use tokio_serial::{SerialPortInfo, SerialPortType};
+
+/// From `--keyboard`, an alias or a serial number
+static SELECTED: OnceCell<String> = OnceCell::new();
+
+pub fn select(keyboard: Option<String>) {
+    if let Some(keyboard) = keyboard {
+        let _ = SELECTED.set(keyboard);
+    }
+}
+
+pub fn selected() -> Option<&'static str> {
+    SELECTED.get().map(String::as_str)
+}
+
+/// What to call the selected keyboard in metrics, its alias if it has one
+pub fn label() -> Result<Option<String>> {
+    let selected = match selected() {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let aliases = load_aliases()?;
+    let alias = aliases
+        .iter()
+        .find(|(alias, serial)| alias == selected || serial == selected)
+        .map_or(selected, |(alias, _)| alias);
+
+    Ok(Some(alias.to_owned()))
+}
+
+fn aliases_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config.join("keyboard_control").join("keyboards"))
+}
+
+/// `(alias, serial)` pairs, there being no aliases file is fine
+pub fn load_aliases() -> Result<Vec<(String, String)>> {
+    let path = match aliases_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut aliases = Vec::new();
+    for (idx, line) in std::fs::read_to_string(&path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [alias, serial] => aliases.push((alias.to_owned(), serial.to_owned())),
+            _ => {
+                return Err(eyre!(
+                    "{}:{}: expected `<alias> <serial>`",
+                    path.display(),
+                    idx + 1
+                ))
+            }
+        }
+    }
+
+    Ok(aliases)
+}
+
+pub fn serial_number(port: &SerialPortInfo) -> Option<&str> {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => usb.serial_number.as_deref(),
+        _ => None,
+    }
+}
+
+/// The ports of the keyboard picked by `selector`, an alias or a serial
+/// number. There are two if it has a port on each half.
+pub fn find_ports(selector: &str) -> Result<Vec<String>> {
+    let aliases = load_aliases()?;
+    let mut serials = aliases
+        .iter()
+        .filter(|(alias, _)| alias == selector)
+        .map(|(_, serial)| serial.as_str())
+        .collect::<Vec<_>>();
+    if serials.is_empty() {
+        serials.push(selector);
+    }
+
+    let ports = tokio_serial::available_ports()?;
+    let found = ports
+        .iter()
+        .filter(|p| p.port_name.contains("ttyACM"))
+        .filter(|p| serial_number(p).is_some_and(|s| serials.contains(&s)))
+        .map(|p| p.port_name.clone())
+        .collect::<Vec<_>>();
+    if !found.is_empty() {
+        return Ok(found);
+    }
+
+    let available = ports
+        .iter()
+        .filter(|p| p.port_name.contains("ttyACM"))
+        .filter_map(serial_number)
+        .map(|s| match aliases.iter().find(|(_, serial)| serial == s) {
+            Some((alias, _)) => format!("{} ({})", s, alias),
+            None => s.to_owned(),
+        })
+        .collect::<Vec<_>>();
+
+    Err(eyre!(
+        "No keyboard with serial number {}, found: {}",
+        serials.join(" or "),
+        if available.is_empty() {
+            "none".to_owned()
+        } else {
+            available.join(", ")
+        }
+    ))
+}
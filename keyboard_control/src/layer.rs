@@ -0,0 +1,108 @@
+use std::{fs, io::Read, path::PathBuf, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardToHost, StatusReport, STATUS_REPORT_DESCRIPTOR};
+
+use crate::host_link::HostLink;
+
+/// How often the serial port is asked for the status while watching
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// Show the active layer and whether the keyboard is locked
+#[derive(Debug, clap::Parser)]
+pub struct LayerOpts {
+    /// Keep printing the state each time it changes
+    #[clap(long)]
+    watch: bool,
+
+    /// Read the status HID interface through hidraw instead of using the
+    /// serial port, which leaves the port free for things like metrics. The
+    /// keyboard only sends the status when it changes, so this needs --watch.
+    #[clap(long, requires = "watch")]
+    hid: bool,
+
+    /// Serial port, or the hidraw device with --hid
+    port: Option<String>,
+}
+
+impl LayerOpts {
+    pub async fn execute(self) -> Result<()> {
+        if self.hid {
+            let path = match self.port {
+                Some(path) => PathBuf::from(path),
+                None => find_hidraw()?,
+            };
+            tracing::info!(?path, "watching status");
+
+            return tokio::task::spawn_blocking(move || watch_hid(path)).await?;
+        }
+
+        let mut link = HostLink::open(self.port.as_deref())?;
+        let mut last = None;
+
+        loop {
+            let status = request_status(&mut link).await?;
+            if last != Some(status) {
+                print_status(status);
+                last = Some(status);
+            }
+
+            if !self.watch {
+                return Ok(());
+            }
+
+            tokio::time::sleep(POLL_PERIOD).await;
+        }
+    }
+}
+
+async fn request_status(link: &mut HostLink) -> Result<StatusReport> {
+    link.send(HostToKeyboard::RequestStatus).await?;
+
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(1))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for the status"))?;
+
+        if let KeyboardToHost::Status(status) = msg {
+            return Ok(status);
+        }
+    }
+}
+
+fn watch_hid(path: PathBuf) -> Result<()> {
+    let mut dev = fs::File::open(&path)?;
+    let mut buf = [0u8; StatusReport::LEN];
+
+    loop {
+        // hidraw hands over one report per read
+        dev.read_exact(&mut buf)?;
+        print_status(StatusReport::from_bytes(buf));
+    }
+}
+
+/// The hidraw device whose report descriptor is the status interface's
+fn find_hidraw() -> Result<PathBuf> {
+    for entry in fs::read_dir("/sys/class/hidraw")? {
+        let entry = entry?;
+        let descriptor = match fs::read(entry.path().join("device/report_descriptor")) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        if descriptor == STATUS_REPORT_DESCRIPTOR {
+            return Ok(PathBuf::from("/dev").join(entry.file_name()));
+        }
+    }
+
+    Err(eyre!("Couldn't find the keyboard's status interface"))
+}
+
+fn print_status(status: StatusReport) {
+    if status.locked() {
+        println!("{} (locked)", status.layer);
+    } else {
+        println!("{}", status.layer);
+    }
+}
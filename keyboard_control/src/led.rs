@@ -0,0 +1,56 @@
+use color_eyre::Result;
+use keyboard_shared::{ConfigItem, HostToKeyboard, LedColour};
+
+use crate::{config::parse_led_mode, host_link::HostLink};
+
+/// Switch the LED effect on both halves, the change is saved on the keyboard
+#[derive(Debug, clap::Parser)]
+pub struct LedOpts {
+    /// One of rainbow_waves, rainbow, solid, breathing, reactive, heatmap,
+    /// flash, tap_fade or off
+    mode: String,
+
+    /// Hue of the solid, breathing and tap_fade modes, from 0 to 255
+    #[clap(long)]
+    hue: Option<u8>,
+
+    /// Saturation of the solid, breathing and tap_fade modes, from 0 to 255.
+    /// Defaults to full when only a hue is given.
+    #[clap(long)]
+    sat: Option<u8>,
+
+    /// Brightness of the solid mode, from 0 to 255
+    #[clap(long)]
+    val: Option<u8>,
+
+    port: Option<String>,
+}
+
+impl LedOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mode = parse_led_mode(&self.mode)?;
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        if self.hue.is_some() || self.sat.is_some() || self.val.is_some() {
+            let default = LedColour::DEFAULT;
+            // a hue on its own would otherwise come out white
+            let sat = match self.hue {
+                Some(_) => 255,
+                None => default.s,
+            };
+            let colour = LedColour {
+                h: self.hue.unwrap_or(default.h),
+                s: self.sat.unwrap_or(sat),
+                v: self.val.unwrap_or(default.v),
+            };
+            link.send(HostToKeyboard::SetConfig(ConfigItem::LedColour(colour)))
+                .await?;
+        }
+
+        link.send(HostToKeyboard::SetConfig(ConfigItem::LedMode(mode)))
+            .await?;
+        println!("LEDs set to {}", self.mode);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::{io::Write, time::Duration};
+
+use color_eyre::Result;
+use keyboard_shared::{HostToKeyboard, KeyboardSide, LedLayout};
+
+use crate::{host_link::HostLink, util::read_line};
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+enum Side {
+    Left,
+    Right,
+    Both,
+}
+
+/// Light each LED in turn so the wiring can be checked against the board
+#[derive(Debug, clap::Parser)]
+pub struct LedTestOpts {
+    #[clap(long, arg_enum, default_value = "both")]
+    side: Side,
+
+    /// Step through the LEDs on a timer rather than waiting for Enter
+    #[clap(long)]
+    auto: bool,
+
+    /// Time each LED is lit for with --auto
+    #[clap(long, default_value = "500")]
+    delay_ms: u64,
+
+    port: Option<String>,
+}
+
+impl LedTestOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        let sides: &[KeyboardSide] = match self.side {
+            Side::Left => &[KeyboardSide::Left],
+            Side::Right => &[KeyboardSide::Right],
+            Side::Both => &[KeyboardSide::Left, KeyboardSide::Right],
+        };
+
+        if !self.auto {
+            println!("Press Enter for the next LED, q to finish");
+        }
+
+        let result = self.step(&mut link, sides).await;
+
+        for side in sides {
+            link.send(HostToKeyboard::LedTestIndex {
+                side: *side,
+                index: None,
+            })
+            .await?;
+        }
+
+        result
+    }
+
+    async fn step(&self, link: &mut HostLink, sides: &[KeyboardSide]) -> Result<()> {
+        for side in sides {
+            let leds = side.leds();
+            for index in 0..leds.len() {
+                link.send(HostToKeyboard::LedTestIndex {
+                    side: *side,
+                    index: Some(index as u8),
+                })
+                .await?;
+
+                print!("{:?} LED {:>2}: {}", side, index, describe(leds, index));
+
+                if self.auto {
+                    println!();
+                    link.keep_alive_while(tokio::time::sleep(Duration::from_millis(self.delay_ms)))
+                        .await?;
+                    continue;
+                }
+
+                print!(" ");
+                std::io::stdout().flush()?;
+
+                match link.keep_alive_while(read_line()).await?? {
+                    Some(line) if line.trim() == "q" => return Ok(()),
+                    Some(_) => {}
+                    None => return Ok(()),
+                }
+            }
+
+            // the LEDs on this half go back to normal before moving on
+            link.send(HostToKeyboard::LedTestIndex {
+                side: *side,
+                index: None,
+            })
+            .await?;
+        }
+
+        println!("All LEDs done");
+
+        Ok(())
+    }
+}
+
+/// Where an LED should be, LED indices in the chain start at 0
+fn describe(leds: &LedLayout, index: usize) -> String {
+    let switches = index.checked_sub(leds.underglow.len());
+    let extra = switches.and_then(|i| i.checked_sub(leds.switches.len()));
+
+    match (switches, extra) {
+        (None, _) => {
+            let (row, col) = leds.underglow[index];
+            format!("underglow, near row {} col {}", row, col)
+        }
+        (Some(i), None) => {
+            let (row, col) = leds.switches[i];
+            format!("under the key at row {} col {}", row, col)
+        }
+        (_, Some(i)) => {
+            let (row, col) = leds.extra[i];
+            format!(
+                "underglow under the wrist rest, near row {} col {}",
+                row, col
+            )
+        }
+    }
+}
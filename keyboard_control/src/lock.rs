@@ -0,0 +1,25 @@
+use color_eyre::Result;
+use keyboard_shared::HostToKeyboard;
+
+use crate::host_link::HostLink;
+
+/// Lock the keyboard so no keys are sent to the host, hold both outer thumb
+/// keys for two seconds to unlock it again
+#[derive(Debug, clap::Parser)]
+pub struct LockOpts {
+    /// Unlock the keyboard instead
+    #[clap(long)]
+    unlock: bool,
+
+    port: Option<String>,
+}
+
+impl LockOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        link.send(HostToKeyboard::Lock(!self.unlock)).await?;
+
+        Ok(())
+    }
+}
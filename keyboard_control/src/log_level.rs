@@ -0,0 +1,36 @@
+use color_eyre::Result;
+use keyboard_shared::{HostToKeyboard, LogLevel};
+
+use crate::host_link::HostLink;
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+enum Level {
+    Quiet,
+    Sampled,
+    Verbose,
+}
+
+/// Set how much the firmware logs per key event and link frame, for when a
+/// debugger is attached. Lasts until the keyboard restarts.
+#[derive(Debug, clap::Parser)]
+pub struct LogLevelOpts {
+    #[clap(arg_enum)]
+    level: Level,
+
+    port: Option<String>,
+}
+
+impl LogLevelOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        let level = match self.level {
+            Level::Quiet => LogLevel::Quiet,
+            Level::Sampled => LogLevel::Sampled,
+            Level::Verbose => LogLevel::Verbose,
+        };
+        link.send(HostToKeyboard::SetLogLevel(level)).await?;
+
+        Ok(())
+    }
+}
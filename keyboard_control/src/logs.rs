@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use color_eyre::Result;
+use keyboard_shared::{KeyboardToHost, LogSeverity};
+
+use crate::host_link::HostLink;
+
+/// Without `--follow`, stop once the keyboard has been quiet this long
+const QUIET_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// Print the log lines the keyboard sends, with its uptime when each was
+/// logged. The keyboard only sends warnings and errors unless its verbosity
+/// is raised with `log-level`.
+#[derive(Debug, clap::Parser)]
+pub struct LogsOpts {
+    /// Keep printing lines as they arrive, instead of stopping once the
+    /// queued ones have been printed
+    #[clap(long)]
+    follow: bool,
+
+    /// Skip lines less serious than this
+    #[clap(long, arg_enum, default_value = "debug")]
+    level: Level,
+
+    port: Option<String>,
+}
+
+impl LogsOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        let threshold = match self.level {
+            Level::Error => LogSeverity::Error,
+            Level::Warn => LogSeverity::Warn,
+            Level::Info => LogSeverity::Info,
+            Level::Debug => LogSeverity::Debug,
+        };
+
+        loop {
+            let msg = if self.follow {
+                link.recv().await?
+            } else {
+                match link.recv_timeout(QUIET_TIME).await? {
+                    Some(msg) => msg,
+                    None => return Ok(()),
+                }
+            };
+
+            if let KeyboardToHost::Log {
+                severity,
+                uptime_ms,
+                msg,
+            } = msg
+            {
+                if severity <= threshold {
+                    println!(
+                        "[{:>6}.{:03}] {:<5} {}",
+                        uptime_ms / 1000,
+                        uptime_ms % 1000,
+                        severity_name(severity),
+                        String::from_utf8_lossy(msg.as_bytes())
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn severity_name(severity: LogSeverity) -> &'static str {
+    match severity {
+        LogSeverity::Error => "ERROR",
+        LogSeverity::Warn => "WARN",
+        LogSeverity::Info => "INFO",
+        LogSeverity::Debug => "DEBUG",
+    }
+}
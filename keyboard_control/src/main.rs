@@ -1,9 +1,45 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use color_eyre::Result;
 
+mod bench_latency;
+mod calibrate_leds;
+mod capture;
+mod chatter;
+mod cli_json;
+mod completions;
+mod config;
+mod debug_screen;
+mod dfu;
+mod emulator;
+mod factory_reset;
+mod force_alloc;
+mod heatmap;
+mod heatmap_render;
+mod hold_tap;
+mod host_link;
+mod key_led;
+mod keyboards;
+mod layer;
+mod led;
+mod led_test;
+mod lock;
+mod log_level;
+mod logs;
 mod metrics;
+mod prepare_shutdown;
+mod pty;
+mod remap;
 mod render;
+mod screenshot;
+mod set_time;
+mod settings;
+mod stats;
+mod tail;
+mod timing;
+mod tune;
+mod usage;
 pub mod util;
+mod watch;
 
 fn install_tracing() -> color_eyre::Result<()> {
     use tracing_subscriber::layer::SubscriberExt;
@@ -34,17 +70,66 @@ fn install_tracing() -> color_eyre::Result<()> {
 }
 
 #[derive(Debug, clap::Parser)]
+#[clap(arg_required_else_help = true)]
 struct Opts {
+    /// Which keyboard to talk to, by USB serial number or by an alias from
+    /// the keyboards file (see `ports`), when more than one is plugged in
+    #[clap(long, global = true)]
+    keyboard: Option<String>,
+
+    /// Write every frame received from the keyboard to its own file in this
+    /// directory, for seeding the protocol fuzzer's corpus
+    #[clap(long, global = true, parse(from_os_str))]
+    dump_frames: Option<std::path::PathBuf>,
+
+    /// Print every command and option as JSON, for wrappers that build
+    /// their own UI
+    #[clap(long, hide = true)]
+    dump_cli_json: bool,
+
     #[clap(subcommand)]
-    command: ControlCommand,
+    command: Option<ControlCommand>,
 }
 
 #[derive(Debug, clap::Subcommand)]
 pub enum ControlCommand {
-    /// List possible ports
+    /// List possible ports, with the serial number and alias of each
+    /// keyboard. Aliases are read from keyboard_control/keyboards in the
+    /// config directory, one `<alias> <serial>` per line.
     Ports,
     Render(crate::render::RenderOpts),
     Metrics(crate::metrics::MetricsOpts),
+    Timing(crate::timing::TimingOpts),
+    DebugScreen(crate::debug_screen::DebugScreenOpts),
+    Lock(crate::lock::LockOpts),
+    Screenshot(crate::screenshot::ScreenshotOpts),
+    Settings(crate::settings::SettingsOpts),
+    Config(crate::config::ConfigOpts),
+    CalibrateLeds(crate::calibrate_leds::CalibrateLedsOpts),
+    Stats(crate::stats::StatsOpts),
+    Led(crate::led::LedOpts),
+    LedTest(crate::led_test::LedTestOpts),
+    KeyLed(crate::key_led::KeyLedOpts),
+    BenchLatency(crate::bench_latency::BenchLatencyOpts),
+    Layer(crate::layer::LayerOpts),
+    Watch(crate::watch::WatchOpts),
+    Tail(crate::tail::TailOpts),
+    Heatmap(crate::heatmap::HeatmapOpts),
+    Emulator(crate::emulator::EmulatorOpts),
+    LogLevel(crate::log_level::LogLevelOpts),
+    Logs(crate::logs::LogsOpts),
+    SetTime(crate::set_time::SetTimeOpts),
+    Tune(crate::tune::TuneOpts),
+    HoldTap(crate::hold_tap::HoldTapOpts),
+    Remap(crate::remap::RemapOpts),
+    Usage(crate::usage::UsageOpts),
+    Chatter(crate::chatter::ChatterOpts),
+    Completions(crate::completions::CompletionsOpts),
+    FactoryReset(crate::factory_reset::FactoryResetOpts),
+    PrepareShutdown(crate::prepare_shutdown::PrepareShutdownOpts),
+    Dfu(crate::dfu::DfuOpts),
+    Capture(crate::capture::CaptureOpts),
+    ForceAlloc(crate::force_alloc::ForceAllocOpts),
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -55,21 +140,79 @@ async fn main() -> Result<()> {
 
     install_tracing()?;
 
-    match opts.command {
+    keyboards::select(opts.keyboard);
+    host_link::dump_frames(opts.dump_frames);
+
+    if opts.dump_cli_json {
+        return cli_json::dump();
+    }
+
+    let command = match opts.command {
+        Some(command) => command,
+        None => {
+            Opts::command().print_help()?;
+            return Ok(());
+        }
+    };
+
+    match command {
         ControlCommand::Ports => {
             let ports = tokio_serial::available_ports()?;
+            let aliases = keyboards::load_aliases()?;
 
             if ports.is_empty() {
                 println!("No ports found");
             } else {
                 println!("The following ports were found:");
                 for port in ports {
-                    println!("{}: {:?}", port.port_name, port.port_type);
+                    match keyboards::serial_number(&port) {
+                        Some(serial) => {
+                            let alias = aliases.iter().find(|(_, s)| s == serial);
+                            match alias {
+                                Some((alias, _)) => {
+                                    println!("{}: serial {} ({})", port.port_name, serial, alias)
+                                }
+                                None => println!("{}: serial {}", port.port_name, serial),
+                            }
+                        }
+                        None => println!("{}: {:?}", port.port_name, port.port_type),
+                    }
                 }
             }
         }
         ControlCommand::Render(r) => r.execute().await?,
         ControlCommand::Metrics(m) => m.execute().await?,
+        ControlCommand::Timing(t) => t.execute().await?,
+        ControlCommand::DebugScreen(d) => d.execute().await?,
+        ControlCommand::Lock(l) => l.execute().await?,
+        ControlCommand::Screenshot(s) => s.execute().await?,
+        ControlCommand::Settings(s) => s.execute().await?,
+        ControlCommand::Config(c) => c.execute().await?,
+        ControlCommand::CalibrateLeds(c) => c.execute().await?,
+        ControlCommand::Stats(s) => s.execute().await?,
+        ControlCommand::Led(l) => l.execute().await?,
+        ControlCommand::LedTest(l) => l.execute().await?,
+        ControlCommand::KeyLed(k) => k.execute().await?,
+        ControlCommand::BenchLatency(b) => b.execute().await?,
+        ControlCommand::Layer(l) => l.execute().await?,
+        ControlCommand::Watch(w) => w.execute().await?,
+        ControlCommand::Tail(t) => t.execute().await?,
+        ControlCommand::Heatmap(h) => h.execute().await?,
+        ControlCommand::Emulator(e) => e.execute().await?,
+        ControlCommand::LogLevel(l) => l.execute().await?,
+        ControlCommand::Logs(l) => l.execute().await?,
+        ControlCommand::SetTime(s) => s.execute().await?,
+        ControlCommand::Tune(t) => t.execute().await?,
+        ControlCommand::HoldTap(h) => h.execute().await?,
+        ControlCommand::Remap(r) => r.execute().await?,
+        ControlCommand::Usage(u) => u.execute().await?,
+        ControlCommand::Chatter(c) => c.execute().await?,
+        ControlCommand::Completions(c) => c.execute()?,
+        ControlCommand::FactoryReset(f) => f.execute().await?,
+        ControlCommand::PrepareShutdown(p) => p.execute().await?,
+        ControlCommand::Dfu(d) => d.execute().await?,
+        ControlCommand::Capture(c) => c.execute().await?,
+        ControlCommand::ForceAlloc(f) => f.execute().await?,
     }
 
     Ok(())
@@ -1,24 +1,248 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use color_eyre::{eyre::eyre, Result};
-use keyboard_shared::{CmdOrAck, Command, HostToKeyboard, KeyboardToHost};
+use keyboard_shared::{
+    DropChannel, EventSource, HostToKeyboard, KeyboardSide, KeyboardToHost, LinkErrorKind,
+    LATENCY_BUCKETS,
+};
 use once_cell::sync::Lazy;
-use postcard::CobsAccumulator;
-use prometheus::{register_int_counter, Encoder, IntCounter, ProtobufEncoder};
-use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    select,
-    time::interval,
+use prometheus::{
+    register_gauge, register_int_counter, register_int_gauge, register_int_gauge_vec, Encoder,
+    Gauge, IntCounter, IntGauge, IntGaugeVec, ProtobufEncoder,
 };
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
 use tracing::info;
 
-use crate::util::open_port;
+use crate::{
+    bench_latency::bucket_label, keyboards, set_time::local_seconds_of_day, util::open_keyboard,
+};
+
+/// How often stats are asked for
+const STATS_PERIOD: Duration = Duration::from_secs(5);
+/// How often the keyboard's clock is set again, it drifts and forgets the
+/// time when it restarts
+const TIME_SYNC_PERIOD: Duration = Duration::from_secs(5 * 60);
 
 static KEYPRESS_COUNTER: Lazy<IntCounter> = Lazy::new(|| {
     register_int_counter!("total_keypresses", "Total number of keys pressed").unwrap()
 });
 
+static LEFT_KEYPRESS_COUNTER: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("left_keypresses", "Keys pressed on the left half").unwrap()
+});
+
+static RIGHT_KEYPRESS_COUNTER: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("right_keypresses", "Keys pressed on the right half").unwrap()
+});
+
+static UPTIME_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("uptime_seconds", "Seconds since the left half booted").unwrap()
+});
+
+static CPS_GAUGE: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "keypresses_per_second",
+        "Key presses a second averaged over the last few seconds"
+    )
+    .unwrap()
+});
+
+static CPU_BUSY_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "cpu_busy_pct",
+        "Estimated CPU busy percentage of the left half"
+    )
+    .unwrap()
+});
+
+static CORRUPT_FRAMES_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "link_corrupt_frames",
+        "Link frames the left half dropped for failing to decode"
+    )
+    .unwrap()
+});
+
+static LINK_READ_ERRORS_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "link_read_errors",
+        "Reads from the link that the left half's UART failed"
+    )
+    .unwrap()
+});
+
+static LINK_ERRORS_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "link_errors",
+        "Reads from the link that the left half's UART failed, by kind",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+static LINK_RESYNCS_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "link_resyncs",
+        "Bursts of link errors that had the left half say hello again"
+    )
+    .unwrap()
+});
+
+static LINK_UP_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("link_up", "1 while the right half is answering heartbeats").unwrap()
+});
+
+static LINK_LOSSES_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "link_losses",
+        "Times the right half stopped answering heartbeats"
+    )
+    .unwrap()
+});
+
+static RETRANSMITS_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "link_retransmits",
+        "Commands the left half sent again because no ack arrived in time"
+    )
+    .unwrap()
+});
+
+static FAILED_SENDS_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "link_failed_sends",
+        "Best effort commands the left half gave up on after running out of retries"
+    )
+    .unwrap()
+});
+
+static SOURCE_PRESSES_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "keypresses_by_source",
+        "Key presses seen by the layout, by where they came from",
+        &["source"]
+    )
+    .unwrap()
+});
+
+static CHANNEL_DROPS_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "channel_drops",
+        "Messages the left half dropped because a channel was full",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+static SETTINGS_ERASES_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "settings_erases",
+        "Erase cycles of the flash pages the left half saves settings to"
+    )
+    .unwrap()
+});
+
+static REJECTED_PIXEL_WRITES_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "rejected_pixel_writes",
+        "Host pixel writes the keyboard turned away for being out of bounds"
+    )
+    .unwrap()
+});
+
+static LINK_QUEUE_HIGH_WATER_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "link_queue_high_water",
+        "Most frames the left half has had queued to send at once",
+        &["queue"]
+    )
+    .unwrap()
+});
+
+static FIRMWARE_MISMATCH_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "firmware_mismatch",
+        "1 if the halves are running different firmware"
+    )
+    .unwrap()
+});
+
+static PRESENCE_MODE_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "presence_mode",
+        "1 while presence mode is nudging the mouse"
+    )
+    .unwrap()
+});
+
+static SAFE_MODE_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "safe_mode",
+        "1 if the left half booted in safe mode after failing to boot"
+    )
+    .unwrap()
+});
+
+static HEAP_HIGH_WATER_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "heap_high_water_bytes",
+        "Most bytes of the left half's heap in use at once"
+    )
+    .unwrap()
+});
+
+static OOM_AVERTED_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "oom_averted",
+        "Allocations the left half turned down rather than run out of heap"
+    )
+    .unwrap()
+});
+
+static DISPLAY_FLUSH_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "display_flush_us",
+        "How long the left half's display takes to flush",
+        &["stat"]
+    )
+    .unwrap()
+});
+
+static DISPLAY_BUS_BUSY_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "display_bus_busy_pct",
+        "Percentage of the last second the left half's display bus spent flushing"
+    )
+    .unwrap()
+});
+
+static DISPLAY_BUS_KHZ_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "display_bus_khz",
+        "Bus clock achieved by the left half's last full display flush"
+    )
+    .unwrap()
+});
+
+static PHANTOM_PRESSES_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "phantom_presses",
+        "Presses that only lasted a single matrix scan",
+        &["side"]
+    )
+    .unwrap()
+});
+
+static LATENCY_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "keypress_latency",
+        "Key presses by how long they took to reach a HID report, or the LEDs for the lights \
+         path. The counts wrap at 65536",
+        &["path", "bucket"]
+    )
+    .unwrap()
+});
+
 static HTTP_CLIENT: Lazy<Client> = Lazy::new(|| {
     Client::builder()
         .timeout(Duration::from_secs(10))
@@ -26,7 +250,9 @@ static HTTP_CLIENT: Lazy<Client> = Lazy::new(|| {
         .unwrap()
 });
 
-/// Extract metrics from the keyboard
+/// Extract metrics from the keyboard. With a port to each half, each half's
+/// are pushed separately with a `side` label. The keyboard's clock is kept
+/// set from the host's while this runs.
 #[derive(Debug, clap::Parser)]
 pub struct MetricsOpts {
     #[clap(short, long, default_value = "http://127.0.0.1:9091")]
@@ -37,71 +263,228 @@ pub struct MetricsOpts {
 
 impl MetricsOpts {
     pub async fn execute(self) -> color_eyre::Result<()> {
-        let mut port = open_port(self.port.as_deref())?;
-
-        let mut interval = interval(Duration::from_secs(5));
-        let mut buf = [0u8; 64];
-        let mut accumulator = CobsAccumulator::<128>::new();
-        let mut count = 0u64;
+        let mut conn = open_keyboard(self.port.as_deref()).await?;
         info!("counter: {}", KEYPRESS_COUNTER.get());
+        let mut time_synced: Option<Instant> = None;
 
         loop {
-            let buf = select! {
-                _ = interval.tick() => {
-                    let cmd = CmdOrAck::Cmd(Command::new(HostToKeyboard::RequestStats));
-                    let send_buf = postcard::to_allocvec_cobs(&cmd).map_err(|e| eyre!("Serde error: {}", e))?;
-                    let _ = port.write_all(&send_buf).await;
-                    None
-                },
-                Ok(len) = port.read(&mut buf) => {
-                    Some(&buf[..len])
+            if time_synced.is_none_or(|t| t.elapsed() >= TIME_SYNC_PERIOD) {
+                // the left half passes it on to the right
+                let seconds_of_day = local_seconds_of_day()?;
+                conn.link_for(KeyboardSide::Left)
+                    .send(HostToKeyboard::SetTime { seconds_of_day })
+                    .await?;
+                time_synced = Some(Instant::now());
+            }
+
+            let mut links = conn.links();
+            // the halves take turns, each filling in the metrics and then
+            // pushing them as its own group
+            let period = STATS_PERIOD / links.len() as u32;
+            for (side, link) in links.iter_mut() {
+                if side.is_some() {
+                    reset_metrics();
                 }
-            };
-
-            if let Some(mut window) = buf {
-                'cobs: while !window.is_empty() {
-                    window = match accumulator.feed(window) {
-                        postcard::FeedResult::Consumed => break 'cobs,
-                        postcard::FeedResult::OverFull(buf) => buf,
-                        postcard::FeedResult::DeserError(buf) => buf,
-                        postcard::FeedResult::Success { data, remaining } => {
-                            let data: CmdOrAck<KeyboardToHost> = data;
-
-                            match data {
-                                CmdOrAck::Cmd(c) => {
-                                    if c.validate() {
-                                        info!("cmd: {:?}", c);
-                                        let ack = CmdOrAck::<HostToKeyboard>::Ack(c.ack());
-                                        let send_buf = postcard::to_allocvec_cobs(&ack)
-                                            .map_err(|e| eyre!("Serde error: {}", e))?;
-                                        let _ = port.write_all(&send_buf).await;
-                                        match c.cmd {
-                                            KeyboardToHost::Stats { keypresses } => {
-                                                let keypresses = keypresses as u64;
-                                                let delta = keypresses - count;
-                                                KEYPRESS_COUNTER.inc_by(delta);
-                                                count = keypresses;
-
-                                                push_metrics(&self.prometheus_gateway).await?;
-                                            }
-                                        }
-                                    } else {
-                                    }
-                                }
-                                CmdOrAck::Ack(_) => {}
-                            }
-
-                            remaining
-                        }
+                link.send(HostToKeyboard::RequestStats).await?;
+                link.send(HostToKeyboard::RequestLatency).await?;
+
+                let mut stats = false;
+                let deadline = Instant::now() + period;
+                while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                    if let Some(msg) = link.recv_timeout(remaining).await? {
+                        info!("cmd: {:?}", msg);
+                        stats |= record(msg);
                     }
                 }
+
+                if stats {
+                    push_metrics(&self.prometheus_gateway, *side).await?;
+                }
             }
         }
     }
 }
 
-async fn push_metrics(url: &url::Url) -> Result<()> {
-    let url = url.join("/metrics/job/keyboard_worker")?;
+/// Clear what the last half filled in, so none of it is pushed again under
+/// the next half's `side`
+fn reset_metrics() {
+    for counter in [
+        &*KEYPRESS_COUNTER,
+        &*LEFT_KEYPRESS_COUNTER,
+        &*RIGHT_KEYPRESS_COUNTER,
+    ] {
+        counter.reset();
+    }
+    CPS_GAUGE.set(0.0);
+    for gauge in [
+        &*UPTIME_GAUGE,
+        &*CPU_BUSY_GAUGE,
+        &*CORRUPT_FRAMES_GAUGE,
+        &*LINK_READ_ERRORS_GAUGE,
+        &*LINK_RESYNCS_GAUGE,
+        &*LINK_UP_GAUGE,
+        &*LINK_LOSSES_GAUGE,
+        &*RETRANSMITS_GAUGE,
+        &*FAILED_SENDS_GAUGE,
+        &*SETTINGS_ERASES_GAUGE,
+        &*REJECTED_PIXEL_WRITES_GAUGE,
+        &*FIRMWARE_MISMATCH_GAUGE,
+        &*PRESENCE_MODE_GAUGE,
+        &*SAFE_MODE_GAUGE,
+        &*HEAP_HIGH_WATER_GAUGE,
+        &*OOM_AVERTED_GAUGE,
+        &*DISPLAY_BUS_BUSY_GAUGE,
+        &*DISPLAY_BUS_KHZ_GAUGE,
+    ] {
+        gauge.set(0);
+    }
+    for gauges in [
+        &*LINK_ERRORS_GAUGE,
+        &*SOURCE_PRESSES_GAUGE,
+        &*CHANNEL_DROPS_GAUGE,
+        &*LINK_QUEUE_HIGH_WATER_GAUGE,
+        &*DISPLAY_FLUSH_GAUGE,
+        &*PHANTOM_PRESSES_GAUGE,
+        &*LATENCY_GAUGE,
+    ] {
+        gauges.reset();
+    }
+}
+
+/// Update the metrics from a message from the keyboard, returning whether it
+/// was the keypress count
+fn record(msg: KeyboardToHost) -> bool {
+    if let KeyboardToHost::Stats { keypresses } = msg {
+        // the count is the keyboard's lifetime count kept in flash, and with
+        // a port to each half it alternates between them
+        KEYPRESS_COUNTER.reset();
+        KEYPRESS_COUNTER.inc_by(keypresses as u64);
+        return true;
+    } else if let KeyboardToHost::StatsV2 {
+        left_keypresses,
+        right_keypresses,
+        uptime_secs,
+        cps_hundredths,
+        ..
+    } = msg
+    {
+        // since boot, so set rather than added to
+        LEFT_KEYPRESS_COUNTER.reset();
+        LEFT_KEYPRESS_COUNTER.inc_by(left_keypresses as u64);
+        RIGHT_KEYPRESS_COUNTER.reset();
+        RIGHT_KEYPRESS_COUNTER.inc_by(right_keypresses as u64);
+        UPTIME_GAUGE.set(uptime_secs as i64);
+        CPS_GAUGE.set(cps_hundredths as f64 / 100.0);
+        return true;
+    } else if let KeyboardToHost::DebugStats {
+        cpu_busy_pct,
+        presses_by_source,
+        corrupt_frames,
+        retransmits,
+        failed_sends,
+        channel_drops,
+        settings_erases,
+        rejected_pixel_writes,
+        firmware_mismatch,
+        command_queue_high_water,
+        ack_queue_high_water,
+        presence_mode,
+        link_read_errors,
+        link_errors,
+        link_resyncs,
+        link_up,
+        link_losses,
+        display_bus,
+        phantom_presses,
+        safe_mode,
+        heap_high_water,
+        oom_averted,
+    } = msg
+    {
+        LINK_QUEUE_HIGH_WATER_GAUGE
+            .with_label_values(&["command"])
+            .set(command_queue_high_water as i64);
+        LINK_QUEUE_HIGH_WATER_GAUGE
+            .with_label_values(&["ack"])
+            .set(ack_queue_high_water as i64);
+        FIRMWARE_MISMATCH_GAUGE.set(firmware_mismatch as i64);
+        PRESENCE_MODE_GAUGE.set(presence_mode as i64);
+        SAFE_MODE_GAUGE.set(safe_mode.is_some() as i64);
+        HEAP_HIGH_WATER_GAUGE.set(heap_high_water as i64);
+        OOM_AVERTED_GAUGE.set(oom_averted as i64);
+        SETTINGS_ERASES_GAUGE.set(settings_erases as i64);
+        REJECTED_PIXEL_WRITES_GAUGE.set(rejected_pixel_writes as i64);
+        CPU_BUSY_GAUGE.set(cpu_busy_pct as i64);
+        CORRUPT_FRAMES_GAUGE.set(corrupt_frames as i64);
+        LINK_READ_ERRORS_GAUGE.set(link_read_errors as i64);
+        LINK_RESYNCS_GAUGE.set(link_resyncs as i64);
+        LINK_UP_GAUGE.set(link_up as i64);
+        LINK_LOSSES_GAUGE.set(link_losses as i64);
+        RETRANSMITS_GAUGE.set(retransmits as i64);
+        FAILED_SENDS_GAUGE.set(failed_sends as i64);
+        for (stat, us) in [
+            ("last", display_bus.last_flush_us),
+            ("avg", display_bus.avg_flush_us),
+            ("max", display_bus.max_flush_us),
+        ] {
+            DISPLAY_FLUSH_GAUGE
+                .with_label_values(&[stat])
+                .set(us as i64);
+        }
+        DISPLAY_BUS_BUSY_GAUGE.set(display_bus.busy_pct as i64);
+        DISPLAY_BUS_KHZ_GAUGE.set(display_bus.khz as i64);
+        for (side, presses) in ["left", "right"].iter().zip(phantom_presses) {
+            PHANTOM_PRESSES_GAUGE
+                .with_label_values(&[side])
+                .set(presses as i64);
+        }
+        for (source, presses) in EventSource::ALL.iter().zip(presses_by_source) {
+            SOURCE_PRESSES_GAUGE
+                .with_label_values(&[&format!("{:?}", source)])
+                .set(presses as i64);
+        }
+        for (kind, errors) in LinkErrorKind::ALL.iter().zip(link_errors) {
+            LINK_ERRORS_GAUGE
+                .with_label_values(&[&format!("{:?}", kind)])
+                .set(errors as i64);
+        }
+        for (channel, drops) in DropChannel::ALL.iter().zip(channel_drops) {
+            CHANNEL_DROPS_GAUGE
+                .with_label_values(&[&format!("{:?}", channel)])
+                .set(drops as i64);
+        }
+    } else if let KeyboardToHost::Latency { synthetic, buckets } = msg {
+        let path = if synthetic { "synthetic" } else { "typed" };
+        record_latency(path, buckets);
+    } else if let KeyboardToHost::LightLatency { buckets } = msg {
+        record_latency("lights", buckets);
+    }
+
+    false
+}
+
+fn record_latency(path: &str, buckets: [u16; LATENCY_BUCKETS]) {
+    for (idx, presses) in buckets.iter().enumerate() {
+        LATENCY_GAUGE
+            .with_label_values(&[path, &bucket_label(idx)])
+            .set(*presses as i64);
+    }
+}
+
+/// Push the metrics, labelled with `side` if they're from one half's own
+/// port
+async fn push_metrics(url: &url::Url, side: Option<KeyboardSide>) -> Result<()> {
+    // each keyboard gets its own group, so one instance can run per keyboard
+    let mut path = match keyboards::label()? {
+        Some(label) => format!("/metrics/job/keyboard_worker/keyboard/{}", label),
+        None => "/metrics/job/keyboard_worker".to_owned(),
+    };
+    match side {
+        Some(KeyboardSide::Left) => path.push_str("/side/left"),
+        Some(KeyboardSide::Right) => path.push_str("/side/right"),
+        None => {}
+    }
+    let url = url.join(&path)?;
 
     let encoder = ProtobufEncoder::new();
     let mut buf = Vec::new();
@@ -0,0 +1,22 @@
+use color_eyre::Result;
+use keyboard_shared::HostToKeyboard;
+
+use crate::host_link::HostLink;
+
+/// Have the keyboard save anything it only writes to flash now and then,
+/// like the keypress count, before it's unplugged or the host powers off
+#[derive(Debug, clap::Parser)]
+pub struct PrepareShutdownOpts {
+    port: Option<String>,
+}
+
+impl PrepareShutdownOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        link.send(HostToKeyboard::PrepareShutdown).await?;
+        println!("The keyboard is saving, it's safe to unplug in a moment");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+//! A pseudo terminal, standing in for the keyboard's serial port so the
+//! other commands can talk to the emulator.
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+};
+
+use color_eyre::Result;
+use tokio::io::unix::AsyncFd;
+
+pub struct Pty {
+    master: AsyncFd<File>,
+    /// Held open so reads don't fail while no client has the port open
+    _slave: File,
+    path: String,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl Pty {
+    pub fn open() -> Result<Self> {
+        let master = unsafe {
+            let fd = check(libc::posix_openpt(
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK,
+            ))?;
+            let master = File::from_raw_fd(fd);
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+            master
+        };
+
+        let mut name = [0 as libc::c_char; 64];
+        check(unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) })?;
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let slave = File::options().read(true).write(true).open(&path)?;
+
+        // nothing the host sends should be echoed back or translated
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+        }
+
+        Ok(Self {
+            master: AsyncFd::new(master)?,
+            _slave: slave,
+            path,
+        })
+    }
+
+    /// Where clients open the port
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.master.readable().await?;
+            match guard.try_io(|fd| fd.get_ref().read(buf)) {
+                Ok(r) => return r,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let mut guard = self.master.writable().await?;
+            match guard.try_io(|fd| fd.get_ref().write(buf)) {
+                Ok(len) => buf = &buf[len?..],
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::HostToKeyboard;
+
+use crate::host_link::HostLink;
+
+/// Try out a layout without reflashing by making keys send other keycodes,
+/// until they're cleared or the keyboard resets
+#[derive(Debug, clap::Parser)]
+pub struct RemapOpts {
+    #[clap(subcommand)]
+    command: RemapCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum RemapCommand {
+    /// Make the key at row, col of the layout send a HID keycode, like 4 or
+    /// 0x04 for A, while on `layer`
+    Set {
+        layer: u8,
+        row: u8,
+        col: u8,
+        #[clap(parse(try_from_str = parse_keycode))]
+        keycode: u8,
+
+        port: Option<String>,
+    },
+    /// Give every key back to the keymap
+    Clear { port: Option<String> },
+}
+
+fn parse_keycode(value: &str) -> Result<u8> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| eyre!("Bad keycode {}: {}", value, e))
+}
+
+impl RemapOpts {
+    pub async fn execute(self) -> Result<()> {
+        match self.command {
+            RemapCommand::Set {
+                layer,
+                row,
+                col,
+                keycode,
+                port,
+            } => {
+                let mut link = HostLink::open(port.as_deref())?;
+                link.send(HostToKeyboard::SetKeyAction {
+                    layer,
+                    row,
+                    col,
+                    keycode,
+                })
+                .await?;
+            }
+            RemapCommand::Clear { port } => {
+                let mut link = HostLink::open(port.as_deref())?;
+                link.send(HostToKeyboard::ClearRemaps).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
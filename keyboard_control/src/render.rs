@@ -5,24 +5,49 @@ use std::{
     time::Duration,
 };
 
-use bitvec::{bitarr, order::Lsb0};
+use bitvec::{array::BitArray, bitarr, order::Lsb0};
 use color_eyre::{eyre::eyre, Help, Result};
 use image::{
     imageops::{dither, grayscale, resize, BiLevel, FilterType},
-    AnimationDecoder,
+    AnimationDecoder, Frame, GrayImage, ImageDecoder, Rgba, RgbaImage,
 };
 use itertools::Itertools;
-use keyboard_shared::{CmdOrAck, Command, HostToKeyboard};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    time::Instant,
+use keyboard_shared::{
+    CmdOrAck, Command, FrameCrc, HostToKeyboard, KeyboardSide, KeyboardToHost, PackedRows,
+    PACKED_ROWS_MAX,
 };
-use tokio_serial::SerialStream;
+use tokio::time::Instant;
 use tracing::Instrument;
 
-use crate::util::open_port;
+use crate::{
+    host_link::HostLink,
+    util::{open_keyboard, KeyboardConnection},
+};
+
+type RowBits = BitArray<[u8; 4], Lsb0>;
+
+/// The size of each display `WritePixels` can address
+const WIDTH: u8 = 32;
+const HEIGHT: u8 = 128;
+
+/// How long to give the keyboard to turn away a frame that stays up
+const NACK_WAIT: Duration = Duration::from_millis(100);
+/// Times a frame that stays up is sent again before giving up on it
+const MAX_RESENDS: usize = 3;
+
+/// What streaming frames to the keyboard carries from one frame to the next
+#[derive(Default)]
+pub struct Stream {
+    /// While the link between the halves is degraded the right half drops
+    /// its pixels anyway, so they aren't sent
+    link_degraded: bool,
+    next_frame: u8,
+    /// Frames turned away by the keyboard since the last one was sent
+    nacked: Vec<(KeyboardSide, u8)>,
+}
 
-/// Render a gif to the keyboard displays
+/// Render a gif to the keyboard displays. With a port to each half, each
+/// half's rows go down its own port at the same time.
 #[derive(Debug, clap::Parser)]
 pub struct RenderOpts {
     #[clap(parse(from_os_str))]
@@ -31,48 +56,350 @@ pub struct RenderOpts {
     #[clap(long, short)]
     no_loop: bool,
 
+    /// Send at most this many frames a second, the frames in between are
+    /// skipped
+    #[clap(long)]
+    max_fps: Option<f32>,
+
+    /// Send every row uncompressed, for comparing how many bytes frames take
+    #[clap(long)]
+    raw: bool,
+
     port: Option<String>,
 }
 
 impl RenderOpts {
     pub async fn execute(self) -> color_eyre::Result<()> {
-        let mut port = open_port(self.port.as_deref())?;
+        let mut conn = open_keyboard(self.port.as_deref()).await?;
+        check_displays(&mut conn).await?;
 
         let mut gif = File::open(&self.file).section("Couldn't find your gif")?;
+        let mut stream = Stream::default();
+        let mut pacer = Pacer::new(self.max_fps);
 
         loop {
-            let decoder =
-                image::codecs::gif::GifDecoder::new(&gif).section("Are you sure this is a gif")?;
+            play(
+                &gif,
+                self.no_loop,
+                self.raw,
+                &mut conn,
+                &mut stream,
+                &mut pacer,
+            )
+            .await?;
+
+            if self.no_loop {
+                break;
+            }
+
+            tracing::info!("{}", pacer.summary());
+            gif.seek(SeekFrom::Start(0))?;
+        }
+
+        println!("{}", pacer.summary());
+
+        Ok(())
+    }
+}
+
+/// Play through the gif once, `last_pass` makes sure its final frame is sent
+async fn play(
+    gif: &File,
+    last_pass: bool,
+    raw: bool,
+    conn: &mut KeyboardConnection,
+    stream: &mut Stream,
+    pacer: &mut Pacer,
+) -> Result<()> {
+    let decoder = image::codecs::gif::GifDecoder::new(gif).section("Are you sure this is a gif")?;
+    let (width, height) = decoder.dimensions();
+    let mut canvas = RgbaImage::from_pixel(width, height, BACKGROUND);
+    let mut frames = decoder.into_frames().peekable();
+
+    while let Some(frame) = frames.next() {
+        let frame = frame.section("Some frame is borked")?;
+        let delay = Duration::from(frame.delay());
 
-            for frame in decoder.into_frames() {
-                let frame = frame.section("Some frame is borked")?;
+        // skipped frames still need drawing, later ones only cover
+        // the parts that changed
+        composite(&mut canvas, &frame);
 
-                let next_frame = Instant::now() + frame.delay().into();
+        // the last image shown has to be the gif's last frame
+        let last = last_pass && frames.peek().is_none();
+        if last || pacer.should_send(delay) {
+            let image = for_displays(&canvas);
+
+            let started = Instant::now();
+            // a frame turned away is usually replaced soon enough by the
+            // next, but nothing replaces the last
+            let span = tracing::info_span!("sending frame", frame_time = ?delay);
+            let bytes = if last {
+                emit_lasting_image(&image, raw, conn, stream)
+                    .instrument(span)
+                    .await?
+            } else {
+                emit_image(&image, raw, conn, stream)
+                    .instrument(span)
+                    .await?
+            };
+            pacer.sent(started.elapsed(), bytes);
+        } else {
+            pacer.skipped();
+        }
+
+        pacer.advance(delay).await;
+    }
+
+    Ok(())
+}
+
+/// Decides which frames to send so playback keeps to the gif's timing when
+/// the link can't keep up
+struct Pacer {
+    start: Instant,
+    /// How far through the gif's timeline playback is, over every loop
+    position: Duration,
+    /// Shortest time between sent frames, from `--max-fps`
+    min_interval: Duration,
+    /// Timeline position the next frame may be sent at. It moves on by
+    /// `min_interval` each time so skips are spread out evenly.
+    next_allowed: Duration,
+    /// Smoothed time taken to send a frame
+    transfer: Duration,
+    /// Bytes written for all the frames sent
+    bytes: usize,
+    sent: u32,
+    skipped: u32,
+}
+
+impl Pacer {
+    fn new(max_fps: Option<f32>) -> Self {
+        Self {
+            start: Instant::now(),
+            position: Duration::ZERO,
+            min_interval: max_fps.map_or(Duration::ZERO, |fps| {
+                Duration::from_secs_f32(1.0 / fps.max(0.1))
+            }),
+            next_allowed: Duration::ZERO,
+            transfer: Duration::ZERO,
+            bytes: 0,
+            sent: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Whether the frame that starts at the current position and lasts
+    /// `delay` should be sent. Frames that would mostly arrive after they
+    /// should already have been replaced are skipped, as are any over the
+    /// frame rate cap.
+    fn should_send(&self, delay: Duration) -> bool {
+        let frame_end = self.start + self.position + delay;
+        let on_time = Instant::now() + self.transfer / 2 <= frame_end;
+
+        on_time && self.position >= self.next_allowed
+    }
+
+    fn sent(&mut self, took: Duration, bytes: usize) {
+        self.sent += 1;
+        self.bytes += bytes;
+        self.transfer = if self.sent == 1 {
+            took
+        } else {
+            (self.transfer * 3 + took) / 4
+        };
+
+        self.next_allowed += self.min_interval;
+        // don't bank sends from a stretch where we were behind
+        if self.next_allowed < self.position {
+            self.next_allowed = self.position + self.min_interval;
+        }
+    }
+
+    fn skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Move on past a frame lasting `delay`, waiting until the next one is due
+    async fn advance(&mut self, delay: Duration) {
+        self.position += delay;
+        tokio::time::sleep_until(self.start + self.position).await;
+    }
 
-                let mut image = grayscale(&resize(frame.buffer(), 64, 128, FilterType::Lanczos3));
-                dither(&mut image, &BiLevel);
-                emit_image(&image, &mut port)
-                    .instrument(tracing::info_span!("sending frame", frame_time = ?Duration::from(frame.delay())))
-                    .await?;
+    fn summary(&self) -> String {
+        let elapsed = self.start.elapsed().as_secs_f32();
+        let total = (self.sent + self.skipped).max(1);
 
-                tokio::time::sleep_until(next_frame).await;
+        format!(
+            "{:.1} fps, {:.0}% of frames skipped, {:?} and {} bytes per frame sent",
+            self.sent as f32 / elapsed,
+            self.skipped as f32 * 100.0 / total as f32,
+            self.transfer,
+            self.bytes / self.sent.max(1) as usize
+        )
+    }
+}
+
+/// Shown wherever the gif is transparent, which is an unlit pixel
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// Draw `frame` onto the canvas at its offset. The decoder has already
+/// applied the previous frame's disposal method, so a transparent pixel here
+/// means the background shows through rather than the last frame.
+fn composite(canvas: &mut RgbaImage, frame: &Frame) {
+    let buffer = frame.buffer();
+
+    for (x, y, pixel) in buffer.enumerate_pixels() {
+        let (cx, cy) = (frame.left() + x, frame.top() + y);
+        if cx >= canvas.width() || cy >= canvas.height() {
+            continue;
+        }
+
+        let pixel = if pixel.0[3] == 0 { BACKGROUND } else { *pixel };
+        canvas.put_pixel(cx, cy, pixel);
+    }
+}
+
+/// The canvas scaled down to both displays and dithered to black and white
+fn for_displays(canvas: &RgbaImage) -> GrayImage {
+    let mut image = grayscale(&resize(canvas, 64, 128, FilterType::Lanczos3));
+    dither(&mut image, &BiLevel);
+    image
+}
+
+/// [`check_display`] for both halves, each on the link that handles it
+pub async fn check_displays(conn: &mut KeyboardConnection) -> Result<()> {
+    for side in [KeyboardSide::Left, KeyboardSide::Right] {
+        check_display(conn.link_for(side), side).await?;
+    }
+    Ok(())
+}
+
+/// Make sure the display is one we know how to address, the keyboard takes
+/// care of how it's mounted
+pub async fn check_display(link: &mut HostLink, side: KeyboardSide) -> Result<()> {
+    link.send(HostToKeyboard::RequestDisplayInfo { side })
+        .await?;
+
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(1))
+            .await?
+            .ok_or_else(|| eyre!("Timed out asking about the {:?} display", side))?;
+
+        if let KeyboardToHost::DisplayInfo {
+            side: s,
+            width,
+            height,
+            rotation,
+            transform,
+        } = msg
+        {
+            if s != side {
+                continue;
             }
 
-            if self.no_loop {
-                break;
+            tracing::info!(?side, width, height, ?rotation, ?transform, "display info");
+
+            if (width, height) != (WIDTH, HEIGHT) {
+                return Err(eyre!(
+                    "The {:?} display is {}x{}, only {}x{} is supported",
+                    side,
+                    width,
+                    height,
+                    WIDTH,
+                    HEIGHT
+                ));
             }
 
-            gif.seek(SeekFrom::Start(0))?;
+            return Ok(());
         }
+    }
+}
 
-        Ok(())
+/// Keep an eye on the keyboard while streaming. While the link between the
+/// halves is degraded it drops pixels for the right half anyway, so we stop
+/// sending them.
+async fn poll_link(
+    link: &mut HostLink,
+    link_degraded: &mut bool,
+    nacked: &mut Vec<(KeyboardSide, u8)>,
+) -> Result<()> {
+    // the link's reader has already decoded whatever arrived, so no need to
+    // wait around for more
+    link.poll(Duration::ZERO).await?;
+
+    while let Some(msg) = link.try_recv() {
+        match msg {
+            KeyboardToHost::LinkDegraded(degraded) => {
+                if degraded {
+                    tracing::warn!("link between the halves degraded, pausing the right display");
+                } else {
+                    tracing::info!("link between the halves recovered");
+                }
+                *link_degraded = degraded;
+            }
+            KeyboardToHost::PixelsRejected { side, row } => {
+                tracing::warn!(?side, row, "keyboard rejected pixels");
+            }
+            KeyboardToHost::FrameNack { side, frame_id } => {
+                tracing::warn!(
+                    ?side,
+                    frame_id,
+                    "keyboard dropped a frame that arrived damaged"
+                );
+                nacked.push((side, frame_id));
+            }
+            _ => {}
+        }
     }
+
+    Ok(())
 }
 
-async fn emit_image(
+/// [`emit_image`] for an image that stays up, sending it again for as long
+/// as the keyboard turns it away
+pub async fn emit_lasting_image(
     image: &image::ImageBuffer<image::Luma<u8>, Vec<u8>>,
-    port: &mut SerialStream,
-) -> Result<()> {
+    raw: bool,
+    conn: &mut KeyboardConnection,
+    stream: &mut Stream,
+) -> Result<usize> {
+    let mut bytes = 0;
+
+    for attempt in 0..=MAX_RESENDS {
+        if attempt > 0 {
+            tracing::info!("sending the frame again");
+        }
+        bytes += emit_image(image, raw, conn, stream).await?;
+        let frame_id = stream.next_frame.wrapping_sub(1);
+
+        tokio::time::sleep(NACK_WAIT).await;
+        for (_, link) in conn.links() {
+            poll_link(link, &mut stream.link_degraded, &mut stream.nacked).await?;
+        }
+
+        if !stream.nacked.iter().any(|(_, id)| *id == frame_id) {
+            return Ok(bytes);
+        }
+    }
+
+    Err(eyre!(
+        "The keyboard turned the frame away {} times",
+        MAX_RESENDS + 1
+    ))
+}
+
+/// Send a frame, returning how many bytes it took. Each half's rows are
+/// wrapped in `BeginFrame` and `EndFrame`, so a frame that arrives damaged
+/// is dropped rather than half drawn. Over a single link the halves' rows
+/// are interleaved so both displays fill in together, over a link to each
+/// half they're sent at once.
+pub async fn emit_image(
+    image: &image::ImageBuffer<image::Luma<u8>, Vec<u8>>,
+    raw: bool,
+    conn: &mut KeyboardConnection,
+    stream: &mut Stream,
+) -> Result<usize> {
     let mut lhs = [bitarr![u8, Lsb0; 1; 32]; 128];
     let mut rhs = [bitarr![u8, Lsb0; 1; 32]; 128];
 
@@ -84,49 +411,415 @@ async fn emit_image(
         buf[y as usize].set(x as usize, p.0[0] > 127);
     }
 
-    let mut o_buf = Vec::new();
+    let frame_id = stream.next_frame;
+    stream.next_frame = stream.next_frame.wrapping_add(1);
+    stream.nacked.clear();
 
-    let lhs_iter = lhs.chunks_exact(2).enumerate().map(|(row_idx, rows)| {
-        let cmd = HostToKeyboard::WritePixels {
-            side: keyboard_shared::KeyboardSide::Left,
-            row: (2 * row_idx) as u8,
-            data_0: rows[0].data,
-            data_1: rows[1].data,
-        };
-        CmdOrAck::Cmd(Command::new(cmd))
-    });
+    let lhs_cmds = frame_cmds(KeyboardSide::Left, &lhs, frame_id, raw);
+    // nothing at all while the right half's rows are skipped
+    let rhs_cmds = if stream.link_degraded {
+        Vec::new()
+    } else {
+        frame_cmds(KeyboardSide::Right, &rhs, frame_id, raw)
+    };
 
-    let rhs_iter = rhs.chunks_exact(2).enumerate().map(|(row_idx, rows)| {
-        let cmd = HostToKeyboard::WritePixels {
-            side: keyboard_shared::KeyboardSide::Right,
-            row: (2 * row_idx) as u8,
-            data_0: rows[0].data,
-            data_1: rows[1].data,
-        };
-        CmdOrAck::Cmd(Command::new(cmd))
+    match conn {
+        KeyboardConnection::Single(link) => {
+            let cmds = lhs_cmds.into_iter().interleave(rhs_cmds);
+            send_cmds(link, cmds, &mut stream.link_degraded, &mut stream.nacked).await
+        }
+        KeyboardConnection::Dual { left, right } => {
+            // each port's own report of the link between the halves doesn't
+            // matter here
+            let (mut left_degraded, mut right_degraded) = (false, false);
+            let (mut left_nacked, mut right_nacked) = (Vec::new(), Vec::new());
+            let (l, r) = tokio::try_join!(
+                send_cmds(
+                    left,
+                    lhs_cmds.into_iter(),
+                    &mut left_degraded,
+                    &mut left_nacked
+                ),
+                send_cmds(
+                    right,
+                    rhs_cmds.into_iter(),
+                    &mut right_degraded,
+                    &mut right_nacked
+                )
+            )?;
+            stream
+                .nacked
+                .extend(left_nacked.into_iter().chain(right_nacked));
+            Ok(l + r)
+        }
+    }
+}
+
+/// One side's rows as a frame, between its `BeginFrame` and `EndFrame`
+fn frame_cmds(
+    side: KeyboardSide,
+    rows: &[RowBits],
+    frame_id: u8,
+    raw: bool,
+) -> Vec<CmdOrAck<HostToKeyboard>> {
+    let mut crc = FrameCrc::new();
+    let mut cmds = vec![HostToKeyboard::BeginFrame { side, frame_id }];
+
+    for (row_idx, rows) in rows.chunks_exact(2).enumerate() {
+        let row = 2 * row_idx;
+        crc.add_rows(row as u8, [rows[0].data, rows[1].data]);
+        cmds.push(write_rows(side, row, rows, raw));
+    }
+
+    cmds.push(HostToKeyboard::EndFrame {
+        side,
+        frame_id,
+        crc: crc.value(),
     });
 
-    // let rhs_iter = std::iter::empty();
+    cmds.into_iter()
+        .map(|cmd| CmdOrAck::Cmd(Command::new(cmd)))
+        .collect()
+}
+
+/// Write `cmds` in batches, keeping an eye on the keyboard in between.
+/// Returns how many bytes they took.
+async fn send_cmds(
+    link: &mut HostLink,
+    cmds: impl Iterator<Item = CmdOrAck<HostToKeyboard>>,
+    link_degraded: &mut bool,
+    nacked: &mut Vec<(KeyboardSide, u8)>,
+) -> Result<usize> {
+    let mut o_buf = Vec::new();
+    let mut bytes = 0;
 
-    for cmd in lhs_iter.interleave(rhs_iter) {
+    for cmd in cmds {
         let buf = postcard::to_allocvec_cobs(&cmd).map_err(|e| eyre!("Serde error: {}", e))?;
+        bytes += buf.len();
         if (o_buf.len() + buf.len()) > 64 {
-            port.write_all(&o_buf).await?;
+            link.write_raw(&o_buf).await?;
             o_buf.clear();
-            let mut buf = [0u8; 128];
-            let _ = tokio::time::timeout(Duration::from_micros(100), port.read(&mut buf)).await;
+            poll_link(link, link_degraded, nacked).await?;
         }
         o_buf.extend_from_slice(&buf);
     }
 
     if !o_buf.is_empty() {
-        let _ = port.write_all(&o_buf).await;
-        port.write_all(&o_buf)
+        link.write_raw(&o_buf)
             .instrument(tracing::debug_span!("sending remainder", len = o_buf.len()))
             .await?;
-        let mut buf = [0u8; 128];
-        let _ = tokio::time::timeout(Duration::from_micros(100), port.read(&mut buf)).await;
+        poll_link(link, link_degraded, nacked).await?;
     }
 
-    Ok(())
+    Ok(bytes)
+}
+
+/// The command writing a pair of rows, packed if that makes it smaller
+fn write_rows(side: KeyboardSide, row: usize, rows: &[RowBits], raw: bool) -> HostToKeyboard {
+    let (data_0, data_1) = (rows[0].data, rows[1].data);
+    let row = row as u8;
+
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&data_0);
+    bytes[4..].copy_from_slice(&data_1);
+
+    match pack_rows(bytes) {
+        Some(data) if !raw => HostToKeyboard::WritePackedPixels { side, row, data },
+        _ => HostToKeyboard::WritePixels {
+            side,
+            row,
+            data_0,
+            data_1,
+        },
+    }
+}
+
+/// Run length encode a pair of rows as described on `PackedRows`, `None` if
+/// that doesn't make them any smaller
+fn pack_rows(bytes: [u8; 8]) -> Option<PackedRows> {
+    let mut out = Vec::new();
+    let mut literals = Vec::new();
+    let flush_literals = |out: &mut Vec<u8>, literals: &mut Vec<u8>| {
+        if !literals.is_empty() {
+            out.push(literals.len() as u8 - 1);
+            out.append(literals);
+        }
+    };
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = bytes[i..].iter().take_while(|b| **b == bytes[i]).count();
+        if run >= 2 {
+            flush_literals(&mut out, &mut literals);
+            out.push(PackedRows::RUN | (run as u8 - 1));
+            out.push(bytes[i]);
+        } else {
+            literals.push(bytes[i]);
+        }
+        i += run;
+    }
+    flush_literals(&mut out, &mut literals);
+
+    // it's sent with its length
+    if out.len() + 1 >= PACKED_ROWS_MAX {
+        return None;
+    }
+    PackedRows::new(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use image::{ImageBuffer, Luma};
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::emulator;
+
+    /// What `side`'s display shows, a pair of rows at a time
+    async fn shown(link: &mut HostLink, side: KeyboardSide) -> Result<Vec<[[u8; 4]; 2]>> {
+        let mut shown = Vec::new();
+        for row in (0..128).step_by(2) {
+            link.send(HostToKeyboard::ReadPixels { side, row }).await?;
+            loop {
+                match link.recv_timeout(Duration::from_secs(1)).await? {
+                    Some(KeyboardToHost::PixelRow {
+                        row: r,
+                        data_0,
+                        data_1,
+                    }) if r == row => break shown.push([data_0, data_1]),
+                    Some(_) => {}
+                    None => return Err(eyre!("No reply reading row {}", row)),
+                }
+            }
+        }
+        Ok(shown)
+    }
+
+    #[tokio::test]
+    async fn split_render_shows_the_same_frames() -> Result<()> {
+        let image = ImageBuffer::from_fn(64, 128, |x, y| {
+            Luma([if (x * 7 + y * 3) % 5 < 2 { 255 } else { 0 }])
+        });
+
+        let mut single = KeyboardConnection::Single(HostLink::open(Some(&emulator::spawn(
+            KeyboardSide::Left,
+        )?))?);
+        let mut dual = KeyboardConnection::Dual {
+            left: HostLink::open(Some(&emulator::spawn(KeyboardSide::Left)?))?,
+            right: HostLink::open(Some(&emulator::spawn(KeyboardSide::Right)?))?,
+        };
+        for conn in [&mut single, &mut dual] {
+            emit_image(&image, false, conn, &mut Stream::default()).await?;
+        }
+
+        for side in [KeyboardSide::Left, KeyboardSide::Right] {
+            let expected = shown(single.link_for(side), side).await?;
+            assert!(expected.iter().any(|rows| *rows != [[0; 4]; 2]));
+            assert_eq!(shown(dual.link_for(side), side).await?, expected);
+        }
+        Ok(())
+    }
+
+    /// Wait for the emulator to get through everything sent so far, it
+    /// handles commands in order
+    async fn settled(link: &mut HostLink) -> Result<()> {
+        let read = HostToKeyboard::ReadPixels {
+            side: KeyboardSide::Left,
+            row: 0,
+        };
+        link.send(read).await?;
+        loop {
+            match link.recv_timeout(Duration::from_secs(1)).await? {
+                Some(KeyboardToHost::PixelRow { .. }) => return Ok(()),
+                Some(_) => {}
+                None => return Err(eyre!("The emulator stopped answering")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn each_frame_flushes_each_side_once() -> Result<()> {
+        let image = ImageBuffer::from_fn(64, 128, |x, y| Luma([if x == y { 255 } else { 0 }]));
+        let flushes = |counts: &[Arc<[AtomicU32; 2]>]| {
+            [0, 1].map(|side| {
+                counts
+                    .iter()
+                    .map(|c| c[side].load(Ordering::Relaxed))
+                    .sum::<u32>()
+            })
+        };
+
+        let (path, single_counts) = emulator::spawn_counting_flushes(KeyboardSide::Left)?;
+        let mut single = KeyboardConnection::Single(HostLink::open(Some(&path))?);
+        let (left, left_counts) = emulator::spawn_counting_flushes(KeyboardSide::Left)?;
+        let (right, right_counts) = emulator::spawn_counting_flushes(KeyboardSide::Right)?;
+        let mut dual = KeyboardConnection::Dual {
+            left: HostLink::open(Some(&left))?,
+            right: HostLink::open(Some(&right))?,
+        };
+
+        for (conn, counts) in [
+            (&mut single, vec![single_counts]),
+            (&mut dual, vec![left_counts, right_counts]),
+        ] {
+            let mut stream = Stream::default();
+            for frame in 1..=3 {
+                emit_image(&image, false, conn, &mut stream).await?;
+                for side in [KeyboardSide::Left, KeyboardSide::Right] {
+                    settled(conn.link_for(side)).await?;
+                }
+                assert_eq!(flushes(&counts), [frame, frame]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Four small frames at offsets on a 64x128 gif, using transparency and
+    /// both keeping and clearing to the background between frames, see
+    /// `OPTIMISED_PIXELS` for what's drawn
+    const OPTIMISED_GIF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/optimised.gif");
+
+    /// Each frame of `OPTIMISED_GIF` as it's sent to the displays
+    fn optimised_frames() -> Result<Vec<GrayImage>> {
+        let decoder = image::codecs::gif::GifDecoder::new(File::open(OPTIMISED_GIF)?)?;
+        let (width, height) = decoder.dimensions();
+        let mut canvas = RgbaImage::from_pixel(width, height, BACKGROUND);
+        decoder
+            .into_frames()
+            .map(|frame| {
+                composite(&mut canvas, &frame?);
+                Ok(for_displays(&canvas))
+            })
+            .collect()
+    }
+
+    /// Pixels of `OPTIMISED_GIF` and whether they're lit in each frame
+    const OPTIMISED_PIXELS: [((u32, u32), [bool; 4]); 5] = [
+        // the first frame's square, kept throughout
+        ((5, 5), [true; 4]),
+        // in the middle of it, until the last frame draws over it in black
+        // leaving the rest transparent
+        ((8, 8), [true, true, true, false]),
+        // the second frame's square, cleared to the background after it
+        ((21, 41), [false, true, false, false]),
+        // the third frame's, kept
+        ((41, 81), [false, false, true, true]),
+        // never drawn on
+        ((30, 100), [false; 4]),
+    ];
+
+    #[test]
+    fn optimised_gif_frames_build_on_each_other() -> Result<()> {
+        let frames = optimised_frames()?;
+        assert_eq!(frames.len(), 4);
+
+        for ((x, y), lit) in OPTIMISED_PIXELS {
+            for (idx, (frame, lit)) in frames.iter().zip(lit).enumerate() {
+                assert_eq!(
+                    frame.get_pixel(x, y).0[0] > 127,
+                    lit,
+                    "({x}, {y}) in frame {idx}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn optimised_gif_ends_on_its_last_frame() -> Result<()> {
+        let mut conn = KeyboardConnection::Single(HostLink::open(Some(&emulator::spawn(
+            KeyboardSide::Left,
+        )?))?);
+        let gif = File::open(OPTIMISED_GIF)?;
+        play(
+            &gif,
+            true,
+            false,
+            &mut conn,
+            &mut Stream::default(),
+            &mut Pacer::new(None),
+        )
+        .await?;
+
+        let displays = [KeyboardSide::Left, KeyboardSide::Right];
+        let mut shown_on = Vec::new();
+        for side in displays {
+            shown_on.push(shown(conn.link_for(side), side).await?);
+        }
+        for ((x, y), lit) in OPTIMISED_PIXELS {
+            let (side, col) = ((x / 32) as usize, (x % 32) as usize);
+            let row = shown_on[side][y as usize / 2][y as usize % 2];
+            assert_eq!(row[col / 8] & (1 << (col % 8)) != 0, lit[3], "({x}, {y})");
+        }
+        Ok(())
+    }
+
+    fn round_trip(bytes: [u8; 8]) -> Option<[u8; 8]> {
+        let rows = pack_rows(bytes)?
+            .unpack()
+            .expect("packed rows don't unpack");
+        let mut out = [0; 8];
+        out[..4].copy_from_slice(&rows[0]);
+        out[4..].copy_from_slice(&rows[1]);
+        Some(out)
+    }
+
+    #[test]
+    fn blank_and_solid_rows_pack() {
+        for byte in [0, 0xff] {
+            let packed = pack_rows([byte; 8]).unwrap();
+            assert_eq!(packed.as_bytes(), [PackedRows::RUN | 7, byte]);
+        }
+    }
+
+    #[test]
+    fn rows_that_dont_shrink_are_sent_raw() {
+        assert!(pack_rows([1, 2, 3, 4, 5, 6, 7, 8]).is_none());
+
+        let blank = [RowBits::default(); 2];
+        let cmd = write_rows(KeyboardSide::Left, 0, &blank, false);
+        assert!(matches!(cmd, HostToKeyboard::WritePackedPixels { .. }));
+        // unless asked not to
+        let cmd = write_rows(KeyboardSide::Left, 0, &blank, true);
+        assert!(matches!(cmd, HostToKeyboard::WritePixels { .. }));
+    }
+
+    /// Rows like text on black, mostly blank or solid with the odd byte of
+    /// anything
+    fn text_like_rows() -> impl Strategy<Value = [u8; 8]> {
+        let byte = prop_oneof![4 => Just(0u8), 2 => Just(0xff), 1 => any::<u8>()];
+        proptest::collection::vec(byte, 8).prop_map(|v| v.try_into().unwrap())
+    }
+
+    fn packing_config() -> ProptestConfig {
+        ProptestConfig {
+            cases: 1024,
+            rng_seed: proptest::test_runner::RngSeed::Fixed(0x1459),
+            failure_persistence: None,
+            ..Default::default()
+        }
+    }
+
+    proptest! {
+        #![proptest_config(packing_config())]
+
+        #[test]
+        fn random_rows_round_trip(bytes in any::<[u8; 8]>()) {
+            if let Some(out) = round_trip(bytes) {
+                prop_assert_eq!(out, bytes);
+            }
+        }
+
+        #[test]
+        fn text_like_rows_round_trip(bytes in text_like_rows()) {
+            if let Some(out) = round_trip(bytes) {
+                prop_assert_eq!(out, bytes);
+            }
+        }
+    }
 }
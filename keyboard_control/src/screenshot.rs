@@ -0,0 +1,69 @@
+use std::{path::PathBuf, time::Duration};
+
+use bitvec::{order::Lsb0, view::BitView};
+use color_eyre::{eyre::eyre, Result};
+use image::{GrayImage, Luma};
+use keyboard_shared::{HostToKeyboard, KeyboardSide, KeyboardToHost};
+
+use crate::host_link::HostLink;
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 128;
+
+/// Read back the contents of both displays and save them as an image
+#[derive(Debug, clap::Parser)]
+pub struct ScreenshotOpts {
+    #[clap(long, short, parse(from_os_str), default_value = "frame.png")]
+    out: PathBuf,
+
+    port: Option<String>,
+}
+
+impl ScreenshotOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        // laid out the same way `render` splits an image between the halves
+        let mut image = GrayImage::new(WIDTH * 2, HEIGHT);
+
+        for (side, x_offset) in [(KeyboardSide::Left, 0), (KeyboardSide::Right, WIDTH)] {
+            for row in (0..HEIGHT as u8).step_by(2) {
+                let (data_0, data_1) = read_rows(&mut link, side, row).await?;
+
+                for (y, data) in [(row as u32, data_0), (row as u32 + 1, data_1)] {
+                    for (x, on) in data.view_bits::<Lsb0>().iter().enumerate() {
+                        let v = if *on { 255 } else { 0 };
+                        image.put_pixel(x_offset + x as u32, y, Luma([v]));
+                    }
+                }
+            }
+        }
+
+        image.save(&self.out)?;
+        println!("Saved screenshot to {}", self.out.display());
+
+        Ok(())
+    }
+}
+
+async fn read_rows(link: &mut HostLink, side: KeyboardSide, row: u8) -> Result<([u8; 4], [u8; 4])> {
+    link.send(HostToKeyboard::ReadPixels { side, row }).await?;
+
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(1))
+            .await?
+            .ok_or_else(|| eyre!("Timed out reading row {} of the {:?} display", row, side))?;
+
+        if let KeyboardToHost::PixelRow {
+            row: r,
+            data_0,
+            data_1,
+        } = msg
+        {
+            if r == row {
+                return Ok((data_0, data_1));
+            }
+        }
+    }
+}
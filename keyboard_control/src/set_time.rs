@@ -0,0 +1,41 @@
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::HostToKeyboard;
+
+use crate::host_link::HostLink;
+
+/// Tell the keyboard the local time of day, which quiet hours are scheduled
+/// against and the displays show. The keyboard has no clock of its own, so
+/// this needs running again whenever it restarts, `metrics` does it as it
+/// goes.
+#[derive(Debug, clap::Parser)]
+pub struct SetTimeOpts {
+    port: Option<String>,
+}
+
+impl SetTimeOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        let seconds_of_day = local_seconds_of_day()?;
+        link.send(HostToKeyboard::SetTime { seconds_of_day })
+            .await?;
+        println!(
+            "Set the time to {:02}:{:02}",
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60
+        );
+
+        Ok(())
+    }
+}
+
+pub fn local_seconds_of_day() -> Result<u32> {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return Err(eyre!("Couldn't get the local time"));
+    }
+
+    Ok((tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32)
+}
@@ -0,0 +1,159 @@
+use std::{path::PathBuf, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    settings_checksum, HostToKeyboard, KeyboardToHost, SettingsImportStatus, SETTINGS_CHUNK,
+    SETTINGS_MAX_BLOB,
+};
+
+use crate::host_link::HostLink;
+
+/// Back up or restore the keyboard's settings
+#[derive(Debug, clap::Parser)]
+pub struct SettingsOpts {
+    #[clap(subcommand)]
+    command: SettingsCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum SettingsCommand {
+    /// Save the keyboard's settings to a file
+    Export {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+
+        port: Option<String>,
+    },
+    /// Restore settings previously saved with `export`
+    Import {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+
+        port: Option<String>,
+    },
+}
+
+impl SettingsOpts {
+    pub async fn execute(self) -> Result<()> {
+        match self.command {
+            SettingsCommand::Export { file, port } => {
+                let mut link = HostLink::open(port.as_deref())?;
+                let blob = export(&mut link).await?;
+                check_blob(&blob)?;
+                std::fs::write(&file, &blob)?;
+                println!(
+                    "Saved {} bytes of settings to {}",
+                    blob.len(),
+                    file.display()
+                );
+            }
+            SettingsCommand::Import { file, port } => {
+                let blob = std::fs::read(&file)?;
+                check_blob(&blob)?;
+                let mut link = HostLink::open(port.as_deref())?;
+                import(&mut link, &blob).await?;
+                println!("Restored settings from {}", file.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_blob(blob: &[u8]) -> Result<()> {
+    if blob.len() < 5 || blob.len() > SETTINGS_MAX_BLOB {
+        return Err(eyre!(
+            "Settings blob is {} bytes, which isn't valid",
+            blob.len()
+        ));
+    }
+
+    let (body, csum) = blob.split_at(blob.len() - 4);
+    if settings_checksum(body).to_le_bytes() != csum {
+        return Err(eyre!("Settings blob checksum doesn't match"));
+    }
+
+    Ok(())
+}
+
+async fn export(link: &mut HostLink) -> Result<Vec<u8>> {
+    let mut blob = Vec::new();
+
+    loop {
+        let offset = blob.len() as u16;
+        link.send(HostToKeyboard::ExportSettings { offset }).await?;
+
+        let (total, chunk) = loop {
+            let msg = link
+                .recv_timeout(Duration::from_secs(1))
+                .await?
+                .ok_or_else(|| eyre!("Timed out waiting for settings at offset {}", offset))?;
+
+            if let KeyboardToHost::SettingsChunk {
+                offset: o,
+                total,
+                len,
+                data,
+            } = msg
+            {
+                if o == offset {
+                    break (total as usize, data[..len as usize].to_vec());
+                }
+            }
+        };
+
+        if chunk.is_empty() && blob.len() < total {
+            return Err(eyre!("Keyboard sent an empty chunk at offset {}", offset));
+        }
+
+        blob.extend_from_slice(&chunk);
+
+        if blob.len() >= total {
+            return Ok(blob);
+        }
+    }
+}
+
+async fn import(link: &mut HostLink, blob: &[u8]) -> Result<()> {
+    let total = blob.len() as u16;
+
+    for (idx, chunk) in blob.chunks(SETTINGS_CHUNK).enumerate() {
+        let offset = (idx * SETTINGS_CHUNK) as u16;
+        let mut data = [0u8; SETTINGS_CHUNK];
+        data[..chunk.len()].copy_from_slice(chunk);
+
+        link.send(HostToKeyboard::ImportSettings {
+            offset,
+            total,
+            len: chunk.len() as u8,
+            data,
+        })
+        .await?;
+
+        let status = loop {
+            let msg = link
+                .recv_timeout(Duration::from_secs(1))
+                .await?
+                .ok_or_else(|| eyre!("Timed out importing settings at offset {}", offset))?;
+
+            if let KeyboardToHost::SettingsImport { offset: o, status } = msg {
+                if o == offset {
+                    break status;
+                }
+            }
+        };
+
+        match status {
+            SettingsImportStatus::Continue => {}
+            SettingsImportStatus::Applied => return Ok(()),
+            SettingsImportStatus::UnsupportedVersion => {
+                return Err(eyre!(
+                    "These settings are from a different settings version, flash matching firmware first"
+                ))
+            }
+            s => return Err(eyre!("Keyboard rejected the settings: {:?}", s)),
+        }
+    }
+
+    Err(eyre!("Keyboard never applied the settings"))
+}
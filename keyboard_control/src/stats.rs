@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{
+    DropChannel, HostToKeyboard, KeyboardSide, KeyboardToHost, LinkErrorKind, SafeModeReason,
+    SelfTestResults,
+};
+
+use crate::{host_link::HostLink, util::open_keyboard};
+
+/// Show keypress counts and each half's power on self test results
+#[derive(Debug, clap::Parser)]
+pub struct StatsOpts {
+    port: Option<String>,
+}
+
+impl StatsOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut conn = open_keyboard(self.port.as_deref()).await?;
+
+        let mut total = 0;
+        let links = conn.links();
+        let dual = links.len() > 1;
+        for (side, link) in links {
+            if let Some(side) = side {
+                println!("== {:?} half ==", side);
+            }
+            total += print_stats(link).await?;
+            if dual {
+                println!();
+            }
+        }
+
+        if dual {
+            println!("Keypresses on both halves: {}", total);
+        }
+
+        Ok(())
+    }
+}
+
+/// Print what one link reports, returning its keypress count
+async fn print_stats(link: &mut HostLink) -> Result<u32> {
+    link.send(HostToKeyboard::RequestStats).await?;
+
+    let mut self_tests = 0;
+    let mut keypresses = 0;
+    while self_tests < 2 {
+        let msg = link
+            .recv_timeout(Duration::from_secs(2))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for stats"))?;
+
+        match msg {
+            KeyboardToHost::Stats { keypresses: k } => {
+                keypresses = k;
+                println!("Keypresses: {}", k);
+            }
+            KeyboardToHost::DebugStats {
+                cpu_busy_pct,
+                corrupt_frames,
+                link_read_errors,
+                link_errors,
+                link_resyncs,
+                link_up,
+                link_losses,
+                retransmits,
+                failed_sends,
+                channel_drops,
+                settings_erases,
+                rejected_pixel_writes,
+                firmware_mismatch,
+                command_queue_high_water,
+                ack_queue_high_water,
+                presence_mode,
+                display_bus,
+                phantom_presses,
+                safe_mode,
+                heap_high_water,
+                oom_averted,
+                ..
+            } => {
+                if let Some(reason) = safe_mode {
+                    println!(
+                        "SAFE MODE ({}): the left half kept failing to boot, so it's running \
+                         without its display, LED effects or saved settings. \
+                         `factory-reset --yes` resets the settings and restarts it.",
+                        safe_mode_reason(reason)
+                    );
+                }
+                if firmware_mismatch {
+                    println!("WARNING: the halves are running different firmware, flash them both");
+                }
+                if !link_up {
+                    println!("WARNING: the right half isn't answering heartbeats");
+                }
+                println!("CPU busy: {}%", cpu_busy_pct);
+                println!("Corrupt link frames: {}", corrupt_frames);
+                println!("Link read errors: {}", link_read_errors);
+                for (kind, errors) in LinkErrorKind::ALL.iter().zip(link_errors) {
+                    if errors > 0 {
+                        println!("  {:?}: {}", kind, errors);
+                    }
+                }
+                if link_resyncs > 0 {
+                    println!("Link resyncs after bursts of errors: {}", link_resyncs);
+                }
+                if link_losses > 0 {
+                    println!("Times the link went down: {}", link_losses);
+                }
+                println!("Link retransmits: {}", retransmits);
+                if failed_sends > 0 {
+                    println!("Link sends given up on: {}", failed_sends);
+                }
+                println!(
+                    "Phantom presses: {} left, {} right",
+                    phantom_presses[KeyboardSide::Left as usize],
+                    phantom_presses[KeyboardSide::Right as usize]
+                );
+                println!(
+                    "Most queued to send: {} commands, {} acks",
+                    command_queue_high_water, ack_queue_high_water
+                );
+                if presence_mode {
+                    println!("Presence mode is on");
+                }
+                println!(
+                    "Display flush: {}us last, {}us average, {}us max",
+                    display_bus.last_flush_us, display_bus.avg_flush_us, display_bus.max_flush_us
+                );
+                println!(
+                    "Display bus: {}% busy, {}kHz achieved",
+                    display_bus.busy_pct, display_bus.khz
+                );
+                if display_bus.saturated {
+                    println!("WARNING: the display bus is busy enough to delay other work");
+                }
+                println!("Most heap used: {} bytes", heap_high_water);
+                if oom_averted > 0 {
+                    println!("Allocations turned down for lack of heap: {}", oom_averted);
+                }
+                println!("Settings flash erases: {}", settings_erases);
+                println!("Rejected pixel writes: {}", rejected_pixel_writes);
+                println!("Channel drops:");
+                for (channel, drops) in DropChannel::ALL.iter().zip(channel_drops) {
+                    println!("  {:?}: {}", channel, drops);
+                }
+            }
+            KeyboardToHost::SelfTest { side, results } => {
+                self_tests += 1;
+                print_self_test(side, results);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(keypresses)
+}
+
+fn safe_mode_reason(reason: SafeModeReason) -> &'static str {
+    match reason {
+        SafeModeReason::Panic => "panicked",
+        SafeModeReason::Lockup => "locked up",
+        SafeModeReason::Watchdog => "watchdog",
+        SafeModeReason::Other => "reset",
+    }
+}
+
+fn print_self_test(side: KeyboardSide, results: Option<SelfTestResults>) {
+    println!();
+    println!("{:?} self test:", side);
+
+    let results = match results {
+        Some(results) => results,
+        None => {
+            println!("  no results");
+            return;
+        }
+    };
+
+    for (name, passed) in results.checks() {
+        println!("  {:<6} {}", name, if passed { "ok" } else { "FAIL" });
+    }
+}
@@ -0,0 +1,32 @@
+use std::{io::Write, path::PathBuf};
+
+use color_eyre::Result;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    net::UnixStream,
+};
+
+/// Print the stream served by `watch --socket`
+#[derive(Debug, clap::Parser)]
+pub struct TailOpts {
+    #[clap(long)]
+    socket: PathBuf,
+}
+
+impl TailOpts {
+    pub async fn execute(self) -> Result<()> {
+        let stream = UnixStream::connect(&self.socket).await?;
+        tail(stream, std::io::stdout()).await
+    }
+}
+
+/// Copy each line of `stream` to `out` until it ends
+pub async fn tail(stream: impl AsyncRead + Unpin, mut out: impl Write) -> Result<()> {
+    let mut lines = BufReader::new(stream).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        writeln!(out, "{}", line)?;
+    }
+
+    Ok(())
+}
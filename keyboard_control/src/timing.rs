@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardToHost, TIMING_BUCKETS, TIMING_BUCKETS_MS};
+
+use crate::host_link::HostLink;
+
+/// Show hold-tap and chord timing statistics, with tuning suggestions
+#[derive(Debug, clap::Parser)]
+pub struct TimingOpts {
+    port: Option<String>,
+}
+
+struct HoldTap {
+    row: u8,
+    col: u8,
+    timeout_ms: u16,
+    taps: u16,
+    holds: u16,
+    tap_durations: [u16; TIMING_BUCKETS],
+    hold_durations: [u16; TIMING_BUCKETS],
+}
+
+struct Chord {
+    fires: u16,
+    near_misses: u16,
+}
+
+impl TimingOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        link.send(HostToKeyboard::RequestTimingStats).await?;
+
+        let mut hold_taps = Vec::new();
+        let mut chords = Vec::new();
+
+        loop {
+            let msg = link
+                .recv_timeout(Duration::from_secs(2))
+                .await?
+                .ok_or_else(|| eyre!("Timed out waiting for timing stats"))?;
+
+            match msg {
+                KeyboardToHost::HoldTapStats {
+                    row,
+                    col,
+                    timeout_ms,
+                    taps,
+                    holds,
+                    tap_durations,
+                    hold_durations,
+                    ..
+                } => hold_taps.push(HoldTap {
+                    row,
+                    col,
+                    timeout_ms,
+                    taps,
+                    holds,
+                    tap_durations,
+                    hold_durations,
+                }),
+                KeyboardToHost::ChordStats {
+                    index,
+                    total,
+                    fires,
+                    near_misses,
+                } => {
+                    chords.push(Chord { fires, near_misses });
+                    if index + 1 >= total {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        println!("Hold-taps:");
+        println!(
+            "{:>8} {:>8} {:>6} {:>6} {:>10} {:>10}",
+            "key", "timeout", "taps", "holds", "tap p95", "hold p5"
+        );
+        for ht in &hold_taps {
+            println!(
+                "{:>8} {:>6}ms {:>6} {:>6} {:>10} {:>10}",
+                format!("({}, {})", ht.row, ht.col),
+                ht.timeout_ms,
+                ht.taps,
+                ht.holds,
+                percentile_label(&ht.tap_durations, 0.95),
+                percentile_label(&ht.hold_durations, 0.05),
+            );
+        }
+
+        println!();
+        println!("Chords:");
+        println!("{:>6} {:>8} {:>12}", "chord", "fires", "near misses");
+        for (idx, chord) in chords.iter().enumerate() {
+            println!("{:>6} {:>8} {:>12}", idx, chord.fires, chord.near_misses);
+        }
+
+        println!();
+        let suggestions = hold_taps
+            .iter()
+            .flat_map(|ht| suggest_hold_tap(ht).map(|s| format!("({}, {}): {}", ht.row, ht.col, s)))
+            .chain(
+                chords
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(idx, c)| suggest_chord(c).map(|s| format!("chord {}: {}", idx, s))),
+            )
+            .collect::<Vec<_>>();
+
+        if suggestions.is_empty() {
+            println!("No suggestions, timings look fine");
+        } else {
+            println!("Suggestions:");
+            for s in suggestions {
+                println!("  {}", s);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The bucket containing the given percentile, as the bucket's upper bound in ms
+fn percentile_bucket(buckets: &[u16; TIMING_BUCKETS], pct: f32) -> Option<usize> {
+    let total: u32 = buckets.iter().map(|b| *b as u32).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let target = (total as f32 * pct).ceil().max(1.0) as u32;
+    let mut seen = 0;
+    for (idx, b) in buckets.iter().enumerate() {
+        seen += *b as u32;
+        if seen >= target {
+            return Some(idx);
+        }
+    }
+
+    Some(TIMING_BUCKETS - 1)
+}
+
+fn bucket_label(idx: usize) -> String {
+    match TIMING_BUCKETS_MS.get(idx) {
+        Some(upper) => format!("<{}ms", upper),
+        None => format!(">={}ms", TIMING_BUCKETS_MS[TIMING_BUCKETS_MS.len() - 1]),
+    }
+}
+
+fn percentile_label(buckets: &[u16; TIMING_BUCKETS], pct: f32) -> String {
+    percentile_bucket(buckets, pct)
+        .map(bucket_label)
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+fn suggest_hold_tap(ht: &HoldTap) -> Option<String> {
+    if let Some(idx) = percentile_bucket(&ht.tap_durations, 0.95) {
+        let upper = TIMING_BUCKETS_MS.get(idx).copied().unwrap_or(u16::MAX);
+        if upper >= ht.timeout_ms {
+            return Some(format!(
+                "95th percentile tap duration is {}, consider raising the {}ms timeout",
+                bucket_label(idx),
+                ht.timeout_ms
+            ));
+        }
+    }
+
+    if let Some(idx) = percentile_bucket(&ht.hold_durations, 0.05) {
+        if ht.holds > 0 && idx == 0 {
+            return Some(format!(
+                "5th percentile hold duration is {}, fast rolls are probably being read as holds",
+                bucket_label(idx),
+            ));
+        }
+    }
+
+    None
+}
+
+fn suggest_chord(chord: &Chord) -> Option<String> {
+    if chord.near_misses > chord.fires && chord.near_misses >= 5 {
+        Some(format!(
+            "{} near misses vs {} fires, the chord is hard to hit",
+            chord.near_misses, chord.fires
+        ))
+    } else {
+        None
+    }
+}
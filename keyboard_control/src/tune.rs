@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardToHost, Tuning};
+
+use crate::host_link::HostLink;
+
+/// Try out timings on the keyboard, they take effect straight away but are
+/// only saved with --commit. With no options, prints the timings in use.
+#[derive(Debug, clap::Parser)]
+pub struct TuneOpts {
+    /// Window in ms that every key of a strict chord must be pressed within
+    #[clap(long)]
+    chord_window: Option<u16>,
+
+    /// Timeout in ms before every hold-tap resolves as a hold
+    #[clap(long)]
+    hold_tap: Option<u16>,
+
+    /// Save the timings in use, after applying any given here
+    #[clap(long)]
+    commit: bool,
+
+    /// Go back to the saved timings
+    #[clap(long, conflicts_with_all = &["chord-window", "hold-tap", "commit"])]
+    revert: bool,
+
+    port: Option<String>,
+}
+
+impl TuneOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+
+        let mut commands = Vec::new();
+        if self.chord_window.is_some() || self.hold_tap.is_some() {
+            commands.push(HostToKeyboard::SetTuning {
+                chord_window_ms: self.chord_window,
+                hold_tap_timeout_ms: self.hold_tap,
+            });
+        }
+        if self.commit {
+            commands.push(HostToKeyboard::CommitTuning);
+        }
+        if self.revert {
+            commands.push(HostToKeyboard::RevertTuning);
+        }
+        if commands.is_empty() {
+            commands.push(HostToKeyboard::RequestTuning);
+        }
+
+        let mut last = None;
+        for cmd in commands {
+            link.send(cmd).await?;
+            last = Some(recv_tuning(&mut link).await?);
+        }
+
+        if let Some((tuning, saved)) = last {
+            println!("Chord window: {}ms", tuning.chord_window_ms);
+            println!("Hold-tap timeout: {}ms", tuning.hold_tap_timeout_ms);
+            if !saved {
+                println!("Not saved, use --commit to keep these or --revert to undo them");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn recv_tuning(link: &mut HostLink) -> Result<(Tuning, bool)> {
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(1))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for the keyboard's timings"))?;
+
+        match msg {
+            KeyboardToHost::Tuning { tuning, saved } => return Ok((tuning, saved)),
+            KeyboardToHost::TuningRejected => {
+                return Err(eyre!(
+                    "The keyboard rejected the timings, they need to be 1 to {}ms",
+                    Tuning::MAX_MS
+                ))
+            }
+            _ => {}
+        }
+    }
+}
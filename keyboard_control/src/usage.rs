@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardToHost, UsageKind, KEY_COLS};
+
+use crate::host_link::HostLink;
+
+/// Show how much each chord, layer and hold-tap gets used since the keyboard
+/// powered on
+#[derive(Debug, clap::Parser)]
+pub struct UsageOpts {
+    /// Names for the keymap, one `<kind> <index> <name>` per line where kind
+    /// is layer, chord or hold-tap. Hold-taps are indexed by `<row>,<col>`.
+    /// Blank lines and lines starting with `#` are skipped.
+    #[clap(long, parse(from_os_str))]
+    keymap: Option<PathBuf>,
+
+    /// Keep running totals in this file, adding whatever has been used since
+    /// the last run so they survive the keyboard restarting
+    #[clap(long, parse(from_os_str))]
+    accumulate: Option<PathBuf>,
+
+    port: Option<String>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counter {
+    count: u64,
+    millis: u64,
+}
+
+type Counts = HashMap<(UsageKind, u8), Counter>;
+
+impl UsageOpts {
+    pub async fn execute(self) -> Result<()> {
+        let mut link = HostLink::open(self.port.as_deref())?;
+        let counts = request_usage(&mut link).await?;
+
+        let counts = match &self.accumulate {
+            Some(path) => accumulate(path, counts)?,
+            None => counts,
+        };
+
+        let names = match &self.keymap {
+            Some(path) => load_names(path)?,
+            None => HashMap::new(),
+        };
+
+        print_usage(&counts, &names);
+
+        Ok(())
+    }
+}
+
+async fn request_usage(link: &mut HostLink) -> Result<Counts> {
+    link.send(HostToKeyboard::RequestUsageStats).await?;
+
+    let mut counts = Counts::new();
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(2))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for usage stats"))?;
+
+        if let KeyboardToHost::Usage {
+            offset,
+            total,
+            len,
+            entries,
+        } = msg
+        {
+            for entry in entries.iter().take(len as usize) {
+                counts.insert(
+                    (entry.kind, entry.index),
+                    Counter {
+                        count: entry.count as u64,
+                        millis: entry.millis as u64,
+                    },
+                );
+            }
+
+            if offset as usize + len as usize >= total as usize {
+                return Ok(counts);
+            }
+        }
+    }
+}
+
+fn kind_name(kind: UsageKind) -> &'static str {
+    match kind {
+        UsageKind::Chord => "chord",
+        UsageKind::Layer => "layer",
+        UsageKind::Tap => "tap",
+        UsageKind::Hold => "hold",
+    }
+}
+
+fn parse_kind(name: &str) -> Option<UsageKind> {
+    match name {
+        "chord" => Some(UsageKind::Chord),
+        "layer" => Some(UsageKind::Layer),
+        "tap" => Some(UsageKind::Tap),
+        "hold" => Some(UsageKind::Hold),
+        _ => None,
+    }
+}
+
+/// Add the counts since the snapshot in `path` to its totals, and save the
+/// new counts as the snapshot for next time.
+///
+/// The file has a `total` and a `last` line for each counter, each being
+/// `<kind> <index> <count> <millis>`.
+fn accumulate(path: &Path, counts: Counts) -> Result<Counts> {
+    let mut totals = Counts::new();
+    let mut last = Counts::new();
+
+    if path.exists() {
+        for (idx, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let parsed = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [which, kind, index, count, millis] => parse_kind(kind).and_then(|kind| {
+                    Some((
+                        which,
+                        (kind, index.parse().ok()?),
+                        Counter {
+                            count: count.parse().ok()?,
+                            millis: millis.parse().ok()?,
+                        },
+                    ))
+                }),
+                _ => None,
+            };
+
+            match parsed {
+                Some(("total", key, counter)) => totals.insert(key, counter),
+                Some(("last", key, counter)) => last.insert(key, counter),
+                _ => {
+                    return Err(eyre!(
+                        "{}:{}: not a usage snapshot",
+                        path.display(),
+                        idx + 1
+                    ))
+                }
+            };
+        }
+    }
+
+    // the time spent across all the layers is the keyboard's uptime, so the
+    // keyboard has restarted if that went backwards
+    let uptime = |c: &Counts| -> u64 {
+        c.iter()
+            .filter(|((kind, _), _)| *kind == UsageKind::Layer)
+            .map(|(_, c)| c.millis)
+            .sum()
+    };
+    if uptime(&counts) < uptime(&last) {
+        last.clear();
+    }
+
+    for (key, now) in &counts {
+        let before = last.get(key).copied().unwrap_or_default();
+        let total = totals.entry(*key).or_default();
+        total.count += now.count.saturating_sub(before.count);
+        total.millis += now.millis.saturating_sub(before.millis);
+    }
+
+    let mut out = String::new();
+    for (which, counts) in [("total", &totals), ("last", &counts)] {
+        for ((kind, index), c) in sorted(counts) {
+            out += &format!(
+                "{} {} {} {} {}\n",
+                which,
+                kind_name(kind),
+                index,
+                c.count,
+                c.millis
+            );
+        }
+    }
+    fs::write(path, out)?;
+
+    Ok(totals)
+}
+
+/// Names from a keymap file, hold-taps are keyed as taps
+fn load_names(path: &Path) -> Result<HashMap<(UsageKind, u8), String>> {
+    let mut names = HashMap::new();
+
+    for (idx, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(3, char::is_whitespace);
+        let key = match (parts.next(), parts.next()) {
+            (Some("layer"), Some(index)) => index.parse().ok().map(|i| (UsageKind::Layer, i)),
+            (Some("chord"), Some(index)) => index.parse().ok().map(|i| (UsageKind::Chord, i)),
+            (Some("hold-tap"), Some(coord)) => coord.split_once(',').and_then(|(row, col)| {
+                let (row, col) = (row.parse::<u8>().ok()?, col.parse::<u8>().ok()?);
+                let index = row.checked_mul(KEY_COLS as u8)?.checked_add(col)?;
+                Some((UsageKind::Tap, index))
+            }),
+            _ => None,
+        };
+
+        match (key, parts.next()) {
+            (Some(key), Some(name)) => names.insert(key, name.trim().to_owned()),
+            _ => {
+                return Err(eyre!(
+                    "{}:{}: expected `<kind> <index> <name>`",
+                    path.display(),
+                    idx + 1
+                ))
+            }
+        };
+    }
+
+    Ok(names)
+}
+
+fn sorted(counts: &Counts) -> Vec<((UsageKind, u8), Counter)> {
+    let mut sorted = counts.iter().map(|(k, c)| (*k, *c)).collect::<Vec<_>>();
+    sorted.sort_by_key(|((kind, index), _)| (*kind as u8, *index));
+    sorted
+}
+
+/// The entries of one kind, most used first
+fn by_use(counts: &Counts, kind: UsageKind) -> Vec<(u8, Counter)> {
+    let mut entries = counts
+        .iter()
+        .filter(|((k, _), _)| *k == kind)
+        .map(|((_, index), c)| (*index, *c))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(index, c)| (std::cmp::Reverse((c.millis, c.count)), *index));
+    entries
+}
+
+fn print_usage(counts: &Counts, names: &HashMap<(UsageKind, u8), String>) {
+    let name = |kind: UsageKind, index: u8, default: String| {
+        names.get(&(kind, index)).cloned().unwrap_or(default)
+    };
+
+    let layers = by_use(counts, UsageKind::Layer);
+    let uptime = layers.iter().map(|(_, c)| c.millis).sum::<u64>().max(1);
+    println!("Layers:");
+    println!(
+        "{:>16} {:>10} {:>12} {:>6}",
+        "layer", "switches", "time", "share"
+    );
+    for (index, c) in layers {
+        println!(
+            "{:>16} {:>10} {:>12} {:>5}%",
+            name(UsageKind::Layer, index, format!("layer {}", index)),
+            c.count,
+            format_millis(c.millis),
+            c.millis * 100 / uptime,
+        );
+    }
+
+    println!();
+    println!("Chords:");
+    println!("{:>16} {:>10}", "chord", "fires");
+    for (index, c) in by_use(counts, UsageKind::Chord) {
+        println!(
+            "{:>16} {:>10}",
+            name(UsageKind::Chord, index, format!("chord {}", index)),
+            c.count
+        );
+    }
+
+    println!();
+    println!("Hold-taps:");
+    println!("{:>16} {:>10} {:>10} {:>6}", "key", "taps", "holds", "held");
+    let mut hold_taps = by_use(counts, UsageKind::Tap)
+        .into_iter()
+        .map(|(index, taps)| {
+            let holds = counts.get(&(UsageKind::Hold, index)).map_or(0, |c| c.count);
+            (index, taps.count, holds)
+        })
+        .collect::<Vec<_>>();
+    hold_taps.sort_by_key(|(index, taps, holds)| (std::cmp::Reverse(taps + holds), *index));
+    for (index, taps, holds) in hold_taps {
+        let (row, col) = (index / KEY_COLS as u8, index % KEY_COLS as u8);
+        println!(
+            "{:>16} {:>10} {:>10} {:>5}%",
+            name(UsageKind::Tap, index, format!("({}, {})", row, col)),
+            taps,
+            holds,
+            holds * 100 / (taps + holds).max(1),
+        );
+    }
+}
+
+fn format_millis(millis: u64) -> String {
+    let secs = millis / 1000;
+    match secs {
+        0..=59 => format!("{}.{}s", secs, millis % 1000 / 100),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
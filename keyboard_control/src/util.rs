@@ -1,37 +1,202 @@
 use std::{path::Path, time::Duration};
 
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
+use keyboard_shared::{HostToKeyboard, KeyboardSide, KeyboardToHost};
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 use tracing::info;
 
+use crate::{host_link::HostLink, keyboards};
+
+/// Open `port`, or the keyboard picked with `--keyboard`, or failing that
+/// the first one we find. For a keyboard with a port on each half this is
+/// the first of them, see [`open_keyboard`] to get both.
 pub fn open_port(port: Option<&str>) -> Result<SerialStream> {
     if let Some(name) = port {
-        return tokio_serial::new(name, 921_600)
-            .timeout(Duration::from_millis(100))
-            .open_native_async()
-            .map_err(Into::into);
-    } else {
-        let ports = tokio_serial::available_ports()?;
-
-        for port in ports {
-            if port.port_name.contains("ttyACM") {
-                let name = Path::new(&port.port_name).file_name().ok_or_else(|| {
-                    color_eyre::eyre::eyre!("Couldn't get name of port {}", &port.port_name)
-                })?;
-                let path = Path::new("/dev")
-                    .join(name)
-                    .into_os_string()
-                    .into_string()
-                    .unwrap();
-                info!("Selected port: {}", path);
-
-                return tokio_serial::new(path, 921_600)
-                    .timeout(Duration::from_millis(100))
-                    .open_native_async()
-                    .map_err(Into::into);
-            }
+        return open(name);
+    }
+
+    if let Some(selector) = keyboards::selected() {
+        let name = keyboards::find_ports(selector)?.swap_remove(0);
+        info!("Selected port: {}", name);
+        return open(&name);
+    }
+
+    let ports = tokio_serial::available_ports()?;
+
+    for port in ports {
+        if port.port_name.contains("ttyACM") {
+            let name = Path::new(&port.port_name).file_name().ok_or_else(|| {
+                color_eyre::eyre::eyre!("Couldn't get name of port {}", &port.port_name)
+            })?;
+            let path = Path::new("/dev")
+                .join(name)
+                .into_os_string()
+                .into_string()
+                .unwrap();
+            info!("Selected port: {}", path);
+
+            return open(&path);
         }
     }
 
     Err(color_eyre::eyre::eyre!("No ports!"))
 }
+
+/// The link or links to a keyboard
+pub enum KeyboardConnection {
+    /// One port that speaks for both halves, the left half passing on
+    /// anything for the right
+    Single(HostLink),
+    /// A port to each half
+    Dual { left: HostLink, right: HostLink },
+}
+
+impl KeyboardConnection {
+    /// Each link with the half it's to, `None` for a single link that
+    /// speaks for both
+    pub fn links(&mut self) -> Vec<(Option<KeyboardSide>, &mut HostLink)> {
+        match self {
+            Self::Single(link) => vec![(None, link)],
+            Self::Dual { left, right } => vec![
+                (Some(KeyboardSide::Left), left),
+                (Some(KeyboardSide::Right), right),
+            ],
+        }
+    }
+
+    /// The link that handles `side`'s commands
+    pub fn link_for(&mut self, side: KeyboardSide) -> &mut HostLink {
+        match (self, side) {
+            (Self::Single(link), _) => link,
+            (Self::Dual { left, .. }, KeyboardSide::Left) => left,
+            (Self::Dual { right, .. }, KeyboardSide::Right) => right,
+        }
+    }
+}
+
+/// Like [`open_port`], but a keyboard picked with `--keyboard` that has a
+/// port on each half gets both, each half saying which it is
+pub async fn open_keyboard(port: Option<&str>) -> Result<KeyboardConnection> {
+    let ports = match (port, keyboards::selected()) {
+        (None, Some(selector)) => keyboards::find_ports(selector)?,
+        _ => return Ok(KeyboardConnection::Single(HostLink::open(port)?)),
+    };
+
+    match &ports[..] {
+        [port] => {
+            info!("Selected port: {}", port);
+            Ok(KeyboardConnection::Single(HostLink::new(open(port)?)))
+        }
+        [a, b] => open_halves(a, b).await,
+        _ => Err(eyre!(
+            "Found {} ports for the keyboard, expected one or one per half: {}",
+            ports.len(),
+            ports.join(", ")
+        )),
+    }
+}
+
+/// Open a port to each half, in either order
+async fn open_halves(a: &str, b: &str) -> Result<KeyboardConnection> {
+    let mut a = (a, HostLink::new(open(a)?));
+    let mut b = (b, HostLink::new(open(b)?));
+    match (identify(&mut a.1).await?, identify(&mut b.1).await?) {
+        (KeyboardSide::Left, KeyboardSide::Right) => {}
+        (KeyboardSide::Right, KeyboardSide::Left) => std::mem::swap(&mut a, &mut b),
+        (side, _) => {
+            return Err(eyre!(
+                "Both {} and {} say they're the {:?} half",
+                a.0,
+                b.0,
+                side
+            ))
+        }
+    }
+
+    info!("Selected ports: {} (left), {} (right)", a.0, b.0);
+    Ok(KeyboardConnection::Dual {
+        left: a.1,
+        right: b.1,
+    })
+}
+
+/// Which half `link` is to
+async fn identify(link: &mut HostLink) -> Result<KeyboardSide> {
+    link.send(HostToKeyboard::Identify).await?;
+
+    loop {
+        let msg = link
+            .recv_timeout(Duration::from_secs(1))
+            .await?
+            .ok_or_else(|| eyre!("Timed out waiting for the keyboard to say which half it is"))?;
+
+        if let KeyboardToHost::Identity { side } = msg {
+            return Ok(side);
+        }
+    }
+}
+
+fn open(path: &str) -> Result<SerialStream> {
+    tokio_serial::new(path, 921_600)
+        .timeout(Duration::from_millis(100))
+        .open_native_async()
+        .map_err(Into::into)
+}
+
+/// Read a line from stdin without blocking the runtime, `None` at EOF
+pub async fn read_line() -> Result<Option<String>> {
+    let line = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|n| (n, line))
+    })
+    .await??;
+
+    Ok(match line {
+        (0, _) => None,
+        (_, line) => Some(line),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator;
+
+    async fn sides(conn: &mut KeyboardConnection) -> Result<Vec<KeyboardSide>> {
+        let mut sides = Vec::new();
+        for (_, link) in conn.links() {
+            sides.push(identify(link).await?);
+        }
+        Ok(sides)
+    }
+
+    #[tokio::test]
+    async fn halves_are_told_apart_in_either_order() -> Result<()> {
+        for right_first in [false, true] {
+            let mut ports = [
+                emulator::spawn(KeyboardSide::Left)?,
+                emulator::spawn(KeyboardSide::Right)?,
+            ];
+            if right_first {
+                ports.reverse();
+            }
+
+            let mut conn = open_halves(&ports[0], &ports[1]).await?;
+            assert!(matches!(conn, KeyboardConnection::Dual { .. }));
+            assert_eq!(
+                sides(&mut conn).await?,
+                [KeyboardSide::Left, KeyboardSide::Right]
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn two_ports_claiming_one_half_are_refused() -> Result<()> {
+        let a = emulator::spawn(KeyboardSide::Left)?;
+        let b = emulator::spawn(KeyboardSide::Left)?;
+
+        assert!(open_halves(&a, &b).await.is_err());
+        Ok(())
+    }
+}
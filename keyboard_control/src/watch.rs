@@ -0,0 +1,441 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use keyboard_shared::{HostToKeyboard, KeyboardToHost, MonotonicCounter, REPORT_SUBSCRIPTION_SECS};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::broadcast::{self, error::RecvError},
+    time::Instant,
+};
+
+use crate::host_link::HostLink;
+
+/// How often the keyboard is asked for its status and keypress count
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+/// How often the report subscription is renewed, well before it lapses
+const RESUBSCRIBE_PERIOD: Duration = Duration::from_secs(REPORT_SUBSCRIPTION_SECS / 2);
+/// Lines a socket client can fall behind by before it's disconnected
+const CLIENT_BACKLOG: usize = 256;
+/// A socket client that hasn't taken a line in this long is disconnected,
+/// as it may have stopped reading altogether
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Stream what the keyboard is doing as JSON, one object per line.
+///
+/// Objects have a "type" of "keypresses" (presses since the last one, and the
+/// running total), "layer", "link" (whether the link between the halves is
+/// degraded), "stats" or "report". The keyboard only reports how many keys
+/// were pressed, never which ones, unless `--reports` is given.
+#[derive(Debug, clap::Parser)]
+pub struct WatchOpts {
+    /// Also serve the stream on this UNIX socket, to any number of clients.
+    /// Clients that don't keep up are disconnected.
+    #[clap(long)]
+    socket: Option<PathBuf>,
+
+    /// Seconds between stats objects
+    #[clap(long, default_value = "5")]
+    stats_interval: u64,
+
+    /// Also stream every HID report the keyboard sends, as "report" objects
+    /// with the keycodes and the modifier bits. This is everything typed,
+    /// passwords included. The keyboard shows a red LED and "LOG" on its
+    /// display while it's on, and stops shortly after this exits.
+    #[clap(long)]
+    reports: bool,
+
+    port: Option<String>,
+}
+
+impl WatchOpts {
+    pub async fn execute(self) -> Result<()> {
+        let link = HostLink::open(self.port.as_deref())?;
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+
+        if let Some(path) = &self.socket {
+            let listener = bind(path)?;
+            tracing::info!(?path, "serving key events");
+            tokio::spawn(accept_clients(listener, lines.clone()));
+        }
+
+        if self.reports {
+            eprintln!("Warning: streaming everything typed on the keyboard");
+        }
+
+        let mut poller = Poller::new(link, Duration::from_secs(self.stats_interval), self.reports);
+        loop {
+            poller
+                .poll(|line| {
+                    println!("{}", line);
+                    // nobody listening isn't an error
+                    let _ = lines.send(line);
+                })
+                .await?;
+        }
+    }
+}
+
+/// Asks the keyboard what it's doing and hands on each line as it's made
+struct Poller {
+    link: HostLink,
+    watcher: Watcher,
+    stats_interval: Duration,
+    next_stats: Instant,
+    reports: bool,
+    next_subscribe: Instant,
+}
+
+impl Poller {
+    fn new(link: HostLink, stats_interval: Duration, reports: bool) -> Self {
+        Self {
+            link,
+            watcher: Watcher::default(),
+            stats_interval,
+            next_stats: Instant::now(),
+            reports,
+            next_subscribe: Instant::now(),
+        }
+    }
+
+    /// Ask once, then pass `emit` each line from the replies for the next
+    /// `POLL_PERIOD`
+    async fn poll(&mut self, mut emit: impl FnMut(String)) -> Result<()> {
+        if self.reports && Instant::now() >= self.next_subscribe {
+            self.next_subscribe = Instant::now() + RESUBSCRIBE_PERIOD;
+            self.link
+                .send(HostToKeyboard::SubscribeReports(true))
+                .await?;
+        }
+
+        self.link.send(HostToKeyboard::RequestStatus).await?;
+        self.link.send(HostToKeyboard::RequestStats).await?;
+
+        let deadline = Instant::now() + POLL_PERIOD;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            self.link.poll(remaining).await?;
+
+            while let Some(msg) = self.link.try_recv() {
+                let stats_due = Instant::now() >= self.next_stats;
+                if stats_due && matches!(msg, KeyboardToHost::DebugStats { .. }) {
+                    self.next_stats = Instant::now() + self.stats_interval;
+                }
+
+                if let Some(line) = self.watcher.handle(msg, stats_due) {
+                    emit(line);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns replies from the keyboard into JSON lines, skipping anything that
+/// hasn't changed
+#[derive(Default)]
+struct Watcher {
+    keypresses: Option<MonotonicCounter>,
+    status: Option<(u8, bool)>,
+}
+
+impl Watcher {
+    fn handle(&mut self, msg: KeyboardToHost, stats_due: bool) -> Option<String> {
+        match msg {
+            KeyboardToHost::Stats { keypresses } => {
+                let count = match &mut self.keypresses {
+                    Some(total) => total.delta_since(keypresses),
+                    None => {
+                        self.keypresses = Some(MonotonicCounter::new(keypresses));
+                        return None;
+                    }
+                };
+                (count > 0).then(|| {
+                    format!(
+                        r#"{{"type":"keypresses","count":{},"total":{}}}"#,
+                        count, keypresses
+                    )
+                })
+            }
+            KeyboardToHost::Status(status) => {
+                let status = (status.layer, status.locked());
+                if self.status.replace(status) == Some(status) {
+                    return None;
+                }
+                Some(format!(
+                    r#"{{"type":"layer","layer":{},"locked":{}}}"#,
+                    status.0, status.1
+                ))
+            }
+            KeyboardToHost::LinkDegraded(degraded) => {
+                Some(format!(r#"{{"type":"link","degraded":{}}}"#, degraded))
+            }
+            KeyboardToHost::Report {
+                len,
+                keycodes,
+                mods,
+            } => {
+                let keycodes = keycodes
+                    .iter()
+                    .take(len as usize)
+                    .map(|k| k.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                Some(format!(
+                    r#"{{"type":"report","keycodes":[{}],"mods":{}}}"#,
+                    keycodes, mods
+                ))
+            }
+            KeyboardToHost::DebugStats {
+                cpu_busy_pct,
+                corrupt_frames,
+                retransmits,
+                channel_drops,
+                settings_erases,
+                ..
+            } if stats_due => Some(format!(
+                r#"{{"type":"stats","cpu_busy_pct":{},"corrupt_frames":{},"retransmits":{},"channel_drops":{},"settings_erases":{}}}"#,
+                cpu_busy_pct,
+                corrupt_frames,
+                retransmits,
+                channel_drops.iter().sum::<u32>(),
+                settings_erases
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Listen on `path`, replacing a socket left behind by an earlier run
+fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+async fn accept_clients(listener: UnixListener, lines: broadcast::Sender<String>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_client(stream, lines.subscribe()));
+            }
+            Err(e) => tracing::warn!(%e, "failed to accept a client"),
+        }
+    }
+}
+
+async fn serve_client(mut stream: UnixStream, mut lines: broadcast::Receiver<String>) {
+    loop {
+        let line = match lines.recv().await {
+            Ok(line) => line,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::info!(skipped, "disconnecting a client that fell behind");
+                return;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let write = async {
+            stream.write_all(line.as_bytes()).await?;
+            stream.write_all(b"\n").await
+        };
+        match tokio::time::timeout(CLIENT_WRITE_TIMEOUT, write).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return,
+            Err(_) => {
+                tracing::info!("disconnecting a client that stopped reading");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keyboard_shared::KeyboardSide;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+    use super::*;
+    use crate::{emulator, tail::tail};
+
+    /// A watcher on an emulated keyboard, with stats only from the first
+    /// poll
+    fn poller() -> Result<Poller> {
+        let link = HostLink::open(Some(&emulator::spawn(KeyboardSide::Left)?))?;
+        Ok(Poller::new(link, Duration::from_secs(3600), false))
+    }
+
+    /// Press a key on the emulated keyboard `count` times
+    async fn press(poller: &mut Poller, count: usize) -> Result<()> {
+        for _ in 0..count {
+            poller
+                .link
+                .send(HostToKeyboard::InjectKey {
+                    row: 0,
+                    col: 0,
+                    duration_ms: 20,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The lines from a round of polling, after pressing `count` keys
+    async fn lines_after(poller: &mut Poller, count: usize) -> Result<Vec<String>> {
+        press(poller, count).await?;
+        let mut lines = Vec::new();
+        poller.poll(|line| lines.push(line)).await?;
+        Ok(lines)
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "keyboard-watch-{}-{}.sock",
+            std::process::id(),
+            name
+        ))
+    }
+
+    /// Wait for `count` clients to be subscribed to `lines`
+    async fn clients(lines: &broadcast::Sender<String>, count: usize) {
+        while lines.receiver_count() < count {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lines_are_one_json_object_each() -> Result<()> {
+        let mut poller = poller()?;
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+        let (server, client) = UnixStream::pair()?;
+        tokio::spawn(serve_client(server, lines.subscribe()));
+
+        // the first poll only learns the count, the second sees the presses
+        let mut sent = lines_after(&mut poller, 0).await?;
+        sent.extend(lines_after(&mut poller, 3).await?);
+        // nothing changed, so nothing more is said
+        assert_eq!(lines_after(&mut poller, 0).await?, Vec::<String>::new());
+        for line in &sent {
+            lines.send(line.clone())?;
+        }
+
+        let mut received = BufReader::new(client).lines();
+        let mut types = Vec::new();
+        for line in &sent {
+            let got = received.next_line().await?.unwrap();
+            assert_eq!(&got, line);
+            let object: serde_json::Value = serde_json::from_str(&got)?;
+            types.push(object["type"].as_str().unwrap().to_owned());
+            match object["type"].as_str() {
+                Some("keypresses") => {
+                    assert_eq!(object["count"], 3);
+                    assert_eq!(object["total"], 3);
+                }
+                Some("stats") => assert_eq!(object["retransmits"], 0),
+                _ => {}
+            }
+        }
+        assert_eq!(types, ["layer", "stats", "keypresses"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_client_gets_every_line() -> Result<()> {
+        let path = socket_path("fan-out");
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+        let server = tokio::spawn(accept_clients(bind(&path)?, lines.clone()));
+
+        let mut readers = Vec::new();
+        for _ in 0..3 {
+            readers.push(BufReader::new(UnixStream::connect(&path).await?).lines());
+        }
+        clients(&lines, 3).await;
+
+        for idx in 0..10 {
+            lines.send(format!(r#"{{"type":"layer","layer":{}}}"#, idx))?;
+        }
+        for reader in &mut readers {
+            for idx in 0..10 {
+                let line = reader.next_line().await?.unwrap();
+                assert_eq!(line, format!(r#"{{"type":"layer","layer":{}}}"#, idx));
+            }
+        }
+
+        server.abort();
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_disconnected() -> Result<()> {
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+        let (server, mut slow) = UnixStream::pair()?;
+        let (fast_server, fast) = UnixStream::pair()?;
+        let serving = tokio::spawn(serve_client(server, lines.subscribe()));
+        tokio::spawn(serve_client(fast_server, lines.subscribe()));
+        let mut fast = BufReader::new(fast).lines();
+
+        // lines bigger than the socket buffer, so writes to the slow client
+        // soon stall
+        let line = "x".repeat(64 * 1024);
+        for _ in 0..=CLIENT_BACKLOG * 2 {
+            lines.send(line.clone())?;
+            assert_eq!(fast.next_line().await?.as_deref(), Some(line.as_str()));
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), serving).await??;
+        // what was written before it fell behind, then nothing
+        let mut rest = Vec::new();
+        slow.read_to_end(&mut rest).await?;
+        assert!(rest.len() < line.len() * CLIENT_BACKLOG);
+
+        // the fast one is still served
+        lines.send("{}".to_owned())?;
+        assert_eq!(fast.next_line().await?.as_deref(), Some("{}"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tail_prints_what_the_keyboard_does() -> Result<()> {
+        let path = socket_path("tail");
+        let (lines, _) = broadcast::channel(CLIENT_BACKLOG);
+        let server = tokio::spawn(accept_clients(bind(&path)?, lines.clone()));
+
+        let stream = UnixStream::connect(&path).await?;
+        let tailing = tokio::spawn(async move {
+            let mut out = Vec::new();
+            tail(stream, &mut out).await.map(|()| out)
+        });
+        clients(&lines, 1).await;
+
+        let mut poller = poller()?;
+        let mut sent = Vec::new();
+        for count in [0, 2, 5] {
+            press(&mut poller, count).await?;
+            poller
+                .poll(|line| {
+                    sent.push(line.clone());
+                    let _ = lines.send(line);
+                })
+                .await?;
+        }
+
+        // closing the channel ends the client's stream, and with it tail
+        server.abort();
+        drop(lines);
+        let out = tokio::time::timeout(Duration::from_secs(5), tailing).await???;
+
+        let mut expected = sent.join("\n");
+        expected.push('\n');
+        assert_eq!(String::from_utf8(out)?, expected);
+        assert_eq!(sent.len(), 4);
+        assert!(sent[3].contains(r#""count":5,"total":7"#));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
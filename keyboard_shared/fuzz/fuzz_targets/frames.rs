@@ -0,0 +1,56 @@
+//! Feeds arbitrary bytes through the same decoding the keyboard and
+//! `keyboard_control` do with what comes over serial: COBS frames decoded
+//! with postcard, checked with `CmdOrAck::receive`, and packed pixel rows
+//! unpacked as the keyboard would. A valid frame after whatever came before
+//! it must still decode.
+//!
+//! Seed the corpus with frames from a real keyboard using
+//! `keyboard_control --dump-frames fuzz/corpus/frames <command>`.
+
+#![no_main]
+
+use core::hash::Hash;
+
+use keyboard_shared::{CmdOrAck, Command, HostToKeyboard, KeyboardToHost, Received};
+use libfuzzer_sys::fuzz_target;
+use postcard::accumulator::{CobsAccumulator, FeedResult};
+use serde::de::DeserializeOwned;
+
+/// Decode every frame in `window`, handing each command to `f`
+fn feed<T: DeserializeOwned + Hash>(mut window: &[u8], mut f: impl FnMut(T)) {
+    let mut accumulator = CobsAccumulator::<256>::new();
+
+    while !window.is_empty() {
+        window = match accumulator.feed::<CmdOrAck<T>>(window) {
+            FeedResult::Consumed => break,
+            FeedResult::OverFull(remaining) => remaining,
+            FeedResult::DeserError(remaining) => remaining,
+            FeedResult::Success { data, remaining } => {
+                if let Received::Cmd { cmd, .. } = data.receive() {
+                    f(cmd);
+                }
+                remaining
+            }
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut last = None;
+    let mut bytes = data.to_vec();
+    // end whatever frame the input left open, then a good one
+    bytes.push(0);
+    let mut buf = [0u8; 32];
+    let good = Command::new(HostToKeyboard::Lock(true));
+    bytes.extend_from_slice(postcard::to_slice_cobs(&CmdOrAck::Cmd(good), &mut buf).unwrap());
+
+    feed(&bytes, |cmd: HostToKeyboard| {
+        if let HostToKeyboard::WritePackedPixels { data, .. } = &cmd {
+            let _ = data.unpack();
+        }
+        last = Some(cmd);
+    });
+    assert_eq!(last, Some(HostToKeyboard::Lock(true)));
+
+    feed(data, |_: KeyboardToHost| {});
+});
@@ -0,0 +1,60 @@
+//! Records of the traffic between the halves, for replaying offline.
+
+/// Bytes of the link capture carried by each `CaptureChunk`
+pub const CAPTURE_CHUNK: usize = 32;
+
+/// Which way a captured run of link bytes went, as the left half saw it
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum CaptureDirection {
+    /// From the right half, so `SubToDom` frames and acks
+    Received,
+    /// To the right half, so `DomToSub` frames and acks
+    Sent,
+}
+
+/// Bytes read from or written to the link between the halves, as kept in a
+/// link capture: the direction, microseconds since boot, the length, then
+/// the bytes. The time wraps after about 71 minutes.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct CaptureRecord<'a> {
+    pub direction: CaptureDirection,
+    pub at_us: u32,
+    pub bytes: &'a [u8],
+}
+
+/// Bytes before those of each `CaptureRecord`
+pub const CAPTURE_HEADER_LEN: usize = 6;
+
+impl<'a> CaptureRecord<'a> {
+    /// Goes before the bytes, which can't be more than 255
+    pub fn header(&self) -> [u8; CAPTURE_HEADER_LEN] {
+        let [a, b, c, d] = self.at_us.to_le_bytes();
+        [self.direction as u8, a, b, c, d, self.bytes.len() as u8]
+    }
+
+    /// The record at the start of `buf` and whatever follows it, `None` if
+    /// `buf` doesn't start with a whole record
+    pub fn parse(buf: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        let header = buf.get(..CAPTURE_HEADER_LEN)?;
+        let direction = match header[0] {
+            0 => CaptureDirection::Received,
+            1 => CaptureDirection::Sent,
+            _ => return None,
+        };
+        let at_us = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+
+        let rest = &buf[CAPTURE_HEADER_LEN..];
+        let len = header[5] as usize;
+        let bytes = rest.get(..len)?;
+
+        Some((
+            Self {
+                direction,
+                at_us,
+                bytes,
+            },
+            &rest[len..],
+        ))
+    }
+}
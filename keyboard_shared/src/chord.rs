@@ -0,0 +1,920 @@
+//! Chording that the layout can't do on its own: timing guards, action
+//! chords, chords across the halves, and catching members that leak through.
+
+use crate::matrix::{KEY_COLS, KEY_ROWS};
+
+/// Extra timing constraints checked before a chord is allowed to fire
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChordTiming {
+    /// every member must have been pressed within the tuned chord window of
+    /// the first, see `Tuning::chord_window_ms`
+    pub limit_spread: bool,
+    /// every member must have been pressed within this many ms of the first,
+    /// in place of the tuned chord window
+    pub max_spread_ms: Option<u16>,
+    /// the chord only fires if no other key was pressed in the last this many ms
+    pub idle_guard_ms: Option<u16>,
+}
+
+impl ChordTiming {
+    pub const DEFAULT: Self = Self {
+        limit_spread: false,
+        max_spread_ms: None,
+        idle_guard_ms: None,
+    };
+
+    /// for chords on keys that get rolled over a lot while typing
+    pub const STRICT: Self = Self {
+        limit_spread: true,
+        max_spread_ms: None,
+        idle_guard_ms: Some(100),
+    };
+
+    /// How far apart the members can be pressed, given the tuned chord window
+    pub fn spread_ms(&self, window_ms: u16) -> Option<u16> {
+        self.max_spread_ms
+            .or(self.limit_spread.then_some(window_ms))
+    }
+}
+
+/// Why a chord was kept from firing
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Defused {
+    /// The members were pressed this many ms apart
+    Spread(u64),
+    /// Another key was pressed within the idle guard
+    Busy,
+}
+
+/// When each key of the layout was pressed, for checking chords against
+/// their [`ChordTiming`].
+///
+/// Chording fires a chord whenever all its members come out of the debouncer
+/// in the same tick, which happens for fast rolls too. Presses are timed as
+/// they happen instead, before debouncing for this half's keys and as they
+/// arrive for the other half's.
+pub struct ChordGuard {
+    held: [[bool; KEY_COLS]; KEY_ROWS],
+    pressed_at: [[Option<u64>; KEY_COLS]; KEY_ROWS],
+}
+
+impl ChordGuard {
+    pub const fn new() -> Self {
+        Self {
+            held: [[false; KEY_COLS]; KEY_ROWS],
+            pressed_at: [[None; KEY_COLS]; KEY_ROWS],
+        }
+    }
+
+    /// Record the state of the key at `(row, col)` of the layout at `now_ms`,
+    /// a press is timed from when it's first seen held
+    pub fn observe(&mut self, (row, col): (u8, u8), pressed: bool, now_ms: u64) {
+        let (row, col) = (row as usize, col as usize);
+        if row >= KEY_ROWS || col >= KEY_COLS {
+            return;
+        }
+
+        if pressed && !self.held[row][col] {
+            self.pressed_at[row][col] = Some(now_ms);
+        }
+        self.held[row][col] = pressed;
+    }
+
+    pub fn held(&self, (row, col): (u8, u8)) -> bool {
+        matches!(self.held.get(row as usize), Some(r) if r.get(col as usize) == Some(&true))
+    }
+
+    /// When the key was last pressed, `None` if it hasn't been
+    pub fn pressed_at(&self, (row, col): (u8, u8)) -> Option<u64> {
+        *self.pressed_at.get(row as usize)?.get(col as usize)?
+    }
+
+    /// Check a chord whose members were all pressed in the same tick,
+    /// returning the member to hold back for a tick so the chord doesn't
+    /// fire, and why. `window_ms` is the tuned chord window.
+    pub fn defuse(
+        &self,
+        keys: &[(u8, u8)],
+        timing: ChordTiming,
+        window_ms: u16,
+    ) -> Option<((u8, u8), Defused)> {
+        let spread = timing.spread_ms(window_ms);
+        if spread.is_none() && timing.idle_guard_ms.is_none() {
+            return None;
+        }
+
+        let presses = || keys.iter().map(|&k| (k, self.pressed_at(k).unwrap_or(0)));
+        let first = presses().map(|(_, at)| at).min()?;
+        let (last_key, last) = presses().max_by_key(|(_, at)| *at)?;
+
+        if matches!(spread, Some(ms) if last - first > ms as u64) {
+            return Some((last_key, Defused::Spread(last - first)));
+        }
+
+        if let Some(guard) = timing.idle_guard_ms {
+            let busy = (0..KEY_ROWS as u8)
+                .flat_map(|row| (0..KEY_COLS as u8).map(move |col| (row, col)))
+                .filter(|k| !keys.contains(k))
+                .filter_map(|k| self.pressed_at(k))
+                .any(|at| at + guard as u64 > first);
+
+            if busy {
+                return Some((last_key, Defused::Busy));
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for ChordGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drop presses of a chord's members that come out of chording alongside the
+/// chord itself, returning the index of the chord and the coordinates of each
+/// press dropped. `chords` are each chord's output and members, and `press`
+/// gives the coordinates of a press.
+///
+/// `input` is the batch handed to chording and `output` what it gave back. A
+/// chord only counts as fired when all its members were pressed in `input`
+/// and its output wasn't, as some chords share an output and some resolve to
+/// a physical key.
+///
+/// Keyberon should swallow the members of a chord it fires, but has been seen
+/// letting one through in the same tick.
+pub fn suppress_leaks<'a, E, const N: usize>(
+    input: &[E],
+    output: &mut heapless::Vec<E, N>,
+    chords: impl IntoIterator<Item = ((u8, u8), &'a [(u8, u8)])>,
+    press: fn(&E) -> Option<(u8, u8)>,
+) -> heapless::Vec<(usize, (u8, u8)), N> {
+    let pressed = |events: &[E], at: (u8, u8)| events.iter().any(|e| press(e) == Some(at));
+    let mut leaks = heapless::Vec::new();
+
+    for (idx, (out, keys)) in chords.into_iter().enumerate() {
+        let fired =
+            pressed(output, out) && !pressed(input, out) && keys.iter().all(|&k| pressed(input, k));
+        if !fired {
+            continue;
+        }
+
+        output.retain(|e| match press(e) {
+            Some(at) if keys.contains(&at) => {
+                let _ = leaks.push((idx, at));
+                false
+            }
+            _ => true,
+        });
+    }
+
+    leaks
+}
+
+/// Whether a chord has members on both halves
+pub fn spans_halves(keys: &[(u8, u8)]) -> bool {
+    let half = (KEY_COLS / 2) as u8;
+    keys.iter().any(|&(_, y)| y < half) && keys.iter().any(|&(_, y)| y >= half)
+}
+
+/// Whether the right half sends its key events unchorded, which follows
+/// cross side chording being on but only changes while no keys are held. A
+/// key's release then always goes the same way as its press, so the half
+/// that chorded a press is the one that sees it released.
+pub struct RawMode {
+    raw: bool,
+    held: u8,
+}
+
+impl RawMode {
+    pub const fn new() -> Self {
+        Self {
+            raw: false,
+            held: 0,
+        }
+    }
+
+    /// Count a scan's events, given as whether each is a press, returning
+    /// whether they go out raw
+    pub fn update(&mut self, presses: impl IntoIterator<Item = bool>, cross_side: bool) -> bool {
+        if self.held == 0 {
+            self.raw = cross_side;
+        }
+
+        for press in presses {
+            if press {
+                self.held = self.held.saturating_add(1);
+            } else {
+                self.held = self.held.saturating_sub(1);
+            }
+        }
+
+        self.raw
+    }
+}
+
+impl Default for RawMode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Most events that go through [`CrossSideGather`] in a tick, in or out
+pub const GATHER_BATCH: usize = 8;
+/// Events [`CrossSideGather`] can have waiting, held back or carried over
+const GATHER_BACKLOG: usize = 2 * GATHER_BATCH;
+
+/// Holds back presses of the members of chords that span the halves, for up
+/// to the chord window, so presses made together on both halves reach
+/// chording in the same tick. The right half's arrive a little after the
+/// left's, over the link.
+///
+/// Presses are let go as soon as a whole chord is held, the window runs out,
+/// or any other event comes along, which goes after them to keep the order.
+/// Anything that doesn't fit in a tick's batch is carried over to the next.
+pub struct CrossSideGather<E: 'static> {
+    chords: &'static [&'static [(u8, u8)]],
+    /// The layout coordinate of an event that's a press
+    press: fn(&E) -> Option<(u8, u8)>,
+    held: heapless::Vec<(E, u64), GATHER_BATCH>,
+    /// Events let go that didn't fit in the batch they were let go in
+    carried: heapless::Deque<E, GATHER_BACKLOG>,
+}
+
+impl<E: Copy> CrossSideGather<E> {
+    pub const fn new(
+        chords: &'static [&'static [(u8, u8)]],
+        press: fn(&E) -> Option<(u8, u8)>,
+    ) -> Self {
+        Self {
+            chords,
+            press,
+            held: heapless::Vec::new(),
+            carried: heapless::Deque::new(),
+        }
+    }
+
+    /// Events held back or carried over, which there should be room for in
+    /// a tick's batch before any more are taken in
+    pub fn len(&self) -> usize {
+        self.held.len() + self.carried.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take a tick's events at `now` in milliseconds, returning those to
+    /// hand on to chording. Call this every tick, events or not, so held
+    /// presses time out.
+    pub fn tick(
+        &mut self,
+        events: impl IntoIterator<Item = E>,
+        now: u64,
+        window_ms: u64,
+        gathering: bool,
+    ) -> heapless::Vec<E, GATHER_BATCH> {
+        for event in events {
+            let member = matches!((self.press)(&event), Some(c) if self.is_member(c));
+            if gathering && member && !self.held.is_full() {
+                let _ = self.held.push((event, now));
+                continue;
+            }
+
+            self.release();
+            self.carry(event);
+        }
+
+        let expired =
+            matches!(self.held.first(), Some((_, at)) if now.saturating_sub(*at) >= window_ms);
+        if !gathering || expired || self.chord_held() {
+            self.release();
+        }
+
+        let mut out = heapless::Vec::new();
+        while !out.is_full() {
+            let Some(event) = self.carried.pop_front() else {
+                break;
+            };
+            let _ = out.push(event);
+        }
+        out
+    }
+
+    fn is_member(&self, coord: (u8, u8)) -> bool {
+        self.chords
+            .iter()
+            .any(|keys| spans_halves(keys) && keys.contains(&coord))
+    }
+
+    fn chord_held(&self) -> bool {
+        self.chords.iter().any(|keys| {
+            spans_halves(keys)
+                && keys
+                    .iter()
+                    .all(|k| self.held.iter().any(|(e, _)| (self.press)(e) == Some(*k)))
+        })
+    }
+
+    fn carry(&mut self, event: E) {
+        if self.carried.push_back(event).is_err() {
+            defmt::warn!("Cross side chording backlog full, dropping an event");
+        }
+    }
+
+    fn release(&mut self) {
+        let held = core::mem::take(&mut self.held);
+        for (event, _) in held {
+            self.carry(event);
+        }
+    }
+}
+
+/// How long after the first member of an action chord every other member
+/// has to be pressed, in milliseconds
+pub const ACTION_CHORD_WINDOW_MS: u64 = 40;
+/// Most members an action chord can have
+pub const ACTION_CHORD_MEMBERS: usize = 4;
+
+/// A chord that runs a firmware action rather than typing
+#[derive(Clone, Copy)]
+pub struct ActionChord<A: 'static> {
+    pub action: A,
+    /// Layout coordinates, these can be on either half
+    pub keys: &'static [(u8, u8)],
+}
+
+const fn is_subset(a: &[(u8, u8)], b: &[(u8, u8)]) -> bool {
+    let mut i = 0;
+    while i < a.len() {
+        let mut found = false;
+        let mut j = 0;
+        while j < b.len() {
+            if a[i].0 == b[j].0 && a[i].1 == b[j].1 {
+                found = true;
+            }
+            j += 1;
+        }
+        if !found {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Every chord has at least two keys and at most [`ACTION_CHORD_MEMBERS`],
+/// all within the first `rows` rows, and none is contained in another, since
+/// the smaller one would always fire first
+pub const fn action_chords_valid<A>(chords: &[ActionChord<A>], rows: usize) -> bool {
+    let mut i = 0;
+    while i < chords.len() {
+        let keys = chords[i].keys;
+        if keys.len() < 2 || keys.len() > ACTION_CHORD_MEMBERS {
+            return false;
+        }
+
+        let mut k = 0;
+        while k < keys.len() {
+            if keys[k].0 as usize >= rows {
+                return false;
+            }
+            k += 1;
+        }
+
+        let mut j = 0;
+        while j < chords.len() {
+            if i != j && is_subset(keys, chords[j].keys) {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// A key event as the action chord matcher sees it
+pub trait ChordEvent: Copy {
+    fn coord(&self) -> (u8, u8);
+    fn is_press(&self) -> bool;
+    /// Made up by the firmware, which never starts a chord
+    fn is_synthetic(&self) -> bool;
+}
+
+/// Events let through by one call to the matcher, the held presses and the
+/// event that let them go
+pub type ChordPassed<E> = heapless::Vec<E, { ACTION_CHORD_MEMBERS + 1 }>;
+
+/// Matches action chords in a stream of key events.
+///
+/// A press of a member that would type is held back until either every
+/// member of its chord is down within [`ACTION_CHORD_WINDOW_MS`] of the
+/// first, when the action runs and the held presses and their releases are
+/// swallowed, or the chord can't happen any more, when the held presses are
+/// let through in order. A member that doesn't type anything on its own,
+/// like a modifier, is let through straight away, so holding it isn't
+/// delayed.
+pub struct ActionChordMatcher<A: 'static, E, const N: usize> {
+    chords: &'static [ActionChord<A>; N],
+    /// Presses of members held back, oldest first
+    held: heapless::Vec<E, ACTION_CHORD_MEMBERS>,
+    /// Members that were let through as they were pressed
+    through: heapless::Vec<(u8, u8), ACTION_CHORD_MEMBERS>,
+    /// When the first of the members down was pressed
+    started: u64,
+    /// Members of a chord that fired, whose releases are swallowed
+    swallowed: heapless::Vec<(u8, u8), ACTION_CHORD_MEMBERS>,
+}
+
+impl<A: Copy, E: ChordEvent, const N: usize> ActionChordMatcher<A, E, N> {
+    pub const fn new(chords: &'static [ActionChord<A>; N]) -> Self {
+        Self {
+            chords,
+            held: heapless::Vec::new(),
+            through: heapless::Vec::new(),
+            started: 0,
+            swallowed: heapless::Vec::new(),
+        }
+    }
+
+    fn down(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.held
+            .iter()
+            .map(|e| e.coord())
+            .chain(self.through.iter().copied())
+    }
+
+    /// Whether some chord has `coord` and every member down
+    fn could_complete(&self, coord: (u8, u8)) -> bool {
+        self.chords
+            .iter()
+            .any(|c| c.keys.contains(&coord) && self.down().all(|d| c.keys.contains(&d)))
+    }
+
+    /// Feed in the next key event at `now` in milliseconds, `types` being
+    /// whether its key types anything on its own. Returns the action of a
+    /// chord it completes and the events to hand on to the layout.
+    pub fn event(&mut self, event: E, now: u64, types: bool) -> (Option<A>, ChordPassed<E>) {
+        let mut passed = self.poll(now);
+        let coord = event.coord();
+
+        if !event.is_press() {
+            if let Some(idx) = self.swallowed.iter().position(|c| *c == coord) {
+                self.swallowed.swap_remove(idx);
+                return (None, passed);
+            }
+        }
+
+        if event.is_press() && !event.is_synthetic() && self.could_complete(coord) {
+            if self.down().next().is_none() {
+                self.started = now;
+            }
+            if types {
+                let _ = self.held.push(event);
+            } else {
+                let _ = self.through.push(coord);
+                let _ = passed.push(event);
+            }
+            return (self.fire(), passed);
+        }
+
+        // anything else means the members down can't be a chord
+        for e in self.release_held() {
+            let _ = passed.push(e);
+        }
+        let _ = passed.push(event);
+        (None, passed)
+    }
+
+    /// Runs the chord the members down make up, if they make up a whole one
+    fn fire(&mut self) -> Option<A> {
+        let down = self.held.len() + self.through.len();
+        let chord = self
+            .chords
+            .iter()
+            .find(|c| c.keys.len() == down && self.down().all(|d| c.keys.contains(&d)))?;
+
+        // the members let through have been seen being pressed, so their
+        // releases have to be seen too
+        self.swallowed.clear();
+        for e in self.held.iter() {
+            let _ = self.swallowed.push(e.coord());
+        }
+        self.held.clear();
+        self.through.clear();
+        Some(chord.action)
+    }
+
+    fn release_held(&mut self) -> ChordPassed<E> {
+        let passed = self.held.iter().copied().collect();
+        self.held.clear();
+        self.through.clear();
+        passed
+    }
+
+    /// Whether any presses are being held back
+    pub fn holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// When the members down stop being able to make a chord if nothing
+    /// else happens
+    pub fn deadline(&self) -> Option<u64> {
+        self.down()
+            .next()
+            .map(|_| self.started + ACTION_CHORD_WINDOW_MS)
+    }
+
+    /// Let the held presses through once the window has passed
+    pub fn poll(&mut self, now: u64) -> ChordPassed<E> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => self.release_held(),
+            _ => ChordPassed::new(),
+        }
+    }
+
+    /// Forget everything, for when the layout is being bypassed
+    pub fn reset(&mut self) {
+        self.held.clear();
+        self.through.clear();
+        self.swallowed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Q: (u8, u8) = (0, 1);
+    const W: (u8, u8) = (0, 2);
+    const E: (u8, u8) = (0, 3);
+
+    /// q and w pressed `gap_ms` apart, as they reach the guard
+    fn roll(gap_ms: u64) -> ChordGuard {
+        let mut guard = ChordGuard::new();
+        guard.observe(Q, true, 1000);
+        guard.observe(Q, true, 1000 + gap_ms);
+        guard.observe(W, true, 1000 + gap_ms);
+        guard
+    }
+
+    #[test]
+    fn rolled_strict_chord_is_defused() {
+        let guard = roll(15);
+        assert_eq!(
+            guard.defuse(&[Q, W], ChordTiming::STRICT, 8),
+            Some((W, Defused::Spread(15)))
+        );
+    }
+
+    #[test]
+    fn simultaneous_strict_chord_fires() {
+        let guard = roll(5);
+        assert_eq!(guard.defuse(&[Q, W], ChordTiming::STRICT, 8), None);
+        // the window is the tuned one
+        assert!(roll(15).defuse(&[Q, W], ChordTiming::STRICT, 20).is_none());
+    }
+
+    #[test]
+    fn chords_can_have_a_window_of_their_own() {
+        let own = |ms| ChordTiming {
+            max_spread_ms: Some(ms),
+            ..ChordTiming::DEFAULT
+        };
+        assert!(roll(15).defuse(&[Q, W], own(20), 8).is_none());
+        assert_eq!(
+            roll(15).defuse(&[Q, W], own(10), 20),
+            Some((W, Defused::Spread(15)))
+        );
+    }
+
+    #[test]
+    fn loose_chords_fire_however_they_were_pressed() {
+        let guard = roll(40);
+        assert_eq!(guard.defuse(&[Q, W], ChordTiming::DEFAULT, 8), None);
+    }
+
+    #[test]
+    fn chord_right_after_typing_is_defused() {
+        let mut guard = ChordGuard::new();
+        guard.observe(E, true, 950);
+        guard.observe(E, false, 990);
+        guard.observe(Q, true, 1000);
+        guard.observe(W, true, 1002);
+        assert_eq!(
+            guard.defuse(&[Q, W], ChordTiming::STRICT, 8),
+            Some((W, Defused::Busy))
+        );
+
+        let mut idle = ChordGuard::new();
+        idle.observe(E, true, 850);
+        idle.observe(Q, true, 1000);
+        idle.observe(W, true, 1002);
+        assert_eq!(idle.defuse(&[Q, W], ChordTiming::STRICT, 8), None);
+    }
+
+    #[test]
+    fn keys_off_the_layout_are_ignored() {
+        let mut guard = ChordGuard::new();
+        guard.observe((KEY_ROWS as u8, 0), true, 10);
+        guard.observe((0, KEY_COLS as u8), true, 10);
+        assert!(!guard.held((KEY_ROWS as u8, 0)));
+        assert_eq!(guard.pressed_at((0, KEY_COLS as u8)), None);
+    }
+
+    /// Key events as (row, col, press)
+    type Key = (u8, u8, bool);
+
+    fn key_press(event: &Key) -> Option<(u8, u8)> {
+        event.2.then_some((event.0, event.1))
+    }
+
+    static GATHER_CHORDS: [&[(u8, u8)]; 3] = [
+        // t + y, one key from each half
+        &[(0, 5), (0, 6)],
+        &[(1, 5), (1, 6)],
+        // only on the left, so never gathered
+        &[(0, 0), (0, 1)],
+    ];
+
+    fn gather() -> CrossSideGather<Key> {
+        CrossSideGather::new(&GATHER_CHORDS, key_press)
+    }
+
+    #[test]
+    fn cross_side_chord_members_reach_chording_together() {
+        let mut gather = gather();
+        // the left's press first, the right's a few ms later over the link
+        assert!(gather.tick([(0, 5, true)], 0, 30, true).is_empty());
+        assert!(gather.tick([], 1, 30, true).is_empty());
+        let out = gather.tick([(0, 6, true)], 4, 30, true);
+        assert_eq!(out.as_slice(), &[(0, 5, true), (0, 6, true)]);
+        assert!(gather.is_empty());
+    }
+
+    #[test]
+    fn gathered_presses_go_once_the_window_runs_out() {
+        let mut gather = gather();
+        gather.tick([(0, 5, true)], 0, 30, true);
+        assert!(gather.tick([], 29, 30, true).is_empty());
+        assert_eq!(gather.tick([], 30, 30, true).as_slice(), &[(0, 5, true)]);
+    }
+
+    #[test]
+    fn other_events_go_after_gathered_presses() {
+        let mut gather = gather();
+        let out = gather.tick([(0, 5, true), (0, 0, true), (0, 1, true)], 0, 30, true);
+        assert_eq!(out.as_slice(), &[(0, 5, true), (0, 0, true), (0, 1, true)]);
+
+        // and with cross side chording off nothing's held
+        let out = gather.tick([(0, 6, true)], 1, 30, false);
+        assert_eq!(out.as_slice(), &[(0, 6, true)]);
+    }
+
+    #[test]
+    fn gathered_overflow_is_carried_to_the_next_tick() {
+        let mut gather = gather();
+        gather.tick([(0, 5, true), (1, 5, true)], 0, 30, true);
+        let others = (0..8).map(|col| (2, col, true)).collect::<Vec<_>>();
+        let out = gather.tick(others.iter().copied(), 1, 30, true);
+        assert_eq!(out.len(), GATHER_BATCH);
+        assert_eq!(gather.len(), 2);
+
+        let carried = gather.tick([], 2, 30, true);
+        let all = out.iter().chain(&carried).copied().collect::<Vec<_>>();
+        let mut expected = vec![(0, 5, true), (1, 5, true)];
+        expected.extend(others);
+        assert_eq!(all, expected);
+        assert!(gather.is_empty());
+    }
+
+    #[test]
+    fn raw_mode_only_changes_with_nothing_held() {
+        let mut raw = RawMode::new();
+        assert!(!raw.update([true], false));
+        // turned on mid press, the release still goes out chorded
+        assert!(!raw.update([], true));
+        assert!(!raw.update([false], true));
+        assert!(raw.update([true, true], true));
+        assert!(raw.update([false], false));
+        assert!(raw.update([false], false));
+        assert!(!raw.update([], false));
+    }
+
+    #[test]
+    fn chords_across_the_split_span_halves() {
+        assert!(spans_halves(&[(0, 5), (0, 6)]));
+        assert!(!spans_halves(&[(0, 0), (0, 1)]));
+        assert!(!spans_halves(&[(0, 6), (0, 7)]));
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    struct ChordKey {
+        coord: (u8, u8),
+        press: bool,
+        synthetic: bool,
+    }
+
+    impl ChordEvent for ChordKey {
+        fn coord(&self) -> (u8, u8) {
+            self.coord
+        }
+
+        fn is_press(&self) -> bool {
+            self.press
+        }
+
+        fn is_synthetic(&self) -> bool {
+            self.synthetic
+        }
+    }
+
+    fn press(coord: (u8, u8)) -> ChordKey {
+        ChordKey {
+            coord,
+            press: true,
+            synthetic: false,
+        }
+    }
+
+    fn release(coord: (u8, u8)) -> ChordKey {
+        ChordKey {
+            press: false,
+            ..press(coord)
+        }
+    }
+
+    /// A chord's output and members
+    type ChordOutput = ((u8, u8), &'static [(u8, u8)]);
+    /// A chord and the member whose press leaked
+    type Leak = (usize, (u8, u8));
+
+    /// y + u = backspace, which is a key of its own, and two chords that
+    /// share escape
+    const LEAK_CHORDS: [ChordOutput; 3] = [
+        ((3, 8), &[(0, 6), (0, 7)]),
+        ((4, 0), &[(0, 0), (0, 1)]),
+        ((4, 0), &[(0, 10), (0, 11)]),
+    ];
+
+    fn press_at(key: &ChordKey) -> Option<(u8, u8)> {
+        key.press.then_some(key.coord)
+    }
+
+    fn suppress(input: &[ChordKey], output: &[ChordKey]) -> (Vec<ChordKey>, Vec<Leak>) {
+        let mut output = heapless::Vec::<_, 8>::from_slice(output).unwrap();
+        let leaks = suppress_leaks(input, &mut output, LEAK_CHORDS, press_at);
+        (output.to_vec(), leaks.to_vec())
+    }
+
+    #[test]
+    fn leaked_member_press_is_dropped() {
+        let input = [press((0, 0)), press((0, 1)), press((1, 3))];
+        let output = [press((4, 0)), press((0, 1)), press((1, 3))];
+        let (output, leaks) = suppress(&input, &output);
+        assert_eq!(output, [press((4, 0)), press((1, 3))]);
+        assert_eq!(leaks, [(1, (0, 1))]);
+    }
+
+    #[test]
+    fn chord_without_a_leak_is_untouched() {
+        let input = [press((0, 6)), press((0, 7)), release((2, 2))];
+        let output = [press((3, 8)), release((2, 2))];
+        let (out, leaks) = suppress(&input, &output);
+        assert_eq!(out, output);
+        assert!(leaks.is_empty());
+    }
+
+    #[test]
+    fn batches_without_a_chord_pass_through() {
+        // members of different chords, and backspace pressed by itself
+        let input = [press((0, 0)), press((0, 6)), press((3, 8))];
+        let (out, leaks) = suppress(&input, &input);
+        assert_eq!(out, input);
+        assert!(leaks.is_empty());
+
+        // a chord's members split over ticks come out as plain keys
+        let input = [press((0, 10))];
+        let (out, _) = suppress(&input, &input);
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn member_releases_are_kept() {
+        let input = [press((0, 10)), press((0, 11)), release((0, 11))];
+        let output = [press((4, 0)), release((0, 11))];
+        let (out, leaks) = suppress(&input, &output);
+        assert_eq!(out, output);
+        assert!(leaks.is_empty());
+    }
+
+    static ACTION_CHORDS: [ActionChord<char>; 2] = [
+        ActionChord {
+            action: 'a',
+            keys: &[(0, 0), (0, 11)],
+        },
+        ActionChord {
+            action: 'b',
+            keys: &[(2, 0), (2, 11)],
+        },
+    ];
+
+    fn chord_matcher() -> ActionChordMatcher<char, ChordKey, 2> {
+        ActionChordMatcher::new(&ACTION_CHORDS)
+    }
+
+    #[test]
+    fn action_chord_swallows_its_members() {
+        let mut m = chord_matcher();
+        let (action, passed) = m.event(press((0, 0)), 0, true);
+        assert_eq!((action, passed.as_slice()), (None, &[][..]));
+        let (action, passed) = m.event(press((0, 11)), 10, true);
+        assert_eq!((action, passed.as_slice()), (Some('a'), &[][..]));
+        for key in [(0, 11), (0, 0)] {
+            let (action, passed) = m.event(release(key), 20, true);
+            assert_eq!((action, passed.as_slice()), (None, &[][..]));
+        }
+        assert_eq!(m.deadline(), None);
+    }
+
+    #[test]
+    fn action_chord_modifiers_arent_held_back() {
+        let mut m = chord_matcher();
+        let (action, passed) = m.event(press((2, 0)), 0, false);
+        assert_eq!((action, passed.as_slice()), (None, &[press((2, 0))][..]));
+        assert!(!m.holding());
+        let (action, passed) = m.event(press((2, 11)), 10, false);
+        assert_eq!(
+            (action, passed.as_slice()),
+            (Some('b'), &[press((2, 11))][..])
+        );
+        // the layout saw the presses, so it sees the releases too
+        let (_, passed) = m.event(release((2, 0)), 20, false);
+        assert_eq!(passed.as_slice(), &[release((2, 0))]);
+    }
+
+    #[test]
+    fn other_keys_let_held_members_go_at_once() {
+        let mut m = chord_matcher();
+        m.event(press((0, 0)), 0, true);
+        assert!(m.holding());
+        let (action, passed) = m.event(press((3, 3)), 5, true);
+        assert_eq!(action, None);
+        assert_eq!(passed.as_slice(), &[press((0, 0)), press((3, 3))]);
+        assert!(!m.holding());
+
+        // and a modifier member stops counting once something else is typed
+        m.event(press((2, 0)), 10, false);
+        m.event(press((3, 3)), 15, true);
+        let (action, passed) = m.event(press((2, 11)), 20, false);
+        assert_eq!((action, passed.as_slice()), (None, &[press((2, 11))][..]));
+    }
+
+    #[test]
+    fn action_chord_members_go_after_the_window() {
+        let mut m = chord_matcher();
+        m.event(press((0, 0)), 0, true);
+        assert_eq!(m.deadline(), Some(ACTION_CHORD_WINDOW_MS));
+        assert!(m.poll(ACTION_CHORD_WINDOW_MS - 1).is_empty());
+        assert_eq!(m.poll(ACTION_CHORD_WINDOW_MS).as_slice(), &[press((0, 0))]);
+
+        // the other member on its own doesn't complete the chord
+        let (action, _) = m.event(press((0, 11)), ACTION_CHORD_WINDOW_MS + 5, true);
+        assert_eq!(action, None);
+    }
+
+    #[test]
+    fn synthetic_presses_never_start_a_chord() {
+        let mut m = chord_matcher();
+        let key = ChordKey {
+            synthetic: true,
+            ..press((0, 0))
+        };
+        let (_, passed) = m.event(key, 0, true);
+        assert_eq!(passed.as_slice(), &[key]);
+        assert!(!m.holding());
+    }
+
+    #[test]
+    fn action_chord_tables_are_checked() {
+        const fn chord(keys: &'static [(u8, u8)]) -> ActionChord<()> {
+            ActionChord { action: (), keys }
+        }
+        assert!(action_chords_valid(&ACTION_CHORDS, 4));
+        // a member off the matrix
+        assert!(!action_chords_valid(&ACTION_CHORDS, 2));
+        assert!(!action_chords_valid(&[chord(&[(0, 0)])], 4));
+        assert!(!action_chords_valid(
+            &[chord(&[(0, 0), (0, 1), (0, 2), (0, 3), (0, 4)])],
+            4
+        ));
+        // the smaller one would always fire first
+        assert!(!action_chords_valid(
+            &[chord(&[(0, 0), (0, 1)]), chord(&[(0, 0), (0, 1), (0, 2)])],
+            4
+        ));
+    }
+}
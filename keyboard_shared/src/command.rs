@@ -0,0 +1,766 @@
+//! Commands, acks and their checksums, which every frame on the wire
+//! is one of.
+
+use core::{
+    hash::{Hash, Hasher},
+    sync::atomic::AtomicU8,
+};
+
+use defmt::debug;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, defmt::Format, Debug)]
+pub struct Command<T> {
+    pub uuid: u8,
+    pub csum: u8,
+    pub cmd: T,
+}
+
+pub fn csum<T: Hash>(v: T) -> u8 {
+    let mut hasher = StableHasher::new(fnv::FnvHasher::default());
+    v.hash(&mut hasher);
+    let checksum = hasher.finish();
+
+    let bytes = checksum.to_le_bytes();
+
+    bytes.iter().fold(0, core::ops::BitXor::bitxor)
+}
+
+impl<T: Hash> Command<T> {
+    pub fn new(cmd: T) -> Self {
+        static UUID_GEN: AtomicU8 = AtomicU8::new(0);
+        let uuid = UUID_GEN.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        let csum = csum((&cmd, uuid));
+        Self { uuid, csum, cmd }
+    }
+
+    /// validate the data of the command
+    /// though the data will probably fail to deserialize if it has been corrupted, this just makes sure
+    pub fn validate(&self) -> bool {
+        let csum = csum((&self.cmd, self.uuid));
+        if csum == self.csum {
+            true
+        } else {
+            debug!(
+                "Invalid csum on {}, expected: {}, computed: {}",
+                core::any::type_name::<Self>(),
+                self.csum,
+                csum
+            );
+            false
+        }
+    }
+
+    pub fn ack(&self) -> Ack {
+        let csum = csum(self.uuid);
+        Ack {
+            uuid: self.uuid,
+            csum,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, defmt::Format, Debug)]
+pub struct Ack {
+    pub uuid: u8,
+    pub csum: u8,
+}
+
+#[derive(Serialize, Deserialize, defmt::Format, Debug)]
+#[repr(u8)]
+pub enum CmdOrAck<T> {
+    Cmd(Command<T>),
+    Ack(Ack),
+}
+
+impl Ack {
+    pub fn validate(self) -> Option<Self> {
+        let csum = csum(self.uuid);
+        if csum == self.csum {
+            Some(self)
+        } else {
+            None
+        }
+    }
+}
+
+/// Which lane a command waits in to go out, high priority commands are sent
+/// ahead of any low priority ones queued, but after acks
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format, Debug)]
+pub enum Priority {
+    /// Key events and the like, which shouldn't wait behind bulk traffic
+    High,
+    Low,
+}
+
+/// One of something for each `Priority`, such as the queue commands of that
+/// priority wait in
+pub struct Lanes<L> {
+    high: L,
+    low: L,
+}
+
+impl<L> Lanes<L> {
+    pub const fn new(high: L, low: L) -> Self {
+        Self { high, low }
+    }
+
+    pub fn lane(&self, priority: Priority) -> &L {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Low => &self.low,
+        }
+    }
+}
+
+/// Which of a link's queues a frame going out is taken from
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format, Debug)]
+pub enum Outgoing {
+    Ack,
+    Cmd(Priority),
+}
+
+impl Outgoing {
+    /// The order the queues are drained in. Acks go first so the other half
+    /// isn't left waiting on one stuck behind bulk traffic until it times
+    /// out and retransmits, adding to the traffic.
+    pub const ORDER: [Self; 3] = [
+        Outgoing::Ack,
+        Outgoing::Cmd(Priority::High),
+        Outgoing::Cmd(Priority::Low),
+    ];
+
+    /// The next frame to go out, the first `take` gives from each queue in
+    /// turn
+    pub fn first<F>(take: impl FnMut(Self) -> Option<F>) -> Option<F> {
+        Self::ORDER.into_iter().find_map(take)
+    }
+}
+
+/// What a frame off the wire turned out to be once its checksum was checked
+#[derive(defmt::Format, Debug)]
+pub enum Received<T> {
+    /// A command, which should be answered with `ack`
+    Cmd { cmd: T, ack: Ack },
+    /// The ack for the command with this uuid
+    Ack(u8),
+    /// The checksum didn't match, so it shouldn't be trusted
+    Corrupt,
+}
+
+impl<T: Hash> CmdOrAck<T> {
+    /// Check a decoded frame, this is all that happens between decoding a
+    /// frame and handing it on so it can be run off the keyboard too
+    pub fn receive(self) -> Received<T> {
+        match self {
+            CmdOrAck::Cmd(c) if c.validate() => Received::Cmd {
+                ack: c.ack(),
+                cmd: c.cmd,
+            },
+            CmdOrAck::Ack(a) => match a.validate() {
+                Some(a) => Received::Ack(a.uuid),
+                None => Received::Corrupt,
+            },
+            CmdOrAck::Cmd(_) => Received::Corrupt,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct StableHasher<T> {
+    inner: T,
+}
+
+impl<T: Hasher> Hasher for StableHasher<T> {
+    fn write_u8(&mut self, i: u8) {
+        self.write(&[i])
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        let bytes = i.to_le_bytes().iter().fold(0, core::ops::BitXor::bitxor);
+        self.write(&[bytes])
+    }
+
+    fn write_i8(&mut self, i: i8) {
+        self.write_u8(i as u8)
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16)
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32)
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128)
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_usize(i as usize)
+    }
+
+    fn finish(&self) -> u64 {
+        self.inner.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.inner.write(bytes);
+    }
+}
+
+impl<T> StableHasher<T> {
+    fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+    };
+
+    use super::*;
+    use crate::{
+        frame::{PackedRows, PACKED_ROWS_MAX},
+        link::{LinkByte, LinkReader},
+        protocol::{DomToSub, HostToKeyboard, KeyLocation, KeyboardToHost},
+        KeyboardSide,
+    };
+
+    #[test]
+    fn commands_go_in_their_priority_lane() {
+        let lanes = Lanes::new(RefCell::new(Vec::new()), RefCell::new(Vec::new()));
+        for (cmd, priority) in [
+            ("key", Priority::High),
+            ("pixels", Priority::Low),
+            ("ping", Priority::High),
+        ] {
+            lanes.lane(priority).borrow_mut().push(cmd);
+        }
+
+        assert_eq!(*lanes.lane(Priority::High).borrow(), ["key", "ping"]);
+        assert_eq!(*lanes.lane(Priority::Low).borrow(), ["pixels"]);
+    }
+
+    /// Time to send a byte between the halves, at 460800 baud with a start
+    /// and a stop bit
+    const LINK_BYTE_NS: u64 = 10 * 1_000_000_000 / 460_800;
+
+    fn frame_ns(frame: &CmdOrAck<DomToSub>) -> u64 {
+        postcard::to_allocvec_cobs(frame).unwrap().len() as u64 * LINK_BYTE_NS
+    }
+
+    /// How long each of `count` key presses, one every 7ms, takes from being
+    /// queued on the left half to reaching the right half, while the host
+    /// streams pixel rows to the right half as fast as they're acked. Each
+    /// lane sends a command and waits for its ack before the next, the left
+    /// half sends high priority commands before low ones, and the right half
+    /// sends an ack as soon as a frame arrives. With `lanes` false both
+    /// priorities share the low lane, as all commands did before.
+    fn key_latencies_under_render(lanes: bool, count: u64) -> Vec<u64> {
+        let pixels = frame_ns(&CmdOrAck::Cmd(Command::new(DomToSub::WritePixels {
+            row: 0,
+            data_0: [0xa5; 4],
+            data_1: [0x5a; 4],
+        })));
+        let key = frame_ns(&CmdOrAck::Cmd(Command::new(DomToSub::KeyPressed(
+            KeyLocation::pack(3, 5),
+        ))));
+        let ack = frame_ns(&CmdOrAck::Ack(Command::new(DomToSub::Reset).ack()));
+        // how deep `CommandChannel`'s lanes are
+        let depth = 4;
+
+        // each queued command is its priority and when it was queued, the
+        // host's pixel rows keep the low lane full
+        let queues = Lanes::new(RefCell::new(VecDeque::new()), RefCell::new(VecDeque::new()));
+        // when each lane's last command is acked
+        let acked = Lanes::new(Cell::new(0), Cell::new(0));
+        let lane_for = |priority| if lanes { priority } else { Priority::Low };
+
+        let mut presses = (0..count).map(|i| 1_000_000 + i * 7_013_000).peekable();
+        let mut latencies = Vec::new();
+        let mut right_tx_free = 0;
+        let mut now = 0;
+        while latencies.len() < count as usize {
+            while let Some(&at) = presses.peek() {
+                let mut queue = queues.lane(lane_for(Priority::High)).borrow_mut();
+                if at > now || queue.len() == depth {
+                    break;
+                }
+                queue.push_back((Priority::High, at));
+                presses.next();
+            }
+            let mut low = queues.lane(Priority::Low).borrow_mut();
+            while low.len() < depth {
+                low.push_back((Priority::Low, now));
+            }
+            drop(low);
+
+            let ready = [Priority::High, Priority::Low]
+                .into_iter()
+                .find(|&p| !queues.lane(p).borrow().is_empty() && acked.lane(p).get() <= now);
+            let Some(lane) = ready else {
+                // wait for an ack, or for the next press if there's room for it
+                let next_ack = [Priority::High, Priority::Low]
+                    .into_iter()
+                    .map(|p| acked.lane(p).get())
+                    .filter(|&t| t > now);
+                let next_press = presses.peek().copied().filter(|&at| at > now);
+                now = next_ack.chain(next_press).min().unwrap();
+                continue;
+            };
+
+            let (priority, queued) = queues.lane(lane).borrow_mut().pop_front().unwrap();
+            let arrived = now
+                + if priority == Priority::High {
+                    key
+                } else {
+                    pixels
+                };
+            if priority == Priority::High {
+                latencies.push(arrived - queued);
+            }
+            right_tx_free = right_tx_free.max(arrived) + ack;
+            acked.lane(lane).set(right_tx_free);
+            now = arrived;
+        }
+
+        latencies
+    }
+
+    #[test]
+    fn key_events_dont_wait_behind_pixel_rows() {
+        let pixels = frame_ns(&CmdOrAck::Cmd(Command::new(DomToSub::WritePixels {
+            row: 0,
+            data_0: [0xa5; 4],
+            data_1: [0x5a; 4],
+        })));
+
+        let laned = key_latencies_under_render(true, 200);
+        let shared = key_latencies_under_render(false, 200);
+        let worst = *laned.iter().max().unwrap();
+
+        // at worst a key press waits for the pixel row already going out,
+        // however long the render keeps the link busy
+        assert!(worst < 2 * pixels, "{worst}ns");
+        // where in one queue it waited for every row ahead of it to be acked
+        assert!(shared.iter().all(|&l| l > 3 * pixels), "{shared:?}");
+        // and the latency doesn't creep up as the render goes on
+        assert!(laned[150..].iter().max() <= laned[..50].iter().max());
+    }
+
+    /// Time the other half gets on top of the frames crossing the link to
+    /// handle a command and send the ack, as `messages::round_trip` allows
+    const HANDLING_SLACK_NS: u64 = 2_000_000;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Frame {
+        Ack(u32),
+        Cmd(u32, Priority),
+    }
+
+    /// What a lane in `flood_both_ways` is doing
+    #[derive(Clone, Copy)]
+    enum Sending {
+        /// Waiting on the ack for this command until the timeout
+        Waiting(u32, u64),
+        /// Sending the next command at this time
+        Next(u64),
+    }
+
+    /// One half of the link in `flood_both_ways`
+    struct Half {
+        acks: VecDeque<Frame>,
+        cmds: Lanes<VecDeque<Frame>>,
+        lanes: Lanes<Sending>,
+        /// Whether acks get their own queue, or wait with low priority
+        /// commands as they all did before
+        acks_first: bool,
+        /// The frame on the wire and when it's all arrived
+        on_wire: Option<(Frame, u64)>,
+    }
+
+    impl Half {
+        fn queue(&mut self, from: Outgoing) -> &mut VecDeque<Frame> {
+            match from {
+                Outgoing::Ack if self.acks_first => &mut self.acks,
+                Outgoing::Ack | Outgoing::Cmd(Priority::Low) => &mut self.cmds.low,
+                Outgoing::Cmd(Priority::High) => &mut self.cmds.high,
+            }
+        }
+
+        fn lane_mut(&mut self, priority: Priority) -> &mut Sending {
+            match priority {
+                Priority::High => &mut self.lanes.high,
+                Priority::Low => &mut self.lanes.low,
+            }
+        }
+
+        fn acks_waiting(&self) -> bool {
+            let mut queued = self.acks.iter().chain(&self.cmds.low);
+            queued.any(|f| matches!(f, Frame::Ack(_)))
+        }
+
+        /// When something next happens on this half
+        fn next_event(&self) -> u64 {
+            let lanes = [Priority::High, Priority::Low].map(|p| match *self.lanes.lane(p) {
+                Sending::Waiting(_, at) | Sending::Next(at) => at,
+            });
+            let arrival = self.on_wire.map(|(_, at)| at);
+            lanes.into_iter().chain(arrival).min().unwrap()
+        }
+    }
+
+    #[derive(Debug)]
+    struct Flood {
+        retransmits: u32,
+        /// Commands sent while an ack was waiting to go out
+        acks_held_back: u32,
+        /// Commands acked in time, both ways
+        acked: u32,
+        /// How long each half's UART was sending
+        busy: [u64; 2],
+    }
+
+    /// Both halves send pixel rows to each other for `duration`, each one as
+    /// soon as the last is acked, along with a key press every 3ms. A lane
+    /// sends its command again once it times out as `EventSender::send`
+    /// does. A half queues the ack for a command as soon as it's arrived, and
+    /// picks what to send next with `Outgoing::first`.
+    fn flood_both_ways(acks_first: bool, duration: u64) -> Flood {
+        let key = frame_ns(&CmdOrAck::Cmd(Command::new(DomToSub::KeyPressed(
+            KeyLocation::pack(3, 5),
+        ))));
+        let pixels = frame_ns(&CmdOrAck::Cmd(Command::new(DomToSub::WritePixels {
+            row: 0,
+            data_0: [0xa5; 4],
+            data_1: [0x5a; 4],
+        })));
+        let ack = frame_ns(&CmdOrAck::Ack(Command::new(DomToSub::Reset).ack()));
+        let frame_time = |frame| match frame {
+            Frame::Ack(_) => ack,
+            Frame::Cmd(_, Priority::High) => key,
+            Frame::Cmd(_, Priority::Low) => pixels,
+        };
+        // as `SendPolicy::KEY_EVENT` and `SendPolicy::BULK` time out
+        let timeouts = Lanes::new(
+            key + ack + HANDLING_SLACK_NS,
+            pixels + ack + HANDLING_SLACK_NS,
+        );
+        let key_interval = 3_000_000;
+
+        let mut flood = Flood {
+            retransmits: 0,
+            acks_held_back: 0,
+            acked: 0,
+            busy: [0; 2],
+        };
+        let mut uuids = 0..;
+        let mut send = |half: &mut Half, priority, now| {
+            let uuid = uuids.next().unwrap();
+            half.queue(Outgoing::Cmd(priority))
+                .push_back(Frame::Cmd(uuid, priority));
+            *half.lane_mut(priority) = Sending::Waiting(uuid, now + timeouts.lane(priority));
+        };
+
+        // the right half starts a little later, so the halves don't keep
+        // sending in step
+        let mut halves = [0, 1_234_567].map(|start| Half {
+            acks: VecDeque::new(),
+            cmds: Lanes::new(VecDeque::new(), VecDeque::new()),
+            lanes: Lanes::new(Sending::Next(start), Sending::Next(start)),
+            acks_first,
+            on_wire: None,
+        });
+
+        let mut now = 0;
+        while now < duration {
+            for from in 0..2 {
+                let Some((frame, at)) = halves[from].on_wire.filter(|&(_, at)| at <= now) else {
+                    continue;
+                };
+                halves[from].on_wire = None;
+                let to = &mut halves[1 - from];
+                let uuid = match frame {
+                    Frame::Cmd(uuid, _) => {
+                        to.queue(Outgoing::Ack).push_back(Frame::Ack(uuid));
+                        continue;
+                    }
+                    Frame::Ack(uuid) => uuid,
+                };
+                for priority in [Priority::High, Priority::Low] {
+                    // an ack for a command that already timed out is dropped
+                    if !matches!(to.lanes.lane(priority), Sending::Waiting(u, _) if *u == uuid) {
+                        continue;
+                    }
+                    flood.acked += 1;
+                    match priority {
+                        Priority::High => *to.lane_mut(priority) = Sending::Next(at + key_interval),
+                        Priority::Low => send(to, priority, at),
+                    }
+                }
+            }
+
+            for half in &mut halves {
+                for priority in [Priority::High, Priority::Low] {
+                    match *half.lanes.lane(priority) {
+                        Sending::Next(at) if at <= now => send(half, priority, now),
+                        Sending::Waiting(_, timeout) if timeout <= now => {
+                            flood.retransmits += 1;
+                            send(half, priority, now);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            for (half, busy) in halves.iter_mut().zip(&mut flood.busy) {
+                if half.on_wire.is_some() {
+                    continue;
+                }
+                let frame = Outgoing::first(|from| match from {
+                    // they're in with the low priority commands
+                    Outgoing::Ack if !half.acks_first => None,
+                    _ => half.queue(from).pop_front(),
+                });
+                let Some(frame) = frame else {
+                    continue;
+                };
+                if matches!(frame, Frame::Cmd(..)) && half.acks_waiting() {
+                    flood.acks_held_back += 1;
+                }
+                half.on_wire = Some((frame, now + frame_time(frame)));
+                *busy += frame_time(frame);
+            }
+
+            now = halves.iter().map(Half::next_event).min().unwrap();
+        }
+
+        flood
+    }
+
+    #[test]
+    fn acks_go_out_ahead_of_a_flood_both_ways() {
+        let duration = 1_000_000_000;
+        let flood = flood_both_ways(true, duration);
+
+        assert_eq!(flood.retransmits, 0, "{flood:?}");
+        assert_eq!(flood.acks_held_back, 0, "{flood:?}");
+        // and the link was kept busy both ways the whole time
+        assert!(
+            flood.busy.iter().all(|&b| b > duration * 9 / 10),
+            "{flood:?}"
+        );
+
+        // waiting behind commands, as they did before, acks are held back
+        let shared = flood_both_ways(false, duration);
+        assert!(shared.acks_held_back > 0, "{shared:?}");
+    }
+
+    #[test]
+    fn frames_that_fail_their_checksum_are_corrupt() {
+        let cmd = Command::new(HostToKeyboard::Lock(true));
+        let Ack { uuid, csum } = cmd.ack();
+        let bad_cmd = Command {
+            cmd: HostToKeyboard::Lock(false),
+            ..cmd
+        };
+        let bad_ack = Ack {
+            uuid,
+            csum: csum.wrapping_add(1),
+        };
+
+        assert!(matches!(
+            CmdOrAck::Cmd(Command::new(HostToKeyboard::Lock(true))).receive(),
+            Received::Cmd {
+                cmd: HostToKeyboard::Lock(true),
+                ..
+            }
+        ));
+        assert!(matches!(
+            CmdOrAck::<HostToKeyboard>::Ack(Ack { uuid, csum }).receive(),
+            Received::Ack(u) if u == uuid
+        ));
+        assert!(matches!(
+            CmdOrAck::Cmd(bad_cmd).receive(),
+            Received::Corrupt
+        ));
+        assert!(matches!(
+            CmdOrAck::<HostToKeyboard>::Ack(bad_ack).receive(),
+            Received::Corrupt
+        ));
+    }
+
+    /// Read commands from `bytes` as the halves do off the UART. A frame
+    /// that's too long, doesn't decode or fails its checksum is thrown away
+    /// and reading carries on. Packed pixel rows are unpacked as they would
+    /// be on the keyboard.
+    fn read_stream<T: serde::de::DeserializeOwned + Hash>(
+        bytes: &[u8],
+        mut f: impl FnMut(&T),
+    ) -> Vec<T> {
+        let mut reader = LinkReader::<128>::new();
+        let mut cmds = Vec::new();
+        for &byte in bytes {
+            let frame = match reader.byte(byte) {
+                LinkByte::Partial => continue,
+                LinkByte::TooLong => {
+                    reader.flush();
+                    continue;
+                }
+                LinkByte::Frame(frame) => frame,
+            };
+            match postcard::from_bytes_cobs::<CmdOrAck<T>>(frame).map(CmdOrAck::receive) {
+                Ok(Received::Cmd { cmd, .. }) => {
+                    reader.frame_ok();
+                    f(&cmd);
+                    cmds.push(cmd);
+                }
+                Ok(Received::Ack(_)) => reader.frame_ok(),
+                Ok(Received::Corrupt) | Err(_) => reader.flush(),
+            }
+        }
+        cmds
+    }
+
+    fn unpack_pixels(cmd: &HostToKeyboard) {
+        if let HostToKeyboard::WritePackedPixels { data, .. } = cmd {
+            let _ = data.unpack();
+        }
+    }
+
+    fn sample_commands() -> [HostToKeyboard; 6] {
+        [
+            HostToKeyboard::RequestStats,
+            HostToKeyboard::Lock(true),
+            HostToKeyboard::WritePixels {
+                side: KeyboardSide::Left,
+                row: 3,
+                data_0: [1, 2, 3, 4],
+                data_1: [0, 0, 0xff, 0],
+            },
+            HostToKeyboard::WritePackedPixels {
+                side: KeyboardSide::Right,
+                row: 6,
+                data: PackedRows::new(&[0x83, 0, 0x03, 1, 2, 3, 4]).unwrap(),
+            },
+            HostToKeyboard::ExportSettings { offset: 300 },
+            HostToKeyboard::LedTestColour(Some([1, 2, 3])),
+        ]
+    }
+
+    fn encode(cmd: &HostToKeyboard) -> Vec<u8> {
+        postcard::to_allocvec_cobs(&CmdOrAck::Cmd(Command::new(cmd.clone()))).unwrap()
+    }
+
+    #[test]
+    fn packed_rows_longer_than_the_rows_dont_decode() {
+        let fits = postcard::to_allocvec(&[1u8; PACKED_ROWS_MAX].to_vec()).unwrap();
+        let packed: PackedRows = postcard::from_bytes(&fits).unwrap();
+        assert_eq!(packed.as_bytes(), [1; PACKED_ROWS_MAX]);
+
+        let long = postcard::to_allocvec(&[1u8; PACKED_ROWS_MAX + 1].to_vec()).unwrap();
+        assert!(postcard::from_bytes::<PackedRows>(&long).is_err());
+    }
+
+    /// Seeded, so CI runs the same cases every time
+    fn fuzz_config() -> proptest::test_runner::Config {
+        proptest::test_runner::Config {
+            cases: 512,
+            rng_seed: proptest::test_runner::RngSeed::Fixed(0x1482),
+            failure_persistence: None,
+            ..Default::default()
+        }
+    }
+
+    proptest::proptest! {
+        #![proptest_config(fuzz_config())]
+
+        #[test]
+        fn garbage_doesnt_hide_the_next_frame(
+            garbage in proptest::collection::vec(proptest::arbitrary::any::<u8>(), 0..600),
+            pick in 0..6usize,
+        ) {
+            let cmd = &sample_commands()[pick];
+            let mut bytes = garbage.clone();
+            bytes.push(0);
+            bytes.extend(encode(cmd));
+
+            let cmds = read_stream::<HostToKeyboard>(&bytes, unpack_pixels);
+            proptest::prop_assert_eq!(cmds.last(), Some(cmd));
+            read_stream::<KeyboardToHost>(&garbage, |_| {});
+        }
+
+        #[test]
+        fn packed_rows_never_unpack_past_the_rows(
+            tokens in proptest::collection::vec(
+                proptest::arbitrary::any::<u8>(),
+                0..=PACKED_ROWS_MAX,
+            ),
+        ) {
+            // `unpack` returns exactly the two rows or nothing, and mustn't
+            // panic on the way
+            let _ = PackedRows::new(&tokens).unwrap().unpack();
+        }
+
+        #[test]
+        fn mangled_frames_dont_hide_the_next_one(
+            pick in 0..6usize,
+            next in 0..6usize,
+            edits in proptest::collection::vec(
+                (
+                    proptest::arbitrary::any::<proptest::sample::Index>(),
+                    proptest::arbitrary::any::<u8>(),
+                    0..3u8,
+                ),
+                1..4,
+            ),
+        ) {
+            let mut bytes = encode(&sample_commands()[pick]);
+            for (at, byte, edit) in edits {
+                match edit {
+                    0 => {
+                        let at = at.index(bytes.len());
+                        bytes[at] = byte;
+                    }
+                    1 => bytes.insert(at.index(bytes.len() + 1), byte),
+                    _ if bytes.len() > 1 => {
+                        bytes.remove(at.index(bytes.len()));
+                    }
+                    _ => {}
+                }
+            }
+            // wherever the edits left the frame's end, one always follows
+            bytes.push(0);
+            let cmd = &sample_commands()[next];
+            bytes.extend(encode(cmd));
+
+            let cmds = read_stream::<HostToKeyboard>(&bytes, unpack_pixels);
+            proptest::prop_assert_eq!(cmds.last(), Some(cmd));
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Per key debouncing, with thresholds that learn from chatter.
+
+use serde::{Deserialize, Serialize};
+
+/// A key's debounce threshold changing, because it chattered or because it
+/// went long enough without
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct DebounceAdjustment {
+    pub row: u8,
+    pub col: u8,
+    /// The new threshold, in scans
+    pub scans: u16,
+    pub secs_ago: u32,
+}
+
+impl DebounceAdjustment {
+    pub const EMPTY: Self = Self {
+        row: 0,
+        col: 0,
+        scans: 0,
+        secs_ago: 0,
+    };
+}
+
+/// Most recent debounce adjustments the keyboard remembers
+pub const MAX_DEBOUNCE_ADJUSTMENTS: usize = 8;
+
+/// Time between scans of the key matrix, which debounce thresholds are
+/// counted in
+pub const SCAN_PERIOD_US: u32 = 200;
+
+/// How far a debounce threshold moves at a time, in scans
+pub const DEBOUNCE_STEP_TICKS: u16 = 25;
+/// Highest a debounce threshold can go, in scans. Accepted presses are at
+/// least two thresholds apart, so past `CHATTER_MS` chatter couldn't be seen
+/// any more.
+pub const DEBOUNCE_MAX_TICKS: u16 = 100;
+/// Presses of one key closer together than this are chatter
+pub const CHATTER_MS: u64 = 50;
+/// Lower a debounce threshold after this long without chatter
+pub const DEBOUNCE_DECAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// The chatter average is kept in 1/4096ths
+const CHATTER_AVG_ONE: u32 = 4096;
+/// Each press moves the chatter average 1/16th of the way
+const CHATTER_AVG_SHIFT: u32 = 4;
+/// Raise a threshold once more than about 1 in 8 recent presses chatter
+const CHATTER_RAISE_AT: u32 = CHATTER_AVG_ONE / 8;
+
+/// One key debounced on its own, with a threshold that learns from chatter.
+///
+/// Two presses closer together than `CHATTER_MS` are taken to be chatter,
+/// since nobody types that fast. The key keeps a moving average of how many
+/// of its presses chatter, and once that gets too high its threshold goes up
+/// a step, up to `DEBOUNCE_MAX_TICKS`. After `DEBOUNCE_DECAY_MS` without
+/// chattering it comes back down a step, until it's back where it started.
+#[derive(Clone, Copy, Debug)]
+pub struct DebouncedKey {
+    pressed: bool,
+    /// Scans in a row that the raw state has disagreed with `pressed`
+    pending: u16,
+    ticks: u16,
+    /// The threshold it starts at and decays back to
+    base_ticks: u16,
+    last_press_ms: Option<u64>,
+    chatter_avg: u32,
+    chatters: u16,
+    clean_since_ms: u64,
+}
+
+impl DebouncedKey {
+    pub const fn new(ticks: u16) -> Self {
+        Self {
+            pressed: false,
+            pending: 0,
+            ticks,
+            base_ticks: ticks,
+            last_press_ms: None,
+            chatter_avg: 0,
+            chatters: 0,
+            clean_since_ms: 0,
+        }
+    }
+
+    /// Scans a change has to hold for before it's accepted
+    pub fn ticks(&self) -> u16 {
+        self.ticks
+    }
+
+    /// Presses that were chatter
+    pub fn chatters(&self) -> u16 {
+        self.chatters
+    }
+
+    /// Feed in a scan of the key, returning whether it's now pressed if a
+    /// change held for long enough, and whether the threshold or chatter
+    /// count moved. With `accept` false a change that's ready is held for a
+    /// later scan.
+    pub fn scan(&mut self, raw: bool, now_ms: u64, accept: bool) -> (Option<bool>, bool) {
+        let mut changed = self.decay(now_ms);
+
+        if raw == self.pressed {
+            self.pending = 0;
+            return (None, changed);
+        }
+
+        self.pending = self.pending.saturating_add(1);
+        if self.pending < self.ticks || !accept {
+            return (None, changed);
+        }
+
+        self.pressed = raw;
+        self.pending = 0;
+        if raw {
+            changed |= self.press(now_ms);
+        }
+
+        (Some(raw), changed)
+    }
+
+    /// Note an accepted press, true if it chattered
+    fn press(&mut self, now_ms: u64) -> bool {
+        let chattered = matches!(self.last_press_ms, Some(last) if now_ms - last < CHATTER_MS);
+        self.last_press_ms = Some(now_ms);
+
+        let sample = if chattered { CHATTER_AVG_ONE } else { 0 };
+        self.chatter_avg = self.chatter_avg - (self.chatter_avg >> CHATTER_AVG_SHIFT)
+            + (sample >> CHATTER_AVG_SHIFT);
+
+        if chattered {
+            self.chatters = self.chatters.saturating_add(1);
+            self.clean_since_ms = now_ms;
+
+            if self.chatter_avg > CHATTER_RAISE_AT && self.ticks < DEBOUNCE_MAX_TICKS {
+                self.ticks = (self.ticks + DEBOUNCE_STEP_TICKS).min(DEBOUNCE_MAX_TICKS);
+                // start over, to see whether the new threshold is enough
+                self.chatter_avg = 0;
+            }
+        }
+
+        chattered
+    }
+
+    /// Step the threshold back down after a clean stretch, true if it moved
+    fn decay(&mut self, now_ms: u64) -> bool {
+        if self.ticks <= self.base_ticks || now_ms - self.clean_since_ms < DEBOUNCE_DECAY_MS {
+            return false;
+        }
+
+        self.ticks = self
+            .ticks
+            .saturating_sub(DEBOUNCE_STEP_TICKS)
+            .max(self.base_ticks);
+        self.clean_since_ms = now_ms;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scans in one synthetic keystroke, 100ms held then 200ms released
+    const STROKE_SCANS: u64 = 1500;
+
+    /// Type `strokes` keystrokes on `key` from `start_ms`, where the switch
+    /// opens for `gap` scans starting `after` scans into each press. Returns
+    /// how many presses got through for each keystroke.
+    fn type_with_chatter(
+        key: &mut DebouncedKey,
+        start_ms: u64,
+        strokes: u64,
+        after: u64,
+        gap: u64,
+    ) -> Vec<usize> {
+        (0..strokes)
+            .map(|stroke| {
+                (0..STROKE_SCANS)
+                    .filter(|&scan| {
+                        let raw = scan < 500 && !(after..after + gap).contains(&scan);
+                        let now_us = (stroke * STROKE_SCANS + scan) * SCAN_PERIOD_US as u64;
+                        let (edge, _) = key.scan(raw, start_ms + now_us / 1000, true);
+                        edge == Some(true)
+                    })
+                    .count()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debounce_waits_for_the_threshold() {
+        let mut key = DebouncedKey::new(50);
+        let mut edges = (0..200).filter_map(|scan| {
+            // a 40 scan bounce at the start of the press, then held
+            let raw = !(10..50).contains(&scan);
+            key.scan(raw, 0, true).0.map(|e| (scan, e))
+        });
+
+        assert_eq!(edges.next(), Some((99, true)));
+        assert_eq!(edges.next(), None);
+    }
+
+    #[test]
+    fn debounce_holds_changes_until_theres_room() {
+        let mut key = DebouncedKey::new(2);
+        assert_eq!(key.scan(true, 0, true), (None, false));
+        assert_eq!(key.scan(true, 0, false), (None, false));
+        assert_eq!(key.scan(true, 0, false), (None, false));
+        assert_eq!(key.scan(true, 0, true), (Some(true), false));
+        assert_eq!(key.scan(true, 0, true), (None, false));
+    }
+
+    #[test]
+    fn chattering_key_settles_on_a_threshold_that_hides_it() {
+        // a 12ms gap gets past 10ms of debouncing but not 15ms
+        let mut key = DebouncedKey::new(50);
+        let presses = type_with_chatter(&mut key, 0, 20, 60, 60);
+
+        assert_eq!(key.ticks(), 50 + DEBOUNCE_STEP_TICKS);
+        assert_eq!(presses[..3], [2, 2, 2]);
+        assert!(presses[3..].iter().all(|&p| p == 1), "{presses:?}");
+        assert_eq!(key.chatters(), 3);
+    }
+
+    #[test]
+    fn chatter_is_still_seen_at_the_cap() {
+        // a 22ms gap gets past every threshold, and the presses either side
+        // of it are still close enough to count
+        let mut key = DebouncedKey::new(50);
+        let presses = type_with_chatter(&mut key, 0, 30, 110, 110);
+
+        assert_eq!(key.ticks(), DEBOUNCE_MAX_TICKS);
+        assert!(presses.iter().all(|&p| p == 2), "{presses:?}");
+        assert_eq!(key.chatters(), 30);
+    }
+
+    #[test]
+    fn clean_keys_dont_move() {
+        let mut key = DebouncedKey::new(50);
+        let presses = type_with_chatter(&mut key, 0, 50, 0, 0);
+
+        assert!(presses.iter().all(|&p| p == 1));
+        assert_eq!((key.ticks(), key.chatters()), (50, 0));
+    }
+
+    #[test]
+    fn debounce_decays_a_step_a_day_once_it_stops_chattering() {
+        let mut key = DebouncedKey::new(50);
+        type_with_chatter(&mut key, 0, 30, 110, 110);
+        assert_eq!(key.ticks(), DEBOUNCE_MAX_TICKS);
+        let last_chatter_ms = 30 * STROKE_SCANS * SCAN_PERIOD_US as u64 / 1000;
+
+        // typing cleanly doesn't bring it down until a day has passed
+        type_with_chatter(&mut key, last_chatter_ms, 10, 0, 0);
+        assert_eq!(key.ticks(), DEBOUNCE_MAX_TICKS);
+        assert_eq!(
+            key.scan(false, last_chatter_ms + DEBOUNCE_DECAY_MS - 1000, true),
+            (None, false)
+        );
+
+        let day = |n| last_chatter_ms + n * DEBOUNCE_DECAY_MS;
+        assert_eq!(key.scan(false, day(1), true), (None, true));
+        assert_eq!(key.ticks(), DEBOUNCE_MAX_TICKS - DEBOUNCE_STEP_TICKS);
+        assert_eq!(key.scan(false, day(2), true), (None, true));
+        assert_eq!(key.ticks(), 50);
+        assert_eq!(key.scan(false, day(3), true), (None, false));
+        assert_eq!(key.ticks(), 50);
+    }
+}
@@ -0,0 +1,245 @@
+//! Counters and reports the host reads to see how the keyboard is doing.
+
+use serde::{Deserialize, Serialize};
+
+/// Power on self test results for one half, `true` means the check passed
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct SelfTestResults {
+    /// The display answered on I2C
+    pub oled: bool,
+    /// The other half answered over the UART link
+    pub link: bool,
+    /// A frame could be written to the LED chain
+    pub leds: bool,
+    /// No key read as pressed during boot
+    pub matrix: bool,
+}
+
+impl SelfTestResults {
+    pub fn checks(&self) -> [(&'static str, bool); 4] {
+        [
+            ("oled", self.oled),
+            ("link", self.link),
+            ("leds", self.leds),
+            ("keys", self.matrix),
+        ]
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks().iter().all(|(_, passed)| *passed)
+    }
+}
+
+/// What reset a half during the boots that put it in safe mode
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum SafeModeReason {
+    /// Reset itself, as a panic does in release builds
+    Panic,
+    /// Locked up, from a fault while handling a fault
+    Lockup,
+    Watchdog,
+    /// The reset pin, or anything else that keeps RAM
+    Other,
+}
+
+/// How much the firmware logs from its hot paths, when a debugger is attached
+#[derive(
+    Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord, defmt::Format, Hash, Clone, Copy, Debug,
+)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Nothing per frame or per key event
+    Quiet,
+    /// Every key event, and a sample of the link frames
+    Sampled,
+    /// Every key event and link frame
+    Verbose,
+}
+
+/// How serious a `Log` from the keyboard is, most serious first
+#[derive(
+    Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord, defmt::Format, Hash, Clone, Copy, Debug,
+)]
+#[repr(u8)]
+pub enum LogSeverity {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /// The least serious `Log` sent to the host at this verbosity
+    pub fn host_log_threshold(self) -> LogSeverity {
+        match self {
+            LogLevel::Quiet => LogSeverity::Warn,
+            LogLevel::Sampled => LogSeverity::Info,
+            LogLevel::Verbose => LogSeverity::Debug,
+        }
+    }
+}
+
+/// Whether taking `bytes` from a heap with `free` bytes left still leaves
+/// `reserve` of them, the firmware turns down allocations that don't
+pub const fn leaves_reserve(free: usize, bytes: usize, reserve: usize) -> bool {
+    match bytes.checked_add(reserve) {
+        Some(needed) => free >= needed,
+        None => false,
+    }
+}
+
+/// Where a key event entered the processed event stream
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum EventSource {
+    /// This half's matrix
+    Local,
+    /// The other half's matrix
+    Remote,
+    /// Output of chord resolution on either half
+    Chord,
+    Macro,
+    /// Generated by the firmware itself, such as releases for keys that got stuck
+    Synthetic,
+}
+
+impl EventSource {
+    pub const COUNT: usize = 5;
+    pub const ALL: [EventSource; Self::COUNT] = [
+        EventSource::Local,
+        EventSource::Remote,
+        EventSource::Chord,
+        EventSource::Macro,
+        EventSource::Synthetic,
+    ];
+}
+
+/// Fire-and-forget channels that drop messages when full, drops are counted
+/// for each of these
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum DropChannel {
+    /// Debounced key events for the LED effects
+    LedKeyListen,
+    /// Debounced key events on their way to the other half
+    KeyTransmit,
+    /// Periodic LED frame counter resyncs
+    LedResync,
+    /// Display pixel rows on their way to the host
+    PixelRow,
+    InjectKey,
+    /// `LinkDegraded` and the right half's `EnteringBootloader` notifications
+    /// on their way to the host
+    LinkStatus,
+    /// Mirrored HID reports on their way to the host
+    Report,
+    /// `Log` messages on their way to the host
+    HostLog,
+    /// `StatsV2` on its way to the host once the right half has answered
+    Stats,
+}
+
+impl DropChannel {
+    pub const COUNT: usize = 9;
+    pub const ALL: [DropChannel; Self::COUNT] = [
+        DropChannel::LedKeyListen,
+        DropChannel::KeyTransmit,
+        DropChannel::LedResync,
+        DropChannel::PixelRow,
+        DropChannel::InjectKey,
+        DropChannel::LinkStatus,
+        DropChannel::Report,
+        DropChannel::HostLog,
+        DropChannel::Stats,
+    ];
+}
+
+/// What went wrong reading from the link between the halves, errors are
+/// counted for each of these
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum LinkErrorKind {
+    /// A byte arrived before the one before it was taken
+    Overrun,
+    Parity,
+    /// A byte's stop bit never came
+    Framing,
+    /// The line was held low for longer than a byte, as it is while the
+    /// other half resets
+    Break,
+    /// The UART driver failed the read
+    Driver,
+}
+
+impl LinkErrorKind {
+    pub const COUNT: usize = 5;
+    pub const ALL: [LinkErrorKind; Self::COUNT] = [
+        LinkErrorKind::Overrun,
+        LinkErrorKind::Parity,
+        LinkErrorKind::Framing,
+        LinkErrorKind::Break,
+        LinkErrorKind::Driver,
+    ];
+}
+
+/// What a `UsageEntry` counts
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum UsageKind {
+    /// Fires of a chord, indexed like the layout's chords
+    Chord,
+    /// Switches to a layer, with the time spent on it
+    Layer,
+    /// Presses of a hold-tap that resolved as a tap, indexed by the key's
+    /// position in the layout, `row * KEY_COLS + col`
+    Tap,
+    /// Presses of a hold-tap that resolved as a hold, indexed like `Tap`
+    Hold,
+}
+
+/// One counter from the usage stats, these only last until the keyboard
+/// restarts
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct UsageEntry {
+    pub kind: UsageKind,
+    pub index: u8,
+    pub count: u32,
+    /// Time spent active, only counted for layers
+    pub millis: u32,
+}
+
+impl UsageEntry {
+    pub const EMPTY: Self = Self {
+        kind: UsageKind::Chord,
+        index: 0,
+        count: 0,
+        millis: 0,
+    };
+}
+
+/// Usage stats carried by each `Usage` message
+pub const USAGE_CHUNK: usize = 8;
+
+/// Exclusive upper bounds of the press duration buckets in the timing stats,
+/// the final bucket catches everything longer
+pub const TIMING_BUCKETS_MS: [u16; 5] = [50, 100, 150, 200, 300];
+pub const TIMING_BUCKETS: usize = TIMING_BUCKETS_MS.len() + 1;
+
+/// Exclusive upper bounds of the key press to HID report latency buckets, the
+/// final bucket catches everything longer
+pub const LATENCY_BUCKETS_US: [u16; 7] = [250, 500, 1000, 2000, 4000, 8000, 16000];
+pub const LATENCY_BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_keep_the_reserve() {
+        assert!(leaves_reserve(1024 + 64, 64, 1024));
+        assert!(!leaves_reserve(1024 + 63, 64, 1024));
+        assert!(!leaves_reserve(512, 0, 1024));
+        assert!(!leaves_reserve(usize::MAX, usize::MAX, 1024));
+    }
+}
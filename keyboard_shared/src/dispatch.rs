@@ -0,0 +1,985 @@
+//! What the left half does with a command from the host: what it handles
+//! itself, what goes over the link to the right half and what the host is
+//! told straight away. Carrying it out is up to the firmware.
+
+use crate::{
+    frame::{FrameCheck, DISPLAY_ROWS},
+    protocol::{DomToSub, HostToKeyboard, KeyboardToHost},
+    tuning::Tuning,
+    KeyboardSide,
+};
+
+/// Most steps a single command takes
+pub const MAX_STEPS: usize = 4;
+
+/// How a command forwarded to the right half is sent, each is one of the
+/// firmware's `SendPolicy`s
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Traffic {
+    KeyEvent,
+    Bulk,
+    Background,
+}
+
+/// Something to do for a command from the host, in the order given by
+/// [`HostRouter::route`]
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Step {
+    /// Handle the command on this half, as the host sent it
+    Local,
+    /// Use these timings on this half without saving them
+    UseTuning(Tuning),
+    /// Stage a pair of rows on this half's display
+    Draw {
+        row: u8,
+        rows: [[u8; 4]; 2],
+    },
+    /// Show the rows staged on this half's display
+    Flush,
+    /// Throw away the rows staged on this half's display
+    Discard,
+    /// Count a pixel write turned away for being out of bounds
+    RejectedPixels {
+        row: u8,
+    },
+    /// Count a pixel write for the right half dropped while the link is
+    /// degraded
+    DroppedPixels,
+    /// Forward a command over the link
+    Peer(DomToSub, Traffic),
+    Reply(KeyboardToHost),
+    /// Reply with the timings in use and whether they're saved
+    ReplyTuning,
+}
+
+pub type Steps = heapless::Vec<Step, MAX_STEPS>;
+
+/// What routing needs to know about the rest of the keyboard
+#[derive(Clone, Copy, Debug)]
+pub struct Conditions {
+    /// The right half has answered `Hello`, and hasn't gone missing since
+    pub link_up: bool,
+    /// Bulk traffic for the right half is being dropped
+    pub link_degraded: bool,
+    /// The timings in use
+    pub tuning: Tuning,
+    /// The timings saved with the settings
+    pub saved_tuning: Tuning,
+}
+
+/// Whether both of the rows starting at `row` are on the display, anything
+/// from the host is checked before it's queued
+pub fn row_in_bounds(row: u8) -> bool {
+    row as usize + 1 < DISPLAY_ROWS
+}
+
+/// Routes commands from the host, keeping the state the host has set up
+/// that only lasts while it keeps talking to us
+pub struct HostRouter {
+    led_test_colour: bool,
+    led_test_index: bool,
+    mirror_reports: bool,
+    /// The host sends `FlushDisplay` or `EndFrame`, so the last rows of the
+    /// display don't need to flush it
+    flush_markers: bool,
+    /// The frame the host has started on each side, by `KeyboardSide`. The
+    /// right half checks its own frames too, as rows can go missing on the
+    /// way over.
+    frames: [Option<FrameCheck>; 2],
+}
+
+impl HostRouter {
+    pub const fn new() -> Self {
+        Self {
+            led_test_colour: false,
+            led_test_index: false,
+            mirror_reports: false,
+            flush_markers: false,
+            frames: [None; 2],
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.led_test_colour || self.led_test_index || self.mirror_reports
+    }
+
+    /// The session is over, returns the commands that undo anything the host
+    /// left running, to be routed like any other
+    pub fn end(&mut self) -> heapless::Vec<HostToKeyboard, 4> {
+        let mut undo = heapless::Vec::new();
+        self.flush_markers = false;
+        self.frames = [None; 2];
+        if self.mirror_reports {
+            let _ = undo.push(HostToKeyboard::SubscribeReports(false));
+        }
+        if self.led_test_colour {
+            let _ = undo.push(HostToKeyboard::LedTestColour(None));
+        }
+        if self.led_test_index {
+            for side in [KeyboardSide::Left, KeyboardSide::Right] {
+                let _ = undo.push(HostToKeyboard::LedTestIndex { side, index: None });
+            }
+        }
+        undo
+    }
+
+    /// Work out the steps for a command from the host
+    pub fn route(&mut self, cmd: &HostToKeyboard, now: Conditions) -> Steps {
+        let mut steps = Steps::new();
+        let mut step = |s| {
+            let _ = steps.push(s);
+        };
+
+        match *cmd {
+            HostToKeyboard::KeepAlive => {}
+            HostToKeyboard::RequestStats => {
+                step(Step::Local);
+                // the right half's count comes back in its own time, and is
+                // passed on as `StatsV2`
+                if now.link_up {
+                    step(Step::Peer(DomToSub::RequestStats, Traffic::Bulk));
+                }
+            }
+            HostToKeyboard::ReadPixels { side, row } => match side {
+                KeyboardSide::Left => step(Step::Local),
+                KeyboardSide::Right => {
+                    step(Step::Peer(DomToSub::ReadPixels { row }, Traffic::Bulk))
+                }
+            },
+            HostToKeyboard::LedTestColour(colour) => {
+                self.led_test_colour = colour.is_some();
+                step(Step::Local);
+                step(Step::Peer(
+                    DomToSub::LedTestColour(colour),
+                    Traffic::KeyEvent,
+                ));
+            }
+            HostToKeyboard::LedTestIndex { side, index } => {
+                self.led_test_index = index.is_some();
+                match side {
+                    KeyboardSide::Left => step(Step::Local),
+                    KeyboardSide::Right => {
+                        step(Step::Peer(DomToSub::LedTestIndex(index), Traffic::KeyEvent))
+                    }
+                }
+            }
+            HostToKeyboard::SetKeyLed {
+                side,
+                index,
+                r,
+                g,
+                b,
+            } => match side {
+                KeyboardSide::Left => step(Step::Local),
+                KeyboardSide::Right => step(Step::Peer(
+                    DomToSub::SetKeyLed { index, r, g, b },
+                    Traffic::KeyEvent,
+                )),
+            },
+            HostToKeyboard::ClearKeyLeds => {
+                step(Step::Local);
+                step(Step::Peer(DomToSub::ClearKeyLeds, Traffic::KeyEvent));
+            }
+            HostToKeyboard::WritePixels {
+                side,
+                row,
+                data_0,
+                data_1,
+            } => self.write_pixels(&mut step, now, side, row, [data_0, data_1]),
+            HostToKeyboard::WritePackedPixels { side, row, data } => match data.unpack() {
+                Some(rows) => self.write_pixels(&mut step, now, side, row, rows),
+                None => reject_pixels(&mut step, side, row),
+            },
+            HostToKeyboard::SetTime { seconds_of_day } => {
+                step(Step::Local);
+                step(Step::Peer(
+                    DomToSub::SetTime { seconds_of_day },
+                    Traffic::Background,
+                ));
+            }
+            HostToKeyboard::SetTuning {
+                chord_window_ms,
+                hold_tap_timeout_ms,
+            } => {
+                let tuning = Tuning {
+                    chord_window_ms: chord_window_ms.unwrap_or(now.tuning.chord_window_ms),
+                    hold_tap_timeout_ms: hold_tap_timeout_ms
+                        .unwrap_or(now.tuning.hold_tap_timeout_ms),
+                };
+
+                if tuning.valid() {
+                    use_tuning(&mut step, tuning);
+                    step(Step::ReplyTuning);
+                } else {
+                    step(Step::Reply(KeyboardToHost::TuningRejected));
+                }
+            }
+            HostToKeyboard::CommitTuning => {
+                step(Step::Local);
+                step(Step::ReplyTuning);
+            }
+            HostToKeyboard::RevertTuning => {
+                use_tuning(&mut step, now.saved_tuning);
+                step(Step::ReplyTuning);
+            }
+            HostToKeyboard::RequestTuning => step(Step::ReplyTuning),
+            HostToKeyboard::SetHoldTap { behaviour, .. } => match behaviour {
+                Some(b) if !b.valid() => step(Step::Reply(KeyboardToHost::HoldTapRejected)),
+                _ => step(Step::Local),
+            },
+            HostToKeyboard::SetLogLevel(level) => {
+                step(Step::Local);
+                step(Step::Peer(DomToSub::SetLogLevel(level), Traffic::KeyEvent));
+            }
+            HostToKeyboard::FlushDisplay { side } => {
+                self.flush_markers = true;
+                flush_display(&mut step, now, side);
+            }
+            HostToKeyboard::BeginFrame { side, frame_id } => {
+                self.flush_markers = true;
+                let unended = self.frames[side as usize]
+                    .replace(FrameCheck::new(frame_id))
+                    .is_some();
+                match side {
+                    KeyboardSide::Left if unended => step(Step::Discard),
+                    KeyboardSide::Left => {}
+                    // the right half throws away an unended frame itself
+                    KeyboardSide::Right if now.link_degraded => {}
+                    KeyboardSide::Right => {
+                        step(Step::Peer(DomToSub::BeginFrame { frame_id }, Traffic::Bulk))
+                    }
+                }
+            }
+            HostToKeyboard::EndFrame {
+                side,
+                frame_id,
+                crc,
+            } => self.end_frame(&mut step, now, side, frame_id, crc),
+            HostToKeyboard::SubscribeReports(on) => {
+                self.mirror_reports = on;
+                step(Step::Local);
+            }
+            HostToKeyboard::EnterBootloader { side } => match side {
+                KeyboardSide::Left => step(Step::Local),
+                // the right half's reply is passed on as it arrives, without
+                // the link the command would wait and go off whenever it
+                // came up
+                KeyboardSide::Right if now.link_up => {
+                    step(Step::Peer(DomToSub::EnterBootloader, Traffic::KeyEvent))
+                }
+                KeyboardSide::Right => {}
+            },
+            HostToKeyboard::RequestDisplayInfo { .. }
+            | HostToKeyboard::Lock(_)
+            | HostToKeyboard::ShowDebugScreen(_)
+            | HostToKeyboard::SetConfig(_)
+            | HostToKeyboard::SetLedCalibration { .. }
+            | HostToKeyboard::SetKeyAction { .. }
+            | HostToKeyboard::ClearRemaps
+            | HostToKeyboard::ExportSettings { .. }
+            | HostToKeyboard::ImportSettings { .. }
+            | HostToKeyboard::RequestTimingStats
+            | HostToKeyboard::RequestUsageStats
+            | HostToKeyboard::RequestDebounce
+            | HostToKeyboard::RequestStatus
+            | HostToKeyboard::RequestLatency
+            | HostToKeyboard::RequestKeyPresses
+            | HostToKeyboard::InjectKey { .. }
+            | HostToKeyboard::InjectRemoteKey { .. }
+            | HostToKeyboard::RequestHoldTap { .. }
+            | HostToKeyboard::FactoryReset
+            | HostToKeyboard::PrepareShutdown
+            | HostToKeyboard::Identify
+            | HostToKeyboard::ForceAllocation { .. }
+            | HostToKeyboard::ReadCapture { .. }
+            | HostToKeyboard::ClearCapture => step(Step::Local),
+        }
+
+        steps
+    }
+
+    fn write_pixels(
+        &mut self,
+        step: &mut impl FnMut(Step),
+        now: Conditions,
+        side: KeyboardSide,
+        row: u8,
+        rows: [[u8; 4]; 2],
+    ) {
+        match side {
+            _ if !row_in_bounds(row) => return reject_pixels(step, side, row),
+            KeyboardSide::Left => step(Step::Draw { row, rows }),
+            KeyboardSide::Right if now.link_degraded => {
+                step(Step::DroppedPixels);
+                if let Some(frame) = &mut self.frames[side as usize] {
+                    frame.drop_rows();
+                }
+                return;
+            }
+            KeyboardSide::Right => {
+                let [data_0, data_1] = rows;
+                step(Step::Peer(
+                    DomToSub::WritePixels {
+                        row,
+                        data_0,
+                        data_1,
+                    },
+                    Traffic::Bulk,
+                ))
+            }
+        }
+
+        if let Some(frame) = &mut self.frames[side as usize] {
+            frame.add_rows(row, rows);
+        }
+
+        // older hosts end each frame with the last rows instead
+        if !self.flush_markers && row as usize == DISPLAY_ROWS - 2 {
+            flush_display(step, now, side);
+        }
+    }
+
+    /// Show the frame if all of it arrived, otherwise throw it away and ask
+    /// for it again. The right half's frames are checked again once they're
+    /// over there, and it nacks them itself if they didn't make it.
+    fn end_frame(
+        &mut self,
+        step: &mut impl FnMut(Step),
+        now: Conditions,
+        side: KeyboardSide,
+        frame_id: u8,
+        crc: u16,
+    ) {
+        let intact = matches!(
+            self.frames[side as usize].take(),
+            Some(frame) if frame.intact(frame_id, crc)
+        );
+
+        match side {
+            KeyboardSide::Left if intact => flush_display(step, now, side),
+            KeyboardSide::Right if intact && !now.link_degraded => step(Step::Peer(
+                DomToSub::EndFrame { frame_id, crc },
+                Traffic::Bulk,
+            )),
+            _ => {
+                discard_display(step, now, side);
+                step(Step::Reply(KeyboardToHost::FrameNack { side, frame_id }));
+            }
+        }
+    }
+}
+
+impl Default for HostRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Apply timings to both halves, without saving them
+fn use_tuning(step: &mut impl FnMut(Step), tuning: Tuning) {
+    step(Step::UseTuning(tuning));
+    step(Step::Peer(DomToSub::SetTuning(tuning), Traffic::KeyEvent));
+}
+
+fn reject_pixels(step: &mut impl FnMut(Step), side: KeyboardSide, row: u8) {
+    step(Step::RejectedPixels { row });
+    step(Step::Reply(KeyboardToHost::PixelsRejected { side, row }));
+}
+
+fn flush_display(step: &mut impl FnMut(Step), now: Conditions, side: KeyboardSide) {
+    match side {
+        KeyboardSide::Left => step(Step::Flush),
+        // the frame's rows were dropped too, it'll time out
+        KeyboardSide::Right if now.link_degraded => {}
+        KeyboardSide::Right => step(Step::Peer(DomToSub::FlushDisplay, Traffic::Bulk)),
+    }
+}
+
+fn discard_display(step: &mut impl FnMut(Step), now: Conditions, side: KeyboardSide) {
+    match side {
+        KeyboardSide::Left => step(Step::Discard),
+        KeyboardSide::Right if now.link_degraded => {}
+        KeyboardSide::Right => step(Step::Peer(DomToSub::DiscardDisplay, Traffic::Bulk)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frame::{FrameCrc, PackedRows},
+        protocol::ConfigItem,
+        storage::SETTINGS_CHUNK,
+        tuning::{HoldTapBehaviour, HoldTapMode},
+        LogLevel,
+    };
+
+    const SAVED: Tuning = Tuning {
+        chord_window_ms: 30,
+        hold_tap_timeout_ms: 250,
+    };
+
+    const LINKED: Conditions = Conditions {
+        link_up: true,
+        link_degraded: false,
+        tuning: Tuning::DEFAULT,
+        saved_tuning: SAVED,
+    };
+    const UNLINKED: Conditions = Conditions {
+        link_up: false,
+        ..LINKED
+    };
+    const DEGRADED: Conditions = Conditions {
+        link_degraded: true,
+        ..LINKED
+    };
+
+    const ROWS: [[u8; 4]; 2] = [[1, 2, 3, 4], [5, 6, 7, 8]];
+
+    fn route(cmd: HostToKeyboard, now: Conditions) -> Vec<Step> {
+        HostRouter::new().route(&cmd, now).to_vec()
+    }
+
+    fn write(side: KeyboardSide, row: u8) -> HostToKeyboard {
+        HostToKeyboard::WritePixels {
+            side,
+            row,
+            data_0: ROWS[0],
+            data_1: ROWS[1],
+        }
+    }
+
+    fn peer(cmd: DomToSub, traffic: Traffic) -> Step {
+        Step::Peer(cmd, traffic)
+    }
+
+    #[test]
+    fn keep_alive_does_nothing() {
+        assert_eq!(route(HostToKeyboard::KeepAlive, LINKED), []);
+    }
+
+    #[test]
+    fn commands_for_this_half_stay_here() {
+        let local = [
+            HostToKeyboard::RequestDisplayInfo {
+                side: KeyboardSide::Right,
+            },
+            HostToKeyboard::Lock(true),
+            HostToKeyboard::ShowDebugScreen(true),
+            HostToKeyboard::SetConfig(ConfigItem::DisplaySwap(true)),
+            HostToKeyboard::SetLedCalibration {
+                side: KeyboardSide::Right,
+                r: 1,
+                g: 2,
+                b: 3,
+            },
+            HostToKeyboard::SetKeyAction {
+                layer: 0,
+                row: 1,
+                col: 2,
+                keycode: 4,
+            },
+            HostToKeyboard::ClearRemaps,
+            HostToKeyboard::ExportSettings { offset: 0 },
+            HostToKeyboard::ImportSettings {
+                offset: 0,
+                total: 1,
+                len: 1,
+                data: [0; SETTINGS_CHUNK],
+            },
+            HostToKeyboard::RequestTimingStats,
+            HostToKeyboard::RequestUsageStats,
+            HostToKeyboard::RequestDebounce,
+            HostToKeyboard::RequestStatus,
+            HostToKeyboard::RequestLatency,
+            HostToKeyboard::RequestKeyPresses,
+            HostToKeyboard::InjectKey {
+                row: 0,
+                col: 0,
+                duration_ms: 10,
+            },
+            // the right half's key is injected by a task of this half
+            HostToKeyboard::InjectRemoteKey {
+                row: 0,
+                col: 0,
+                duration_ms: 10,
+            },
+            HostToKeyboard::RequestHoldTap {
+                layer: 0,
+                row: 0,
+                col: 0,
+            },
+            HostToKeyboard::FactoryReset,
+            HostToKeyboard::PrepareShutdown,
+            HostToKeyboard::Identify,
+            HostToKeyboard::ForceAllocation { bytes: 1024 },
+            HostToKeyboard::ReadCapture { offset: 0 },
+            HostToKeyboard::ClearCapture,
+        ];
+        for cmd in local {
+            for now in [LINKED, UNLINKED, DEGRADED] {
+                assert_eq!(route(cmd.clone(), now), [Step::Local], "{cmd:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn stats_ask_the_right_half_while_the_link_is_up() {
+        assert_eq!(
+            route(HostToKeyboard::RequestStats, LINKED),
+            [Step::Local, peer(DomToSub::RequestStats, Traffic::Bulk)]
+        );
+        assert_eq!(route(HostToKeyboard::RequestStats, UNLINKED), [Step::Local]);
+    }
+
+    #[test]
+    fn pixels_are_read_from_the_side_asked() {
+        let read = |side| HostToKeyboard::ReadPixels { side, row: 4 };
+        assert_eq!(route(read(KeyboardSide::Left), LINKED), [Step::Local]);
+        assert_eq!(
+            route(read(KeyboardSide::Right), LINKED),
+            [peer(DomToSub::ReadPixels { row: 4 }, Traffic::Bulk)]
+        );
+    }
+
+    #[test]
+    fn led_test_colour_is_shown_on_both_halves() {
+        let colour = Some([1, 2, 3]);
+        assert_eq!(
+            route(HostToKeyboard::LedTestColour(colour), LINKED),
+            [
+                Step::Local,
+                peer(DomToSub::LedTestColour(colour), Traffic::KeyEvent)
+            ]
+        );
+    }
+
+    #[test]
+    fn led_test_index_is_shown_on_one_half() {
+        let test = |side| HostToKeyboard::LedTestIndex {
+            side,
+            index: Some(3),
+        };
+        assert_eq!(route(test(KeyboardSide::Left), LINKED), [Step::Local]);
+        assert_eq!(
+            route(test(KeyboardSide::Right), LINKED),
+            [peer(DomToSub::LedTestIndex(Some(3)), Traffic::KeyEvent)]
+        );
+    }
+
+    #[test]
+    fn key_leds_are_set_on_one_half_and_cleared_on_both() {
+        let set = |side| HostToKeyboard::SetKeyLed {
+            side,
+            index: 5,
+            r: 1,
+            g: 2,
+            b: 3,
+        };
+        assert_eq!(route(set(KeyboardSide::Left), LINKED), [Step::Local]);
+        assert_eq!(
+            route(set(KeyboardSide::Right), LINKED),
+            [peer(
+                DomToSub::SetKeyLed {
+                    index: 5,
+                    r: 1,
+                    g: 2,
+                    b: 3
+                },
+                Traffic::KeyEvent
+            )]
+        );
+        assert_eq!(
+            route(HostToKeyboard::ClearKeyLeds, LINKED),
+            [Step::Local, peer(DomToSub::ClearKeyLeds, Traffic::KeyEvent)]
+        );
+    }
+
+    #[test]
+    fn time_is_kept_on_both_halves() {
+        let seconds_of_day = 3600;
+        assert_eq!(
+            route(HostToKeyboard::SetTime { seconds_of_day }, UNLINKED),
+            [
+                Step::Local,
+                peer(DomToSub::SetTime { seconds_of_day }, Traffic::Background)
+            ]
+        );
+    }
+
+    #[test]
+    fn log_level_is_set_on_both_halves() {
+        assert_eq!(
+            route(HostToKeyboard::SetLogLevel(LogLevel::Verbose), LINKED),
+            [
+                Step::Local,
+                peer(DomToSub::SetLogLevel(LogLevel::Verbose), Traffic::KeyEvent)
+            ]
+        );
+    }
+
+    #[test]
+    fn tuning_keeps_the_timings_left_out() {
+        let tuning = Tuning {
+            chord_window_ms: 20,
+            ..Tuning::DEFAULT
+        };
+        assert_eq!(
+            route(
+                HostToKeyboard::SetTuning {
+                    chord_window_ms: Some(20),
+                    hold_tap_timeout_ms: None,
+                },
+                LINKED
+            ),
+            [
+                Step::UseTuning(tuning),
+                peer(DomToSub::SetTuning(tuning), Traffic::KeyEvent),
+                Step::ReplyTuning,
+            ]
+        );
+    }
+
+    #[test]
+    fn tuning_out_of_range_is_rejected() {
+        for (chord_window_ms, hold_tap_timeout_ms) in [
+            (Some(0), None),
+            (None, Some(Tuning::MAX_MS + 1)),
+            (Some(Tuning::MAX_MS + 1), Some(0)),
+        ] {
+            assert_eq!(
+                route(
+                    HostToKeyboard::SetTuning {
+                        chord_window_ms,
+                        hold_tap_timeout_ms,
+                    },
+                    LINKED
+                ),
+                [Step::Reply(KeyboardToHost::TuningRejected)]
+            );
+        }
+    }
+
+    #[test]
+    fn tuning_is_committed_reverted_and_reported() {
+        assert_eq!(
+            route(HostToKeyboard::CommitTuning, LINKED),
+            [Step::Local, Step::ReplyTuning]
+        );
+        assert_eq!(
+            route(HostToKeyboard::RevertTuning, LINKED),
+            [
+                Step::UseTuning(SAVED),
+                peer(DomToSub::SetTuning(SAVED), Traffic::KeyEvent),
+                Step::ReplyTuning,
+            ]
+        );
+        assert_eq!(
+            route(HostToKeyboard::RequestTuning, LINKED),
+            [Step::ReplyTuning]
+        );
+    }
+
+    #[test]
+    fn hold_tap_with_an_interval_out_of_range_is_rejected() {
+        let set = |tap_hold_interval_ms| HostToKeyboard::SetHoldTap {
+            layer: 0,
+            row: 1,
+            col: 2,
+            behaviour: Some(HoldTapBehaviour {
+                mode: HoldTapMode::PermissiveHold,
+                tap_hold_interval_ms,
+            }),
+        };
+        assert_eq!(route(set(Tuning::MAX_MS), LINKED), [Step::Local]);
+        assert_eq!(
+            route(set(Tuning::MAX_MS + 1), LINKED),
+            [Step::Reply(KeyboardToHost::HoldTapRejected)]
+        );
+
+        let reset = HostToKeyboard::SetHoldTap {
+            layer: 0,
+            row: 1,
+            col: 2,
+            behaviour: None,
+        };
+        assert_eq!(route(reset, LINKED), [Step::Local]);
+    }
+
+    #[test]
+    fn right_half_only_enters_the_bootloader_over_a_working_link() {
+        let enter = |side| HostToKeyboard::EnterBootloader { side };
+        assert_eq!(route(enter(KeyboardSide::Left), UNLINKED), [Step::Local]);
+        assert_eq!(
+            route(enter(KeyboardSide::Right), LINKED),
+            [peer(DomToSub::EnterBootloader, Traffic::KeyEvent)]
+        );
+        assert_eq!(route(enter(KeyboardSide::Right), UNLINKED), []);
+    }
+
+    #[test]
+    fn pixels_are_drawn_on_the_side_asked() {
+        assert_eq!(
+            route(write(KeyboardSide::Left, 10), LINKED),
+            [Step::Draw {
+                row: 10,
+                rows: ROWS
+            }]
+        );
+        assert_eq!(
+            route(write(KeyboardSide::Right, 10), LINKED),
+            [peer(
+                DomToSub::WritePixels {
+                    row: 10,
+                    data_0: ROWS[0],
+                    data_1: ROWS[1],
+                },
+                Traffic::Bulk
+            )]
+        );
+    }
+
+    #[test]
+    fn pixels_off_the_display_are_rejected() {
+        let row = DISPLAY_ROWS as u8 - 1;
+        for side in [KeyboardSide::Left, KeyboardSide::Right] {
+            assert_eq!(
+                route(write(side, row), DEGRADED),
+                [
+                    Step::RejectedPixels { row },
+                    Step::Reply(KeyboardToHost::PixelsRejected { side, row })
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn pixels_for_the_right_half_are_dropped_while_the_link_is_degraded() {
+        assert_eq!(
+            route(write(KeyboardSide::Right, 10), DEGRADED),
+            [Step::DroppedPixels]
+        );
+        assert_eq!(
+            route(write(KeyboardSide::Left, 10), DEGRADED),
+            [Step::Draw {
+                row: 10,
+                rows: ROWS
+            }]
+        );
+    }
+
+    #[test]
+    fn packed_pixels_are_drawn_unpacked_or_rejected() {
+        // a run of 8 ones
+        let ones = PackedRows::new(&[0x87, 1]).unwrap();
+        assert_eq!(
+            route(
+                HostToKeyboard::WritePackedPixels {
+                    side: KeyboardSide::Left,
+                    row: 2,
+                    data: ones,
+                },
+                LINKED
+            ),
+            [Step::Draw {
+                row: 2,
+                rows: [[1; 4]; 2]
+            }]
+        );
+
+        // only covers half the rows
+        let short = PackedRows::new(&[0x83, 1]).unwrap();
+        assert_eq!(
+            route(
+                HostToKeyboard::WritePackedPixels {
+                    side: KeyboardSide::Right,
+                    row: 2,
+                    data: short,
+                },
+                LINKED
+            ),
+            [
+                Step::RejectedPixels { row: 2 },
+                Step::Reply(KeyboardToHost::PixelsRejected {
+                    side: KeyboardSide::Right,
+                    row: 2
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn last_rows_flush_until_the_host_sends_flushes() {
+        let last = DISPLAY_ROWS as u8 - 2;
+        let mut router = HostRouter::new();
+        assert_eq!(
+            router.route(&write(KeyboardSide::Left, last), LINKED)[..],
+            [
+                Step::Draw {
+                    row: last,
+                    rows: ROWS
+                },
+                Step::Flush
+            ]
+        );
+        assert_eq!(
+            router.route(&write(KeyboardSide::Right, last), LINKED)[1..],
+            [peer(DomToSub::FlushDisplay, Traffic::Bulk)]
+        );
+
+        let flush = HostToKeyboard::FlushDisplay {
+            side: KeyboardSide::Left,
+        };
+        assert_eq!(router.route(&flush, LINKED)[..], [Step::Flush]);
+        assert_eq!(
+            router.route(&write(KeyboardSide::Left, last), LINKED).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn right_half_isnt_flushed_while_the_link_is_degraded() {
+        let flush = HostToKeyboard::FlushDisplay {
+            side: KeyboardSide::Right,
+        };
+        assert_eq!(
+            route(flush.clone(), LINKED),
+            [peer(DomToSub::FlushDisplay, Traffic::Bulk)]
+        );
+        assert_eq!(route(flush, DEGRADED), []);
+    }
+
+    /// Begin a frame on `side`, write `rows` pairs to it and end it with the
+    /// CRC of `crc_rows` pairs, returning the steps of the end
+    fn frame(side: KeyboardSide, rows: u8, crc_rows: u8, now: Conditions) -> Vec<Step> {
+        let mut router = HostRouter::new();
+        router.route(&HostToKeyboard::BeginFrame { side, frame_id: 7 }, now);
+        let mut crc = FrameCrc::new();
+        for pair in 0..crc_rows {
+            crc.add_rows(pair * 2, ROWS);
+        }
+        for pair in 0..rows {
+            router.route(&write(side, pair * 2), now);
+        }
+        router
+            .route(
+                &HostToKeyboard::EndFrame {
+                    side,
+                    frame_id: 7,
+                    crc: crc.value(),
+                },
+                now,
+            )
+            .to_vec()
+    }
+
+    #[test]
+    fn whole_frames_are_shown() {
+        assert_eq!(frame(KeyboardSide::Left, 4, 4, LINKED), [Step::Flush]);
+        assert_eq!(
+            frame(KeyboardSide::Right, 4, 4, LINKED),
+            [peer(
+                DomToSub::EndFrame {
+                    frame_id: 7,
+                    crc: {
+                        let mut crc = FrameCrc::new();
+                        for pair in 0..4 {
+                            crc.add_rows(pair * 2, ROWS);
+                        }
+                        crc.value()
+                    }
+                },
+                Traffic::Bulk
+            )]
+        );
+    }
+
+    #[test]
+    fn frames_missing_rows_are_thrown_away_and_nacked() {
+        let nack = |side| Step::Reply(KeyboardToHost::FrameNack { side, frame_id: 7 });
+        assert_eq!(
+            frame(KeyboardSide::Left, 3, 4, LINKED),
+            [Step::Discard, nack(KeyboardSide::Left)]
+        );
+        assert_eq!(
+            frame(KeyboardSide::Right, 3, 4, LINKED),
+            [
+                peer(DomToSub::DiscardDisplay, Traffic::Bulk),
+                nack(KeyboardSide::Right)
+            ]
+        );
+        // its rows were dropped, so there's nothing over there to discard
+        assert_eq!(
+            frame(KeyboardSide::Right, 4, 4, DEGRADED),
+            [nack(KeyboardSide::Right)]
+        );
+    }
+
+    #[test]
+    fn beginning_a_frame_throws_away_the_unended_one() {
+        let begin = |side| HostToKeyboard::BeginFrame { side, frame_id: 1 };
+        let mut router = HostRouter::new();
+        assert_eq!(router.route(&begin(KeyboardSide::Left), LINKED)[..], []);
+        assert_eq!(
+            router.route(&begin(KeyboardSide::Left), LINKED)[..],
+            [Step::Discard]
+        );
+
+        // the right half does that itself
+        assert_eq!(
+            router.route(&begin(KeyboardSide::Right), LINKED)[..],
+            [peer(DomToSub::BeginFrame { frame_id: 1 }, Traffic::Bulk)]
+        );
+        assert_eq!(router.route(&begin(KeyboardSide::Right), DEGRADED)[..], []);
+    }
+
+    #[test]
+    fn ending_the_session_undoes_what_the_host_left_running() {
+        let mut router = HostRouter::new();
+        assert!(!router.is_active());
+        assert_eq!(router.end(), []);
+
+        router.route(&HostToKeyboard::SubscribeReports(true), LINKED);
+        router.route(&HostToKeyboard::LedTestColour(Some([1, 2, 3])), LINKED);
+        router.route(
+            &HostToKeyboard::LedTestIndex {
+                side: KeyboardSide::Right,
+                index: Some(2),
+            },
+            LINKED,
+        );
+        assert!(router.is_active());
+
+        let undo = router.end();
+        assert_eq!(
+            undo,
+            [
+                HostToKeyboard::SubscribeReports(false),
+                HostToKeyboard::LedTestColour(None),
+                HostToKeyboard::LedTestIndex {
+                    side: KeyboardSide::Left,
+                    index: None
+                },
+                HostToKeyboard::LedTestIndex {
+                    side: KeyboardSide::Right,
+                    index: None
+                },
+            ]
+        );
+        for cmd in &undo {
+            router.route(cmd, LINKED);
+        }
+        assert!(!router.is_active());
+    }
+
+    #[test]
+    fn turning_test_modes_off_closes_the_session() {
+        let mut router = HostRouter::new();
+        router.route(&HostToKeyboard::LedTestColour(Some([1, 2, 3])), LINKED);
+        router.route(&HostToKeyboard::LedTestColour(None), LINKED);
+        router.route(&HostToKeyboard::SubscribeReports(true), LINKED);
+        router.route(&HostToKeyboard::SubscribeReports(false), LINKED);
+        assert!(!router.is_active());
+        assert_eq!(router.end(), []);
+    }
+}
@@ -0,0 +1,512 @@
+//! Display settings, and the maths behind what's drawn on them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::frame::DISPLAY_ROWS;
+
+/// What the graph under the stats screen shows
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum StatsGraph {
+    /// Presses in each sample the presses per second are worked out from,
+    /// the last few seconds
+    Cps,
+    /// Presses every few seconds over the last few minutes
+    History,
+}
+
+/// Height of the bar for `sample` in a graph `max` tall whose tallest bar is
+/// for `peak`, so the peak fills it and any sample above zero shows
+pub const fn bar_height(sample: u8, peak: u8, max: u32) -> u32 {
+    if peak == 0 {
+        return 0;
+    }
+    let sample = if sample < peak { sample } else { peak };
+    // rounded up, so any sample above zero shows
+    let scaled = sample as u32 * max;
+    let height = scaled / peak as u32;
+    if height * peak as u32 == scaled {
+        height
+    } else {
+        height + 1
+    }
+}
+
+/// How a display is mounted, relative to the usual orientation. Pixel
+/// addressing from the host is the same either way.
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum Rotation {
+    Rotate0,
+    Rotate180,
+}
+
+/// Pixels across either half's display, in the host's orientation
+pub const DISPLAY_WIDTH: usize = 32;
+
+impl Rotation {
+    /// Where a pixel drawn at `(x, y)` actually goes on a display mounted
+    /// this way
+    pub const fn orient(self, (x, y): (i32, i32)) -> (i32, i32) {
+        match self {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate180 => (DISPLAY_WIDTH as i32 - 1 - x, DISPLAY_ROWS as i32 - 1 - y),
+        }
+    }
+}
+
+/// Applied by the keyboard to host supplied pixels before they're drawn, so
+/// host tools don't need to care how a display is wired up
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct FrameTransform {
+    /// Swap lit and unlit pixels
+    pub invert: bool,
+    /// Mirror each row
+    pub flip_x: bool,
+    /// Mirror the frame top to bottom
+    pub flip_y: bool,
+}
+
+impl FrameTransform {
+    pub const NONE: Self = Self {
+        invert: false,
+        flip_x: false,
+        flip_y: false,
+    };
+}
+
+// Host supplied pixels arrive as pairs of 32 pixel rows with the leftmost
+// pixel in the lowest bit of the first byte.
+
+/// Mirror a row left to right
+pub fn reverse_row(row: [u8; 4]) -> [u8; 4] {
+    u32::from_le_bytes(row).reverse_bits().to_le_bytes()
+}
+
+/// Swap the lit and unlit pixels of a row
+pub fn invert_row(row: [u8; 4]) -> [u8; 4] {
+    (!u32::from_le_bytes(row)).to_le_bytes()
+}
+
+/// Where the pair of rows starting at `row` starts once the frame is flipped
+/// top to bottom, the two rows within the pair also swap over
+pub fn flip_pair_row(row: u8) -> u8 {
+    (DISPLAY_ROWS as u8 - 2).saturating_sub(row)
+}
+
+/// Apply `transform` to the pair of rows starting at `row`
+pub fn transform_pair(
+    transform: FrameTransform,
+    row: u8,
+    [mut data_0, mut data_1]: [[u8; 4]; 2],
+) -> (u8, [[u8; 4]; 2]) {
+    if transform.invert {
+        data_0 = invert_row(data_0);
+        data_1 = invert_row(data_1);
+    }
+
+    if transform.flip_x {
+        data_0 = reverse_row(data_0);
+        data_1 = reverse_row(data_1);
+    }
+
+    if transform.flip_y {
+        (flip_pair_row(row), [data_1, data_0])
+    } else {
+        (row, [data_0, data_1])
+    }
+}
+
+/// Brightness levels a display can be set to, 0 is the dimmest
+pub const CONTRAST_LEVELS: u8 = 5;
+
+/// How bright the displays are when on
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub enum DisplayContrast {
+    /// Follows what's on screen, dimmer the more of it is lit
+    Auto(ContrastCurve),
+    /// Always this level, below `CONTRAST_LEVELS`
+    Manual(u8),
+}
+
+/// Maps the share of a frame that's lit to a brightness level. Each threshold
+/// is a percentage of lit pixels at or above which the display drops a
+/// level, so a mostly dark frame shows at the brightest level and a mostly
+/// lit one at the dimmest.
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct ContrastCurve {
+    /// Ascending
+    pub thresholds: [u8; CONTRAST_LEVELS as usize - 1],
+}
+
+impl ContrastCurve {
+    pub const DEFAULT: Self = Self {
+        thresholds: [10, 25, 45, 70],
+    };
+    /// How far past a threshold the lit share has to go before the level
+    /// changes, so a frame sitting on one doesn't flick between two
+    pub const HYSTERESIS: u8 = 3;
+
+    pub const fn is_valid(&self) -> bool {
+        let mut i = 1;
+        while i < self.thresholds.len() {
+            if self.thresholds[i] < self.thresholds[i - 1] {
+                return false;
+            }
+            i += 1;
+        }
+        self.thresholds[self.thresholds.len() - 1] <= 100
+    }
+
+    /// The level for a frame with `lit_percent` of its pixels lit
+    pub const fn level(&self, lit_percent: u8) -> u8 {
+        let mut level = CONTRAST_LEVELS - 1;
+        let mut i = 0;
+        while i < self.thresholds.len() {
+            if lit_percent >= self.thresholds[i] {
+                level -= 1;
+            }
+            i += 1;
+        }
+        level
+    }
+
+    /// The level to move to from `current`, only going as far as the lit
+    /// share is still past the thresholds in between by `HYSTERESIS`. It
+    /// never moves the other way, which thresholds closer together than
+    /// `HYSTERESIS` would otherwise do.
+    pub const fn settle(&self, lit_percent: u8, current: u8) -> u8 {
+        let target = self.level(lit_percent);
+        if target < current {
+            let settled = self.level(lit_percent.saturating_sub(Self::HYSTERESIS));
+            if settled < current {
+                settled
+            } else {
+                current
+            }
+        } else if target > current {
+            let settled = self.level(lit_percent.saturating_add(Self::HYSTERESIS));
+            if settled > current {
+                settled
+            } else {
+                current
+            }
+        } else {
+            current
+        }
+    }
+}
+
+/// Share of the pixels in `rows` that are lit, as a percentage
+pub fn lit_percent(rows: &[[u8; 4]]) -> u8 {
+    let lit: usize = rows
+        .iter()
+        .map(|row| u32::from_le_bytes(*row).count_ones() as usize)
+        .sum();
+    let total = rows.len() * 32;
+    (lit * 100).checked_div(total).unwrap_or(0) as u8
+}
+
+/// How long a half's display takes to flush over its I2C bus
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct DisplayBusStats {
+    pub last_flush_us: u32,
+    /// Moving average over the last few flushes
+    pub avg_flush_us: u32,
+    pub max_flush_us: u32,
+    /// Percentage of the last second the bus spent flushing
+    pub busy_pct: u8,
+    /// Bus clock achieved by the last full frame flush, 0 until there's been one
+    pub khz: u16,
+    /// The bus was busy for more than half of the last second, which is
+    /// enough to visibly delay other work
+    pub saturated: bool,
+}
+
+impl DisplayBusStats {
+    pub const NONE: Self = Self {
+        last_flush_us: 0,
+        avg_flush_us: 0,
+        max_flush_us: 0,
+        busy_pct: 0,
+        khz: 0,
+        saturated: false,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: FrameTransform = FrameTransform {
+        invert: true,
+        flip_x: true,
+        flip_y: true,
+    };
+
+    #[test]
+    fn rows_reverse_bit_for_bit() {
+        // the leftmost pixel becomes the rightmost
+        assert_eq!(reverse_row([0x01, 0, 0, 0]), [0, 0, 0, 0x80]);
+        assert_eq!(reverse_row([0, 0, 0, 0x80]), [0x01, 0, 0, 0]);
+        assert_eq!(reverse_row([0x0f, 0, 0, 0xf0]), [0x0f, 0, 0, 0xf0]);
+        assert_eq!(
+            reverse_row([0x12, 0x34, 0x56, 0x78]),
+            [0x1e, 0x6a, 0x2c, 0x48]
+        );
+        assert_eq!(reverse_row([0xff; 4]), [0xff; 4]);
+    }
+
+    #[test]
+    fn rows_invert_every_pixel() {
+        assert_eq!(invert_row([0; 4]), [0xff; 4]);
+        assert_eq!(
+            invert_row([0x00, 0xff, 0x0f, 0xa5]),
+            [0xff, 0x00, 0xf0, 0x5a]
+        );
+    }
+
+    #[test]
+    fn flipped_pairs_stay_on_screen() {
+        assert_eq!(flip_pair_row(0), 126);
+        assert_eq!(flip_pair_row(126), 0);
+        assert_eq!(flip_pair_row(64), 62);
+        // rows past the end are refused before this, but don't wrap
+        assert_eq!(flip_pair_row(130), 0);
+    }
+
+    #[test]
+    fn no_transform_leaves_the_pair_alone() {
+        let pair = [[0x12, 0x34, 0x56, 0x78], [0x9a, 0xbc, 0xde, 0xf0]];
+        assert_eq!(transform_pair(FrameTransform::NONE, 10, pair), (10, pair));
+    }
+
+    #[test]
+    fn each_transform_moves_a_pixel_where_expected() {
+        // the top left pixel
+        let pair = [[0x01, 0, 0, 0], [0; 4]];
+        let only = |invert, flip_x, flip_y| FrameTransform {
+            invert,
+            flip_x,
+            flip_y,
+        };
+
+        assert_eq!(
+            transform_pair(only(true, false, false), 0, pair),
+            (0, [[0xfe, 0xff, 0xff, 0xff], [0xff; 4]])
+        );
+        // to the top right
+        assert_eq!(
+            transform_pair(only(false, true, false), 0, pair),
+            (0, [[0, 0, 0, 0x80], [0; 4]])
+        );
+        // to the bottom left, the last row of the last pair
+        assert_eq!(
+            transform_pair(only(false, false, true), 0, pair),
+            (126, [[0; 4], [0x01, 0, 0, 0]])
+        );
+        // to the bottom right, the only unlit pixel
+        assert_eq!(
+            transform_pair(ALL, 0, pair),
+            (126, [[0xff; 4], [0xff, 0xff, 0xff, 0x7f]])
+        );
+    }
+
+    #[test]
+    fn flip_and_invert_round_trips() {
+        let patterns = [
+            [[0; 4], [0xff; 4]],
+            [[0x01, 0, 0, 0], [0, 0, 0, 0x80]],
+            [[0x12, 0x34, 0x56, 0x78], [0x9a, 0xbc, 0xde, 0xf0]],
+            [[0xa5, 0x5a, 0xc3, 0x3c], [0x0f, 0xf0, 0x00, 0xff]],
+        ];
+        for row in (0..DISPLAY_ROWS as u8).step_by(2) {
+            for pair in patterns {
+                let (moved, data) = transform_pair(ALL, row, pair);
+                assert_ne!((moved, data), (row, pair));
+                assert_eq!(transform_pair(ALL, moved, data), (row, pair));
+            }
+        }
+    }
+
+    type Buffer = [[u8; 4]; DISPLAY_ROWS];
+
+    fn set(buffer: &mut Buffer, (x, y): (i32, i32), on: bool) {
+        let byte = &mut buffer[y as usize][x as usize / 8];
+        let mask = 1 << (x % 8);
+        if on {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    /// Draws as the firmware's `oled::Canvas` does, keeping a shadow in the
+    /// host's orientation and committing each pixel to the panel where it
+    /// goes for how the display is mounted
+    struct MockDisplay {
+        rotation: Rotation,
+        shadow: Buffer,
+        panel: Buffer,
+    }
+
+    impl MockDisplay {
+        fn new(rotation: Rotation) -> Self {
+            Self {
+                rotation,
+                shadow: [[0; 4]; DISPLAY_ROWS],
+                panel: [[0; 4]; DISPLAY_ROWS],
+            }
+        }
+
+        fn draw(&mut self, pattern: &Buffer) {
+            for y in 0..DISPLAY_ROWS as i32 {
+                for x in 0..DISPLAY_WIDTH as i32 {
+                    let on = pattern[y as usize][x as usize / 8] & (1 << (x % 8)) != 0;
+                    set(&mut self.shadow, (x, y), on);
+                    set(&mut self.panel, self.rotation.orient((x, y)), on);
+                }
+            }
+        }
+    }
+
+    /// Nothing in it lines up with itself turned round: a lone corner
+    /// pixel, a line along the top, one down the side and a noisy patch
+    fn test_pattern() -> Buffer {
+        let mut pattern = [[0; 4]; DISPLAY_ROWS];
+        set(&mut pattern, (0, 0), true);
+        for x in 2..20 {
+            set(&mut pattern, (x, 3), true);
+        }
+        for y in 10..90 {
+            set(&mut pattern, (27, y), true);
+        }
+        for y in 100..120 {
+            for x in 4..16 {
+                set(&mut pattern, (x, y), (x * 7 + y * 3) % 5 < 2);
+            }
+        }
+        pattern
+    }
+
+    #[test]
+    fn each_rotation_commits_the_expected_transform() {
+        let pattern = test_pattern();
+        let turned: Vec<_> = pattern.iter().rev().map(|&row| reverse_row(row)).collect();
+
+        for (rotation, expected) in [
+            (Rotation::Rotate0, pattern.to_vec()),
+            (Rotation::Rotate180, turned),
+        ] {
+            let mut display = MockDisplay::new(rotation);
+            display.draw(&pattern);
+
+            assert_eq!(display.panel.to_vec(), expected, "{rotation:?}");
+            // reading pixels back gives what was drawn either way
+            assert_eq!(display.shadow, pattern, "{rotation:?}");
+        }
+    }
+
+    #[test]
+    fn rotating_half_way_round_flips_both_ways() {
+        let pattern = test_pattern();
+        let mut display = MockDisplay::new(Rotation::Rotate180);
+        display.draw(&pattern);
+
+        let flip = FrameTransform {
+            invert: false,
+            flip_x: true,
+            flip_y: true,
+        };
+        for row in (0..DISPLAY_ROWS).step_by(2) {
+            let (moved, data) = transform_pair(flip, row as u8, [pattern[row], pattern[row + 1]]);
+            let moved = moved as usize;
+            assert_eq!([display.panel[moved], display.panel[moved + 1]], data);
+        }
+
+        // and turning it again puts it back
+        let mut again = MockDisplay::new(Rotation::Rotate180);
+        again.draw(&display.panel);
+        assert_eq!(again.panel, pattern);
+    }
+
+    #[test]
+    fn default_curve_dims_lit_frames() {
+        let curve = ContrastCurve::DEFAULT;
+        let top = CONTRAST_LEVELS - 1;
+        assert!(curve.is_valid());
+        assert_eq!(curve.level(0), top);
+        assert_eq!(curve.level(10), top - 1);
+        assert_eq!(curve.level(100), 0);
+    }
+
+    #[test]
+    fn settle_waits_for_the_hysteresis() {
+        let curve = ContrastCurve::DEFAULT;
+        let top = CONTRAST_LEVELS - 1;
+        assert_eq!(curve.settle(11, top), top);
+        assert_eq!(curve.settle(13, top), top - 1);
+        assert_eq!(curve.settle(8, top - 1), top - 1);
+        assert_eq!(curve.settle(6, top - 1), top);
+        assert_eq!(curve.settle(100, top), 0);
+        assert_eq!(curve.settle(0, 0), top);
+    }
+
+    #[test]
+    fn settle_never_moves_against_the_target() {
+        let curve = ContrastCurve {
+            thresholds: [10, 11, 12, 13],
+        };
+        assert_eq!(curve.settle(13, 1), 1);
+        assert_eq!(curve.settle(9, 3), 3);
+        for lit in 0..=100 {
+            for current in 0..CONTRAST_LEVELS {
+                let target = curve.level(lit);
+                let settled = curve.settle(lit, current);
+                assert!(
+                    (current.min(target)..=current.max(target)).contains(&settled),
+                    "lit {lit} from {current} settled on {settled}, target {target}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lit_percent_counts_pixels() {
+        assert_eq!(lit_percent(&[]), 0);
+        assert_eq!(lit_percent(&[[0; 4]; 4]), 0);
+        assert_eq!(lit_percent(&[[0xff; 4]; 4]), 100);
+        assert_eq!(lit_percent(&[[0xff, 0xff, 0, 0], [0; 4]]), 25);
+        assert_eq!(lit_percent(&[[1, 0, 0, 0], [0; 4], [0; 4]]), 1);
+    }
+
+    #[test]
+    fn graph_bars_scale_to_the_peak() {
+        assert_eq!(bar_height(0, 0, 31), 0);
+        assert_eq!(bar_height(0, 9, 31), 0);
+        assert_eq!(bar_height(9, 9, 31), 31);
+        assert_eq!(bar_height(10, 20, 30), 15);
+        // samples above the peak are clipped to it
+        assert_eq!(bar_height(200, 20, 31), 31);
+    }
+
+    #[test]
+    fn graph_bars_above_zero_always_show() {
+        for peak in 1..=255 {
+            for sample in 1..=peak {
+                let height = bar_height(sample, peak, 31);
+                assert!((1..=31).contains(&height), "{sample}/{peak}: {height}");
+            }
+        }
+    }
+
+    #[test]
+    fn graph_bars_grow_with_the_sample() {
+        for peak in 1..=255 {
+            let heights = (0..=peak)
+                .map(|s| bar_height(s, peak, 31))
+                .collect::<Vec<_>>();
+            assert!(heights.windows(2).all(|w| w[0] <= w[1]), "peak {peak}");
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Pixel frames from the host: packed rows and the check that a frame
+//! arrived whole.
+
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Most bytes a `PackedRows` holds, as many as the rows it encodes
+pub const PACKED_ROWS_MAX: usize = 8;
+
+/// A pair of pixel rows, as in `WritePixels`, run length encoded as a
+/// series of tokens covering the 8 bytes of `data_0` then `data_1`.
+///
+/// A token byte with the top bit set is a run: the next byte repeated
+/// `(token & 0x7f) + 1` times. Otherwise it's `token + 1` literal bytes
+/// which follow it. Serialized with its length, so short ones stay short.
+#[derive(Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct PackedRows {
+    len: u8,
+    data: [u8; PACKED_ROWS_MAX],
+}
+
+impl PackedRows {
+    /// Set on a token for a run
+    pub const RUN: u8 = 0x80;
+
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut data = [0; PACKED_ROWS_MAX];
+        data.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        Some(Self {
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// Decode the rows, `None` unless the tokens cover exactly the two rows.
+    /// Never writes past them, whatever the input.
+    pub fn unpack(&self) -> Option<[[u8; 4]; 2]> {
+        let mut out = [0u8; 8];
+        let mut pos = 0;
+        let mut tokens = self.as_bytes().iter();
+
+        while let Some(&token) = tokens.next() {
+            let count = (token & !Self::RUN) as usize + 1;
+            let dest = out.get_mut(pos..pos + count)?;
+
+            if token & Self::RUN != 0 {
+                dest.fill(*tokens.next()?);
+            } else {
+                for byte in dest {
+                    *byte = *tokens.next()?;
+                }
+            }
+
+            pos += count;
+        }
+
+        if pos != out.len() {
+            return None;
+        }
+
+        let mut rows = [[0; 4]; 2];
+        rows[0].copy_from_slice(&out[..4]);
+        rows[1].copy_from_slice(&out[4..]);
+        Some(rows)
+    }
+}
+
+/// The check sent with `EndFrame`, a CRC-16/CCITT-FALSE over each pair of
+/// rows written since `BeginFrame` in row order, as the row then the 8
+/// bytes of the rows unpacked. A pair written more than once counts once,
+/// with what was written last.
+#[derive(Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct FrameCrc(u16);
+
+impl FrameCrc {
+    const POLY: u16 = 0x1021;
+
+    pub const fn new() -> Self {
+        Self(0xffff)
+    }
+
+    pub fn add_rows(&mut self, row: u8, rows: [[u8; 4]; 2]) {
+        self.add(row);
+        for byte in rows.into_iter().flatten() {
+            self.add(byte);
+        }
+    }
+
+    fn add(&mut self, byte: u8) {
+        self.0 ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            self.0 = if self.0 & 0x8000 != 0 {
+                (self.0 << 1) ^ Self::POLY
+            } else {
+                self.0 << 1
+            };
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Default for FrameCrc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rows of either half's display
+pub const DISPLAY_ROWS: usize = 128;
+
+/// A frame of pixels that's been started with `BeginFrame` and not yet
+/// ended, keeping the rows written so a retransmitted pair doesn't count
+/// twice. Pairs of rows aren't expected to overlap.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub struct FrameCheck {
+    id: u8,
+    rows: [[u8; 4]; DISPLAY_ROWS],
+    /// Bit `n` is set once a pair starting at row `n` has been written
+    pairs: u128,
+    /// Some of the frame was dropped on the way
+    dropped: bool,
+}
+
+impl FrameCheck {
+    pub const fn new(id: u8) -> Self {
+        Self {
+            id,
+            rows: [[0; 4]; DISPLAY_ROWS],
+            pairs: 0,
+            dropped: false,
+        }
+    }
+
+    pub fn add_rows(&mut self, row: u8, rows: [[u8; 4]; 2]) {
+        match self.rows.get_mut(row as usize..row as usize + 2) {
+            Some(staged) => {
+                staged.copy_from_slice(&rows);
+                self.pairs |= 1 << row;
+            }
+            None => self.dropped = true,
+        }
+    }
+
+    /// Part of the frame didn't make it, so it can't be shown whatever the
+    /// CRC says
+    pub fn drop_rows(&mut self) {
+        self.dropped = true;
+    }
+
+    pub fn crc(&self) -> FrameCrc {
+        let mut crc = FrameCrc::new();
+        for row in (0..DISPLAY_ROWS - 1).filter(|row| self.pairs & (1 << row) != 0) {
+            crc.add_rows(row as u8, [self.rows[row], self.rows[row + 1]]);
+        }
+        crc
+    }
+
+    /// Whether the frame an `EndFrame` with `id` and `crc` ends is this one,
+    /// and all of it arrived
+    pub fn intact(&self, id: u8, crc: u16) -> bool {
+        !self.dropped && self.id == id && self.crc().value() == crc
+    }
+}
+
+impl Serialize for PackedRows {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for PackedRows {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PackedRowsVisitor;
+
+        impl<'de> Visitor<'de> for PackedRowsVisitor {
+            type Value = PackedRows;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(f, "at most {} bytes", PACKED_ROWS_MAX)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<PackedRows, E> {
+                PackedRows::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_bytes(PackedRowsVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_crc_is_ccitt_false() {
+        let mut crc = FrameCrc::new();
+        for byte in *b"123456789" {
+            crc.add(byte);
+        }
+        assert_eq!(crc.value(), 0x29b1);
+    }
+
+    fn frame_rows(seed: u8) -> impl Iterator<Item = (u8, [[u8; 4]; 2])> {
+        (0..DISPLAY_ROWS as u8).step_by(2).map(move |row| {
+            let byte = row.wrapping_mul(seed);
+            (row, [[byte; 4], [!byte; 4]])
+        })
+    }
+
+    /// What the host sends with `EndFrame`
+    fn host_crc(seed: u8) -> u16 {
+        let mut crc = FrameCrc::new();
+        for (row, rows) in frame_rows(seed) {
+            crc.add_rows(row, rows);
+        }
+        crc.value()
+    }
+
+    #[test]
+    fn whole_frame_is_intact() {
+        let mut check = FrameCheck::new(7);
+        for (row, rows) in frame_rows(3) {
+            check.add_rows(row, rows);
+        }
+        assert!(check.intact(7, host_crc(3)));
+        assert!(!check.intact(8, host_crc(3)));
+    }
+
+    #[test]
+    fn retransmitted_rows_count_once() {
+        let mut check = FrameCheck::new(1);
+        for (row, rows) in frame_rows(5) {
+            check.add_rows(row, rows);
+            check.add_rows(row, rows);
+        }
+        assert!(check.intact(1, host_crc(5)));
+    }
+
+    #[test]
+    fn missing_damaged_or_dropped_rows_fail() {
+        let mut missing = FrameCheck::new(0);
+        let mut damaged = FrameCheck::new(0);
+        let mut dropped = FrameCheck::new(0);
+        for (row, rows) in frame_rows(9) {
+            if row != 40 {
+                missing.add_rows(row, rows);
+            }
+            damaged.add_rows(row, if row == 40 { [[0; 4]; 2] } else { rows });
+            dropped.add_rows(row, rows);
+        }
+        dropped.drop_rows();
+
+        assert!(!missing.intact(0, host_crc(9)));
+        assert!(!damaged.intact(0, host_crc(9)));
+        assert!(!dropped.intact(0, host_crc(9)));
+    }
+}
@@ -0,0 +1,190 @@
+//! The HID reports sent to the computer, and their mirror to the host.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::KeyboardToHost;
+
+/// Which keyboard report is sent to the computer
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum HidMode {
+    /// Any number of keys at once
+    Nkro,
+    /// The standard six key report, for KVMs and switches that can't cope
+    /// with anything else
+    SixKro,
+}
+
+/// A `SubscribeReports` lapses after this long unless it's sent again
+pub const REPORT_SUBSCRIPTION_SECS: u64 = 60;
+/// Most keys a `Report` carries besides the modifiers
+pub const REPORT_KEYCODES: usize = 12;
+
+/// Whether the host wants HID reports mirrored to it, see
+/// `HostToKeyboard::SubscribeReports`
+#[derive(Clone, Copy, Default)]
+pub struct ReportSubscription {
+    /// When the host last subscribed, `None` while it isn't
+    since_ms: Option<u64>,
+}
+
+impl ReportSubscription {
+    pub const fn new() -> Self {
+        Self { since_ms: None }
+    }
+
+    pub fn subscribe(&mut self, on: bool, now_ms: u64) {
+        self.since_ms = on.then_some(now_ms);
+    }
+
+    /// Whether reports are being mirrored, turning it off once the
+    /// subscription has lapsed so it doesn't come back
+    pub fn active(&mut self, now_ms: u64) -> bool {
+        match self.since_ms {
+            Some(at) if now_ms.saturating_sub(at) < REPORT_SUBSCRIPTION_SECS * 1000 => true,
+            Some(_) => {
+                self.since_ms = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// The `Report` for the keycodes of a HID report
+pub fn hid_report(codes: impl IntoIterator<Item = u8>) -> KeyboardToHost {
+    /// Left control to right GUI, which are bits of the modifiers instead
+    const MODIFIERS: core::ops::RangeInclusive<u8> = 0xe0..=0xe7;
+
+    let mut keycodes = [0; REPORT_KEYCODES];
+    let mut len = 0;
+    let mut mods = 0;
+
+    for code in codes {
+        if MODIFIERS.contains(&code) {
+            mods |= 1 << (code - MODIFIERS.start());
+        } else if let Some(slot) = keycodes.get_mut(len) {
+            *slot = code;
+            len += 1;
+        }
+    }
+
+    KeyboardToHost::Report {
+        len: len as u8,
+        keycodes,
+        mods,
+    }
+}
+
+/// Vendor defined usage page of the status report interface
+pub const STATUS_USAGE_PAGE: u16 = 0xff60;
+
+/// Report descriptor of the status interface, a single input report of a
+/// `StatusReport` with no report ID
+#[rustfmt::skip]
+pub const STATUS_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xff, // usage page (STATUS_USAGE_PAGE)
+    0x09, 0x01,       // usage (1)
+    0xa1, 0x01,       // collection (application)
+    0x09, 0x02,       //   usage (2)
+    0x15, 0x00,       //   logical minimum (0)
+    0x26, 0xff, 0x00, //   logical maximum (255)
+    0x75, 0x08,       //   report size (8)
+    0x95, 0x02,       //   report count (2)
+    0x81, 0x02,       //   input (data, variable, absolute)
+    0xc0,             // end collection
+];
+
+/// The keyboard state shown to the OS through the status interface, sent
+/// only when it changes
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct StatusReport {
+    pub layer: u8,
+    pub flags: u8,
+}
+
+impl StatusReport {
+    pub const LEN: usize = 2;
+    /// Set while the keyboard is locked, other bits are reserved
+    pub const LOCKED: u8 = 1 << 0;
+
+    pub fn locked(&self) -> bool {
+        self.flags & Self::LOCKED != 0
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        [self.layer, self.flags]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self {
+            layer: bytes[0],
+            flags: bytes[1],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_subscription_lapses_unless_renewed() {
+        let mut sub = ReportSubscription::new();
+        assert!(!sub.active(0));
+
+        sub.subscribe(true, 1_000);
+        assert!(sub.active(1_000));
+        assert!(sub.active(60_999));
+        assert!(!sub.active(61_000));
+        // and stays off, even if the clock were to go back
+        assert!(!sub.active(2_000));
+
+        sub.subscribe(true, 61_000);
+        sub.subscribe(true, 100_000);
+        assert!(sub.active(159_999));
+        assert!(!sub.active(160_000));
+    }
+
+    #[test]
+    fn report_subscription_can_be_ended() {
+        let mut sub = ReportSubscription::new();
+        sub.subscribe(true, 0);
+        sub.subscribe(false, 10);
+        assert!(!sub.active(20));
+    }
+
+    #[test]
+    fn reports_split_out_modifiers() {
+        // a, left shift, b, right GUI
+        let KeyboardToHost::Report {
+            len,
+            keycodes,
+            mods,
+        } = hid_report([0x04, 0xe1, 0x05, 0xe7])
+        else {
+            panic!("not a report");
+        };
+        assert_eq!(keycodes[..len as usize], [0x04, 0x05]);
+        assert_eq!(mods, 0b1000_0010);
+    }
+
+    #[test]
+    fn reports_keep_the_first_keys_that_fit() {
+        let codes = (0x04..0x04 + REPORT_KEYCODES as u8 + 3).chain([0xe0]);
+        let KeyboardToHost::Report {
+            len,
+            keycodes,
+            mods,
+        } = hid_report(codes)
+        else {
+            panic!("not a report");
+        };
+        assert_eq!(len as usize, REPORT_KEYCODES);
+        assert_eq!(
+            keycodes[REPORT_KEYCODES - 1],
+            0x04 + REPORT_KEYCODES as u8 - 1
+        );
+        assert_eq!(mods, 1);
+    }
+}
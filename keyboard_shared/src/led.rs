@@ -0,0 +1,689 @@
+//! Where each half's LEDs are, and the colour maths shared by the effects.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    display::CONTRAST_LEVELS,
+    matrix::{to_global, KEY_COLS, KEY_ROWS},
+    KeyboardSide,
+};
+
+/// Which LED effect is running, see `leds::Effects` in the firmware
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum LedMode {
+    /// Rainbow with ripples from each key press
+    RainbowWaves,
+    Rainbow,
+    Solid,
+    Breathing,
+    /// Keys glow when pressed and fade out
+    Reactive,
+    /// Each key's colour shows how much it's been used recently
+    Heatmap,
+    /// Rainbow, with keys flashing white the moment they're pressed rather
+    /// than on the next frame
+    Flash,
+    /// Every LED dark
+    Off,
+    /// Only the pressed key's own LED lights, in the LED colour, and fades
+    /// out quickly. Kept after `Off` so saved modes keep their meaning.
+    TapFade,
+}
+
+impl LedMode {
+    /// Whether the underglow shows anything in this mode, and so whether the
+    /// layer tint goes over it
+    pub const fn lights_underglow(self) -> bool {
+        !matches!(self, LedMode::Off | LedMode::TapFade)
+    }
+}
+
+/// Colour of the solid LED mode, breathing and tap fade take its hue and
+/// saturation
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct LedColour {
+    pub h: u8,
+    pub s: u8,
+    pub v: u8,
+}
+
+impl LedColour {
+    /// A dim white
+    pub const DEFAULT: Self = Self { h: 0, s: 0, v: 127 };
+}
+
+pub const UNDERGLOW_LEDS: usize = 6;
+#[cfg(not(feature = "pcb-v3"))]
+pub const SWITCH_LEDS: usize = 21;
+#[cfg(feature = "pcb-v3")]
+pub const SWITCH_LEDS: usize = 23;
+/// Extra underglow on a strip under the right half's wrist rest
+#[cfg(not(feature = "wrist-leds"))]
+pub const WRIST_LEDS: usize = 0;
+/// Extra underglow on a strip under the right half's wrist rest
+#[cfg(feature = "wrist-leds")]
+pub const WRIST_LEDS: usize = 3;
+/// The longest chain either half drives
+pub const MAX_LEDS: usize = UNDERGLOW_LEDS + SWITCH_LEDS + WRIST_LEDS;
+/// Rows an LED can sit on, from the top row of keys down to the wrist rest
+pub const LED_ROWS: usize = 6;
+
+// underglow LEDs are left to right
+#[rustfmt::skip]
+pub const UNDERGLOW_LED_POSITIONS: [(u8, u8); UNDERGLOW_LEDS] = [
+    // top row: 1, 2, 3
+    (0, 1), (2, 1), (4, 1),
+    // bottom row: 4, 5, 6
+    (4, 2), (2, 3), (0, 3),
+];
+
+// switch leds are bottom to top
+#[rustfmt::skip]
+pub const SWITCH_LED_POSITIONS: [(u8, u8); SWITCH_LEDS] = [
+    // first column: 7, 8, 9, 10
+    (3, 5), (2, 5), (1, 5), (0, 5),
+    // second column: 11, 12, 13, 14
+    (0, 4), (1, 4), (2, 4), (3, 4),
+    // third column: 15, 16, 17, 18
+    (3, 3), (2, 3), (1, 3), (0, 3),
+    // fourth column: 19, 20, 21
+    (0, 2), (1, 2), (2, 2),
+    // fifth column: 22, 23, 24
+    (2, 1), (1, 1), (0, 1),
+    // sixth column: 25, 26, 27
+    (0, 0), (1, 0), (2, 0),
+    // v3 thumb row: 28, 29
+    #[cfg(feature = "pcb-v3")]
+    (4, 4),
+    #[cfg(feature = "pcb-v3")]
+    (4, 5),
+];
+
+// the wrist rest strip hangs off the end of the right half's chain, and
+// runs from the inner edge out
+#[rustfmt::skip]
+pub const WRIST_LED_POSITIONS: [(u8, u8); WRIST_LEDS] = [
+    #[cfg(feature = "wrist-leds")]
+    (5, 5),
+    #[cfg(feature = "wrist-leds")]
+    (5, 3),
+    #[cfg(feature = "wrist-leds")]
+    (5, 1),
+];
+
+/// Where the LEDs of one half are, as `(row, col)` of that half in the order
+/// they're chained
+pub struct LedLayout {
+    pub side: KeyboardSide,
+    pub underglow: &'static [(u8, u8)],
+    pub switches: &'static [(u8, u8)],
+    /// More underglow after the switches
+    pub extra: &'static [(u8, u8)],
+}
+
+impl LedLayout {
+    pub const fn len(&self) -> usize {
+        self.underglow.len() + self.switches.len() + self.extra.len()
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every LED's position, in chain order
+    pub fn positions(&self) -> impl Iterator<Item = (u8, u8)> + 'static {
+        let (underglow, switches, extra) = (self.underglow, self.switches, self.extra);
+        underglow.iter().chain(switches).chain(extra).copied()
+    }
+
+    /// Where `(row, col)` of this half is on the whole keyboard, from 0.0 to
+    /// 1.0 each way. x runs from the outer edge of the left half to the
+    /// outer edge of the right, so effects that go by this line up across
+    /// the two halves however many LEDs each has.
+    pub fn board_position(&self, (row, col): (u8, u8)) -> (f32, f32) {
+        let (_, col) = to_global(self.side, row, col);
+
+        (
+            col as f32 / (KEY_COLS - 1) as f32,
+            row as f32 / (LED_ROWS - 1) as f32,
+        )
+    }
+}
+
+pub const LEFT_LEDS: LedLayout = LedLayout {
+    side: KeyboardSide::Left,
+    underglow: &UNDERGLOW_LED_POSITIONS,
+    switches: &SWITCH_LED_POSITIONS,
+    extra: &[],
+};
+
+pub const RIGHT_LEDS: LedLayout = LedLayout {
+    side: KeyboardSide::Right,
+    underglow: &UNDERGLOW_LED_POSITIONS,
+    switches: &SWITCH_LED_POSITIONS,
+    extra: &WRIST_LED_POSITIONS,
+};
+
+/// The rainbow effect's hue for an LED at `board`, see
+/// [`LedLayout::board_position`], before it's moved along by the effect's
+/// offset. It goes by where the LED is on the whole keyboard, so the rainbow
+/// carries on from one half to the other.
+pub fn rainbow_hue((x, y): (f32, f32)) -> u8 {
+    (y * 30.0 + x * 22.0) as u8
+}
+
+/// Something an LED effect keeps for each key, such as how recently it was
+/// pressed. Keys are given as effects see them, `x` the row and `y` the
+/// column across both halves, and anything off the keyboard, like a chord,
+/// has none.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyHeat<T> {
+    keys: [[T; KEY_ROWS]; KEY_COLS],
+}
+
+impl<T: Copy> KeyHeat<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            keys: [[value; KEY_ROWS]; KEY_COLS],
+        }
+    }
+
+    pub fn get(&self, x: u8, y: u8) -> Option<&T> {
+        self.keys.get(y as usize)?.get(x as usize)
+    }
+
+    pub fn get_mut(&mut self, x: u8, y: u8) -> Option<&mut T> {
+        self.keys.get_mut(y as usize)?.get_mut(x as usize)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.keys.iter_mut().flatten()
+    }
+}
+
+impl<T: Copy + Default> Default for KeyHeat<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Fill `out` with `base` and light the switch LEDs under `keys` with
+/// `lit`, which is given each LED's index in the chain. `keys` has a bit per
+/// column for each row of the half. `base` is left alone, so every flash is
+/// drawn over the frame the effect rendered rather than over the last flash.
+pub fn flash_over<T: Copy>(
+    layout: &LedLayout,
+    base: &[T],
+    out: &mut [T],
+    keys: &[u16],
+    mut lit: impl FnMut(usize) -> T,
+) {
+    out.copy_from_slice(base);
+
+    let first_switch = layout.underglow.len();
+    for (i, &(x, y)) in layout.switches.iter().enumerate() {
+        let pressed = matches!(keys.get(x as usize), Some(row) if (row >> y) & 1 != 0);
+        if let (true, Some(slot)) = (pressed, out.get_mut(first_switch + i)) {
+            *slot = lit(first_switch + i);
+        }
+    }
+}
+
+/// Spaces out LED writes made between frames, so the driver is never handed
+/// frames faster than it can clock them out
+pub struct FlashLimiter {
+    gap_us: u64,
+    last_us: Option<u64>,
+}
+
+impl FlashLimiter {
+    pub const fn new(gap_us: u64) -> Self {
+        Self {
+            gap_us,
+            last_us: None,
+        }
+    }
+
+    /// The soonest a flash asked for at `now_us` can be written
+    pub fn next_write_us(&self, now_us: u64) -> u64 {
+        match self.last_us {
+            Some(last) => now_us.max(last + self.gap_us),
+            None => now_us,
+        }
+    }
+
+    pub fn written(&mut self, at_us: u64) {
+        self.last_us = Some(at_us);
+    }
+}
+
+impl KeyboardSide {
+    pub fn leds(self) -> &'static LedLayout {
+        match self {
+            KeyboardSide::Left => &LEFT_LEDS,
+            KeyboardSide::Right => &RIGHT_LEDS,
+        }
+    }
+}
+
+/// The exponent of the LEDs' gamma curve, the same one `smart_leds::gamma`
+/// uses
+const LED_GAMMA: f32 = 2.8;
+
+/// Gamma correction for the LEDs, precise enough that a colour shown very
+/// dim keeps its hue once it's dithered. Colours come in as the colour at
+/// full value and the value to show it at, which the gamma curve being a
+/// power law lets be corrected apart and multiplied back together. Linear
+/// light comes out in 1/2^24ths of a PWM level.
+pub struct LedGamma {
+    /// By channel level, in 1/2^24ths of full brightness
+    channel: [u32; 256],
+    /// By value in 1/256ths, with the end of the range last to interpolate
+    /// up to
+    value: [u32; 257],
+}
+
+impl LedGamma {
+    pub fn new() -> Self {
+        fn curve(x: f32) -> u32 {
+            if x <= 0.0 {
+                return 0;
+            }
+            (libm::powf(x, LED_GAMMA) * (1 << 24) as f32 + 0.5) as u32
+        }
+
+        let mut channel = [0; 256];
+        for (level, slot) in channel.iter_mut().enumerate() {
+            *slot = curve(level as f32 / 255.0);
+        }
+        let mut value = [0; 257];
+        for (step, slot) in value.iter_mut().enumerate() {
+            *slot = curve(step as f32 / 256.0);
+        }
+        // exactly full brightness, whatever the curve's rounding
+        channel[255] = 1 << 24;
+        value[256] = 1 << 24;
+
+        Self { channel, value }
+    }
+
+    /// Linear light for `colour` shown at `value` out of `u16::MAX`
+    pub fn correct(&self, colour: [u8; 3], value: u16) -> [u32; 3] {
+        // where `value` falls in the table, in 1/256ths of a step
+        let at = value as u32 * (256 * 256) / u16::MAX as u32;
+        let (step, frac) = ((at >> 8) as usize, (at & 0xff) as u64);
+        let below = self.value[step] as u64;
+        let above = self.value[(step + 1).min(256)] as u64;
+        let value = (below * (256 - frac) + above * frac) >> 8;
+
+        colour.map(|c| ((self.channel[c as usize] as u64 * value * 255) >> 24) as u32)
+    }
+}
+
+impl Default for LedGamma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Take linear light from [`LedGamma::correct`] down to PWM levels for one
+/// LED, carrying the part of a level that doesn't fit over to the next frame
+/// in `error`. Over enough frames each channel averages out to the right
+/// level, however dim.
+pub fn dither_led(error: &mut [u32; 3], linear: [u32; 3]) -> [u8; 3] {
+    let mut out = [0; 3];
+    for ((out, error), linear) in out.iter_mut().zip(error).zip(linear) {
+        let total = linear as u64 + *error as u64;
+        *error = (total & 0xff_ffff) as u32;
+        *out = (total >> 24).min(255) as u8;
+    }
+    out
+}
+
+/// How far a display is through fading between off and fully on. Fades go
+/// a step at a time so one can turn round part way, stepping towards a new
+/// target from wherever the last one got to.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format, Debug)]
+pub struct Fade {
+    /// 0 is off, otherwise a contrast level plus one
+    level: u8,
+}
+
+impl Fade {
+    pub const OFF: Self = Self { level: 0 };
+    pub const ON: Self = Self {
+        level: CONTRAST_LEVELS,
+    };
+    /// Half brightness
+    pub const PEEK: Self = Self { level: 3 };
+
+    /// The next step towards `target`, `None` once it's there
+    pub fn step(self, target: Self) -> Option<Self> {
+        let level = match self.level.cmp(&target.level) {
+            core::cmp::Ordering::Less => self.level + 1,
+            core::cmp::Ordering::Greater => self.level - 1,
+            core::cmp::Ordering::Equal => return None,
+        };
+        Some(Self { level })
+    }
+
+    /// The contrast level to show, `None` when the display should be off.
+    /// The fade goes no brighter than `contrast`.
+    pub fn contrast(self, contrast: u8) -> Option<u8> {
+        let level = self.level.checked_sub(1)?;
+        Some(level.min(contrast))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamma_keeps_full_and_off() {
+        let gamma = LedGamma::new();
+        assert_eq!(
+            gamma.correct([255, 0, 255], u16::MAX),
+            [255 << 24, 0, 255 << 24]
+        );
+        assert_eq!(gamma.correct([255, 255, 255], 0), [0; 3]);
+
+        let mut error = [0; 3];
+        for _ in 0..100 {
+            assert_eq!(
+                dither_led(&mut error, gamma.correct([255; 3], u16::MAX)),
+                [255; 3]
+            );
+        }
+    }
+
+    #[test]
+    fn dim_colours_average_out_with_their_hue() {
+        const FRAMES: u32 = 1_000_000;
+        let gamma = LedGamma::new();
+        // orange at brightness 3, which 8 bit maths rounds to off
+        let colour = [255, 128, 0];
+        let value = 3 * 257;
+        let linear = gamma.correct(colour, value);
+
+        let mut error = [0; 3];
+        let mut sums = [0u32; 3];
+        for _ in 0..FRAMES {
+            let out = dither_led(&mut error, linear);
+            for (sum, out) in sums.iter_mut().zip(out) {
+                *sum += out as u32;
+            }
+        }
+
+        let level = |c: u8| {
+            ((c as f64 / 255.0) * (3.0 / 255.0)).powf(LED_GAMMA as f64) * 255.0 * FRAMES as f64
+        };
+        for (sum, c) in sums.into_iter().zip(colour) {
+            let expected = level(c);
+            assert!(
+                (sum as f64 - expected).abs() <= expected * 0.05 + 1.0,
+                "channel {c} lit {sum} frames of {FRAMES}, expected {expected:.0}"
+            );
+        }
+        assert!(sums[1] > 0 && sums[2] == 0);
+    }
+
+    #[test]
+    fn dark_modes_keep_the_underglow_dark() {
+        assert!(!LedMode::Off.lights_underglow());
+        assert!(!LedMode::TapFade.lights_underglow());
+        assert!(LedMode::RainbowWaves.lights_underglow());
+        assert!(LedMode::Solid.lights_underglow());
+    }
+
+    #[test]
+    fn key_heat_has_one_value_per_key() {
+        let mut heat = KeyHeat::new(0u8);
+        for (x, y) in (0..KEY_ROWS as u8).flat_map(|x| (0..KEY_COLS as u8).map(move |y| (x, y))) {
+            *heat.get_mut(x, y).unwrap() = x * KEY_COLS as u8 + y;
+        }
+
+        for (x, y) in (0..KEY_ROWS as u8).flat_map(|x| (0..KEY_COLS as u8).map(move |y| (x, y))) {
+            assert_eq!(heat.get(x, y), Some(&(x * KEY_COLS as u8 + y)));
+        }
+        assert_eq!(heat.iter_mut().count(), KEY_ROWS * KEY_COLS);
+        // the chord row and anything else off the keyboard has nowhere to go
+        assert!(heat.get_mut(KEY_ROWS as u8, 0).is_none());
+        assert!(heat.get(0, KEY_COLS as u8).is_none());
+    }
+
+    #[test]
+    fn flashes_are_drawn_over_the_rendered_frame() {
+        let base = (0..LEFT_LEDS.len()).map(|i| i as u32).collect::<Vec<_>>();
+        let mut out = vec![0; base.len()];
+        let mut keys = [0u16; KEY_ROWS];
+        // the key at row 1, column 2 has the 20th LED in the chain
+        keys[1] |= 1 << 2;
+
+        flash_over(&LEFT_LEDS, &base, &mut out, &keys, |idx| 1000 + idx as u32);
+        let lit = out
+            .iter()
+            .zip(&base)
+            .filter(|(o, b)| o != b)
+            .collect::<Vec<_>>();
+        assert_eq!(lit, [(&1019, &19)]);
+        assert_eq!(LEFT_LEDS.switches[19 - UNDERGLOW_LEDS], (1, 2));
+
+        // a second flash starts from the rendered frame again, so a key let
+        // out of `keys` goes back to the effect
+        keys = [0; KEY_ROWS];
+        keys[0] |= 1;
+        flash_over(&LEFT_LEDS, &base, &mut out, &keys, |_| u32::MAX);
+        let lit = out.iter().zip(&base).filter(|(o, b)| o != b).count();
+        assert_eq!(lit, 1);
+        assert_eq!(out[19], 19);
+    }
+
+    #[test]
+    fn flashes_skip_keys_without_a_switch_led() {
+        let base = vec![0u8; LEFT_LEDS.len()];
+        let mut out = base.clone();
+        // no switch LED sits at row 3, column 0
+        assert!(!SWITCH_LED_POSITIONS.contains(&(3, 0)));
+        let mut keys = [0u16; KEY_ROWS];
+        keys[3] = 1;
+
+        flash_over(&LEFT_LEDS, &base, &mut out, &keys, |_| 1);
+        assert_eq!(out, base);
+    }
+
+    #[test]
+    fn flash_writes_are_spaced_out() {
+        let mut limiter = FlashLimiter::new(2000);
+        assert_eq!(limiter.next_write_us(500), 500);
+        limiter.written(500);
+        assert_eq!(limiter.next_write_us(600), 2500);
+        assert_eq!(limiter.next_write_us(9000), 9000);
+    }
+
+    /// Time to clock the left half's LEDs out, at 24 bits of 1.25us each
+    /// plus the 50us latch
+    const LED_WRITE_US: u64 = LEFT_LEDS.len() as u64 * 24 * 5 / 4 + 50;
+    /// The LED ticker's period, 30 frames a second
+    const LED_FRAME_US: u64 = 33_333;
+
+    /// How long each of a run of presses takes to light its key, from when
+    /// it's scanned to when the chain is written. Presses come in bursts
+    /// like fast typing, and the next frame lights them if that's sooner.
+    /// With `flash` false only frames light them, as in every other mode.
+    fn press_to_light_us(flash: bool) -> Vec<u64> {
+        let mut limiter = FlashLimiter::new(2000);
+        let mut rng = 0x1492u64;
+        let mut now = 0;
+        let mut latencies = Vec::new();
+        for _ in 0..2000 {
+            // xorshift, for gaps between presses of 1 to 120ms
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            now += 1000 + rng % 119_000;
+
+            let frame = (now / LED_FRAME_US + 1) * LED_FRAME_US + LED_WRITE_US;
+            let shown = if flash {
+                let at = limiter.next_write_us(now);
+                if at < frame {
+                    limiter.written(at);
+                }
+                frame.min(at + LED_WRITE_US)
+            } else {
+                frame
+            };
+            latencies.push(shown - now);
+        }
+        latencies
+    }
+
+    #[test]
+    fn flashes_light_keys_within_5ms() {
+        let framed = press_to_light_us(false);
+        let flashed = press_to_light_us(true);
+        let mean = |l: &[u64]| l.iter().sum::<u64>() / l.len() as u64;
+
+        // waiting for a frame averages half of one
+        assert!(
+            (15_000..19_000).contains(&mean(&framed)),
+            "{}",
+            mean(&framed)
+        );
+        assert!(framed.iter().any(|&l| l > 30_000));
+        // a flash is at most the gap between writes and one write
+        assert!(
+            flashed.iter().all(|&l| l <= 2000 + LED_WRITE_US),
+            "{flashed:?}"
+        );
+        assert!(mean(&flashed) < 2000);
+    }
+
+    #[test]
+    fn each_half_has_a_position_for_every_led() {
+        assert_eq!(LEFT_LEDS.len(), UNDERGLOW_LEDS + SWITCH_LEDS);
+        assert_eq!(RIGHT_LEDS.len(), MAX_LEDS);
+        for layout in [&LEFT_LEDS, &RIGHT_LEDS] {
+            assert!(layout.len() <= MAX_LEDS);
+            assert_eq!(layout.positions().count(), layout.len());
+            assert!(layout
+                .positions()
+                .all(|(row, col)| (row as usize) < LED_ROWS && (col as usize) < KEY_COLS / 2));
+        }
+    }
+
+    #[test]
+    fn board_positions_span_both_halves() {
+        for (row, col) in LEFT_LEDS.positions() {
+            let (lx, ly) = LEFT_LEDS.board_position((row, col));
+            let (rx, ry) = RIGHT_LEDS.board_position((row, col));
+            assert!((0.0..0.5).contains(&lx), "{lx}");
+            assert!((0.5..=1.0).contains(&rx), "{rx}");
+            // the right half is a mirror of the left
+            assert!((lx + rx - 1.0).abs() < 1e-6);
+            assert_eq!(ly, ry);
+        }
+    }
+
+    #[test]
+    fn rainbow_carries_on_across_the_halves() {
+        for row in 0..LED_ROWS as u8 {
+            // each half's columns from the outer edge of the left half
+            let hues = (0..KEY_COLS as u8 / 2)
+                .map(|col| LEFT_LEDS.board_position((row, col)))
+                .chain(
+                    (0..KEY_COLS as u8 / 2)
+                        .rev()
+                        .map(|col| RIGHT_LEDS.board_position((row, col))),
+                )
+                .map(rainbow_hue)
+                .collect::<Vec<_>>();
+
+            // the same step from one column to the next, including from the
+            // left half's inner column to the right half's
+            for pair in hues.windows(2) {
+                assert_eq!(pair[1] - pair[0], 2, "row {row}: {hues:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn rainbow_looks_the_same_on_the_left() {
+        // the hues before it went by board position
+        for (row, col) in LEFT_LEDS.positions() {
+            let hue = rainbow_hue(LEFT_LEDS.board_position((row, col)));
+            assert_eq!(hue, row * 6 + col * 2);
+        }
+    }
+
+    /// The contrast levels shown fading from `from` towards each target in
+    /// turn, taking up to that many steps towards each before turning round
+    fn fade(from: Fade, targets: &[(Fade, usize)]) -> Vec<Option<u8>> {
+        let mut fade = from;
+        let mut shown = Vec::new();
+        for &(target, steps) in targets {
+            for _ in 0..steps {
+                let Some(next) = fade.step(target) else {
+                    break;
+                };
+                fade = next;
+                shown.push(fade.contrast(CONTRAST_LEVELS - 1));
+            }
+        }
+        shown
+    }
+
+    #[test]
+    fn fades_step_through_every_level() {
+        assert_eq!(
+            fade(Fade::ON, &[(Fade::OFF, 10)]),
+            [Some(3), Some(2), Some(1), Some(0), None]
+        );
+        assert_eq!(
+            fade(Fade::OFF, &[(Fade::ON, 10)]),
+            [Some(0), Some(1), Some(2), Some(3), Some(4)]
+        );
+        // and stop once they're there
+        assert_eq!(Fade::ON.step(Fade::ON), None);
+        assert_eq!(Fade::OFF.step(Fade::OFF), None);
+    }
+
+    #[test]
+    fn fade_cancelled_part_way_turns_round() {
+        // a key pressed two steps into fading out
+        assert_eq!(
+            fade(Fade::ON, &[(Fade::OFF, 2), (Fade::ON, 10)]),
+            [Some(3), Some(2), Some(3), Some(4)]
+        );
+        // and changing its mind again on the way back up
+        assert_eq!(
+            fade(Fade::ON, &[(Fade::OFF, 3), (Fade::ON, 1), (Fade::OFF, 10)]),
+            [Some(3), Some(2), Some(1), Some(2), Some(1), Some(0), None]
+        );
+    }
+
+    #[test]
+    fn new_fade_starts_from_the_current_level() {
+        // dimmed to peek, then idle long enough to go off
+        assert_eq!(
+            fade(Fade::ON, &[(Fade::PEEK, 10), (Fade::OFF, 10)]),
+            [Some(3), Some(2), Some(1), Some(0), None]
+        );
+        // woken from peek, it doesn't go dark first
+        assert_eq!(fade(Fade::PEEK, &[(Fade::ON, 10)]), [Some(3), Some(4)]);
+        // and a fade out cut short by a peek settles there
+        assert_eq!(
+            fade(Fade::ON, &[(Fade::OFF, 4), (Fade::PEEK, 10)]),
+            [Some(3), Some(2), Some(1), Some(0), Some(1), Some(2)]
+        );
+    }
+
+    #[test]
+    fn fades_go_no_brighter_than_the_contrast() {
+        assert_eq!(Fade::ON.contrast(1), Some(1));
+        assert_eq!(Fade::PEEK.contrast(4), Some(2));
+        assert_eq!(Fade::PEEK.contrast(0), Some(0));
+        assert_eq!(Fade::OFF.contrast(4), None);
+    }
+}
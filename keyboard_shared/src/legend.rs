@@ -0,0 +1,324 @@
+//! Short labels for the layer legend, which build.rs turns `LAYERS` into for
+//! `legend_display`. Keys are HID usage codes, which is what keyberon's
+//! `KeyCode`s are, so build.rs only has to say what each action is.
+
+use core::fmt::Write;
+
+use crate::tuning::KeymapPos;
+
+/// Longest a label can be, so two keys fit side by side on the display
+pub const MAX_LABEL: usize = 3;
+/// Most keys a [`LegendKey::Combo`] can hold
+pub const MAX_COMBO: usize = 8;
+
+pub type Label = heapless::String<MAX_LABEL>;
+
+/// What an action of the keymap shows on the legend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LegendKey {
+    /// Does nothing, shown blank
+    NoOp,
+    /// Shows the base layer's key instead
+    Trans,
+    Key(u8),
+    /// Several keys pressed at once
+    Combo(heapless::Vec<u8, MAX_COMBO>),
+    Layer(usize),
+    DefaultLayer(usize),
+    /// A key with a label of its own, like a media key
+    Named(&'static str),
+    /// Anything else, shown as `?`
+    Other,
+}
+
+/// A keycode in the keymap without a label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingLabel {
+    pub pos: KeymapPos,
+    pub keycode: u8,
+}
+
+const LETTERS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "1234567890";
+/// What the digits type with shift held, on a US layout
+const SHIFTED_DIGITS: &str = "!@#$%^&*()";
+const F_KEYS: [&str; 12] = [
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+];
+
+/// The character at `i` of an ASCII `s`
+fn nth(s: &'static str, i: u8) -> &'static str {
+    let i = i as usize;
+    &s[i..i + 1]
+}
+
+pub fn key_label(keycode: u8) -> Option<&'static str> {
+    Some(match keycode {
+        0x04..=0x1d => nth(LETTERS, keycode - 0x04),
+        0x1e..=0x27 => nth(DIGITS, keycode - 0x1e),
+        0x28 => "Ent",
+        0x29 => "Esc",
+        0x2a => "Bsp",
+        0x2b => "Tab",
+        0x2c => "Spc",
+        0x2d => "-",
+        0x2e => "=",
+        0x2f => "[",
+        0x30 => "]",
+        0x31 => "\\",
+        0x33 => ";",
+        0x34 => "'",
+        0x35 => "`",
+        0x36 => ",",
+        0x37 => ".",
+        0x38 => "/",
+        0x39 => "Cap",
+        0x3a..=0x45 => F_KEYS[(keycode - 0x3a) as usize],
+        0x46 => "PSc",
+        0x47 => "ScL",
+        0x48 => "Pau",
+        0x49 => "Ins",
+        0x4a => "Hom",
+        0x4b => "PgU",
+        0x4c => "Del",
+        0x4d => "End",
+        0x4e => "PgD",
+        0x4f => "Rgt",
+        0x50 => "Lft",
+        0x51 => "Dn",
+        0x52 => "Up",
+        0x7f => "Mut",
+        0x80 => "Vo+",
+        0x81 => "Vo-",
+        0xe0 | 0xe4 => "Ctl",
+        0xe1 | 0xe5 => "Sft",
+        0xe2 => "Alt",
+        0xe6 => "AlG",
+        0xe3 | 0xe7 => "Gui",
+        _ => return None,
+    })
+}
+
+/// What a key types with shift held, on a US layout
+pub fn shifted_label(keycode: u8) -> Option<&'static str> {
+    Some(match keycode {
+        0x1e..=0x27 => nth(SHIFTED_DIGITS, keycode - 0x1e),
+        0x2d => "_",
+        0x2e => "+",
+        0x2f => "{",
+        0x30 => "}",
+        0x31 => "|",
+        0x33 => ":",
+        0x34 => "\"",
+        0x35 => "~",
+        0x36 => "<",
+        0x37 => ">",
+        0x38 => "?",
+        k => return key_label(k),
+    })
+}
+
+/// Left control to right GUI, going control, shift, alt, GUI on each side
+fn modifier_prefix(keycode: u8) -> Option<&'static str> {
+    match keycode {
+        0xe0..=0xe7 => Some(["^", "S", "M", "G"][(keycode % 4) as usize]),
+        _ => None,
+    }
+}
+
+fn is_shift(keycode: u8) -> bool {
+    matches!(keycode, 0xe1 | 0xe5)
+}
+
+/// Add as much of `s` to `label` as fits
+fn push_truncated(label: &mut Label, s: &str) {
+    for c in s.chars() {
+        if label.push(c).is_err() {
+            break;
+        }
+    }
+}
+
+/// Label for several keys pressed at once, shifted keys show the character
+/// they produce and other modifiers become a one character prefix. Returns the
+/// keycode missing a label if there is one.
+pub fn combo_label(keys: &[u8]) -> Result<Label, u8> {
+    let is_mod = |k: &u8| modifier_prefix(*k).is_some();
+    let mods = || keys.iter().copied().filter(is_mod);
+    let others = || keys.iter().copied().filter(|k| !is_mod(k));
+    let mut label = Label::new();
+
+    if let [key] = others().collect::<heapless::Vec<u8, MAX_COMBO>>()[..] {
+        if keys.len() == 2 && mods().all(is_shift) {
+            push_truncated(&mut label, shifted_label(key).ok_or(key)?);
+            return Ok(label);
+        }
+
+        for prefix in mods().filter_map(modifier_prefix) {
+            push_truncated(&mut label, prefix);
+        }
+        push_truncated(&mut label, key_label(key).ok_or(key)?);
+        return Ok(label);
+    }
+
+    // rare enough that the first character of each is fine
+    for k in mods().chain(others()) {
+        push_truncated(&mut label, &key_label(k).ok_or(k)?[..1]);
+    }
+    Ok(label)
+}
+
+/// Label for a key on the legend, `Trans` is resolved by [`legend`]. Returns
+/// the keycode missing a label if there is one.
+pub fn legend_label(key: &LegendKey) -> Result<Label, u8> {
+    let mut label = Label::new();
+    match key {
+        LegendKey::NoOp | LegendKey::Trans => {}
+        LegendKey::Key(k) => push_truncated(&mut label, key_label(*k).ok_or(*k)?),
+        LegendKey::Combo(keys) => return combo_label(keys),
+        LegendKey::Layer(n) => {
+            let _ = write!(label, "L{}", n);
+        }
+        LegendKey::DefaultLayer(n) => {
+            let _ = write!(label, "D{}", n);
+        }
+        LegendKey::Named(name) => push_truncated(&mut label, name),
+        LegendKey::Other => push_truncated(&mut label, "?"),
+    }
+    Ok(label)
+}
+
+/// The label of every key of `layers`, with `Trans` keys showing the base
+/// layer's, or the first keycode in the keymap without a label.
+/// `legend_key` says what each action is.
+pub fn legend<A, const C: usize, const R: usize, const L: usize>(
+    layers: &[[[A; C]; R]; L],
+    legend_key: impl Fn(&A) -> LegendKey,
+) -> Result<[[[Label; C]; R]; L], MissingLabel> {
+    let mut labels: [[[Label; C]; R]; L] =
+        core::array::from_fn(|_| core::array::from_fn(|_| core::array::from_fn(|_| Label::new())));
+
+    for (l, layer) in layers.iter().enumerate() {
+        for (r, row) in layer.iter().enumerate() {
+            for (c, action) in row.iter().enumerate() {
+                let key = match legend_key(action) {
+                    LegendKey::Trans => legend_key(&layers[0][r][c]),
+                    key => key,
+                };
+                labels[l][r][c] = legend_label(&key).map_err(|keycode| MissingLabel {
+                    pos: (l as u8, r as u8, c as u8),
+                    keycode,
+                })?;
+            }
+        }
+    }
+
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u8 = 0x04;
+    const KB1: u8 = 0x1e;
+    const SCOLON: u8 = 0x33;
+    const SPACE: u8 = 0x2c;
+    const GRAVE: u8 = 0x35;
+    const DOWN: u8 = 0x51;
+    const F10: u8 = 0x43;
+    const L_CTRL: u8 = 0xe0;
+    const L_SHIFT: u8 = 0xe1;
+    const L_ALT: u8 = 0xe2;
+    const R_SHIFT: u8 = 0xe5;
+    /// The non-US `#`, which has no label
+    const NON_US_HASH: u8 = 0x32;
+
+    fn combo(keys: &[u8]) -> LegendKey {
+        LegendKey::Combo(keys.iter().copied().collect())
+    }
+
+    fn label(key: LegendKey) -> Result<Label, u8> {
+        legend_label(&key)
+    }
+
+    #[test]
+    fn keys_are_labelled_by_their_usage() {
+        assert_eq!(label(LegendKey::Key(A)).unwrap(), "A");
+        assert_eq!(label(LegendKey::Key(A + 25)).unwrap(), "Z");
+        assert_eq!(label(LegendKey::Key(KB1)).unwrap(), "1");
+        assert_eq!(label(LegendKey::Key(KB1 + 9)).unwrap(), "0");
+        assert_eq!(label(LegendKey::Key(F10)).unwrap(), "F10");
+        assert_eq!(label(LegendKey::Key(SPACE)).unwrap(), "Spc");
+        assert_eq!(label(LegendKey::Key(NON_US_HASH)), Err(NON_US_HASH));
+    }
+
+    #[test]
+    fn shift_combos_show_the_character_typed() {
+        assert_eq!(label(combo(&[L_SHIFT, KB1])).unwrap(), "!");
+        assert_eq!(label(combo(&[SCOLON, R_SHIFT])).unwrap(), ":");
+        assert_eq!(label(combo(&[L_SHIFT, A])).unwrap(), "A");
+    }
+
+    #[test]
+    fn other_modifiers_become_a_prefix() {
+        assert_eq!(label(combo(&[L_CTRL, DOWN])).unwrap(), "^Dn");
+        assert_eq!(label(combo(&[L_ALT, L_SHIFT, F10])).unwrap(), "MSF");
+    }
+
+    #[test]
+    fn combos_of_several_keys_take_the_first_character_of_each() {
+        assert_eq!(label(combo(&[SPACE, GRAVE])).unwrap(), "S`");
+        assert_eq!(label(combo(&[SPACE, NON_US_HASH])), Err(NON_US_HASH));
+    }
+
+    #[test]
+    fn layers_and_the_rest() {
+        assert_eq!(label(LegendKey::Layer(2)).unwrap(), "L2");
+        assert_eq!(label(LegendKey::DefaultLayer(0)).unwrap(), "D0");
+        assert_eq!(label(LegendKey::Named("Ply")).unwrap(), "Ply");
+        assert_eq!(label(LegendKey::Named("Play")).unwrap(), "Pla");
+        assert_eq!(label(LegendKey::Other).unwrap(), "?");
+        assert_eq!(label(LegendKey::NoOp).unwrap(), "");
+    }
+
+    #[test]
+    fn every_label_fits() {
+        for keycode in 0..=u8::MAX {
+            for label in [key_label(keycode), shifted_label(keycode)]
+                .into_iter()
+                .flatten()
+            {
+                assert!(label.len() <= MAX_LABEL, "{label:?} for {keycode:#04x}");
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_keys_show_the_base_layer() {
+        let layers = [
+            [[LegendKey::Key(A), LegendKey::Layer(1)]],
+            [[LegendKey::Trans, LegendKey::Key(SPACE)]],
+        ];
+
+        let labels = legend(&layers, LegendKey::clone).unwrap();
+
+        assert_eq!(labels, [[["A", "L1"]], [["A", "Spc"]]]);
+    }
+
+    #[test]
+    fn the_first_key_without_a_label_is_reported() {
+        let layers = [
+            [[LegendKey::Key(A)], [LegendKey::Key(SPACE)]],
+            [[LegendKey::Trans], [combo(&[L_CTRL, NON_US_HASH])]],
+        ];
+
+        assert_eq!(
+            legend(&layers, LegendKey::clone),
+            Err(MissingLabel {
+                pos: (1, 1, 0),
+                keycode: NON_US_HASH
+            })
+        );
+    }
+}
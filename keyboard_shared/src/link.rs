@@ -0,0 +1,810 @@
+//! Reading frames off the link between the halves, and keeping the right
+//! half in step once it's up.
+
+/// Transport errors in a row, without a frame read cleanly in between, that
+/// make a burst, after which each half says `Hello` again
+pub const LINK_BURST_ERRORS: u8 = 4;
+/// First wait before reading from the link again after a transport error,
+/// in milliseconds
+pub const LINK_BACKOFF_MIN_MS: u64 = 1;
+/// Longest wait between reads while the transport keeps erroring
+pub const LINK_BACKOFF_MAX_MS: u64 = 500;
+
+/// What a byte read from the link made of the frame being read
+#[derive(PartialEq, Eq, Debug)]
+pub enum LinkByte<'a> {
+    /// Nothing yet, the frame isn't finished or the byte was skipped
+    Partial,
+    /// A whole COBS encoded frame, delimiter included
+    Frame(&'a mut [u8]),
+    /// The frame didn't fit, the rest of it is skipped
+    TooLong,
+}
+
+/// Splits the bytes read from the link between the halves into frames.
+///
+/// After a transport error the frame being read is thrown away and the rest
+/// of it is skipped up to the next delimiter, so its tail isn't decoded as a
+/// frame of its own. After backing off, the reader always skips to a
+/// delimiter, since bytes may have been lost while it wasn't reading.
+pub struct LinkReader<const N: usize> {
+    frame: heapless::Vec<u8, N>,
+    /// Dropping bytes up to the next delimiter
+    skipping: bool,
+    /// Transport errors since a frame was last read cleanly
+    errors: u8,
+    backoff_ms: Option<u64>,
+}
+
+impl<const N: usize> LinkReader<N> {
+    pub const fn new() -> Self {
+        Self {
+            frame: heapless::Vec::new(),
+            skipping: false,
+            errors: 0,
+            backoff_ms: None,
+        }
+    }
+
+    /// Whether bytes have gone in since the last delimiter
+    fn mid_frame(&self) -> bool {
+        matches!(self.frame.last(), Some(b) if *b != 0)
+    }
+
+    /// Take the next byte the transport read without error
+    pub fn byte(&mut self, byte: u8) -> LinkByte<'_> {
+        self.backoff_ms = None;
+        if !self.mid_frame() {
+            self.frame.clear();
+        }
+        if self.skipping {
+            self.skipping = byte != 0;
+            return LinkByte::Partial;
+        }
+        if self.frame.push(byte).is_err() {
+            self.frame.clear();
+            self.skipping = byte != 0;
+            return LinkByte::TooLong;
+        }
+        if byte == 0 {
+            LinkByte::Frame(&mut self.frame)
+        } else {
+            LinkByte::Partial
+        }
+    }
+
+    /// A frame decoded and passed its checksum, so the link is working
+    pub fn frame_ok(&mut self) {
+        self.errors = 0;
+    }
+
+    /// Throw away the frame being read, and skip the rest of it
+    pub fn flush(&mut self) {
+        self.skipping |= self.mid_frame();
+        self.frame.clear();
+    }
+
+    /// Count a transport error, which flushes the frame. True if it makes a
+    /// burst.
+    pub fn error(&mut self) -> bool {
+        self.flush();
+        self.errors = self.errors.saturating_add(1);
+        self.errors == LINK_BURST_ERRORS
+    }
+
+    /// How long to wait before reading again after an error, doubling while
+    /// they keep coming so a link that errors on every read doesn't spin
+    pub fn backoff_ms(&mut self) -> u64 {
+        let delay = match self.backoff_ms {
+            Some(d) => (d * 2).min(LINK_BACKOFF_MAX_MS),
+            None => LINK_BACKOFF_MIN_MS,
+        };
+        self.backoff_ms = Some(delay);
+        delay
+    }
+
+    /// Reading again after backing off. This skips up to the next delimiter
+    /// even if no frame had started, as bytes may have been lost from the
+    /// start of the next one.
+    pub fn resume(&mut self) {
+        self.flush();
+        self.skipping = true;
+    }
+}
+
+impl<const N: usize> Default for LinkReader<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Retransmits within a sample period that mark the link as degraded
+pub const DEGRADED_RETRANSMITS: u32 = 5;
+/// Consecutive quiet sample periods before the link counts as recovered
+pub const RECOVERY_SAMPLES: u8 = 10;
+
+/// Hysteresis over the retransmit rate, fed once a second. A single bad
+/// sample marks the link as degraded, and it takes `RECOVERY_SAMPLES` quiet
+/// ones in a row to recover, so a link that's in and out doesn't flap.
+pub struct LinkHealth {
+    last_retransmits: u32,
+    quiet_samples: u8,
+    degraded: bool,
+}
+
+impl LinkHealth {
+    pub const fn new(retransmits: u32) -> Self {
+        Self {
+            last_retransmits: retransmits,
+            quiet_samples: 0,
+            degraded: false,
+        }
+    }
+
+    pub fn degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Take a sample of the retransmit counter, returning the new state if
+    /// the link just became degraded or recovered
+    pub fn sample(&mut self, retransmits: u32) -> Option<bool> {
+        let rate = retransmits.wrapping_sub(self.last_retransmits);
+        self.last_retransmits = retransmits;
+
+        let was_degraded = self.degraded;
+        let now_degraded = if rate >= DEGRADED_RETRANSMITS {
+            self.quiet_samples = 0;
+            true
+        } else if was_degraded {
+            self.quiet_samples += 1;
+            self.quiet_samples < RECOVERY_SAMPLES
+        } else {
+            false
+        };
+
+        if now_degraded == was_degraded {
+            return None;
+        }
+
+        self.quiet_samples = 0;
+        self.degraded = now_degraded;
+        Some(now_degraded)
+    }
+}
+
+/// LED frames between the left half resending its effect counter
+pub const LED_RESYNC_FRAMES: u16 = 128;
+
+/// What the left half sends to keep the right half in step: the LED effect
+/// counter, as soon as the link is up then every `LED_RESYNC_FRAMES`, and the
+/// keypress count whenever it's changed. Nothing goes before the link is up,
+/// as nobody is listening to ack it.
+pub struct PeerSync {
+    /// LED frames since a `ResyncLeds` was last queued, `None` before the
+    /// first
+    frames_since_resync: Option<u16>,
+    /// The keypress count the right half last acked
+    synced_keypresses: u32,
+}
+
+impl PeerSync {
+    pub const fn new() -> Self {
+        Self {
+            frames_since_resync: None,
+            synced_keypresses: 0,
+        }
+    }
+
+    /// Called once per LED frame, whether to queue a `ResyncLeds`. Keeps
+    /// saying to until one is queued.
+    pub fn resync_leds(&mut self, up: bool) -> bool {
+        if let Some(frames) = &mut self.frames_since_resync {
+            *frames = frames.saturating_add(1);
+        }
+        up && !matches!(self.frames_since_resync, Some(f) if f < LED_RESYNC_FRAMES)
+    }
+
+    /// A `ResyncLeds` was queued
+    pub fn leds_resynced(&mut self) {
+        self.frames_since_resync = Some(0);
+    }
+
+    /// The keypress count to send, if the link is up and it has changed
+    /// since the right half last acked one. Keypresses pile up until then, so
+    /// the first sync brings it up to date.
+    pub fn keypresses(&self, up: bool, total: u32) -> Option<u32> {
+        (up && total != self.synced_keypresses).then_some(total)
+    }
+
+    /// The right half acked a sync of `total`
+    pub fn keypresses_synced(&mut self, total: u32) {
+        self.synced_keypresses = total;
+    }
+}
+
+impl Default for PeerSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the left half holds for the right half while the link is down, so
+/// nothing waits on a queue that isn't being drained.
+///
+/// Commands that only set a piece of state are never queued, the latest of
+/// each is kept here instead and sent when the link is up. All of them are
+/// sent again whenever it comes up, so a right half that turns up late or
+/// restarted ends up with the whole state. Other commands are dropped while
+/// the link is down, as nobody is listening to act on them.
+pub struct LinkGate<K, T, const N: usize> {
+    /// The latest command for each key, and whether it's still to be sent
+    state: heapless::Vec<(K, T, bool), N>,
+    /// Commands dropped while the link was down, or with no room left to
+    /// keep them
+    dropped: u32,
+}
+
+impl<K: PartialEq, T: Clone, const N: usize> LinkGate<K, T, N> {
+    pub const fn new() -> Self {
+        Self {
+            state: heapless::Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Take a command, with the key of the state it sets if that's all it
+    /// does. Returns it if it should be queued now, which is only ever the
+    /// case for a command without a key while the link is up.
+    pub fn offer(&mut self, up: bool, key: Option<K>, cmd: T) -> Option<T> {
+        let Some(key) = key else {
+            if !up {
+                self.dropped = self.dropped.saturating_add(1);
+                return None;
+            }
+            return Some(cmd);
+        };
+
+        if let Some(held) = self.state.iter_mut().find(|(k, ..)| *k == key) {
+            *held = (key, cmd, true);
+        } else if self.state.push((key, cmd, true)).is_err() {
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        None
+    }
+
+    /// The link just came up, everything is sent again
+    pub fn link_up(&mut self) {
+        for (.., pending) in &mut self.state {
+            *pending = true;
+        }
+    }
+
+    /// The next command to send, if the link is up. Each is sent once
+    /// until it changes or the link comes up again.
+    pub fn next(&mut self, up: bool) -> Option<T> {
+        if !up {
+            return None;
+        }
+        let (_, cmd, pending) = self.state.iter_mut().find(|(.., pending)| *pending)?;
+        *pending = false;
+        Some(cmd.clone())
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+impl<K: PartialEq, T: Clone, const N: usize> Default for LinkGate<K, T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigItem, DomToSub, KeyLocation, KeyboardSide, LedMode, Rotation, StateKey};
+
+    fn link_frame(seq: u32) -> (u32, [u8; 6]) {
+        (seq, [(seq as u8) ^ 0x5a; 6])
+    }
+
+    /// 200 frames back to back, and when each starts in microseconds at a
+    /// byte per 100us
+    fn link_line() -> (Vec<u8>, Vec<u64>) {
+        let mut line = Vec::new();
+        let mut starts = Vec::new();
+        for seq in 0..200 {
+            starts.push(line.len() as u64 * 100);
+            line.extend(postcard::to_allocvec_cobs(&link_frame(seq)).unwrap());
+        }
+        (line, starts)
+    }
+
+    /// Reads the frames of `link_line` with the transport failing every read
+    /// from `error_from` up to `error_to` microseconds. Bytes that arrive
+    /// while the reader is backing off are lost. Returns the frames decoded
+    /// and whether a burst was seen.
+    fn read_link(error_from: u64, error_to: u64) -> (Vec<(u32, [u8; 6])>, bool) {
+        let frame = link_frame;
+        let (line, _) = link_line();
+
+        let mut reader = LinkReader::<16>::new();
+        let mut frames = Vec::new();
+        let mut burst = false;
+        let mut now = 0;
+        loop {
+            if (error_from..error_to).contains(&now) {
+                burst |= reader.error();
+                now += reader.backoff_ms() * 1000;
+                reader.resume();
+                continue;
+            }
+
+            let idx = now.div_ceil(100);
+            let Some(byte) = line.get(idx as usize) else {
+                break;
+            };
+            now = idx * 100 + 100;
+            if let LinkByte::Frame(bytes) = reader.byte(*byte) {
+                let decoded: (u32, [u8; 6]) = postcard::from_bytes_cobs(bytes).unwrap();
+                assert_eq!(decoded, frame(decoded.0), "misread frame");
+                reader.frame_ok();
+                frames.push(decoded);
+            }
+        }
+
+        (frames, burst)
+    }
+
+    #[test]
+    fn link_reads_every_frame_without_errors() {
+        let (frames, burst) = read_link(0, 0);
+        assert_eq!(frames.len(), 200);
+        assert!(!burst);
+    }
+
+    #[test]
+    fn link_recovers_from_errors_mid_frame() {
+        // 50ms of errors from four bytes into frame 50
+        let (_, starts) = link_line();
+        let error_from = starts[50] + 400;
+        let error_to = error_from + 50_000;
+        let (frames, burst) = read_link(error_from, error_to);
+        let seqs = frames.iter().map(|f| f.0).collect::<Vec<_>>();
+
+        assert!(burst);
+        // everything up to the frame cut short and none of that frame
+        assert_eq!(seqs[..50], (0..50).collect::<Vec<_>>());
+        assert!(!seqs.contains(&50));
+        // then every frame from once the last backoff ends, which doubling
+        // from 1ms through 50ms of errors is at most 64ms after they stop,
+        // and the reader finds a delimiter
+        let resumed = seqs[50] as usize;
+        assert!(
+            starts[resumed - 1] <= error_to + 64_000,
+            "resumed at frame {resumed}"
+        );
+        assert_eq!(seqs[50..], (resumed as u32..200).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn link_skips_frames_that_dont_fit() {
+        let mut reader = LinkReader::<4>::new();
+        let results = [1, 2, 3, 4, 5, 0, 1, 0].map(|b| match reader.byte(b) {
+            LinkByte::Frame(bytes) => bytes.len(),
+            LinkByte::TooLong => 99,
+            LinkByte::Partial => 0,
+        });
+        assert_eq!(results, [0, 0, 0, 0, 99, 0, 0, 2]);
+    }
+
+    #[test]
+    fn link_backoff_doubles_until_a_clean_byte() {
+        let mut reader = LinkReader::<4>::new();
+        let delays = [(); 12].map(|_| reader.backoff_ms());
+        assert_eq!(delays[..4], [1, 2, 4, 8]);
+        assert_eq!(delays[11], LINK_BACKOFF_MAX_MS);
+        reader.byte(0);
+        assert_eq!(reader.backoff_ms(), LINK_BACKOFF_MIN_MS);
+    }
+
+    #[test]
+    fn link_errors_in_a_row_make_one_burst() {
+        let mut reader = LinkReader::<4>::new();
+        let bursts = [(); 8].map(|_| reader.error());
+        let expected = core::array::from_fn(|i| i + 1 == LINK_BURST_ERRORS as usize);
+        assert_eq!(bursts, expected);
+
+        // a clean frame starts the count again
+        reader.frame_ok();
+        let bursts = [(); 4].map(|_| reader.error());
+        assert_eq!(bursts, [false, false, false, true]);
+    }
+
+    #[test]
+    fn link_errors_between_clean_frames_arent_a_burst() {
+        let mut reader = LinkReader::<4>::new();
+        for _ in 0..20 {
+            assert!(!reader.error());
+            assert!(!reader.error());
+            reader.frame_ok();
+        }
+    }
+
+    type Gate = LinkGate<StateKey, DomToSub, 8>;
+
+    /// Keep `cmd` in `state` the way the right half would, replacing
+    /// whatever set the same state before it
+    fn apply(state: &mut Vec<DomToSub>, cmd: DomToSub) {
+        state.retain(|c| c.state() != cmd.state());
+        state.push(cmd);
+    }
+
+    /// How a cold boot went, see `boot_with_late_peer`
+    struct Boot {
+        /// Sends that went unanswered and were tried again
+        retransmits: u32,
+        /// Sends made before the right half was listening, not counting the
+        /// heartbeats that find it
+        sent_early: u32,
+        /// The left half's keypresses and LED counter at the end
+        left: (u32, u16),
+        /// What the right half has for them at the end
+        right: (u32, u16),
+        /// The latest of each state command the left half made, and the
+        /// ones the right half has
+        left_state: Vec<DomToSub>,
+        right_state: Vec<DomToSub>,
+        /// Key grid updates the right half got, which aren't state
+        key_grid_updates: u32,
+        dropped: u32,
+    }
+
+    /// Five seconds of the left half booting with the right half only
+    /// listening after `peer_ms`. The left half applies its settings
+    /// straight away, presses a key every 70ms, draws an LED frame every
+    /// 33ms, syncs keypresses every 100ms, changes layer every 150ms and
+    /// sends the held keys every 200ms. A heartbeat every 500ms brings the
+    /// link up once it's answered. Sends go out straight away, and one nobody
+    /// answers is tried `RETRIES` more times. With `gated` off, sends don't
+    /// wait for the link.
+    fn boot_with_late_peer(peer_ms: u64, gated: bool) -> Boot {
+        const RETRIES: u32 = 3;
+
+        let mut sync = PeerSync::new();
+        let mut gate = Gate::new();
+        let mut up = false;
+        let mut boot = Boot {
+            retransmits: 0,
+            sent_early: 0,
+            left: (0, 0),
+            right: (0, 0),
+            left_state: Vec::new(),
+            right_state: Vec::new(),
+            key_grid_updates: 0,
+            dropped: 0,
+        };
+
+        let settings = [
+            DomToSub::ShowDebugScreen(true),
+            DomToSub::SetLedCalibration([255, 200, 180]),
+            DomToSub::SetConfig(ConfigItem::LedMode(LedMode::Breathing)),
+            DomToSub::SetConfig(ConfigItem::DisplayRotation {
+                side: KeyboardSide::Left,
+                rotation: Rotation::Rotate180,
+            }),
+            DomToSub::SetConfig(ConfigItem::DisplayRotation {
+                side: KeyboardSide::Right,
+                rotation: Rotation::Rotate0,
+            }),
+        ];
+
+        for now in 0..5_000 {
+            let listening = now >= peer_ms;
+            let send = |boot: &mut Boot| {
+                if listening {
+                    return true;
+                }
+                boot.sent_early += 1;
+                boot.retransmits += RETRIES;
+                false
+            };
+
+            if now % 500 == 0 && listening && !up {
+                up = true;
+                gate.link_up();
+            }
+            let link = up || !gated;
+
+            let set = |boot: &mut Boot, gate: &mut Gate, cmd: DomToSub| {
+                apply(&mut boot.left_state, cmd.clone());
+                assert!(gate.offer(link, cmd.state(), cmd).is_none());
+            };
+            if now == 0 {
+                for cmd in settings.clone() {
+                    set(&mut boot, &mut gate, cmd);
+                }
+            }
+            if now % 150 == 0 {
+                let layer = (now / 150 % 4) as u8;
+                set(&mut boot, &mut gate, DomToSub::SetLayer(layer));
+            }
+            if now % 200 == 0 {
+                let cmd = DomToSub::KeyGridState { held: now };
+                if gate.offer(link, cmd.state(), cmd).is_some() && send(&mut boot) {
+                    boot.key_grid_updates += 1;
+                }
+            }
+            while let Some(cmd) = gate.next(link) {
+                if send(&mut boot) {
+                    apply(&mut boot.right_state, cmd);
+                }
+            }
+
+            if now % 70 == 0 {
+                boot.left.0 += 1;
+            }
+            if now % 33 == 0 {
+                boot.left.1 = boot.left.1.wrapping_add(1);
+                if listening {
+                    boot.right.1 = boot.right.1.wrapping_add(1);
+                }
+                if sync.resync_leds(link) {
+                    sync.leds_resynced();
+                    if send(&mut boot) {
+                        boot.right.1 = boot.left.1;
+                    }
+                }
+            }
+            if now % 100 == 0 {
+                if let Some(kp) = sync.keypresses(link, boot.left.0) {
+                    if send(&mut boot) {
+                        boot.right.0 = kp;
+                        sync.keypresses_synced(kp);
+                    }
+                }
+            }
+        }
+
+        boot.dropped = gate.dropped();
+        boot
+    }
+
+    #[test]
+    fn late_peer_gets_nothing_until_the_link_is_up() {
+        let boot = boot_with_late_peer(2_000, true);
+        assert_eq!(boot.sent_early, 0);
+        assert_eq!(boot.retransmits, 0);
+    }
+
+    #[test]
+    fn late_peer_ends_up_in_step() {
+        let boot = boot_with_late_peer(2_000, true);
+        assert_eq!(boot.right_state, boot.left_state);
+        // the last sync was at most a sync period of presses ago
+        assert!(
+            boot.left.0 - boot.right.0 <= 2,
+            "{:?}",
+            (boot.left, boot.right)
+        );
+        assert_eq!(boot.right.1, boot.left.1);
+    }
+
+    #[test]
+    fn late_peer_without_the_gate_storms() {
+        let boot = boot_with_late_peer(2_000, false);
+        assert!(boot.retransmits > 20 * 3, "{}", boot.retransmits);
+    }
+
+    #[test]
+    fn peer_thats_there_from_the_start_is_synced() {
+        let boot = boot_with_late_peer(0, true);
+        assert_eq!(boot.retransmits, 0);
+        assert_eq!(boot.dropped, 0);
+        assert_eq!(boot.right_state, boot.left_state);
+        assert_eq!(boot.right.1, boot.left.1);
+    }
+
+    #[test]
+    fn late_peer_misses_only_what_wasnt_state() {
+        let boot = boot_with_late_peer(2_000, true);
+        // the key grid updates before the link came up at 2s were dropped
+        // rather than queued, and the rest got through
+        assert_eq!(boot.dropped, 10);
+        assert_eq!(boot.key_grid_updates, 15);
+    }
+
+    #[test]
+    fn gate_keeps_the_latest_of_each_state() {
+        let mut gate = Gate::new();
+        for layer in 0..20 {
+            let cmd = DomToSub::SetLayer(layer);
+            assert!(gate.offer(false, cmd.state(), cmd).is_none());
+        }
+        let cmd = DomToSub::SetLocked(true);
+        gate.offer(false, cmd.state(), cmd);
+
+        assert_eq!(gate.next(false), None);
+        assert_eq!(gate.next(true), Some(DomToSub::SetLayer(19)));
+        assert_eq!(gate.next(true), Some(DomToSub::SetLocked(true)));
+        assert_eq!(gate.next(true), None);
+        assert_eq!(gate.dropped(), 0);
+    }
+
+    #[test]
+    fn gate_drops_other_commands_while_down() {
+        let mut gate = Gate::new();
+        let cmd = DomToSub::KeyPressed(KeyLocation::pack(1, 2));
+        assert_eq!(gate.offer(false, cmd.state(), cmd.clone()), None);
+        assert_eq!(gate.next(true), None);
+        assert_eq!(gate.dropped(), 1);
+
+        assert_eq!(gate.offer(true, cmd.state(), cmd.clone()), Some(cmd));
+        assert_eq!(gate.dropped(), 1);
+    }
+
+    #[test]
+    fn gate_sends_everything_again_when_the_link_comes_up() {
+        let mut gate = Gate::new();
+        let settings = [
+            DomToSub::SetLayer(2),
+            DomToSub::SetConfig(ConfigItem::LedMode(LedMode::Solid)),
+            DomToSub::SetConfig(ConfigItem::DisplaySwap(true)),
+        ];
+        for cmd in settings.clone() {
+            gate.offer(true, cmd.state(), cmd);
+        }
+        let drain = |gate: &mut Gate| core::iter::from_fn(|| gate.next(true)).collect::<Vec<_>>();
+        assert_eq!(drain(&mut gate), settings);
+        assert_eq!(drain(&mut gate), []);
+
+        // the right half restarted
+        gate.link_up();
+        assert_eq!(drain(&mut gate), settings);
+    }
+
+    #[test]
+    fn gate_counts_state_it_has_no_room_for() {
+        let mut gate = LinkGate::<StateKey, DomToSub, 2>::new();
+        let settings = [
+            DomToSub::SetLayer(2),
+            DomToSub::SetLocked(false),
+            DomToSub::ShowDebugScreen(true),
+        ];
+        for cmd in settings {
+            gate.offer(true, cmd.state(), cmd);
+        }
+        assert_eq!(gate.dropped(), 1);
+        // a change to what it has still fits
+        let cmd = DomToSub::SetLayer(3);
+        gate.offer(true, cmd.state(), cmd);
+        assert_eq!(gate.dropped(), 1);
+        assert_eq!(gate.next(true), Some(DomToSub::SetLayer(3)));
+    }
+
+    #[test]
+    fn state_is_kept_per_config_item_and_side() {
+        let rotate = |side| {
+            DomToSub::SetConfig(ConfigItem::DisplayRotation {
+                side,
+                rotation: Rotation::Rotate0,
+            })
+            .state()
+        };
+        assert_ne!(rotate(KeyboardSide::Left), rotate(KeyboardSide::Right));
+        assert_eq!(
+            DomToSub::SetConfig(ConfigItem::DisplaySwap(true)).state(),
+            DomToSub::SetConfig(ConfigItem::DisplaySwap(false)).state(),
+        );
+        assert_ne!(
+            DomToSub::SetConfig(ConfigItem::DisplaySwap(true)).state(),
+            DomToSub::SetConfig(ConfigItem::LayerLegend(true)).state(),
+        );
+        assert_eq!(DomToSub::Ping.state(), None);
+    }
+
+    /// Feed `health` a sample per rate, each the retransmits since the last,
+    /// returning what each sample reported
+    fn sample_rates(health: &mut LinkHealth, total: &mut u32, rates: &[u32]) -> Vec<Option<bool>> {
+        rates
+            .iter()
+            .map(|rate| {
+                *total = total.wrapping_add(*rate);
+                health.sample(*total)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn health_degrades_at_the_threshold() {
+        let mut health = LinkHealth::new(0);
+        let mut total = 0;
+        let below = [DEGRADED_RETRANSMITS - 1; 30];
+        assert!(sample_rates(&mut health, &mut total, &below)
+            .iter()
+            .all(Option::is_none));
+        assert!(!health.degraded());
+
+        assert_eq!(
+            sample_rates(&mut health, &mut total, &[DEGRADED_RETRANSMITS]),
+            [Some(true)]
+        );
+        assert!(health.degraded());
+    }
+
+    #[test]
+    fn health_recovers_after_enough_quiet_samples() {
+        let mut health = LinkHealth::new(0);
+        let mut total = 0;
+        sample_rates(&mut health, &mut total, &[DEGRADED_RETRANSMITS]);
+
+        let quiet = [0; RECOVERY_SAMPLES as usize];
+        let reports = sample_rates(&mut health, &mut total, &quiet);
+        assert!(reports[..RECOVERY_SAMPLES as usize - 1]
+            .iter()
+            .all(Option::is_none));
+        assert_eq!(reports.last(), Some(&Some(false)));
+        assert!(!health.degraded());
+    }
+
+    #[test]
+    fn health_counts_a_few_retransmits_as_quiet() {
+        let mut health = LinkHealth::new(0);
+        let mut total = 0;
+        sample_rates(&mut health, &mut total, &[DEGRADED_RETRANSMITS]);
+
+        let noisy = [DEGRADED_RETRANSMITS - 1; RECOVERY_SAMPLES as usize];
+        let reports = sample_rates(&mut health, &mut total, &noisy);
+        assert_eq!(reports.last(), Some(&Some(false)));
+    }
+
+    #[test]
+    fn health_starts_recovering_again_after_a_bad_sample() {
+        let mut health = LinkHealth::new(0);
+        let mut total = 0;
+        sample_rates(&mut health, &mut total, &[DEGRADED_RETRANSMITS]);
+
+        let almost = [0; RECOVERY_SAMPLES as usize - 1];
+        sample_rates(&mut health, &mut total, &almost);
+        assert_eq!(
+            sample_rates(&mut health, &mut total, &[DEGRADED_RETRANSMITS]),
+            [None]
+        );
+        let reports = sample_rates(&mut health, &mut total, &almost);
+        assert!(reports.iter().all(Option::is_none));
+        assert!(health.degraded());
+        assert_eq!(sample_rates(&mut health, &mut total, &[0]), [Some(false)]);
+    }
+
+    #[test]
+    fn flapping_link_stays_degraded() {
+        let mut health = LinkHealth::new(0);
+        let mut total = 0;
+        let flapping = [DEGRADED_RETRANSMITS, 0, 0].repeat(20);
+        let reports = sample_rates(&mut health, &mut total, &flapping);
+
+        // one report when it first goes bad and none after
+        assert_eq!(reports[0], Some(true));
+        assert!(reports[1..].iter().all(Option::is_none));
+        assert!(health.degraded());
+    }
+
+    #[test]
+    fn health_only_counts_retransmits_since_it_started() {
+        let mut health = LinkHealth::new(1000);
+        assert_eq!(health.sample(1000), None);
+        assert_eq!(health.sample(1000 + DEGRADED_RETRANSMITS - 1), None);
+        assert!(!health.degraded());
+    }
+
+    #[test]
+    fn health_survives_the_counter_wrapping() {
+        let mut health = LinkHealth::new(u32::MAX - 1);
+        assert_eq!(health.sample(DEGRADED_RETRANSMITS - 2), Some(true));
+
+        let mut health = LinkHealth::new(u32::MAX - 1);
+        assert_eq!(health.sample(1), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! The key matrix, and how each half's rows and columns map onto the layout.
+
+use serde::{Deserialize, Serialize};
+
+use crate::KeyboardSide;
+
+/// Order the key matrix rows are driven in each scan
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum ScanOrder {
+    /// Top to bottom every time
+    Fixed,
+    /// Start one row further down each scan
+    Rotate,
+    /// Shuffled each scan
+    Random,
+}
+
+/// Longest `ConfigItem::ScanSettleUs` the firmware will wait
+pub const MAX_SCAN_SETTLE_US: u8 = 50;
+
+/// Rows and columns of the key matrix, with both halves side by side
+#[cfg(not(feature = "pcb-v3"))]
+pub const KEY_ROWS: usize = 4;
+/// Rows and columns of the key matrix, with both halves side by side. The v3
+/// PCB adds a row of two thumb keys per side.
+#[cfg(feature = "pcb-v3")]
+pub const KEY_ROWS: usize = 5;
+pub const KEY_COLS: usize = 12;
+
+/// The bit of a [`DomToSub::KeyGridState`](crate::DomToSub::KeyGridState) for
+/// a key of the layout
+pub const fn key_grid_bit(row: u8, col: u8) -> u64 {
+    1 << (row as usize * KEY_COLS + col as usize)
+}
+
+const _: () = assert!(KEY_ROWS * KEY_COLS <= u64::BITS as usize);
+
+/// Where the key wired to `(row, col)` of a half's matrix actually sits, in
+/// the same half's coordinates. Applied straight after debouncing, so the
+/// layout, chords, LEDs and heatmaps only see where keys are and not how
+/// the PCB was routed. Every board so far is routed in order, so this is
+/// free; one that isn't gives its table here behind its feature.
+#[inline(always)]
+pub const fn to_logical(row: u8, col: u8) -> (u8, u8) {
+    (row, col)
+}
+
+/// Where `(row, col)` of `side`'s own matrix is in the layout, which has both
+/// halves side by side. The right half's matrix numbers its columns from the
+/// other edge.
+pub const fn to_global(side: KeyboardSide, row: u8, col: u8) -> (u8, u8) {
+    match side {
+        KeyboardSide::Left => (row, col),
+        KeyboardSide::Right => (row, (KEY_COLS - 1) as u8 - col),
+    }
+}
+
+/// Where `(row, col)` of the layout is in `side`'s own coordinates, the ones
+/// its LEDs are laid out in. Keys of the other half land past its own
+/// columns.
+pub const fn to_local(side: KeyboardSide, row: u8, col: u8) -> (u8, u8) {
+    // mirroring is its own inverse
+    to_global(side, row, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{led::RIGHT_LEDS, protocol::KeyLocation};
+
+    /// Whether `remap` puts every key of a half in a different spot of the
+    /// same half, so none are lost or doubled up
+    fn remap_is_bijection(remap: impl Fn(u8, u8) -> (u8, u8)) -> bool {
+        let mut seen = [[false; KEY_COLS / 2]; KEY_ROWS];
+        for row in 0..KEY_ROWS as u8 {
+            for col in 0..(KEY_COLS / 2) as u8 {
+                let (r, c) = remap(row, col);
+                let Some(seen) = seen.get_mut(r as usize).and_then(|s| s.get_mut(c as usize))
+                else {
+                    return false;
+                };
+                if std::mem::replace(seen, true) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn matrix_remap_is_a_bijection() {
+        assert!(remap_is_bijection(to_logical));
+    }
+
+    #[test]
+    fn remaps_that_lose_keys_are_caught() {
+        // two keys wired to one spot
+        assert!(!remap_is_bijection(|row, col| (row, col.max(1))));
+        // a key pushed off the half
+        assert!(!remap_is_bijection(|row, col| (row, col + 1)));
+        // rows routed bottom up are fine
+        assert!(remap_is_bijection(|row, col| (
+            KEY_ROWS as u8 - 1 - row,
+            col
+        )));
+    }
+
+    /// Every key of one half's matrix
+    fn matrix() -> impl Iterator<Item = (u8, u8)> {
+        (0..KEY_ROWS as u8).flat_map(|row| (0..(KEY_COLS / 2) as u8).map(move |col| (row, col)))
+    }
+
+    #[test]
+    fn each_half_keeps_to_its_own_columns() {
+        for (row, col) in matrix() {
+            let (row, col) = to_logical(row, col);
+            let (lr, lc) = to_global(KeyboardSide::Left, row, col);
+            let (rr, rc) = to_global(KeyboardSide::Right, row, col);
+
+            assert_eq!((lr, rr), (row, row));
+            assert!(
+                (lc as usize) < KEY_COLS / 2,
+                "left ({row}, {col}) went to {lc}"
+            );
+            assert!(
+                (KEY_COLS / 2..KEY_COLS).contains(&(rc as usize)),
+                "right ({row}, {col}) went to {rc}"
+            );
+        }
+    }
+
+    #[test]
+    fn to_local_undoes_to_global() {
+        for side in [KeyboardSide::Left, KeyboardSide::Right] {
+            for (row, col) in matrix() {
+                let (r, c) = to_global(side, row, col);
+                assert_eq!(to_local(side, r, c), (row, col), "{side:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn right_half_presses_agree_on_where_they_are() {
+        for (row, col) in matrix() {
+            let local = to_logical(row, col);
+            let (gr, gc) = to_global(KeyboardSide::Right, local.0, local.1);
+
+            // what the left half's layout gets in a SubToDom::KeyEvents
+            let (x, y, released) = KeyLocation::unpack_key(KeyLocation::pack_key(gr, gc, true));
+            assert_eq!((x, y, released), (gr, gc, true));
+
+            // the LED effects put the event back in the half's own
+            // coordinates, so the tapwave starts on the pressed key
+            let (lr, lc) = to_local(KeyboardSide::Right, x, y);
+            assert_eq!((lr, lc), local);
+            let (bx, _) = RIGHT_LEDS.board_position((lr, lc));
+            assert_eq!((bx * (KEY_COLS - 1) as f32).round() as u8, gc);
+        }
+    }
+}
@@ -0,0 +1,992 @@
+//! The messages sent between the host and the keyboard, and between the
+//! halves.
+
+use core::{
+    hash::{Hash, Hasher},
+    mem::{discriminant, Discriminant},
+};
+
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    capture::CAPTURE_CHUNK,
+    command::csum,
+    debounce::{DebounceAdjustment, MAX_DEBOUNCE_ADJUSTMENTS},
+    diagnostics::{
+        DropChannel, EventSource, LinkErrorKind, LogLevel, LogSeverity, SafeModeReason,
+        SelfTestResults, UsageEntry, LATENCY_BUCKETS, TIMING_BUCKETS, USAGE_CHUNK,
+    },
+    display::{DisplayBusStats, DisplayContrast, FrameTransform, Rotation, StatsGraph},
+    frame::PackedRows,
+    hid::{HidMode, StatusReport, REPORT_KEYCODES},
+    led::{LedColour, LedMode},
+    matrix::{ScanOrder, KEY_COLS},
+    quiet_hours::QuietHours,
+    storage::SETTINGS_CHUNK,
+    tuning::{HoldTapBehaviour, Tuning},
+    KeyboardSide,
+};
+
+/// Session state set up by the host is dropped after this long without a
+/// valid frame from the host
+pub const HOST_TIMEOUT_MS: u64 = 5000;
+
+/// Longest message a `Log` carries, longer ones are cut short
+pub const LOG_MSG_LEN: usize = 56;
+
+/// The UTF-8 text of a `Log`, which may have been cut off mid character.
+/// Serialized with its length, like `PackedRows`.
+#[derive(Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct LogText {
+    len: u8,
+    data: [u8; LOG_MSG_LEN],
+}
+
+impl LogText {
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut data = [0; LOG_MSG_LEN];
+        data.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        Some(Self {
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+impl Serialize for LogText {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for LogText {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LogTextVisitor;
+
+        impl<'de> Visitor<'de> for LogTextVisitor {
+            type Value = LogText;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(f, "at most {} bytes", LOG_MSG_LEN)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<LogText, E> {
+                LogText::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_bytes(LogTextVisitor)
+    }
+}
+
+/// A single runtime configuration value, changes are saved with the settings
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum ConfigItem {
+    /// Show the stats screen on the left and the bongo cat on the right
+    DisplaySwap(bool),
+    /// Show the active layer's key labels instead of the usual screens
+    LayerLegend(bool),
+    DisplayRotation {
+        side: KeyboardSide,
+        rotation: Rotation,
+    },
+    LedMode(LedMode),
+    /// Read at boot, changing it resets the keyboard so it enumerates again
+    HidMode(HidMode),
+    FrameTransform {
+        side: KeyboardSide,
+        transform: FrameTransform,
+    },
+    QuietHours(QuietHours),
+    /// Minutes before presence mode turns itself off
+    JiggleMaxMinutes(u16),
+    ScanOrder(ScanOrder),
+    /// Microseconds to wait after driving a matrix row before reading the
+    /// columns
+    ScanSettleUs(u8),
+    /// A manual level turns off the automatic contrast
+    DisplayContrast(DisplayContrast),
+    /// Show a grid of every key, lighting the held ones, instead of the
+    /// usual screens
+    KeyGrid(bool),
+    LedColour(LedColour),
+    /// Index of an LED in the left half's chain kept for showing the link
+    /// and USB state, `None` leaves every LED to the effects
+    StatusLed(Option<u8>),
+    /// Chord keys from both halves together. The right half sends its keys
+    /// unchorded and the left chords the lot, which lets a chord span the
+    /// halves at the cost of a little latency on the right's keys.
+    CrossSideChords(bool),
+    StatsGraph(StatsGraph),
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum SettingsImportStatus {
+    /// The chunk was accepted, send the next one
+    Continue,
+    /// The full blob was received, validated and applied
+    Applied,
+    /// The chunk didn't follow on from the previous one, restart from offset 0
+    BadOffset,
+    TooLarge,
+    BadChecksum,
+    /// The blob was written by firmware with different settings
+    UnsupportedVersion,
+    /// The blob passed the checksum but couldn't be decoded
+    Invalid,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Debug)]
+#[repr(u8)]
+pub enum HostToKeyboard {
+    RequestStats,
+    /// Replied to with one `HoldTapStats` per hold-tap key followed by one `ChordStats` per chord
+    RequestTimingStats,
+    ShowDebugScreen(bool),
+    /// Staged until the next `FlushDisplay` or `EndFrame` for the same
+    /// side. Writing the last rows (126 and 127) also flushes, for hosts
+    /// that predate both, until one of them or `BeginFrame` is sent.
+    WritePixels {
+        side: KeyboardSide,
+        row: u8,
+        data_0: [u8; 4],
+        data_1: [u8; 4],
+    },
+    /// Lock or unlock the keyboard, while locked no keys are sent to the host
+    Lock(bool),
+    /// Replied to with a `PixelRow` holding `row` and `row + 1`
+    ReadPixels {
+        side: KeyboardSide,
+        row: u8,
+    },
+    /// Replied to with the `SettingsChunk` starting at `offset`
+    ExportSettings {
+        offset: u16,
+    },
+    /// Replied to with `SettingsImport`, chunks must be sent in order starting from 0
+    ImportSettings {
+        offset: u16,
+        total: u16,
+        len: u8,
+        data: [u8; SETTINGS_CHUNK],
+    },
+    SetConfig(ConfigItem),
+    /// Per channel gains applied to one half's LEDs, 255 leaves a channel as is
+    SetLedCalibration {
+        side: KeyboardSide,
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    /// Show a solid colour on both halves, or go back to the usual animation.
+    /// Ends with the host session.
+    LedTestColour(Option<[u8; 3]>),
+    /// Holds the host session open when there's no other traffic
+    KeepAlive,
+    /// Replied to with `DisplayInfo`
+    RequestDisplayInfo {
+        side: KeyboardSide,
+    },
+    /// Light a single LED on one half, by its index in the chain, with the
+    /// rest of that half dark. `None` goes back to the usual animation. Ends
+    /// with the host session.
+    LedTestIndex {
+        side: KeyboardSide,
+        index: Option<u8>,
+    },
+    /// Press the key at `row`, `col` of the full layout for `duration_ms`,
+    /// entering at the same point as debounced key events. Tagged as
+    /// `EventSource::Synthetic`. Ignored unless the firmware is built with
+    /// the `inject-keys` feature.
+    InjectKey {
+        row: u8,
+        col: u8,
+        duration_ms: u16,
+    },
+    /// Replied to with two `Latency` messages, real presses then synthetic ones
+    RequestLatency,
+    /// Like `InjectKey`, but for a key on the right half. The press enters
+    /// the right half's event stream and crosses the link like a real one.
+    InjectRemoteKey {
+        row: u8,
+        col: u8,
+        duration_ms: u16,
+    },
+    /// Replied to with `Status`
+    RequestStatus,
+    /// Show the pixels written since the last flush. Rows left waiting too
+    /// long for their flush are thrown away.
+    FlushDisplay {
+        side: KeyboardSide,
+    },
+    /// `WritePixels` with the rows run length encoded, for rows that shrink
+    WritePackedPixels {
+        side: KeyboardSide,
+        row: u8,
+        data: PackedRows,
+    },
+    /// Replied to with one `KeyPresses` per row of the key matrix
+    RequestKeyPresses,
+    /// Set how much both halves log from their hot paths
+    SetLogLevel(LogLevel),
+    /// The host's local time of day, the keyboard has no clock of its own
+    /// so quiet hours and the clock on the displays only apply once this has
+    /// been sent. Forwarded to the right half.
+    SetTime {
+        seconds_of_day: u32,
+    },
+    /// Change timings on both halves straight away without saving them,
+    /// leaving any that are `None` as they are. Replied to with `Tuning`, or
+    /// `TuningRejected` if the result would be out of range.
+    SetTuning {
+        chord_window_ms: Option<u16>,
+        hold_tap_timeout_ms: Option<u16>,
+    },
+    /// Save the timings in use with the settings
+    CommitTuning,
+    /// Go back to the saved timings
+    RevertTuning,
+    RequestTuning,
+    /// Change how the hold-tap at a key of the layout behaves until the
+    /// keyboard restarts, or go back to the keymap's behaviour with `None`.
+    /// Replied to with `HoldTap`, or `HoldTapRejected` if there's no hold-tap
+    /// there, it's too far into the keymap to be changed, the interval is out
+    /// of range or too many are changed already.
+    SetHoldTap {
+        layer: u8,
+        row: u8,
+        col: u8,
+        behaviour: Option<HoldTapBehaviour>,
+    },
+    /// Replied to with `HoldTap`, or `HoldTapRejected` if there's no hold-tap
+    /// there
+    RequestHoldTap {
+        layer: u8,
+        row: u8,
+        col: u8,
+    },
+    /// Replied to with `Usage` messages until all the usage stats are sent
+    RequestUsageStats,
+    /// Replied to with one `Debounce` per row of the key matrix, then
+    /// `DebounceAdjustments`
+    RequestDebounce,
+    /// Send a `Report` whenever the keyboard's HID report changes. This
+    /// gives away everything typed, so it lapses after
+    /// `REPORT_SUBSCRIPTION_SECS` unless sent again, shows on the keyboard
+    /// while it's on, and ends with the host session.
+    SubscribeReports(bool),
+    /// Put the left half's settings back to the defaults and restart it,
+    /// leaving safe mode
+    FactoryReset,
+    /// Replied to with the `CaptureChunk` starting at `offset`. Reading from
+    /// 0 pauses the link capture until the last chunk has been read, so the
+    /// chunks agree with each other.
+    ReadCapture {
+        offset: u16,
+    },
+    /// Empty the link capture, so it only holds what happens from now on
+    ClearCapture,
+    /// Allocate `bytes` on the left half's heap and free them again, to
+    /// check a big allocation is turned down rather than resetting it.
+    /// Replied to with `ForcedAllocation`.
+    ForceAllocation {
+        bytes: u16,
+    },
+    /// Replied to with `Identity`, so a host with a port to each half can
+    /// tell which is which
+    Identify,
+    /// Hold one LED of one half at a colour, by its index in the chain, over
+    /// whatever effect is running. Out of range indices are ignored. Lasts
+    /// until `ClearKeyLeds` or a reset, not just the host session.
+    SetKeyLed {
+        side: KeyboardSide,
+        index: u8,
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    /// Drop every colour set by `SetKeyLed` on both halves
+    ClearKeyLeds,
+    /// Make a key of the layout send `keycode` on `layer`, in place of
+    /// whatever the keymap has there. Keys that aren't in the layout are
+    /// ignored. Lasts until `ClearRemaps` or a reset.
+    SetKeyAction {
+        layer: u8,
+        row: u8,
+        col: u8,
+        keycode: u8,
+    },
+    /// Drop every remap set by `SetKeyAction`
+    ClearRemaps,
+    /// Save the keypress count to flash now rather than waiting for enough
+    /// presses to pile up, for just before the keyboard loses power
+    PrepareShutdown,
+    /// Restart one half into its UF2 bootloader to be flashed, replied to
+    /// with `EnteringBootloader`. The right half goes through the left, so
+    /// to do both, wait for the right's reply before sending the left's.
+    EnterBootloader {
+        side: KeyboardSide,
+    },
+    /// Start a frame of pixels for one side, throwing away any frame that
+    /// was started and never ended
+    BeginFrame {
+        side: KeyboardSide,
+        frame_id: u8,
+    },
+    /// Show the frame's pixels if `crc` matches the `FrameCrc` of the rows
+    /// written since its `BeginFrame`. Otherwise they're thrown away and
+    /// `FrameNack` is sent, so the host can send the frame again.
+    EndFrame {
+        side: KeyboardSide,
+        frame_id: u8,
+        crc: u16,
+    },
+}
+
+impl HostToKeyboard {
+    /// Whether this sets up state that only lasts as long as the host session,
+    /// which the host then needs to hold open with `KeepAlive`
+    pub fn opens_session(&self) -> bool {
+        matches!(
+            self,
+            HostToKeyboard::LedTestColour(Some(_))
+                | HostToKeyboard::LedTestIndex { index: Some(_), .. }
+                | HostToKeyboard::SubscribeReports(true)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Debug)]
+#[repr(u8)]
+pub enum KeyboardToHost {
+    Stats {
+        keypresses: u32,
+    },
+    /// Sent after `Stats` in reply to `RequestStats`
+    DebugStats {
+        cpu_busy_pct: u8,
+        /// Key presses seen by the layout, indexed by `EventSource`
+        presses_by_source: [u32; EventSource::COUNT],
+        /// Link frames dropped by the left half for failing to decode
+        corrupt_frames: u32,
+        /// Commands the left half sent again because no ack arrived in time
+        retransmits: u32,
+        /// Best effort commands the left half gave up on after running out of
+        /// retries
+        failed_sends: u32,
+        /// Messages the left half dropped on full channels, indexed by
+        /// `DropChannel`
+        channel_drops: [u32; DropChannel::COUNT],
+        /// Erase cycles of the flash pages settings are saved to
+        settings_erases: u32,
+        /// `WritePixels` turned away by either half for being out of bounds
+        rejected_pixel_writes: u32,
+        /// The halves are running different firmware, or the right half's is
+        /// too old to say
+        firmware_mismatch: bool,
+        /// Most commands the left half has had queued to go out at once
+        command_queue_high_water: u8,
+        /// Most acks the left half has had queued to go out at once
+        ack_queue_high_water: u8,
+        /// Whether presence mode is nudging the mouse
+        presence_mode: bool,
+        /// Reads from the link that the left half's UART failed
+        link_read_errors: u32,
+        /// The same errors, indexed by `LinkErrorKind`
+        link_errors: [u32; LinkErrorKind::COUNT],
+        /// Times a burst of link errors had the left half say `Hello` again
+        link_resyncs: u32,
+        /// Whether the right half is answering heartbeats
+        link_up: bool,
+        /// Times the right half stopped answering heartbeats
+        link_losses: u32,
+        /// Flush timings of the left half's display
+        display_bus: DisplayBusStats,
+        /// Presses that only lasted a single matrix scan, indexed by
+        /// `KeyboardSide`
+        phantom_presses: [u32; 2],
+        /// Why the left half booted in safe mode, `None` if it didn't
+        safe_mode: Option<SafeModeReason>,
+        /// Most bytes of the left half's heap in use at once
+        heap_high_water: u16,
+        /// Allocations the left half turned down rather than run out of heap
+        oom_averted: u32,
+    },
+    HoldTapStats {
+        index: u8,
+        total: u8,
+        row: u8,
+        col: u8,
+        timeout_ms: u16,
+        taps: u16,
+        holds: u16,
+        tap_durations: [u16; TIMING_BUCKETS],
+        hold_durations: [u16; TIMING_BUCKETS],
+    },
+    ChordStats {
+        index: u8,
+        total: u8,
+        fires: u16,
+        near_misses: u16,
+    },
+    PixelRow {
+        row: u8,
+        data_0: [u8; 4],
+        data_1: [u8; 4],
+    },
+    SettingsChunk {
+        offset: u16,
+        total: u16,
+        len: u8,
+        data: [u8; SETTINGS_CHUNK],
+    },
+    SettingsImport {
+        offset: u16,
+        status: SettingsImportStatus,
+    },
+    DisplayInfo {
+        side: KeyboardSide,
+        width: u8,
+        height: u8,
+        rotation: Rotation,
+        /// Applied to pixels written with `WritePixels`
+        transform: FrameTransform,
+    },
+    /// Sent for each half after `DebugStats`, `results` is `None` if that
+    /// half hasn't reported yet
+    SelfTest {
+        side: KeyboardSide,
+        results: Option<SelfTestResults>,
+    },
+    /// Counts of key presses by how long they took to reach a HID report,
+    /// bucketed by `LATENCY_BUCKETS_US`
+    Latency {
+        synthetic: bool,
+        buckets: [u16; LATENCY_BUCKETS],
+    },
+    /// Counts of key presses on the left half by how long until the LEDs
+    /// were next written, bucketed by `LATENCY_BUCKETS_US`. Sent after the
+    /// two `Latency`.
+    LightLatency {
+        buckets: [u16; LATENCY_BUCKETS],
+    },
+    /// Sent when the link between the halves starts or stops struggling.
+    /// While degraded, `WritePixels` for the right half are dropped.
+    LinkDegraded(bool),
+    Status(StatusReport),
+    /// Sent instead of drawing a `WritePixels` whose rows fall outside the
+    /// display
+    PixelsRejected {
+        side: KeyboardSide,
+        row: u8,
+    },
+    /// Presses of each key in a row of the key matrix since power on
+    KeyPresses {
+        row: u8,
+        counts: [u32; KEY_COLS],
+    },
+    /// The timings in use, and whether they match the saved ones
+    Tuning {
+        tuning: Tuning,
+        saved: bool,
+    },
+    /// A `SetTuning` was out of range and nothing was changed
+    TuningRejected,
+    /// How the hold-tap at a key of the layout behaves, and whether that's
+    /// been changed from the keymap's
+    HoldTap {
+        layer: u8,
+        row: u8,
+        col: u8,
+        behaviour: HoldTapBehaviour,
+        overridden: bool,
+    },
+    /// A `SetHoldTap` or `RequestHoldTap` couldn't be done
+    HoldTapRejected,
+    /// The usage stats from `offset`, `len` of `entries` are filled in
+    Usage {
+        offset: u8,
+        total: u8,
+        len: u8,
+        entries: [UsageEntry; USAGE_CHUNK],
+    },
+    /// Debounce threshold of each key in a row of the key matrix in scans,
+    /// and how many times each has chattered since power on
+    Debounce {
+        row: u8,
+        scans: [u16; KEY_COLS],
+        chatters: [u16; KEY_COLS],
+    },
+    /// The latest threshold changes, oldest first, `len` of them are filled in
+    DebounceAdjustments {
+        len: u8,
+        adjustments: [DebounceAdjustment; MAX_DEBOUNCE_ADJUSTMENTS],
+    },
+    /// The HID report just sent, while subscribed with `SubscribeReports`.
+    /// `len` of the keycodes are filled in, with the modifiers as a bitmask
+    /// like a boot keyboard report's.
+    Report {
+        len: u8,
+        keycodes: [u8; REPORT_KEYCODES],
+        mods: u8,
+    },
+    /// A log line from the left half, sent whenever the host is reading.
+    /// Which ones are sent follows the verbosity set by `SetLogLevel`.
+    Log {
+        severity: LogSeverity,
+        uptime_ms: u32,
+        msg: LogText,
+    },
+    /// Part of the link capture, a run of `CaptureRecord`s oldest first.
+    /// `total` is 0 if the firmware was built without `link-capture`.
+    CaptureChunk {
+        offset: u16,
+        total: u16,
+        len: u8,
+        data: [u8; CAPTURE_CHUNK],
+    },
+    /// Whether a `ForceAllocation` went ahead
+    ForcedAllocation {
+        bytes: u16,
+        allocated: bool,
+    },
+    /// Which half this port is to
+    Identity {
+        side: KeyboardSide,
+    },
+    /// Sent last in reply to `RequestStats`, once the right half has said
+    /// how many of its keys were pressed. Nothing is sent while the link is
+    /// down.
+    StatsV2 {
+        /// The same count as `Stats`, which carries on across restarts
+        keypresses: u32,
+        /// Since the left half booted, like the right half's count
+        left_keypresses: u32,
+        right_keypresses: u32,
+        uptime_secs: u32,
+        /// Key presses a second averaged over the last few seconds, in
+        /// hundredths
+        cps_hundredths: u16,
+    },
+    /// A half is about to restart into its bootloader, the left's link to
+    /// the host goes with it
+    EnteringBootloader {
+        side: KeyboardSide,
+    },
+    /// A frame didn't arrive intact and wasn't shown, from `EndFrame`
+    FrameNack {
+        side: KeyboardSide,
+        frame_id: u8,
+    },
+}
+
+/// A key on one half, row in the top nibble and column in the bottom
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, defmt::Format, Hash, Copy, Clone)]
+pub struct KeyLocation(u8);
+
+/// Most key events carried by one `SubToDom::KeyEvents`, a scan never
+/// produces more
+pub const MAX_KEY_EVENTS: usize = 8;
+/// Set on a packed key event for a release
+const RELEASED: u8 = 1 << 7;
+
+impl KeyLocation {
+    pub const fn unpack(self) -> (u8, u8) {
+        ((self.0 >> 4) & 0xf, self.0 & 0xf)
+    }
+
+    pub const fn pack(x: u8, y: u8) -> Self {
+        Self(((x & 0xf) << 4) | (y & 0xf))
+    }
+
+    /// Pack a key event into a byte, rows fit in three bits so the top bit
+    /// says whether it's a release
+    pub fn pack_key(x: u8, y: u8, released: bool) -> u8 {
+        let packed = Self::pack(x & 0x7, y).0;
+        if released {
+            packed | RELEASED
+        } else {
+            packed
+        }
+    }
+
+    /// The row, column and whether it was a release of a packed key event
+    pub fn unpack_key(packed: u8) -> (u8, u8, bool) {
+        let (x, y) = Self(packed & !RELEASED).unpack();
+        (x, y, packed & RELEASED != 0)
+    }
+}
+
+/// Bump this when `DomToSub`, `SubToDom` or the `ConfigItem`s they carry
+/// change
+pub const PROTOCOL_VERSION: u16 = 25;
+
+/// Swapped by the halves in `Hello`, see `version_check`
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, defmt::Format, Hash, Copy, Clone)]
+pub struct FirmwareVersion {
+    pub protocol: u16,
+    /// Short git hash of the build, followed by `-dirty` if it had
+    /// uncommitted changes, padded with `?`
+    pub git_hash: [u8; 16],
+}
+
+/// A field added to the end of a message after firmware that doesn't know
+/// about it was out. That firmware stops reading before it, so it's left out
+/// of the message's checksum and carries a checksum of its own instead. It's
+/// `None` from such firmware, or if it arrived damaged.
+#[derive(Eq, PartialEq, Debug, defmt::Format, Copy, Clone)]
+pub struct Trailing<T>(pub Option<T>);
+
+impl<T> Hash for Trailing<T> {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl<T: Serialize + Hash> Serialize for Trailing<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Some(value) => (value, csum(value)).serialize(serializer),
+            None => serializer.serialize_unit(),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de> + Hash> Deserialize<'de> for Trailing<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // running out of message means the sender didn't know about it
+        let value = <(T, u8)>::deserialize(deserializer)
+            .ok()
+            .filter(|(value, sum)| csum(value) == *sum)
+            .map(|(value, _)| value);
+        Ok(Self(value))
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Debug)]
+pub enum DomToSub {
+    ResyncLeds(u16),
+    Reset,
+    /// The left half's own presses since it booted. The right half adds on
+    /// however many it hasn't seen yet, so a lost sync is made up for by
+    /// the next one.
+    SyncKeypresses(u32),
+    WritePixels {
+        row: u8,
+        data_0: [u8; 4],
+        data_1: [u8; 4],
+    },
+    KeyPressed(KeyLocation),
+    ShowDebugScreen(bool),
+    SetLocked(bool),
+    ReadPixels {
+        row: u8,
+    },
+    SetConfig(ConfigItem),
+    SetLayer(u8),
+    SetLedCalibration([u8; 3]),
+    LedTestColour(Option<[u8; 3]>),
+    LedTestIndex(Option<u8>),
+    /// Sent once at boot, answered with `SubToDom::Hello`
+    Hello(Trailing<FirmwareVersion>),
+    /// Press or release a key, entering the right half as if the debouncer
+    /// produced it. Ignored unless built with the `inject-keys` feature.
+    InjectKey {
+        row: u8,
+        col: u8,
+        pressed: bool,
+    },
+    /// Sent after `Hello` by firmware from before the version trailed it
+    Version(FirmwareVersion),
+    /// Show the pixels written since the last flush
+    FlushDisplay,
+    /// Throw away the pixels written since the last flush, for a frame that
+    /// didn't arrive intact
+    DiscardDisplay,
+    SetLogLevel(LogLevel),
+    /// Quiet hours started or ended, only the left half keeps the time
+    SetQuiet(bool),
+    SetTuning(Tuning),
+    /// The left half's held keys, a bit per layout key from
+    /// [`key_grid_bit`](crate::key_grid_bit). Only sent while the key grid
+    /// is shown, it's how the right half hears about releases.
+    KeyGridState {
+        held: u64,
+    },
+    /// A `HostToKeyboard::SetKeyLed` for the right half
+    SetKeyLed {
+        index: u8,
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    ClearKeyLeds,
+    /// Replied to with `SubToDom::Stats`
+    RequestStats,
+    /// The keypress count the left half loaded from flash, sent after boot
+    /// and again whenever the right half says `Hello`. The right adds what's
+    /// beyond the count it was last sent to its total. Unlike
+    /// `SyncKeypresses` it isn't counted towards the presses per second.
+    RestoredKeypresses(u32),
+    /// Like `Reset`, but into the bootloader. Replied to with
+    /// `SubToDom::EnteringBootloader` first.
+    EnterBootloader,
+    /// A `HostToKeyboard::SetTime` for the right half's display, also sent
+    /// again when the right half says `Hello`
+    SetTime {
+        seconds_of_day: u32,
+    },
+    /// Sent every half second, replied to with `SubToDom::Pong`. Each half
+    /// counts the link as down after going a few without word from the other.
+    Ping,
+    /// A `HostToKeyboard::BeginFrame` for the right half, which checks the
+    /// frame's rows as they arrive there
+    BeginFrame {
+        frame_id: u8,
+    },
+    /// A `HostToKeyboard::EndFrame` for the right half, replied to with
+    /// `SubToDom::FrameNack` if the frame didn't arrive intact
+    EndFrame {
+        frame_id: u8,
+        crc: u16,
+    },
+}
+
+/// The piece of the right half's state a `DomToSub` sets, see
+/// [`DomToSub::state`]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum StateKey {
+    Command(Discriminant<DomToSub>),
+    Config(Discriminant<ConfigItem>, Option<KeyboardSide>),
+}
+
+impl DomToSub {
+    /// The state this command sets, if that's all it does. A later command
+    /// with the same key makes it redundant, and sending it again does no
+    /// harm.
+    pub fn state(&self) -> Option<StateKey> {
+        match self {
+            DomToSub::SetConfig(item) => {
+                let side = match item {
+                    ConfigItem::DisplayRotation { side, .. }
+                    | ConfigItem::FrameTransform { side, .. } => Some(*side),
+                    _ => None,
+                };
+                Some(StateKey::Config(discriminant(item), side))
+            }
+            DomToSub::ShowDebugScreen(_)
+            | DomToSub::SetLocked(_)
+            | DomToSub::SetLayer(_)
+            | DomToSub::SetLedCalibration(_)
+            | DomToSub::LedTestColour(_)
+            | DomToSub::LedTestIndex(_)
+            | DomToSub::SetLogLevel(_)
+            | DomToSub::SetQuiet(_)
+            | DomToSub::SetTuning(_)
+            | DomToSub::RestoredKeypresses(_)
+            | DomToSub::SetTime { .. } => Some(StateKey::Command(discriminant(self))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Debug)]
+pub enum SubToDom {
+    KeyPressed(KeyLocation),
+    KeyReleased(KeyLocation),
+    ChordStats {
+        chord: u8,
+        fires: u16,
+        near_misses: u16,
+    },
+    PixelRow {
+        row: u8,
+        data_0: [u8; 4],
+        data_1: [u8; 4],
+    },
+    Hello(SelfTestResults, Trailing<FirmwareVersion>),
+    /// A key event that started as a `DomToSub::InjectKey`
+    InjectedKey {
+        key: KeyLocation,
+        pressed: bool,
+    },
+    /// A `DomToSub::WritePixels` was out of bounds and wasn't drawn
+    PixelsRejected {
+        row: u8,
+    },
+    /// Sent after `Hello` by firmware from before the version trailed it
+    Version(FirmwareVersion),
+    /// Key events from the same scan in one frame, packed with
+    /// `KeyLocation::pack_key`, in the order they happened
+    KeyEvents {
+        len: u8,
+        events: [u8; MAX_KEY_EVENTS],
+    },
+    /// The right half's `matrix::PHANTOM_PRESSES`, sent when it changes
+    PhantomPresses(u32),
+    /// The debounce threshold and chatter count of one of the right half's
+    /// keys, in layout coordinates, sent when they change
+    Debounce {
+        row: u8,
+        col: u8,
+        scans: u16,
+        chatters: u16,
+    },
+    /// Presses of the right half's own keys since power on
+    Stats {
+        keypresses: u32,
+    },
+    /// Sent before restarting for `DomToSub::EnterBootloader`
+    EnteringBootloader,
+    /// Like `KeyEvents`, but before chording, sent instead while
+    /// `ConfigItem::CrossSideChords` is on
+    RawKeyEvents {
+        len: u8,
+        events: [u8; MAX_KEY_EVENTS],
+    },
+    /// The reply to `DomToSub::Ping`
+    Pong,
+    /// A frame ended with `DomToSub::EndFrame` didn't arrive intact and was
+    /// thrown away
+    FrameNack {
+        frame_id: u8,
+    },
+}
+
+impl SubToDom {
+    pub fn key_pressed(x: u8, y: u8) -> Self {
+        Self::KeyPressed(KeyLocation::pack(x, y))
+    }
+
+    pub fn key_released(x: u8, y: u8) -> Self {
+        Self::KeyReleased(KeyLocation::pack(x, y))
+    }
+
+    /// Pack a scan's key events, each a row, column and whether it was a
+    /// release, into one `KeyEvents`, or `RawKeyEvents` if they haven't been
+    /// chorded. Only the first `MAX_KEY_EVENTS` fit.
+    pub fn key_events(batch: impl IntoIterator<Item = (u8, u8, bool)>, raw: bool) -> Self {
+        let mut events = [0; MAX_KEY_EVENTS];
+        let mut len = 0;
+        for (packed, (x, y, released)) in events.iter_mut().zip(batch) {
+            *packed = KeyLocation::pack_key(x, y, released);
+            len += 1;
+        }
+
+        if raw {
+            Self::RawKeyEvents { len, events }
+        } else {
+            Self::KeyEvents { len, events }
+        }
+    }
+
+    /// The packed key events a `KeyEvents` or `RawKeyEvents` carries, in the
+    /// order they're to be applied
+    pub fn packed_key_events(&self) -> &[u8] {
+        match self {
+            Self::KeyEvents { len, events } | Self::RawKeyEvents { len, events } => {
+                &events[..(*len as usize).min(MAX_KEY_EVENTS)]
+            }
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION: FirmwareVersion = FirmwareVersion {
+        protocol: PROTOCOL_VERSION,
+        git_hash: *b"0123abcd-dirty??",
+    };
+
+    #[test]
+    fn hello_reads_the_same_without_its_version() {
+        let old = postcard::to_allocvec(&DomToSub::Hello(Trailing(None))).unwrap();
+        let new = postcard::to_allocvec(&DomToSub::Hello(Trailing(Some(VERSION)))).unwrap();
+
+        // firmware that doesn't know about the version reads up to it and
+        // stops, and checks the same checksum
+        assert!(new.len() > old.len() && new.starts_with(&old));
+        assert_eq!(
+            csum(DomToSub::Hello(Trailing(Some(VERSION)))),
+            csum(DomToSub::Hello(Trailing(None)))
+        );
+
+        let decoded: DomToSub = postcard::from_bytes(&new).unwrap();
+        assert_eq!(decoded, DomToSub::Hello(Trailing(Some(VERSION))));
+        let decoded: DomToSub = postcard::from_bytes(&old).unwrap();
+        assert_eq!(decoded, DomToSub::Hello(Trailing(None)));
+    }
+
+    /// What the left half applies from a frame of key events, as
+    /// `messages::unpack_event` reads them
+    fn applied(msg: &SubToDom) -> Vec<(u8, u8, bool)> {
+        let bytes = postcard::to_allocvec(msg).unwrap();
+        let msg: SubToDom = postcard::from_bytes(&bytes).unwrap();
+        msg.packed_key_events()
+            .iter()
+            .map(|&packed| KeyLocation::unpack_key(packed))
+            .collect()
+    }
+
+    #[test]
+    fn press_and_release_in_one_batch_apply_in_order() {
+        // a tap quick enough to land in one scan, then the next key down
+        let tap = [(3, 5, false), (3, 5, true), (4, 6, false)];
+        for raw in [false, true] {
+            assert_eq!(applied(&SubToDom::key_events(tap, raw)), tap);
+        }
+
+        // released first, as when a hold ends as a roll starts
+        let roll = [(3, 5, true), (3, 5, false)];
+        assert_eq!(applied(&SubToDom::key_events(roll, false)), roll);
+    }
+
+    #[test]
+    fn every_key_event_round_trips() {
+        for x in 0..8 {
+            for y in 0..16 {
+                for released in [false, true] {
+                    let packed = KeyLocation::pack_key(x, y, released);
+                    assert_eq!(KeyLocation::unpack_key(packed), (x, y, released));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn key_events_past_a_frame_are_left_out() {
+        let batch = (0..10).map(|y| (1, y, y % 2 == 1));
+        let applied = applied(&SubToDom::key_events(batch.clone(), false));
+        assert_eq!(applied, batch.take(MAX_KEY_EVENTS).collect::<Vec<_>>());
+
+        // and a length that doesn't fit isn't read past the events
+        let events = [KeyLocation::pack_key(2, 3, false); MAX_KEY_EVENTS];
+        let msg = SubToDom::KeyEvents { len: 200, events };
+        assert_eq!(msg.packed_key_events(), events);
+        assert!(SubToDom::Pong.packed_key_events().is_empty());
+    }
+
+    #[test]
+    fn damaged_version_is_dropped() {
+        let results = SelfTestResults {
+            oled: true,
+            link: true,
+            leds: false,
+            matrix: true,
+        };
+        let mut new =
+            postcard::to_allocvec(&SubToDom::Hello(results, Trailing(Some(VERSION)))).unwrap();
+        let last = new.len() - 3;
+        new[last] ^= 0x10;
+
+        let decoded: SubToDom = postcard::from_bytes(&new).unwrap();
+        assert_eq!(decoded, SubToDom::Hello(results, Trailing(None)));
+    }
+}
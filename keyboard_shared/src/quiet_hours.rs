@@ -0,0 +1,153 @@
+//! The daily window where the keyboard keeps itself quiet.
+
+use serde::{Deserialize, Serialize};
+
+/// A daily window where the keyboard keeps itself quiet, in minutes since
+/// midnight. The window wraps past midnight when `end` is before `start`.
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct QuietHours {
+    pub enabled: bool,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl QuietHours {
+    pub const OFF: Self = Self {
+        enabled: false,
+        start: 22 * 60,
+        end: 7 * 60,
+    };
+
+    /// Whether `minute` of the day falls inside the window, the end is
+    /// exclusive so an empty window never matches
+    pub fn contains(&self, minute: u16) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Tracks quiet hours window boundaries so an override only lasts until the
+/// window next starts or ends
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuietHoursTracker {
+    in_window: Option<bool>,
+}
+
+impl QuietHoursTracker {
+    pub const fn new() -> Self {
+        Self { in_window: None }
+    }
+
+    /// Whether it should be quiet at `minute` of the day, or `None` if the
+    /// time isn't known yet. `overridden` is cleared when the window starts
+    /// or ends.
+    pub fn quiet(
+        &mut self,
+        schedule: QuietHours,
+        minute: Option<u16>,
+        overridden: &mut bool,
+    ) -> bool {
+        let in_window = matches!(minute, Some(m) if schedule.contains(m));
+
+        if self.in_window != Some(in_window) {
+            self.in_window = Some(in_window);
+            *overridden = false;
+        }
+
+        in_window && !*overridden
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NIGHT: QuietHours = QuietHours {
+        enabled: true,
+        start: 22 * 60,
+        end: 7 * 60,
+    };
+
+    #[test]
+    fn quiet_hours_wrap_past_midnight() {
+        assert!(!NIGHT.contains(21 * 60 + 59));
+        assert!(NIGHT.contains(22 * 60));
+        assert!(NIGHT.contains(23 * 60 + 59));
+        assert!(NIGHT.contains(0));
+        assert!(NIGHT.contains(7 * 60 - 1));
+        assert!(!NIGHT.contains(7 * 60));
+        assert!(!NIGHT.contains(12 * 60));
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let lunch = QuietHours {
+            enabled: true,
+            start: 12 * 60,
+            end: 13 * 60,
+        };
+
+        assert!(!lunch.contains(12 * 60 - 1));
+        assert!(lunch.contains(12 * 60));
+        assert!(lunch.contains(13 * 60 - 1));
+        assert!(!lunch.contains(13 * 60));
+        assert!(!lunch.contains(0));
+    }
+
+    #[test]
+    fn empty_or_disabled_quiet_hours_never_match() {
+        let empty = QuietHours {
+            enabled: true,
+            start: 9 * 60,
+            end: 9 * 60,
+        };
+        let off = QuietHours {
+            enabled: false,
+            ..NIGHT
+        };
+
+        for minute in 0..24 * 60 {
+            assert!(!empty.contains(minute));
+            assert!(!off.contains(minute));
+            assert!(!QuietHours::OFF.contains(minute));
+        }
+    }
+
+    #[test]
+    fn quiet_hours_override_lasts_until_the_next_boundary() {
+        let mut tracker = QuietHoursTracker::new();
+        let mut overridden = false;
+
+        assert!(!tracker.quiet(NIGHT, None, &mut overridden));
+        assert!(tracker.quiet(NIGHT, Some(23 * 60), &mut overridden));
+
+        overridden = true;
+        assert!(!tracker.quiet(NIGHT, Some(23 * 60 + 1), &mut overridden));
+        // past midnight is still the same window
+        assert!(!tracker.quiet(NIGHT, Some(3 * 60), &mut overridden));
+        assert!(overridden);
+
+        assert!(!tracker.quiet(NIGHT, Some(7 * 60), &mut overridden));
+        assert!(!overridden);
+        assert!(tracker.quiet(NIGHT, Some(22 * 60), &mut overridden));
+    }
+
+    #[test]
+    fn quiet_hours_override_set_outside_the_window_ends_when_it_starts() {
+        let mut tracker = QuietHoursTracker::new();
+        let mut overridden = false;
+
+        assert!(!tracker.quiet(NIGHT, Some(21 * 60), &mut overridden));
+        overridden = true;
+        assert!(!tracker.quiet(NIGHT, Some(21 * 60 + 30), &mut overridden));
+        assert!(tracker.quiet(NIGHT, Some(22 * 60), &mut overridden));
+        assert!(!overridden);
+    }
+}
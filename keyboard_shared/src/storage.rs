@@ -0,0 +1,734 @@
+//! What's kept in flash: the keypress count and the settings blob.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Bytes of the settings blob carried by each export or import message
+pub const SETTINGS_CHUNK: usize = 32;
+/// Largest settings blob the firmware will accept
+pub const SETTINGS_MAX_BLOB: usize = 512;
+
+/// Checksum (32 bit FNV-1a) stored little endian in the last four bytes of a
+/// settings blob, covering everything before it.
+///
+/// The blob itself is a version byte followed by the firmware's serialized
+/// settings, the host doesn't look inside it.
+pub fn settings_checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+const KEYPRESS_RECORD_LEN: usize = 8;
+/// Xored into the count for the second half of a keypress record, so erased
+/// flash and a record that lost power partway through don't check out
+const KEYPRESS_CHECK: u32 = 0x4b50_5253;
+/// Presses made since the keypress count was last saved that make the next
+/// periodic save due
+pub const KEYPRESS_SAVE_EVERY: u32 = 1000;
+
+pub const fn encode_keypresses(count: u32) -> [u8; KEYPRESS_RECORD_LEN] {
+    let c = count.to_le_bytes();
+    let k = (count ^ KEYPRESS_CHECK).to_le_bytes();
+    [c[0], c[1], c[2], c[3], k[0], k[1], k[2], k[3]]
+}
+
+pub const fn decode_keypresses(record: [u8; KEYPRESS_RECORD_LEN]) -> Option<u32> {
+    let count = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+    let check = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+    if check == count ^ KEYPRESS_CHECK {
+        Some(count)
+    } else {
+        None
+    }
+}
+
+fn is_erased(record: &[u8; KEYPRESS_RECORD_LEN]) -> bool {
+    record.iter().all(|b| *b == 0xff)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct SaveFailed;
+
+/// The lifetime keypress count, kept in a flash page of its own.
+///
+/// Each save appends an eight byte record after the last one instead of
+/// erasing, so the page is only erased once it's full. The flash itself is
+/// borrowed for each call so it can be shared, and erasing is left to the
+/// caller, see [`KeypressStore::erase_needed`].
+pub struct KeypressStore {
+    page: u32,
+    page_size: u32,
+    /// The slot the next record goes in, `records()` once the page is full
+    next: usize,
+    saved: u32,
+}
+
+impl KeypressStore {
+    pub const fn new(page: u32, page_size: u32) -> Self {
+        Self {
+            page,
+            page_size,
+            next: 0,
+            saved: 0,
+        }
+    }
+
+    /// Records that fit in the page before it has to be erased
+    pub const fn records(&self) -> usize {
+        self.page_size as usize / KEYPRESS_RECORD_LEN
+    }
+
+    /// Find the newest record and return its count, 0 if there isn't one.
+    /// A record that doesn't check out is skipped for the one before it.
+    pub fn load<F: ReadNorFlash>(&mut self, flash: &mut F) -> u32 {
+        self.next = 0;
+        self.saved = 0;
+
+        for idx in 0..self.records() {
+            let mut record = [0u8; KEYPRESS_RECORD_LEN];
+            if flash.read(self.address(idx), &mut record).is_err() {
+                break;
+            }
+            // records are written in order, so nothing follows the first gap
+            if is_erased(&record) {
+                break;
+            }
+            if let Some(count) = decode_keypresses(record) {
+                self.saved = count;
+            }
+            self.next = idx + 1;
+        }
+
+        self.saved
+    }
+
+    /// Whether enough presses have been made since the last save to make
+    /// another
+    pub fn due(&self, count: u32) -> bool {
+        count.saturating_sub(self.saved) >= KEYPRESS_SAVE_EVERY
+    }
+
+    /// The range to erase before `count` can be saved, once the page is
+    /// full. Call [`KeypressStore::erased`] after erasing it.
+    pub fn erase_needed(&self, count: u32) -> Option<(u32, u32)> {
+        (count != self.saved && self.next >= self.records())
+            .then_some((self.page, self.page + self.page_size))
+    }
+
+    /// The page has been erased, the next record goes at its start
+    pub fn erased(&mut self) {
+        self.next = 0;
+    }
+
+    /// Write `count` unless it's what was last saved. Fails if the page is
+    /// full and hasn't been erased. Losing power between erasing a full page
+    /// and writing the first record of the next loses the count, which is
+    /// the price of a single page.
+    pub fn save<F: NorFlash>(&mut self, flash: &mut F, count: u32) -> Result<(), SaveFailed> {
+        if count == self.saved {
+            return Ok(());
+        }
+        if self.next >= self.records() {
+            return Err(SaveFailed);
+        }
+
+        // a failed write may have left something behind, so the slot is
+        // used up either way
+        let idx = self.next;
+        self.next += 1;
+        flash
+            .write(self.address(idx), &encode_keypresses(count))
+            .map_err(|_| SaveFailed)?;
+        self.saved = count;
+
+        Ok(())
+    }
+
+    fn address(&self, idx: usize) -> u32 {
+        self.page + (idx * KEYPRESS_RECORD_LEN) as u32
+    }
+}
+
+/// Marks a settings page that has been written
+const JOURNAL_MAGIC: u32 = 0x5345_5432;
+/// Magic, sequence number, erase count, then the blob length padded to a word
+pub const JOURNAL_HEADER_LEN: usize = 16;
+
+/// Header at the start of each settings page
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PageHeader {
+    /// Bumped on every save, the page with the higher one is newer
+    pub seq: u32,
+    /// Times this page has been erased, including for the save it holds
+    pub erases: u32,
+    pub len: u16,
+}
+
+impl PageHeader {
+    pub fn parse(bytes: &[u8; JOURNAL_HEADER_LEN]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let len = u16::from_le_bytes([bytes[12], bytes[13]]);
+        if word(0) != JOURNAL_MAGIC || len as usize > SETTINGS_MAX_BLOB {
+            return None;
+        }
+
+        Some(Self {
+            seq: word(4),
+            erases: word(8),
+            len,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; JOURNAL_HEADER_LEN] {
+        let mut bytes = [0u8; JOURNAL_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.erases.to_le_bytes());
+        bytes[12..14].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+}
+
+/// Indices of the pages worth trying to load, newest first
+pub fn load_order(headers: [Option<PageHeader>; 2]) -> impl Iterator<Item = usize> {
+    let newest_first = match headers {
+        [Some(a), Some(b)] if b.seq.wrapping_sub(a.seq) as i32 > 0 => [1, 0],
+        _ => [0, 1],
+    };
+
+    newest_first
+        .into_iter()
+        .filter(move |&idx| headers[idx].is_some())
+}
+
+/// The settings blob, saved to two flash pages in turn so each wears at half
+/// the rate.
+///
+/// A save goes to the page not holding the current settings, so losing power
+/// partway through leaves the previous save to load. Like [`KeypressStore`]
+/// the flash is borrowed for each call and erasing is left to the caller, see
+/// [`SettingsJournal::erase_needed`].
+pub struct SettingsJournal {
+    pages: [u32; 2],
+    page_size: u32,
+    headers: [Option<PageHeader>; 2],
+    /// The page holding the settings that were loaded or last saved
+    active: Option<usize>,
+    /// The erase count of the page the next save goes to, once it's been
+    /// erased for it
+    erased: Option<u32>,
+}
+
+impl SettingsJournal {
+    pub const fn new(pages: [u32; 2], page_size: u32) -> Self {
+        Self {
+            pages,
+            page_size,
+            headers: [None; 2],
+            active: None,
+            erased: None,
+        }
+    }
+
+    /// Erase cycles of both pages
+    pub fn erases(&self) -> u32 {
+        let target = self.target().0;
+        (0..2)
+            .map(|idx| match self.erased {
+                Some(erases) if idx == target => erases,
+                _ => self.headers[idx].map_or(0, |h| h.erases),
+            })
+            .sum()
+    }
+
+    /// Load the newest blob that checks out and that `parse` accepts. A page
+    /// whose blob doesn't, such as from losing power partway through a save,
+    /// is skipped in favour of the other. `None` if there wasn't a blob that
+    /// checked out to try.
+    pub fn load<F: ReadNorFlash, T, E>(
+        &mut self,
+        flash: &mut F,
+        mut parse: impl FnMut(&[u8]) -> Result<T, E>,
+    ) -> Option<Result<T, E>> {
+        self.read_headers(flash);
+
+        let mut result = None;
+        for idx in load_order(self.headers) {
+            let len = self.headers[idx].map_or(0, |h| h.len as usize);
+            let addr = self.pages[idx] + JOURNAL_HEADER_LEN as u32;
+            let parsed = read_blob(flash, addr, len, &mut parse);
+            if let Some(Ok(_)) = parsed {
+                self.active = Some(idx);
+                return parsed;
+            }
+            result = parsed.or(result);
+        }
+
+        result
+    }
+
+    /// Make the next save land after the newest page, without loading it
+    pub fn follow_newest<F: ReadNorFlash>(&mut self, flash: &mut F) {
+        self.read_headers(flash);
+        self.active = load_order(self.headers).next();
+    }
+
+    fn read_headers<F: ReadNorFlash>(&mut self, flash: &mut F) {
+        for (idx, page) in self.pages.iter().enumerate() {
+            let mut header = [0u8; JOURNAL_HEADER_LEN];
+            self.headers[idx] = match flash.read(*page, &mut header) {
+                Ok(()) => PageHeader::parse(&header),
+                Err(_) => None,
+            };
+        }
+        // the next save may go to the other page now
+        self.erased = None;
+    }
+
+    /// The page the next save goes to and the sequence number it gets
+    fn target(&self) -> (usize, u32) {
+        match self
+            .active
+            .and_then(|idx| self.headers[idx].map(|h| (idx, h)))
+        {
+            Some((idx, header)) => (1 - idx, header.seq.wrapping_add(1)),
+            None => (0, 0),
+        }
+    }
+
+    /// The range to erase before the next save, which is the whole page it
+    /// goes to. Call [`SettingsJournal::erased`] after erasing it.
+    pub fn erase_needed(&self) -> Option<(u32, u32)> {
+        let page = self.pages[self.target().0];
+        self.erased
+            .is_none()
+            .then_some((page, page + self.page_size))
+    }
+
+    /// The page the next save goes to has been erased
+    pub fn erased(&mut self) {
+        let target = self.target().0;
+        // a page with a torn header has lost its count, the other page's is
+        // the best guess
+        let erases = match self.headers[target] {
+            Some(h) => h.erases,
+            None => self.headers[1 - target].map_or(0, |h| h.erases),
+        };
+        self.erased = Some(erases + 1);
+    }
+
+    /// Write `blob` to the page not holding the current settings. Fails if
+    /// that page hasn't been erased for it.
+    pub fn save<F: NorFlash>(&mut self, flash: &mut F, blob: &[u8]) -> Result<(), SaveFailed> {
+        if blob.len() > SETTINGS_MAX_BLOB {
+            return Err(SaveFailed);
+        }
+        // a failed write may have left something behind, so the erase is
+        // used up either way
+        let erases = self.erased.take().ok_or(SaveFailed)?;
+
+        let (target, seq) = self.target();
+        let header = PageHeader {
+            seq,
+            erases,
+            len: blob.len() as u16,
+        };
+
+        let mut buf = [0u8; JOURNAL_HEADER_LEN + SETTINGS_MAX_BLOB];
+        buf[..JOURNAL_HEADER_LEN].copy_from_slice(&header.to_bytes());
+        buf[JOURNAL_HEADER_LEN..JOURNAL_HEADER_LEN + blob.len()].copy_from_slice(blob);
+
+        // writes have to be whole words
+        let len = (JOURNAL_HEADER_LEN + blob.len() + 3) & !3;
+
+        self.headers[target] = Some(header);
+        flash
+            .write(self.pages[target], &buf[..len])
+            .map_err(|_| SaveFailed)?;
+        self.active = Some(target);
+
+        Ok(())
+    }
+}
+
+/// Read the blob at `addr` and parse it if its checksum checks out
+fn read_blob<F: ReadNorFlash, T, E>(
+    flash: &mut F,
+    addr: u32,
+    len: usize,
+    mut parse: impl FnMut(&[u8]) -> Result<T, E>,
+) -> Option<Result<T, E>> {
+    if !(4..=SETTINGS_MAX_BLOB).contains(&len) {
+        return None;
+    }
+
+    let mut buf = [0u8; SETTINGS_MAX_BLOB];
+    flash.read(addr, &mut buf[..len]).ok()?;
+
+    let (body, csum) = buf[..len].split_at(len - 4);
+    (settings_checksum(body).to_le_bytes() == csum).then(|| parse(&buf[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// NOR flash that only clears bits when written, like the real thing
+    struct MockFlash {
+        base: u32,
+        bytes: Vec<u8>,
+        erases: usize,
+    }
+
+    impl MockFlash {
+        const PAGE: u32 = 0xfc000;
+        /// The pages the settings alternate between, above the keypress page
+        const SETTINGS_PAGES: [u32; 2] = [0xfe000, 0xfd000];
+
+        fn new() -> Self {
+            Self {
+                base: Self::PAGE,
+                bytes: vec![0xff; 3 * 4096],
+                erases: 0,
+            }
+        }
+
+        fn page(&self, page: u32) -> &[u8] {
+            &self.bytes[self.range(page, 4096)]
+        }
+
+        fn range(&self, offset: u32, len: usize) -> std::ops::Range<usize> {
+            let start = (offset - self.base) as usize;
+            start..start + len
+        }
+    }
+
+    impl embedded_storage::nor_flash::ErrorType for MockFlash {
+        type Error = core::convert::Infallible;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            bytes.copy_from_slice(&self.bytes[self.range(offset, bytes.len())]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 4096;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let range = self.range(from, (to - from) as usize);
+            self.bytes[range].fill(0xff);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let range = self.range(offset, bytes.len());
+            for (old, new) in self.bytes[range].iter_mut().zip(bytes) {
+                *old &= new;
+            }
+            Ok(())
+        }
+    }
+
+    fn keypress_store() -> KeypressStore {
+        KeypressStore::new(MockFlash::PAGE, 4096)
+    }
+
+    #[test]
+    fn keypress_records_check_out() {
+        for count in [0, 1_234_567, u32::MAX] {
+            assert_eq!(decode_keypresses(encode_keypresses(count)), Some(count));
+        }
+        assert_eq!(decode_keypresses([0xff; 8]), None);
+        // lost power before the check was written
+        let mut torn = encode_keypresses(42);
+        torn[4..].fill(0xff);
+        assert_eq!(decode_keypresses(torn), None);
+        assert!(!is_erased(&torn));
+    }
+
+    #[test]
+    fn keypress_count_loads_the_newest_save() {
+        let mut flash = MockFlash::new();
+        let mut store = keypress_store();
+        assert_eq!(store.load(&mut flash), 0);
+
+        for count in [1000, 2500, 2500, 4000] {
+            store.save(&mut flash, count).unwrap();
+        }
+        let mut store = keypress_store();
+        assert_eq!(store.load(&mut flash), 4000);
+        // the repeat didn't take a slot
+        assert_eq!(store.next, 3);
+        assert!(!store.due(4999));
+        assert!(store.due(5000));
+    }
+
+    #[test]
+    fn torn_keypress_record_falls_back_to_the_one_before() {
+        let mut flash = MockFlash::new();
+        let mut store = keypress_store();
+        store.save(&mut flash, 1000).unwrap();
+        flash
+            .write(MockFlash::PAGE + 8, &encode_keypresses(2000)[..4])
+            .unwrap();
+
+        let mut store = keypress_store();
+        assert_eq!(store.load(&mut flash), 1000);
+        // and the next save goes after it
+        store.save(&mut flash, 3000).unwrap();
+        assert_eq!(keypress_store().load(&mut flash), 3000);
+    }
+
+    #[test]
+    fn full_keypress_page_is_erased_before_the_next_save() {
+        let mut flash = MockFlash::new();
+        let mut store = keypress_store();
+        for count in 1..=store.records() as u32 {
+            assert_eq!(store.erase_needed(count), None);
+            store.save(&mut flash, count).unwrap();
+        }
+        assert_eq!(flash.erases, 0);
+
+        let next = store.records() as u32 + 1;
+        // nothing to write, so nothing to erase
+        assert_eq!(store.erase_needed(next - 1), None);
+        assert_eq!(store.save(&mut flash, next), Err(SaveFailed));
+        let (from, to) = store.erase_needed(next).unwrap();
+        assert_eq!((from, to), (MockFlash::PAGE, MockFlash::PAGE + 4096));
+
+        flash.erase(from, to).unwrap();
+        store.erased();
+        store.save(&mut flash, next).unwrap();
+        let mut store = keypress_store();
+        assert_eq!(store.load(&mut flash), next);
+        assert_eq!(store.next, 1);
+    }
+
+    fn journal() -> SettingsJournal {
+        SettingsJournal::new(MockFlash::SETTINGS_PAGES, 4096)
+    }
+
+    /// A blob as the firmware would write it, `fill` repeated then the
+    /// checksum
+    fn blob(fill: u8, len: usize) -> Vec<u8> {
+        let mut blob = vec![fill; len - 4];
+        blob.extend(settings_checksum(&blob).to_le_bytes());
+        blob
+    }
+
+    /// The blob of the newest save that checks out, if any
+    fn load_blob(flash: &mut MockFlash) -> Option<Vec<u8>> {
+        journal()
+            .load(flash, |b| Ok::<_, ()>(b.to_vec()))
+            .map(Result::unwrap)
+    }
+
+    /// Erase the page the next save goes to and save `blob` to it, as the
+    /// firmware does
+    fn save(
+        store: &mut SettingsJournal,
+        flash: &mut MockFlash,
+        blob: &[u8],
+    ) -> Result<(), SaveFailed> {
+        if let Some((from, to)) = store.erase_needed() {
+            flash.erase(from, to).unwrap();
+            store.erased();
+        }
+        store.save(flash, blob)
+    }
+
+    fn header(flash: &MockFlash, idx: usize) -> Option<PageHeader> {
+        let page = flash.page(MockFlash::SETTINGS_PAGES[idx]);
+        PageHeader::parse(page[..JOURNAL_HEADER_LEN].try_into().unwrap())
+    }
+
+    #[test]
+    fn page_header_round_trips() {
+        let header = PageHeader {
+            seq: 7,
+            erases: 1234,
+            len: 300,
+        };
+        assert_eq!(PageHeader::parse(&header.to_bytes()), Some(header));
+        assert_eq!(PageHeader::parse(&[0xff; JOURNAL_HEADER_LEN]), None);
+
+        let mut too_long = header;
+        too_long.len = SETTINGS_MAX_BLOB as u16 + 1;
+        assert_eq!(PageHeader::parse(&too_long.to_bytes()), None);
+    }
+
+    #[test]
+    fn newest_page_loads_first() {
+        let at = |seq| {
+            Some(PageHeader {
+                seq,
+                erases: 1,
+                len: 8,
+            })
+        };
+        let order = |headers| load_order(headers).collect::<Vec<_>>();
+
+        assert_eq!(order([at(4), at(5)]), [1, 0]);
+        assert_eq!(order([at(6), at(5)]), [0, 1]);
+        // the sequence number wrapped
+        assert_eq!(order([at(u32::MAX), at(0)]), [1, 0]);
+        assert_eq!(order([None, at(3)]), [1]);
+        assert_eq!(order([None, None]), []);
+    }
+
+    #[test]
+    fn nothing_saved_loads_nothing() {
+        assert_eq!(load_blob(&mut MockFlash::new()), None);
+    }
+
+    #[test]
+    fn saves_alternate_between_the_pages() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        for fill in 1..=3 {
+            save(&mut store, &mut flash, &blob(fill, 20)).unwrap();
+            assert_eq!(load_blob(&mut flash), Some(blob(fill, 20)));
+        }
+
+        assert_eq!(header(&flash, 0).unwrap().seq, 2);
+        assert_eq!(header(&flash, 1).unwrap().seq, 1);
+
+        // and a store that loaded carries on after the newest
+        let mut store = journal();
+        store
+            .load(&mut flash, |_| Ok::<_, ()>(()))
+            .unwrap()
+            .unwrap();
+        save(&mut store, &mut flash, &blob(4, 20)).unwrap();
+        assert_eq!(header(&flash, 1).unwrap().seq, 3);
+        assert_eq!(load_blob(&mut flash), Some(blob(4, 20)));
+    }
+
+    #[test]
+    fn only_the_page_being_written_is_erased() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        save(&mut store, &mut flash, &blob(1, 20)).unwrap();
+        let before = flash.page(MockFlash::SETTINGS_PAGES[0]).to_vec();
+
+        save(&mut store, &mut flash, &blob(2, 20)).unwrap();
+        assert_eq!(flash.erases, 2);
+        assert_eq!(flash.page(MockFlash::SETTINGS_PAGES[0]), before);
+    }
+
+    #[test]
+    fn torn_save_falls_back_to_the_one_before() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        save(&mut store, &mut flash, &blob(1, 20)).unwrap();
+        save(&mut store, &mut flash, &blob(2, 20)).unwrap();
+
+        // lost power after the header made it but before all of the body did
+        let page = MockFlash::SETTINGS_PAGES[1];
+        flash.erase(page, page + 4096).unwrap();
+        let mut torn = header(&flash, 0).unwrap();
+        torn.seq += 1;
+        flash.write(page, &torn.to_bytes()).unwrap();
+        flash
+            .write(page + JOURNAL_HEADER_LEN as u32, &blob(2, 20)[..8])
+            .unwrap();
+        assert!(header(&flash, 1).is_some());
+
+        let mut store = journal();
+        let loaded = store.load(&mut flash, |b| Ok::<_, ()>(b.to_vec()));
+        assert_eq!(loaded, Some(Ok(blob(1, 20))));
+
+        // the next save goes over the torn page, not the good one
+        save(&mut store, &mut flash, &blob(3, 20)).unwrap();
+        assert_eq!(header(&flash, 1).unwrap().seq, 1);
+        assert_eq!(load_blob(&mut flash), Some(blob(3, 20)));
+    }
+
+    #[test]
+    fn rejected_blob_falls_back_to_the_one_before() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        save(&mut store, &mut flash, &blob(1, 20)).unwrap();
+        save(&mut store, &mut flash, &blob(2, 20)).unwrap();
+
+        // the newest checks out but is too new to understand
+        let parse = |b: &[u8]| if b[0] == 2 { Err(b[0]) } else { Ok(b[0]) };
+        assert_eq!(journal().load(&mut flash, parse), Some(Ok(1)));
+
+        let parse = |b: &[u8]| Err::<(), _>(b[0]);
+        // the error of the last one tried
+        assert_eq!(journal().load(&mut flash, parse), Some(Err(1)));
+    }
+
+    #[test]
+    fn erase_count_follows_each_page() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        for fill in 1..=5 {
+            save(&mut store, &mut flash, &blob(fill, 20)).unwrap();
+        }
+        // the second page had no count of its own to start from so took the
+        // other's, the total errs high rather than low
+        assert_eq!(header(&flash, 1).unwrap().erases, 3);
+        assert_eq!(header(&flash, 0).unwrap().erases, 3);
+        assert_eq!(store.erases(), 6);
+
+        // a page that lost its header takes the other's count
+        let page = MockFlash::SETTINGS_PAGES[1];
+        flash.erase(page, page + 4096).unwrap();
+        let mut store = journal();
+        store.follow_newest(&mut flash);
+        assert_eq!(store.erases(), 3);
+        save(&mut store, &mut flash, &blob(6, 20)).unwrap();
+        assert_eq!(header(&flash, 1).unwrap().erases, 4);
+        assert_eq!(header(&flash, 1).unwrap().seq, 5);
+    }
+
+    #[test]
+    fn saves_wait_for_their_page_to_be_erased() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        assert_eq!(store.save(&mut flash, &blob(1, 20)), Err(SaveFailed));
+        assert_eq!(load_blob(&mut flash), None);
+
+        let page = MockFlash::SETTINGS_PAGES[0];
+        assert_eq!(store.erase_needed(), Some((page, page + 4096)));
+        flash.erase(page, page + 4096).unwrap();
+        store.erased();
+        assert_eq!(store.erase_needed(), None);
+        // the count goes up with the erase, not the write after it
+        assert_eq!(store.erases(), 1);
+
+        store.save(&mut flash, &blob(1, 20)).unwrap();
+        // each erase is good for one save
+        assert_eq!(store.save(&mut flash, &blob(2, 20)), Err(SaveFailed));
+        let page = MockFlash::SETTINGS_PAGES[1];
+        assert_eq!(store.erase_needed(), Some((page, page + 4096)));
+        assert_eq!(flash.erases, 1);
+        assert_eq!(load_blob(&mut flash), Some(blob(1, 20)));
+    }
+
+    #[test]
+    fn oversized_blob_is_not_saved() {
+        let mut flash = MockFlash::new();
+        let mut store = journal();
+        let blob = blob(1, SETTINGS_MAX_BLOB + 1);
+        store.erased();
+        assert_eq!(store.save(&mut flash, &blob), Err(SaveFailed));
+        // and the erase is still there for the next save
+        assert_eq!(store.erase_needed(), None);
+    }
+}
@@ -0,0 +1,548 @@
+//! Counters, pacing and rates over time.
+
+/// The last reading of a counter that only ever counts up, like the keypress
+/// totals, for finding how much it went up by between readings.
+///
+/// A counter can start again from zero when its half restarts. A reading
+/// below the last one counts as such a reset, so the reading is all new
+/// counts. That goes for wrapping past `u32::MAX` too, which only loses the
+/// counts from before the wrap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MonotonicCounter {
+    last: u32,
+}
+
+impl MonotonicCounter {
+    pub const fn new(start: u32) -> Self {
+        Self { last: start }
+    }
+
+    /// How much the counter went up by since the last reading, remembering
+    /// `current` for next time
+    pub fn delta_since(&mut self, current: u32) -> u32 {
+        let last = core::mem::replace(&mut self.last, current);
+        counter_delta(last, current)
+    }
+}
+
+const fn counter_delta(last: u32, current: u32) -> u32 {
+    if current < last {
+        current
+    } else {
+        current - last
+    }
+}
+
+/// The event of a scan that may skip the queue into the layout: there has to
+/// be exactly one, and nothing can be waiting on its key to see what follows.
+///
+/// That's only half of it, the caller also has to check nothing queued
+/// before it is still on its way in, see [`may_overtake`].
+pub fn lone_event<E: Copy>(events: &[E], waits_on: impl Fn(&E) -> bool) -> Option<E> {
+    match events {
+        [event] if !waits_on(event) => Some(*event),
+        _ => None,
+    }
+}
+
+/// Whether a lone event can go into the layout now, ahead of the queue. Not
+/// if the event task has taken an event off the queue that it's yet to
+/// apply, or if presses are being held back for an action chord.
+pub const fn may_overtake(in_flight: bool, held_back: bool) -> bool {
+    !in_flight && !held_back
+}
+
+/// Paces the layout's ticks, which also time its hold-taps.
+///
+/// Ticks are a period apart, but an event that skipped the queue wakes the
+/// tick early. An early tick takes the place of the next one and there can
+/// only be one taken at a time, so however many events come through a
+/// timeout of `n` ticks still takes at least `n - 1` periods, the same as
+/// when ticks only come on time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TickPacer {
+    period: u64,
+    next: u64,
+}
+
+impl TickPacer {
+    pub const fn new(now: u64, period: u64) -> Self {
+        Self {
+            period,
+            next: now + period,
+        }
+    }
+
+    /// When the next tick is due
+    pub const fn due(&self) -> u64 {
+        self.next
+    }
+
+    /// Whether a tick at `now` can come before it's due
+    pub const fn early_ok(&self, now: u64) -> bool {
+        self.next <= now + self.period
+    }
+
+    /// Record a tick at `now`, early or not. A late tick isn't caught up on.
+    pub fn ticked(&mut self, now: u64) {
+        self.next = self.next.max(now) + self.period;
+    }
+}
+
+/// The last `N` samples of how many events happened in each, every sample
+/// covering the same length of time, for event rates over a sliding window
+pub struct RateWindow<const N: usize> {
+    samples: heapless::HistoryBuffer<u8, N>,
+    sample_us: u32,
+}
+
+impl<const N: usize> RateWindow<N> {
+    pub const fn new(sample_us: u32) -> Self {
+        Self {
+            samples: heapless::HistoryBuffer::new(),
+            sample_us,
+        }
+    }
+
+    /// Record the events counted over the latest sample, dropping the oldest
+    /// once the window is full
+    pub fn push(&mut self, count: u8) {
+        self.samples.write(count);
+    }
+
+    /// Events per second over the samples taken so far, which only covers
+    /// the whole window once it's full
+    pub fn per_second(&self) -> f32 {
+        let elapsed_us = self.samples.len() as u64 * self.sample_us as u64;
+        if elapsed_us == 0 {
+            return 0.0;
+        }
+
+        let total: u32 = self.samples.iter().map(|s| *s as u32).sum();
+        (total as u64 * 1_000_000) as f32 / elapsed_us as f32
+    }
+
+    /// Most events counted in a single sample in the window
+    pub fn peak(&self) -> u8 {
+        self.samples.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn recent(&self) -> Option<u8> {
+        self.samples.recent().copied()
+    }
+
+    /// The samples from oldest to newest
+    pub fn oldest_ordered(&self) -> impl Iterator<Item = &u8> {
+        self.samples.oldest_ordered()
+    }
+}
+
+/// Counts are tracked in thousandths while being spread out
+const MILLI: u32 = 1000;
+
+/// Spreads counts that arrive in batches, like key presses synced from the
+/// other half, over the samples they were made across, so a batch landing
+/// just before a sample doesn't show up as a spike followed by a gap.
+///
+/// Each batch covers `batch_us`, so it drains at `sample_us / batch_us` of it
+/// per sample, the rate it was made at. Anything still pending from earlier
+/// batches is caught up over the next two.
+pub struct BatchSpreader {
+    /// Batched counts already added to the total but not yet sampled
+    arrived: u32,
+    pending: u32,
+    per_tick: u32,
+    carry: u32,
+    sample_us: u32,
+    batch_us: u32,
+}
+
+impl BatchSpreader {
+    pub const fn new(sample_us: u32, batch_us: u32) -> Self {
+        Self {
+            arrived: 0,
+            pending: 0,
+            per_tick: 0,
+            carry: 0,
+            sample_us,
+            batch_us,
+        }
+    }
+
+    pub fn add(&mut self, count: u32) {
+        self.arrived += count;
+        self.pending += count * MILLI;
+        let share = |count: u32, over: u32| {
+            (count as u64 * self.sample_us as u64 / (over as u64 * self.batch_us as u64)) as u32
+        };
+        self.per_tick = share(count * MILLI, 1).max(share(self.pending, 2)).max(1);
+    }
+
+    /// Leave out a `count` that was added to the total but wasn't made just
+    /// now
+    pub fn skip(&mut self, count: u32) {
+        self.arrived = self.arrived.saturating_add(count);
+    }
+
+    /// Turn `raw`, the change in the total since the last sample, into the
+    /// sample with any batches swapped for their share
+    pub fn tick(&mut self, raw: u32) -> u32 {
+        let local = raw.saturating_sub(core::mem::take(&mut self.arrived));
+
+        let take = self.pending.min(self.per_tick);
+        self.pending -= take;
+        self.carry += take;
+        let spread = self.carry / MILLI;
+        self.carry %= MILLI;
+
+        local + spread
+    }
+}
+
+/// The samples of a `RateWindow` added up `every` at a time into a window of
+/// their own, for the same rate over a longer time
+pub struct RateHistory<const N: usize> {
+    window: RateWindow<N>,
+    pending: u32,
+    ticks: u32,
+    every: u32,
+}
+
+impl<const N: usize> RateHistory<N> {
+    pub const fn new(sample_us: u32, every: u32) -> Self {
+        Self {
+            window: RateWindow::new(sample_us * every),
+            pending: 0,
+            ticks: 0,
+            every,
+        }
+    }
+
+    /// Count the events of a sample, returning whether that finished a sample
+    /// of the history
+    pub fn tick(&mut self, count: u32) -> bool {
+        self.pending = self.pending.saturating_add(count);
+        self.ticks += 1;
+        if self.ticks < self.every {
+            return false;
+        }
+
+        self.window.push(self.pending.min(u8::MAX as u32) as u8);
+        self.pending = 0;
+        self.ticks = 0;
+        true
+    }
+
+    pub fn window(&self) -> &RateWindow<N> {
+        &self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_counts_progress() {
+        let mut counter = MonotonicCounter::new(10);
+        assert_eq!(counter.delta_since(15), 5);
+        assert_eq!(counter.delta_since(15), 0);
+        assert_eq!(counter.delta_since(1015), 1000);
+    }
+
+    #[test]
+    fn counter_reset_is_all_new_counts() {
+        let mut counter = MonotonicCounter::new(1000);
+        assert_eq!(counter.delta_since(0), 0);
+        assert_eq!(counter.delta_since(4), 4);
+
+        let mut counter = MonotonicCounter::new(1000);
+        assert_eq!(counter.delta_since(999), 999);
+    }
+
+    #[test]
+    fn counter_wraps_at_max() {
+        let mut counter = MonotonicCounter::new(u32::MAX - 5);
+        assert_eq!(counter.delta_since(u32::MAX), 5);
+        assert_eq!(counter.delta_since(3), 3);
+        assert_eq!(counter.delta_since(10), 7);
+    }
+
+    #[test]
+    fn empty_rate_window_is_idle() {
+        let window = RateWindow::<4>::new(250_000);
+        assert_eq!(window.per_second(), 0.0);
+        assert_eq!(window.peak(), 0);
+        assert_eq!(window.recent(), None);
+    }
+
+    #[test]
+    fn partial_rate_window_covers_only_its_samples() {
+        let mut window = RateWindow::<4>::new(250_000);
+        window.push(3);
+        // 3 in a quarter of a second, not 3 spread over the whole second
+        assert_eq!(window.per_second(), 12.0);
+        window.push(1);
+        assert_eq!(window.per_second(), 8.0);
+        assert_eq!(window.peak(), 3);
+    }
+
+    #[test]
+    fn rate_window_drops_the_oldest_samples() {
+        let mut window = RateWindow::<4>::new(250_000);
+        for count in [9, 1, 1, 1, 1] {
+            window.push(count);
+        }
+        assert_eq!(window.per_second(), 4.0);
+        assert_eq!(window.peak(), 1);
+        assert_eq!(window.oldest_ordered().copied().collect::<Vec<_>>(), [1; 4]);
+        window.push(2);
+        assert_eq!(window.recent(), Some(2));
+        assert_eq!(
+            window.oldest_ordered().copied().collect::<Vec<_>>(),
+            [1, 1, 1, 2]
+        );
+    }
+
+    /// Plays the poll task's fast path against the event task, stepping
+    /// whichever a seeded generator picks. Returns the events in the order
+    /// the layout got them, and how many took the fast path.
+    fn race_fast_path(seed: u64, mind_in_flight: bool) -> (Vec<u32>, usize) {
+        const EVENTS: u32 = 200;
+        const QUEUE: usize = 4;
+
+        let mut rng = seed;
+        let mut next = move |n: u64| {
+            rng = rng
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (rng >> 33) % n
+        };
+
+        let mut queue = std::collections::VecDeque::new();
+        let mut unsent = std::collections::VecDeque::new();
+        let mut in_flight = None;
+        let mut layout = Vec::new();
+        let mut produced = 0;
+        let mut fast = 0;
+
+        while produced < EVENTS || !unsent.is_empty() || !queue.is_empty() || in_flight.is_some() {
+            if next(2) == 0 {
+                // the poll task, blocked on a full queue until there's room
+                if !unsent.is_empty() {
+                    if queue.len() < QUEUE {
+                        queue.push_back(unsent.pop_front().unwrap());
+                    }
+                    continue;
+                }
+                if produced == EVENTS {
+                    continue;
+                }
+                let count = (next(3) as u32).min(EVENTS - produced);
+                let events = (produced..produced + count).collect::<Vec<_>>();
+                produced += count;
+
+                let held_back = next(8) == 0;
+                let overtake = may_overtake(mind_in_flight && in_flight.is_some(), held_back);
+                match lone_event(&events, |e| e % 5 == 0) {
+                    Some(event) if overtake => {
+                        layout.extend(queue.drain(..));
+                        layout.push(event);
+                        fast += 1;
+                    }
+                    _ => unsent.extend(events),
+                }
+            } else {
+                // the event task, which takes an event off the queue and
+                // then waits for the layout
+                match in_flight.take() {
+                    Some(event) => {
+                        layout.push(event);
+                        layout.extend(queue.drain(..));
+                    }
+                    None => in_flight = queue.pop_front(),
+                }
+            }
+        }
+
+        (layout, fast)
+    }
+
+    #[test]
+    fn fast_path_applies_each_event_once_in_order() {
+        let mut fast = 0;
+        for seed in 0..500 {
+            let (layout, taken) = race_fast_path(seed, true);
+            assert_eq!(layout, (0..200).collect::<Vec<_>>(), "seed {seed}");
+            fast += taken;
+        }
+        // both paths are actually exercised
+        assert!(fast > 500 * 20, "only {fast} took the fast path");
+    }
+
+    #[test]
+    fn fast_path_would_overtake_an_event_in_flight() {
+        // the race above would find it if the fast path didn't look
+        assert!((0..500).any(|seed| {
+            let (layout, _) = race_fast_path(seed, false);
+            layout != (0..200).collect::<Vec<_>>()
+        }));
+    }
+
+    #[test]
+    fn fast_path_only_takes_lone_events_nothing_waits_on() {
+        let waits_on = |e: &u8| *e == 9;
+        assert_eq!(lone_event(&[1], waits_on), Some(1));
+        assert_eq!(lone_event(&[9], waits_on), None);
+        assert_eq!(lone_event(&[1, 2], waits_on), None);
+        assert_eq!(lone_event::<u8>(&[], waits_on), None);
+        assert!(may_overtake(false, false));
+        assert!(!may_overtake(true, false));
+        assert!(!may_overtake(false, true));
+    }
+
+    #[test]
+    fn early_ticks_dont_shorten_timeouts() {
+        // a fast path event every 50us, each wanting a tick straight away
+        let mut pacer = TickPacer::new(0, 1000);
+        let mut now = 0;
+        let mut ticks = 0;
+        let mut early = 0;
+        while ticks < 200 {
+            let event = (now / 50 + 1) * 50;
+            if event < pacer.due() && pacer.early_ok(event) {
+                now = event;
+                early += 1;
+            } else {
+                now = now.max(pacer.due());
+            }
+            pacer.ticked(now);
+            ticks += 1;
+        }
+        assert!(early > 0);
+        assert!(now >= 199 * 1000, "200 ticks took {now}us");
+    }
+
+    #[test]
+    fn early_tick_comes_straight_away() {
+        let mut pacer = TickPacer::new(0, 1000);
+        pacer.ticked(1000);
+        // an event just after a tick doesn't wait out the period
+        assert!(pacer.early_ok(1010));
+        pacer.ticked(1010);
+        assert_eq!(pacer.due(), 3000);
+        // the next one has to, as the early tick took its place
+        assert!(!pacer.early_ok(1020));
+        assert!(pacer.early_ok(2000));
+    }
+
+    #[test]
+    fn late_ticks_arent_caught_up() {
+        let mut pacer = TickPacer::new(0, 1000);
+        pacer.ticked(5000);
+        assert_eq!(pacer.due(), 6000);
+    }
+
+    const SAMPLE_US: u32 = 93_750;
+    const SYNC_US: u32 = 100_000;
+
+    /// `seconds` of presses made every `press_us` and synced in batches
+    /// every `SYNC_US`, returning each sample, spread or as it arrived
+    fn sample_batches(press_us: u64, seconds: u64, spread: bool) -> Vec<u32> {
+        let mut spreader = BatchSpreader::new(SAMPLE_US, SYNC_US);
+        let mut counter = MonotonicCounter::new(0);
+        let (mut made, mut synced, mut total) = (0, 0, 0);
+        let mut samples = Vec::new();
+
+        for now in 1..=seconds * 1_000_000 {
+            if now % press_us == 0 {
+                made += 1;
+            }
+            if now % SYNC_US as u64 == 0 {
+                total += made - synced;
+                if spread {
+                    spreader.add(made - synced);
+                }
+                synced = made;
+            }
+            if now % SAMPLE_US as u64 == 0 {
+                let raw = counter.delta_since(total);
+                samples.push(if spread { spreader.tick(raw) } else { raw });
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn batches_are_spread_over_their_samples() {
+        // 40 a second, so 4 a batch and 3.75 a sample
+        let raw = sample_batches(25_000, 10, false);
+        assert!(raw.contains(&0) && raw.contains(&4), "{raw:?}");
+
+        let spread = sample_batches(25_000, 10, true);
+        assert!(
+            spread[1..].iter().all(|s| (3..=4).contains(s)),
+            "{spread:?}"
+        );
+    }
+
+    #[test]
+    fn spread_batches_give_a_steady_rate() {
+        // 5 a second, with a window of 3s
+        let mut window = RateWindow::<32>::new(SAMPLE_US);
+        for (i, sample) in sample_batches(200_000, 20, true).into_iter().enumerate() {
+            window.push(sample as u8);
+            if i >= 32 {
+                let rate = window.per_second();
+                assert!((4.0..=6.0).contains(&rate), "sample {i}: {rate}");
+            }
+        }
+    }
+
+    #[test]
+    fn spreading_loses_nothing() {
+        let made = 20 * 1_000_000 / 25_000;
+        let spread: u32 = sample_batches(25_000, 20, true).iter().sum();
+        // what's left is the last batch, synced after the last sample, and
+        // the share of the one before it still pending
+        assert!(made - spread <= 2 * 4, "{spread} of {made}");
+    }
+
+    #[test]
+    fn counts_outside_batches_pass_straight_through() {
+        let mut spreader = BatchSpreader::new(SAMPLE_US, SYNC_US);
+        assert_eq!(spreader.tick(4), 4);
+
+        spreader.add(2);
+        // the batch's 2 are swapped for their share, the other 4 are kept
+        assert_eq!(spreader.tick(6), 4 + 1);
+    }
+
+    #[test]
+    fn skipped_counts_never_show() {
+        let mut spreader = BatchSpreader::new(SAMPLE_US, SYNC_US);
+        spreader.skip(1000);
+        assert_eq!(spreader.tick(1001), 1);
+        assert_eq!(spreader.tick(0), 0);
+    }
+
+    #[test]
+    fn history_adds_up_its_samples() {
+        let mut history = RateHistory::<4>::new(SAMPLE_US, 3);
+        let finished = [1, 2, 3, 100, 100, 100].map(|count| history.tick(count));
+        assert_eq!(finished, [false, false, true, false, false, true]);
+        assert_eq!(
+            history
+                .window()
+                .oldest_ordered()
+                .copied()
+                .collect::<Vec<_>>(),
+            [6, u8::MAX]
+        );
+        // each history sample covers three of the samples fed to it
+        assert_eq!(
+            history.window().per_second(),
+            (6.0 + 255.0) / (2.0 * 3.0 * 0.09375)
+        );
+    }
+}
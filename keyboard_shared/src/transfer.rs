@@ -0,0 +1,341 @@
+//! Moving the settings blob to and from the host a chunk at a time.
+//!
+//! Neither side looks inside the blob beyond its checksum, decoding it is up
+//! to the firmware.
+
+use crate::{
+    protocol::{KeyboardToHost, SettingsImportStatus},
+    storage::{settings_checksum, SETTINGS_CHUNK, SETTINGS_MAX_BLOB},
+};
+
+pub type SettingsBlob = heapless::Vec<u8, SETTINGS_MAX_BLOB>;
+
+/// Reassembles an imported blob from `ImportSettings` chunks, which have to
+/// arrive in order starting from offset 0.
+pub struct SettingsImporter {
+    buf: SettingsBlob,
+    total: usize,
+}
+
+impl SettingsImporter {
+    pub const fn new() -> Self {
+        Self {
+            buf: SettingsBlob::new(),
+            total: 0,
+        }
+    }
+
+    /// Add the chunk at `offset` of a blob `total` long, returning the whole
+    /// blob once the last chunk has arrived and its checksum checks out. A
+    /// chunk at offset 0 starts over, anything that doesn't follow on from
+    /// the chunk before is refused and drops what's been gathered.
+    pub fn chunk(
+        &mut self,
+        offset: u16,
+        total: u16,
+        data: &[u8],
+    ) -> Result<Option<SettingsBlob>, SettingsImportStatus> {
+        let (offset, total) = (offset as usize, total as usize);
+
+        if offset == 0 {
+            self.buf.clear();
+            self.total = total;
+        }
+
+        if total > SETTINGS_MAX_BLOB {
+            return Err(SettingsImportStatus::TooLarge);
+        }
+
+        if offset != self.buf.len() || total != self.total || offset + data.len() > total {
+            self.buf.clear();
+            self.total = 0;
+            return Err(SettingsImportStatus::BadOffset);
+        }
+
+        // fits, as it's no longer than `total`
+        let _ = self.buf.extend_from_slice(data);
+
+        if self.buf.len() < total {
+            return Ok(None);
+        }
+
+        let blob = core::mem::take(&mut self.buf);
+        self.total = 0;
+
+        if blob.len() < 4 {
+            return Err(SettingsImportStatus::Invalid);
+        }
+        let (body, csum) = blob.split_at(blob.len() - 4);
+        if settings_checksum(body).to_le_bytes() != csum {
+            return Err(SettingsImportStatus::BadChecksum);
+        }
+
+        Ok(Some(blob))
+    }
+}
+
+impl Default for SettingsImporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Streams a blob to the host as `SettingsChunk`s. The blob is taken when an
+/// export starts, so settings changing partway through can't tear it.
+pub struct SettingsExporter {
+    blob: SettingsBlob,
+}
+
+impl SettingsExporter {
+    pub const fn new() -> Self {
+        Self {
+            blob: SettingsBlob::new(),
+        }
+    }
+
+    /// The `SettingsChunk` reply for an `ExportSettings` at `offset`. An
+    /// export starts at offset 0, which is when `snapshot` is taken.
+    pub fn chunk(
+        &mut self,
+        offset: u16,
+        snapshot: impl FnOnce() -> SettingsBlob,
+    ) -> KeyboardToHost {
+        if offset == 0 {
+            self.blob = snapshot();
+        }
+
+        let start = (offset as usize).min(self.blob.len());
+        let end = (start + SETTINGS_CHUNK).min(self.blob.len());
+
+        let mut data = [0u8; SETTINGS_CHUNK];
+        data[..end - start].copy_from_slice(&self.blob[start..end]);
+
+        KeyboardToHost::SettingsChunk {
+            offset: start as u16,
+            total: self.blob.len() as u16,
+            len: (end - start) as u8,
+            data,
+        }
+    }
+}
+
+impl Default for SettingsExporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A blob as the firmware would write it, counting up then the checksum
+    fn blob(len: usize) -> SettingsBlob {
+        let mut blob: SettingsBlob = (0..len - 4).map(|b| b as u8).collect();
+        blob.extend(settings_checksum(&blob).to_le_bytes());
+        blob
+    }
+
+    /// Send `blob` in order, returning the status after each chunk
+    fn send(
+        importer: &mut SettingsImporter,
+        blob: &[u8],
+    ) -> Vec<Result<bool, SettingsImportStatus>> {
+        blob.chunks(SETTINGS_CHUNK)
+            .enumerate()
+            .map(|(idx, chunk)| {
+                let offset = (idx * SETTINGS_CHUNK) as u16;
+                importer
+                    .chunk(offset, blob.len() as u16, chunk)
+                    .map(|done| done.is_some())
+            })
+            .collect()
+    }
+
+    /// The whole of an export, one chunk at a time
+    fn receive(exporter: &mut SettingsExporter, current: &SettingsBlob) -> Vec<u8> {
+        let mut received = Vec::new();
+        loop {
+            let KeyboardToHost::SettingsChunk {
+                offset,
+                total,
+                len,
+                data,
+            } = exporter.chunk(received.len() as u16, || current.clone())
+            else {
+                panic!("not a settings chunk");
+            };
+            assert_eq!(offset as usize, received.len());
+            received.extend_from_slice(&data[..len as usize]);
+            if received.len() >= total as usize {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_in_order_give_the_blob() {
+        let blob = blob(100);
+        let mut importer = SettingsImporter::new();
+
+        let statuses = send(&mut importer, &blob);
+        assert_eq!(statuses, [Ok(false), Ok(false), Ok(false), Ok(true)]);
+
+        // and the last one hands it over
+        let mut importer = SettingsImporter::new();
+        assert_eq!(importer.chunk(0, 100, &blob[..96]), Ok(None));
+        assert_eq!(importer.chunk(96, 100, &blob[96..]), Ok(Some(blob)));
+    }
+
+    #[test]
+    fn out_of_order_chunk_is_refused() {
+        let blob = blob(100);
+        let mut importer = SettingsImporter::new();
+        assert_eq!(importer.chunk(0, 100, &blob[..32]), Ok(None));
+        assert_eq!(
+            importer.chunk(64, 100, &blob[64..96]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+        // what was gathered is gone, so the missing chunk doesn't help
+        assert_eq!(
+            importer.chunk(32, 100, &blob[32..64]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+
+        // a chunk before the first one is no better
+        let mut importer = SettingsImporter::new();
+        assert_eq!(
+            importer.chunk(32, 100, &blob[32..64]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+
+        // but starting over works
+        assert_eq!(send(&mut importer, &blob).last(), Some(&Ok(true)));
+    }
+
+    #[test]
+    fn duplicate_chunk_is_refused() {
+        let blob = blob(100);
+        let mut importer = SettingsImporter::new();
+        assert_eq!(importer.chunk(0, 100, &blob[..32]), Ok(None));
+        assert_eq!(importer.chunk(32, 100, &blob[32..64]), Ok(None));
+        assert_eq!(
+            importer.chunk(32, 100, &blob[32..64]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+        assert_eq!(
+            importer.chunk(64, 100, &blob[64..96]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+
+        // a repeat of the first chunk starts over rather than failing
+        assert_eq!(importer.chunk(0, 100, &blob[..32]), Ok(None));
+        assert_eq!(importer.chunk(0, 100, &blob[..32]), Ok(None));
+        assert_eq!(importer.chunk(32, 100, &blob[32..64]), Ok(None));
+    }
+
+    #[test]
+    fn overlong_blob_or_chunk_is_refused() {
+        let mut importer = SettingsImporter::new();
+        let total = SETTINGS_MAX_BLOB as u16 + 1;
+        assert_eq!(
+            importer.chunk(0, total, &[0; SETTINGS_CHUNK]),
+            Err(SettingsImportStatus::TooLarge)
+        );
+
+        // a chunk running past the end of the blob
+        let blob = blob(40);
+        assert_eq!(importer.chunk(0, 40, &blob[..32]), Ok(None));
+        assert_eq!(
+            importer.chunk(32, 40, &[0; SETTINGS_CHUNK]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+
+        // or a total that changes partway through
+        assert_eq!(importer.chunk(0, 40, &blob[..32]), Ok(None));
+        assert_eq!(
+            importer.chunk(32, 48, &blob[32..]),
+            Err(SettingsImportStatus::BadOffset)
+        );
+    }
+
+    #[test]
+    fn truncated_blob_is_refused() {
+        let blob = blob(100);
+        let mut importer = SettingsImporter::new();
+
+        // the host gave up partway through and started another
+        assert_eq!(importer.chunk(0, 100, &blob[..32]), Ok(None));
+        assert_eq!(send(&mut importer, &blob).last(), Some(&Ok(true)));
+
+        // a blob cut short, with a total to match, fails its checksum
+        let cut = &blob[..70];
+        assert_eq!(
+            send(&mut importer, cut).last(),
+            Some(&Err(SettingsImportStatus::BadChecksum))
+        );
+        // too short to have one
+        assert_eq!(
+            importer.chunk(0, 3, &blob[..3]),
+            Err(SettingsImportStatus::Invalid)
+        );
+
+        // nothing is left over from either
+        assert_eq!(send(&mut importer, &blob).last(), Some(&Ok(true)));
+    }
+
+    #[test]
+    fn export_streams_the_blob() {
+        let blob = blob(100);
+        let mut exporter = SettingsExporter::new();
+        assert_eq!(receive(&mut exporter, &blob), blob.as_slice());
+
+        // past the end there's nothing more
+        let KeyboardToHost::SettingsChunk { offset, len, .. } =
+            exporter.chunk(200, || blob.clone())
+        else {
+            panic!("not a settings chunk");
+        };
+        assert_eq!((offset, len), (100, 0));
+    }
+
+    #[test]
+    fn export_is_a_snapshot_from_its_start() {
+        let before = blob(100);
+        let after = blob(60);
+        let mut exporter = SettingsExporter::new();
+
+        // the settings change after the first chunk was sent
+        let first = exporter.chunk(0, || before.clone());
+        let mut received = Vec::new();
+        let mut next = first;
+        loop {
+            let KeyboardToHost::SettingsChunk {
+                total, len, data, ..
+            } = next
+            else {
+                panic!("not a settings chunk");
+            };
+            assert_eq!(total, 100);
+            received.extend_from_slice(&data[..len as usize]);
+            if received.len() >= total as usize {
+                break;
+            }
+            next = exporter.chunk(received.len() as u16, || after.clone());
+        }
+        assert_eq!(received, before.as_slice());
+
+        // and the next export picks the change up
+        assert_eq!(receive(&mut exporter, &after), after.as_slice());
+    }
+
+    #[test]
+    fn exported_blob_imports() {
+        let blob = blob(SETTINGS_MAX_BLOB);
+        let received = receive(&mut SettingsExporter::new(), &blob);
+        assert_eq!(
+            send(&mut SettingsImporter::new(), &received).last(),
+            Some(&Ok(true))
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Timings and hold-tap behaviour the host can change on the fly.
+
+use serde::{Deserialize, Serialize};
+
+/// Timings that can be changed on the fly to try them out, and saved with the
+/// settings once they feel right
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct Tuning {
+    /// Every member of a strict chord must be pressed within this long of
+    /// the first, unless the chord has a window of its own
+    pub chord_window_ms: u16,
+    /// How long every hold-tap waits before it resolves as a hold
+    pub hold_tap_timeout_ms: u16,
+}
+
+impl Tuning {
+    pub const DEFAULT: Self = Self {
+        chord_window_ms: 8,
+        hold_tap_timeout_ms: 200,
+    };
+    /// Longest any timing can be set to
+    pub const MAX_MS: u16 = 2000;
+
+    /// Whether every timing is in a usable range
+    pub fn valid(&self) -> bool {
+        [self.chord_window_ms, self.hold_tap_timeout_ms]
+            .iter()
+            .all(|ms| (1..=Self::MAX_MS).contains(ms))
+    }
+}
+
+/// What makes a hold-tap resolve as a hold before its timeout
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub enum HoldTapMode {
+    /// Nothing, only the timeout
+    Default,
+    /// Another key being pressed
+    HoldOnOtherKeyPress,
+    /// Another key being pressed and released
+    PermissiveHold,
+}
+
+/// How a hold-tap of the layout behaves, apart from its timeout
+#[derive(Serialize, Deserialize, Eq, PartialEq, defmt::Format, Hash, Clone, Copy, Debug)]
+pub struct HoldTapBehaviour {
+    pub mode: HoldTapMode,
+    /// Pressing the key again within this long of a tap holds the tap down,
+    /// so it can auto-repeat. 0 turns this off.
+    pub tap_hold_interval_ms: u16,
+}
+
+impl HoldTapBehaviour {
+    /// Whether the interval is in a usable range
+    pub fn valid(&self) -> bool {
+        self.tap_hold_interval_ms <= Tuning::MAX_MS
+    }
+}
+
+/// Where a key of the keymap is, as its layer, row and column
+pub type KeymapPos = (u8, u8, u8);
+
+/// Which hold-tap of the keymap is at `pos`, counting through layers, rows
+/// then columns. `None` if there isn't one there.
+pub fn hold_tap_index<A, const C: usize, const R: usize, const L: usize>(
+    layers: &[[[A; C]; R]; L],
+    is_hold_tap: impl Fn(&A) -> bool,
+    pos: KeymapPos,
+) -> Option<usize> {
+    let (layer, row, col) = pos;
+    let at = layers
+        .get(layer as usize)?
+        .get(row as usize)?
+        .get(col as usize)?;
+    if !is_hold_tap(at) {
+        return None;
+    }
+
+    let keys = layers.iter().flatten().flatten();
+    let before = (layer as usize * R + row as usize) * C + col as usize;
+    Some(keys.take(before).filter(|a| is_hold_tap(a)).count())
+}
+
+/// Hold-taps whose behaviour the host has changed from the keymap's
+pub struct HoldTapOverrides<const N: usize> {
+    overrides: heapless::Vec<(KeymapPos, HoldTapBehaviour), N>,
+}
+
+impl<const N: usize> HoldTapOverrides<N> {
+    pub const fn new() -> Self {
+        Self {
+            overrides: heapless::Vec::new(),
+        }
+    }
+
+    pub fn get(&self, pos: KeymapPos) -> Option<HoldTapBehaviour> {
+        self.overrides
+            .iter()
+            .find(|(p, _)| *p == pos)
+            .map(|(_, b)| *b)
+    }
+
+    /// How the hold-tap at `pos` behaves when the keymap gives it `keymap`,
+    /// and whether the host has changed that
+    pub fn behaviour(&self, pos: KeymapPos, keymap: HoldTapBehaviour) -> (HoldTapBehaviour, bool) {
+        match self.get(pos) {
+            Some(behaviour) => (behaviour, true),
+            None => (keymap, false),
+        }
+    }
+
+    /// Hand each hold-tap of `layers` to `retune` with a slot to put its
+    /// retuned copy in, and the behaviour the host has changed it to if any.
+    /// They're handed out in [`hold_tap_index`] order, so any past the last
+    /// slot keep what the keymap gave them.
+    pub fn retune<A, S, const C: usize, const R: usize, const L: usize>(
+        &self,
+        layers: &mut [[[A; C]; R]; L],
+        slots: impl IntoIterator<Item = S>,
+        is_hold_tap: impl Fn(&A) -> bool,
+        mut retune: impl FnMut(&mut A, S, Option<HoldTapBehaviour>),
+    ) {
+        let mut slots = slots.into_iter();
+        for (layer, rows) in layers.iter_mut().enumerate() {
+            for (row, actions) in rows.iter_mut().enumerate() {
+                for (col, action) in actions.iter_mut().enumerate() {
+                    if !is_hold_tap(action) {
+                        continue;
+                    }
+                    let Some(slot) = slots.next() else {
+                        return;
+                    };
+
+                    let behaviour = self.get((layer as u8, row as u8, col as u8));
+                    retune(action, slot, behaviour);
+                }
+            }
+        }
+    }
+
+    /// Change the behaviour at `pos`, or go back to the keymap's with `None`.
+    /// False if there's no room for another.
+    pub fn set(&mut self, pos: KeymapPos, behaviour: Option<HoldTapBehaviour>) -> bool {
+        let existing = self.overrides.iter().position(|(p, _)| *p == pos);
+
+        match (existing, behaviour) {
+            (Some(idx), Some(behaviour)) => {
+                self.overrides[idx].1 = behaviour;
+                true
+            }
+            (Some(idx), None) => {
+                self.overrides.swap_remove(idx);
+                true
+            }
+            (None, Some(behaviour)) => self.overrides.push((pos, behaviour)).is_ok(),
+            (None, None) => true,
+        }
+    }
+}
+
+impl<const N: usize> Default for HoldTapOverrides<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two layers of a 2x3 keymap, with hold-taps as 'h'
+    const HOLD_TAP_KEYMAP: [[[char; 3]; 2]; 2] = [
+        [['a', 'h', 'b'], ['h', 'c', 'h']],
+        [['h', 'd', 'e'], ['f', 'g', 'h']],
+    ];
+
+    fn hold_tap_at(pos: KeymapPos) -> Option<usize> {
+        hold_tap_index(&HOLD_TAP_KEYMAP, |a| *a == 'h', pos)
+    }
+
+    #[test]
+    fn hold_taps_are_counted_in_keymap_order() {
+        assert_eq!(hold_tap_at((0, 0, 1)), Some(0));
+        assert_eq!(hold_tap_at((0, 1, 0)), Some(1));
+        assert_eq!(hold_tap_at((0, 1, 2)), Some(2));
+        assert_eq!(hold_tap_at((1, 0, 0)), Some(3));
+        assert_eq!(hold_tap_at((1, 1, 2)), Some(4));
+    }
+
+    #[test]
+    fn only_hold_taps_have_an_index() {
+        assert_eq!(hold_tap_at((0, 0, 0)), None);
+        assert_eq!(hold_tap_at((1, 1, 1)), None);
+        assert_eq!(hold_tap_at((0, 0, 3)), None);
+        assert_eq!(hold_tap_at((0, 2, 0)), None);
+        assert_eq!(hold_tap_at((2, 0, 0)), None);
+    }
+
+    const PERMISSIVE: HoldTapBehaviour = HoldTapBehaviour {
+        mode: HoldTapMode::PermissiveHold,
+        tap_hold_interval_ms: 0,
+    };
+
+    const REPEATING: HoldTapBehaviour = HoldTapBehaviour {
+        mode: HoldTapMode::Default,
+        tap_hold_interval_ms: 150,
+    };
+
+    #[test]
+    fn hold_tap_overrides_change_and_reset() {
+        let mut overrides = HoldTapOverrides::<2>::new();
+        assert!(overrides.set((0, 1, 0), Some(PERMISSIVE)));
+        assert_eq!(overrides.get((0, 1, 0)), Some(PERMISSIVE));
+        assert_eq!(overrides.get((0, 0, 1)), None);
+
+        assert!(overrides.set((0, 1, 0), Some(REPEATING)));
+        assert_eq!(overrides.get((0, 1, 0)), Some(REPEATING));
+
+        assert!(overrides.set((0, 1, 0), None));
+        assert_eq!(overrides.get((0, 1, 0)), None);
+        assert!(overrides.set((0, 1, 0), None));
+    }
+
+    #[test]
+    fn hold_tap_overrides_are_refused_when_full() {
+        let mut overrides = HoldTapOverrides::<2>::new();
+        assert!(overrides.set((0, 0, 1), Some(PERMISSIVE)));
+        assert!(overrides.set((0, 1, 0), Some(PERMISSIVE)));
+        assert!(!overrides.set((0, 1, 2), Some(PERMISSIVE)));
+        assert_eq!(overrides.get((0, 1, 2)), None);
+
+        // changing one already there still works, and resetting makes room
+        assert!(overrides.set((0, 0, 1), Some(REPEATING)));
+        assert!(overrides.set((0, 0, 1), None));
+        assert!(overrides.set((0, 1, 2), Some(PERMISSIVE)));
+    }
+
+    /// A key of a keymap, hold-taps carrying what they were retuned with
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Key {
+        Plain,
+        HoldTap {
+            slot: Option<usize>,
+            behaviour: Option<HoldTapBehaviour>,
+        },
+    }
+
+    const HT: Key = Key::HoldTap {
+        slot: None,
+        behaviour: None,
+    };
+
+    /// `HOLD_TAP_KEYMAP` as `Key`s
+    fn keymap() -> [[[Key; 3]; 2]; 2] {
+        HOLD_TAP_KEYMAP
+            .map(|rows| rows.map(|keys| keys.map(|k| if k == 'h' { HT } else { Key::Plain })))
+    }
+
+    fn retuned(overrides: &HoldTapOverrides<2>, slots: usize) -> [[[Key; 3]; 2]; 2] {
+        let mut layers = keymap();
+        overrides.retune(
+            &mut layers,
+            0..slots,
+            |k| matches!(k, Key::HoldTap { .. }),
+            |key, slot, behaviour| {
+                *key = Key::HoldTap {
+                    slot: Some(slot),
+                    behaviour,
+                }
+            },
+        );
+        layers
+    }
+
+    #[test]
+    fn retuning_gives_each_hold_tap_its_slot_and_override() {
+        let mut overrides = HoldTapOverrides::<2>::new();
+        overrides.set((0, 1, 2), Some(PERMISSIVE));
+        overrides.set((1, 1, 2), Some(REPEATING));
+        let layers = retuned(&overrides, 8);
+
+        for (pos, slot, behaviour) in [
+            ((0, 0, 1), 0, None),
+            ((0, 1, 0), 1, None),
+            ((0, 1, 2), 2, Some(PERMISSIVE)),
+            ((1, 0, 0), 3, None),
+            ((1, 1, 2), 4, Some(REPEATING)),
+        ] {
+            let (l, r, c) = pos;
+            assert_eq!(
+                layers[l][r][c],
+                Key::HoldTap {
+                    slot: Some(slot),
+                    behaviour
+                },
+                "at {pos:?}"
+            );
+            assert_eq!(hold_tap_at((l as u8, r as u8, c as u8)), Some(slot));
+        }
+        assert_eq!(layers[0][0][0], Key::Plain);
+        assert_eq!(layers[1][1][1], Key::Plain);
+    }
+
+    #[test]
+    fn hold_taps_past_the_slots_keep_the_keymaps() {
+        let mut overrides = HoldTapOverrides::<2>::new();
+        overrides.set((1, 0, 0), Some(PERMISSIVE));
+        let layers = retuned(&overrides, 3);
+
+        assert!(matches!(
+            layers[0][1][2],
+            Key::HoldTap { slot: Some(2), .. }
+        ));
+        // overridden or not, there's nowhere to put them
+        assert_eq!(layers[1][0][0], HT);
+        assert_eq!(layers[1][1][2], HT);
+    }
+
+    #[test]
+    fn behaviour_is_the_keymaps_until_overridden() {
+        let mut overrides = HoldTapOverrides::<2>::new();
+        assert_eq!(
+            overrides.behaviour((0, 1, 0), REPEATING),
+            (REPEATING, false)
+        );
+
+        overrides.set((0, 1, 0), Some(PERMISSIVE));
+        assert_eq!(
+            overrides.behaviour((0, 1, 0), REPEATING),
+            (PERMISSIVE, true)
+        );
+        assert_eq!(
+            overrides.behaviour((0, 0, 1), REPEATING),
+            (REPEATING, false)
+        );
+
+        overrides.set((0, 1, 0), None);
+        assert_eq!(
+            overrides.behaviour((0, 1, 0), REPEATING),
+            (REPEATING, false)
+        );
+    }
+}